@@ -0,0 +1,85 @@
+//! Records details about how the SDK was built, for `Wasmer.buildInfo()`.
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let lockfile = Path::new(&manifest_dir).join("Cargo.lock");
+    let lock = std::fs::read_to_string(&lockfile).unwrap_or_default();
+
+    for (name, var) in [
+        ("wasmer", "WASMER_JS_WASMER_VERSION"),
+        ("wasmer-wasix", "WASMER_JS_WASIX_VERSION"),
+    ] {
+        let version = locked_version(&lock, name).unwrap_or("unknown");
+        println!("cargo:rustc-env={var}={version}");
+    }
+
+    // Let CI provide the commit when building outside a git checkout
+    let git_hash = std::env::var("WASMER_JS_GIT_HASH").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .current_dir(&manifest_dir)
+            .output()
+            .ok()?;
+        let hash = String::from_utf8(output.stdout).ok()?;
+        output.status.success().then(|| hash.trim().to_string())
+    });
+    if let Some(hash) = git_hash {
+        println!("cargo:rustc-env=WASMER_JS_GIT_HASH={hash}");
+    }
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=WASMER_JS_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    watch_git_head(Path::new(&manifest_dir));
+    println!("cargo:rerun-if-env-changed=WASMER_JS_GIT_HASH");
+}
+
+/// Rebuild when the checked out commit changes.
+///
+/// `.git/HEAD` only changes when switching branches, so we also watch the
+/// branch it points to (which lives in `packed-refs` until it is next
+/// updated).
+fn watch_git_head(manifest_dir: &Path) {
+    let git_dir = manifest_dir.join(".git");
+    let head = git_dir.join("HEAD");
+    let Ok(contents) = std::fs::read_to_string(&head) else {
+        return;
+    };
+    println!("cargo:rerun-if-changed={}", head.display());
+
+    if let Some(branch) = contents.trim().strip_prefix("ref: ") {
+        // Note: Cargo always reruns the script if a watched file is missing
+        for path in [git_dir.join(branch), git_dir.join("packed-refs")] {
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+}
+
+/// Find a package's version in `Cargo.lock`.
+fn locked_version<'a>(lock: &'a str, name: &str) -> Option<&'a str> {
+    let mut lines = lock.lines();
+    let needle = format!("name = \"{name}\"");
+
+    while let Some(line) = lines.next() {
+        if line.trim() == needle {
+            return lines
+                .next()?
+                .trim()
+                .strip_prefix("version = \"")?
+                .strip_suffix('"');
+        }
+    }
+
+    None
+}
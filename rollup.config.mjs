@@ -55,6 +55,7 @@ const makeConfig = (env = "development", input, name, plugins = []) => {
             copy({
                 targets: [
                     { src: ['pkg/wasmer_js_bg.wasm', 'pkg/wasmer_js_bg.wasm.d.ts'], dest: 'dist' },
+                    { src: 'src/tasks/worker.js', dest: 'dist' },
                 ]
             })
         ],
@@ -0,0 +1,101 @@
+//! Aborting a runtime's in-flight HTTP requests when it is disposed.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{AbortHandle, Abortable, BoxFuture};
+use wasmer_wasix::http::{DynHttpClient, HttpClient, HttpRequest, HttpResponse};
+
+/// Every request currently being made through an [`AbortableHttpClient`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InFlightRequests(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    pending: BTreeMap<u64, AbortHandle>,
+    next_id: u64,
+    aborted: bool,
+}
+
+impl InFlightRequests {
+    /// Abort every pending request and fail any that are made afterwards.
+    pub(crate) fn abort_all(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.aborted = true;
+
+        let pending = std::mem::take(&mut state.pending);
+        tracing::debug!(count = pending.len(), "Aborting in-flight HTTP requests");
+        for handle in pending.into_values() {
+            handle.abort();
+        }
+    }
+
+    fn register(&self) -> Option<(u64, AbortHandle, futures::future::AbortRegistration)> {
+        let mut state = self.0.lock().unwrap();
+        if state.aborted {
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let (handle, registration) = AbortHandle::new_pair();
+        state.pending.insert(id, handle.clone());
+
+        Some((id, handle, registration))
+    }
+
+    fn finished(&self, id: u64) {
+        self.0.lock().unwrap().pending.remove(&id);
+    }
+}
+
+/// An [`HttpClient`] whose requests can all be aborted at once.
+#[derive(Debug, Clone)]
+pub(crate) struct AbortableHttpClient {
+    pub(crate) inner: DynHttpClient,
+    pub(crate) in_flight: InFlightRequests,
+}
+
+impl HttpClient for AbortableHttpClient {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, anyhow::Error>> {
+        let Some((id, _handle, registration)) = self.in_flight.register() else {
+            return Box::pin(async { Err(disposed()) });
+        };
+
+        let response = Abortable::new(self.inner.request(request), registration);
+        let in_flight = self.in_flight.clone();
+
+        Box::pin(async move {
+            let result = response.await;
+            in_flight.finished(id);
+            result.unwrap_or_else(|_| Err(disposed()))
+        })
+    }
+}
+
+fn disposed() -> anyhow::Error {
+    anyhow::anyhow!("The request was aborted because the runtime was disposed")
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn nothing_can_be_registered_after_aborting() {
+        let in_flight = InFlightRequests::default();
+        let (id, handle, _registration) = in_flight.register().unwrap();
+        let (_, other, _registration) = in_flight.register().unwrap();
+        in_flight.finished(id);
+
+        in_flight.abort_all();
+
+        assert!(!handle.is_aborted(), "finished requests are left alone");
+        assert!(other.is_aborted());
+        assert!(in_flight.register().is_none());
+    }
+}
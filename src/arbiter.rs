@@ -0,0 +1,424 @@
+//! Sharing workers and cache space between several runtimes on one page.
+//!
+//! Every runtime sizes its thread pool and package cache as if it had the
+//! page to itself, so a page embedding a handful of independent widgets can
+//! easily end up with several times more workers than cores. Runtimes
+//! created with the `arbiter` option announce themselves on a
+//! `BroadcastChannel` and split the budgets between everyone on the page:
+//!
+//! - the smallest total anyone announced wins, so one runtime can't raise
+//!   the limits for everyone else
+//! - each member gets an even share of that total, and always at least one
+//!   worker
+//! - members send a heartbeat every second, and ones that go quiet (e.g. a
+//!   runtime which was never disposed) are forgotten after a few seconds
+//!
+//! The worker share is a soft limit, like the pool's own capacity. Blocking
+//! work still gets a worker rather than risking a deadlock, so a runtime can
+//! go over its share while that work runs. Only idle workers are trimmed,
+//! and ones with async tasks in flight are terminated once those finish.
+//!
+//! A `BroadcastChannel` reaches every same-origin page, so messages are
+//! tagged with an ID stored on `globalThis` and other pages' runtimes are
+//! ignored.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use instant::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BroadcastChannel, MessageEvent};
+
+use crate::{
+    package_loader::PackageLoader,
+    tasks::ThreadPool,
+    utils::{Error, GlobalScope},
+};
+
+const DEFAULT_CHANNEL: &str = "wasmer-runtime-arbiter";
+/// The workers shared between runtimes when `navigator.hardwareConcurrency`
+/// isn't available.
+const DEFAULT_MAX_WORKERS: u32 = 4;
+const DEFAULT_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+const HEARTBEAT: Duration = Duration::from_secs(1);
+/// How long a member can go without a heartbeat before it is forgotten.
+const EXPIRY: Duration = Duration::from_secs(3);
+/// Where the page's ID is kept, so every runtime on the page agrees on it.
+const PAGE_ID: &str = "__wasmerArbiterPage";
+
+/// The parsed `arbiter` option.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ArbiterOptions {
+    channel: String,
+    limits: Limits,
+}
+
+impl ArbiterOptions {
+    /// Parse the `arbiter` option, returning `None` if the runtime shouldn't
+    /// share anything.
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Self>, Error> {
+        let max_workers = GlobalScope::current()
+            .hardware_concurrency()
+            .map(|n| u32::try_from(n.get()).unwrap_or(u32::MAX))
+            .unwrap_or(DEFAULT_MAX_WORKERS);
+        let defaults = ArbiterOptions {
+            channel: DEFAULT_CHANNEL.to_string(),
+            limits: Limits {
+                max_workers,
+                cache_bytes: DEFAULT_CACHE_BYTES as f64,
+            },
+        };
+
+        if let Some(enabled) = value.as_bool() {
+            return Ok(enabled.then_some(defaults));
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Init {
+            channel: Option<String>,
+            max_workers: Option<f64>,
+            cache_bytes: Option<f64>,
+        }
+
+        let Init {
+            channel,
+            max_workers,
+            cache_bytes,
+        } = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+
+        let positive = |name: &str, value: f64| {
+            if value.is_finite() && value >= 1.0 {
+                Ok(value)
+            } else {
+                let msg = format!("The arbiter's {name} must be at least 1, not {value}");
+                Err(Error::js(js_sys::RangeError::new(&msg)))
+            }
+        };
+
+        Ok(Some(ArbiterOptions {
+            channel: channel.unwrap_or(defaults.channel),
+            limits: Limits {
+                max_workers: match max_workers {
+                    Some(n) => positive("maxWorkers", n)? as u32,
+                    None => defaults.limits.max_workers,
+                },
+                cache_bytes: match cache_bytes {
+                    Some(bytes) => positive("cacheBytes", bytes)?.floor(),
+                    None => defaults.limits.cache_bytes,
+                },
+            },
+        }))
+    }
+}
+
+/// The totals a member thinks should be shared between everyone.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Limits {
+    max_workers: u32,
+    cache_bytes: f64,
+}
+
+/// What one member may use.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Share {
+    workers: NonZeroUsize,
+    cache_bytes: u64,
+}
+
+impl Share {
+    /// Split the smallest of everyone's limits evenly between `ours` and
+    /// our `peers`.
+    fn split(ours: Limits, peers: impl IntoIterator<Item = Limits>) -> Share {
+        let mut members = 1_usize;
+        let mut total = ours;
+        for peer in peers {
+            members += 1;
+            total.max_workers = total.max_workers.min(peer.max_workers);
+            total.cache_bytes = total.cache_bytes.min(peer.cache_bytes);
+        }
+
+        let workers = total.max_workers as usize / members;
+        Share {
+            workers: NonZeroUsize::new(workers).unwrap_or(NonZeroUsize::MIN),
+            cache_bytes: (total.cache_bytes / members as f64) as u64,
+        }
+    }
+}
+
+/// Messages sent between the members of an arbiter's channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Message {
+    /// A runtime joined, and everyone else should announce themselves.
+    Hello {
+        page: String,
+        id: String,
+        limits: Limits,
+    },
+    /// A runtime is still alive.
+    Heartbeat {
+        page: String,
+        id: String,
+        limits: Limits,
+    },
+    /// A runtime was disposed, so its share can be handed out again.
+    Bye { page: String, id: String },
+}
+
+/// A runtime's membership of an arbiter, which lasts until
+/// [`Membership::leave()`] is called.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Membership {
+    leaving: Arc<AtomicBool>,
+}
+
+impl Membership {
+    /// Tell the other members we're gone, so they can take over our share.
+    pub(crate) fn leave(&self) {
+        self.leaving.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Our side of the channel, which lives on the thread that joined.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Member {
+    id: String,
+    page: String,
+    limits: Limits,
+    channel: BroadcastChannel,
+    pool: ThreadPool,
+    #[derivative(Debug = "ignore")]
+    package_loader: Arc<PackageLoader>,
+    /// Everyone else on the page, and when we last heard from them.
+    peers: RefCell<BTreeMap<String, (Limits, Instant)>>,
+    /// The share we were last given.
+    share: Cell<Option<Share>>,
+}
+
+impl Member {
+    fn post(&self, message: &Message) {
+        let result = serde_wasm_bindgen::to_value(message)
+            .map_err(Error::js)
+            .and_then(|value| self.channel.post_message(&value).map_err(Error::js));
+
+        if let Err(e) = result {
+            tracing::debug!(
+                error = &*e.into_anyhow(),
+                "Unable to message the other runtimes"
+            );
+        }
+    }
+
+    fn heartbeat(&self) -> Message {
+        Message::Heartbeat {
+            page: self.page.clone(),
+            id: self.id.clone(),
+            limits: self.limits,
+        }
+    }
+
+    fn receive(&self, data: JsValue) {
+        let Ok(message) = serde_wasm_bindgen::from_value::<Message>(data) else {
+            return;
+        };
+
+        let (page, id) = match &message {
+            Message::Hello { page, id, .. }
+            | Message::Heartbeat { page, id, .. }
+            | Message::Bye { page, id } => (page, id),
+        };
+        if *page != self.page || *id == self.id {
+            return;
+        }
+
+        match message {
+            Message::Hello { id, limits, .. } => {
+                tracing::debug!(peer = %id, "Another runtime joined the arbiter");
+                self.peers.borrow_mut().insert(id, (limits, Instant::now()));
+                self.post(&self.heartbeat());
+            }
+            Message::Heartbeat { id, limits, .. } => {
+                self.peers.borrow_mut().insert(id, (limits, Instant::now()));
+            }
+            Message::Bye { id, .. } => {
+                tracing::debug!(peer = %id, "Another runtime left the arbiter");
+                self.peers.borrow_mut().remove(&id);
+            }
+        }
+
+        self.rebalance();
+    }
+
+    /// Forget any peers we haven't heard from in a while.
+    fn expire(&self, now: Instant) {
+        self.peers.borrow_mut().retain(|id, (_, last_seen)| {
+            let alive = now.duration_since(*last_seen) < EXPIRY;
+            if !alive {
+                tracing::debug!(peer = %id, "Forgetting a runtime that stopped responding");
+            }
+            alive
+        });
+    }
+
+    /// Recalculate our share, applying it if it changed.
+    fn rebalance(&self) {
+        let share = Share::split(
+            self.limits,
+            self.peers.borrow().values().map(|(limits, _)| *limits),
+        );
+        if self.share.get() == Some(share) {
+            return;
+        }
+
+        tracing::info!(
+            workers = share.workers.get(),
+            cache_bytes = share.cache_bytes,
+            peers = self.peers.borrow().len(),
+            "Resources were rebalanced between the page's runtimes",
+        );
+        self.share.set(Some(share));
+        self.pool.set_worker_share(Some(share.workers));
+        self.package_loader.set_cache_share(Some(share.cache_bytes));
+    }
+
+    fn leave(&self) {
+        self.post(&Message::Bye {
+            page: self.page.clone(),
+            id: self.id.clone(),
+        });
+        self.channel.set_onmessage(None);
+        self.channel.close();
+    }
+}
+
+/// Start sharing `pool`'s workers and `package_loader`'s cache with the
+/// other runtimes on the page.
+///
+/// This must be called on the scheduler's thread.
+pub(crate) fn join(
+    pool: ThreadPool,
+    package_loader: Arc<PackageLoader>,
+    options: ArbiterOptions,
+) -> Result<Membership, Error> {
+    let channel = BroadcastChannel::new(&options.channel).map_err(Error::js)?;
+    let member = Rc::new(Member {
+        id: random_id(),
+        page: page_id(),
+        limits: options.limits,
+        channel,
+        pool,
+        package_loader,
+        peers: RefCell::default(),
+        share: Cell::new(None),
+    });
+
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new({
+        let member = Rc::clone(&member);
+        move |event: MessageEvent| member.receive(event.data())
+    });
+    member
+        .channel
+        .set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+    member.post(&Message::Hello {
+        page: member.page.clone(),
+        id: member.id.clone(),
+        limits: member.limits,
+    });
+    member.rebalance();
+
+    let membership = Membership::default();
+    let leaving = Arc::clone(&membership.leaving);
+    wasm_bindgen_futures::spawn_local(async move {
+        // Note: The callback needs to live for as long as we're listening
+        let _onmessage = onmessage;
+        let ms = i32::try_from(HEARTBEAT.as_millis()).unwrap_or(i32::MAX);
+
+        loop {
+            let _ = JsFuture::from(GlobalScope::current().sleep(ms)).await;
+
+            if leaving.load(Ordering::Relaxed) {
+                member.leave();
+                return;
+            }
+
+            member.post(&member.heartbeat());
+            member.expire(Instant::now());
+            member.rebalance();
+        }
+    });
+
+    Ok(membership)
+}
+
+/// The ID shared by every runtime on this page.
+fn page_id() -> String {
+    let global = js_sys::global();
+    let key = JsValue::from_str(PAGE_ID);
+
+    if let Some(id) = js_sys::Reflect::get(&global, &key)
+        .ok()
+        .and_then(|id| id.as_string())
+    {
+        return id;
+    }
+
+    let id = random_id();
+    let _ = js_sys::Reflect::set(&global, &key, &JsValue::from_str(&id));
+    id
+}
+
+fn random_id() -> String {
+    let part = || (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("{:08x}{:08x}", part(), part())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn the_smallest_limits_are_split_evenly() {
+        let limits = |max_workers, cache_bytes| Limits {
+            max_workers,
+            cache_bytes,
+        };
+        let share = |workers, cache_bytes| Share {
+            workers: NonZeroUsize::new(workers).unwrap(),
+            cache_bytes,
+        };
+
+        assert_eq!(Share::split(limits(8, 1000.0), []), share(8, 1000));
+        assert_eq!(
+            Share::split(limits(8, 1000.0), [limits(6, 3000.0)]),
+            share(3, 500)
+        );
+        assert_eq!(
+            Share::split(limits(2, 900.0), [limits(8, 900.0), limits(8, 900.0)]),
+            share(1, 300)
+        );
+
+        let options = js_sys::JSON::parse(r#"{"maxWorkers": 4, "channel": "widgets"}"#).unwrap();
+        let options = ArbiterOptions::parse(options).unwrap().unwrap();
+        assert_eq!(options.channel, "widgets");
+        assert_eq!(options.limits.max_workers, 4);
+        assert_eq!(ArbiterOptions::parse(false.into()).unwrap(), None);
+        let zero = js_sys::JSON::parse(r#"{"maxWorkers": 0}"#).unwrap();
+        assert!(ArbiterOptions::parse(zero).is_err());
+    }
+}
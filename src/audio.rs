@@ -0,0 +1,425 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{Pipe, VirtualFile};
+use wasm_bindgen::prelude::wasm_bindgen;
+use web_sys::ReadableStream;
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Opener},
+    Directory,
+};
+
+const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+const DEFAULT_CHANNELS: u32 = 2;
+/// Each sample is a 32-bit float.
+const BYTES_PER_SAMPLE: u32 = 4;
+
+/// An audio output device that WASIX programs can stream raw PCM samples to.
+///
+/// The device is exposed to guests as a {@link Directory} containing two files:
+///
+/// - `dsp` - write interleaved 32-bit little-endian float samples here
+/// - `config` - a `key=value` description of the expected stream format
+///
+/// Everything written to `dsp` comes out of {@link AudioOutput.stream}, which
+/// can be forwarded to an `AudioWorklet`.
+///
+/// Only {@link AudioOutputOptions.bufferFrames} frames can be waiting to be
+/// read at a time. Like a sound card, writes which arrive while that buffer
+/// is full are dropped rather than queued, so a guest producing audio faster
+/// than it is played can't use up the page's memory. Dropped frames are
+/// counted in {@link AudioOutput.droppedFrames}.
+///
+/// @example
+/// ```ts
+/// const ctx = new AudioContext();
+/// const audio = new AudioOutput({ context: ctx });
+///
+/// const instance = await pkg.entrypoint!.run({
+///     mount: { "/dev/audio": audio.directory },
+/// });
+///
+/// for await (const chunk of audio.stream) {
+///     worklet.port.postMessage(new Float32Array(chunk.buffer));
+/// }
+/// ```
+#[derive(Debug)]
+#[wasm_bindgen]
+pub struct AudioOutput {
+    /// The number of frames per second the guest should produce.
+    #[wasm_bindgen(js_name = "sampleRate", readonly)]
+    pub sample_rate: u32,
+    /// The number of interleaved channels in each frame.
+    #[wasm_bindgen(readonly)]
+    pub channels: u32,
+    /// The number of frames that can be waiting to be read before writes
+    /// start getting dropped.
+    #[wasm_bindgen(js_name = "bufferFrames", readonly)]
+    pub buffer_frames: u32,
+    /// The raw PCM data written by the guest.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub stream: ReadableStream,
+    pipe: Pipe,
+    buffer: Arc<Buffer>,
+    dir: Directory,
+}
+
+#[wasm_bindgen]
+impl AudioOutput {
+    #[wasm_bindgen(constructor)]
+    pub fn new(options: Option<AudioOutputOptions>) -> AudioOutput {
+        let sample_rate = options
+            .as_ref()
+            .and_then(|opts| {
+                opts.sample_rate()
+                    .or_else(|| opts.context().map(|ctx| ctx.sample_rate() as u32))
+            })
+            .unwrap_or(DEFAULT_SAMPLE_RATE);
+        let channels = options
+            .as_ref()
+            .and_then(|opts| opts.channels())
+            .unwrap_or(DEFAULT_CHANNELS)
+            .max(1);
+        let buffer_frames = options
+            .as_ref()
+            .and_then(|opts| opts.buffer_frames())
+            .unwrap_or(sample_rate / 4)
+            .max(1);
+
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let (pipe, stream) =
+            crate::streams::counted_output_pipe(Arc::clone(&bytes_read), Arc::default());
+        let buffer = Arc::new(Buffer {
+            frame_bytes: u64::from(channels * BYTES_PER_SAMPLE),
+            capacity: u64::from(buffer_frames) * u64::from(channels * BYTES_PER_SAMPLE),
+            written: AtomicU64::new(0),
+            read: bytes_read,
+            dropped_frames: AtomicU64::new(0),
+        });
+
+        let fs = DeviceFileSystem::default();
+        let opener = Opener::new({
+            let pipe = pipe.clone();
+            let buffer = Arc::clone(&buffer);
+            move || {
+                Box::new(DspFile {
+                    pipe: pipe.clone(),
+                    buffer: Arc::clone(&buffer),
+                })
+            }
+        });
+        fs.insert("dsp", Device::Opener(opener));
+        fs.insert(
+            "config",
+            Device::Static(config_file(sample_rate, channels, buffer_frames).into_bytes()),
+        );
+
+        AudioOutput {
+            sample_rate,
+            channels,
+            buffer_frames,
+            stream,
+            pipe,
+            buffer,
+            dir: Directory::from_filesystem(fs),
+        }
+    }
+
+    /// How many frames have been dropped because the guest wrote them while
+    /// the buffer was full.
+    #[wasm_bindgen(getter, js_name = "droppedFrames")]
+    pub fn dropped_frames(&self) -> f64 {
+        self.buffer.dropped_frames.load(Ordering::Relaxed) as f64
+    }
+
+    /// A {@link Directory} that can be mounted inside a WASIX instance to give
+    /// it access to this device.
+    #[wasm_bindgen(getter)]
+    pub fn directory(&self) -> Directory {
+        self.dir.clone()
+    }
+
+    /// Close the device, signalling the end of {@link AudioOutput.stream}.
+    pub fn close(&self) {
+        self.pipe.close();
+    }
+}
+
+fn config_file(sample_rate: u32, channels: u32, buffer_frames: u32) -> String {
+    format!(
+        "sample_rate={sample_rate}\nchannels={channels}\nformat=f32le\nbuffer_frames={buffer_frames}\n"
+    )
+}
+
+/// Keeps track of how much audio is waiting to be read from the stream.
+#[derive(Debug)]
+struct Buffer {
+    /// The size of one frame, in bytes.
+    frame_bytes: u64,
+    /// The most bytes that can be waiting to be read.
+    capacity: u64,
+    written: AtomicU64,
+    /// Updated by the stream as JavaScript reads from it.
+    read: Arc<AtomicU64>,
+    dropped_frames: AtomicU64,
+}
+
+impl Buffer {
+    /// How many more bytes can be written before the buffer is full?
+    fn available(&self) -> u64 {
+        let waiting = self
+            .written
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.read.load(Ordering::Relaxed));
+        self.capacity.saturating_sub(waiting)
+    }
+}
+
+/// A handle to the `dsp` device, created whenever it is opened.
+#[derive(Debug)]
+struct DspFile {
+    pipe: Pipe,
+    buffer: Arc<Buffer>,
+}
+
+impl VirtualFile for DspFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(virtual_fs::FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Err(virtual_fs::FsError::PermissionDenied) })
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(self.buffer.capacity as usize))
+    }
+}
+
+impl AsyncRead for DspFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DspFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.len() as u64 > self.buffer.available() {
+            // Note: drop the whole write so the frames that do make it
+            // through stay aligned
+            let frames = buf.len() as u64 / self.buffer.frame_bytes;
+            self.buffer
+                .dropped_frames
+                .fetch_add(frames, Ordering::Relaxed);
+            tracing::trace!(frames, "The audio buffer is full, dropping samples");
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        let this = &mut *self;
+        let result = Pin::new(&mut this.pipe).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.buffer
+                .written
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Note: other handles may still be writing, so only
+        // AudioOutput.close() ends the stream
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for DspFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The audio device can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const AUDIO_OUTPUT_OPTIONS_TYPE_DECLARATION: &str = r#"
+/**
+ * Options used when constructing an {@link AudioOutput}.
+ */
+export type AudioOutputOptions = {
+    /**
+     * The `AudioContext` the samples will be played through. The guest is
+     * told to produce audio at its `sampleRate`, so nothing needs to be
+     * resampled.
+     */
+    context?: BaseAudioContext;
+    /**
+     * The sample rate the guest should produce audio at, overriding the
+     * {@link AudioOutputOptions.context}'s.
+     *
+     * Defaults to the context's `sampleRate`, or `48000` without one.
+     */
+    sampleRate?: number;
+    /**
+     * The number of interleaved channels.
+     *
+     * Defaults to `2`.
+     */
+    channels?: number;
+    /**
+     * How many frames can be waiting to be read from
+     * {@link AudioOutput.stream} before writes start getting dropped.
+     *
+     * Defaults to a quarter of a second's worth.
+     */
+    bufferFrames?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "AudioOutputOptions")]
+    pub type AudioOutputOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "sampleRate")]
+    fn sample_rate(this: &AudioOutputOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn channels(this: &AudioOutputOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter, js_name = "bufferFrames")]
+    fn buffer_frames(this: &AudioOutputOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn context(this: &AudioOutputOptions) -> Option<AudioContext>;
+
+    #[wasm_bindgen(typescript_type = "BaseAudioContext")]
+    type AudioContext;
+
+    #[wasm_bindgen(method, getter, js_name = "sampleRate")]
+    fn sample_rate(this: &AudioContext) -> f64;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use virtual_fs::{AsyncReadExt, AsyncWriteExt, FileSystem};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn samples_written_by_the_guest_come_out_of_the_stream() {
+        let audio = AudioOutput::new(None);
+        let dir = audio.directory();
+
+        let mut dsp = dir.new_open_options().write(true).open("/dsp").unwrap();
+        dsp.write_all(&[1, 2, 3, 4]).await.unwrap();
+        audio.close();
+
+        let data = crate::streams::read_to_end(audio.stream.clone())
+            .try_fold(Vec::new(), |mut buffer, chunk| async {
+                buffer.extend(chunk);
+                Ok(buffer)
+            })
+            .await
+            .unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn guests_can_read_the_stream_format() {
+        let audio = AudioOutput::new(None);
+        let dir = audio.directory();
+
+        let mut config = dir.new_open_options().read(true).open("/config").unwrap();
+        let mut contents = String::new();
+        config.read_to_string(&mut contents).await.unwrap();
+
+        assert_eq!(
+            contents,
+            "sample_rate=48000\nchannels=2\nformat=f32le\nbuffer_frames=12000\n"
+        );
+    }
+
+    fn options(value: &str) -> AudioOutputOptions {
+        js_sys::JSON::parse(value).unwrap().unchecked_into()
+    }
+
+    #[wasm_bindgen_test]
+    fn the_sample_rate_comes_from_the_audio_context() {
+        let audio = AudioOutput::new(Some(options(r#"{"context": {"sampleRate": 44100}}"#)));
+
+        assert_eq!(audio.sample_rate, 44100);
+        assert_eq!(audio.buffer_frames, 44100 / 4);
+    }
+
+    #[wasm_bindgen_test]
+    async fn writes_are_dropped_while_the_buffer_is_full() {
+        let audio = AudioOutput::new(Some(options(r#"{"channels": 1, "bufferFrames": 2}"#)));
+        let dir = audio.directory();
+
+        let mut dsp = dir.new_open_options().write(true).open("/dsp").unwrap();
+        dsp.write_all(&[1; 8]).await.unwrap();
+        // Nobody has read anything yet, so there's no room for these
+        dsp.write_all(&[2; 8]).await.unwrap();
+        audio.close();
+
+        let data = crate::streams::read_to_end(audio.stream.clone())
+            .try_fold(Vec::new(), |mut buffer, chunk| async {
+                buffer.extend(chunk);
+                Ok(buffer)
+            })
+            .await
+            .unwrap();
+        assert_eq!(data, [1; 8]);
+        assert_eq!(audio.dropped_frames(), 2.0);
+    }
+}
@@ -0,0 +1,243 @@
+//! Running lots of short commands in one go.
+//!
+//! Grading and CI-style workloads run the same handful of packages
+//! thousands of times, and starting each run with `Wasmer.fromRegistry()`
+//! repeats work that only needs doing once. A batch loads every distinct
+//! package up front, then runs the jobs with a bounded amount of
+//! concurrency. Compiled modules come from the runtime's module cache and
+//! workers are reused between jobs, so after the first run of each command
+//! all that's left per job is instantiating it.
+//!
+//! A failing job doesn't stop the batch. Every job gets a result, in the
+//! order the jobs were given.
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures::StreamExt;
+use js_sys::{Array, Object, Reflect};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+use crate::{instance::JsOutput, options::SpawnOptions, runtime::Runtime, utils::Error, Wasmer};
+
+/// Run every job in `jobs` on `runtime`.
+pub(crate) async fn batch(
+    runtime: Arc<Runtime>,
+    jobs: ListOfBatchJob,
+    options: Option<BatchOptions>,
+) -> Result<ListOfBatchResult, Error> {
+    let options = options.unwrap_or_else(|| Object::new().unchecked_into());
+    let concurrency = match options.concurrency() {
+        Some(n) => crate::js_runtime::parse_count("concurrency", n)?,
+        None => runtime.thread_pool().parallelism(),
+    };
+    let limit = options.max_captured_bytes().map(|limit| limit as usize);
+
+    let jobs = Array::from(&jobs)
+        .iter()
+        .map(|job| Job::parse(job.unchecked_into()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let packages = load_packages(&runtime, &jobs).await;
+    tracing::debug!(
+        jobs = jobs.len(),
+        packages = packages.len(),
+        concurrency = concurrency.get(),
+        "Running a batch"
+    );
+
+    let results: Vec<JsValue> = futures::stream::iter(jobs)
+        .map(|job| {
+            let pkg = packages[&job.package].clone();
+            async move {
+                let result = match pkg {
+                    Ok(pkg) => job.run(&pkg, limit).await.map_err(JsValue::from),
+                    Err(e) => Err(e),
+                };
+                to_js(result)
+            }
+        })
+        .buffered(concurrency.get())
+        .collect()
+        .await;
+
+    Ok(results.into_iter().collect::<Array>().unchecked_into())
+}
+
+/// Load each package the jobs need, once.
+async fn load_packages(
+    runtime: &Arc<Runtime>,
+    jobs: &[Job],
+) -> HashMap<String, Result<Wasmer, JsValue>> {
+    let mut specifiers: Vec<&str> = jobs.iter().map(|job| job.package.as_str()).collect();
+    specifiers.sort_unstable();
+    specifiers.dedup();
+
+    let loaded = futures::future::join_all(specifiers.iter().map(|specifier| {
+        let runtime = Arc::clone(runtime);
+        async move {
+            Wasmer::from_registry_with_runtime(specifier, runtime)
+                .await
+                .map_err(JsValue::from)
+        }
+    }))
+    .await;
+
+    specifiers
+        .into_iter()
+        .map(String::from)
+        .zip(loaded)
+        .collect()
+}
+
+/// A parsed [`BatchJob`].
+#[derive(Debug)]
+struct Job {
+    package: String,
+    command: Option<String>,
+    options: SpawnOptions,
+}
+
+impl Job {
+    fn parse(job: BatchJob) -> Result<Self, Error> {
+        let Some(package) = job.package() else {
+            let msg = "Every batch job needs a \"package\"";
+            return Err(Error::js(js_sys::TypeError::new(msg)));
+        };
+
+        // Note: Copy the job so defaults don't leak into the caller's object
+        let options = Object::assign(&Object::new(), &job);
+        let has = |key: &str| Reflect::has(&options, &key.into()).unwrap_or(false);
+        if !has("stdin") && !has("emptyStdin") {
+            Reflect::set(&options, &"emptyStdin".into(), &"eof".into()).map_err(Error::js)?;
+        }
+
+        Ok(Job {
+            package,
+            command: job.command(),
+            options: options.unchecked_into(),
+        })
+    }
+
+    async fn run(self, pkg: &Wasmer, limit: Option<usize>) -> Result<JsOutput, Error> {
+        let cmd = match self.command.as_deref() {
+            Some(name) => pkg.command(name),
+            None => pkg.entrypoint.clone(),
+        }
+        .ok_or_else(|| {
+            let msg = match &self.command {
+                Some(name) => format!("\"{}\" doesn't have a \"{name}\" command", self.package),
+                None => format!("\"{}\" doesn't have an entrypoint", self.package),
+            };
+            Error::js(js_sys::TypeError::new(&msg))
+        })?;
+
+        let instance = cmd.run(Some(self.options)).await?;
+        instance.wait_for_output(limit).await
+    }
+}
+
+fn to_js(result: Result<JsOutput, JsValue>) -> JsValue {
+    let obj = Object::new();
+    let _ = match result {
+        Ok(output) => Reflect::set(&obj, &"output".into(), &output),
+        Err(error) => Reflect::set(&obj, &"error".into(), &error),
+    };
+    obj.into()
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const BATCH_TYPE_DEFS: &'static str = r#"
+/**
+ * One command to run as part of {@link Runtime.batch}.
+ *
+ * Any {@link SpawnOptions} can be given as well. Unless `stdin` or
+ * `emptyStdin` is set, the command sees an empty stdin instead of waiting
+ * for input.
+ */
+export type BatchJob = SpawnOptions & {
+    /** The package to run, as passed to {@link Wasmer.fromRegistry}. */
+    package: string;
+    /** The command to run, if not the package's entrypoint. */
+    command?: string;
+};
+
+export type BatchOptions = {
+    /**
+     * How many jobs may run at once (default: {@link Runtime.threads}).
+     *
+     * The runtime's `maxConcurrentInstances` still applies.
+     */
+    concurrency?: number;
+    /**
+     * The maximum number of bytes to capture from each job's stdout and
+     * stderr (see {@link WaitOptions}).
+     */
+    maxCapturedBytes?: number;
+};
+
+/**
+ * What happened to one {@link BatchJob}: its output if it ran, or why it
+ * couldn't (e.g. the package failed to load).
+ */
+export type BatchResult =
+    | { output: Output; error?: undefined }
+    | { output?: undefined; error: unknown };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "BatchJob", extends = SpawnOptions)]
+    pub type BatchJob;
+
+    #[wasm_bindgen(method, getter)]
+    fn package(this: &BatchJob) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn command(this: &BatchJob) -> Option<String>;
+
+    #[wasm_bindgen(typescript_type = "BatchJob[]")]
+    pub type ListOfBatchJob;
+
+    #[wasm_bindgen(typescript_type = "BatchOptions")]
+    pub type BatchOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn concurrency(this: &BatchOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "maxCapturedBytes")]
+    fn max_captured_bytes(this: &BatchOptions) -> Option<f64>;
+
+    #[wasm_bindgen(typescript_type = "BatchResult[]")]
+    pub type ListOfBatchResult;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn jobs_default_to_an_empty_stdin() {
+        let job: BatchJob = js_sys::JSON::parse(r#"{"package": "wasmer/hello", "args": ["-v"]}"#)
+            .unwrap()
+            .unchecked_into();
+        let parsed = Job::parse(job.clone()).unwrap();
+
+        assert_eq!(parsed.package, "wasmer/hello");
+        assert_eq!(parsed.command, None);
+        assert_eq!(
+            Reflect::get(&parsed.options, &"emptyStdin".into()).unwrap(),
+            "eof"
+        );
+        assert!(!Reflect::has(&job, &"emptyStdin".into()).unwrap());
+
+        let piped: BatchJob = js_sys::JSON::parse(r#"{"package": "a/b", "stdin": "hi"}"#)
+            .unwrap()
+            .unchecked_into();
+        let parsed = Job::parse(piped).unwrap();
+        assert!(!Reflect::has(&parsed.options, &"emptyStdin".into()).unwrap());
+
+        let missing: BatchJob = Object::new().unchecked_into();
+        assert!(Job::parse(missing).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! A benchmark harness for packages, so performance numbers from different
+//! browsers (or bug reports) are measured the same way.
+//!
+//! Each iteration loads the package, compiles its command, and runs it to
+//! completion. The "evicted" variant drops the runtime's cached modules and
+//! packages before every iteration, while the "warm" variant runs after a
+//! few unmeasured warm-up iterations so everything is already cached.
+//!
+//! Neither is a true cold start. The browser's HTTP cache can still serve
+//! the package, and the workers are left running because other programs
+//! may be sharing the runtime.
+
+use std::sync::Arc;
+
+use instant::Instant;
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasmer_wasix::Runtime as _;
+
+use crate::{options::SpawnOptions, runtime::Runtime, utils::Error, Wasmer};
+
+const DEFAULT_ITERATIONS: u32 = 5;
+const DEFAULT_WARMUP: u32 = 1;
+
+/// How long one iteration's phases took, in milliseconds.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+struct Sample {
+    startup: f64,
+    compile: f64,
+    run: f64,
+    total: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+struct Stats {
+    min: f64,
+    max: f64,
+    mean: f64,
+    median: f64,
+    stddev: f64,
+}
+
+impl Stats {
+    fn from_samples(samples: impl IntoIterator<Item = f64>) -> Self {
+        let mut values: Vec<f64> = samples.into_iter().collect();
+        values.sort_by(f64::total_cmp);
+
+        if values.is_empty() {
+            return Stats {
+                min: 0.0,
+                max: 0.0,
+                mean: 0.0,
+                median: 0.0,
+                stddev: 0.0,
+            };
+        }
+
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        let mid = values.len() / 2;
+        let median = if values.len() % 2 == 0 {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        };
+
+        Stats {
+            min: values[0],
+            max: values[values.len() - 1],
+            mean,
+            median,
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Variant {
+    startup: Stats,
+    compile: Stats,
+    run: Stats,
+    total: Stats,
+    samples: Vec<Sample>,
+}
+
+impl Variant {
+    fn new(samples: Vec<Sample>) -> Self {
+        let stats = |f: fn(&Sample) -> f64| Stats::from_samples(samples.iter().map(f));
+
+        Variant {
+            startup: stats(|s| s.startup),
+            compile: stats(|s| s.compile),
+            run: stats(|s| s.run),
+            total: stats(|s| s.total),
+            samples,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BenchReport {
+    package: String,
+    command: String,
+    iterations: u32,
+    warmup: u32,
+    evicted: Variant,
+    warm: Variant,
+    user_agent: Option<String>,
+    hardware_concurrency: Option<usize>,
+}
+
+/// Benchmark `specifier`'s command on `runtime`.
+pub(crate) async fn bench(
+    specifier: &str,
+    options: Option<BenchOptions>,
+    runtime: Arc<Runtime>,
+) -> Result<JsBenchReport, Error> {
+    let options = options.unwrap_or_else(|| js_sys::Object::new().unchecked_into());
+    let iterations = options.iterations().unwrap_or(DEFAULT_ITERATIONS);
+    let warmup = options.warmup().unwrap_or(DEFAULT_WARMUP);
+    if iterations == 0 {
+        let msg = "A benchmark needs at least one iteration";
+        return Err(Error::js(js_sys::RangeError::new(msg)));
+    }
+    let args = match options.args() {
+        Some(args) => crate::utils::js_string_array(args)?,
+        None => Vec::new(),
+    };
+    let command = options.command();
+
+    let mut evicted = Vec::new();
+    for _ in 0..iterations {
+        runtime.evict_caches();
+        evicted.push(
+            measure(specifier, command.as_deref(), &args, &runtime)
+                .await?
+                .0,
+        );
+    }
+
+    for _ in 0..warmup {
+        measure(specifier, command.as_deref(), &args, &runtime).await?;
+    }
+
+    let mut warm = Vec::new();
+    let mut command_name = String::new();
+    for _ in 0..iterations {
+        let (sample, name) = measure(specifier, command.as_deref(), &args, &runtime).await?;
+        warm.push(sample);
+        command_name = name;
+    }
+
+    let scope = crate::utils::GlobalScope::current();
+    let report = BenchReport {
+        package: specifier.to_string(),
+        command: command_name,
+        iterations,
+        warmup,
+        evicted: Variant::new(evicted),
+        warm: Variant::new(warm),
+        user_agent: scope.user_agent(),
+        hardware_concurrency: scope.hardware_concurrency().map(|n| n.get()),
+    };
+
+    let value = serde_wasm_bindgen::to_value(&report).map_err(Error::js)?;
+    Ok(value.unchecked_into())
+}
+
+/// Load, compile and run the command once, returning how long each step
+/// took and the name of the command that was run.
+async fn measure(
+    specifier: &str,
+    command: Option<&str>,
+    args: &[String],
+    runtime: &Arc<Runtime>,
+) -> Result<(Sample, String), Error> {
+    let started = Instant::now();
+    let pkg = Wasmer::from_registry_with_runtime(specifier, Arc::clone(runtime)).await?;
+    let startup = started.elapsed();
+
+    let cmd = match command {
+        Some(name) => pkg.command(name),
+        None => pkg.entrypoint.clone(),
+    }
+    .ok_or_else(|| {
+        let msg = format!("\"{specifier}\" doesn't have a command called {command:?}");
+        Error::js(js_sys::TypeError::new(&msg))
+    })?;
+
+    let name = String::from(&cmd.name);
+    let binary = cmd.binary().to_vec();
+
+    let compiling = Instant::now();
+    runtime.load_module(&binary).await?;
+    let compile = compiling.elapsed();
+
+    let running = Instant::now();
+    let code = cmd
+        .run(Some(spawn_options(args)?))
+        .await?
+        .wait_for_exit()
+        .await?;
+    let run = running.elapsed();
+    if code != 0 {
+        let msg = format!("\"{name}\" exited with code {code} while benchmarking");
+        return Err(Error::js(js_sys::Error::new(&msg)));
+    }
+
+    let ms = |d: instant::Duration| d.as_secs_f64() * 1000.0;
+    let sample = Sample {
+        startup: ms(startup),
+        compile: ms(compile),
+        run: ms(run),
+        total: ms(started.elapsed()),
+    };
+
+    Ok((sample, name))
+}
+
+/// Run the command with `args` and nothing on stdin, so it can't block
+/// waiting for input.
+fn spawn_options(args: &[String]) -> Result<SpawnOptions, Error> {
+    let options = js_sys::Object::new();
+    let args: js_sys::Array = args.iter().map(|a| JsValue::from_str(a)).collect();
+    js_sys::Reflect::set(&options, &"args".into(), &args).map_err(Error::js)?;
+    js_sys::Reflect::set(&options, &"emptyStdin".into(), &"eof".into()).map_err(Error::js)?;
+    Ok(options.unchecked_into())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const BENCH_TYPE_DEFS: &'static str = r#"
+export type BenchOptions = {
+    /** How many measured iterations to run for each variant (default: 5). */
+    iterations?: number;
+    /**
+     * How many unmeasured iterations to run before the warm variant
+     * (default: 1).
+     */
+    warmup?: number;
+    /** The command to run, if not the package's entrypoint. */
+    command?: string;
+    /** Arguments to pass to the command. */
+    args?: string[];
+};
+
+/** Statistics for one phase, in milliseconds. */
+export type BenchStats = {
+    min: number;
+    max: number;
+    mean: number;
+    median: number;
+    stddev: number;
+};
+
+/** How long each iteration took, in milliseconds. */
+export type BenchSample = {
+    /** Resolving, downloading and loading the package. */
+    startup: number;
+    /** Compiling the command's module. */
+    compile: number;
+    /** Running the command until it exits. */
+    run: number;
+    total: number;
+};
+
+export type BenchVariant = {
+    startup: BenchStats;
+    compile: BenchStats;
+    run: BenchStats;
+    total: BenchStats;
+    samples: BenchSample[];
+};
+
+/**
+ * The results of {@link Wasmer.bench}.
+ *
+ * The `evicted` variant drops the runtime's cached modules and packages
+ * before every iteration, and the `warm` variant runs with everything
+ * cached. Neither is a true cold start: the browser's HTTP cache may still
+ * serve the package, and the runtime's workers are kept.
+ */
+export type BenchReport = {
+    package: string;
+    command: string;
+    iterations: number;
+    warmup: number;
+    evicted: BenchVariant;
+    warm: BenchVariant;
+    userAgent?: string;
+    hardwareConcurrency?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "BenchOptions")]
+    pub type BenchOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn iterations(this: &BenchOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn warmup(this: &BenchOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn command(this: &BenchOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn args(this: &BenchOptions) -> Option<js_sys::Array>;
+
+    #[wasm_bindgen(typescript_type = "BenchReport")]
+    pub type JsBenchReport;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn summarize_samples() {
+        let stats = Stats::from_samples([4.0, 1.0, 3.0, 2.0]);
+
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 4.0);
+        assert_eq!(stats.mean, 2.5);
+        assert_eq!(stats.median, 2.5);
+        assert!((stats.stddev - 1.118).abs() < 0.001);
+        assert_eq!(Stats::from_samples([7.0]).median, 7.0);
+    }
+}
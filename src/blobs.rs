@@ -0,0 +1,271 @@
+//! A page-wide, content-addressed store for immutable blobs of bytes.
+//!
+//! Sessions that use several runtimes (or keep re-pinning the same local
+//! package) would otherwise keep one copy of a `*.webc` file per cache that
+//! has seen it. Storing blobs by their SHA-256 hash means identical contents
+//! are only kept in memory once, and because [`Container`]s and the atoms
+//! loaded from them are slices of the same [`Bytes`], their volumes are
+//! shared too. Directory snapshots keep file contents here as well, so
+//! files that didn't change between snapshots (or that match a file
+//! elsewhere) don't take up any extra space.
+//!
+//! Every [`Blob`] handle holds a reference to its entry. Entries nobody
+//! references any more stick around so a package that gets loaded again
+//! soon doesn't need to be downloaded, until the amount of garbage passes
+//! [`GC_THRESHOLD`] and it is all freed.
+//!
+//! [`Container`]: webc::Container
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use wasmer_wasix::runtime::resolver::WebcHash;
+
+/// Unreferenced bytes we are willing to keep around before freeing them.
+const GC_THRESHOLD: u64 = 32 * 1024 * 1024;
+
+static GLOBAL: Lazy<BlobStore> = Lazy::new(BlobStore::default);
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BlobStore(Arc<Mutex<StoreState>>);
+
+#[derive(Debug, Default)]
+struct StoreState {
+    entries: HashMap<WebcHash, Entry>,
+    /// The total size of entries without any references.
+    garbage: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    bytes: Bytes,
+    refs: usize,
+}
+
+impl BlobStore {
+    /// The store shared by everything on this page.
+    pub(crate) fn global() -> &'static BlobStore {
+        &GLOBAL
+    }
+
+    /// Add `bytes` to the store, reusing the existing copy if we already have
+    /// one with the same contents.
+    pub(crate) fn insert(&self, bytes: Bytes) -> Blob {
+        let hash = WebcHash::sha256(bytes.as_ref());
+        self.insert_hashed(hash, bytes)
+    }
+
+    /// Like [`BlobStore::insert()`], but only if `bytes` really do have the
+    /// hash someone (e.g. the registry) claimed they have.
+    ///
+    /// The store is shared by every runtime on the page, so trusting the
+    /// claim would let one bad download replace the package for everyone.
+    pub(crate) fn insert_with_hash(
+        &self,
+        expected: WebcHash,
+        bytes: Bytes,
+    ) -> Result<Blob, anyhow::Error> {
+        let hash = WebcHash::sha256(bytes.as_ref());
+        if hash != expected {
+            anyhow::bail!("Expected contents with a SHA-256 hash of {expected}, but got {hash}");
+        }
+
+        Ok(self.insert_hashed(hash, bytes))
+    }
+
+    fn insert_hashed(&self, hash: WebcHash, bytes: Bytes) -> Blob {
+        let mut state = self.0.lock().unwrap();
+        let bytes = if state.entries.contains_key(&hash) {
+            state.acquire(&hash);
+            state.entries[&hash].bytes.clone()
+        } else {
+            // Note: new entries start out referenced, so they were never
+            // counted as garbage
+            state.entries.insert(
+                hash,
+                Entry {
+                    bytes: bytes.clone(),
+                    refs: 1,
+                },
+            );
+            bytes
+        };
+
+        Blob {
+            hash,
+            bytes,
+            store: self.clone(),
+        }
+    }
+
+    /// Get a handle to the blob with this hash, if it is still in the store.
+    pub(crate) fn get(&self, hash: &WebcHash) -> Option<Blob> {
+        let mut state = self.0.lock().unwrap();
+        let bytes = state.entries.get(hash)?.bytes.clone();
+        state.acquire(hash);
+
+        Some(Blob {
+            hash: *hash,
+            bytes,
+            store: self.clone(),
+        })
+    }
+
+    /// Free every blob that is no longer referenced, returning the number of
+    /// bytes freed.
+    pub(crate) fn collect_garbage(&self) -> u64 {
+        self.0.lock().unwrap().collect_garbage()
+    }
+
+    /// How many blobs are stored and their total size in bytes.
+    pub(crate) fn usage(&self) -> (usize, u64) {
+        let state = self.0.lock().unwrap();
+        let size = state.entries.values().map(|e| e.bytes.len() as u64).sum();
+        (state.entries.len(), size)
+    }
+
+    fn release(&self, hash: &WebcHash) {
+        let mut guard = self.0.lock().unwrap();
+        let state = &mut *guard;
+        let Some(entry) = state.entries.get_mut(hash) else {
+            return;
+        };
+
+        entry.refs -= 1;
+        if entry.refs == 0 {
+            state.garbage += entry.bytes.len() as u64;
+            if state.garbage > GC_THRESHOLD {
+                state.collect_garbage();
+            }
+        }
+    }
+}
+
+impl StoreState {
+    fn acquire(&mut self, hash: &WebcHash) {
+        if let Some(entry) = self.entries.get_mut(hash) {
+            if entry.refs == 0 {
+                self.garbage -= entry.bytes.len() as u64;
+            }
+            entry.refs += 1;
+        }
+    }
+
+    fn collect_garbage(&mut self) -> u64 {
+        let before = self.garbage;
+        self.entries.retain(|hash, entry| {
+            if entry.refs == 0 {
+                tracing::trace!(%hash, size = entry.bytes.len(), "Freeing an unused blob");
+            }
+            entry.refs > 0
+        });
+        self.garbage = 0;
+        before
+    }
+}
+
+/// A reference-counted handle to some bytes in a [`BlobStore`].
+pub(crate) struct Blob {
+    hash: WebcHash,
+    bytes: Bytes,
+    store: BlobStore,
+}
+
+impl Blob {
+    pub(crate) fn hash(&self) -> WebcHash {
+        self.hash
+    }
+
+    /// The blob's contents (a cheap, reference-counted copy).
+    pub(crate) fn bytes(&self) -> Bytes {
+        self.bytes.clone()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl std::fmt::Debug for Blob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blob")
+            .field("hash", &self.hash)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+impl Clone for Blob {
+    fn clone(&self) -> Self {
+        self.store.0.lock().unwrap().acquire(&self.hash);
+
+        Blob {
+            hash: self.hash,
+            bytes: self.bytes.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl Drop for Blob {
+    fn drop(&mut self) {
+        self.store.release(&self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn identical_contents_are_stored_once() {
+        let store = BlobStore::default();
+
+        let first = store.insert(Bytes::from(b"python.webc".to_vec()));
+        let second = store.insert(Bytes::from(b"python.webc".to_vec()));
+        let other = store.insert(Bytes::from_static(b"coreutils.webc"));
+
+        assert_eq!(first.hash(), second.hash());
+        assert_eq!(first.bytes().as_ptr(), second.bytes().as_ptr());
+        assert_eq!(store.usage(), (2, 25));
+
+        // Unreferenced blobs stay around until they are collected
+        let hash = other.hash();
+        drop(other);
+        assert_eq!(store.usage(), (2, 25));
+        let revived = store.get(&hash).unwrap();
+        assert_eq!(store.collect_garbage(), 0);
+
+        drop((first, revived));
+        assert_eq!(store.usage(), (2, 25));
+        drop(second.clone());
+        assert_eq!(store.collect_garbage(), 14);
+        assert_eq!(store.usage(), (1, 11));
+        drop(second);
+        store.collect_garbage();
+        assert_eq!(store.usage(), (0, 0));
+    }
+
+    #[wasm_bindgen_test]
+    fn contents_must_match_their_claimed_hash() {
+        let store = BlobStore::default();
+        let python = Bytes::from_static(b"python.webc");
+        let claimed = WebcHash::sha256(b"coreutils.webc");
+
+        assert!(store.insert_with_hash(claimed, python.clone()).is_err());
+        assert_eq!(store.usage(), (0, 0));
+
+        let blob = store
+            .insert_with_hash(WebcHash::sha256(python.as_ref()), python)
+            .unwrap();
+        assert_eq!(store.get(&claimed).map(|b| b.hash()), None);
+        assert_eq!(store.usage(), (1, 11));
+        drop(blob);
+    }
+}
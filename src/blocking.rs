@@ -0,0 +1,234 @@
+//! An opt-in diagnostic mode for finding code that blocks the main thread.
+//!
+//! Browsers don't let the main thread block, so `Atomics.wait()` throws and
+//! anything waiting on another thread can deadlock the page. When the
+//! diagnostics are enabled, every time the SDK is about to do that a warning
+//! is logged and a `"main-thread-blocked"` event (with the call stack) is
+//! emitted on each runtime that lives on the main thread.
+//!
+//! While the diagnostics are enabled, the main thread's `Atomics.wait()` is
+//! wrapped so calls to it get reported too, and the original is put back
+//! when they are disabled. WebAssembly's `memory.atomic.wait32` instructions
+//! don't go through `Atomics`, so those can't be seen.
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use js_sys::{Array, Function, Reflect};
+use wasm_bindgen::{closure::Closure, prelude::wasm_bindgen, JsCast, JsValue};
+use wasmer_wasix::runtime::task_manager::InlineWaker;
+
+use crate::{events::RuntimeEvent, utils::GlobalScope};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+type AtomicsWait = Closure<dyn Fn(JsValue, JsValue, JsValue, JsValue) -> Result<JsValue, JsValue>>;
+
+thread_local! {
+    /// The original `Atomics.wait()` and the wrapper that replaced it.
+    static ATOMICS_WAIT: std::cell::RefCell<Option<(Function, AtomicsWait)>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Report operations which block the main thread.
+///
+/// When enabled, a `"main-thread-blocked"` event is emitted on every
+/// {@link Runtime} created on the main thread whenever the SDK is about to
+/// block it or something calls `Atomics.wait()` there. This is meant for
+/// finding setup problems during development, before they turn into
+/// deadlocks in production.
+///
+/// A WebAssembly module's `memory.atomic.wait32` instructions don't go
+/// through `Atomics.wait()`, so they aren't reported.
+#[wasm_bindgen(js_name = "setBlockingDiagnostics")]
+pub fn set_blocking_diagnostics(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+
+    if !is_main_thread() {
+        return;
+    }
+
+    let result = if enabled {
+        wrap_atomics_wait()
+    } else {
+        unwrap_atomics_wait()
+    };
+    if let Err(e) = result {
+        tracing::warn!(error = ?e, "Unable to instrument Atomics.wait()");
+    }
+}
+
+fn is_main_thread() -> bool {
+    matches!(GlobalScope::current(), GlobalScope::Window(_))
+}
+
+/// Run a future to completion, blocking the current thread.
+///
+/// This behaves like [`InlineWaker::block_on()`], except it reports when it
+/// would block the main thread and the diagnostics are enabled.
+pub(crate) fn block_on<F: Future>(operation: &str, future: F) -> F::Output {
+    if !ENABLED.load(Ordering::Relaxed) || !is_main_thread() {
+        return InlineWaker::block_on(future);
+    }
+
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+        // It finished without needing to wait, so nothing was blocked
+        return value;
+    }
+
+    report(operation);
+    InlineWaker::block_on(future)
+}
+
+fn report(operation: &str) {
+    let stack = Reflect::get(&js_sys::Error::new(""), &JsValue::from_str("stack"))
+        .ok()
+        .and_then(|stack| stack.as_string());
+
+    tracing::warn!(operation, stack, "Blocking the main thread");
+    crate::events::dispatch_on_this_thread(&RuntimeEvent::MainThreadBlocked {
+        operation: operation.to_string(),
+        stack,
+    });
+}
+
+fn atomics() -> Result<JsValue, JsValue> {
+    Reflect::get(&js_sys::global(), &JsValue::from_str("Atomics"))
+}
+
+/// Replace the current thread's `Atomics.wait()` with a wrapper that reports
+/// each call.
+fn wrap_atomics_wait() -> Result<(), JsValue> {
+    if ATOMICS_WAIT.with(|w| w.borrow().is_some()) {
+        return Ok(());
+    }
+
+    let atomics = atomics()?;
+    let original: Function = Reflect::get(&atomics, &JsValue::from_str("wait"))?.dyn_into()?;
+
+    let wrapper = {
+        let original = original.clone();
+        let this = atomics.clone();
+        AtomicsWait::new(move |array, index, value, timeout| {
+            report("Atomics.wait()");
+            let args = Array::of4(&array, &index, &value, &timeout);
+            Reflect::apply(&original, &this, &args)
+        })
+    };
+    Reflect::set(&atomics, &JsValue::from_str("wait"), wrapper.as_ref())?;
+    ATOMICS_WAIT.with(|w| *w.borrow_mut() = Some((original, wrapper)));
+
+    Ok(())
+}
+
+/// Put the original `Atomics.wait()` back.
+fn unwrap_atomics_wait() -> Result<(), JsValue> {
+    let Some((original, wrapper)) = ATOMICS_WAIT.with(|w| w.borrow_mut().take()) else {
+        return Ok(());
+    };
+
+    let atomics = atomics()?;
+    let current = Reflect::get(&atomics, &JsValue::from_str("wait"))?;
+    // Note: leave it alone if somebody else has wrapped it since, otherwise
+    // we'd be throwing away their wrapper
+    if current == *wrapper.as_ref() {
+        Reflect::set(&atomics, &JsValue::from_str("wait"), &original)?;
+        return Ok(());
+    }
+
+    // Their wrapper still calls ours, so it has to stay alive
+    wrapper.forget();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    /// A future which is only ready the second time it is polled.
+    fn yield_once() -> impl Future<Output = u32> {
+        let mut polled = false;
+        std::future::poll_fn(move |_| {
+            if std::mem::replace(&mut polled, true) {
+                Poll::Ready(42)
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    fn blocked_operations(events: &crate::events::EventChannel) -> Rc<RefCell<Vec<String>>> {
+        let operations = Rc::new(RefCell::new(Vec::new()));
+        let listener = {
+            let operations = operations.clone();
+            Closure::<dyn FnMut(web_sys::CustomEvent)>::new(move |e: web_sys::CustomEvent| {
+                let operation = Reflect::get(&e.detail(), &JsValue::from_str("operation"))
+                    .unwrap()
+                    .as_string()
+                    .unwrap();
+                operations.borrow_mut().push(operation);
+            })
+        };
+        events
+            .target()
+            .add_event_listener_with_callback(
+                "main-thread-blocked",
+                listener.as_ref().unchecked_ref(),
+            )
+            .unwrap();
+        listener.forget();
+
+        operations
+    }
+
+    #[wasm_bindgen_test]
+    fn blocking_the_main_thread_is_reported() {
+        let events = crate::events::EventChannel::new();
+        let operations = blocked_operations(&events);
+        set_blocking_diagnostics(true);
+
+        let ready = block_on("ready", async { 1 });
+        let pending = block_on("waiting for a worker", yield_once());
+
+        set_blocking_diagnostics(false);
+        assert_eq!(ready, 1);
+        assert_eq!(pending, 42);
+        // Only the future which had to wait blocked anything
+        assert_eq!(*operations.borrow(), ["waiting for a worker"]);
+    }
+
+    #[wasm_bindgen_test]
+    fn atomics_wait_is_reported_while_enabled() {
+        let events = crate::events::EventChannel::new();
+        let operations = blocked_operations(&events);
+        let wait = || {
+            let atomics = atomics().unwrap();
+            let wait: Function = Reflect::get(&atomics, &JsValue::from_str("wait"))
+                .unwrap()
+                .dyn_into()
+                .unwrap();
+            // Note: waiting on a non-shared array always throws, but only
+            // after the wrapper has seen the call
+            let array = js_sys::Int32Array::new_with_length(1);
+            let args = Array::of4(&array, &0.into(), &0.into(), &0.into());
+            let _ = Reflect::apply(&wait, &atomics, &args);
+        };
+
+        set_blocking_diagnostics(true);
+        wait();
+        set_blocking_diagnostics(false);
+        wait();
+
+        assert_eq!(*operations.borrow(), ["Atomics.wait()"]);
+    }
+}
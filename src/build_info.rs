@@ -0,0 +1,100 @@
+//! Machine-readable details about this build of the SDK, so bug reports and
+//! feature negotiation don't need to rely on the npm version alone.
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::{
+    capabilities::Capability,
+    host_info::HostInfo,
+    utils::{Error, GlobalScope},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BuildInfo {
+    pub(crate) version: &'static str,
+    pub(crate) wasmer_version: &'static str,
+    pub(crate) wasix_version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) git_hash: Option<&'static str>,
+    pub(crate) features: Vec<&'static str>,
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) host_features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub(crate) fn current() -> Self {
+        let features = env!("WASMER_JS_FEATURES")
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .collect();
+
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            wasmer_version: env!("WASMER_JS_WASMER_VERSION"),
+            wasix_version: env!("WASMER_JS_WASIX_VERSION"),
+            git_hash: option_env!("WASMER_JS_GIT_HASH"),
+            features,
+            capabilities: Capability::available(&GlobalScope::current()),
+            host_features: HostInfo::current().features,
+        }
+    }
+
+    pub(crate) fn to_js(&self) -> Result<JsBuildInfo, Error> {
+        let value = serde_wasm_bindgen::to_value(self).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const BUILD_INFO_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Details about this build of the SDK. See {@link Wasmer.buildInfo}.
+ */
+export type BuildInfo = {
+    /** The SDK's version. */
+    version: string;
+    /** The version of the `wasmer` crate the SDK was built with. */
+    wasmerVersion: string;
+    /** The version of the `wasmer-wasix` crate the SDK was built with. */
+    wasixVersion: string;
+    /** The commit the SDK was built from, when known. */
+    gitHash?: string;
+    /** The cargo features the SDK was compiled with. */
+    features: string[];
+    /**
+     * The capabilities this page or worker can provide, whether or not any
+     * runtime has been granted them.
+     */
+    capabilities: CapabilityName[];
+    /**
+     * Features the host supports (`"threads"`, `"jspi"`, `"mobile"`), the
+     * same as `features` in `/proc/host`.
+     */
+    hostFeatures: string[];
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "BuildInfo")]
+    pub type JsBuildInfo;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn build_info_is_reported() {
+        let info = BuildInfo::current();
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(info.wasix_version.starts_with("0."));
+        assert!(info.features.iter().all(|f| !f.is_empty()));
+        assert!(info.to_js().is_ok());
+    }
+}
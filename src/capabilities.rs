@@ -0,0 +1,269 @@
+//! Which host features a runtime's programs are allowed to use.
+//!
+//! Every capability is denied unless it is granted with
+//! `RuntimeOptions.capabilities`, and a granted capability is only available
+//! if the page or worker the runtime lives in can actually provide it (e.g.
+//! `navigator.gpu` doesn't exist everywhere). Attempts to use something that
+//! isn't available are reported with a `"capability-denied"` event.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+use crate::{
+    events::RuntimeEvent,
+    tasks::ThreadPool,
+    utils::{Error, GlobalScope},
+};
+
+/// A host feature that can be granted to a runtime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Capability {
+    /// TCP/UDP via a network gateway, and HTTP requests via `host_fetch`.
+    Network,
+    /// Persistent browser storage.
+    Storage,
+    /// Reading from and writing to the clipboard.
+    Clipboard,
+    /// WebGPU.
+    Gpu,
+    /// Directories on the user's machine.
+    HostFs,
+}
+
+impl Capability {
+    const ALL: [Capability; 5] = [
+        Capability::Network,
+        Capability::Storage,
+        Capability::Clipboard,
+        Capability::Gpu,
+        Capability::HostFs,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Capability::Network => "network",
+            Capability::Storage => "storage",
+            Capability::Clipboard => "clipboard",
+            Capability::Gpu => "gpu",
+            Capability::HostFs => "hostFs",
+        }
+    }
+
+    pub(crate) fn parse(name: &str) -> Result<Self, Error> {
+        Capability::ALL
+            .into_iter()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| {
+                let msg = format!("Unknown capability, \"{name}\"");
+                Error::js(js_sys::TypeError::new(&msg))
+            })
+    }
+
+    /// Every capability the current embedding context can provide.
+    pub(crate) fn available(scope: &GlobalScope) -> Vec<Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(|c| c.is_supported(scope))
+            .collect()
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Can the current embedding context provide this capability at all?
+    fn is_supported(self, scope: &GlobalScope) -> bool {
+        match self {
+            Capability::Network => scope.lookup(&["WebSocket"]).is_some(),
+            Capability::Storage => scope.indexed_db().is_some(),
+            Capability::Clipboard => scope.lookup(&["navigator", "clipboard"]).is_some(),
+            Capability::Gpu => scope.lookup(&["navigator", "gpu"]).is_some(),
+            Capability::HostFs => {
+                scope.lookup(&["showDirectoryPicker"]).is_some()
+                    || scope
+                        .lookup(&["navigator", "storage", "getDirectory"])
+                        .is_some()
+            }
+        }
+    }
+}
+
+/// The set of capabilities available to a runtime.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Capabilities(u8);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Grants {
+    network: Option<bool>,
+    storage: Option<bool>,
+    clipboard: Option<bool>,
+    gpu: Option<bool>,
+    host_fs: Option<bool>,
+}
+
+impl Capabilities {
+    /// No capabilities at all.
+    pub(crate) fn none() -> Self {
+        Capabilities(0)
+    }
+
+    /// Parse `RuntimeOptions.capabilities`, dropping anything the current
+    /// embedding context doesn't support.
+    pub(crate) fn parse(value: JsValue) -> Result<Self, Error> {
+        let grants: Grants = serde_wasm_bindgen::from_value(value).map_err(|e| {
+            let msg = format!("Invalid capabilities: {e}");
+            Error::js(js_sys::TypeError::new(&msg))
+        })?;
+
+        Ok(Capabilities::from_grants(grants).supported_by(&GlobalScope::current()))
+    }
+
+    fn from_grants(grants: Grants) -> Self {
+        let Grants {
+            network,
+            storage,
+            clipboard,
+            gpu,
+            host_fs,
+        } = grants;
+
+        let mut caps = Capabilities::none();
+        for (capability, granted) in [
+            (Capability::Network, network),
+            (Capability::Storage, storage),
+            (Capability::Clipboard, clipboard),
+            (Capability::Gpu, gpu),
+            (Capability::HostFs, host_fs),
+        ] {
+            if granted == Some(true) {
+                caps.0 |= capability.bit();
+            }
+        }
+
+        caps
+    }
+
+    fn supported_by(self, scope: &GlobalScope) -> Self {
+        let mut caps = self;
+
+        for capability in Capability::ALL {
+            if self.has(capability) && !capability.is_supported(scope) {
+                tracing::warn!(
+                    capability = capability.name(),
+                    "The capability was granted, but isn't available in this context",
+                );
+                caps.0 &= !capability.bit();
+            }
+        }
+
+        caps
+    }
+
+    pub(crate) fn has(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Check whether a capability is available, emitting a
+    /// `"capability-denied"` event if it isn't.
+    pub(crate) fn check(
+        self,
+        capability: Capability,
+        pool: &ThreadPool,
+        pid: Option<u32>,
+        detail: Option<String>,
+    ) -> bool {
+        if self.has(capability) {
+            return true;
+        }
+
+        tracing::debug!(capability = capability.name(), ?detail, "Capability denied");
+        pool.emit(RuntimeEvent::CapabilityDenied {
+            capability,
+            pid,
+            detail,
+        });
+        false
+    }
+
+    /// Make sure a capability is available before configuring something that
+    /// needs it.
+    pub(crate) fn require(self, capability: Capability, option: &str) -> Result<(), Error> {
+        if self.has(capability) {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "\"{option}\" requires the \"{name}\" capability (e.g. `capabilities: {{ {name}: true }}`)",
+            name = capability.name(),
+        );
+        Err(Error::js(js_sys::TypeError::new(&msg)))
+    }
+
+    pub(crate) fn to_js(self) -> js_sys::Object {
+        let obj = js_sys::Object::new();
+        for capability in Capability::ALL {
+            let _ = js_sys::Reflect::set(
+                &obj,
+                &JsValue::from_str(capability.name()),
+                &JsValue::from_bool(self.has(capability)),
+            );
+        }
+        obj
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const CAPABILITIES_TYPE_DEFINITION: &'static str = r#"
+/**
+ * A host feature that can be granted to a {@link Runtime}.
+ */
+export type CapabilityName = "network" | "storage" | "clipboard" | "gpu" | "hostFs";
+
+/**
+ * The host features a {@link Runtime}'s programs may use. Anything not set
+ * to `true` is denied.
+ *
+ * - `network` - TCP/UDP through `networkGateway`, and HTTP requests through
+ *   `hostFetch`
+ * - `storage` - persistent browser storage (see
+ *   {@link Runtime.requestPersistentStorage})
+ * - `gpu` - WebGPU, through the experimental `wasmer_gpu` module (see
+ *   `RuntimeOptions.gpuCanvas`)
+ * - `clipboard`, `hostFs` - the clipboard and directories on the user's
+ *   machine, for host extensions which check
+ *   {@link Runtime.checkCapability}
+ *
+ * Capabilities the page or worker can't provide (e.g. `gpu` where
+ * `navigator.gpu` doesn't exist) stay denied even when granted.
+ */
+export type Capabilities = { [name in CapabilityName]?: boolean };
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn capabilities_are_denied_by_default() {
+        let caps = Capabilities::from_grants(Grants {
+            network: Some(true),
+            gpu: Some(false),
+            ..Default::default()
+        });
+
+        assert!(caps.has(Capability::Network));
+        assert!(!caps.has(Capability::Gpu));
+        assert!(!caps.has(Capability::Storage));
+        assert!(Capabilities::none()
+            .require(Capability::Network, "networkGateway")
+            .is_err());
+
+        let value = js_sys::JSON::parse(r#"{"network": true, "microphone": true}"#).unwrap();
+        assert!(Capabilities::parse(value).is_err());
+        assert_eq!(Capability::parse("hostFs").unwrap(), Capability::HostFs);
+    }
+}
@@ -0,0 +1,141 @@
+//! Picking how much to read at a time when streaming a program's output to
+//! JavaScript.
+//!
+//! Interactive programs write a few bytes at a time, and zeroing a large
+//! buffer for every keystroke is wasted work, while bulk transfers (e.g.
+//! `cat`-ing a big file) go much faster with fewer, bigger chunks. Each
+//! stream starts with a small chunk size and doubles it whenever a read
+//! fills the whole buffer and the consumer is keeping up, then halves it
+//! again when reads come back mostly empty.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// The smallest buffer a stream will read into.
+const MIN_CHUNK_SIZE: u32 = 256;
+/// The largest buffer a stream will read into.
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+/// Where every stream starts, which fits a screenful of terminal output.
+const INITIAL_CHUNK_SIZE: u32 = 4 * 1024;
+
+/// Chooses the chunk size for a single stream, keeping track of the sizes it
+/// chose so they can be reported with the rest of a command's usage.
+///
+/// This is shared with the stream, so it uses atomics.
+#[derive(Debug)]
+pub(crate) struct ChunkSizer {
+    current: AtomicU32,
+    smallest: AtomicU32,
+    largest: AtomicU32,
+    chunks: AtomicU64,
+}
+
+impl Default for ChunkSizer {
+    fn default() -> Self {
+        ChunkSizer {
+            current: AtomicU32::new(INITIAL_CHUNK_SIZE),
+            smallest: AtomicU32::new(u32::MAX),
+            largest: AtomicU32::new(0),
+            chunks: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ChunkSizer {
+    /// How many bytes the next read should ask for.
+    pub(crate) fn next(&self) -> u32 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Adjust the chunk size after reading `len` bytes into a buffer of
+    /// `requested` bytes, where `backpressure` means the consumer hasn't
+    /// caught up with the chunks it already has.
+    pub(crate) fn record(&self, requested: u32, len: u32, backpressure: bool) {
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        self.smallest.fetch_min(requested, Ordering::Relaxed);
+        self.largest.fetch_max(requested, Ordering::Relaxed);
+
+        let current = self.current.load(Ordering::Relaxed);
+        let next = next_size(current, requested, len, backpressure);
+        if next != current {
+            tracing::trace!(from = current, to = next, "Changing the chunk size");
+            self.current.store(next, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ChunkSizeSnapshot {
+        let chunks = self.chunks.load(Ordering::Relaxed);
+
+        ChunkSizeSnapshot {
+            current: self.current.load(Ordering::Relaxed),
+            smallest: if chunks == 0 {
+                0
+            } else {
+                self.smallest.load(Ordering::Relaxed)
+            },
+            largest: self.largest.load(Ordering::Relaxed),
+            chunks,
+        }
+    }
+}
+
+fn next_size(current: u32, requested: u32, len: u32, backpressure: bool) -> u32 {
+    if len >= requested {
+        // Note: growing while the consumer is behind would only make its
+        // backlog bigger
+        if backpressure {
+            current
+        } else {
+            current.saturating_mul(2).min(MAX_CHUNK_SIZE)
+        }
+    } else if len.saturating_mul(4) <= current {
+        (current / 2).max(MIN_CHUNK_SIZE)
+    } else {
+        current
+    }
+}
+
+/// A point-in-time copy of a [`ChunkSizer`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChunkSizeSnapshot {
+    pub(crate) current: u32,
+    pub(crate) smallest: u32,
+    pub(crate) largest: u32,
+    pub(crate) chunks: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn grow_for_bulk_transfers_and_shrink_for_interactive_output() {
+        let sizer = ChunkSizer::default();
+
+        // A program dumping a big file fills every buffer
+        for _ in 0..20 {
+            let size = sizer.next();
+            sizer.record(size, size, false);
+        }
+        assert_eq!(sizer.next(), MAX_CHUNK_SIZE);
+
+        // Someone typing at a shell only produces a few bytes at a time
+        for _ in 0..20 {
+            sizer.record(sizer.next(), 3, false);
+        }
+        assert_eq!(sizer.next(), MIN_CHUNK_SIZE);
+
+        // A slow consumer stops the chunks from growing
+        sizer.record(MIN_CHUNK_SIZE, MIN_CHUNK_SIZE, true);
+        assert_eq!(sizer.next(), MIN_CHUNK_SIZE);
+
+        let snapshot = sizer.snapshot();
+        assert_eq!(snapshot.smallest, MIN_CHUNK_SIZE);
+        assert_eq!(snapshot.largest, MAX_CHUNK_SIZE);
+        assert_eq!(snapshot.chunks, 41);
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+
+use futures::channel::oneshot;
+use http::{HeaderMap, HeaderValue, Method};
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasmer_wasix::{
+    http::{DynHttpClient, HttpRequest},
+    runtime::{module_cache::ModuleHash, resolver::WebcHash},
+    WasiRuntimeError,
+};
+
+use crate::{options::CommonOptions, utils::Error};
+
+const DEFAULT_LOG_LINES: usize = 50;
+const REDACTED: &str = "[redacted]";
+
+/// Details about a crash, captured on the thread that was running the guest.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Crash {
+    error: String,
+    backtrace: Vec<String>,
+}
+
+impl Crash {
+    /// Inspect the result of running a program, returning `None` if it
+    /// exited normally (including calling `exit()` with a non-zero code).
+    pub(crate) fn from_result(result: &Result<(), anyhow::Error>) -> Option<Crash> {
+        let err = result.as_ref().err()?;
+
+        let runtime_error = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<WasiRuntimeError>());
+
+        if runtime_error.and_then(|e| e.as_exit_code()).is_some() {
+            return None;
+        }
+
+        let backtrace = match runtime_error {
+            Some(WasiRuntimeError::Runtime(trap)) => trap
+                .trace()
+                .iter()
+                .map(|frame| {
+                    let function = frame
+                        .function_name()
+                        .map(String::from)
+                        .unwrap_or_else(|| format!("<func {}>", frame.func_index()));
+                    format!(
+                        "{}!{function} @ {:#x}",
+                        frame.module_name(),
+                        frame.module_offset()
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(Crash {
+            error: format!("{err:?}"),
+            backtrace,
+        })
+    }
+}
+
+/// The thread-safe half of a [`CrashReporter`], used by whoever is running
+/// the program to say whether it crashed.
+#[derive(Debug, Default)]
+pub(crate) struct CrashSender(Option<oneshot::Sender<Crash>>);
+
+impl CrashSender {
+    pub(crate) fn notify(self, result: &Result<(), anyhow::Error>) {
+        if let (Some(sender), Some(crash)) = (self.0, Crash::from_result(result)) {
+            let _ = sender.send(crash);
+        }
+    }
+}
+
+/// Turns a [`Crash`] into a [`CrashReport`] and delivers it to the
+/// destinations from [`CrashReportOptions`].
+#[derive(Debug)]
+pub(crate) struct CrashReporter {
+    options: CrashReportOptions,
+    context: CrashContext,
+    http_client: Option<DynHttpClient>,
+}
+
+/// Everything we know about the program before it starts.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct CrashContext {
+    pub(crate) module_hash: Option<ModuleHash>,
+    pub(crate) package: Option<String>,
+    pub(crate) command: Option<String>,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) mounts: Vec<String>,
+}
+
+impl CrashContext {
+    /// Read the arguments, environment, and mounts from the options the
+    /// program was started with.
+    pub(crate) fn from_options(options: &CommonOptions) -> Result<Self, Error> {
+        Ok(CrashContext {
+            args: options.parse_args()?,
+            env: options.parse_env()?,
+            mounts: options.mount_points(),
+            ..Default::default()
+        })
+    }
+
+    /// A hash of the program's configuration, so crashes with the same
+    /// setup can be grouped without sending the setup itself.
+    ///
+    /// This is the SHA-256 of a length-prefixed encoding of the
+    /// configuration, so it stays the same across sessions and SDK releases.
+    fn config_hash(&self) -> String {
+        let mut buffer = Vec::new();
+        let mut write = |values: Vec<&str>| {
+            buffer.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                buffer.extend_from_slice(value.as_bytes());
+            }
+        };
+        write(self.command.iter().map(String::as_str).collect());
+        write(self.args.iter().map(String::as_str).collect());
+        write(
+            self.env
+                .iter()
+                .flat_map(|(key, value)| [key.as_str(), value.as_str()])
+                .collect(),
+        );
+        write(self.mounts.iter().map(String::as_str).collect());
+
+        WebcHash::sha256(&buffer).to_string()
+    }
+}
+
+impl CrashReporter {
+    /// Create a reporter if crash reporting was requested.
+    ///
+    /// The [`CrashContext`] is only gathered when it will be used.
+    pub(crate) fn new(
+        options: &CommonOptions,
+        context: impl FnOnce() -> Result<CrashContext, Error>,
+        http_client: Option<&DynHttpClient>,
+    ) -> Result<Option<Self>, Error> {
+        let options = options.crash_report();
+        if !options.is_object() {
+            return Ok(None);
+        }
+
+        Ok(Some(CrashReporter {
+            options: options.unchecked_into(),
+            context: context()?,
+            http_client: http_client.cloned(),
+        }))
+    }
+
+    /// Wait for the program to crash in the background, returning the
+    /// [`CrashSender`] it should be notified through.
+    pub(crate) fn spawn(reporter: Option<Self>) -> CrashSender {
+        let Some(reporter) = reporter else {
+            return CrashSender::default();
+        };
+
+        let (sender, receiver) = oneshot::channel();
+
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Ok(crash) = receiver.await {
+                if let Err(e) = reporter.deliver(crash).await {
+                    tracing::warn!(
+                        error = &*e.into_anyhow(),
+                        "Unable to deliver a crash report"
+                    );
+                }
+            }
+        });
+
+        CrashSender(Some(sender))
+    }
+
+    fn report(&self, crash: Crash) -> CrashReport {
+        let CrashContext {
+            module_hash,
+            package,
+            command,
+            args,
+            env,
+            mounts: _,
+        } = &self.context;
+
+        let include_args = self.options.include_args().unwrap_or(false);
+        let include_env = self.options.include_env().unwrap_or(false);
+        let log_lines = self
+            .options
+            .log_lines()
+            .map(|n| n as usize)
+            .unwrap_or(DEFAULT_LOG_LINES);
+
+        // Anything we weren't allowed to include shouldn't sneak in through
+        // the logs or error message either.
+        let mut secrets: Vec<&str> = Vec::new();
+        if !include_args {
+            secrets.extend(args.iter().map(String::as_str));
+        }
+        if !include_env {
+            secrets.extend(env.values().map(String::as_str));
+        }
+        let scrub = |text: &str| redact(text, &secrets);
+
+        CrashReport {
+            module_hash: module_hash.as_ref().map(|h| h.to_string()),
+            package: package.clone(),
+            command: command.clone(),
+            sdk_version: env!("CARGO_PKG_VERSION"),
+            config_hash: self.context.config_hash(),
+            error: scrub(&crash.error),
+            backtrace: crash.backtrace,
+            logs: crate::logging::recent_logs(log_lines)
+                .iter()
+                .map(|line| scrub(line))
+                .collect(),
+            args: include_args.then(|| args.clone()),
+            env: env
+                .iter()
+                .map(|(key, value)| {
+                    let value = if include_env {
+                        value.as_str()
+                    } else {
+                        REDACTED
+                    };
+                    (key.clone(), value.to_string())
+                })
+                .collect(),
+        }
+    }
+
+    async fn deliver(self, crash: Crash) -> Result<(), Error> {
+        let report = self.report(crash);
+        let mut value = report
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::js)?;
+
+        if let Some(scrub) = self.options.scrub() {
+            let scrubbed = scrub.call1(&JsValue::NULL, &value).map_err(Error::js)?;
+            if scrubbed.is_null() {
+                tracing::debug!("The crash report was dropped");
+                return Ok(());
+            } else if !scrubbed.is_undefined() {
+                value = scrubbed;
+            }
+        }
+
+        if let Some(on_crash) = self.options.on_crash() {
+            on_crash.call1(&JsValue::NULL, &value).map_err(Error::js)?;
+        }
+
+        if let Some(endpoint) = self.options.endpoint() {
+            let client = self
+                .http_client
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No HTTP client available"))?;
+            let body = js_sys::JSON::stringify(&value).map_err(Error::js)?;
+
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+            let request = HttpRequest {
+                url: endpoint.parse()?,
+                method: Method::POST,
+                headers,
+                body: Some(String::from(body).into_bytes()),
+                options: Default::default(),
+            };
+
+            let response = client.request(request).await?;
+            if !response.is_ok() {
+                return Err(crate::package_loader::http_error(&response)
+                    .context(format!("Posting the crash report to \"{endpoint}\" failed"))
+                    .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn redact(text: &str, secrets: &[&str]) -> String {
+    let mut text = text.to_string();
+
+    // Note: really short values would redact half the report and are
+    // unlikely to be sensitive.
+    for secret in secrets.iter().filter(|s| s.len() >= 4) {
+        text = text.replace(secret, REDACTED);
+    }
+
+    text
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CrashReport {
+    module_hash: Option<String>,
+    package: Option<String>,
+    command: Option<String>,
+    sdk_version: &'static str,
+    config_hash: String,
+    error: String,
+    backtrace: Vec<String>,
+    logs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Vec<String>>,
+    env: BTreeMap<String, String>,
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const CRASH_REPORT_TYPE_DECLARATIONS: &str = r#"
+/**
+ * Details about a program that trapped or otherwise exited abnormally.
+ */
+export type CrashReport = {
+    /** A hash of the WebAssembly module, if known. */
+    moduleHash: string | null;
+    /** The package the program came from (e.g. `"wasmer/python@3.12.0"`). */
+    package: string | null;
+    /** The command that was being run. */
+    command: string | null;
+    /** The version of `@wasmer/sdk`. */
+    sdkVersion: string;
+    /**
+     * A SHA-256 hash of the program's arguments, environment variables, and
+     * mounts, for grouping similar crashes. It doesn't change between
+     * sessions or SDK releases.
+     */
+    configHash: string;
+    /** The error that caused the crash. */
+    error: string;
+    /** The WebAssembly stack at the time of the crash, if it trapped. */
+    backtrace: string[];
+    /**
+     * The most recent log lines. These are only recorded after
+     * {@link initializeLogger} has been called.
+     */
+    logs: string[];
+    /** The program's arguments, if {@link CrashReportOptions.includeArgs} is set. */
+    args?: string[];
+    /**
+     * The program's environment variables. Values are redacted unless
+     * {@link CrashReportOptions.includeEnv} is set.
+     */
+    env: Record<string, string>;
+};
+
+/**
+ * Opt in to crash reports when a program traps or exits abnormally.
+ *
+ * Arguments and environment variable values are left out (and scrubbed from
+ * the logs and error message) by default.
+ */
+export type CrashReportOptions = {
+    /** Called with each crash report. */
+    onCrash?: (report: CrashReport) => void;
+    /** A URL that each crash report will be POSTed to as JSON. */
+    endpoint?: string;
+    /** How many recent log lines to include. Defaults to 50. */
+    logLines?: number;
+    /** Include the program's arguments. */
+    includeArgs?: boolean;
+    /** Include environment variable values. */
+    includeEnv?: boolean;
+    /**
+     * A final chance to scrub the report before it is delivered.
+     *
+     * Return a replacement report, `undefined` to keep the (possibly
+     * mutated) original, or `null` to drop it entirely.
+     */
+    scrub?: (report: CrashReport) => CrashReport | null | undefined;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "CrashReportOptions", extends = js_sys::Object)]
+    #[derive(Debug)]
+    pub type CrashReportOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "onCrash")]
+    fn on_crash(this: &CrashReportOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(method, getter)]
+    fn endpoint(this: &CrashReportOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "logLines")]
+    fn log_lines(this: &CrashReportOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "includeArgs")]
+    fn include_args(this: &CrashReportOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter, js_name = "includeEnv")]
+    fn include_env(this: &CrashReportOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter)]
+    fn scrub(this: &CrashReportOptions) -> Option<js_sys::Function>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use wasmer_wasix::{types::wasi::ExitCode, WasiError};
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn calling_exit_is_not_a_crash() {
+        let exit: Result<(), anyhow::Error> =
+            Err(WasiRuntimeError::Wasi(WasiError::Exit(ExitCode::Other(1))).into());
+        let panic: Result<(), anyhow::Error> = Err(anyhow::anyhow!("unreachable"));
+
+        assert_eq!(Crash::from_result(&Ok(())), None);
+        assert_eq!(Crash::from_result(&exit), None);
+        assert!(Crash::from_result(&panic).is_some());
+    }
+
+    #[wasm_bindgen_test]
+    fn secrets_are_scrubbed_from_reports() {
+        let options = js_sys::Object::new();
+        let reporter = CrashReporter {
+            options: options.unchecked_into(),
+            context: CrashContext {
+                args: vec!["--token=hunter2".to_string()],
+                env: [("API_KEY".to_string(), "s3cr3t".to_string())].into(),
+                ..Default::default()
+            },
+            http_client: None,
+        };
+        let crash = Crash {
+            error: "failed to authenticate with s3cr3t".to_string(),
+            backtrace: Vec::new(),
+        };
+
+        let report = reporter.report(crash);
+
+        assert_eq!(report.error, "failed to authenticate with [redacted]");
+        assert_eq!(report.args, None);
+        assert_eq!(report.env["API_KEY"], REDACTED);
+    }
+
+    #[wasm_bindgen_test]
+    fn config_hashes_are_stable() {
+        let context = CrashContext {
+            command: Some("python".to_string()),
+            args: vec!["-c".to_string(), "print(1)".to_string()],
+            env: [("HOME".to_string(), "/root".to_string())].into(),
+            mounts: vec!["/data".to_string()],
+            ..Default::default()
+        };
+        let joined = CrashContext {
+            args: vec!["-c print(1)".to_string()],
+            ..context.clone()
+        };
+
+        // Note: this is the SHA-256 of the encoded configuration, so it must
+        // never change between releases
+        assert!(context.config_hash().eq_ignore_ascii_case(
+            "5e97dc2548438f3da9dacca40caeb346c99164c311bb761de1a70869f01c4d47"
+        ));
+        assert_ne!(context.config_hash(), joined.config_hash());
+    }
+}
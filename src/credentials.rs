@@ -0,0 +1,498 @@
+//! Answering guests' credential prompts from the host.
+//!
+//! With `onCredentialRequest`, programs which speak git's [credential helper
+//! protocol][protocol] have their requests routed to the host instead of
+//! prompting on the terminal. Every instance gets a `/dev/credentials`
+//! directory with one device per action:
+//!
+//! - Writing `key=value` lines (the request) to `get` and then reading from
+//!   the same handle waits for the callback, and returns its answer in the
+//!   same format
+//! - `store` and `erase` tell the host that a credential worked or was
+//!   rejected, and reads return nothing
+//!
+//! Programs are also given `GIT_CONFIG_*` variables which make git use these
+//! devices as its `credential.helper`. Like any other helper, git runs it
+//! with `sh`, so a shell needs to be available.
+//!
+//! [protocol]: https://git-scm.com/docs/git-credential#IOFMT
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{channel::oneshot, future::BoxFuture};
+use js_sys::{Function, Promise};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::VirtualFile;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Opener},
+    tasks::ThreadPool,
+};
+
+/// Where the credential devices get mounted.
+pub(crate) const MOUNT_POINT: &str = "/dev/credentials";
+
+/// Requests are only a handful of short lines, so anything bigger is
+/// probably a program writing to the wrong file.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Teaches git to use the devices as its credential helper. Git appends the
+/// action (`get`, `store` or `erase`) as the first argument.
+const GIT_HELPER: &str = r#"!f() { exec 3<>/dev/credentials/"$1" && cat >&3 && cat <&3; }; f"#;
+
+thread_local! {
+    /// `onCredentialRequest` callbacks, which live on the scheduler's thread.
+    static CALLBACKS: RefCell<BTreeMap<u32, Function>> = RefCell::default();
+}
+
+/// Would mounting the credential devices clash with something the user
+/// mounted at `mount_point`?
+pub(crate) fn conflicts_with(mount_point: &str) -> bool {
+    crate::locale::conflicts_with(MOUNT_POINT, mount_point)
+}
+
+/// What a program wants done with a credential.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Action {
+    /// Find a username and password.
+    Get,
+    /// The credential worked, so it can be saved.
+    Store,
+    /// The credential was rejected, so it should be forgotten.
+    Erase,
+}
+
+impl Action {
+    const ALL: [Action; 3] = [Action::Get, Action::Store, Action::Erase];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Get => "get",
+            Action::Store => "store",
+            Action::Erase => "erase",
+        }
+    }
+}
+
+/// Routes credential requests to the host's `onCredentialRequest` callback.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CredentialHelper(Arc<Mutex<Option<u32>>>);
+
+impl CredentialHelper {
+    /// Answer every credential request with `callback`.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn set_callback(&self, callback: Function) {
+        static NEXT_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let previous = self.0.lock().unwrap().replace(id);
+        CALLBACKS.with(|callbacks| {
+            let mut callbacks = callbacks.borrow_mut();
+            if let Some(previous) = previous {
+                callbacks.remove(&previous);
+            }
+            callbacks.insert(id, callback);
+        });
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Point git at the devices by adding to the `GIT_CONFIG_*` variables
+    /// in `env`, after any the user already set.
+    pub(crate) fn add_git_config(&self, env: &mut BTreeMap<String, String>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let count = match env.get("GIT_CONFIG_COUNT") {
+            None => 0,
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => {
+                    // Note: git refuses to start with a bad count anyway
+                    tracing::warn!(%count, "Not adding a credential helper to an invalid GIT_CONFIG_COUNT");
+                    return;
+                }
+            },
+        };
+
+        env.insert(
+            format!("GIT_CONFIG_KEY_{count}"),
+            "credential.helper".to_string(),
+        );
+        env.insert(format!("GIT_CONFIG_VALUE_{count}"), GIT_HELPER.to_string());
+        env.insert("GIT_CONFIG_COUNT".to_string(), (count + 1).to_string());
+    }
+
+    /// A directory containing a device for each action, ready to be mounted
+    /// at [`MOUNT_POINT`].
+    pub(crate) fn filesystem(&self, pool: &ThreadPool) -> DeviceFileSystem {
+        let fs = DeviceFileSystem::default();
+
+        for action in Action::ALL {
+            let helper = self.clone();
+            let pool = pool.clone();
+            let opener = Opener::new(move || {
+                Box::new(CredentialFile::new(action, helper.clone(), pool.clone()))
+            });
+            fs.insert(action.as_str(), Device::Opener(opener));
+        }
+
+        fs
+    }
+
+    /// Pass a request to the callback, resolving to its answer.
+    ///
+    /// The callback is called straight away, even if the answer is never
+    /// awaited.
+    fn request(
+        &self,
+        action: Action,
+        fields: Vec<(String, String)>,
+        pool: &ThreadPool,
+    ) -> oneshot::Receiver<Vec<(String, String)>> {
+        let (sender, receiver) = oneshot::channel();
+        let Some(callback) = *self.0.lock().unwrap() else {
+            let _ = sender.send(Vec::new());
+            return receiver;
+        };
+
+        pool.run_on_scheduler(Box::new(move || {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = sender.send(ask(callback, action, &fields).await);
+            });
+        }));
+
+        receiver
+    }
+}
+
+/// Call an `onCredentialRequest` callback, treating errors as not knowing
+/// the answer.
+async fn ask(callback: u32, action: Action, fields: &[(String, String)]) -> Vec<(String, String)> {
+    let Some(callback) = CALLBACKS.with(|callbacks| callbacks.borrow().get(&callback).cloned())
+    else {
+        return Vec::new();
+    };
+
+    let request = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&request, &"action".into(), &action.as_str().into());
+    for (key, value) in fields {
+        let _ = js_sys::Reflect::set(&request, &key.into(), &value.into());
+    }
+
+    let outcome = match callback.call1(&JsValue::NULL, &request) {
+        Ok(value) => match value.dyn_into::<Promise>() {
+            Ok(promise) => JsFuture::from(promise).await,
+            Err(value) => Ok(value),
+        },
+        Err(e) => Err(e),
+    };
+
+    match outcome.and_then(parse_answer) {
+        Ok(fields) => fields,
+        Err(e) => {
+            tracing::warn!(error = ?e, action = action.as_str(), "The onCredentialRequest callback failed");
+            Vec::new()
+        }
+    }
+}
+
+/// Read the string fields out of whatever the callback returned, where
+/// `null` or `undefined` means it doesn't know.
+fn parse_answer(value: JsValue) -> Result<Vec<(String, String)>, JsValue> {
+    if value.is_null() || value.is_undefined() {
+        return Ok(Vec::new());
+    }
+    let Some(obj) = value.dyn_ref::<js_sys::Object>() else {
+        return Err(js_sys::TypeError::new("Expected an object of credential fields").into());
+    };
+
+    let mut fields = Vec::new();
+    for (key, value) in crate::utils::object_entries(obj).map_err(JsValue::from)? {
+        if value.is_undefined() {
+            continue;
+        }
+        let key: String = key.into();
+        let Some(value) = value.as_string() else {
+            let msg = format!("The \"{key}\" credential field should be a string");
+            return Err(js_sys::TypeError::new(&msg).into());
+        };
+        fields.push((key, value));
+    }
+
+    Ok(fields)
+}
+
+/// Parse the `key=value` lines of a request, stopping at the first blank
+/// line.
+fn parse_request(raw: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(raw)
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Format an answer as `key=value` lines, dropping any field which would
+/// change the meaning of the others (e.g. a password with a newline in it).
+fn render_answer(fields: &[(String, String)]) -> Vec<u8> {
+    let mut rendered = String::new();
+
+    for (key, value) in fields {
+        let valid_key = !key.is_empty() && !key.contains(['=', '\n', '\0']);
+        if !valid_key || value.contains(['\n', '\0']) {
+            tracing::warn!(key = %key, "Ignoring a credential field which can't be passed on");
+            continue;
+        }
+        rendered.push_str(key);
+        rendered.push('=');
+        rendered.push_str(value);
+        rendered.push('\n');
+    }
+
+    rendered.into_bytes()
+}
+
+/// A single request, created whenever one of the devices is opened.
+#[derive(Debug)]
+struct CredentialFile {
+    action: Action,
+    helper: CredentialHelper,
+    pool: ThreadPool,
+    request: Vec<u8>,
+    pending: Option<oneshot::Receiver<Vec<(String, String)>>>,
+    /// The part of the answer that hasn't been read yet, once there is one.
+    unread: Option<Vec<u8>>,
+}
+
+impl CredentialFile {
+    fn new(action: Action, helper: CredentialHelper, pool: ThreadPool) -> Self {
+        CredentialFile {
+            action,
+            helper,
+            pool,
+            request: Vec::new(),
+            pending: None,
+            unread: None,
+        }
+    }
+
+    fn send(&mut self) -> oneshot::Receiver<Vec<(String, String)>> {
+        let fields = parse_request(&self.request);
+        tracing::debug!(
+            action = self.action.as_str(),
+            keys = ?fields.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            "Asking the host for credentials",
+        );
+        self.helper.request(self.action, fields, &self.pool)
+    }
+}
+
+impl Drop for CredentialFile {
+    fn drop(&mut self) {
+        // Note: a program which doesn't care about the answer might never
+        // read, but the host should still hear about it
+        let unsent = self.pending.is_none() && self.unread.is_none();
+        if unsent && self.action != Action::Get && !self.request.is_empty() {
+            drop(self.send());
+        }
+    }
+}
+
+impl VirtualFile for CredentialFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(virtual_fs::FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Err(virtual_fs::FsError::PermissionDenied) })
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(1))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(1))
+    }
+}
+
+impl AsyncRead for CredentialFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.unread.is_none() {
+            if self.pending.is_none() {
+                self.pending = Some(self.send());
+            }
+            let pending = self.pending.as_mut().expect("just set");
+            let fields = futures::ready!(Pin::new(pending).poll(cx)).unwrap_or_default();
+            self.pending = None;
+
+            let answer = match self.action {
+                Action::Get => render_answer(&fields),
+                Action::Store | Action::Erase => Vec::new(),
+            };
+            self.unread = Some(answer);
+        }
+
+        let unread = self.unread.as_mut().expect("answered");
+        let n = unread.len().min(buf.remaining());
+        buf.put_slice(&unread[..n]);
+        unread.drain(..n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for CredentialFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_some() || self.unread.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The request has already been sent",
+            )));
+        }
+        if self.request.len() + buf.len() > MAX_REQUEST_SIZE {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Credential requests are limited to 64 KiB",
+            )));
+        }
+
+        self.request.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for CredentialFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Credential requests can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const CREDENTIAL_TYPE_DEFS: &'static str = r#"
+/**
+ * A program asking for credentials, passed to
+ * {@link RuntimeOptions.onCredentialRequest}.
+ *
+ * The fields are the ones from git's credential helper protocol. `"get"`
+ * asks for a username and password, while `"store"` and `"erase"` report
+ * that a credential was accepted or rejected (their answer is ignored).
+ */
+export type CredentialRequest = {
+    action: "get" | "store" | "erase";
+    protocol?: string;
+    host?: string;
+    path?: string;
+    username?: string;
+    password?: string;
+    [field: string]: string | undefined;
+};
+
+/**
+ * The answer to a {@link CredentialRequest}, where `null` means the host
+ * doesn't know and the program should fall back to asking the user.
+ */
+export type CredentialResponse =
+    | { username?: string; password?: string; [field: string]: string | undefined }
+    | null
+    | undefined;
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn answers_cant_smuggle_extra_fields() {
+        let request = parse_request(b"protocol=https\nhost=example.com\n\nignored=true\n");
+        assert_eq!(
+            request,
+            [
+                ("protocol".to_string(), "https".to_string()),
+                ("host".to_string(), "example.com".to_string()),
+            ]
+        );
+
+        let answer = [
+            ("username".to_string(), "me".to_string()),
+            ("password".to_string(), "secret\nquit=1".to_string()),
+            ("a=b".to_string(), "c".to_string()),
+        ];
+        assert_eq!(render_answer(&answer), b"username=me\n");
+    }
+
+    #[wasm_bindgen_test]
+    fn the_helper_is_added_after_the_users_git_config() {
+        let helper = CredentialHelper::default();
+        *helper.0.lock().unwrap() = Some(42);
+        let mut env = BTreeMap::from([
+            ("GIT_CONFIG_COUNT".to_string(), "1".to_string()),
+            ("GIT_CONFIG_KEY_0".to_string(), "user.name".to_string()),
+            ("GIT_CONFIG_VALUE_0".to_string(), "Me".to_string()),
+        ]);
+
+        helper.add_git_config(&mut env);
+
+        assert_eq!(env["GIT_CONFIG_COUNT"], "2");
+        assert_eq!(env["GIT_CONFIG_KEY_0"], "user.name");
+        assert_eq!(env["GIT_CONFIG_KEY_1"], "credential.helper");
+        assert_eq!(env["GIT_CONFIG_VALUE_1"], GIT_HELPER);
+    }
+}
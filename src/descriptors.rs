@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use virtual_fs::AsyncWriteExt;
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasmer_wasix::{
+    fs::{Fd, Kind},
+    types::wasi::Fdflags,
+};
+
+use crate::{fs::DetachedFile, utils::Error};
+
+/// A shared handle to a running instance's file descriptor table.
+///
+/// The table only exists once the worker thread has instantiated the
+/// WebAssembly module, so it starts off empty and gets populated by
+/// [`DescriptorTable::attach()`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DescriptorTable(Arc<Mutex<Option<FdMap>>>);
+
+type FdMap = Arc<RwLock<HashMap<u32, Fd>>>;
+
+impl DescriptorTable {
+    /// Give the table access to the instance's file descriptors.
+    pub(crate) fn attach(&self, fds: FdMap) {
+        *self.0.lock().unwrap() = Some(fds);
+    }
+
+    fn fds(&self) -> Result<FdMap, Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The instance hasn't started yet").into())
+    }
+
+    /// Describe every open file descriptor.
+    pub(crate) fn list(&self) -> Result<Vec<DescriptorInfo>, Error> {
+        let fds = self.fds()?;
+        let fds = fds.read().unwrap();
+
+        let mut descriptors: Vec<_> = fds
+            .iter()
+            .map(|(&fd, entry)| DescriptorInfo::new(fd, entry))
+            .collect();
+        descriptors.sort_by_key(|d| d.fd);
+
+        Ok(descriptors)
+    }
+
+    /// Close a file descriptor on the guest's behalf.
+    ///
+    /// Whatever the descriptor refers to is closed first, so buffered writes
+    /// aren't lost and anything reading from a pipe sees EOF.
+    pub(crate) fn close(&self, fd: u32) -> Result<(), Error> {
+        let fds = self.fds()?;
+        let mut fds = fds.write().unwrap();
+        let entry = fds
+            .get(&fd)
+            .ok_or_else(|| anyhow::anyhow!("File descriptor {fd} isn't open"))?;
+
+        close_entry(entry).with_context(|| format!("Unable to close file descriptor {fd}"))?;
+        fds.remove(&fd);
+
+        Ok(())
+    }
+
+    /// Replace the file backing a descriptor, so any future reads and writes
+    /// from the guest go to `file` instead.
+    pub(crate) fn redirect(
+        &self,
+        fd: u32,
+        file: Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>,
+    ) -> Result<(), Error> {
+        let fds = self.fds()?;
+        let fds = fds.read().unwrap();
+        let entry = fds
+            .get(&fd)
+            .ok_or_else(|| anyhow::anyhow!("File descriptor {fd} isn't open"))?;
+
+        let mut guard = entry.inode.write();
+        match &mut *guard {
+            Kind::File {
+                handle: Some(handle),
+                ..
+            } => {
+                *handle.write().unwrap() = file;
+                entry.offset.store(0, Ordering::SeqCst);
+                Ok(())
+            }
+            other => Err(anyhow::anyhow!(
+                "File descriptor {fd} refers to a {}, but only files can be redirected",
+                kind_name(other),
+            )
+            .into()),
+        }
+    }
+}
+
+impl DescriptorTable {
+    /// Make every file opened under `dir` fail with `EIO` from now on.
+    ///
+    /// This does nothing if the instance hasn't started yet.
+    pub(crate) fn invalidate_under(&self, dir: &Path) {
+        let Ok(fds) = self.fds() else {
+            return;
+        };
+        let fds = fds.read().unwrap();
+
+        for (fd, entry) in fds.iter() {
+            let mut guard = entry.inode.write();
+            if let Kind::File {
+                handle: Some(handle),
+                path,
+                ..
+            } = &mut *guard
+            {
+                if path.starts_with(dir) {
+                    tracing::debug!(fd, path = %path.display(), "Invalidating a file under an unmounted directory");
+                    *handle.write().unwrap() = Box::new(DetachedFile);
+                }
+            }
+        }
+    }
+}
+
+/// Information about an open file descriptor.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DescriptorInfo {
+    fd: u32,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    offset: u64,
+    flags: Vec<&'static str>,
+    is_stdio: bool,
+}
+
+impl DescriptorInfo {
+    fn new(fd: u32, entry: &Fd) -> Self {
+        let guard = entry.inode.read();
+        let path = match &*guard {
+            Kind::File { path, .. } | Kind::Dir { path, .. } => Some(path.display().to_string()),
+            _ => None,
+        };
+
+        DescriptorInfo {
+            fd,
+            kind: kind_name(&guard),
+            path,
+            offset: entry.offset.load(Ordering::SeqCst),
+            flags: flag_names(entry.flags),
+            is_stdio: entry.is_stdio,
+        }
+    }
+}
+
+/// Close the file or pipe behind a descriptor, the way the guest's own
+/// `fd_close()` would.
+///
+/// Sockets and the rest are closed when the last descriptor referring to
+/// them is dropped.
+fn close_entry(entry: &Fd) -> Result<(), std::io::Error> {
+    let mut guard = entry.inode.write();
+    match &mut *guard {
+        Kind::File {
+            handle: Some(handle),
+            ..
+        } => {
+            let mut handle = handle.write().unwrap();
+            crate::blocking::block_on("flushing a file descriptor", handle.flush())
+        }
+        Kind::Pipe { pipe } => {
+            pipe.close();
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn kind_name(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::File { .. } => "file",
+        Kind::Dir { .. } | Kind::Root { .. } => "dir",
+        Kind::Socket { .. } => "socket",
+        Kind::Pipe { .. } => "pipe",
+        Kind::Symlink { .. } => "symlink",
+        Kind::Buffer { .. } => "buffer",
+        Kind::EventNotifications { .. } => "event",
+    }
+}
+
+fn flag_names(flags: Fdflags) -> Vec<&'static str> {
+    [
+        (Fdflags::APPEND, "append"),
+        (Fdflags::DSYNC, "dsync"),
+        (Fdflags::NONBLOCK, "nonblock"),
+        (Fdflags::RSYNC, "rsync"),
+        (Fdflags::SYNC, "sync"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| name)
+    .collect()
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const DESCRIPTOR_INFO_TYPE_DECLARATION: &str = r#"
+/**
+ * Information about a file descriptor held open by a running {@link Instance}.
+ */
+export type DescriptorInfo = {
+    /** The file descriptor number. */
+    fd: number;
+    /** What the descriptor refers to. */
+    kind: "file" | "dir" | "socket" | "pipe" | "symlink" | "buffer" | "event";
+    /** The path the descriptor was opened with, for files and directories. */
+    path?: string;
+    /** The current read/write offset. */
+    offset: number;
+    /** Any flags set on the descriptor (e.g. via `fd_fdstat_set_flags()`). */
+    flags: Array<"append" | "dsync" | "nonblock" | "rsync" | "sync">;
+    /** Is this one of stdin, stdout, or stderr? */
+    isStdio: boolean;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "DescriptorInfo[]")]
+    pub type ListOfDescriptorInfo;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn tables_are_unavailable_until_attached() {
+        let table = DescriptorTable::default();
+
+        let err = table.list().unwrap_err();
+
+        assert_eq!(err.to_string(), "The instance hasn't started yet");
+    }
+
+    #[wasm_bindgen_test]
+    fn list_an_empty_table() {
+        let table = DescriptorTable::default();
+        table.attach(FdMap::default());
+
+        assert!(table.list().unwrap().is_empty());
+        assert!(table.close(3).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn flags_are_given_readable_names() {
+        let names = flag_names(Fdflags::APPEND | Fdflags::NONBLOCK);
+
+        assert_eq!(names, ["append", "nonblock"]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Static host mappings and nameservers, exposed to guests as `/etc/hosts`
+//! and `/etc/resolv.conf` because many C programs read those files directly
+//! instead of asking the resolver.
+
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Generator},
+    utils::Error,
+};
+
+/// Where the generated files get mounted.
+pub(crate) const MOUNT_POINT: &str = "/etc";
+
+/// A runtime's DNS configuration.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DnsConfig(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    hosts: BTreeMap<String, IpAddr>,
+    nameservers: Vec<IpAddr>,
+}
+
+impl DnsConfig {
+    /// Map `hostname` to an address, or remove its mapping if `address` is
+    /// `None`.
+    pub(crate) fn set_host(&self, hostname: &str, address: Option<&str>) -> Result<(), Error> {
+        let mut state = self.0.lock().unwrap();
+
+        match address {
+            Some(address) => {
+                let address = parse_address(address)?;
+                state.hosts.insert(hostname.to_ascii_lowercase(), address);
+            }
+            None => {
+                state.hosts.remove(&hostname.to_ascii_lowercase());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_nameservers(&self, nameservers: &[String]) -> Result<(), Error> {
+        let nameservers = nameservers
+            .iter()
+            .map(|s| parse_address(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.0.lock().unwrap().nameservers = nameservers;
+        Ok(())
+    }
+
+    /// Has anything been configured?
+    ///
+    /// The files are only mounted when there is something to put in them so
+    /// a package's own `/etc` isn't hidden unnecessarily.
+    pub(crate) fn is_configured(&self) -> bool {
+        let state = self.0.lock().unwrap();
+        !state.hosts.is_empty() || !state.nameservers.is_empty()
+    }
+
+    fn render_hosts(&self) -> String {
+        let mut hosts = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost\n");
+
+        for (hostname, address) in &self.0.lock().unwrap().hosts {
+            hosts.push_str(&format!("{address}\t{hostname}\n"));
+        }
+
+        hosts
+    }
+
+    fn render_resolv_conf(&self) -> String {
+        self.0
+            .lock()
+            .unwrap()
+            .nameservers
+            .iter()
+            .map(|ns| format!("nameserver {ns}\n"))
+            .collect()
+    }
+
+    /// Add `hosts` and `resolv.conf` to a directory which will be mounted at
+    /// [`MOUNT_POINT`].
+    ///
+    /// The files are regenerated whenever they are opened, so changes made
+    /// while a program is running are picked up.
+    pub(crate) fn insert_files(&self, fs: &DeviceFileSystem) {
+        let dns = self.clone();
+        let hosts = Generator::new(move || dns.render_hosts().into_bytes());
+        fs.insert("hosts", Device::Generated(hosts));
+
+        let dns = self.clone();
+        let resolv_conf = Generator::new(move || dns.render_resolv_conf().into_bytes());
+        fs.insert("resolv.conf", Device::Generated(resolv_conf));
+    }
+}
+
+fn parse_address(address: &str) -> Result<IpAddr, Error> {
+    address.parse().map_err(|_| {
+        let msg = format!("\"{address}\" isn't a valid IP address");
+        Error::js(js_sys::TypeError::new(&msg))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn render_hosts_and_resolv_conf() {
+        let dns = DnsConfig::default();
+        assert!(!dns.is_configured());
+
+        dns.set_host("Example.Internal", Some("10.0.0.2")).unwrap();
+        dns.set_host("removed.internal", Some("10.0.0.3")).unwrap();
+        dns.set_host("removed.internal", None).unwrap();
+        dns.set_nameservers(&["1.1.1.1".to_string(), "2606:4700::1111".to_string()])
+            .unwrap();
+
+        assert!(dns.is_configured());
+        assert_eq!(
+            dns.render_hosts(),
+            "127.0.0.1\tlocalhost\n::1\tlocalhost\n10.0.0.2\texample.internal\n"
+        );
+        assert_eq!(
+            dns.render_resolv_conf(),
+            "nameserver 1.1.1.1\nnameserver 2606:4700::1111\n"
+        );
+        assert!(dns.set_host("bad.internal", Some("not-an-ip")).is_err());
+    }
+}
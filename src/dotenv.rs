@@ -0,0 +1,319 @@
+//! Reading and writing environment variables in the `.env` format used by
+//! most dev tooling.
+//!
+//! Each line is `KEY=value`, optionally prefixed with `export`. Values may be
+//! unquoted (ending at a ` #` comment), single-quoted (taken literally), or
+//! double-quoted (supporting `\n`, `\r`, `\t`, `\"`, `\\` and `\$` escapes).
+//! Quoted values may span multiple lines. Variables are never expanded.
+
+use std::{collections::BTreeMap, iter::Peekable, str::Chars};
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::utils::Error;
+
+/// Parse the contents of a `.env` file.
+///
+/// If a variable is set more than once, the last value wins.
+#[wasm_bindgen(js_name = "parseDotenv")]
+pub fn parse_dotenv(text: String) -> Result<EnvRecord, Error> {
+    let vars: BTreeMap<_, _> = parse(&text)?.into_iter().collect();
+
+    let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+    let value = vars.serialize(&serializer).map_err(Error::js)?;
+
+    Ok(value.unchecked_into())
+}
+
+/// Format environment variables as a `.env` file which {@link parseDotenv}
+/// (and other dotenv parsers) will read back unchanged.
+#[wasm_bindgen(js_name = "stringifyDotenv")]
+pub fn stringify_dotenv(env: EnvRecord) -> Result<String, Error> {
+    let vars = crate::utils::js_record_of_strings(env.unchecked_ref())?;
+    stringify(vars.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Record<string, string>")]
+    pub type EnvRecord;
+}
+
+/// Parse a `.env` file into its variables, in the order they were defined.
+pub(crate) fn parse(text: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut vars = Vec::new();
+
+    while let Some(var) = parser.next_var()? {
+        vars.push(var);
+    }
+
+    Ok(vars)
+}
+
+/// Format variables as a `.env` file, quoting values where necessary.
+pub(crate) fn stringify<'a>(
+    vars: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<String, Error> {
+    let mut out = String::new();
+
+    for (key, value) in vars {
+        if !is_valid_key(key) {
+            let msg = format!("\"{key}\" can't be used as a variable name in a .env file");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        out.push_str(key);
+        out.push('=');
+
+        if !value.is_empty() && value.chars().all(is_bare) {
+            out.push_str(value);
+        } else {
+            out.push('"');
+            for c in value.chars() {
+                match c {
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    '"' | '\\' | '$' => {
+                        out.push('\\');
+                        out.push(c);
+                    }
+                    other => out.push(other),
+                }
+            }
+            out.push('"');
+        }
+
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Characters that can appear in a value without quoting it.
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-.,/:@%+=".contains(c)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next_var(&mut self) -> Result<Option<(String, String)>, Error> {
+        loop {
+            self.skip_inline_whitespace();
+            match self.chars.peek() {
+                None => return Ok(None),
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_line(),
+                Some(_) => break,
+            }
+        }
+
+        let line = self.line;
+        let mut key = self.take_while(|c| c != '=' && c != '\n');
+        if self.chars.peek() != Some(&'=') {
+            return Err(self.error(line, format!("expected \"=\" after \"{}\"", key.trim())));
+        }
+        self.bump();
+
+        if let Some(rest) = key.strip_prefix("export") {
+            if rest.starts_with(char::is_whitespace) {
+                key = rest.to_string();
+            }
+        }
+        let key = key.trim().to_string();
+        if !is_valid_key(&key) {
+            return Err(self.error(line, format!("\"{key}\" isn't a valid variable name")));
+        }
+
+        self.skip_inline_whitespace();
+        let value = match self.chars.peek() {
+            Some('\'') => {
+                self.bump();
+                let value = self.take_while(|c| c != '\'');
+                self.expect_closing('\'', line)?;
+                value
+            }
+            Some('"') => {
+                self.bump();
+                let value = self.double_quoted();
+                self.expect_closing('"', line)?;
+                value
+            }
+            _ => self.unquoted(),
+        };
+
+        self.skip_inline_whitespace();
+        match self.chars.peek() {
+            None | Some('\n') | Some('#') => self.skip_line(),
+            Some(c) => {
+                let msg = format!("unexpected \"{c}\" after the value of \"{key}\"");
+                return Err(self.error(self.line, msg));
+            }
+        }
+
+        Ok(Some((key, value)))
+    }
+
+    fn unquoted(&mut self) -> String {
+        let mut value = String::new();
+
+        while let Some(&c) = self.chars.peek() {
+            if c == '\n' || (c == '#' && value.ends_with(char::is_whitespace)) {
+                break;
+            }
+            value.push(c);
+            self.bump();
+        }
+
+        value.trim_end().to_string()
+    }
+
+    fn double_quoted(&mut self) -> String {
+        let mut value = String::new();
+
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '"' => break,
+                '\\' => {
+                    self.bump();
+                    match self.bump() {
+                        Some('n') => value.push('\n'),
+                        Some('r') => value.push('\r'),
+                        Some('t') => value.push('\t'),
+                        Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                        Some(other) => {
+                            value.push('\\');
+                            value.push(other);
+                        }
+                        None => value.push('\\'),
+                    }
+                }
+                _ => {
+                    value.push(c);
+                    self.bump();
+                }
+            }
+        }
+
+        value
+    }
+
+    fn expect_closing(&mut self, quote: char, line: usize) -> Result<(), Error> {
+        match self.bump() {
+            Some(c) if c == quote => Ok(()),
+            _ => Err(self.error(line, format!("missing a closing {quote}"))),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !predicate(c) {
+                break;
+            }
+            taken.push(c);
+            self.bump();
+        }
+        taken
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        self.take_while(|c| c != '\n' && c.is_whitespace());
+    }
+
+    fn skip_line(&mut self) {
+        self.take_while(|c| c != '\n');
+        self.bump();
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn error(&self, line: usize, message: String) -> Error {
+        let msg = format!("Invalid .env file on line {line}: {message}");
+        Error::js(js_sys::SyntaxError::new(&msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn parse_a_dotenv_file() {
+        let text = r#"
+# A comment
+export NODE_ENV=development
+PORT = 8080 # trailing comment
+URL=http://localhost/#anchor
+LITERAL='no $expansion \n here'
+MULTILINE="first
+second\tline \"quoted\""
+EMPTY=
+"#;
+
+        let vars = parse(text).unwrap();
+
+        let expected = [
+            ("NODE_ENV", "development"),
+            ("PORT", "8080"),
+            ("URL", "http://localhost/#anchor"),
+            ("LITERAL", "no $expansion \\n here"),
+            ("MULTILINE", "first\nsecond\tline \"quoted\""),
+            ("EMPTY", ""),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(vars, expected);
+    }
+
+    #[wasm_bindgen_test]
+    fn stringified_variables_round_trip() {
+        let vars = [
+            ("PLAIN", "value"),
+            ("SPACES", "hello world"),
+            (
+                "TRICKY",
+                "a \"quote\", a $dollar\\ and\na newline # not a comment",
+            ),
+            ("EMPTY", ""),
+        ];
+
+        let text = stringify(vars).unwrap();
+        let parsed = parse(&text).unwrap();
+
+        let parsed: Vec<_> = parsed
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(parsed, vars);
+        assert!(stringify([("1BAD", "x")]).is_err());
+        assert!(parse("UNTERMINATED=\"oops").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! A short history of the events a runtime has emitted.
+//!
+//! Log output is usually gone (or far too noisy) by the time someone asks
+//! for help, so each runtime also remembers its most recent
+//! [`RuntimeEvent`]s. `runtime.events()` hands them back as plain JSON which
+//! users can paste into a bug report.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::{events::RuntimeEvent, utils::Error};
+
+/// How many events are kept before the oldest ones are dropped.
+const CAPACITY: usize = 512;
+
+#[derive(Debug, Clone)]
+pub(crate) struct EventLog {
+    entries: VecDeque<LoggedEvent>,
+    capacity: usize,
+    next_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct LoggedEvent {
+    /// Increases by one for every event, so gaps show where history was
+    /// dropped.
+    id: u64,
+    /// When the event was emitted, in milliseconds since the Unix epoch.
+    time: f64,
+    event: RuntimeEvent,
+}
+
+impl EventLog {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        EventLog {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            next_id: 1,
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: &RuntimeEvent) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(LoggedEvent {
+            id: self.next_id,
+            time: js_sys::Date::now(),
+            event: event.clone(),
+        });
+        self.next_id += 1;
+    }
+
+    /// Every event still in the log with an `id` greater than `since`.
+    fn since(&self, since: u64) -> impl Iterator<Item = &LoggedEvent> {
+        // Note: ids are sorted, so we can skip straight to the first match
+        let start = self.entries.partition_point(|e| e.id <= since);
+        self.entries.range(start..)
+    }
+
+    pub(crate) fn to_js(&self, since: Option<u64>) -> Result<JsLoggedEvents, Error> {
+        let entries: Vec<&LoggedEvent> = self.since(since.unwrap_or(0)).collect();
+        let value = serde_wasm_bindgen::to_value(&entries).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog::with_capacity(CAPACITY)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const LOGGED_EVENT_TYPE_DEF: &'static str = r#"
+/**
+ * An entry in a runtime's event history, as returned by
+ * {@link Runtime.events}.
+ */
+export type LoggedEvent = {
+    /**
+     * A sequence number which increases by one for every event. Pass the
+     * last `id` you saw as `since` to only get newer events.
+     */
+    id: number;
+    /** When the event was emitted, in milliseconds since the Unix epoch. */
+    time: number;
+    event: RuntimeEventMap[keyof RuntimeEventMap];
+};
+
+export type EventLogOptions = {
+    /** Only return events with an `id` greater than this. */
+    since?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "LoggedEvent[]")]
+    pub type JsLoggedEvents;
+
+    #[wasm_bindgen(typescript_type = "EventLogOptions")]
+    pub type EventLogOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn since(this: &EventLogOptions) -> Option<f64>;
+}
+
+impl EventLogOptions {
+    pub(crate) fn since_id(&self) -> Result<Option<u64>, Error> {
+        match self.since() {
+            Some(id) if id.is_finite() && id >= 0.0 => Ok(Some(id as u64)),
+            Some(id) => {
+                let msg = format!("\"since\" must be a non-negative event id, not {id}");
+                Err(Error::js(js_sys::RangeError::new(&msg)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_the_most_recent_events_are_kept() {
+        let mut log = EventLog::with_capacity(3);
+
+        for worker_id in 0..5 {
+            log.record(&RuntimeEvent::WorkerRecovered {
+                worker_id,
+                replacement_id: worker_id + 1,
+                recovery_ms: 0.0,
+            });
+        }
+
+        let ids: Vec<u64> = log.since(0).map(|e| e.id).collect();
+        assert_eq!(ids, [3, 4, 5]);
+        let ids: Vec<u64> = log.since(4).map(|e| e.id).collect();
+        assert_eq!(ids, [5]);
+        assert_eq!(log.since(5).count(), 0);
+    }
+}
@@ -0,0 +1,810 @@
+//! Structured events a [`crate::runtime::Runtime`] reports to JavaScript.
+//!
+//! Each runtime gets its own [`web_sys::EventTarget`] which lives on the same
+//! thread as its scheduler. Code running elsewhere needs to route events
+//! through [`crate::tasks::SchedulerMessage::Emit`] so they get dispatched on
+//! the right thread. Every event dispatched to a runtime is also kept in its
+//! [`EventLog`].
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::wasm_bindgen;
+use web_sys::{CustomEvent, CustomEventInit, EventTarget};
+
+use crate::{
+    capabilities::Capability, event_log::EventLog, fs::ViolationReason,
+    memory_pressure::PressureLevel, terminal::EchoSource, utils::Error,
+};
+
+thread_local! {
+    static CHANNELS: RefCell<BTreeMap<u32, Channel>> = RefCell::default();
+}
+
+struct Channel {
+    target: EventTarget,
+    log: EventLog,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel {
+            target: EventTarget::new().expect("Unable to create an EventTarget"),
+            log: EventLog::default(),
+        }
+    }
+}
+
+/// A cheap, thread-safe identifier for the [`EventTarget`] belonging to a
+/// runtime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct EventChannel(u32);
+
+impl EventChannel {
+    pub(crate) fn new() -> Self {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        EventChannel(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Get the [`EventTarget`] listeners should subscribe to.
+    ///
+    /// This must be called from the thread that owns the runtime's scheduler,
+    /// otherwise listeners will be registered on a target nobody dispatches
+    /// to.
+    pub(crate) fn target(&self) -> EventTarget {
+        self.with_channel(|channel| channel.target.clone())
+    }
+
+    /// Run `func` against the runtime's [`EventLog`].
+    ///
+    /// Like [`EventChannel::target()`], this only sees the events dispatched
+    /// on the current thread.
+    pub(crate) fn with_log<T>(&self, func: impl FnOnce(&EventLog) -> T) -> T {
+        self.with_channel(|channel| func(&channel.log))
+    }
+
+    fn with_channel<T>(&self, func: impl FnOnce(&mut Channel) -> T) -> T {
+        CHANNELS.with(|channels| {
+            func(
+                channels
+                    .borrow_mut()
+                    .entry(self.0)
+                    .or_insert_with(Channel::new),
+            )
+        })
+    }
+
+    /// Dispatch an event to any listeners on the current thread.
+    pub(crate) fn dispatch(&self, event: &RuntimeEvent) -> Result<(), Error> {
+        tracing::debug!(?event, "Dispatching a runtime event");
+        // Note: record the event before anyone gets a chance to react to it,
+        // so the log stays in the order things happened
+        let target = self.with_channel(|channel| {
+            channel.log.record(event);
+            channel.target.clone()
+        });
+        target
+            .dispatch_event(&custom_event(event)?)
+            .map_err(Error::js)?;
+
+        Ok(())
+    }
+}
+
+/// Dispatch an event to every runtime whose [`EventTarget`] lives on the
+/// current thread.
+pub(crate) fn dispatch_on_this_thread(event: &RuntimeEvent) {
+    let targets: Vec<EventTarget> = CHANNELS.with(|channels| {
+        channels
+            .borrow_mut()
+            .values_mut()
+            .map(|channel| {
+                channel.log.record(event);
+                channel.target.clone()
+            })
+            .collect()
+    });
+    if targets.is_empty() {
+        return;
+    }
+
+    let custom_event = match custom_event(event) {
+        Ok(e) => e,
+        Err(e) => {
+            tracing::warn!(error = ?e, ?event, "Unable to create the event");
+            return;
+        }
+    };
+
+    for target in targets {
+        if let Err(e) = target.dispatch_event(&custom_event) {
+            tracing::warn!(error = ?e, ?event, "Unable to dispatch the event");
+        }
+    }
+}
+
+fn custom_event(event: &RuntimeEvent) -> Result<CustomEvent, Error> {
+    let detail = serde_wasm_bindgen::to_value(event).map_err(Error::js)?;
+    let mut init = CustomEventInit::new();
+    init.detail(&detail);
+
+    CustomEvent::new_with_event_init_dict(event.name(), &init).map_err(Error::js)
+}
+
+/// Something notable that happened inside a runtime.
+///
+/// The event's payload is available to listeners as `CustomEvent.detail`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub(crate) enum RuntimeEvent {
+    /// A worker was refused because it isn't compatible with the scheduler.
+    #[serde(rename = "worker-rejected", rename_all = "camelCase")]
+    WorkerRejected { worker_id: u32, reason: String },
+    /// A worker panicked and was taken out of the thread pool.
+    #[serde(rename = "worker-panicked", rename_all = "camelCase")]
+    WorkerPanicked {
+        worker_id: u32,
+        message: String,
+        location: Option<String>,
+        backtrace: Option<String>,
+        /// The task the worker was running, matching the `task.id` in the
+        /// scheduler's and worker's logs.
+        task_id: Option<u64>,
+        /// Was every other worker terminated too?
+        aborted: bool,
+    },
+    /// The page the runtime lives on was hidden or shown.
+    #[serde(rename = "visibility-changed", rename_all = "camelCase")]
+    VisibilityChanged { hidden: bool },
+    /// Every worker has been blocked for a while with work queued behind
+    /// them, which usually means the pool has deadlocked.
+    #[serde(rename = "deadlock-suspected", rename_all = "camelCase")]
+    DeadlockSuspected {
+        stalled_for_ms: u64,
+        workers: Vec<StalledWorker>,
+        /// How many extra workers were started to try to break the cycle.
+        boosted_by: usize,
+    },
+    /// The page's Content Security Policy won't let the thread pool start
+    /// workers.
+    #[serde(rename = "worker-spawn-blocked", rename_all = "camelCase")]
+    WorkerSpawnBlocked {
+        directive: String,
+        blocked_url: String,
+        hints: Vec<String>,
+        /// Did the pool fall back to running tasks on its own thread?
+        single_threaded: bool,
+    },
+    /// A filesystem was mounted into a program.
+    #[serde(rename = "mount-attached", rename_all = "camelCase")]
+    MountAttached {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+        path: String,
+        kind: MountKind,
+    },
+    /// A filesystem was removed from a program.
+    #[serde(rename = "mount-detached", rename_all = "camelCase")]
+    MountDetached {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+        path: String,
+        reason: DetachReason,
+    },
+    /// Something other than what was asked for got mounted.
+    #[serde(rename = "mount-fallback", rename_all = "camelCase")]
+    MountFallback {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+        path: String,
+        requested: MountKind,
+        actual: MountKind,
+        reason: String,
+    },
+    /// A command started by {@link Runtime.supervise} was (re)started.
+    #[serde(rename = "service-started", rename_all = "camelCase")]
+    ServiceStarted { name: String, attempt: u32 },
+    /// A supervised command exited.
+    #[serde(rename = "service-exited", rename_all = "camelCase")]
+    ServiceExited {
+        name: String,
+        code: i32,
+        /// How long until it gets restarted, if it will be.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        restart_in_ms: Option<u64>,
+    },
+    /// A supervisor stopped restarting its command.
+    #[serde(rename = "service-stopped", rename_all = "camelCase")]
+    ServiceStopped {
+        name: String,
+        reason: StopReason,
+        restarts: u32,
+    },
+    /// Something tried to use a capability the runtime wasn't granted.
+    #[serde(rename = "capability-denied", rename_all = "camelCase")]
+    CapabilityDenied {
+        capability: Capability,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    /// The main thread was blocked while `setBlockingDiagnostics()` was
+    /// enabled.
+    #[serde(rename = "main-thread-blocked", rename_all = "camelCase")]
+    MainThreadBlocked {
+        operation: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stack: Option<String>,
+    },
+    /// A guest's path was refused by a strict sandbox.
+    #[serde(rename = "sandbox-violation", rename_all = "camelCase")]
+    SandboxViolation {
+        /// Where the filesystem the path was used on is mounted.
+        mount: String,
+        path: String,
+        operation: String,
+        reason: ViolationReason,
+    },
+    /// A worker that panicked was replaced, and the replacement is ready for
+    /// work.
+    #[serde(rename = "worker-recovered", rename_all = "camelCase")]
+    WorkerRecovered {
+        worker_id: u32,
+        replacement_id: u32,
+        recovery_ms: f64,
+    },
+    /// A program is waiting for the runtime to drop below
+    /// `maxConcurrentInstances`.
+    #[serde(rename = "instance-queued", rename_all = "camelCase")]
+    InstanceQueued {
+        /// Identifies the waiting program across events.
+        ticket: u64,
+        /// How many programs are ahead of it, plus one, or `0` once it is
+        /// starting.
+        position: usize,
+    },
+    /// Input echo was turned on or off, typically because a program is
+    /// prompting for a password.
+    #[serde(rename = "echo-changed", rename_all = "camelCase")]
+    EchoChanged { echo: bool, changed_by: EchoSource },
+    /// The page's memory use moved closer to (or further from) its limit.
+    #[serde(rename = "memory-pressure", rename_all = "camelCase")]
+    MemoryPressure {
+        level: PressureLevel,
+        used_bytes: u64,
+        limit_bytes: u64,
+        /// Were caches dropped and idle workers terminated?
+        shed: bool,
+    },
+    /// The page is being unloaded and running programs were sent `SIGTERM`.
+    #[serde(rename = "shutdown", rename_all = "camelCase")]
+    Shutdown {
+        pids: Vec<u32>,
+        grace_period_ms: u64,
+    },
+    /// A program was started.
+    #[serde(rename = "process-started", rename_all = "camelCase")]
+    ProcessStarted { pid: u32, program: String },
+    /// A program exited.
+    #[serde(rename = "process-exited", rename_all = "camelCase")]
+    ProcessExited { pid: u32, exit_code: i32 },
+    /// A task couldn't be sent to any of the thread pool's workers and was
+    /// dropped.
+    #[serde(rename = "task-undelivered", rename_all = "camelCase")]
+    TaskUndelivered {
+        task_id: Option<u64>,
+        attempts: usize,
+        reason: String,
+    },
+    /// A program wrote to its `/dev/status/progress` device.
+    #[serde(rename = "guest-progress", rename_all = "camelCase")]
+    GuestProgress {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        percent: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// A program attached to a TTY wrote an OSC 8 hyperlink.
+    #[serde(rename = "terminal-hyperlink", rename_all = "camelCase")]
+    TerminalHyperlink {
+        uri: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        text: String,
+    },
+    /// A program attached to a TTY set its window title.
+    #[serde(rename = "terminal-title", rename_all = "camelCase")]
+    TerminalTitle { title: String },
+    /// A program attached to a TTY asked for a desktop notification.
+    #[serde(rename = "terminal-notification", rename_all = "camelCase")]
+    TerminalNotification {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        body: String,
+    },
+}
+
+/// What a mounted filesystem is backed by.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MountKind {
+    /// A {@link Directory} provided by the user.
+    Directory,
+    /// Files generated by the runtime (e.g. `/etc` or `/dev/timer`).
+    Generated,
+}
+
+/// Why a supervisor stopped restarting its command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum StopReason {
+    /// {@link Supervisor.stop} was called.
+    Stopped,
+    /// The command exited in a way its restart policy doesn't restart.
+    Exited,
+    /// The command was restarted `maxRestarts` times.
+    RestartLimit,
+}
+
+/// Why a mount went away.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DetachReason {
+    /// It was removed with `InstanceFs.unmount()`.
+    Unmounted,
+    /// The program it was mounted into exited.
+    Exited,
+}
+
+/// What a worker was doing when the pool stalled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StalledWorker {
+    pub(crate) worker_id: u32,
+    /// How long the worker has been blocked.
+    pub(crate) busy_for_ms: u64,
+    /// The most async tasks that could be stuck behind the worker.
+    pub(crate) queued_tasks: u32,
+    /// The blocking task the worker is running, if we know which one.
+    pub(crate) task_id: Option<u64>,
+}
+
+impl RuntimeEvent {
+    /// The name listeners use to subscribe to this event.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RuntimeEvent::WorkerRejected { .. } => "worker-rejected",
+            RuntimeEvent::WorkerPanicked { .. } => "worker-panicked",
+            RuntimeEvent::VisibilityChanged { .. } => "visibility-changed",
+            RuntimeEvent::DeadlockSuspected { .. } => "deadlock-suspected",
+            RuntimeEvent::WorkerSpawnBlocked { .. } => "worker-spawn-blocked",
+            RuntimeEvent::MountAttached { .. } => "mount-attached",
+            RuntimeEvent::MountDetached { .. } => "mount-detached",
+            RuntimeEvent::MountFallback { .. } => "mount-fallback",
+            RuntimeEvent::ServiceStarted { .. } => "service-started",
+            RuntimeEvent::ServiceExited { .. } => "service-exited",
+            RuntimeEvent::ServiceStopped { .. } => "service-stopped",
+            RuntimeEvent::CapabilityDenied { .. } => "capability-denied",
+            RuntimeEvent::MainThreadBlocked { .. } => "main-thread-blocked",
+            RuntimeEvent::SandboxViolation { .. } => "sandbox-violation",
+            RuntimeEvent::WorkerRecovered { .. } => "worker-recovered",
+            RuntimeEvent::InstanceQueued { .. } => "instance-queued",
+            RuntimeEvent::EchoChanged { .. } => "echo-changed",
+            RuntimeEvent::MemoryPressure { .. } => "memory-pressure",
+            RuntimeEvent::Shutdown { .. } => "shutdown",
+            RuntimeEvent::ProcessStarted { .. } => "process-started",
+            RuntimeEvent::ProcessExited { .. } => "process-exited",
+            RuntimeEvent::TaskUndelivered { .. } => "task-undelivered",
+            RuntimeEvent::GuestProgress { .. } => "guest-progress",
+            RuntimeEvent::TerminalHyperlink { .. } => "terminal-hyperlink",
+            RuntimeEvent::TerminalTitle { .. } => "terminal-title",
+            RuntimeEvent::TerminalNotification { .. } => "terminal-notification",
+        }
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const RUNTIME_EVENT_TYPE_DECLARATIONS: &str = r#"
+/**
+ * Emitted when a worker reports that it isn't compatible with the rest of the
+ * runtime (e.g. because its script isn't served with the same COOP/COEP
+ * headers as the page).
+ */
+export type WorkerRejectedEvent = {
+    type: "worker-rejected";
+    workerId: number;
+    reason: string;
+};
+
+/**
+ * Emitted when a worker panics.
+ *
+ * The worker is terminated and anything it was running fails. If the runtime
+ * was created with `onPanic: "abort"`, every other worker is terminated too
+ * and `aborted` will be `true`.
+ *
+ * `taskId` identifies the task the worker was running, and matches the
+ * `task.id` field in the scheduler's and worker's log lines.
+ */
+export type WorkerPanickedEvent = {
+    type: "worker-panicked";
+    workerId: number;
+    message: string;
+    location?: string;
+    backtrace?: string;
+    taskId?: number;
+    aborted: boolean;
+};
+
+/**
+ * Emitted when the page a runtime was created on is hidden (e.g. the user
+ * switched tabs) or shown again.
+ *
+ * Browsers throttle timers in hidden pages, so programs may run slower than
+ * usual while `hidden` is `true`. See `RuntimeOptions.whenHidden`.
+ */
+export type VisibilityChangedEvent = {
+    type: "visibility-changed";
+    hidden: boolean;
+};
+
+/**
+ * Emitted when every worker has been blocked (e.g. in `Atomics.wait()`) for
+ * longer than `RuntimeOptions.deadlockDetection.after` while async tasks were
+ * queued on their event loops, which usually means the thread pool has
+ * deadlocked.
+ *
+ * `queuedTasks` is the number of async tasks handed to a worker since it
+ * last finished a blocking task. Some of them may have completed already.
+ * `taskId` is the blocking task the worker is stuck in, matching the
+ * `task.id` field in the logs.
+ */
+export type DeadlockSuspectedEvent = {
+    type: "deadlock-suspected";
+    stalledForMs: number;
+    workers: {
+        workerId: number;
+        busyForMs: number;
+        queuedTasks: number;
+        taskId?: number;
+    }[];
+    /** How many extra workers were started to try to break the cycle. */
+    boostedBy: number;
+};
+
+/**
+ * Emitted when the page's Content Security Policy blocks the thread pool's
+ * workers (see {@link WorkerSpawnBlocked}).
+ *
+ * If the runtime was created with `onWorkerBlocked: "single-threaded"`,
+ * `singleThreaded` is `true` and tasks carry on running on the runtime's own
+ * thread. Otherwise the thread pool refuses to run anything else.
+ */
+export type WorkerSpawnBlockedEvent = {
+    type: "worker-spawn-blocked";
+    directive: string;
+    blockedUrl: string;
+    hints: string[];
+    singleThreaded: boolean;
+};
+
+/**
+ * What a mounted filesystem is backed by: a {@link Directory} provided by the
+ * user, or files generated by the runtime (e.g. `/etc` or `/dev/timer`).
+ */
+export type MountKind = "directory" | "generated";
+
+/**
+ * Emitted when a filesystem is mounted into a program, either when it starts
+ * or later on with `InstanceFs.mount()`.
+ *
+ * `pid` is set for programs started with {@link runWasix}.
+ */
+export type MountAttachedEvent = {
+    type: "mount-attached";
+    pid?: number;
+    path: string;
+    kind: MountKind;
+};
+
+/**
+ * Emitted when a filesystem is removed from a program, either with
+ * `InstanceFs.unmount()` or because the program exited.
+ */
+export type MountDetachedEvent = {
+    type: "mount-detached";
+    pid?: number;
+    path: string;
+    reason: "unmounted" | "exited";
+};
+
+/**
+ * Emitted when something other than what was asked for ends up mounted at
+ * `path` (e.g. the runtime's generated `/etc` files are hidden because a
+ * directory was mounted over them), so applications don't silently lose
+ * data or persistence.
+ */
+export type MountFallbackEvent = {
+    type: "mount-fallback";
+    pid?: number;
+    path: string;
+    requested: MountKind;
+    actual: MountKind;
+    reason: string;
+};
+
+/**
+ * Emitted each time a command started with {@link Runtime.supervise} is
+ * started, where `attempt` is `1` the first time.
+ */
+export type ServiceStartedEvent = {
+    type: "service-started";
+    name: string;
+    attempt: number;
+};
+
+/**
+ * Emitted when a supervised command exits. `restartInMs` is set if it is
+ * going to be restarted.
+ */
+export type ServiceExitedEvent = {
+    type: "service-exited";
+    name: string;
+    code: number;
+    restartInMs?: number;
+};
+
+/**
+ * Emitted when a supervisor stops restarting its command, either because
+ * it was stopped, the restart policy says not to, or `maxRestarts` was
+ * reached.
+ */
+export type ServiceStoppedEvent = {
+    type: "service-stopped";
+    name: string;
+    reason: "stopped" | "exited" | "restart-limit";
+    restarts: number;
+};
+
+/**
+ * Emitted when a program (or a host extension, via
+ * {@link Runtime.checkCapability}) tries to use a capability that wasn't
+ * granted in `RuntimeOptions.capabilities` or isn't available here.
+ */
+export type CapabilityDeniedEvent = {
+    type: "capability-denied";
+    capability: CapabilityName;
+    pid?: number;
+    detail?: string;
+};
+
+/**
+ * Emitted when something blocks the main thread (e.g. waiting on a worker,
+ * or calling `Atomics.wait()`) while {@link setBlockingDiagnostics} is
+ * enabled. `stack` is the JavaScript call stack at that point, when the
+ * engine provides one.
+ */
+export type MainThreadBlockedEvent = {
+    type: "main-thread-blocked";
+    operation: string;
+    stack?: string;
+};
+
+/**
+ * Emitted when a program started with `sandbox: "strict"` uses a path that
+ * would leave (or looks like it is trying to leave) one of its mounted
+ * directories. The operation fails with `EACCES`.
+ *
+ * `path` is relative to the directory mounted at `mount`.
+ */
+export type SandboxViolationEvent = {
+    type: "sandbox-violation";
+    mount: string;
+    path: string;
+    operation: string;
+    reason: "escape" | "nul-byte" | "traversal" | "symlink";
+};
+
+/**
+ * Emitted when a worker that panicked has been replaced by a fresh one which
+ * is ready for work (see `onPanic: "recover"`). `recoveryMs` is how long the
+ * replacement took to start.
+ */
+export type WorkerRecoveredEvent = {
+    type: "worker-recovered";
+    /** The worker that panicked. */
+    workerId: number;
+    /** The worker that took its place. */
+    replacementId: number;
+    recoveryMs: number;
+};
+
+/**
+ * Emitted when a program has to wait because the runtime is already running
+ * `RuntimeOptions.maxConcurrentInstances` programs, and again whenever it
+ * moves up the queue. `position` is `1` for the next program to start, and
+ * `0` once the program's turn has come.
+ *
+ * Every waiting program gets its own `ticket`.
+ */
+export type InstanceQueuedEvent = {
+    type: "instance-queued";
+    ticket: number;
+    position: number;
+};
+
+/**
+ * Emitted when the TTY starts or stops echoing input, either because the
+ * program changed its terminal settings (e.g. while prompting for a
+ * password) or because the host called {@link Instance.setEcho}. Terminal
+ * components can use it to mask what the user is typing.
+ */
+export type EchoChangedEvent = {
+    type: "echo-changed";
+    echo: boolean;
+    changedBy: "program" | "host";
+};
+
+/**
+ * Emitted when `RuntimeOptions.memoryPressure` is enabled and the page's
+ * memory use crosses 70% (`"moderate"`) or 90% (`"critical"`) of its limit,
+ * or drops back below it.
+ *
+ * When `shed` is `true` the runtime has already dropped its caches and
+ * terminated idle workers. Hosts may want to free memory of their own too.
+ */
+export type MemoryPressureEvent = {
+    type: "memory-pressure";
+    level: "normal" | "moderate" | "critical";
+    usedBytes: number;
+    limitBytes: number;
+    shed: boolean;
+};
+
+/**
+ * Emitted when `RuntimeOptions.shutdown` is enabled and the page is being
+ * unloaded, after every running program (listed in `pids`) has been sent
+ * `SIGTERM`.
+ *
+ * Listeners run while the page is being torn down, so they should only do
+ * quick, synchronous work (e.g. `navigator.sendBeacon()`).
+ */
+export type ShutdownEvent = {
+    type: "shutdown";
+    pids: number[];
+    gracePeriodMs: number;
+};
+
+/** Emitted once a program has been instantiated and starts running. */
+export type ProcessStartedEvent = {
+    type: "process-started";
+    pid: number;
+    program: string;
+};
+
+/** Emitted when a program exits, whether it succeeded or not. */
+export type ProcessExitedEvent = {
+    type: "process-exited";
+    pid: number;
+    exitCode: number;
+};
+
+/**
+ * Emitted when `postMessage()` kept failing while handing a task to the
+ * thread pool's workers, so the task was dropped. Whoever was waiting on it
+ * (e.g. {@link Instance.wait}) fails with a `TaskDeliveryError`.
+ */
+export type TaskUndeliveredEvent = {
+    type: "task-undelivered";
+    /** The id used for the task in the runtime's logs. */
+    taskId?: number;
+    /** How many workers the task was offered to. */
+    attempts: number;
+    reason: string;
+};
+
+/**
+ * Emitted when a program reports its progress by writing a line to
+ * `/dev/status/progress`, so UIs can show a progress bar without parsing
+ * the program's output.
+ *
+ * A line is either a percentage followed by an optional message
+ * (`"42% Compiling foo.c"`), just a message (`"Linking"`), or a JSON object
+ * with `percent` and `message` fields.
+ */
+export type GuestProgressEvent = {
+    type: "guest-progress";
+    /** The program's pid, if it was started with {@link runWasix}. */
+    pid?: number;
+    /** How far along the program is, from `0` to `100`. */
+    percent?: number;
+    message?: string;
+};
+
+/**
+ * Emitted when a program attached to a TTY finishes writing an OSC 8
+ * hyperlink, so UIs which don't understand the escape sequence can still
+ * make the link clickable. `text` is what was written between the link's
+ * start and end, without any other escape sequences.
+ *
+ * The escape sequences are left in the program's output.
+ */
+export type TerminalHyperlinkEvent = {
+    type: "terminal-hyperlink";
+    uri: string;
+    /** The link's `id` parameter, shared by every part of a wrapped link. */
+    id?: string;
+    text: string;
+};
+
+/**
+ * Emitted when a program attached to a TTY sets the window title with
+ * `OSC 0` or `OSC 2`.
+ */
+export type TerminalTitleEvent = {
+    type: "terminal-title";
+    title: string;
+};
+
+/**
+ * Emitted when a program attached to a TTY asks for a desktop notification,
+ * with either `OSC 9 ; body` or `OSC 777 ; notify ; title ; body`. Showing it
+ * is up to the host.
+ */
+export type TerminalNotificationEvent = {
+    type: "terminal-notification";
+    title?: string;
+    body: string;
+};
+
+/**
+ * A mapping from event names to the `CustomEvent.detail` payload for that
+ * event.
+ */
+export type RuntimeEventMap = {
+    "worker-rejected": WorkerRejectedEvent;
+    "worker-panicked": WorkerPanickedEvent;
+    "visibility-changed": VisibilityChangedEvent;
+    "deadlock-suspected": DeadlockSuspectedEvent;
+    "worker-spawn-blocked": WorkerSpawnBlockedEvent;
+    "mount-attached": MountAttachedEvent;
+    "mount-detached": MountDetachedEvent;
+    "mount-fallback": MountFallbackEvent;
+    "service-started": ServiceStartedEvent;
+    "service-exited": ServiceExitedEvent;
+    "service-stopped": ServiceStoppedEvent;
+    "capability-denied": CapabilityDeniedEvent;
+    "main-thread-blocked": MainThreadBlockedEvent;
+    "sandbox-violation": SandboxViolationEvent;
+    "worker-recovered": WorkerRecoveredEvent;
+    "instance-queued": InstanceQueuedEvent;
+    "echo-changed": EchoChangedEvent;
+    "memory-pressure": MemoryPressureEvent;
+    "shutdown": ShutdownEvent;
+    "process-started": ProcessStartedEvent;
+    "process-exited": ProcessExitedEvent;
+    "task-undelivered": TaskUndeliveredEvent;
+    "guest-progress": GuestProgressEvent;
+    "terminal-hyperlink": TerminalHyperlinkEvent;
+    "terminal-title": TerminalTitleEvent;
+    "terminal-notification": TerminalNotificationEvent;
+};
+
+/** A listener for {@link Runtime.addEventListener}. */
+export type RuntimeEventListener = (
+    event: CustomEvent<RuntimeEventMap[keyof RuntimeEventMap]>,
+) => void;
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "RuntimeEventListener", extends = js_sys::Function)]
+    pub type RuntimeEventListener;
+}
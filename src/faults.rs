@@ -0,0 +1,500 @@
+//! Making a program's I/O fail on purpose, so developers can check how it
+//! copes with a full disk or a flaky device without leaving the browser.
+//!
+//! Faults are injected where the program's files meet the runtime (its
+//! standard streams and `mount`ed directories), so the guest sees them as
+//! ordinary errors from the corresponding syscalls.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{FileSystem, FsError, Metadata, OpenOptionsConfig, ReadDir, VirtualFile};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+use crate::utils::Error;
+
+type BoxedFile = Box<dyn VirtualFile + Send + Sync + 'static>;
+
+/// The operations faults can be injected into.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Syscall {
+    Open,
+    Read,
+    Write,
+    Flush,
+}
+
+/// The errors a program can be given.
+///
+/// Each one is mapped to the [`io::ErrorKind`] that WASIX translates back
+/// into the same errno.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) enum Errno {
+    Enospc,
+    Eio,
+    Eacces,
+    Enoent,
+    Eexist,
+    Eintr,
+    Eagain,
+    Epipe,
+    Einval,
+    Etimedout,
+    Econnreset,
+}
+
+impl Errno {
+    fn to_io_error(self) -> io::Error {
+        let kind = match self {
+            // Note: WASIX reports a write that made no progress as ENOSPC
+            Errno::Enospc => io::ErrorKind::WriteZero,
+            Errno::Eio => io::ErrorKind::Other,
+            Errno::Eacces => io::ErrorKind::PermissionDenied,
+            Errno::Enoent => io::ErrorKind::NotFound,
+            Errno::Eexist => io::ErrorKind::AlreadyExists,
+            Errno::Eintr => io::ErrorKind::Interrupted,
+            Errno::Eagain => io::ErrorKind::WouldBlock,
+            Errno::Epipe => io::ErrorKind::BrokenPipe,
+            Errno::Einval => io::ErrorKind::InvalidInput,
+            Errno::Etimedout => io::ErrorKind::TimedOut,
+            Errno::Econnreset => io::ErrorKind::ConnectionReset,
+        };
+        io::Error::new(kind, format!("injected fault ({self:?})"))
+    }
+}
+
+/// A single `faults` entry, as written by the user.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RuleSpec {
+    syscall: Syscall,
+    errno: Errno,
+    path: Option<String>,
+    every: Option<u64>,
+    rate: Option<f64>,
+    #[serde(default)]
+    after: u64,
+    count: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    spec: RuleSpec,
+    state: Mutex<RuleState>,
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    /// Matching calls seen so far.
+    calls: u64,
+    /// Faults injected so far.
+    injected: u64,
+}
+
+impl Rule {
+    fn matches(&self, syscall: Syscall, path: &Path) -> bool {
+        self.spec.syscall == syscall
+            && self
+                .spec
+                .path
+                .as_deref()
+                .map_or(true, |prefix| path.starts_with(prefix))
+    }
+
+    /// Record a matching call, returning `true` if it should fail.
+    fn trip(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.calls += 1;
+
+        if state.calls <= self.spec.after {
+            return false;
+        }
+        if self.spec.count.is_some_and(|count| state.injected >= count) {
+            return false;
+        }
+        if let Some(every) = self.spec.every {
+            if (state.calls - self.spec.after) % every != 0 {
+                return false;
+            }
+        }
+        if let Some(rate) = self.spec.rate {
+            if js_sys::Math::random() >= rate {
+                return false;
+            }
+        }
+
+        state.injected += 1;
+        true
+    }
+}
+
+/// The parsed `faults` option.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faults {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl Faults {
+    pub(crate) fn parse(value: JsValue) -> Result<Self, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(Faults::default());
+        }
+
+        let specs: Vec<RuleSpec> = serde_wasm_bindgen::from_value(value).map_err(|e| {
+            let msg = format!("Invalid faults: {e}");
+            Error::js(js_sys::TypeError::new(&msg))
+        })?;
+
+        for spec in &specs {
+            if spec.every == Some(0) {
+                let msg = "\"every\" must be at least 1";
+                return Err(Error::js(js_sys::TypeError::new(msg)));
+            }
+            if spec.rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+                let msg = "\"rate\" must be between 0 and 1";
+                return Err(Error::js(js_sys::TypeError::new(msg)));
+            }
+        }
+
+        let rules = specs
+            .into_iter()
+            .map(|spec| Rule {
+                spec,
+                state: Mutex::default(),
+            })
+            .collect();
+
+        Ok(Faults {
+            rules: Arc::new(rules),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The error a `syscall` on `path` should fail with, if any.
+    fn check(&self, syscall: Syscall, path: &Path) -> Option<io::Error> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(syscall, path))
+            .find(|rule| rule.trip())?;
+
+        tracing::debug!(
+            ?syscall,
+            path = %path.display(),
+            errno = ?rule.spec.errno,
+            "Injecting a fault",
+        );
+        Some(rule.spec.errno.to_io_error())
+    }
+
+    pub(crate) fn stdin(&self, file: BoxedFile) -> BoxedFile {
+        self.file("/dev/stdin", file)
+    }
+
+    pub(crate) fn stdout(&self, file: BoxedFile) -> BoxedFile {
+        self.file("/dev/stdout", file)
+    }
+
+    pub(crate) fn stderr(&self, file: BoxedFile) -> BoxedFile {
+        self.file("/dev/stderr", file)
+    }
+
+    fn file(&self, path: &str, file: BoxedFile) -> BoxedFile {
+        if self.is_empty() {
+            return file;
+        }
+
+        Box::new(FaultyFile {
+            inner: file,
+            path: PathBuf::from(path),
+            faults: self.clone(),
+        })
+    }
+
+    /// Wrap the filesystem mounted at `mount_point`, if any rules could
+    /// apply to it.
+    pub(crate) fn mount(
+        &self,
+        mount_point: &str,
+        fs: Arc<dyn FileSystem + Send + Sync>,
+    ) -> Arc<dyn FileSystem + Send + Sync> {
+        if self.is_empty() {
+            return fs;
+        }
+
+        Arc::new(FaultyFileSystem {
+            inner: fs,
+            mount_point: PathBuf::from(mount_point),
+            faults: self.clone(),
+        })
+    }
+}
+
+/// A [`VirtualFile`] whose reads, writes, and flushes may fail.
+#[derive(Debug)]
+struct FaultyFile {
+    inner: BoxedFile,
+    /// Where the program sees this file, for matching against rules.
+    path: PathBuf,
+    faults: Faults,
+}
+
+impl FaultyFile {
+    fn check(&self, syscall: Syscall) -> Option<io::Error> {
+        self.faults.check(syscall, &self.path)
+    }
+}
+
+impl VirtualFile for FaultyFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        self.inner.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        self.inner.unlink()
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for FaultyFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(e) = self.check(Syscall::Read) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultyFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(e) = self.check(Syscall::Write) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(e) = self.check(Syscall::Flush) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for FaultyFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+/// A [`FileSystem`] whose files may fail to open, read, or write.
+#[derive(Debug, Clone)]
+struct FaultyFileSystem {
+    inner: Arc<dyn FileSystem + Send + Sync>,
+    mount_point: PathBuf,
+    faults: Faults,
+}
+
+impl FaultyFileSystem {
+    /// Where the program sees `path`.
+    fn guest_path(&self, path: &Path) -> PathBuf {
+        self.mount_point
+            .join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+impl FileSystem for FaultyFileSystem {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        self.inner.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for FaultyFileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let guest_path = self.guest_path(path);
+        if let Some(e) = self.faults.check(Syscall::Open, &guest_path) {
+            return Err(FsError::from(e));
+        }
+
+        let file = self
+            .inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)?;
+
+        Ok(Box::new(FaultyFile {
+            inner: file,
+            path: guest_path,
+            faults: self.faults.clone(),
+        }))
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const FAULTS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Make a program's I/O fail on purpose. See `CommonOptions.faults`.
+ *
+ * A rule with neither `every` nor `rate` fails every matching call.
+ *
+ * @example
+ * ```ts
+ * // Every third write to /data fails with ENOSPC
+ * { syscall: "write", errno: "ENOSPC", path: "/data", every: 3 }
+ * ```
+ */
+export type FaultRule = {
+    /** The operation to fail. */
+    syscall: "open" | "read" | "write" | "flush";
+    /** The error the program gets. */
+    errno:
+        | "ENOSPC"
+        | "EIO"
+        | "EACCES"
+        | "ENOENT"
+        | "EEXIST"
+        | "EINTR"
+        | "EAGAIN"
+        | "EPIPE"
+        | "EINVAL"
+        | "ETIMEDOUT"
+        | "ECONNRESET";
+    /**
+     * Only fail files at or under this path. The standard streams are
+     * `/dev/stdin`, `/dev/stdout` and `/dev/stderr`.
+     */
+    path?: string;
+    /** Fail every nth matching call. */
+    every?: number;
+    /** The probability (between 0 and 1) of a matching call failing. */
+    rate?: number;
+    /** Let this many matching calls through before failing any. */
+    after?: number;
+    /** Stop once this many faults have been injected. */
+    count?: number;
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::{AsyncWriteExt, StaticFile};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn every_third_write_fails_until_the_count_runs_out() {
+        let rules = js_sys::JSON::parse(
+            r#"[{ "syscall": "write", "errno": "ENOSPC", "path": "/dev/stdout", "every": 3, "count": 1 }]"#,
+        )
+        .unwrap();
+        let faults = Faults::parse(rules).unwrap();
+        let mut stdout = faults.stdout(Box::new(virtual_fs::Pipe::new()));
+        let mut stderr = faults.stderr(Box::new(StaticFile::new(Vec::new())));
+
+        let mut results = Vec::new();
+        for _ in 0..6 {
+            results.push(stdout.write(b"x").await.map_err(|e| e.kind()));
+        }
+        assert_eq!(
+            results,
+            [
+                Ok(1),
+                Ok(1),
+                Err(io::ErrorKind::WriteZero),
+                Ok(1),
+                Ok(1),
+                Ok(1)
+            ]
+        );
+        assert!(stderr.flush().await.is_ok());
+
+        let invalid =
+            js_sys::JSON::parse(r#"[{ "syscall": "read", "errno": "EIO", "every": 0 }]"#).unwrap();
+        assert!(Faults::parse(invalid).is_err());
+    }
+}
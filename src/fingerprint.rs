@@ -0,0 +1,372 @@
+//! A short probe of the environment the SDK runs in, saved so later page
+//! loads can tune their defaults without measuring everything again.
+//!
+//! The first time the SDK is loaded in a browser profile we time how long a
+//! worker takes to start, how quickly the browser compiles WebAssembly, and
+//! check which storage APIs exist. The results are saved in IndexedDB and
+//! reused until the SDK is upgraded or the machine changes.
+
+use std::{cell::RefCell, num::NonZeroUsize, sync::Mutex};
+
+use futures::{
+    future::{LocalBoxFuture, Shared},
+    FutureExt,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::IdbTransactionMode;
+
+use crate::utils::{Error, GlobalScope};
+
+const DB_NAME: &str = "wasmer-fingerprint";
+const STORE_NAME: &str = "fingerprint";
+const KEY: &str = "current";
+
+/// Starting a worker slower than this (in milliseconds) means each extra
+/// worker is expensive, so the pool aims for fewer of them.
+const SLOW_WORKER_SPAWN_MS: f64 = 250.0;
+/// How long restoring the persistent module cache may take at startup, in
+/// milliseconds of compilation.
+const RESTORE_BUDGET_MS: f64 = 500.0;
+
+/// The defaults used when nothing has been measured.
+const DEFAULT_STREAM_CHUNK_SIZE: u32 = 64 * 1024;
+const DEFAULT_RESTORE_BUDGET: usize = 256 * 1024 * 1024;
+
+/// The fingerprint loaded (or measured) for this session, shared by every
+/// thread.
+static CURRENT: Lazy<Mutex<Option<Fingerprint>>> = Lazy::new(Mutex::default);
+
+thread_local! {
+    static PROBE: RefCell<Option<Shared<LocalBoxFuture<'static, Fingerprint>>>> =
+        RefCell::new(None);
+}
+
+/// What we found out about the environment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Fingerprint {
+    /// The SDK version that took the measurements.
+    sdk_version: String,
+    /// When the measurements were taken, in milliseconds since the Unix epoch.
+    measured_at: f64,
+    hardware_concurrency: Option<usize>,
+    /// How long a worker took to start and answer a handshake.
+    worker_spawn_ms: Option<f64>,
+    /// How many bytes of WebAssembly the browser compiled per millisecond.
+    compile_bytes_per_ms: Option<f64>,
+    indexed_db: bool,
+    storage_manager: bool,
+    cross_origin_isolated: bool,
+}
+
+impl Fingerprint {
+    /// Can a saved fingerprint still be trusted?
+    fn is_current(&self, scope: &GlobalScope) -> bool {
+        self.sdk_version == env!("CARGO_PKG_VERSION")
+            && self.hardware_concurrency == scope.hardware_concurrency().map(|n| n.get())
+    }
+
+    pub(crate) fn tuning(&self) -> Tuning {
+        let threads = self
+            .worker_spawn_ms
+            .filter(|ms| *ms > SLOW_WORKER_SPAWN_MS)
+            .map(|_| {
+                let hardware = self.hardware_concurrency.and_then(NonZeroUsize::new);
+                let default = crate::tasks::default_parallelism(hardware).get();
+                NonZeroUsize::new(default.div_ceil(2)).unwrap()
+            });
+
+        let stream_chunk_size = self
+            .compile_bytes_per_ms
+            .map_or(DEFAULT_STREAM_CHUNK_SIZE, |b| {
+                // Note: compile speed is a decent proxy for how much data the
+                // machine can push through a stream without janking the page
+                let bytes = (b * 2.0).clamp(16.0 * 1024.0, 1024.0 * 1024.0) as u32;
+                bytes.next_power_of_two()
+            });
+
+        let restore_budget = self
+            .compile_bytes_per_ms
+            .map_or(DEFAULT_RESTORE_BUDGET, |b| {
+                let bytes = b * RESTORE_BUDGET_MS;
+                bytes.clamp(16.0 * 1024.0 * 1024.0, DEFAULT_RESTORE_BUDGET as f64) as usize
+            });
+
+        Tuning {
+            threads,
+            stream_chunk_size,
+            restore_budget,
+        }
+    }
+
+    async fn measure(scope: &GlobalScope) -> Self {
+        let worker_spawn_ms = match crate::tasks::time_worker_spawn().await {
+            Ok(ms) => Some(ms),
+            Err(e) => {
+                tracing::debug!(error = &*e.into_anyhow(), "Unable to time a worker spawn");
+                None
+            }
+        };
+
+        Fingerprint {
+            sdk_version: env!("CARGO_PKG_VERSION").to_string(),
+            measured_at: js_sys::Date::now(),
+            hardware_concurrency: scope.hardware_concurrency().map(|n| n.get()),
+            worker_spawn_ms,
+            compile_bytes_per_ms: compile_throughput().await,
+            indexed_db: scope.indexed_db().is_some(),
+            storage_manager: scope.storage().is_some(),
+            cross_origin_isolated: scope.cross_origin_isolated().unwrap_or(false),
+        }
+    }
+}
+
+/// Defaults derived from a [`Fingerprint`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Tuning {
+    /// How many threads a pool should aim for, if not the usual default.
+    pub(crate) threads: Option<NonZeroUsize>,
+    /// The chunk size for byte streams' default readers.
+    pub(crate) stream_chunk_size: u32,
+    /// How many bytes of the persistent module cache to restore at startup.
+    pub(crate) restore_budget: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            threads: None,
+            stream_chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            restore_budget: DEFAULT_RESTORE_BUDGET,
+        }
+    }
+}
+
+/// The defaults to use, given what we know about the environment so far.
+pub(crate) fn tuning() -> Tuning {
+    CURRENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(Fingerprint::tuning)
+        .unwrap_or_default()
+}
+
+/// Load the saved fingerprint, or measure and save a new one, the first time
+/// this is called on a thread.
+pub(crate) fn initialize() -> Shared<LocalBoxFuture<'static, Fingerprint>> {
+    PROBE.with(|probe| {
+        probe
+            .borrow_mut()
+            .get_or_insert_with(|| load_or_measure().boxed_local().shared())
+            .clone()
+    })
+}
+
+async fn load_or_measure() -> Fingerprint {
+    if let Some(existing) = CURRENT.lock().unwrap().clone() {
+        return existing;
+    }
+
+    let scope = GlobalScope::current();
+
+    let fingerprint = match load().await {
+        Ok(Some(saved)) if saved.is_current(&scope) => saved,
+        result => {
+            if let Err(e) = result {
+                tracing::debug!(error = &*e.into_anyhow(), "Unable to load the fingerprint");
+            }
+            let measured = Fingerprint::measure(&scope).await;
+            tracing::debug!(fingerprint = ?measured, "Probed the environment");
+            if let Err(e) = save(&measured).await {
+                tracing::debug!(error = &*e.into_anyhow(), "Unable to save the fingerprint");
+            }
+            measured
+        }
+    };
+
+    *CURRENT.lock().unwrap() = Some(fingerprint.clone());
+    fingerprint
+}
+
+async fn load() -> Result<Option<Fingerprint>, Error> {
+    let db = crate::idb::open(DB_NAME, STORE_NAME).await?;
+    let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readonly)?;
+    let request = store.get(&JsValue::from_str(KEY)).map_err(Error::js)?;
+    let value = crate::idb::complete(&request).await?;
+
+    if value.is_undefined() {
+        return Ok(None);
+    }
+
+    // Note: a fingerprint saved by an older SDK may have a different shape
+    Ok(serde_wasm_bindgen::from_value(value).ok())
+}
+
+async fn save(fingerprint: &Fingerprint) -> Result<(), Error> {
+    let db = crate::idb::open(DB_NAME, STORE_NAME).await?;
+    let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readwrite)?;
+    let value = serde_wasm_bindgen::to_value(fingerprint).map_err(Error::js)?;
+    let request = store
+        .put_with_key(&value, &JsValue::from_str(KEY))
+        .map_err(Error::js)?;
+    crate::idb::complete(&request).await?;
+
+    Ok(())
+}
+
+/// Time how long the browser takes to compile a synthetic module.
+async fn compile_throughput() -> Option<f64> {
+    let wasm = synthetic_module(1024);
+    let bytes = js_sys::Uint8Array::from(&wasm[..]);
+
+    let start = instant::Instant::now();
+    JsFuture::from(js_sys::WebAssembly::compile(&bytes))
+        .await
+        .ok()?;
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    Some(wasm.len() as f64 / elapsed_ms.max(0.1))
+}
+
+/// A module with `functions` functions which each add up a few hundred
+/// constants, so it is mostly code.
+fn synthetic_module(functions: u32) -> Vec<u8> {
+    const ADDS: u32 = 64;
+
+    let mut body = vec![0x00]; // no locals
+    body.extend([0x41, 0x00]); // i32.const 0
+    for i in 0..ADDS {
+        body.extend([0x41, (i % 64) as u8, 0x6a]); // i32.const i; i32.add
+    }
+    body.push(0x0b); // end
+
+    let mut code = leb(functions);
+    for _ in 0..functions {
+        code.extend(leb(body.len() as u32));
+        code.extend(&body);
+    }
+
+    let mut function_section = leb(functions);
+    function_section.extend(std::iter::repeat(0x00).take(functions as usize));
+
+    let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+    // (type (func (result i32)))
+    wasm.extend([0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f]);
+    for (id, section) in [(0x03, function_section), (0x0a, code)] {
+        wasm.push(id);
+        wasm.extend(leb(section.len() as u32));
+        wasm.extend(section);
+    }
+
+    wasm
+}
+
+fn leb(mut value: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Get what the SDK measured about this browser the first time it was
+/// loaded, and the defaults it tuned as a result.
+///
+/// The environment is probed in the background when the SDK starts, so this
+/// waits for that to finish. Runtimes created beforehand use the untuned
+/// defaults, and anything that couldn't be measured is left out.
+#[wasm_bindgen(js_name = "environmentFingerprint")]
+pub async fn environment_fingerprint() -> Result<EnvironmentFingerprint, Error> {
+    let fingerprint = initialize().await;
+
+    let value = serde_wasm_bindgen::to_value(&fingerprint).map_err(Error::js)?;
+    let tuning = serde_wasm_bindgen::to_value(&fingerprint.tuning()).map_err(Error::js)?;
+    js_sys::Reflect::set(&value, &JsValue::from_str("tuning"), &tuning).map_err(Error::js)?;
+    // Note: the host can look, but not change the fingerprint
+    js_sys::Object::freeze(value.unchecked_ref());
+
+    Ok(value.unchecked_into())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const ENVIRONMENT_FINGERPRINT_TYPE_DEF: &'static str = r#"
+/**
+ * What the SDK measured about the browser, returned by
+ * {@link environmentFingerprint}.
+ */
+export type EnvironmentFingerprint = {
+    /** The SDK version that took the measurements. */
+    sdkVersion: string;
+    /** When the measurements were taken, in milliseconds since the epoch. */
+    measuredAt: number;
+    hardwareConcurrency?: number;
+    /** How long a worker took to start, in milliseconds. */
+    workerSpawnMs?: number;
+    /** How many bytes of WebAssembly were compiled per millisecond. */
+    compileBytesPerMs?: number;
+    indexedDb: boolean;
+    storageManager: boolean;
+    crossOriginIsolated: boolean;
+    /** The defaults derived from these measurements. */
+    tuning: {
+        /** The pool size, if different from the usual default. */
+        threads?: number;
+        /** The chunk size (in bytes) used by byte streams. */
+        streamChunkSize: number;
+        /** How much of the persistent module cache is restored at startup. */
+        restoreBudget: number;
+    };
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "EnvironmentFingerprint")]
+    pub type EnvironmentFingerprint;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn slow_environments_get_smaller_defaults() {
+        let wasm = synthetic_module(3);
+        let bytes = js_sys::Uint8Array::from(&wasm[..]);
+        assert!(js_sys::WebAssembly::validate(&bytes).unwrap());
+
+        let fingerprint = Fingerprint {
+            sdk_version: env!("CARGO_PKG_VERSION").to_string(),
+            measured_at: 0.0,
+            hardware_concurrency: Some(8),
+            worker_spawn_ms: Some(400.0),
+            compile_bytes_per_ms: Some(10_000.0),
+            indexed_db: true,
+            storage_manager: true,
+            cross_origin_isolated: true,
+        };
+        let tuning = fingerprint.tuning();
+
+        assert_eq!(tuning.threads, NonZeroUsize::new(4));
+        assert_eq!(tuning.stream_chunk_size, 32 * 1024);
+        assert_eq!(tuning.restore_budget, 16 * 1024 * 1024);
+
+        let fast = Fingerprint {
+            worker_spawn_ms: Some(20.0),
+            ..fingerprint
+        };
+        assert_eq!(fast.tuning().threads, None);
+    }
+}
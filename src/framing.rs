@@ -0,0 +1,302 @@
+//! Sending and receiving discrete messages over a program's stdin and stdout,
+//! for guests which speak a framed protocol (e.g. LSP, DAP, or JSON-RPC).
+
+use std::{cell::RefCell, rc::Rc};
+
+use futures::{stream::LocalBoxStream, StreamExt};
+use js_sys::{Promise, Uint8Array};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    ReadableStream, ReadableStreamDefaultController, WritableStream, WritableStreamDefaultWriter,
+};
+
+use crate::utils::{Error, StringOrBytes};
+
+/// The largest message we are willing to buffer.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How messages are delimited on the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// Each message is preceded by its length as a big-endian `u32`.
+    LengthPrefixed,
+    /// Each message is preceded by `Content-Length` headers, as used by LSP
+    /// and DAP.
+    ContentLength,
+}
+
+impl Framing {
+    pub(crate) fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "length-prefixed" => Ok(Framing::LengthPrefixed),
+            "content-length" => Ok(Framing::ContentLength),
+            other => {
+                let msg = format!(
+                    "Unknown framing, \"{other}\" (expected \"length-prefixed\" or \"content-length\")"
+                );
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+
+    fn encode(self, message: &[u8]) -> Vec<u8> {
+        let mut frame = match self {
+            Framing::LengthPrefixed => (message.len() as u32).to_be_bytes().to_vec(),
+            Framing::ContentLength => {
+                format!("Content-Length: {}\r\n\r\n", message.len()).into_bytes()
+            }
+        };
+        frame.extend_from_slice(message);
+        frame
+    }
+}
+
+/// Splits a byte stream back into the messages it was framed from.
+#[derive(Debug)]
+struct Decoder {
+    framing: Framing,
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    fn new(framing: Framing) -> Self {
+        Decoder {
+            framing,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Add more data, returning any messages that are now complete.
+    fn push(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        self.buffer.extend_from_slice(data);
+
+        let mut messages = Vec::new();
+        while let Some((header_len, body_len)) = self.next_frame()? {
+            let end = header_len + body_len;
+            if self.buffer.len() < end {
+                break;
+            }
+            messages.push(self.buffer[header_len..end].to_vec());
+            self.buffer.drain(..end);
+        }
+
+        Ok(messages)
+    }
+
+    /// The sizes of the next frame's header and body, if the whole header has
+    /// been received.
+    fn next_frame(&self) -> Result<Option<(usize, usize)>, Error> {
+        let (header_len, body_len) = match self.framing {
+            Framing::LengthPrefixed => match self.buffer.get(..4) {
+                Some(prefix) => {
+                    let len = u32::from_be_bytes(prefix.try_into().unwrap());
+                    (4, len as usize)
+                }
+                None => return Ok(None),
+            },
+            Framing::ContentLength => {
+                let Some(end) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    return Ok(None);
+                };
+                let headers = std::str::from_utf8(&self.buffer[..end])?;
+                (end + 4, content_length(headers)?)
+            }
+        };
+
+        if body_len > MAX_MESSAGE_SIZE {
+            return Err(anyhow::anyhow!(
+                "A {body_len} byte message is larger than the {MAX_MESSAGE_SIZE} byte limit"
+            )
+            .into());
+        }
+
+        Ok(Some((header_len, body_len)))
+    }
+}
+
+fn content_length(headers: &str) -> Result<usize, Error> {
+    for line in headers.split("\r\n") {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                return Ok(value.trim().parse()?);
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!("The message has no Content-Length header: {headers:?}").into())
+}
+
+/// Frames messages and writes them to a program's stdin.
+#[derive(Debug, Clone)]
+pub(crate) struct FrameWriter {
+    framing: Framing,
+    writer: WritableStreamDefaultWriter,
+}
+
+impl FrameWriter {
+    /// Take over `stdin`, locking the stream.
+    pub(crate) fn new(framing: Framing, stdin: &WritableStream) -> Result<Self, Error> {
+        let writer = stdin.get_writer().map_err(Error::js)?;
+        Ok(FrameWriter { framing, writer })
+    }
+
+    /// Wait until the stream's queue has room for more messages.
+    pub(crate) async fn ready(&self) -> Result<(), Error> {
+        JsFuture::from(self.writer.ready())
+            .await
+            .map_err(Error::js)?;
+        Ok(())
+    }
+
+    pub(crate) async fn send(&self, message: &[u8]) -> Result<(), Error> {
+        let frame = self.framing.encode(message);
+        let chunk = Uint8Array::from(&frame[..]);
+        JsFuture::from(self.writer.write_with_chunk(&chunk))
+            .await
+            .map_err(Error::js)?;
+        Ok(())
+    }
+
+    pub(crate) async fn close(&self) -> Result<(), Error> {
+        JsFuture::from(self.writer.close())
+            .await
+            .map_err(Error::js)?;
+        Ok(())
+    }
+}
+
+/// Read `stdout`, splitting it into the messages it was framed from.
+pub(crate) fn decode(
+    framing: Framing,
+    stdout: ReadableStream,
+) -> LocalBoxStream<'static, Result<Vec<u8>, Error>> {
+    let mut decoder = Decoder::new(framing);
+
+    crate::streams::read_to_end(stdout)
+        .map(
+            move |chunk| match chunk.and_then(|chunk| decoder.push(&chunk)) {
+                Ok(messages) => futures::stream::iter(messages.into_iter().map(Ok)).left_stream(),
+                Err(e) => futures::stream::iter([Err(e)]).right_stream(),
+            },
+        )
+        .flatten()
+        .boxed_local()
+}
+
+/// Discrete messages sent to a program's stdin and received from its stdout.
+#[derive(Debug)]
+#[wasm_bindgen]
+pub struct FramedStdio {
+    writer: FrameWriter,
+    /// Every complete message the program writes to stdout.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub messages: ReadableStream,
+}
+
+impl FramedStdio {
+    /// Take over `stdin` and `stdout`, locking both streams.
+    pub(crate) fn new(
+        framing: Framing,
+        stdin: &WritableStream,
+        stdout: ReadableStream,
+    ) -> Result<Self, Error> {
+        let writer = FrameWriter::new(framing, stdin)?;
+
+        let source = JsValue::from(MessageSource {
+            messages: Rc::new(RefCell::new(Some(decode(framing, stdout)))),
+        });
+        let messages = ReadableStream::new_with_underlying_source(source.unchecked_ref())
+            .map_err(Error::js)?;
+
+        Ok(FramedStdio { writer, messages })
+    }
+}
+
+#[wasm_bindgen]
+impl FramedStdio {
+    /// Send a message to the program. Strings are encoded as UTF-8.
+    pub async fn send(&self, message: StringOrBytes) -> Result<(), Error> {
+        self.writer.send(&message.as_bytes()).await
+    }
+
+    /// Close stdin once every message has been sent.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.writer.close().await
+    }
+}
+
+#[wasm_bindgen(skip_typescript)]
+struct MessageSource {
+    messages: Rc<RefCell<Option<LocalBoxStream<'static, Result<Vec<u8>, Error>>>>>,
+}
+
+#[wasm_bindgen]
+impl MessageSource {
+    pub fn pull(&mut self, controller: ReadableStreamDefaultController) -> Promise {
+        let slot = Rc::clone(&self.messages);
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            // Note: the stream won't call pull() again until this promise
+            // resolves, so nobody else can be using the messages.
+            let Some(mut messages) = slot.borrow_mut().take() else {
+                return Ok(JsValue::UNDEFINED);
+            };
+
+            match messages.next().await {
+                Some(Ok(message)) => {
+                    controller.enqueue_with_chunk(&Uint8Array::from(&message[..]))?;
+                    *slot.borrow_mut() = Some(messages);
+                }
+                Some(Err(e)) => controller.error_with_e(&JsValue::from(e)),
+                None => controller.close()?,
+            }
+
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    pub fn cancel(&mut self) {
+        self.messages.borrow_mut().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn decode_messages_split_across_chunks() {
+        for framing in [Framing::LengthPrefixed, Framing::ContentLength] {
+            let mut wire = framing.encode(b"{\"id\":1}");
+            wire.extend(framing.encode(b""));
+            wire.extend(framing.encode(b"{\"id\":2}"));
+            let mut decoder = Decoder::new(framing);
+
+            let mut messages = Vec::new();
+            for chunk in wire.chunks(3) {
+                messages.extend(decoder.push(chunk).unwrap());
+            }
+
+            assert_eq!(
+                messages,
+                [b"{\"id\":1}".to_vec(), Vec::new(), b"{\"id\":2}".to_vec()],
+                "{framing:?}"
+            );
+            assert!(decoder.buffer.is_empty());
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn content_length_headers_are_case_insensitive() {
+        let mut decoder = Decoder::new(Framing::ContentLength);
+
+        let messages = decoder
+            .push(b"content-length: 2\r\nContent-Type: application/json\r\n\r\n{}")
+            .unwrap();
+
+        assert_eq!(messages, [b"{}".to_vec()]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Timestamps and permissions set from JavaScript.
+//!
+//! None of the filesystems a [`crate::Directory`] can wrap let us change a
+//! file's timestamps or mode, so they are kept to one side and layered over
+//! whatever the filesystem reports. That is enough for tools like `make`,
+//! which only care about what `stat()` says.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use virtual_fs::{Metadata, OpenOptionsConfig};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+use crate::utils::Error;
+
+/// The mode reported for directories nobody has `chmod`-ed.
+const DEFAULT_DIR_MODE: u32 = 0o755;
+/// The mode reported for files nobody has `chmod`-ed.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+/// The owner's write permission.
+const OWNER_WRITE: u32 = 0o200;
+
+/// Overrides for a single path.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct Overrides {
+    mode: Option<u32>,
+    /// Nanoseconds since the Unix epoch.
+    accessed: Option<u64>,
+    /// Nanoseconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+/// Timestamps and modes set on paths in a directory, shared by every clone of
+/// that directory.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AttributeOverlay(Arc<Mutex<BTreeMap<PathBuf, Overrides>>>);
+
+impl AttributeOverlay {
+    pub(crate) fn set_mode(&self, path: &Path, mode: u32) {
+        let mut overrides = self.0.lock().unwrap();
+        overrides.entry(path.to_path_buf()).or_default().mode = Some(mode & 0o7777);
+    }
+
+    pub(crate) fn set_times(&self, path: &Path, accessed: u64, modified: u64) {
+        let mut overrides = self.0.lock().unwrap();
+        let entry = overrides.entry(path.to_path_buf()).or_default();
+        entry.accessed = Some(accessed);
+        entry.modified = Some(modified);
+    }
+
+    /// The path's permission bits.
+    pub(crate) fn mode(&self, path: &Path, metadata: &Metadata) -> u32 {
+        let mode = self.0.lock().unwrap().get(path).and_then(|o| o.mode);
+        mode.unwrap_or(if metadata.is_dir() {
+            DEFAULT_DIR_MODE
+        } else {
+            DEFAULT_FILE_MODE
+        })
+    }
+
+    /// Apply any timestamps set on `path` to what the filesystem reported.
+    pub(crate) fn apply(&self, path: &Path, mut metadata: Metadata) -> Metadata {
+        if let Some(overrides) = self.0.lock().unwrap().get(path) {
+            metadata.accessed = overrides.accessed.unwrap_or(metadata.accessed);
+            metadata.modified = overrides.modified.unwrap_or(metadata.modified);
+        }
+        metadata
+    }
+
+    /// Check whether a file may be opened with `conf`, forgetting its
+    /// timestamps if it is about to be modified so the filesystem's own ones
+    /// show through (otherwise `make` would think it was still up to date).
+    pub(crate) fn check_open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<()> {
+        if !(conf.write || conf.append || conf.truncate) {
+            return Ok(());
+        }
+
+        let mut overrides = self.0.lock().unwrap();
+        let Some(entry) = overrides.get_mut(path) else {
+            return Ok(());
+        };
+
+        if entry.mode.is_some_and(|mode| mode & OWNER_WRITE == 0) {
+            return Err(virtual_fs::FsError::PermissionDenied);
+        }
+        entry.accessed = None;
+        entry.modified = None;
+
+        Ok(())
+    }
+
+    /// Forget everything set on `path` and anything inside it.
+    pub(crate) fn remove(&self, path: &Path) {
+        self.0.lock().unwrap().retain(|p, _| !p.starts_with(path));
+    }
+
+    /// Move everything set on `from` (and anything inside it) to `to`.
+    pub(crate) fn rename(&self, from: &Path, to: &Path) {
+        let mut overrides = self.0.lock().unwrap();
+        let moved: Vec<PathBuf> = overrides
+            .keys()
+            .filter(|p| p.starts_with(from))
+            .cloned()
+            .collect();
+
+        for old in moved {
+            if let Some(entry) = overrides.remove(&old) {
+                let relative = old.strip_prefix(from).unwrap_or(Path::new(""));
+                overrides.insert(to.join(relative), entry);
+            }
+        }
+    }
+}
+
+/// Convert a `Date` or a number of milliseconds since the Unix epoch into
+/// nanoseconds.
+pub(crate) fn parse_timestamp(value: &Timestamp) -> Result<u64, Error> {
+    let ms = match value.dyn_ref::<js_sys::Date>() {
+        Some(date) => date.get_time(),
+        None => value.as_f64().unwrap_or(f64::NAN),
+    };
+
+    if !ms.is_finite() || ms < 0.0 {
+        let msg = "Timestamps must be a Date or a non-negative number of milliseconds";
+        return Err(Error::js(js_sys::TypeError::new(msg)));
+    }
+
+    Ok((ms * 1_000_000.0) as u64)
+}
+
+/// Convert nanoseconds since the Unix epoch into a `Date`.
+pub(crate) fn to_date(nanos: u64) -> js_sys::Date {
+    js_sys::Date::new(&JsValue::from_f64(nanos as f64 / 1_000_000.0))
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const FILE_STAT_TYPE_DEF: &'static str = r#"
+/**
+ * Everything known about an entry in a {@link Directory}.
+ */
+export type FileStat = {
+    type: "file" | "dir" | "unknown";
+    /** The file's length in bytes. */
+    size: number;
+    /**
+     * Permission bits (e.g. `0o644`), as set by {@link Directory.chmod}.
+     *
+     * Clearing the owner's write bit makes the file read-only to programs.
+     */
+    mode: number;
+    accessed: Date;
+    modified: Date;
+    created: Date;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Date | number")]
+    pub type Timestamp;
+
+    #[wasm_bindgen(typescript_type = "FileStat")]
+    pub type FileStat;
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::FileSystem;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn overrides_follow_the_file_until_it_is_written() {
+        let fs = virtual_fs::mem_fs::FileSystem::default();
+        let overlay = AttributeOverlay::default();
+        let path = Path::new("/Makefile");
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+
+        overlay.set_times(path, 1_000, 2_000);
+        overlay.set_mode(path, 0o100444);
+        let metadata = overlay.apply(path, fs.metadata(path).unwrap());
+        assert_eq!(metadata.modified, 2_000);
+        assert_eq!(overlay.mode(path, &metadata), 0o444);
+
+        let conf = OpenOptionsConfig {
+            read: false,
+            write: true,
+            create_new: false,
+            create: false,
+            append: false,
+            truncate: false,
+        };
+        assert!(overlay.check_open(path, &conf).is_err());
+
+        overlay.set_mode(path, 0o644);
+        overlay.check_open(path, &conf).unwrap();
+        let metadata = overlay.apply(path, fs.metadata(path).unwrap());
+        assert_ne!(metadata.modified, 2_000);
+
+        overlay.rename(path, Path::new("/GNUmakefile"));
+        assert_eq!(overlay.mode(Path::new("/GNUmakefile"), &metadata), 0o644);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use virtual_fs::{
+    DirEntry, FileSystem, FileType, FsError, Metadata, OpenOptionsConfig, ReadDir, VirtualFile,
+};
+
+/// A read-only [`FileSystem`] containing a fixed set of device files.
+///
+/// Guests can't create, rename, or remove entries, but opening a device will
+/// give them a handle connected to the host. Devices may be nested (e.g.
+/// `self/stat`), in which case their parent directories exist implicitly.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeviceFileSystem {
+    devices: Arc<Mutex<BTreeMap<PathBuf, Device>>>,
+}
+
+/// A file exposed by a [`DeviceFileSystem`].
+#[derive(Debug, Clone)]
+pub(crate) enum Device {
+    /// A file with fixed contents.
+    Static(Vec<u8>),
+    /// A file whose contents are generated every time it is opened.
+    Generated(Generator),
+    /// A character device which gives every open handle its own file.
+    Opener(Opener),
+}
+
+/// Produces the contents of a [`Device::Generated`] file.
+#[derive(Clone, derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct Generator(#[derivative(Debug = "ignore")] Arc<dyn Fn() -> Vec<u8> + Send + Sync>);
+
+impl Generator {
+    pub(crate) fn new(generate: impl Fn() -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Generator(Arc::new(generate))
+    }
+
+    fn generate(&self) -> Vec<u8> {
+        (self.0)()
+    }
+}
+
+/// Creates the handle for each open [`Device::Opener`].
+#[derive(Clone, derivative::Derivative)]
+#[derivative(Debug)]
+#[allow(clippy::type_complexity)]
+pub(crate) struct Opener(
+    #[derivative(Debug = "ignore")]
+    Arc<dyn Fn() -> Box<dyn VirtualFile + Send + Sync + 'static> + Send + Sync>,
+);
+
+impl Opener {
+    pub(crate) fn new(
+        open: impl Fn() -> Box<dyn VirtualFile + Send + Sync + 'static> + Send + Sync + 'static,
+    ) -> Self {
+        Opener(Arc::new(open))
+    }
+}
+
+impl DeviceFileSystem {
+    /// Add (or replace) a device, relative to the root of the filesystem.
+    pub(crate) fn insert(&self, name: &str, device: Device) {
+        let path = Path::new("/").join(name);
+        self.devices.lock().unwrap().insert(path, device);
+    }
+
+    /// Remove a device, returning `true` if it existed.
+    pub(crate) fn remove(&self, name: &str) -> bool {
+        let path = Path::new("/").join(name);
+        self.devices.lock().unwrap().remove(&path).is_some()
+    }
+
+    fn entry(&self, path: &Path) -> Option<Device> {
+        self.devices.lock().unwrap().get(path).cloned()
+    }
+
+    /// Is `path` the parent of a nested device?
+    fn is_dir(&self, path: &Path) -> bool {
+        self.devices
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|device| device != path && device.starts_with(path))
+    }
+
+    fn dir_metadata() -> Metadata {
+        Metadata {
+            ft: FileType {
+                dir: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn device_metadata(device: &Device) -> Metadata {
+        match device {
+            Device::Opener(_) => Metadata {
+                ft: FileType {
+                    char_device: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Device::Static(contents) => Metadata {
+                ft: FileType {
+                    file: true,
+                    ..Default::default()
+                },
+                len: contents.len() as u64,
+                ..Default::default()
+            },
+            Device::Generated(generator) => Metadata {
+                ft: FileType {
+                    file: true,
+                    ..Default::default()
+                },
+                len: generator.generate().len() as u64,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn is_root(path: &Path) -> bool {
+    path == Path::new("/") || path == Path::new("")
+}
+
+impl FileSystem for DeviceFileSystem {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        if !is_root(path) && !self.is_dir(path) {
+            return Err(FsError::EntryNotFound);
+        }
+
+        let dir = Path::new("/").join(path);
+        let mut children = BTreeMap::new();
+
+        for (device_path, device) in self.devices.lock().unwrap().iter() {
+            let Ok(relative) = device_path.strip_prefix(&dir) else {
+                continue;
+            };
+            let mut components = relative.components();
+            let Some(first) = components.next() else {
+                continue;
+            };
+
+            let metadata = if components.next().is_some() {
+                DeviceFileSystem::dir_metadata()
+            } else {
+                DeviceFileSystem::device_metadata(device)
+            };
+            children.entry(dir.join(first)).or_insert(metadata);
+        }
+
+        let entries = children
+            .into_iter()
+            .map(|(path, metadata)| DirEntry {
+                path,
+                metadata: Ok(metadata),
+            })
+            .collect();
+
+        Ok(ReadDir::new(entries))
+    }
+
+    fn create_dir(&self, _path: &Path) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn remove_dir(&self, _path: &Path) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rename<'a>(
+        &'a self,
+        _from: &'a Path,
+        _to: &'a Path,
+    ) -> futures::future::BoxFuture<'a, virtual_fs::Result<()>> {
+        Box::pin(async { Err(FsError::PermissionDenied) })
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        if is_root(path) || self.is_dir(path) {
+            return Ok(DeviceFileSystem::dir_metadata());
+        }
+
+        self.entry(path)
+            .map(|device| DeviceFileSystem::device_metadata(&device))
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn remove_file(&self, _path: &Path) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for DeviceFileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        _conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        match self.entry(path) {
+            Some(Device::Static(contents)) => Ok(Box::new(virtual_fs::StaticFile::new(contents))),
+            Some(Device::Generated(generator)) => {
+                Ok(Box::new(virtual_fs::StaticFile::new(generator.generate())))
+            }
+            Some(Device::Opener(opener)) => Ok((opener.0)()),
+            None => Err(FsError::EntryNotFound),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Listing a directory a batch at a time, so huge directories (think
+//! `node_modules`) don't freeze the page while they are converted into
+//! JavaScript objects.
+
+use js_sys::{Array, Object, Promise, Reflect};
+use virtual_fs::{FileType, ReadDir};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{ReadableStream, ReadableStreamDefaultController};
+
+use crate::utils::{Error, GlobalScope};
+
+/// How many entries go in each batch unless the caller says otherwise.
+///
+/// Long-running filesystem operations also yield to the event loop this
+/// often.
+pub(crate) const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// Give the browser a chance to render and handle input before carrying on.
+pub(crate) async fn yield_to_event_loop() {
+    let _ = JsFuture::from(GlobalScope::current().sleep(0)).await;
+}
+
+/// Convert a directory entry into a `DirEntry` object.
+pub(crate) fn to_js(entry: &virtual_fs::DirEntry) -> Result<JsValue, Error> {
+    let name = entry.file_name().to_string_lossy().to_string();
+    let ty = match entry.file_type() {
+        Ok(FileType { dir: true, .. }) => "dir",
+        Ok(FileType { file: true, .. }) => "file",
+        _ => "unknown",
+    };
+
+    let obj = Object::new();
+    Reflect::set(&obj, &JsValue::from_str("name"), &JsValue::from(name)).map_err(Error::js)?;
+    Reflect::set(&obj, &JsValue::from_str("type"), &JsValue::from_str(ty)).map_err(Error::js)?;
+
+    Ok(obj.into())
+}
+
+/// Stream a directory's entries as arrays of at most `batch_size` entries.
+pub(crate) fn batches(entries: ReadDir, batch_size: usize) -> DirEntryBatches {
+    let source = JsValue::from(DirEntriesSource {
+        entries: Some(entries),
+        batch_size,
+    });
+
+    // Note: The default strategy buffers a single batch, so we only convert
+    // the next batch once the previous one has been read.
+    ReadableStream::new_with_underlying_source(source.unchecked_ref())
+        .unwrap()
+        .unchecked_into()
+}
+
+#[derive(Debug)]
+#[wasm_bindgen(skip_typescript)]
+struct DirEntriesSource {
+    /// `None` once the stream has been cancelled.
+    entries: Option<ReadDir>,
+    batch_size: usize,
+}
+
+#[wasm_bindgen]
+impl DirEntriesSource {
+    /// Enqueue the next batch, then wait for the event loop to come around
+    /// again before the stream may ask for another one.
+    pub fn pull(&mut self, controller: ReadableStreamDefaultController) -> Result<Promise, Error> {
+        let batch = Array::new();
+        if let Some(entries) = self.entries.as_mut() {
+            for entry in entries.by_ref().take(self.batch_size) {
+                batch.push(&to_js(&entry?)?);
+            }
+        }
+
+        if batch.length() == 0 {
+            self.entries = None;
+            controller.close().map_err(Error::js)?;
+            return Ok(Promise::resolve(&JsValue::UNDEFINED));
+        }
+
+        controller.enqueue_with_chunk(&batch).map_err(Error::js)?;
+
+        Ok(GlobalScope::current().sleep(0))
+    }
+
+    /// Called when the consumer stops early (e.g. by breaking out of a
+    /// `for await` loop), dropping whatever we haven't listed yet.
+    pub fn cancel(&mut self) {
+        tracing::debug!("Directory listing cancelled");
+        self.entries = None;
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const ENTRIES_OPTIONS_TYPE_DEF: &'static str = r#"
+/**
+ * Options for {@link Directory.entries}.
+ */
+export type EntriesOptions = {
+    /** The maximum number of entries in each batch. Defaults to 256. */
+    batchSize?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "EntriesOptions")]
+    pub type EntriesOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "batchSize")]
+    fn batch_size(this: &EntriesOptions) -> Option<f64>;
+
+    #[wasm_bindgen(typescript_type = "ReadableStream<DirEntry[]>", extends = ReadableStream)]
+    pub type DirEntryBatches;
+}
+
+impl EntriesOptions {
+    pub(crate) fn parse_batch_size(&self) -> Result<usize, Error> {
+        match self.batch_size() {
+            None => Ok(DEFAULT_BATCH_SIZE),
+            Some(n) if n >= 1.0 && n.fract() == 0.0 && n <= u32::MAX as f64 => Ok(n as usize),
+            Some(n) => {
+                let msg = format!("The batch size must be a positive integer, not {n}");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::FileSystem;
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use web_sys::ReadableStreamDefaultReader;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn entries_are_listed_in_batches() {
+        let fs = virtual_fs::mem_fs::FileSystem::default();
+        for i in 0..5 {
+            fs.create_dir(format!("/{i}").as_ref()).unwrap();
+        }
+
+        let stream = batches(fs.read_dir("/".as_ref()).unwrap(), 2);
+        let reader = ReadableStreamDefaultReader::new(&stream).unwrap();
+        let read = || async {
+            let result = JsFuture::from(reader.read()).await.unwrap();
+            Reflect::get(&result, &JsValue::from_str("value")).unwrap()
+        };
+
+        let first: Array = read().await.unchecked_into();
+        assert_eq!(first.length(), 2);
+        let entry = first.get(0);
+        let ty = Reflect::get(&entry, &JsValue::from_str("type")).unwrap();
+        assert_eq!(ty.as_string().as_deref(), Some("dir"));
+
+        JsFuture::from(reader.cancel()).await.unwrap();
+        assert!(read().await.is_undefined());
+    }
+}
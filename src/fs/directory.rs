@@ -1,21 +1,31 @@
 use std::{
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::Context;
 use js_sys::Reflect;
 use tracing::Instrument;
-use virtual_fs::{AsyncReadExt, AsyncWriteExt, FileSystem, FileType};
+use virtual_fs::{AsyncReadExt, AsyncWriteExt, FileSystem};
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
-use wasmer_wasix::runtime::task_manager::InlineWaker;
 
-use crate::{utils::Error, StringOrBytes};
+use crate::{
+    fs::{
+        attributes::{self, AttributeOverlay, FileStat, Timestamp},
+        dir_entries::{self, DirEntryBatches, EntriesOptions},
+        snapshot::{DirectorySnapshot, JsDirectoryDiff, MaybeSnapshot},
+    },
+    utils::Error,
+    StringOrBytes,
+};
 
 /// A directory that can be mounted inside a WASIX instance.
 #[derive(Debug, Clone, wasm_bindgen_derive::TryFromJsValue)]
 #[wasm_bindgen]
-pub struct Directory(Arc<dyn FileSystem>);
+pub struct Directory {
+    fs: Arc<dyn FileSystem>,
+    attributes: AttributeOverlay,
+}
 
 #[wasm_bindgen]
 impl Directory {
@@ -25,7 +35,7 @@ impl Directory {
         match init {
             Some(init) => {
                 let fs = init.initialize()?;
-                Ok(Directory(fs))
+                Ok(Directory::from_arc(fs))
             }
             None => Ok(Directory::default()),
         }
@@ -40,30 +50,41 @@ impl Directory {
 
         let contents = js_sys::Array::new();
 
-        let ty = JsValue::from_str("type");
-        let file = JsValue::from_str("file");
-        let dir = JsValue::from_str("dir");
-        let unknown = JsValue::from_str("unknown");
-        let name = JsValue::from_str("name");
-
         for entry in FileSystem::read_dir(self, path.as_ref())? {
-            let entry = entry?;
-
-            let entry_name = entry.file_name().to_string_lossy().to_string();
-            let entry_type = match entry.file_type() {
-                Ok(FileType { dir: true, .. }) => &dir,
-                Ok(FileType { file: true, .. }) => &file,
-                _ => &unknown,
-            };
+            contents.push(&dir_entries::to_js(&entry?)?);
+        }
 
-            let dir_entry = js_sys::Object::new();
-            Reflect::set(&dir_entry, &name, &JsValue::from(entry_name)).map_err(Error::js)?;
-            Reflect::set(&dir_entry, &ty, entry_type).map_err(Error::js)?;
+        Ok(contents.unchecked_into())
+    }
 
-            contents.push(&dir_entry);
+    /// Read the contents of a directory in batches.
+    ///
+    /// Unlike {@link Directory.readDir}, this won't freeze the page on huge
+    /// directories because each batch is only converted when it is read. Stop
+    /// early by breaking out of the loop or calling `cancel()`.
+    ///
+    /// @example
+    /// ```ts
+    /// for await (const batch of dir.entries("/node_modules")) {
+    ///     for (const entry of batch) { ... }
+    /// }
+    /// ```
+    pub fn entries(
+        &self,
+        mut path: String,
+        options: Option<EntriesOptions>,
+    ) -> Result<DirEntryBatches, Error> {
+        if !path.starts_with('/') {
+            path.insert(0, '/');
         }
 
-        Ok(contents.unchecked_into())
+        let batch_size = match &options {
+            Some(options) => options.parse_batch_size()?,
+            None => dir_entries::DEFAULT_BATCH_SIZE,
+        };
+        let entries = FileSystem::read_dir(self, path.as_ref())?;
+
+        Ok(dir_entries::batches(entries, batch_size))
     }
 
     /// Write to a file.
@@ -141,10 +162,158 @@ impl Directory {
 
         Ok(())
     }
+
+    /// Copy a file, or a directory and everything inside it, to another
+    /// location in this directory.
+    ///
+    /// The contents are copied inside the filesystem and never pass through
+    /// JavaScript. Existing files at the destination are overwritten.
+    pub async fn copy(&self, mut src: String, mut dst: String) -> Result<(), Error> {
+        if !src.starts_with('/') {
+            src.insert(0, '/');
+        }
+        if !dst.starts_with('/') {
+            dst.insert(0, '/');
+        }
+
+        copy_tree(self, Path::new(&src), Path::new(&dst)).await?;
+
+        Ok(())
+    }
+
+    /// Get a file or directory's type, size, permissions, and timestamps.
+    pub async fn stat(&self, mut path: String) -> Result<FileStat, Error> {
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+
+        let metadata = FileSystem::metadata(self, path.as_ref())?;
+        let ty = if metadata.is_dir() {
+            "dir"
+        } else if metadata.is_file() {
+            "file"
+        } else {
+            "unknown"
+        };
+        let mode = self.attributes.mode(path.as_ref(), &metadata);
+
+        let stat = js_sys::Object::new();
+        let fields: [(&str, JsValue); 6] = [
+            ("type", ty.into()),
+            ("size", (metadata.len() as f64).into()),
+            ("mode", mode.into()),
+            ("accessed", attributes::to_date(metadata.accessed).into()),
+            ("modified", attributes::to_date(metadata.modified).into()),
+            ("created", attributes::to_date(metadata.created).into()),
+        ];
+        for (key, value) in fields {
+            Reflect::set(&stat, &JsValue::from_str(key), &value).map_err(Error::js)?;
+        }
+
+        Ok(stat.unchecked_into())
+    }
+
+    /// Change a file or directory's permission bits (e.g. `0o755`).
+    ///
+    /// Programs will get `EACCES` when opening a file for writing if its
+    /// owner write bit (`0o200`) is cleared.
+    pub async fn chmod(&self, mut path: String, mode: u32) -> Result<(), Error> {
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+
+        FileSystem::metadata(self, path.as_ref())?;
+        self.attributes.set_mode(path.as_ref(), mode);
+
+        Ok(())
+    }
+
+    /// Set a file or directory's access and modification times, either as a
+    /// `Date` or as milliseconds since the Unix epoch (like `Date.now()`).
+    ///
+    /// The times stick until a program (or {@link Directory.writeFile})
+    /// modifies the file, at which point it is considered modified "now".
+    pub async fn utimes(
+        &self,
+        mut path: String,
+        accessed: Timestamp,
+        modified: Timestamp,
+    ) -> Result<(), Error> {
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+
+        let accessed = attributes::parse_timestamp(&accessed)?;
+        let modified = attributes::parse_timestamp(&modified)?;
+        FileSystem::metadata(self, path.as_ref())?;
+        self.attributes.set_times(path.as_ref(), accessed, modified);
+
+        Ok(())
+    }
+
+    /// Record every file's size and contents, so what changed can be worked
+    /// out later with {@link Directory.diff}, or the directory can be put
+    /// back with {@link Directory.restore}.
+    ///
+    /// Passing an earlier snapshot of the same directory skips re-reading
+    /// files whose size and modification time haven't changed since.
+    ///
+    /// Contents are shared between snapshots, so keeping several of them
+    /// around only costs memory for the files that actually changed. Call
+    /// `free()` on snapshots that are no longer needed.
+    pub async fn snapshot(
+        &self,
+        previous: Option<MaybeSnapshot>,
+    ) -> Result<DirectorySnapshot, Error> {
+        let previous = match previous.map(JsValue::from) {
+            Some(value) if !value.is_undefined() => Some(
+                DirectorySnapshot::try_from(&value)
+                    .map_err(|_| anyhow::anyhow!("Expected a DirectorySnapshot"))?,
+            ),
+            _ => None,
+        };
+
+        DirectorySnapshot::capture(self, previous.as_ref()).await
+    }
+
+    /// Put the directory back the way it was when `snapshot` was taken,
+    /// removing anything added since and rewriting files that changed.
+    pub async fn restore(&self, snapshot: &DirectorySnapshot) -> Result<(), Error> {
+        let now = DirectorySnapshot::capture(self, Some(snapshot)).await?;
+        snapshot.restore(self, &now).await
+    }
+
+    /// Compare two snapshots, listing the paths that were added, removed, or
+    /// modified in between.
+    ///
+    /// @example
+    /// ```ts
+    /// const before = await dir.snapshot();
+    /// await cmd.run({ mount: { "/project": dir } }).then(i => i.wait());
+    /// const { added, removed, modified } = Directory.diff(before, await dir.snapshot(before));
+    /// ```
+    pub fn diff(
+        before: &DirectorySnapshot,
+        after: &DirectorySnapshot,
+    ) -> Result<JsDirectoryDiff, Error> {
+        DirectorySnapshot::diff(before, after).to_js()
+    }
 }
 
 impl Directory {
-    async fn _read_file(&self, mut path: String) -> Result<Vec<u8>, Error> {
+    /// Wrap an existing [`FileSystem`] so it can be handed out to JavaScript.
+    pub(crate) fn from_filesystem(fs: impl FileSystem) -> Self {
+        Directory::from_arc(Arc::new(fs))
+    }
+
+    fn from_arc(fs: Arc<dyn FileSystem>) -> Self {
+        Directory {
+            fs,
+            attributes: AttributeOverlay::default(),
+        }
+    }
+
+    pub(crate) async fn _read_file(&self, mut path: String) -> Result<Vec<u8>, Error> {
         if !path.starts_with('/') {
             path.insert(0, '/');
         }
@@ -159,24 +328,26 @@ impl Directory {
 
 impl Default for Directory {
     fn default() -> Self {
-        Directory(Arc::new(virtual_fs::mem_fs::FileSystem::default()))
+        Directory::from_filesystem(virtual_fs::mem_fs::FileSystem::default())
     }
 }
 
 impl FileSystem for Directory {
     #[tracing::instrument(level = "trace", skip(self))]
     fn read_dir(&self, path: &std::path::Path) -> virtual_fs::Result<virtual_fs::ReadDir> {
-        self.0.read_dir(path)
+        self.fs.read_dir(path)
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
     fn create_dir(&self, path: &std::path::Path) -> virtual_fs::Result<()> {
-        self.0.create_dir(path)
+        self.fs.create_dir(path)
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
     fn remove_dir(&self, path: &std::path::Path) -> virtual_fs::Result<()> {
-        self.0.remove_dir(path)
+        self.fs.remove_dir(path)?;
+        self.attributes.remove(path);
+        Ok(())
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
@@ -185,17 +356,33 @@ impl FileSystem for Directory {
         from: &'a std::path::Path,
         to: &'a std::path::Path,
     ) -> futures::future::BoxFuture<'a, virtual_fs::Result<()>> {
-        Box::pin(self.0.rename(from, to).in_current_span())
+        Box::pin(
+            async move {
+                self.fs.rename(from, to).await?;
+                self.attributes.rename(from, to);
+                Ok(())
+            }
+            .in_current_span(),
+        )
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
     fn metadata(&self, path: &std::path::Path) -> virtual_fs::Result<virtual_fs::Metadata> {
-        self.0.metadata(path)
+        let metadata = self.fs.metadata(path)?;
+        Ok(self.attributes.apply(path, metadata))
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn symlink_metadata(&self, path: &std::path::Path) -> virtual_fs::Result<virtual_fs::Metadata> {
+        let metadata = self.fs.symlink_metadata(path)?;
+        Ok(self.attributes.apply(path, metadata))
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
     fn remove_file(&self, path: &std::path::Path) -> virtual_fs::Result<()> {
-        self.0.remove_file(path)
+        self.fs.remove_file(path)?;
+        self.attributes.remove(path);
+        Ok(())
     }
 
     fn new_open_options(&self) -> virtual_fs::OpenOptions {
@@ -210,7 +397,8 @@ impl virtual_fs::FileOpener for Directory {
         path: &std::path::Path,
         conf: &virtual_fs::OpenOptionsConfig,
     ) -> virtual_fs::Result<Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>> {
-        self.0.new_open_options().options(conf.clone()).open(path)
+        self.attributes.check_open(path, conf)?;
+        self.fs.new_open_options().options(conf.clone()).open(path)
     }
 }
 
@@ -288,7 +476,7 @@ fn in_memory_filesystem(record: &js_sys::Object) -> Result<virtual_fs::mem_fs::F
             file.length=contents.len(),
             "Adding file to directory",
         );
-        InlineWaker::block_on(async {
+        crate::blocking::block_on("populating a Directory", async {
             let mut f = fs
                 .new_open_options()
                 .write(true)
@@ -303,8 +491,123 @@ fn in_memory_filesystem(record: &js_sys::Object) -> Result<virtual_fs::mem_fs::F
     Ok(fs)
 }
 
+/// How much to copy at a time.
+///
+/// This is large enough that small and medium files are copied in one go,
+/// which is a single `memcpy()` for in-memory filesystems.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Recursively copy `src` to `dst`.
+#[tracing::instrument(level = "debug", skip(fs))]
+async fn copy_tree(fs: &dyn FileSystem, src: &Path, dst: &Path) -> Result<(), anyhow::Error> {
+    let src = &resolve_dots(src);
+    let dst = &resolve_dots(dst);
+
+    // Note: Opening the destination truncates it, so copying a file onto
+    // itself would leave it empty
+    if src == dst {
+        fs.metadata(src)
+            .with_context(|| format!("Unable to stat \"{}\"", src.display()))?;
+        return Ok(());
+    }
+
+    if dst.starts_with(src) && fs.metadata(src)?.is_dir() {
+        anyhow::bail!(
+            "Unable to copy \"{}\" into itself (\"{}\")",
+            src.display(),
+            dst.display()
+        );
+    }
+
+    let mut pending = vec![(src.to_path_buf(), dst.to_path_buf())];
+    let mut buffer = Vec::new();
+    let mut copied = 0;
+
+    while let Some((src, dst)) = pending.pop() {
+        // Note: Copying a huge tree would otherwise freeze the page
+        copied += 1;
+        if copied % dir_entries::DEFAULT_BATCH_SIZE == 0 {
+            dir_entries::yield_to_event_loop().await;
+        }
+
+        let metadata = fs
+            .metadata(&src)
+            .with_context(|| format!("Unable to stat \"{}\"", src.display()))?;
+
+        if metadata.is_dir() {
+            create_dir_all(fs, &dst)?;
+
+            for entry in fs.read_dir(&src)? {
+                let entry = entry?;
+                let name = entry.path.file_name().context("Invalid directory entry")?;
+                pending.push((entry.path.clone(), dst.join(name)));
+            }
+        } else {
+            copy_file(fs, &src, &dst, &mut buffer)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Unable to copy \"{}\" to \"{}\"",
+                        src.display(),
+                        dst.display()
+                    )
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve `.` and `..` in an absolute path, so the same file always has the
+/// same name.
+fn resolve_dots(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+        }
+    }
+
+    resolved
+}
+
+pub(crate) async fn copy_file(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dst: &Path,
+    buffer: &mut Vec<u8>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = fs.new_open_options().read(true).open(src)?;
+    let mut writer = fs
+        .new_open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+
+    let size = reader.size() as usize;
+    buffer.resize(std::cmp::min(size, COPY_CHUNK_SIZE).max(1), 0);
+
+    loop {
+        let bytes_read = reader.read(&mut buffer[..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..bytes_read]).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
 #[tracing::instrument(level = "trace", skip(fs))]
-fn create_dir_all(fs: &dyn FileSystem, path: &Path) -> Result<(), anyhow::Error> {
+pub(crate) fn create_dir_all(fs: &dyn FileSystem, path: &Path) -> Result<(), anyhow::Error> {
     let ancestors: Vec<&Path> = path.ancestors().collect();
 
     for ancestor in ancestors.into_iter().rev() {
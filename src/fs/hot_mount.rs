@@ -0,0 +1,378 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{
+    FileSystem, FsError, Metadata, OpenOptionsConfig, ReadDir, TmpFileSystem, VirtualFile,
+};
+
+use crate::{
+    events::{DetachReason, MountKind, RuntimeEvent},
+    tasks::ThreadPool,
+    utils::Error,
+};
+
+type SharedFileSystem = Arc<dyn FileSystem + Send + Sync>;
+
+/// The mount points in an instance's root filesystem, which can be attached
+/// and detached while the instance is running.
+#[derive(Debug, Clone)]
+pub(crate) struct MountTable {
+    root: TmpFileSystem,
+    mounts: Arc<Mutex<BTreeMap<PathBuf, Entry>>>,
+    reporter: Arc<Mutex<Option<Reporter>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    mount: HotMount,
+    kind: MountKind,
+}
+
+/// Where mount lifecycle events get sent.
+#[derive(Debug, Clone)]
+struct Reporter {
+    pool: ThreadPool,
+    pid: Option<u32>,
+}
+
+impl Reporter {
+    fn attached(&self, path: &Path, kind: MountKind) {
+        self.pool.emit(RuntimeEvent::MountAttached {
+            pid: self.pid,
+            path: path.display().to_string(),
+            kind,
+        });
+    }
+
+    fn detached(&self, path: &Path, reason: DetachReason) {
+        self.pool.emit(RuntimeEvent::MountDetached {
+            pid: self.pid,
+            path: path.display().to_string(),
+            reason,
+        });
+    }
+}
+
+impl MountTable {
+    pub(crate) fn new(root: TmpFileSystem) -> Self {
+        MountTable {
+            root,
+            mounts: Arc::default(),
+            reporter: Arc::default(),
+        }
+    }
+
+    /// Start emitting `"mount-*"` events on the pool's runtime, beginning
+    /// with a `"mount-attached"` event for everything mounted so far.
+    pub(crate) fn report_to(&self, pool: ThreadPool, pid: Option<u32>) {
+        let reporter = Reporter { pool, pid };
+
+        for (path, entry) in self.mounts.lock().unwrap().iter() {
+            if entry.mount.is_attached() {
+                reporter.attached(path, entry.kind);
+            }
+        }
+
+        *self.reporter.lock().unwrap() = Some(reporter);
+    }
+
+    /// Let listeners know that everything was detached because the program
+    /// exited. No more events are emitted afterwards.
+    pub(crate) fn program_exited(&self) {
+        let Some(reporter) = self.reporter.lock().unwrap().take() else {
+            return;
+        };
+
+        for (path, entry) in self.mounts.lock().unwrap().iter() {
+            if entry.mount.is_attached() {
+                reporter.detached(path, DetachReason::Exited);
+            }
+        }
+    }
+
+    fn reporter(&self) -> Option<Reporter> {
+        self.reporter.lock().unwrap().clone()
+    }
+
+    /// The filesystem everything is mounted into.
+    pub(crate) fn root(&self) -> &TmpFileSystem {
+        &self.root
+    }
+
+    /// Mount `fs` at `path`.
+    ///
+    /// Remounting a location that was previously unmounted is allowed, but
+    /// anything opened under the old directory stays invalid.
+    pub(crate) fn mount(
+        &self,
+        path: &Path,
+        fs: SharedFileSystem,
+        kind: MountKind,
+    ) -> Result<(), Error> {
+        let mut mounts = self.mounts.lock().unwrap();
+
+        if let Some(existing) = mounts.get_mut(path) {
+            if existing.mount.is_attached() {
+                let path = path.display();
+                return Err(anyhow::anyhow!("Something is already mounted at \"{path}\"").into());
+            }
+            existing.mount.attach(fs);
+            existing.kind = kind;
+        } else {
+            let mount = HotMount::default();
+            mount.attach(fs);
+            let shared: SharedFileSystem = Arc::new(mount.clone());
+            self.root
+                .mount(path.to_path_buf(), &shared, "/".into())
+                .map_err(|e| anyhow::anyhow!("Unable to mount to \"{}\": {e}", path.display()))?;
+            mounts.insert(path.to_path_buf(), Entry { mount, kind });
+        }
+
+        if let Some(reporter) = self.reporter() {
+            reporter.attached(path, kind);
+        }
+
+        Ok(())
+    }
+
+    /// Detach whatever is mounted at `path`. Any further access to the
+    /// location fails with `EIO` until something else gets mounted there.
+    pub(crate) fn unmount(&self, path: &Path) -> Result<(), Error> {
+        let mounts = self.mounts.lock().unwrap();
+
+        match mounts.get(path) {
+            Some(entry) if entry.mount.is_attached() => {
+                entry.mount.detach();
+                if let Some(reporter) = self.reporter() {
+                    reporter.detached(path, DetachReason::Unmounted);
+                }
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!("Nothing is mounted at \"{}\"", path.display()).into()),
+        }
+    }
+}
+
+/// Let listeners know that the files the runtime would normally generate at
+/// `generated` are hidden by a directory mounted at `mount_point`.
+pub(crate) fn report_shadowed(
+    pool: &ThreadPool,
+    pid: Option<u32>,
+    generated: &str,
+    mount_point: &str,
+) {
+    tracing::debug!(
+        generated,
+        mount_point,
+        "A generated filesystem was shadowed"
+    );
+    pool.emit(RuntimeEvent::MountFallback {
+        pid,
+        path: generated.to_string(),
+        requested: MountKind::Generated,
+        actual: MountKind::Directory,
+        reason: format!(
+            "A directory was mounted at \"{mount_point}\", so the runtime's files at \"{generated}\" aren't available"
+        ),
+    });
+}
+
+/// A [`FileSystem`] which forwards to another filesystem until it gets
+/// detached.
+#[derive(Debug, Clone, Default)]
+struct HotMount(Arc<RwLock<Option<SharedFileSystem>>>);
+
+impl HotMount {
+    fn attach(&self, fs: SharedFileSystem) {
+        *self.0.write().unwrap() = Some(fs);
+    }
+
+    fn detach(&self) {
+        self.0.write().unwrap().take();
+    }
+
+    fn is_attached(&self) -> bool {
+        self.0.read().unwrap().is_some()
+    }
+
+    fn current(&self) -> virtual_fs::Result<SharedFileSystem> {
+        self.0.read().unwrap().clone().ok_or(FsError::IOError)
+    }
+}
+
+impl FileSystem for HotMount {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        self.current()?.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.current()?.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.current()?.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        Box::pin(async move { self.current()?.rename(from, to).await })
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.current()?.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.current()?.symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.current()?.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for HotMount {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        self.current()?
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)
+    }
+}
+
+/// A stand-in for files that were open when their directory got unmounted.
+///
+/// Every operation fails with `EIO`.
+#[derive(Debug, Default)]
+pub(crate) struct DetachedFile;
+
+fn detached() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "The file's directory was unmounted")
+}
+
+impl VirtualFile for DetachedFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(FsError::IOError)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Err(FsError::IOError) })
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(detached()))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(detached()))
+    }
+}
+
+impl AsyncRead for DetachedFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Err(detached()))
+    }
+}
+
+impl AsyncWrite for DetachedFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(detached()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Err(detached()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for DetachedFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(detached())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Err(detached()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn detached_mounts_fail_with_eio() {
+        let table = MountTable::new(TmpFileSystem::new());
+        let project = virtual_fs::mem_fs::FileSystem::default();
+        project.create_dir("/src".as_ref()).unwrap();
+        let path = Path::new("/project");
+
+        table
+            .mount(path, Arc::new(project), MountKind::Directory)
+            .unwrap();
+        assert!(table.root().metadata("/project/src".as_ref()).is_ok());
+
+        table.unmount(path).unwrap();
+        assert_eq!(
+            table.root().metadata("/project/src".as_ref()).unwrap_err(),
+            FsError::IOError
+        );
+        assert!(table.unmount(path).is_err());
+
+        // Something else can be mounted in its place
+        table
+            .mount(
+                path,
+                Arc::new(virtual_fs::mem_fs::FileSystem::default()),
+                MountKind::Directory,
+            )
+            .unwrap();
+        assert_eq!(
+            table.root().metadata("/project/src".as_ref()).unwrap_err(),
+            FsError::EntryNotFound
+        );
+    }
+}
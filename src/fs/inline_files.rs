@@ -0,0 +1,442 @@
+//! Small files passed to a program with the `files` option, without having to
+//! create a [`Directory`] for them first.
+//!
+//! Files inside a mounted [`Directory`] are layered on top of it rather than
+//! written into it, because the directory belongs to the host (and may be
+//! persistent, e.g. a `KeyValueStore`).
+
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use js_sys::Uint8Array;
+use virtual_fs::{
+    mem_fs, AsyncWriteExt, DirEntry, FileSystem, FsError, Metadata, OpenOptionsConfig, ReadDir,
+    VirtualFile,
+};
+use wasm_bindgen::{JsCast, JsValue};
+
+use crate::{
+    fs::directory::{copy_file, create_dir_all},
+    utils::Error,
+    Directory,
+};
+
+/// A file to create before the program starts.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct InlineFile {
+    pub(crate) path: PathBuf,
+    pub(crate) contents: Vec<u8>,
+}
+
+/// Parse the `files` option, serializing anything that isn't a string or a
+/// `Uint8Array` as JSON.
+pub(crate) fn parse(record: &js_sys::Object) -> Result<Vec<InlineFile>, Error> {
+    let mut files = Vec::new();
+
+    for (key, value) in crate::utils::object_entries(record)? {
+        let path = String::from(key);
+        let contents = contents(&path, &value)?;
+        files.push(InlineFile {
+            path: validate_path(&path)?,
+            contents,
+        });
+    }
+
+    Ok(files)
+}
+
+fn contents(path: &str, value: &JsValue) -> Result<Vec<u8>, Error> {
+    if let Some(s) = value.as_string() {
+        return Ok(s.into_bytes());
+    }
+    if let Some(bytes) = value.dyn_ref::<Uint8Array>() {
+        return Ok(bytes.to_vec());
+    }
+
+    match js_sys::JSON::stringify(value)
+        .ok()
+        .and_then(|s| s.as_string())
+    {
+        Some(json) => Ok(json.into_bytes()),
+        None => {
+            let msg = format!("The contents of \"{path}\" can't be serialized as JSON");
+            Err(Error::js(js_sys::TypeError::new(&msg)))
+        }
+    }
+}
+
+pub(crate) fn validate_path(path: &str) -> Result<PathBuf, Error> {
+    let p = Path::new(path);
+    let valid = p.is_absolute()
+        && p.file_name().is_some()
+        && p.components()
+            .all(|c| matches!(c, Component::RootDir | Component::Normal(_)));
+
+    if valid {
+        Ok(p.to_path_buf())
+    } else {
+        let msg = format!("\"{path}\" must be an absolute path to a file (e.g. \"/input.txt\")");
+        Err(Error::js(js_sys::TypeError::new(&msg)))
+    }
+}
+
+/// Write the files into a filesystem, creating any missing directories and
+/// replacing files that already exist.
+pub(crate) fn write_all(fs: &dyn FileSystem, files: &[InlineFile]) -> Result<(), Error> {
+    for file in files {
+        if let Some(parent) = file.path.parent() {
+            create_dir_all(fs, parent)?;
+        }
+
+        crate::blocking::block_on("writing files", async {
+            let mut f = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&file.path)?;
+            f.write_all(&file.contents).await?;
+            f.flush().await
+        })
+        .with_context(|| format!("Unable to write to \"{}\"", file.path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Create each of the directories (and their parents) in a filesystem.
+pub(crate) fn create_dirs(fs: &dyn FileSystem, dirs: &[PathBuf]) -> Result<(), Error> {
+    for dir in dirs {
+        create_dir_all(fs, dir)?;
+    }
+
+    Ok(())
+}
+
+/// Layer the files and directories that are inside one of the `mounted`
+/// directories on top of it, leaving the directory itself untouched.
+///
+/// Paths inside nested mounts belong to the innermost one, and anything
+/// outside every mount is ignored.
+pub(crate) fn layer(
+    files: &[InlineFile],
+    dirs: &[PathBuf],
+    mounted: Vec<(String, Directory)>,
+) -> Result<Vec<(String, Directory)>, Error> {
+    let mount_points: Vec<String> = mounted.iter().map(|(dest, _)| dest.clone()).collect();
+    let owner = |path: &Path| {
+        mount_points
+            .iter()
+            .filter(|dest| path.starts_with(dest))
+            .max_by_key(|dest| dest.len())
+            .cloned()
+    };
+
+    mounted
+        .into_iter()
+        .map(|(dest, dir)| {
+            let relative = |path: &Path| {
+                let rest = path.strip_prefix(&dest).ok()?;
+                (owner(path).as_ref() == Some(&dest)).then(|| Path::new("/").join(rest))
+            };
+            let files: Vec<InlineFile> = files
+                .iter()
+                .filter_map(|f| {
+                    Some(InlineFile {
+                        path: relative(&f.path)?,
+                        contents: f.contents.clone(),
+                    })
+                })
+                .collect();
+            let dirs: Vec<PathBuf> = dirs.iter().filter_map(|d| relative(d)).collect();
+
+            if files.is_empty() && dirs.is_empty() {
+                return Ok((dest, dir));
+            }
+
+            let inline = mem_fs::FileSystem::default();
+            create_dirs(&inline, &dirs)?;
+            write_all(&inline, &files)?;
+            let overlay = Overlay {
+                inline,
+                base: Arc::new(dir),
+            };
+            Ok((dest, Directory::from_filesystem(overlay)))
+        })
+        .collect()
+}
+
+/// Put the files and directories somewhere they can be mounted, returning
+/// the new mounts and the `mounted` directories.
+///
+/// Anything inside one of the `mounted` directories is [layered](layer) on
+/// top of it. Everything else is grouped into a new [`Directory`] per
+/// top-level directory (e.g. `/data` for `/data/input.txt`), because
+/// commands from a package can't have files added to `/` itself.
+#[allow(clippy::type_complexity)]
+pub(crate) fn into_mounts(
+    files: Vec<InlineFile>,
+    dirs: &[PathBuf],
+    mounted: Vec<(String, Directory)>,
+) -> Result<(Vec<(String, Directory)>, Vec<(String, Directory)>), Error> {
+    let is_mounted = |path: &Path| mounted.iter().any(|(dest, _)| path.starts_with(dest));
+    let mut groups: BTreeMap<String, (Vec<InlineFile>, Vec<PathBuf>)> = BTreeMap::new();
+
+    for dir in dirs.iter().filter(|d| !is_mounted(d)) {
+        let (top, rest) =
+            split_top_level(dir).unwrap_or_else(|| (dir.display().to_string(), PathBuf::from("/")));
+        groups.entry(top).or_default().1.push(rest);
+    }
+
+    let (inside, outside): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| is_mounted(&f.path));
+    let mounted = layer(&inside, dirs, mounted)?;
+
+    for file in outside {
+        let (top, rest) = split_top_level(&file.path).ok_or_else(|| {
+            let msg = format!(
+                "\"{}\" can't be created in the root directory of a package command, use a sub-directory (e.g. \"/input{}\")",
+                file.path.display(),
+                file.path.display(),
+            );
+            Error::js(js_sys::TypeError::new(&msg))
+        })?;
+        groups.entry(top).or_default().0.push(InlineFile {
+            path: rest,
+            contents: file.contents,
+        });
+    }
+
+    let generated = groups
+        .into_iter()
+        .map(|(dest, (files, dirs))| {
+            let fs = mem_fs::FileSystem::default();
+            create_dirs(&fs, &dirs)?;
+            write_all(&fs, &files)?;
+            Ok((dest, Directory::from_filesystem(fs)))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok((generated, mounted))
+}
+
+/// Shows inline files on top of a mounted [`Directory`].
+///
+/// The program can modify the inline files, but those changes stay in
+/// memory. Everything else is read from and written to the directory.
+#[derive(Debug, Clone)]
+struct Overlay {
+    inline: mem_fs::FileSystem,
+    base: Arc<dyn FileSystem + Send + Sync>,
+}
+
+impl Overlay {
+    fn is_inline_file(&self, path: &Path) -> bool {
+        self.inline
+            .metadata(path)
+            .map(|m| m.is_file())
+            .unwrap_or(false)
+    }
+
+    /// Create `path`'s parent in the mounted directory if it only exists in
+    /// the overlay, so something can be created there.
+    fn prepare_parent(&self, path: &Path) -> virtual_fs::Result<()> {
+        match path.parent() {
+            Some(parent)
+                if self.base.metadata(parent).is_err() && self.inline.metadata(parent).is_ok() =>
+            {
+                create_dir_all(&*self.base, parent).map_err(|_| FsError::IOError)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FileSystem for Overlay {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        let base = self.base.read_dir(path);
+        let inline = self.inline.read_dir(path);
+        if let (Err(e), Err(_)) = (&base, &inline) {
+            return Err(*e);
+        }
+
+        // Note: inline entries come last so they replace the directory's
+        let mut entries: BTreeMap<PathBuf, DirEntry> = BTreeMap::new();
+        for entry in base.into_iter().chain(inline).flatten().flatten() {
+            entries.insert(entry.path.clone(), entry);
+        }
+
+        Ok(ReadDir::new(entries.into_values().collect()))
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.prepare_parent(path)?;
+        self.base.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        match self.base.remove_dir(path) {
+            Err(FsError::EntryNotFound) if self.inline.metadata(path).is_ok() => {
+                self.inline.remove_dir(path)
+            }
+            result => result,
+        }
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        Box::pin(async move {
+            if self.is_inline_file(from) {
+                copy_file(self, from, to, &mut Vec::new())
+                    .await
+                    .map_err(|_| FsError::IOError)?;
+                return self.inline.remove_file(from);
+            }
+
+            self.prepare_parent(to)?;
+            self.base.rename(from, to).await?;
+            if self.is_inline_file(to) {
+                self.inline.remove_file(to)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        if self.is_inline_file(path) {
+            return self.inline.metadata(path);
+        }
+        self.base
+            .metadata(path)
+            .or_else(|e| self.inline.metadata(path).map_err(|_| e))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        if self.is_inline_file(path) {
+            return self.inline.symlink_metadata(path);
+        }
+        self.base
+            .symlink_metadata(path)
+            .or_else(|e| self.inline.symlink_metadata(path).map_err(|_| e))
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        if self.is_inline_file(path) {
+            self.inline.remove_file(path)
+        } else {
+            self.base.remove_file(path)
+        }
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for Overlay {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let fs: &dyn FileSystem = if self.is_inline_file(path) {
+            &self.inline
+        } else {
+            if conf.create() || conf.create_new() {
+                self.prepare_parent(path)?;
+            }
+            &*self.base
+        };
+
+        fs.new_open_options().options(conf.clone()).open(path)
+    }
+}
+
+/// Split `/data/sub/input.txt` into `/data` and `/sub/input.txt`.
+fn split_top_level(path: &Path) -> Option<(String, PathBuf)> {
+    let mut components = path.components().skip(1);
+    let top = components.next()?;
+    let rest: PathBuf = components.collect();
+
+    if rest.as_os_str().is_empty() {
+        return None;
+    }
+
+    Some((
+        Path::new("/").join(top).display().to_string(),
+        Path::new("/").join(rest),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use wasmer_wasix::runtime::task_manager::InlineWaker;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn files_are_grouped_by_top_level_directory() {
+        let record = js_sys::JSON::parse(
+            r#"{"/data/input.txt": "hello", "/data/nested/config.json": {"debug": true}}"#,
+        )
+        .unwrap();
+        let files = parse(record.unchecked_ref()).unwrap();
+
+        assert_eq!(files[1].contents, br#"{"debug":true}"#);
+
+        let (mounts, _) = into_mounts(files, &[], Vec::new()).unwrap();
+
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].0, "/data");
+        let contents = InlineWaker::block_on(mounts[0].1._read_file("/nested/config.json".into()));
+        assert_eq!(contents.unwrap(), br#"{"debug":true}"#);
+        assert!(validate_path("relative.txt").is_err());
+        assert!(validate_path("/../escape").is_err());
+        let top_level = InlineFile {
+            path: "/top.txt".into(),
+            contents: Vec::new(),
+        };
+        assert!(into_mounts(vec![top_level], &[], Vec::new()).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn inline_files_dont_overwrite_mounted_directories() {
+        let host = Directory::from_filesystem(mem_fs::FileSystem::default());
+        let existing = InlineFile {
+            path: "/config.json".into(),
+            contents: b"host".to_vec(),
+        };
+        write_all(&host, &[existing]).unwrap();
+
+        let files = vec![
+            InlineFile {
+                path: "/data/config.json".into(),
+                contents: b"inline".to_vec(),
+            },
+            InlineFile {
+                path: "/data/nested/input.txt".into(),
+                contents: b"input".to_vec(),
+            },
+        ];
+        let (generated, mounted) =
+            into_mounts(files, &[], vec![("/data".to_string(), host.clone())]).unwrap();
+        assert!(generated.is_empty());
+        let overlay = &mounted[0].1;
+
+        let read = |dir: &Directory, path: &str| InlineWaker::block_on(dir._read_file(path.into()));
+        assert_eq!(read(overlay, "/config.json").unwrap(), b"inline");
+        assert_eq!(read(overlay, "/nested/input.txt").unwrap(), b"input");
+        assert_eq!(read(&host, "/config.json").unwrap(), b"host");
+        assert!(read(&host, "/nested/input.txt").is_err());
+
+        let names: Vec<_> = FileSystem::read_dir(overlay, Path::new("/"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path)
+            .collect();
+        assert_eq!(names, [Path::new("/config.json"), Path::new("/nested")]);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{path::PathBuf, sync::Arc};
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    descriptors::DescriptorTable, events::MountKind, fs::MountTable, utils::Error, Directory,
+};
+
+/// The filesystem of a running {@link Instance}.
+///
+/// This lets directories be swapped in and out without restarting the
+/// program (e.g. to point a dev server at a different project).
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct InstanceFs {
+    mounts: MountTable,
+    descriptors: DescriptorTable,
+}
+
+#[wasm_bindgen]
+impl InstanceFs {
+    /// Mount a directory at `path`.
+    pub fn mount(&self, path: String, dir: &Directory) -> Result<(), Error> {
+        self.mounts.mount(
+            &normalize(path),
+            Arc::new(dir.clone()),
+            MountKind::Directory,
+        )
+    }
+
+    /// Unmount the directory at `path`.
+    ///
+    /// Files the program still has open under `path` will fail with `EIO`
+    /// from now on.
+    pub fn unmount(&self, path: String) -> Result<(), Error> {
+        let path = normalize(path);
+        self.mounts.unmount(&path)?;
+        self.descriptors.invalidate_under(&path);
+        Ok(())
+    }
+}
+
+impl InstanceFs {
+    pub(crate) fn new(mounts: MountTable, descriptors: DescriptorTable) -> Self {
+        InstanceFs {
+            mounts,
+            descriptors,
+        }
+    }
+}
+
+fn normalize(mut path: String) -> PathBuf {
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    PathBuf::from(path)
+}
@@ -1,3 +1,19 @@
+mod attributes;
+mod device;
+mod dir_entries;
 mod directory;
+mod hot_mount;
+pub(crate) mod inline_files;
+mod instance_fs;
+mod sandbox;
+mod snapshot;
 
-pub use self::directory::{Directory, DirectoryInit};
+pub(crate) use self::device::{Device, DeviceFileSystem, Generator, Opener};
+pub(crate) use self::hot_mount::{report_shadowed, DetachedFile, MountTable};
+pub(crate) use self::inline_files::InlineFile;
+pub(crate) use self::sandbox::{Sandbox, SandboxMode, ViolationReason};
+pub use self::{
+    directory::{Directory, DirectoryInit},
+    instance_fs::InstanceFs,
+    snapshot::DirectorySnapshot,
+};
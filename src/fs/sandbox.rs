@@ -0,0 +1,407 @@
+//! Making sure a guest's path operations stay inside the directory mounted
+//! for them.
+//!
+//! WASIX resolves paths before they reach a mount, so this is a second line
+//! of defence: every path is canonicalized relative to the mount's root and
+//! anything that would climb out of it is refused.
+//!
+//! A symlink could point anywhere, and the filesystem API doesn't let us see
+//! where, so paths which follow one are refused as well. Operations which
+//! act on a symlink itself (`symlink_metadata()`, `remove_file()` and
+//! `rename()`) are still allowed to name one as their last component.
+//!
+//! In strict mode, paths which merely look like traversal attempts (any
+//! `..`, or a symlink anywhere along the way) are refused too and reported to
+//! the host as `"sandbox-violation"` events.
+
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use virtual_fs::{FileSystem, FsError, Metadata, OpenOptionsConfig, ReadDir, VirtualFile};
+
+use crate::{events::RuntimeEvent, tasks::ThreadPool, utils::Error};
+
+/// How closely guest paths are checked.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SandboxMode {
+    /// Pass paths through untouched.
+    Off,
+    /// Resolve `.` and `..`, refusing paths that leave the mount or follow a
+    /// symlink.
+    #[default]
+    Enforce,
+    /// Like [`SandboxMode::Enforce`], but also refuse any `..` and paths
+    /// through symlinks, reporting every refusal to the host.
+    Strict,
+}
+
+impl SandboxMode {
+    pub(crate) fn parse(mode: Option<&str>) -> Result<Self, Error> {
+        match mode {
+            None | Some("enforce") => Ok(SandboxMode::Enforce),
+            Some("off") => Ok(SandboxMode::Off),
+            Some("strict") => Ok(SandboxMode::Strict),
+            Some(other) => {
+                let msg = format!(
+                    "Expected \"off\", \"enforce\", or \"strict\" for the sandbox, not \"{other}\""
+                );
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+/// Why a path was refused.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ViolationReason {
+    /// The path climbs above the mount's root.
+    Escape,
+    /// The path contains a NUL byte, which would truncate it on a real OS.
+    NulByte,
+    /// The path uses `..` (only refused in strict mode).
+    Traversal,
+    /// The path follows a symlink, which might lead out of the mount.
+    Symlink,
+}
+
+impl fmt::Display for ViolationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViolationReason::Escape => write!(f, "climbs out of the mounted directory"),
+            ViolationReason::NulByte => write!(f, "contains a NUL byte"),
+            ViolationReason::Traversal => write!(f, "uses \"..\""),
+            ViolationReason::Symlink => write!(f, "goes through a symlink"),
+        }
+    }
+}
+
+/// Resolve `.` and `..` in `path`, relative to the root of a mount.
+fn canonicalize(path: &Path, mode: SandboxMode) -> Result<PathBuf, ViolationReason> {
+    if path.to_string_lossy().contains('\0') {
+        return Err(ViolationReason::NulByte);
+    }
+
+    let mut canonical = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir if mode == SandboxMode::Strict => {
+                return Err(ViolationReason::Traversal);
+            }
+            Component::ParentDir => {
+                if !canonical.pop() {
+                    return Err(ViolationReason::Escape);
+                }
+            }
+            Component::Normal(name) => canonical.push(name),
+        }
+    }
+
+    Ok(canonical)
+}
+
+/// Checks paths for the filesystems mounted into a program.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Sandbox {
+    mode: SandboxMode,
+    /// Where violations are reported.
+    pool: Option<ThreadPool>,
+}
+
+impl Sandbox {
+    pub(crate) fn new(mode: SandboxMode, pool: Option<ThreadPool>) -> Self {
+        Sandbox { mode, pool }
+    }
+
+    /// Wrap a filesystem mounted at `mount_point` so its paths get checked.
+    pub(crate) fn mount(
+        &self,
+        mount_point: &str,
+        fs: Arc<dyn FileSystem + Send + Sync>,
+    ) -> Arc<dyn FileSystem + Send + Sync> {
+        if self.mode == SandboxMode::Off {
+            return fs;
+        }
+
+        Arc::new(SandboxedFileSystem {
+            inner: fs,
+            mount_point: mount_point.to_string(),
+            sandbox: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct SandboxedFileSystem {
+    inner: Arc<dyn FileSystem + Send + Sync>,
+    mount_point: String,
+    sandbox: Sandbox,
+}
+
+impl SandboxedFileSystem {
+    /// Canonicalize `path`, refusing (and possibly reporting) anything
+    /// suspicious.
+    fn check(&self, operation: &'static str, path: &Path) -> virtual_fs::Result<PathBuf> {
+        self.check_with(operation, path, true)
+    }
+
+    /// Like [`SandboxedFileSystem::check()`], for operations which don't
+    /// follow a symlink in the last component of the path.
+    fn check_nofollow(&self, operation: &'static str, path: &Path) -> virtual_fs::Result<PathBuf> {
+        self.check_with(operation, path, false)
+    }
+
+    fn check_with(
+        &self,
+        operation: &'static str,
+        path: &Path,
+        follows_last: bool,
+    ) -> virtual_fs::Result<PathBuf> {
+        let mode = self.sandbox.mode;
+        let result = canonicalize(path, mode).and_then(|canonical| {
+            // Note: strict mode doesn't let guests touch symlinks at all
+            let follows_last = follows_last || mode == SandboxMode::Strict;
+            if self.through_symlink(&canonical, follows_last) {
+                Err(ViolationReason::Symlink)
+            } else {
+                Ok(canonical)
+            }
+        });
+
+        result.map_err(|reason| {
+            let path = path.display().to_string();
+            tracing::warn!(
+                mount = %self.mount_point,
+                %path,
+                operation,
+                %reason,
+                "Refused a path outside the sandbox",
+            );
+
+            if mode == SandboxMode::Strict {
+                if let Some(pool) = &self.sandbox.pool {
+                    pool.emit(RuntimeEvent::SandboxViolation {
+                        mount: self.mount_point.clone(),
+                        path,
+                        operation: operation.to_string(),
+                        reason,
+                    });
+                }
+            }
+
+            FsError::PermissionDenied
+        })
+    }
+
+    /// Is anything along `path` a symlink? The path itself is only checked
+    /// when `include_last` is set.
+    fn through_symlink(&self, path: &Path, include_last: bool) -> bool {
+        path.ancestors()
+            .skip(usize::from(!include_last))
+            .filter(|p| *p != Path::new("/"))
+            .any(|p| {
+                self.inner
+                    .symlink_metadata(p)
+                    .map(|m| m.ft.symlink)
+                    .unwrap_or(false)
+            })
+    }
+}
+
+impl FileSystem for SandboxedFileSystem {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        self.inner.read_dir(&self.check("read_dir", path)?)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.create_dir(&self.check("create_dir", path)?)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.remove_dir(&self.check("remove_dir", path)?)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        Box::pin(async move {
+            let from = self.check_nofollow("rename", from)?;
+            let to = self.check_nofollow("rename", to)?;
+            self.inner.rename(&from, &to).await
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.inner.metadata(&self.check("metadata", path)?)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        let path = self.check_nofollow("symlink_metadata", path)?;
+        self.inner.symlink_metadata(&path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        let path = self.check_nofollow("remove_file", path)?;
+        self.inner.remove_file(&path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for SandboxedFileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let path = self.check("open", path)?;
+        self.inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn guests_cannot_climb_out_of_a_mount() {
+        let inner = virtual_fs::mem_fs::FileSystem::default();
+        inner.create_dir("/data".as_ref()).unwrap();
+        let inner: Arc<dyn FileSystem + Send + Sync> = Arc::new(inner);
+
+        let enforced = Sandbox::new(SandboxMode::Enforce, None).mount("/mnt", inner.clone());
+        assert!(enforced
+            .metadata("/data/../data/./".as_ref())
+            .unwrap()
+            .is_dir());
+        for escape in ["/..", "/data/../../etc/passwd", "../../secret"] {
+            assert_eq!(
+                enforced.metadata(escape.as_ref()).unwrap_err(),
+                FsError::PermissionDenied,
+                "{escape}",
+            );
+        }
+        assert_eq!(
+            enforced
+                .new_open_options()
+                .read(true)
+                .open("/data\0.txt")
+                .unwrap_err(),
+            FsError::PermissionDenied,
+        );
+
+        let strict = Sandbox::new(SandboxMode::Strict, None).mount("/mnt", inner);
+        assert!(strict.metadata("/data".as_ref()).unwrap().is_dir());
+        assert_eq!(
+            strict.metadata("/data/../data".as_ref()).unwrap_err(),
+            FsError::PermissionDenied,
+        );
+        assert_eq!(
+            canonicalize("/a/../../b".as_ref(), SandboxMode::Enforce),
+            Err(ViolationReason::Escape),
+        );
+    }
+
+    /// A filesystem which pretends some of its entries are symlinks.
+    #[derive(Debug)]
+    struct WithSymlinks {
+        inner: virtual_fs::mem_fs::FileSystem,
+        links: Vec<PathBuf>,
+    }
+
+    impl FileSystem for WithSymlinks {
+        fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+            self.inner.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+            self.inner.create_dir(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+            self.inner.remove_dir(path)
+        }
+
+        fn rename<'a>(
+            &'a self,
+            from: &'a Path,
+            to: &'a Path,
+        ) -> BoxFuture<'a, virtual_fs::Result<()>> {
+            self.inner.rename(from, to)
+        }
+
+        fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+            let mut metadata = self.inner.metadata(path)?;
+            metadata.ft.symlink = self.links.iter().any(|link| link == path);
+            Ok(metadata)
+        }
+
+        fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+            self.inner.remove_file(path)
+        }
+
+        fn new_open_options(&self) -> virtual_fs::OpenOptions {
+            self.inner.new_open_options()
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn symlinks_are_not_followed() {
+        let inner = virtual_fs::mem_fs::FileSystem::default();
+        inner.create_dir("/link".as_ref()).unwrap();
+        inner.create_dir("/link/etc".as_ref()).unwrap();
+        let inner: Arc<dyn FileSystem + Send + Sync> = Arc::new(WithSymlinks {
+            inner,
+            links: vec![PathBuf::from("/link")],
+        });
+
+        // Following the link could lead anywhere
+        let enforced = Sandbox::new(SandboxMode::Enforce, None).mount("/mnt", inner.clone());
+        for path in ["/link", "/link/etc", "/link/../link/etc"] {
+            assert_eq!(
+                enforced.metadata(path.as_ref()).unwrap_err(),
+                FsError::PermissionDenied,
+                "{path}",
+            );
+        }
+        assert_eq!(
+            enforced.read_dir("/link".as_ref()).unwrap_err(),
+            FsError::PermissionDenied,
+        );
+        // ... but the link itself can still be inspected
+        assert!(
+            enforced
+                .symlink_metadata("/link".as_ref())
+                .unwrap()
+                .ft
+                .symlink
+        );
+        assert_eq!(
+            enforced.symlink_metadata("/link/etc".as_ref()).unwrap_err(),
+            FsError::PermissionDenied,
+        );
+
+        // Strict mode doesn't let the guest touch it at all
+        let strict = Sandbox::new(SandboxMode::Strict, None).mount("/mnt", inner);
+        assert_eq!(
+            strict.symlink_metadata("/link".as_ref()).unwrap_err(),
+            FsError::PermissionDenied,
+        );
+    }
+}
@@ -0,0 +1,354 @@
+//! Recording what a directory contains so two points in time can be
+//! compared (e.g. to show what a command changed), or so the directory can
+//! be put back the way it was.
+//!
+//! File contents are kept in the page-wide [`BlobStore`], so a file only
+//! takes up space once no matter how many snapshots (or packages) contain
+//! it.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use bytes::Bytes;
+use serde::Serialize;
+use virtual_fs::{AsyncReadExt, AsyncWriteExt, FileSystem};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasmer_wasix::runtime::resolver::WebcHash;
+
+use crate::{
+    blobs::{Blob, BlobStore},
+    fs::dir_entries,
+    utils::Error,
+};
+
+/// The files and directories in a {@link Directory} at a point in time, as
+/// returned by {@link Directory.snapshot}.
+#[derive(Debug, Clone, Default, wasm_bindgen_derive::TryFromJsValue)]
+#[wasm_bindgen]
+pub struct DirectorySnapshot {
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    dir: bool,
+    size: u64,
+    modified: u64,
+    /// A file's contents.
+    contents: Option<Blob>,
+}
+
+impl Entry {
+    /// The SHA-256 hash of a file's contents.
+    fn hash(&self) -> Option<WebcHash> {
+        self.contents.as_ref().map(Blob::hash)
+    }
+}
+
+#[wasm_bindgen]
+impl DirectorySnapshot {
+    /// How many files and directories were recorded.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl DirectorySnapshot {
+    /// Walk `fs`, recording every file's contents.
+    ///
+    /// Files with the same size and modification time as in `previous`
+    /// reuse its copy instead of being read again.
+    pub(crate) async fn capture(
+        fs: &dyn FileSystem,
+        previous: Option<&DirectorySnapshot>,
+    ) -> Result<Self, Error> {
+        let mut entries = BTreeMap::new();
+        let mut pending = vec![PathBuf::from("/")];
+        let mut visited = 0;
+
+        while let Some(dir) = pending.pop() {
+            for entry in fs.read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata?;
+                let path = entry.path;
+                let key = path.display().to_string();
+
+                visited += 1;
+                if visited % dir_entries::DEFAULT_BATCH_SIZE == 0 {
+                    dir_entries::yield_to_event_loop().await;
+                }
+
+                if metadata.is_dir() {
+                    let dir = Entry {
+                        dir: true,
+                        size: 0,
+                        modified: metadata.modified,
+                        contents: None,
+                    };
+                    entries.insert(key, dir);
+                    pending.push(path);
+                    continue;
+                }
+
+                let unchanged = previous
+                    .and_then(|p| p.entries.get(&key))
+                    .filter(|e| e.size == metadata.len() && e.modified == metadata.modified)
+                    .and_then(|e| e.contents.clone());
+                let contents = match unchanged {
+                    Some(contents) => contents,
+                    None => {
+                        let mut f = fs.new_open_options().read(true).open(&path)?;
+                        let mut contents = Vec::with_capacity(metadata.len() as usize);
+                        f.read_to_end(&mut contents).await?;
+                        BlobStore::global().insert(Bytes::from(contents))
+                    }
+                };
+
+                let file = Entry {
+                    dir: false,
+                    size: metadata.len(),
+                    modified: metadata.modified,
+                    contents: Some(contents),
+                };
+                entries.insert(key, file);
+            }
+        }
+
+        Ok(DirectorySnapshot { entries })
+    }
+
+    /// Work out which paths were added, removed, or modified between
+    /// `before` and `after`.
+    pub(crate) fn diff(before: &DirectorySnapshot, after: &DirectorySnapshot) -> DirectoryDiff {
+        let mut diff = DirectoryDiff::default();
+
+        for (path, old) in &before.entries {
+            match after.entries.get(path) {
+                None => diff.removed.push(DiffEntry::new(path, old)),
+                Some(new) if new.dir != old.dir || new.hash() != old.hash() => {
+                    diff.modified.push(Modification {
+                        path: path.clone(),
+                        before: DiffEntry::new(path, old),
+                        after: DiffEntry::new(path, new),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (path, new) in &after.entries {
+            if !before.entries.contains_key(path) {
+                diff.added.push(DiffEntry::new(path, new));
+            }
+        }
+
+        diff
+    }
+
+    /// Make `fs` look the way it did when this snapshot was taken, given
+    /// what it looks like `now`.
+    ///
+    /// Anything added since is removed, and only files that are missing or
+    /// have different contents get written.
+    pub(crate) async fn restore(
+        &self,
+        fs: &dyn FileSystem,
+        now: &DirectorySnapshot,
+    ) -> Result<(), Error> {
+        // Note: going in reverse removes a directory's contents before the
+        // directory itself
+        for (path, current) in now.entries.iter().rev() {
+            let wanted = self.entries.get(path);
+            if wanted.is_some_and(|w| w.dir == current.dir) {
+                continue;
+            }
+
+            if current.dir {
+                fs.remove_dir(path.as_ref())?;
+            } else {
+                fs.remove_file(path.as_ref())?;
+            }
+        }
+
+        // Note: parents sort before their children
+        for (path, wanted) in &self.entries {
+            let current = now.entries.get(path).filter(|c| c.dir == wanted.dir);
+
+            match &wanted.contents {
+                None if current.is_none() => fs.create_dir(path.as_ref())?,
+                None => {}
+                Some(contents) => {
+                    if current.is_some_and(|c| c.hash() == wanted.hash()) {
+                        continue;
+                    }
+
+                    let mut f = fs
+                        .new_open_options()
+                        .create(true)
+                        .write(true)
+                        .truncate(true)
+                        .open(path)?;
+                    f.write_all(&contents.bytes()).await?;
+                    f.flush().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct DirectoryDiff {
+    added: Vec<DiffEntry>,
+    removed: Vec<DiffEntry>,
+    modified: Vec<Modification>,
+}
+
+impl DirectoryDiff {
+    pub(crate) fn to_js(&self) -> Result<JsDirectoryDiff, Error> {
+        let value = serde_wasm_bindgen::to_value(self).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DiffEntry {
+    path: String,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+impl DiffEntry {
+    fn new(path: &str, entry: &Entry) -> Self {
+        DiffEntry {
+            path: path.to_string(),
+            ty: if entry.dir { "dir" } else { "file" },
+            size: entry.size,
+            hash: entry.hash().map(|h| h.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Modification {
+    path: String,
+    before: DiffEntry,
+    after: DiffEntry,
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const DIRECTORY_DIFF_TYPE_DEF: &'static str = r#"
+/**
+ * A file or directory in a {@link DirectoryDiff}. `hash` is the hex-encoded
+ * SHA-256 hash of a file's contents.
+ */
+export type DiffEntry = {
+    path: string;
+    type: "file" | "dir";
+    size: number;
+    hash?: string;
+};
+
+/**
+ * What changed between two {@link DirectorySnapshot}s, as returned by
+ * {@link Directory.diff}. Paths are sorted.
+ */
+export type DirectoryDiff = {
+    added: DiffEntry[];
+    removed: DiffEntry[];
+    /** Files whose contents changed, or paths that changed type. */
+    modified: { path: string; before: DiffEntry; after: DiffEntry }[];
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "DirectoryDiff")]
+    pub type JsDirectoryDiff;
+
+    #[wasm_bindgen(typescript_type = "DirectorySnapshot | undefined")]
+    pub type MaybeSnapshot;
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::AsyncWriteExt;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    async fn write(fs: &dyn FileSystem, path: &str, contents: &[u8]) {
+        let mut f = fs
+            .new_open_options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        f.write_all(contents).await.unwrap();
+        f.flush().await.unwrap();
+    }
+
+    #[wasm_bindgen_test]
+    async fn diff_two_snapshots() {
+        let fs = virtual_fs::mem_fs::FileSystem::default();
+        fs.create_dir("/src".as_ref()).unwrap();
+        write(&fs, "/src/main.c", b"int main() {}").await;
+        write(&fs, "/README.md", b"# Hello").await;
+        write(&fs, "/Makefile", b"all:").await;
+        let before = DirectorySnapshot::capture(&fs, None).await.unwrap();
+        assert_eq!(before.length(), 4);
+
+        fs.remove_file("/README.md".as_ref()).unwrap();
+        write(&fs, "/src/main.c", b"int main() { return 1; }").await;
+        write(&fs, "/main.o", b"\0asm").await;
+        let after = DirectorySnapshot::capture(&fs, Some(&before))
+            .await
+            .unwrap();
+
+        let diff = DirectorySnapshot::diff(&before, &after);
+        let paths = |entries: &[DiffEntry]| -> Vec<String> {
+            entries.iter().map(|e| e.path.clone()).collect()
+        };
+        assert_eq!(paths(&diff.added), ["/main.o"]);
+        assert_eq!(paths(&diff.removed), ["/README.md"]);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].path, "/src/main.c");
+        assert_eq!(diff.modified[0].after.size, 24);
+    }
+
+    #[wasm_bindgen_test]
+    async fn restore_a_snapshot() {
+        let fs = virtual_fs::mem_fs::FileSystem::default();
+        fs.create_dir("/src".as_ref()).unwrap();
+        write(&fs, "/src/main.c", b"int main() {}").await;
+        write(&fs, "/README.md", b"# Hello").await;
+        let before = DirectorySnapshot::capture(&fs, None).await.unwrap();
+
+        fs.remove_file("/README.md".as_ref()).unwrap();
+        write(&fs, "/src/main.c", b"int main() { return 1; }").await;
+        fs.create_dir("/build".as_ref()).unwrap();
+        write(&fs, "/build/main.o", b"\0asm").await;
+        let after = DirectorySnapshot::capture(&fs, Some(&before))
+            .await
+            .unwrap();
+
+        before.restore(&fs, &after).await.unwrap();
+
+        let restored = DirectorySnapshot::capture(&fs, None).await.unwrap();
+        let diff = DirectorySnapshot::diff(&before, &restored);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert!(diff.modified.is_empty());
+        // Identical contents are shared rather than copied
+        let readme = |s: &DirectorySnapshot| {
+            let contents = s.entries["/README.md"].contents.as_ref();
+            contents.map(|c| c.bytes().as_ptr())
+        };
+        assert_eq!(readme(&before), readme(&restored));
+    }
+}
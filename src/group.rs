@@ -0,0 +1,406 @@
+use std::{cell::RefCell, rc::Rc};
+
+use futures::{
+    channel::oneshot,
+    future::{Either, LocalBoxFuture, Shared},
+    FutureExt,
+};
+use instant::Duration;
+use js_sys::{Object, Reflect};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer_wasix::types::wasi::Signal;
+
+use crate::{
+    instance::ExitCondition,
+    processes::ProcessTable,
+    tasks::TaskScope,
+    utils::{Error, GlobalScope},
+    Directory, Instance, SpawnOptions, Wasmer,
+};
+
+const DEFAULT_WORKDIR: &str = "/work";
+/// How long [`InstanceGroup::close()`] gives commands to exit before killing
+/// them.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A set of commands from the same package that run concurrently and share a
+/// writable filesystem and environment.
+///
+/// Each {@link Instance} started from the group gets the group's
+/// {@link InstanceGroup.directory} mounted at the same location, so commands
+/// can cooperate through files (e.g. a build watcher writing output that a dev
+/// server picks up).
+///
+/// @example
+/// ```ts
+/// const pkg = await Wasmer.fromRegistry("my/toolchain");
+/// const group = new InstanceGroup(pkg, { env: { NODE_ENV: "development" } });
+///
+/// const watcher = await group.run("build", { args: ["--watch"] });
+/// const server = await group.run("serve");
+///
+/// // later...
+/// const results = await group.close();
+/// ```
+#[derive(Debug)]
+#[wasm_bindgen]
+pub struct InstanceGroup {
+    pkg: Wasmer,
+    dir: Directory,
+    workdir: String,
+    env: Option<Object>,
+    mount: Option<Object>,
+    members: Rc<RefCell<Vec<Member>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    command: String,
+    stdin: Option<web_sys::WritableStream>,
+    exit: Shared<LocalBoxFuture<'static, Option<ExitCondition>>>,
+    process: Option<(u32, ProcessTable)>,
+    tasks: Option<TaskScope>,
+}
+
+impl Member {
+    fn running(&self) -> bool {
+        self.exit.peek().is_none()
+    }
+
+    /// Ask the command to exit by closing its stdin and sending it
+    /// `SIGTERM`.
+    async fn terminate(&self) {
+        if let Some((pid, processes)) = &self.process {
+            // Note: this fails if the program has already exited
+            let _ = processes.signal(*pid, Signal::Sigterm);
+        }
+
+        if let Some(stdin) = self.stdin.as_ref().filter(|s| !s.locked()) {
+            let _ = JsFuture::from(stdin.close()).await;
+        }
+    }
+
+    /// Kill the command and cancel everything it started.
+    fn kill(&self) {
+        tracing::debug!(command = %self.command, "Killing a command which didn't exit in time");
+
+        if let Some((pid, processes)) = &self.process {
+            let _ = processes.signal(*pid, Signal::Sigkill);
+        }
+        if let Some(tasks) = &self.tasks {
+            tasks.cancel();
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl InstanceGroup {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        pkg: &Wasmer,
+        options: Option<InstanceGroupOptions>,
+    ) -> Result<InstanceGroup, Error> {
+        let dir = match options.as_ref().map(|opts| opts.directory()) {
+            Some(value) if !value.is_undefined() => Directory::try_from(&value)
+                .map_err(|_| anyhow::anyhow!("Expected \"directory\" to be a Directory"))?,
+            _ => Directory::default(),
+        };
+
+        Ok(InstanceGroup {
+            pkg: pkg.clone(),
+            dir,
+            workdir: options
+                .as_ref()
+                .and_then(|opts| opts.workdir())
+                .unwrap_or_else(|| DEFAULT_WORKDIR.to_string()),
+            env: options.as_ref().and_then(|opts| opts.env().dyn_into().ok()),
+            mount: options
+                .as_ref()
+                .and_then(|opts| opts.mount().dyn_into().ok()),
+            members: Rc::default(),
+        })
+    }
+
+    /// The filesystem shared by every command in this group.
+    #[wasm_bindgen(getter)]
+    pub fn directory(&self) -> Directory {
+        self.dir.clone()
+    }
+
+    /// Where {@link InstanceGroup.directory} is mounted inside each instance.
+    #[wasm_bindgen(getter)]
+    pub fn workdir(&self) -> String {
+        self.workdir.clone()
+    }
+
+    /// The number of commands that have been started and haven't exited
+    /// yet.
+    #[wasm_bindgen(getter)]
+    pub fn running(&self) -> usize {
+        self.members.borrow().iter().filter(|m| m.running()).count()
+    }
+
+    /// Start one of the package's commands as part of this group.
+    ///
+    /// The group's environment variables and mounts are applied first, so
+    /// anything in `options` takes precedence.
+    pub async fn run(
+        &self,
+        command: String,
+        options: Option<SpawnOptions>,
+    ) -> Result<Instance, Error> {
+        let cmd = self.pkg.command(&command).ok_or_else(|| {
+            anyhow::anyhow!("The package doesn't contain a \"{command}\" command")
+        })?;
+
+        let options = self.spawn_options(options)?;
+        let mut instance = cmd.run(Some(options)).await?;
+
+        // Keep an eye on when the command exits, while still letting the
+        // caller wait() on the instance.
+        let (sender, receiver) = oneshot::channel();
+        let exit = std::mem::replace(&mut instance.exit, receiver);
+        let exit = exit.map(|result| result.ok()).boxed_local().shared();
+
+        wasm_bindgen_futures::spawn_local({
+            let exit = exit.clone();
+            async move {
+                if let Some(condition) = exit.await {
+                    let _ = sender.send(condition);
+                }
+            }
+        });
+
+        self.members.borrow_mut().push(Member {
+            command,
+            stdin: instance.stdin.clone(),
+            exit,
+            process: instance.process.clone(),
+            tasks: instance.tasks.clone(),
+        });
+
+        Ok(instance)
+    }
+
+    /// Wait for every command in the group to exit.
+    pub async fn wait(&self) -> Result<ListOfGroupResults, Error> {
+        let pending: Vec<_> = self
+            .members
+            .borrow()
+            .iter()
+            .map(|m| (m.command.clone(), m.exit.clone()))
+            .collect();
+
+        let results = js_sys::Array::new();
+
+        for (command, exit) in pending {
+            let result = Object::new();
+            Reflect::set(&result, &"command".into(), &command.into()).map_err(Error::js)?;
+            let code = match exit.await {
+                Some(ExitCondition(code)) => JsValue::from(code),
+                None => JsValue::NULL,
+            };
+            Reflect::set(&result, &"code".into(), &code).map_err(Error::js)?;
+            results.push(&result);
+        }
+
+        Ok(results.unchecked_into())
+    }
+
+    /// Tear the group down, then wait for every command to exit.
+    ///
+    /// Each running command has its stdin closed and is sent `SIGTERM` (if
+    /// it was started in a way that lets it receive signals). Commands still
+    /// running after `gracePeriod` milliseconds (1000 unless specified) are
+    /// sent `SIGKILL` and have their tasks cancelled, which wakes sleeping
+    /// threads and aborts in-flight HTTP requests.
+    ///
+    /// Streams the caller has locked (e.g. by calling `getWriter()`) are left
+    /// alone and must be closed by whoever holds the lock.
+    pub async fn close(&self, grace_period: Option<f64>) -> Result<ListOfGroupResults, Error> {
+        let grace_period = match grace_period {
+            Some(ms) if ms.is_finite() && ms >= 0.0 => Duration::from_secs_f64(ms / 1000.0),
+            Some(ms) => {
+                let msg = format!("The grace period must be positive, not {ms}");
+                return Err(Error::js(js_sys::RangeError::new(&msg)));
+            }
+            None => DEFAULT_GRACE_PERIOD,
+        };
+
+        let running: Vec<Member> = self
+            .members
+            .borrow()
+            .iter()
+            .filter(|m| m.running())
+            .cloned()
+            .collect();
+        stop(&running, grace_period).await;
+
+        self.wait().await
+    }
+}
+
+impl InstanceGroup {
+    /// Layer the per-command options on top of the group's.
+    fn spawn_options(&self, options: Option<SpawnOptions>) -> Result<SpawnOptions, Error> {
+        let merged = Object::new();
+        if let Some(options) = &options {
+            Object::assign(&merged, options);
+        }
+
+        let env = Object::new();
+        if let Some(group_env) = &self.env {
+            Object::assign(&env, group_env);
+        }
+        if let Some(options) = &options {
+            if let Some(command_env) = record_field(options, "env")? {
+                Object::assign(&env, &command_env);
+            }
+        }
+        Reflect::set(&merged, &"env".into(), &env).map_err(Error::js)?;
+
+        let mount = Object::new();
+        if let Some(group_mount) = &self.mount {
+            Object::assign(&mount, group_mount);
+        }
+        Reflect::set(
+            &mount,
+            &self.workdir.as_str().into(),
+            &JsValue::from(self.dir.clone()),
+        )
+        .map_err(Error::js)?;
+        if let Some(options) = &options {
+            if let Some(command_mount) = record_field(options, "mount")? {
+                Object::assign(&mount, &command_mount);
+            }
+        }
+        Reflect::set(&merged, &"mount".into(), &mount).map_err(Error::js)?;
+
+        Ok(merged.unchecked_into())
+    }
+}
+
+/// Ask every member to exit, killing whichever ones are still running once
+/// the grace period is up.
+async fn stop(members: &[Member], grace_period: Duration) {
+    for member in members {
+        member.terminate().await;
+    }
+
+    let exited = futures::future::join_all(members.iter().map(|m| m.exit.clone()));
+    let ms = i32::try_from(grace_period.as_millis()).unwrap_or(i32::MAX);
+    let timeout = JsFuture::from(GlobalScope::current().sleep(ms));
+    if let Either::Left(_) = futures::future::select(exited, timeout).await {
+        return;
+    }
+
+    for member in members.iter().filter(|m| m.running()) {
+        member.kill();
+    }
+}
+
+fn record_field(obj: &JsValue, field: &str) -> Result<Option<Object>, Error> {
+    let value = Reflect::get(obj, &field.into()).map_err(Error::js)?;
+    Ok(value.dyn_into().ok())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const INSTANCE_GROUP_TYPE_DECLARATIONS: &str = r#"
+/**
+ * Options used when constructing an {@link InstanceGroup}.
+ */
+export type InstanceGroupOptions = {
+    /** Environment variables set for every command in the group. */
+    env?: Record<string, string>;
+    /** Directories mounted into every command in the group. */
+    mount?: Record<string, DirectoryInit | Directory>;
+    /**
+     * The filesystem shared by every command. Defaults to a new, empty
+     * {@link Directory}.
+     */
+    directory?: Directory;
+    /**
+     * Where the shared filesystem is mounted in each command.
+     *
+     * Defaults to `"/work"`.
+     */
+    workdir?: string;
+};
+
+/**
+ * How one of the commands in an {@link InstanceGroup} exited.
+ */
+export type GroupResult = {
+    /** The name of the command. */
+    command: string;
+    /** The exit code, or `null` if the command never reported one. */
+    code: number | null;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "InstanceGroupOptions")]
+    pub type InstanceGroupOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn env(this: &InstanceGroupOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn mount(this: &InstanceGroupOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn directory(this: &InstanceGroupOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn workdir(this: &InstanceGroupOptions) -> Option<String>;
+
+    #[wasm_bindgen(typescript_type = "GroupResult[]")]
+    pub type ListOfGroupResults;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    fn member(processes: &ProcessTable, exit: oneshot::Receiver<ExitCondition>) -> Member {
+        Member {
+            command: "serve".to_string(),
+            stdin: None,
+            exit: exit.map(|result| result.ok()).boxed_local().shared(),
+            process: Some((processes.reserve(true), processes.clone())),
+            tasks: None,
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn commands_that_ignore_sigterm_are_killed() {
+        let processes = ProcessTable::default();
+        let (_sender, exit) = oneshot::channel();
+        let stubborn = member(&processes, exit);
+        let pid = stubborn.process.as_ref().unwrap().0;
+
+        stop(&[stubborn], Duration::ZERO).await;
+
+        assert_eq!(
+            processes.pending_signals(pid).unwrap(),
+            [Signal::Sigterm, Signal::Sigkill]
+        );
+    }
+
+    #[wasm_bindgen_test]
+    async fn commands_that_exit_in_time_are_left_alone() {
+        let processes = ProcessTable::default();
+        let (sender, exit) = oneshot::channel();
+        let polite = member(&processes, exit);
+        let pid = polite.process.as_ref().unwrap().0;
+        sender.send(ExitCondition(0)).unwrap();
+
+        stop(&[polite], Duration::from_secs(10)).await;
+
+        assert_eq!(processes.pending_signals(pid).unwrap(), [Signal::Sigterm]);
+    }
+}
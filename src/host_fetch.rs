@@ -0,0 +1,611 @@
+//! A `host_fetch` extension which lets guests make HTTP requests through the
+//! host, limited to origins the host has approved.
+//!
+//! Programs started with `runWasix()` can import these functions from the
+//! `wasmer_host` module. Each returns a WASI errno.
+//!
+//! ```text
+//! host_fetch(url_ptr, url_len, method_ptr, method_len, body_ptr, body_len,
+//!            status_out, handle_out) -> errno
+//! host_fetch_read(handle, buf_ptr, buf_len, nread_out) -> errno
+//! host_fetch_close(handle) -> errno
+//! ```
+//!
+//! `host_fetch_read()` copies the next part of the response body into guest
+//! memory, setting `nread_out` to `0` once the whole body has been read. The
+//! body is streamed from the browser as the guest reads it, and reads fail
+//! with `EFBIG` once it grows past the runtime's `maxResponseBytes`.
+//! Requests to origins that haven't been approved fail with `EACCES`.
+//!
+//! Redirects aren't followed, because they could lead to an origin that
+//! hasn't been approved. Requests which get redirected fail with `EACCES`
+//! too.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::Shared,
+    FutureExt, SinkExt, StreamExt,
+};
+use http::Method;
+use js_sys::{Function, Promise};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer::{
+    imports, AsStoreMut, Function as WasmFunction, FunctionEnv, FunctionEnvMut, Imports, Memory,
+    WasmPtr,
+};
+use wasmer_wasix::types::wasi::Errno;
+
+use crate::{
+    capabilities::{Capabilities, Capability},
+    permissions::{PermissionPrompt, PermissionRequest},
+    tasks::ThreadPool,
+    utils::GlobalScope,
+};
+
+/// The import module the functions are exposed under.
+pub(crate) const NAMESPACE: &str = "wasmer_host";
+
+/// The largest response body a guest may read, unless the runtime says
+/// otherwise.
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+/// How many chunks of a response body are read ahead of the guest.
+const READ_AHEAD: usize = 4;
+
+thread_local! {
+    /// Approval callbacks, which live on the scheduler's thread.
+    static APPROVERS: RefCell<BTreeMap<u32, Function>> = RefCell::default();
+}
+
+/// Which origins guests may fetch from.
+///
+/// Anything that isn't pre-approved is denied unless an approval callback
+/// says otherwise, and its decision is remembered for the rest of the
+/// runtime's life.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FetchPolicy(Arc<Mutex<PolicyState>>);
+
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+struct PolicyState {
+    allowed: BTreeSet<String>,
+    decisions: BTreeMap<String, bool>,
+    /// Origins the host is being asked about, so concurrent requests wait
+    /// for the same answer instead of asking again.
+    #[derivative(Debug = "ignore")]
+    pending: BTreeMap<String, Shared<oneshot::Receiver<bool>>>,
+    approver: Option<u32>,
+    /// Used for origins which haven't been approved when there is no
+    /// approval callback.
+    prompt: Option<PermissionPrompt>,
+    max_response_bytes: Option<usize>,
+}
+
+impl FetchPolicy {
+    pub(crate) fn allow(&self, origin: &str) -> Result<(), crate::utils::Error> {
+        let origin = normalize_origin(origin)?;
+        self.0.lock().unwrap().allowed.insert(origin);
+        Ok(())
+    }
+
+    /// Ask `callback` whenever an origin that hasn't been approved is
+    /// requested.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn set_approver(&self, callback: Function) {
+        static NEXT_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        APPROVERS.with(|approvers| approvers.borrow_mut().insert(id, callback));
+        self.0.lock().unwrap().approver = Some(id);
+    }
+
+    /// Fall back to asking `prompt` about origins which haven't been
+    /// approved, if there is no approval callback.
+    pub(crate) fn set_prompt(&self, prompt: PermissionPrompt) {
+        self.0.lock().unwrap().prompt = Some(prompt);
+    }
+
+    /// Limit how much of a response body guests may read.
+    pub(crate) fn set_max_response_bytes(&self, max: usize) {
+        self.0.lock().unwrap().max_response_bytes = Some(max);
+    }
+
+    fn max_response_bytes(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .max_response_bytes
+            .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES)
+    }
+
+    /// Check whether `origin` may be fetched from, asking the approval
+    /// callback on the scheduler's thread if necessary.
+    ///
+    /// Only one request per origin asks, and any others made while it is
+    /// waiting get the same answer.
+    async fn check(&self, origin: &str, pool: &ThreadPool) -> bool {
+        let asking = {
+            let mut state = self.0.lock().unwrap();
+            if state.allowed.contains(origin) {
+                return true;
+            }
+            if let Some(&decision) = state.decisions.get(origin) {
+                return decision;
+            }
+
+            match state.pending.get(origin) {
+                Some(pending) => Err(pending.clone()),
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    state.pending.insert(origin.to_string(), receiver.shared());
+                    Ok((state.approver, state.prompt.clone(), sender))
+                }
+            }
+        };
+        let (approver, prompt, sender) = match asking {
+            Ok(asking) => asking,
+            Err(pending) => return pending.await.unwrap_or(false),
+        };
+
+        let approved = self.ask_host(origin, approver, prompt, pool).await;
+        self.0.lock().unwrap().pending.remove(origin);
+        let _ = sender.send(approved);
+        approved
+    }
+
+    /// Ask the approval callback, or the permission prompt if there isn't
+    /// one, about `origin`.
+    async fn ask_host(
+        &self,
+        origin: &str,
+        approver: Option<u32>,
+        prompt: Option<PermissionPrompt>,
+        pool: &ThreadPool,
+    ) -> bool {
+        let Some(approver) = approver else {
+            return match prompt {
+                Some(prompt) if prompt.is_enabled() => {
+                    let request = PermissionRequest::Fetch {
+                        origin: origin.to_string(),
+                    };
+                    prompt.check(request, pool).await
+                }
+                _ => false,
+            };
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let requested = origin.to_string();
+        pool.run_on_scheduler(Box::new(move || {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = sender.send(ask(approver, &requested).await);
+            });
+        }));
+
+        let approved = receiver.await.unwrap_or(false);
+        tracing::debug!(%origin, approved, "Asked the host about a fetch");
+        self.0
+            .lock()
+            .unwrap()
+            .decisions
+            .insert(origin.to_string(), approved);
+        approved
+    }
+}
+
+/// Call an approval callback, treating errors as a denial.
+async fn ask(approver: u32, origin: &str) -> bool {
+    let Some(callback) = APPROVERS.with(|approvers| approvers.borrow().get(&approver).cloned())
+    else {
+        return false;
+    };
+
+    let outcome = match callback.call1(&JsValue::NULL, &JsValue::from_str(origin)) {
+        Ok(value) => match value.dyn_into::<Promise>() {
+            Ok(promise) => JsFuture::from(promise).await,
+            Err(value) => Ok(value),
+        },
+        Err(e) => Err(e),
+    };
+
+    match outcome {
+        Ok(value) => value.as_bool().unwrap_or(false),
+        Err(e) => {
+            tracing::warn!(error = ?e, %origin, "The fetch approval callback failed");
+            false
+        }
+    }
+}
+
+fn normalize_origin(origin: &str) -> Result<String, crate::utils::Error> {
+    let url: url::Url = origin.parse()?;
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Make a request on one of the pool's workers, returning the status code
+/// and a stream of the body's chunks.
+///
+/// The guest's own worker is blocked in the syscall, so `fetch()` wouldn't
+/// be able to resolve there.
+async fn fetch(
+    pool: &ThreadPool,
+    url: url::Url,
+    method: Method,
+    body: Option<Vec<u8>>,
+    max_bytes: usize,
+) -> Result<(u16, BodyChunks), Errno> {
+    let (sender, receiver) = oneshot::channel();
+    let (chunks, body_chunks) = mpsc::channel(READ_AHEAD);
+    pool.spawn(Box::new(move || {
+        Box::pin(async move {
+            match fetch_without_redirects(&url, &method, body).await {
+                Ok(response) => {
+                    let _ = sender.send(Ok(response.status()));
+                    stream_body(&url, &response, max_bytes, chunks).await;
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                }
+            }
+        })
+    }))
+    .map_err(|_| Errno::Io)?;
+
+    let status = receiver.await.unwrap_or(Err(Errno::Canceled))?;
+    Ok((status, body_chunks))
+}
+
+/// The chunks of a response body, as they are read by the worker which made
+/// the request.
+type BodyChunks = mpsc::Receiver<Result<Vec<u8>, Errno>>;
+
+async fn fetch_without_redirects(
+    url: &url::Url,
+    method: &Method,
+    body: Option<Vec<u8>>,
+) -> Result<web_sys::Response, Errno> {
+    let failed = |e: JsValue| {
+        tracing::debug!(error = ?e, %url, "A guest fetch failed");
+        Errno::Io
+    };
+
+    let mut init = web_sys::RequestInit::new();
+    init.method(method.as_str());
+    // Note: a manual redirect gives us an opaque response instead of
+    // sending the request (and its body) somewhere else
+    init.redirect(web_sys::RequestRedirect::Manual);
+    if let Some(body) = body {
+        init.body(Some(&js_sys::Uint8Array::from(&body[..])));
+    }
+    let request = web_sys::Request::new_with_str_and_init(url.as_str(), &init).map_err(failed)?;
+
+    let response: web_sys::Response = JsFuture::from(GlobalScope::current().fetch(&request))
+        .await
+        .map_err(failed)?
+        .unchecked_into();
+    if response.type_() == web_sys::ResponseType::Opaqueredirect {
+        tracing::debug!(%url, "Denied a guest fetch which was redirected");
+        return Err(Errno::Acces);
+    }
+
+    Ok(response)
+}
+
+/// Read the response body, sending each chunk to the guest as it is able to
+/// take them.
+///
+/// Reading stops (and the request is cancelled) once the body grows past
+/// `max_bytes` or the guest closes the response.
+async fn stream_body(
+    url: &url::Url,
+    response: &web_sys::Response,
+    max_bytes: usize,
+    mut chunks: mpsc::Sender<Result<Vec<u8>, Errno>>,
+) {
+    let Some(body) = response.body() else {
+        return;
+    };
+    let reader: web_sys::ReadableStreamDefaultReader = body.get_reader().unchecked_into();
+    let mut total = 0;
+
+    loop {
+        let chunk = match JsFuture::from(reader.read()).await {
+            Ok(result) => {
+                let field = |name: &str| js_sys::Reflect::get(&result, &JsValue::from_str(name));
+                if field("done").ok().and_then(|done| done.as_bool()) != Some(false) {
+                    return;
+                }
+                field("value")
+                    .map(|value| value.unchecked_into::<js_sys::Uint8Array>().to_vec())
+                    .map_err(|_| Errno::Io)
+            }
+            Err(e) => {
+                tracing::debug!(error = ?e, %url, "Unable to read a guest fetch's body");
+                Err(Errno::Io)
+            }
+        };
+
+        let chunk = chunk.and_then(|chunk| {
+            total += chunk.len();
+            if total > max_bytes {
+                tracing::debug!(%url, max_bytes, "A guest fetch's body was too large");
+                Err(Errno::Fbig)
+            } else {
+                Ok(chunk)
+            }
+        });
+        let failed = chunk.is_err();
+
+        if chunks.send(chunk).await.is_err() || failed {
+            // Note: the guest doesn't want the rest of the body
+            let _ = JsFuture::from(reader.cancel()).await;
+            return;
+        }
+    }
+}
+
+/// State shared by the `host_fetch` functions of a single instance.
+#[derive(Debug)]
+pub(crate) struct HostFetch {
+    pub(crate) policy: FetchPolicy,
+    pub(crate) pool: ThreadPool,
+    pub(crate) capabilities: Capabilities,
+    /// The program making the requests.
+    pub(crate) pid: u32,
+}
+
+#[derive(Debug)]
+struct FetchEnv {
+    config: HostFetch,
+    memory: Option<Memory>,
+    responses: BTreeMap<u32, Response>,
+    next_handle: u32,
+}
+
+#[derive(Debug)]
+struct Response {
+    chunks: BodyChunks,
+    /// The chunk the guest is part-way through reading.
+    chunk: Vec<u8>,
+    offset: usize,
+    /// Set once reading the body failed, so the guest keeps seeing the
+    /// error instead of a truncated body.
+    error: Option<Errno>,
+}
+
+impl HostFetch {
+    /// Create the imports, returning a handle which needs to be given the
+    /// instance's memory once it has been instantiated.
+    pub(crate) fn imports(self, store: &mut impl AsStoreMut) -> (Imports, HostFetchMemory) {
+        let env = FunctionEnv::new(
+            store,
+            FetchEnv {
+                config: self,
+                memory: None,
+                responses: BTreeMap::new(),
+                next_handle: 1,
+            },
+        );
+
+        let imports = imports! {
+            NAMESPACE => {
+                "host_fetch" => WasmFunction::new_typed_with_env(store, &env, host_fetch),
+                "host_fetch_read" => WasmFunction::new_typed_with_env(store, &env, host_fetch_read),
+                "host_fetch_close" => WasmFunction::new_typed_with_env(store, &env, host_fetch_close),
+            }
+        };
+
+        (imports, HostFetchMemory(env))
+    }
+}
+
+/// Gives the `host_fetch` functions access to the guest's memory.
+pub(crate) struct HostFetchMemory(FunctionEnv<FetchEnv>);
+
+impl HostFetchMemory {
+    pub(crate) fn attach(&self, store: &mut impl AsStoreMut, memory: Memory) {
+        self.0.as_mut(store).memory = Some(memory);
+    }
+}
+
+fn errno(e: Errno) -> i32 {
+    e as i32
+}
+
+fn read_string(
+    env: &FunctionEnvMut<'_, FetchEnv>,
+    ptr: WasmPtr<u8>,
+    len: u32,
+) -> Result<String, Errno> {
+    let memory = env.data().memory.as_ref().ok_or(Errno::Fault)?;
+    let view = memory.view(env);
+    ptr.read_utf8_string(&view, len).map_err(|_| Errno::Inval)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn host_fetch(
+    mut env: FunctionEnvMut<'_, FetchEnv>,
+    url: WasmPtr<u8>,
+    url_len: u32,
+    method: WasmPtr<u8>,
+    method_len: u32,
+    body: WasmPtr<u8>,
+    body_len: u32,
+    status_out: WasmPtr<u32>,
+    handle_out: WasmPtr<u32>,
+) -> i32 {
+    let result = (|| -> Result<(u32, u32), Errno> {
+        let url: url::Url = read_string(&env, url, url_len)?
+            .parse()
+            .map_err(|_| Errno::Inval)?;
+        let method: Method = read_string(&env, method, method_len)?
+            .parse()
+            .map_err(|_| Errno::Inval)?;
+        let body = if body_len > 0 {
+            let memory = env.data().memory.as_ref().ok_or(Errno::Fault)?;
+            let view = memory.view(&env);
+            let bytes = body.slice(&view, body_len).map_err(|_| Errno::Fault)?;
+            Some(bytes.read_to_vec().map_err(|_| Errno::Fault)?)
+        } else {
+            None
+        };
+
+        let config = &env.data().config;
+        let origin = url.origin().ascii_serialization();
+        if !config.capabilities.check(
+            Capability::Network,
+            &config.pool,
+            Some(config.pid),
+            Some(format!("host_fetch({origin})")),
+        ) {
+            return Err(Errno::Acces);
+        }
+
+        let (status, chunks) = crate::blocking::block_on("host_fetch()", async {
+            if !config.policy.check(&origin, &config.pool).await {
+                tracing::debug!(%origin, "Denied a guest fetch");
+                return Err(Errno::Acces);
+            }
+
+            let max_bytes = config.policy.max_response_bytes();
+            fetch(&config.pool, url, method, body, max_bytes).await
+        })?;
+
+        let state = env.data_mut();
+        let handle = state.next_handle;
+        state.next_handle += 1;
+        let response = Response {
+            chunks,
+            chunk: Vec::new(),
+            offset: 0,
+            error: None,
+        };
+        state.responses.insert(handle, response);
+
+        Ok((u32::from(status), handle))
+    })();
+
+    let (status, handle) = match result {
+        Ok(values) => values,
+        Err(e) => return errno(e),
+    };
+
+    let Some(memory) = env.data().memory.clone() else {
+        return errno(Errno::Fault);
+    };
+    let view = memory.view(&env);
+    match status_out
+        .write(&view, status)
+        .and_then(|_| handle_out.write(&view, handle))
+    {
+        Ok(()) => errno(Errno::Success),
+        Err(_) => errno(Errno::Fault),
+    }
+}
+
+fn host_fetch_read(
+    mut env: FunctionEnvMut<'_, FetchEnv>,
+    handle: u32,
+    buf: WasmPtr<u8>,
+    buf_len: u32,
+    nread_out: WasmPtr<u32>,
+) -> i32 {
+    let (state, store) = env.data_and_store_mut();
+    let Some(memory) = state.memory.clone() else {
+        return errno(Errno::Fault);
+    };
+    let Some(response) = state.responses.get_mut(&handle) else {
+        return errno(Errno::Badf);
+    };
+
+    // Wait for the next chunk once we've run out, skipping empty ones
+    while response.offset == response.chunk.len() {
+        if let Some(e) = response.error {
+            return errno(e);
+        }
+        match crate::blocking::block_on("reading a host_fetch() response", response.chunks.next()) {
+            Some(Ok(chunk)) => {
+                response.chunk = chunk;
+                response.offset = 0;
+            }
+            Some(Err(e)) => response.error = Some(e),
+            None => break,
+        }
+    }
+
+    let remaining = &response.chunk[response.offset..];
+    let n = remaining.len().min(buf_len as usize);
+    let view = memory.view(&store);
+
+    let written = buf
+        .slice(&view, n as u32)
+        .and_then(|slice| slice.write_slice(&remaining[..n]))
+        .and_then(|_| nread_out.write(&view, n as u32));
+
+    match written {
+        Ok(()) => {
+            response.offset += n;
+            errno(Errno::Success)
+        }
+        Err(_) => errno(Errno::Fault),
+    }
+}
+
+fn host_fetch_close(mut env: FunctionEnvMut<'_, FetchEnv>, handle: u32) -> i32 {
+    match env.data_mut().responses.remove(&handle) {
+        Some(_) => errno(Errno::Success),
+        None => errno(Errno::Badf),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use wasm_bindgen::closure::Closure;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn only_approved_origins_are_allowed() {
+        let policy = FetchPolicy::default();
+        policy.allow("https://example.com/some/path").unwrap();
+        let pool = ThreadPool::new();
+
+        assert!(policy.check("https://example.com", &pool).await);
+        // Without an approval callback, everything else is denied
+        assert!(!policy.check("https://evil.example", &pool).await);
+    }
+
+    #[wasm_bindgen_test]
+    async fn concurrent_requests_only_ask_once() {
+        let policy = FetchPolicy::default();
+        let pool = ThreadPool::new();
+        let asked = Rc::new(Cell::new(0));
+        let approve = Closure::<dyn FnMut(String) -> bool>::new({
+            let asked = Rc::clone(&asked);
+            move |_origin: String| {
+                asked.set(asked.get() + 1);
+                true
+            }
+        });
+        policy.set_approver(approve.as_ref().unchecked_ref::<Function>().clone());
+
+        let (first, second) = futures::join!(
+            policy.check("https://example.com", &pool),
+            policy.check("https://example.com", &pool),
+        );
+
+        assert!(first && second);
+        assert_eq!(asked.get(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+//! A read-only `/proc` describing the environment a guest is running in, so
+//! it can adapt (e.g. by sizing its own thread pool or heap).
+//!
+//! Besides our own `/proc/host`, there are minimal versions of the Linux
+//! `/proc/cpuinfo`, `/proc/meminfo` and `/proc/self/stat` files, backed by
+//! what the browser reports and the program's [`ResourceUsage`].
+
+use std::{fmt::Write, sync::Arc};
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Generator},
+    usage::ResourceUsage,
+    utils::GlobalScope,
+};
+
+/// The most memory a 32-bit WebAssembly program can address, in kB.
+const MAX_MEMORY_KB: u64 = 4 * 1024 * 1024;
+/// The clock ticks per second that `/proc/self/stat` times are measured in
+/// (i.e. `sysconf(_SC_CLK_TCK)`).
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Where the host metadata directory gets mounted.
+pub(crate) const MOUNT_POINT: &str = "/proc";
+
+/// Facts about the host that guests may want to know.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HostInfo {
+    pub(crate) user_agent_family: &'static str,
+    pub(crate) cores: Option<usize>,
+    pub(crate) cross_origin_isolated: bool,
+    pub(crate) features: Vec<&'static str>,
+}
+
+impl HostInfo {
+    pub(crate) fn current() -> Self {
+        let scope = GlobalScope::current();
+        let cross_origin_isolated = scope.cross_origin_isolated().unwrap_or(false);
+
+        let mut features = Vec::new();
+        if cross_origin_isolated {
+            features.push("threads");
+        }
+        if scope.supports_jspi() {
+            features.push("jspi");
+        }
+        if scope.is_mobile() {
+            features.push("mobile");
+        }
+
+        HostInfo {
+            user_agent_family: user_agent_family(scope.user_agent().as_deref().unwrap_or("")),
+            cores: scope.hardware_concurrency().map(|n| n.get()),
+            cross_origin_isolated,
+            features,
+        }
+    }
+
+    /// Render the `/proc/host` file.
+    ///
+    /// Each line is a `key=value` pair, with unknown values left empty.
+    pub(crate) fn render(&self) -> String {
+        let cores = self.cores.map(|n| n.to_string()).unwrap_or_default();
+
+        format!(
+            "user_agent_family={}\ncores={cores}\ncross_origin_isolated={}\nruntime_version={}\nfeatures={}\n",
+            self.user_agent_family,
+            self.cross_origin_isolated,
+            env!("CARGO_PKG_VERSION"),
+            self.features.join(","),
+        )
+    }
+
+    /// Render `/proc/cpuinfo`, with one entry per core.
+    pub(crate) fn render_cpuinfo(&self) -> String {
+        let cores = self.cores.unwrap_or(1);
+        let mut cpuinfo = String::new();
+
+        for processor in 0..cores {
+            let _ = write!(
+                cpuinfo,
+                "processor\t: {processor}\nvendor_id\t: WebAssembly\nmodel name\t: WebAssembly ({})\ncpu cores\t: {cores}\n\n",
+                self.user_agent_family,
+            );
+        }
+
+        cpuinfo
+    }
+
+    /// A directory containing the host metadata files, ready to be mounted
+    /// at [`MOUNT_POINT`].
+    pub(crate) fn filesystem(&self, usage: Arc<ResourceUsage>) -> DeviceFileSystem {
+        let fs = DeviceFileSystem::default();
+        fs.insert("host", Device::Static(self.render().into_bytes()));
+        fs.insert(
+            "cpuinfo",
+            Device::Static(self.render_cpuinfo().into_bytes()),
+        );
+        fs.insert(
+            "meminfo",
+            Device::Generated(Generator::new(|| {
+                let scope = GlobalScope::current();
+                render_meminfo(total_memory_kb(&scope), used_memory_kb(&scope)).into_bytes()
+            })),
+        );
+        fs.insert(
+            "self/stat",
+            Device::Generated(Generator::new(move || {
+                render_stat(usage.snapshot().busy_time_ms).into_bytes()
+            })),
+        );
+        fs
+    }
+}
+
+/// How much memory the guest can use, capped at what 32-bit WebAssembly can
+/// address.
+fn total_memory_kb(scope: &GlobalScope) -> u64 {
+    let device_memory_kb = scope
+        .lookup(&["navigator", "deviceMemory"])
+        .and_then(|gb| gb.as_f64())
+        .map(|gb| (gb * 1024.0 * 1024.0) as u64);
+
+    device_memory_kb.map_or(MAX_MEMORY_KB, |kb| kb.min(MAX_MEMORY_KB))
+}
+
+/// The JavaScript heap in use, on browsers that report it.
+fn used_memory_kb(scope: &GlobalScope) -> Option<u64> {
+    scope
+        .lookup(&["performance", "memory", "usedJSHeapSize"])
+        .and_then(|bytes| bytes.as_f64())
+        .map(|bytes| bytes as u64 / 1024)
+}
+
+fn render_meminfo(total_kb: u64, used_kb: Option<u64>) -> String {
+    let available_kb = total_kb.saturating_sub(used_kb.unwrap_or(0));
+    format!(
+        "MemTotal:       {total_kb:>8} kB\nMemFree:        {available_kb:>8} kB\nMemAvailable:   {available_kb:>8} kB\n"
+    )
+}
+
+/// Render `/proc/self/stat`, filling in the fields we know (the state,
+/// thread count, and time spent running) and zeroing the rest.
+///
+/// We can't measure CPU time, so `utime` is the wall-clock time the program
+/// has kept a worker busy.
+fn render_stat(busy_time_ms: f64) -> String {
+    let utime = (busy_time_ms / 1000.0 * CLOCK_TICKS_PER_SECOND) as u64;
+
+    // pid (comm) state ppid pgrp session tty_nr tpgid flags minflt cminflt
+    // majflt cmajflt utime stime cutime cstime priority nice num_threads
+    let mut stat = format!("1 (wasix) R 0 1 1 0 -1 0 0 0 0 0 {utime} 0 0 0 20 0 1");
+    // The remaining 32 fields, from itrealvalue to exit_code
+    for _ in 0..32 {
+        stat.push_str(" 0");
+    }
+    stat.push('\n');
+
+    stat
+}
+
+/// Reduce a `User-Agent` string to the browser (or runtime) family.
+fn user_agent_family(user_agent: &str) -> &'static str {
+    // Note: order matters because most browsers claim to be several others
+    // for compatibility reasons.
+    const FAMILIES: &[(&str, &str)] = &[
+        ("Deno/", "deno"),
+        ("Node.js/", "node"),
+        ("Firefox/", "firefox"),
+        ("Edg/", "edge"),
+        ("Chrome/", "chrome"),
+        ("Safari/", "safari"),
+    ];
+
+    FAMILIES
+        .iter()
+        .find(|(needle, _)| user_agent.contains(needle))
+        .map(|(_, family)| *family)
+        .unwrap_or("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::FileSystem;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn detect_user_agent_families() {
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+        let safari = "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_2) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15";
+
+        assert_eq!(user_agent_family(chrome), "chrome");
+        assert_eq!(user_agent_family(safari), "safari");
+        assert_eq!(user_agent_family(""), "unknown");
+    }
+
+    #[wasm_bindgen_test]
+    fn render_the_host_file() {
+        let info = HostInfo {
+            user_agent_family: "firefox",
+            cores: Some(8),
+            cross_origin_isolated: true,
+            features: vec!["threads", "jspi"],
+        };
+
+        let rendered = info.render();
+
+        assert!(rendered.contains("user_agent_family=firefox\n"));
+        assert!(rendered.contains("cores=8\n"));
+        assert!(rendered.contains("features=threads,jspi\n"));
+    }
+
+    #[wasm_bindgen_test]
+    fn render_linux_proc_files() {
+        let info = HostInfo {
+            user_agent_family: "chrome",
+            cores: Some(2),
+            cross_origin_isolated: false,
+            features: Vec::new(),
+        };
+
+        assert_eq!(info.render_cpuinfo().matches("processor").count(), 2);
+        assert!(render_meminfo(MAX_MEMORY_KB, Some(1024)).contains("MemAvailable:    4193280 kB"));
+        let stat = render_stat(2500.0);
+        assert_eq!(stat.split_whitespace().count(), 52);
+        assert_eq!(stat.split_whitespace().nth(13), Some("250"));
+
+        let fs = info.filesystem(Arc::default());
+        let entries: Vec<_> = fs
+            .read_dir("/".as_ref())
+            .unwrap()
+            .map(|entry| entry.unwrap().path.display().to_string())
+            .collect();
+        assert_eq!(entries, ["/cpuinfo", "/host", "/meminfo", "/self"]);
+        assert!(fs.metadata("/self".as_ref()).unwrap().is_dir());
+    }
+}
@@ -0,0 +1,122 @@
+//! Thin `async` helpers around the browser's IndexedDB API.
+
+use js_sys::{Promise, Reflect};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbDatabase, IdbObjectStore, IdbOpenDbRequest, IdbRequest, IdbTransaction, IdbTransactionMode,
+};
+
+use crate::utils::{Error, GlobalScope};
+
+/// Open (or create) a database containing a single object store.
+pub(crate) async fn open(name: &str, store_name: &str) -> Result<IdbDatabase, Error> {
+    let factory = GlobalScope::current()
+        .indexed_db()
+        .ok_or_else(|| anyhow::anyhow!("IndexedDB is not available in this context"))?;
+
+    let request: IdbOpenDbRequest = factory.open_with_u32(name, 1).map_err(Error::js)?;
+
+    let on_upgrade_needed: Closure<dyn FnMut(web_sys::Event)> = Closure::new({
+        let request = request.clone();
+        let store_name = store_name.to_string();
+        move |_| {
+            // Note: This only gets triggered when the database is first
+            // created, so the object store will never already exist.
+            let db: IdbDatabase = request.result().unwrap().unchecked_into();
+            if let Err(e) = db.create_object_store(&store_name) {
+                tracing::warn!(
+                    error = &*crate::utils::js_error(e),
+                    store_name,
+                    "Unable to create the object store",
+                );
+            }
+        }
+    });
+    request.set_onupgradeneeded(Some(on_upgrade_needed.as_ref().unchecked_ref()));
+
+    let db = complete(&request).await?;
+    request.set_onupgradeneeded(None);
+
+    Ok(db.unchecked_into())
+}
+
+/// Get a handle to an object store as part of a new transaction.
+pub(crate) fn object_store(
+    db: &IdbDatabase,
+    store_name: &str,
+    mode: IdbTransactionMode,
+) -> Result<IdbObjectStore, Error> {
+    let transaction = db
+        .transaction_with_str_and_mode(store_name, mode)
+        .map_err(Error::js)?;
+    transaction.object_store(store_name).map_err(Error::js)
+}
+
+/// Start a read-write transaction which the browser only reports as complete
+/// once it has been flushed to disk.
+///
+/// IndexedDB transactions are atomic, so if the tab crashes before then,
+/// none of the transaction's changes are visible when the database is next
+/// opened. Browsers which don't understand `durability` ignore it.
+pub(crate) fn durable_transaction(
+    db: &IdbDatabase,
+    store_name: &str,
+) -> Result<IdbTransaction, Error> {
+    let options = js_sys::Object::new();
+    Reflect::set(&options, &"durability".into(), &"strict".into()).map_err(Error::js)?;
+
+    let transaction: js_sys::Function = Reflect::get(db, &"transaction".into())
+        .map_err(Error::js)?
+        .dyn_into()
+        .map_err(Error::js)?;
+    let transaction = transaction
+        .call3(db, &store_name.into(), &"readwrite".into(), &options)
+        .map_err(Error::js)?;
+
+    Ok(transaction.unchecked_into())
+}
+
+/// Wait for a transaction to be committed.
+pub(crate) async fn commit(transaction: &IdbTransaction) -> Result<(), Error> {
+    let done = Promise::new(&mut |resolve, reject| {
+        transaction.set_oncomplete(Some(&resolve));
+        transaction.set_onerror(Some(&reject));
+        transaction.set_onabort(Some(&reject));
+    });
+
+    let outcome = JsFuture::from(done).await;
+
+    transaction.set_oncomplete(None);
+    transaction.set_onerror(None);
+    transaction.set_onabort(None);
+
+    match outcome {
+        Ok(_) => Ok(()),
+        Err(_) => match transaction.error() {
+            Some(e) => Err(Error::js(e)),
+            None => Err(anyhow::anyhow!("The IndexedDB transaction was aborted").into()),
+        },
+    }
+}
+
+/// Wait for an [`IdbRequest`] to finish, returning its result.
+pub(crate) async fn complete(request: &IdbRequest) -> Result<JsValue, Error> {
+    let done = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+
+    let outcome = JsFuture::from(done).await;
+
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+
+    match outcome {
+        Ok(_) => request.result().map_err(Error::js),
+        Err(_) => match request.error() {
+            Ok(Some(e)) => Err(Error::js(e)),
+            _ => Err(anyhow::anyhow!("The IndexedDB request failed").into()),
+        },
+    }
+}
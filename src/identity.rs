@@ -0,0 +1,272 @@
+//! The user programs run as, exposed through `/etc/passwd`, `/etc/group`
+//! and the usual environment variables so tools that look up the current
+//! user (git, ssh, tar, etc.) see something consistent.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use virtual_fs::FileSystem;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Generator},
+    utils::Error,
+};
+
+const DEFAULT_NAME: &str = "user";
+const DEFAULT_ID: u32 = 1000;
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// A runtime's user configuration.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdentityConfig(Arc<Mutex<Option<Identity>>>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Identity {
+    name: String,
+    uid: u32,
+    gid: u32,
+    group: String,
+    home: String,
+    shell: String,
+}
+
+impl Identity {
+    fn from_init(init: UserInit) -> Result<Self, Error> {
+        let name = init.name.unwrap_or_else(|| DEFAULT_NAME.to_string());
+        if !is_valid_name(&name) {
+            let msg = format!("\"{name}\" isn't a valid user name");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        let uid = init.uid.unwrap_or(DEFAULT_ID);
+        let gid = init.gid.unwrap_or(uid);
+        let group = init.group.unwrap_or_else(|| name.clone());
+        if !is_valid_name(&group) {
+            let msg = format!("\"{group}\" isn't a valid group name");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        let home = init.home.unwrap_or_else(|| match uid {
+            0 => "/root".to_string(),
+            _ => format!("/home/{name}"),
+        });
+        if !home.starts_with('/') {
+            let msg = format!("The home directory must be an absolute path, not \"{home}\"");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        Ok(Identity {
+            name,
+            uid,
+            gid,
+            group,
+            home,
+            shell: init.shell.unwrap_or_else(|| DEFAULT_SHELL.to_string()),
+        })
+    }
+
+    fn render_passwd(&self) -> String {
+        let mut passwd = String::new();
+        if self.uid != 0 {
+            passwd.push_str("root:x:0:0:root:/root:/bin/sh\n");
+        }
+        passwd.push_str(&format!(
+            "{}:x:{}:{}:{}:{}:{}\n",
+            self.name, self.uid, self.gid, self.name, self.home, self.shell
+        ));
+        passwd
+    }
+
+    fn render_group(&self) -> String {
+        let mut group = String::new();
+        if self.gid != 0 {
+            group.push_str("root:x:0:\n");
+        }
+        group.push_str(&format!("{}:x:{}:{}\n", self.group, self.gid, self.name));
+        group
+    }
+}
+
+/// Names need to survive being put in a colon-separated file.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+impl IdentityConfig {
+    pub(crate) fn set(&self, init: UserInit) -> Result<(), Error> {
+        let identity = Identity::from_init(init)?;
+        tracing::debug!(?identity, "Updated the user programs run as");
+        *self.0.lock().unwrap() = Some(identity);
+        Ok(())
+    }
+
+    /// Has a user been configured?
+    ///
+    /// Nothing gets generated otherwise, so a package's own `/etc/passwd`
+    /// isn't hidden unnecessarily.
+    pub(crate) fn is_configured(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    fn current(&self) -> Option<Identity> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The `HOME`, `USER` and `LOGNAME` variables for the configured user.
+    pub(crate) fn env(&self) -> Vec<(String, String)> {
+        let Some(identity) = self.current() else {
+            return Vec::new();
+        };
+
+        vec![
+            ("HOME".to_string(), identity.home),
+            ("USER".to_string(), identity.name.clone()),
+            ("LOGNAME".to_string(), identity.name),
+        ]
+    }
+
+    /// Make sure the user's home directory exists.
+    pub(crate) fn create_home(&self, fs: &dyn FileSystem) {
+        let Some(identity) = self.current() else {
+            return;
+        };
+
+        let home = Path::new(&identity.home);
+        for dir in home.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            if fs.metadata(dir).is_err() {
+                if let Err(e) = fs.create_dir(dir) {
+                    tracing::debug!(
+                        error = %e,
+                        home = %identity.home,
+                        "Unable to create the home directory",
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Add `passwd` and `group` to a directory which will be mounted at
+    /// `/etc`.
+    ///
+    /// The files are regenerated whenever they are opened, so changes made
+    /// while a program is running are picked up.
+    pub(crate) fn insert_files(&self, fs: &DeviceFileSystem) {
+        let identity = self.clone();
+        let passwd = Generator::new(move || {
+            identity
+                .current()
+                .map(|i| i.render_passwd())
+                .unwrap_or_default()
+                .into_bytes()
+        });
+        fs.insert("passwd", Device::Generated(passwd));
+
+        let identity = self.clone();
+        let group = Generator::new(move || {
+            identity
+                .current()
+                .map(|i| i.render_group())
+                .unwrap_or_default()
+                .into_bytes()
+        });
+        fs.insert("group", Device::Generated(group));
+    }
+}
+
+/// The JavaScript description of a user, as passed to
+/// `RuntimeOptions.user` or {@link Runtime.setUser}.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub(crate) struct UserInit {
+    name: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    group: Option<String>,
+    home: Option<String>,
+    shell: Option<String>,
+}
+
+impl UserInit {
+    pub(crate) fn parse(value: JsUser) -> Result<Self, Error> {
+        serde_wasm_bindgen::from_value(value.into()).map_err(Error::js)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const USER_TYPE_DECLARATION: &str = r#"
+/**
+ * The user programs run as.
+ *
+ * Programs see the user in `/etc/passwd` and `/etc/group` (unless something
+ * else is mounted at `/etc`) and in the `HOME`, `USER` and `LOGNAME`
+ * environment variables (unless they are set explicitly). The home
+ * directory is created if it doesn't exist.
+ */
+export type User = {
+    /** Defaults to `"user"`. */
+    name?: string;
+    /** Defaults to `1000`. */
+    uid?: number;
+    /** Defaults to the `uid`. */
+    gid?: number;
+    /** The name of the user's primary group. Defaults to the user's name. */
+    group?: string;
+    /** Defaults to `/home/<name>`, or `/root` if `uid` is `0`. */
+    home?: string;
+    /** Defaults to `/bin/sh`. */
+    shell?: string;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "User")]
+    pub type JsUser;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn render_passwd_and_group() {
+        let config = IdentityConfig::default();
+        assert!(!config.is_configured());
+        assert!(config.env().is_empty());
+
+        config
+            .set(UserInit {
+                name: Some("alice".to_string()),
+                uid: Some(501),
+                group: Some("staff".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let identity = config.current().unwrap();
+        assert_eq!(
+            identity.render_passwd(),
+            "root:x:0:0:root:/root:/bin/sh\nalice:x:501:501:alice:/home/alice:/bin/sh\n"
+        );
+        assert_eq!(identity.render_group(), "root:x:0:\nstaff:x:501:alice\n");
+        assert_eq!(
+            config.env()[0],
+            ("HOME".to_string(), "/home/alice".to_string())
+        );
+
+        let bad_name = UserInit {
+            name: Some("a:b".to_string()),
+            ..Default::default()
+        };
+        assert!(config.set(bad_name).is_err());
+    }
+}
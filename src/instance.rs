@@ -1,9 +1,29 @@
-use futures::{channel::oneshot::Receiver, Stream, StreamExt, TryFutureExt};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::oneshot::{self, Receiver},
+    Stream, StreamExt, TryFutureExt,
+};
 use js_sys::Uint8Array;
 use wasm_bindgen::{closure::Closure, prelude::wasm_bindgen, JsCast, JsValue};
-use wasmer_wasix::WasiRuntimeError;
-
-use crate::utils::Error;
+use wasmer_wasix::{types::wasi::Signal, WasiRuntimeError};
+
+use crate::{
+    descriptors::{DescriptorTable, ListOfDescriptorInfo},
+    framing::{FramedStdio, Framing},
+    fs::InstanceFs,
+    memory::{DumpMemoryOptions, GuestMemory},
+    processes::{ProcessTable, SignalName},
+    recording::SessionRecording,
+    streams::StdinHandle,
+    tasks::{PanicGuard, TaskScope},
+    terminal::{EchoControl, EchoSource},
+    usage::{JsResourceUsage, ResourceUsage, UsageSnapshot},
+    utils::Error,
+};
 
 /// A handle connected to a running WASIX program.
 #[derive(Debug)]
@@ -13,33 +33,377 @@ pub struct Instance {
     /// instance.
     #[wasm_bindgen(getter_with_clone, readonly)]
     pub stdin: Option<web_sys::WritableStream>,
+    pub(crate) stdin_handle: Option<StdinHandle>,
     /// The WASI program's standard output.
     #[wasm_bindgen(getter_with_clone, readonly)]
     pub stdout: web_sys::ReadableStream,
     /// The WASI program's standard error.
     #[wasm_bindgen(getter_with_clone, readonly)]
     pub stderr: web_sys::ReadableStream,
+    /// Both stdout and stderr as a single stream of {@link OutputChunk}s, if
+    /// `sequencedOutput` was enabled when starting the program.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub output: Option<web_sys::ReadableStream>,
+    /// Controls the TTY's input echo, if the program has a TTY.
+    pub(crate) echo: Option<EchoControl>,
+    /// The terminal session, if `record` was enabled.
+    pub(crate) recording: Option<SessionRecording>,
     pub(crate) exit: Receiver<ExitCondition>,
+    /// The instance's file descriptors, if the way it was started gives us
+    /// access to them.
+    pub(crate) descriptors: Option<DescriptorTable>,
+    pub(crate) usage: Arc<ResourceUsage>,
+    /// Background tasks spawned by the program, if the way it was started
+    /// lets us track them.
+    pub(crate) tasks: Option<TaskScope>,
+    /// The instance's filesystem, if the way it was started gives us access
+    /// to it.
+    pub(crate) fs: Option<InstanceFs>,
+    /// The program's PID and the process table it was registered with, if
+    /// the way it was started lets us send it signals.
+    pub(crate) process: Option<(u32, ProcessTable)>,
+    /// The environment variables the program was started with.
+    pub(crate) env: BTreeMap<String, String>,
+    /// The program's linear memory, if the way it was started gives us
+    /// access to it.
+    pub(crate) memory: Option<GuestMemory>,
 }
 
 #[wasm_bindgen]
 impl Instance {
     /// Wait for the process to exit.
+    ///
+    /// Anything the program writes to stdout or stderr will be captured into
+    /// the {@link Output}, unless the stream was already consumed elsewhere.
     #[wasm_bindgen(js_name = "wait")]
-    pub async fn js_wait(self) -> Result<JsOutput, Error> {
-        let output = self.wait().await?;
+    pub async fn js_wait(self, options: Option<WaitOptions>) -> Result<JsOutput, Error> {
+        let limit = options
+            .and_then(|opts| opts.max_captured_bytes())
+            .map(|limit| limit as usize);
+        let output = self.wait(limit).await?;
         Ok(output.into())
     }
+
+    /// The instance's filesystem, which lets directories be mounted and
+    /// unmounted while the program is running.
+    ///
+    /// This is only available for programs started with {@link runWasix}.
+    #[wasm_bindgen(getter)]
+    pub fn fs(&self) -> Option<InstanceFs> {
+        self.fs.clone()
+    }
+
+    /// The program's PID in its runtime's process table.
+    ///
+    /// This is only available for programs started with {@link runWasix}.
+    #[wasm_bindgen(getter)]
+    pub fn pid(&self) -> Option<u32> {
+        self.process.as_ref().map(|(pid, _)| *pid)
+    }
+
+    /// Send the program a signal (e.g. `"SIGINT"` or `2`), defaulting to
+    /// `SIGTERM`.
+    ///
+    /// If the program installed a handler for the signal it gets invoked,
+    /// otherwise the signal's default action (typically exiting) is applied.
+    pub fn kill(&self, signal: Option<SignalName>) -> Result<(), Error> {
+        let (pid, processes) = self.process.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Signals can only be sent to programs started with runWasix()")
+        })?;
+        let signal = crate::processes::parse_signal(signal.as_deref())?;
+        processes.signal(*pid, signal)
+    }
+
+    /// Stop the program and everything it started, resolving once it has all
+    /// wound down.
+    ///
+    /// The program is killed (if it was started with {@link runWasix}) and
+    /// its {@link Instance.tasks} are cancelled, which wakes any sleeping
+    /// threads and aborts in-flight HTTP requests. Threads that still haven't
+    /// exited shortly afterwards (e.g. because they are blocked in
+    /// `Atomics.wait()`) have their workers terminated.
+    pub async fn abort(&self) {
+        if let Some((pid, processes)) = &self.process {
+            // Note: this fails if the program has already exited
+            let _ = processes.signal(*pid, Signal::Sigkill);
+        }
+        if let Some(tasks) = &self.tasks {
+            tasks.abort().await;
+            tracing::debug!("Aborted every task belonging to the instance");
+        }
+    }
+
+    /// Whether the program's TTY echoes input back to stdout, or `undefined`
+    /// if it doesn't have a TTY.
+    ///
+    /// The program can turn echo off itself (e.g. while prompting for a
+    /// password), which is reported with an `"echo-changed"` event.
+    #[wasm_bindgen(getter)]
+    pub fn echo(&self) -> Option<bool> {
+        self.echo.as_ref().map(EchoControl::is_enabled)
+    }
+
+    /// Turn the TTY's input echo on or off.
+    ///
+    /// With echo off, input is still passed to the program but isn't written
+    /// back to stdout, and `readline` neither edits it nor saves it to the
+    /// history. Use this to read a password on the program's behalf.
+    ///
+    /// This is only available for programs started with a TTY.
+    #[wasm_bindgen(js_name = "setEcho")]
+    pub fn set_echo(&self, echo: bool) -> Result<(), Error> {
+        let control = self.echo.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Echo can only be controlled for programs with a TTY")
+        })?;
+        control.set(echo, EchoSource::Host);
+        Ok(())
+    }
+
+    /// Export the terminal session as an [asciicast v2] file, which can be
+    /// replayed with `asciinema play` or embedded with asciinema-player.
+    ///
+    /// This is only available for programs started with `record: true` and
+    /// a TTY. The recording so far can be exported while the program is
+    /// still running.
+    ///
+    /// [asciicast v2]: https://docs.asciinema.org/manual/asciicast/v2/
+    #[wasm_bindgen(js_name = "exportRecording")]
+    pub fn export_recording(&self) -> Result<web_sys::Blob, Error> {
+        let recording = self.recording.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Only programs started with a TTY and \"record: true\" are recorded")
+        })?;
+        recording.to_blob()
+    }
+
+    /// Close stdin immediately, so the program sees EOF, even if it is
+    /// already blocked in `read()`.
+    ///
+    /// Unlike `instance.stdin.close()`, this works while {@link Instance.stdin}
+    /// is locked to a writer. Anything still queued on the stream is
+    /// discarded.
+    #[wasm_bindgen(js_name = "closeStdin")]
+    pub fn close_stdin(&self) {
+        if let Some(handle) = &self.stdin_handle {
+            handle.close();
+        }
+        if let Some(stdin) = self.stdin.as_ref().filter(|s| !s.locked()) {
+            let _ = stdin.abort();
+        }
+    }
+
+    /// Close stdin once everything already written to {@link Instance.stdin}
+    /// has been delivered to the program.
+    ///
+    /// This fails if the stream is locked to a writer, in which case the
+    /// writer should be closed instead.
+    #[wasm_bindgen(js_name = "endStdin")]
+    pub async fn end_stdin(&self) -> Result<(), Error> {
+        match &self.stdin {
+            Some(stdin) if stdin.locked() => Err(Error::js(js_sys::TypeError::new(
+                "stdin is locked to a writer, so the writer needs to be closed instead",
+            ))),
+            Some(stdin) => end_stream(stdin).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Stream a remote resource into the program's stdin, closing stdin once
+    /// the whole thing has been written.
+    ///
+    /// The download only goes as fast as the program reads, and if it fails
+    /// part way through it is resumed from where it left off using a `Range`
+    /// request.
+    ///
+    /// This locks {@link Instance.stdin} until the download completes.
+    #[wasm_bindgen(js_name = "stdinFromUrl")]
+    pub async fn stdin_from_url(
+        &self,
+        url: String,
+        options: Option<crate::remote_stdin::StdinFromUrlOptions>,
+    ) -> Result<(), Error> {
+        let remote = crate::remote_stdin::RemoteStdin::new(url, options)?;
+
+        let stdin = match &self.stdin {
+            Some(stdin) if stdin.locked() => {
+                return Err(Error::js(js_sys::TypeError::new(
+                    "stdin is already locked to a writer",
+                )))
+            }
+            Some(stdin) => stdin,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Unable to write to stdin because it was provided up front"
+                )
+                .into())
+            }
+        };
+        let writer = stdin.get_writer().map_err(Error::js)?;
+
+        match remote.copy_to(&writer).await {
+            Ok(bytes_written) => {
+                tracing::debug!(url = %remote.url, bytes_written, "Finished streaming stdin");
+                let result = wasm_bindgen_futures::JsFuture::from(writer.close()).await;
+                writer.release_lock();
+                result.map(|_| ()).map_err(Error::js)
+            }
+            Err(e) => {
+                writer.release_lock();
+                Err(e)
+            }
+        }
+    }
+
+    /// Exchange discrete messages with the program over stdin and stdout,
+    /// for programs that speak a framed protocol like LSP or JSON-RPC.
+    ///
+    /// `framing` is either `"content-length"` (the default), where each
+    /// message is preceded by LSP-style `Content-Length` headers, or
+    /// `"length-prefixed"`, where each message is preceded by its length as a
+    /// big-endian 32-bit integer.
+    ///
+    /// This locks {@link Instance.stdin} and {@link Instance.stdout}, so they
+    /// can't be used directly afterwards.
+    ///
+    /// @example
+    /// ```ts
+    /// const rpc = instance.framed();
+    /// await rpc.send(JSON.stringify({ jsonrpc: "2.0", id: 1, method: "initialize" }));
+    /// for await (const message of rpc.messages) {
+    ///     console.log(JSON.parse(new TextDecoder().decode(message)));
+    /// }
+    /// ```
+    pub fn framed(&self, framing: Option<String>) -> Result<FramedStdio, Error> {
+        let framing = match framing {
+            Some(framing) => Framing::parse(&framing)?,
+            None => Framing::ContentLength,
+        };
+        let stdin = self.stdin.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Messages can't be sent because stdin was provided up front")
+        })?;
+
+        FramedStdio::new(framing, stdin, self.stdout.clone())
+    }
+
+    /// The background tasks (e.g. threads) spawned by the program.
+    ///
+    /// Anything still running is cancelled when the program exits. Use
+    /// {@link TaskScope.join} to make sure it has all wound down.
+    #[wasm_bindgen(getter)]
+    pub fn tasks(&self) -> Option<TaskScope> {
+        self.tasks.clone()
+    }
+
+    /// The program's linear memory, for debugging.
+    ///
+    /// This is only available for programs started with {@link runWasix}.
+    #[wasm_bindgen(getter)]
+    pub fn memory(&self) -> Option<GuestMemory> {
+        self.memory.clone()
+    }
+
+    /// Copy the program's entire memory into a `Blob`, for post-mortem
+    /// analysis.
+    ///
+    /// This is a shorthand for {@link GuestMemory.dump}.
+    #[wasm_bindgen(js_name = "dumpMemory")]
+    pub fn dump_memory(&self, options: Option<DumpMemoryOptions>) -> Result<web_sys::Blob, Error> {
+        let memory = self.memory.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Memory can only be inspected for programs started with runWasix()")
+        })?;
+        memory.dump(options)
+    }
+
+    /// Get a live snapshot of the resources this program has used so far.
+    pub fn usage(&self) -> Result<JsResourceUsage, Error> {
+        let snapshot = self.usage.snapshot();
+        let value = serde_wasm_bindgen::to_value(&snapshot).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// List the file descriptors the program currently has open.
+    pub fn fds(&self) -> Result<ListOfDescriptorInfo, Error> {
+        let descriptors = self.descriptors()?.list()?;
+        let value = serde_wasm_bindgen::to_value(&descriptors).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// Close one of the program's file descriptors.
+    #[wasm_bindgen(js_name = "closeFd")]
+    pub fn close_fd(&self, fd: u32) -> Result<(), Error> {
+        self.descriptors()?.close(fd)
+    }
+
+    /// The environment variables the program was started with (including
+    /// any loaded from `envFile`), formatted as a `.env` file.
+    ///
+    /// Variables set by a package's manifest aren't included.
+    #[wasm_bindgen(js_name = "exportEnv")]
+    pub fn export_env(&self) -> Result<String, Error> {
+        crate::dotenv::stringify(self.env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+
+    /// Send everything the program writes to a file descriptor to `stream`
+    /// instead of wherever it was originally going.
+    ///
+    /// @example
+    /// ```ts
+    /// const { readable, writable } = new TransformStream();
+    /// instance.redirectFd(3, writable);
+    /// ```
+    #[wasm_bindgen(js_name = "redirectFd")]
+    pub fn redirect_fd(&self, fd: u32, stream: web_sys::WritableStream) -> Result<(), Error> {
+        let descriptors = self.descriptors()?;
+        let (pipe, readable) = crate::streams::output_pipe();
+        descriptors.redirect(fd, Box::new(pipe))?;
+
+        // Note: we don't care about the promise because any errors will be
+        // reported on the stream itself.
+        let _ = readable.pipe_to(&stream);
+
+        Ok(())
+    }
 }
 
 impl Instance {
+    fn descriptors(&self) -> Result<&DescriptorTable, Error> {
+        self.descriptors.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("File descriptors aren't available for this instance").into()
+        })
+    }
+
+    /// Wait for the process to exit without keeping any of its output,
+    /// returning its exit code.
+    pub(crate) async fn wait_for_exit(self) -> Result<i32, Error> {
+        let output = self.wait(Some(0)).await?;
+        Ok(output.code)
+    }
+
+    /// Wait for the process to exit, returning the same output as
+    /// `instance.wait()`.
+    pub(crate) async fn wait_for_output(self, limit: Option<usize>) -> Result<JsOutput, Error> {
+        Ok(self.wait(limit).await?.into())
+    }
+
+    /// Wait for the process to exit, capturing at most `limit` bytes from
+    /// each of stdout and stderr.
     #[tracing::instrument(skip_all)]
-    async fn wait(self) -> Result<Output, Error> {
+    async fn wait(self, limit: Option<usize>) -> Result<Output, Error> {
         let Instance {
             stdin,
+            stdin_handle: _,
             stdout,
             stderr,
+            output: _,
+            echo: _,
+            recording: _,
             exit,
+            descriptors: _,
+            usage,
+            tasks: _,
+            fs: _,
+            process: _,
+            env: _,
+            memory: _,
         } = self;
 
         if let Some(stdin) = stdin {
@@ -47,58 +411,104 @@ impl Instance {
                 // The caller has already acquired a writer so it's their
                 // responsibility to close the stream.
             } else {
-                match wasm_bindgen_futures::JsFuture::from(stdin.close()).await {
-                    Ok(_) => {
-                        tracing::debug!("Closed stdin");
-                    }
-
-                    Err(e) if e.has_type::<js_sys::TypeError>() => {
-                        tracing::debug!("Stdin was already closed by the user");
-                    }
-                    Err(e) => {
-                        return Err(Error::js(e));
-                    }
-                }
+                end_stream(&stdin).await?;
             }
         }
 
         let mut stdout_buffer = Vec::new();
-        let stdout_done = copy_to_buffer(crate::streams::read_to_end(stdout), &mut stdout_buffer);
+        let stdout_done = copy_to_buffer(
+            crate::streams::read_to_end(stdout),
+            &mut stdout_buffer,
+            limit,
+        );
         let mut stderr_buffer = Vec::new();
-        let stderr_done = copy_to_buffer(crate::streams::read_to_end(stderr), &mut stderr_buffer);
+        let stderr_done = copy_to_buffer(
+            crate::streams::read_to_end(stderr),
+            &mut stderr_buffer,
+            limit,
+        );
 
         // Note: this relies on the underlying instance closing stdout and
         // stderr when it exits. Failing to do this will block forever.
-        let (_, _, ExitCondition(code)) =
-            futures::try_join!(stdout_done, stderr_done, exit.map_err(Error::from))?;
+        let (stdout_truncated, stderr_truncated, ExitCondition(code)) =
+            futures::try_join!(stdout_done, stderr_done, exit.map_err(|_| never_started()))?;
 
         let output = Output {
             code,
             ok: code == 0,
             stdout: stdout_buffer,
+            stdout_truncated,
             stderr: stderr_buffer,
+            stderr_truncated,
+            usage: usage.snapshot(),
         };
 
         Ok(output)
     }
 }
 
+/// The error for a program whose task was dropped before it could run, which
+/// happens when no worker would accept it.
+fn never_started() -> Error {
+    let error = js_sys::Error::new(
+        "The program was dropped before it started, usually because it couldn't be sent to a worker",
+    );
+    error.set_name("TaskDeliveryError");
+    Error::js(error)
+}
+
+/// Gracefully close stdin, ignoring streams that were already closed.
+async fn end_stream(stdin: &web_sys::WritableStream) -> Result<(), Error> {
+    match wasm_bindgen_futures::JsFuture::from(stdin.close()).await {
+        Ok(_) => {
+            tracing::debug!("Closed stdin");
+            Ok(())
+        }
+        Err(e) if e.has_type::<js_sys::TypeError>() => {
+            tracing::debug!("Stdin was already closed by the user");
+            Ok(())
+        }
+        Err(e) => Err(Error::js(e)),
+    }
+}
+
+/// Copy everything from `stream` into `buffer`, keeping at most `limit`
+/// bytes and returning how many bytes were discarded.
+///
+/// The stream is always read to the end so the program never blocks on a
+/// full pipe.
 async fn copy_to_buffer(
     stream: impl Stream<Item = Result<Vec<u8>, Error>>,
     buffer: &mut Vec<u8>,
-) -> Result<(), Error> {
+    limit: Option<usize>,
+) -> Result<u64, Error> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut truncated = 0;
+
     futures::pin_mut!(stream);
     while let Some(chunk) = stream.next().await {
-        buffer.extend(chunk?);
+        let chunk = chunk?;
+        let room = limit.saturating_sub(buffer.len());
+        let keep = std::cmp::min(room, chunk.len());
+        buffer.extend_from_slice(&chunk[..keep]);
+        truncated += (chunk.len() - keep) as u64;
+    }
+
+    if truncated > 0 {
+        tracing::debug!(truncated, limit, "Discarded captured output");
     }
 
-    Ok(())
+    Ok(truncated)
 }
 
-#[derive(Debug)]
-pub(crate) struct ExitCondition(i32);
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct ExitCondition(pub(crate) i32);
 
 impl ExitCondition {
+    /// The exit code used when the thread running a program panics, matching
+    /// what a native Rust process would exit with.
+    pub(crate) const PANICKED: ExitCondition = ExitCondition(101);
+
     pub(crate) fn from_result(result: Result<(), anyhow::Error>) -> Self {
         let err = match result {
             Ok(_) => return ExitCondition(0),
@@ -121,12 +531,48 @@ impl ExitCondition {
     }
 }
 
+/// The sending half of [`Instance::exit`].
+///
+/// This should be created on the thread running the program. If that thread
+/// panics before [`ExitSender::send()`] is called, the instance exits with
+/// [`ExitCondition::PANICKED`] instead of waiting forever.
+#[derive(Debug)]
+pub(crate) struct ExitSender {
+    sender: Arc<Mutex<Option<oneshot::Sender<ExitCondition>>>>,
+    _panic: PanicGuard,
+}
+
+impl ExitSender {
+    pub(crate) fn new(sender: oneshot::Sender<ExitCondition>) -> Self {
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        let _panic = crate::tasks::on_panic({
+            let sender = Arc::clone(&sender);
+            move |_| {
+                if let Some(sender) = sender.try_lock().ok().and_then(|mut s| s.take()) {
+                    let _ = sender.send(ExitCondition::PANICKED);
+                }
+            }
+        });
+
+        ExitSender { sender, _panic }
+    }
+
+    pub(crate) fn send(self, exit: ExitCondition) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(exit);
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct Output {
     code: i32,
     ok: bool,
     stdout: Vec<u8>,
+    stdout_truncated: u64,
     stderr: Vec<u8>,
+    stderr_truncated: u64,
+    usage: UsageSnapshot,
 }
 
 #[wasm_bindgen]
@@ -141,7 +587,10 @@ impl From<Output> for JsOutput {
             code,
             ok,
             stdout,
+            stdout_truncated,
             stderr,
+            stderr_truncated,
+            usage,
         } = value;
 
         let output = js_sys::Object::new();
@@ -157,6 +606,11 @@ impl From<Output> for JsOutput {
             &JsValue::from_str("stdout"),
             &lazily_decoded_string_property(stdout),
         );
+        let _ = js_sys::Reflect::set(
+            &output,
+            &JsValue::from_str("stdoutTruncated"),
+            &JsValue::from(stdout_truncated as f64),
+        );
         let _ = js_sys::Reflect::set(
             &output,
             &JsValue::from_str("stderrBytes"),
@@ -167,6 +621,14 @@ impl From<Output> for JsOutput {
             &JsValue::from_str("stderr"),
             &lazily_decoded_string_property(stderr),
         );
+        let _ = js_sys::Reflect::set(
+            &output,
+            &JsValue::from_str("stderrTruncated"),
+            &JsValue::from(stderr_truncated as f64),
+        );
+        if let Ok(usage) = serde_wasm_bindgen::to_value(&usage) {
+            let _ = js_sys::Reflect::set(&output, &JsValue::from_str("usage"), &usage);
+        }
 
         output.unchecked_into()
     }
@@ -199,13 +661,45 @@ export type Output = {
     stdoutBytes: Uint8Array;
     /* The program's stdout stream, decoded as UTF-8. */
     readonly stdout: string;
+    /* How many bytes of stdout were discarded because of {@link WaitOptions.maxCapturedBytes}. */
+    stdoutTruncated: number;
     /* The contents of the program's stderr stream. */
     stderrBytes: Uint8Array;
     /* The program's stderr stream, decoded as UTF-8. */
     readonly stderr: string;
+    /* How many bytes of stderr were discarded because of {@link WaitOptions.maxCapturedBytes}. */
+    stderrTruncated: number;
+    /* The resources used by the program. */
+    usage: ResourceUsage;
 }
 "#;
 
+#[wasm_bindgen(typescript_custom_section)]
+const WAIT_OPTIONS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Options for {@link Instance.wait}.
+ */
+export type WaitOptions = {
+    /**
+     * The maximum number of bytes to capture from each of stdout and stderr.
+     *
+     * Anything past this limit is discarded, and the number of discarded bytes
+     * is reported in the {@link Output}. This only affects the captured copy;
+     * streams consumed directly are never limited.
+     */
+    maxCapturedBytes?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "WaitOptions")]
+    pub type WaitOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "maxCapturedBytes")]
+    fn max_captured_bytes(this: &WaitOptions) -> Option<f64>;
+}
+
 #[cfg(test)]
 mod tests {
     use futures::channel::oneshot;
@@ -223,9 +717,20 @@ mod tests {
         let (sender, exit) = oneshot::channel();
         let instance = Instance {
             stdin: Some(stdin_stream),
+            stdin_handle: None,
             stdout: stdout_stream,
             stderr: stderr_stream,
+            output: None,
+            echo: None,
+            recording: None,
             exit,
+            descriptors: None,
+            usage: Arc::new(ResourceUsage::default()),
+            tasks: None,
+            fs: None,
+            process: None,
+            env: BTreeMap::new(),
+            memory: None,
         };
         dbg!(&instance);
 
@@ -241,7 +746,7 @@ mod tests {
         sender.send(ExitCondition(42)).unwrap();
 
         // and wait for the result
-        let output = instance.wait().await.unwrap();
+        let output = instance.wait(None).await.unwrap();
 
         assert_eq!(
             output,
@@ -249,7 +754,19 @@ mod tests {
                 code: 42,
                 ok: false,
                 stdout: b"stdout".to_vec(),
-                stderr: b"stderr".to_vec()
+                stdout_truncated: 0,
+                stderr: b"stderr".to_vec(),
+                stderr_truncated: 0,
+                usage: UsageSnapshot {
+                    busy_time_ms: 0.0,
+                    stdin_bytes: 0,
+                    stdout_bytes: 0,
+                    stderr_bytes: 0,
+                    network_sent_bytes: 0,
+                    network_received_bytes: 0,
+                    chunk_sizes: ResourceUsage::default().snapshot().chunk_sizes,
+                    gas: None,
+                },
             }
         );
         // Reading from stdin should now result in an EOF because it's closed
@@ -257,4 +774,19 @@ mod tests {
         let bytes_read = stdin.read_to_end(&mut buffer).await.unwrap();
         assert_eq!(bytes_read, 0);
     }
+
+    #[wasm_bindgen_test]
+    async fn captured_output_is_truncated_at_the_limit() {
+        let chunks = futures::stream::iter(vec![
+            Ok(b"Hello".to_vec()),
+            Ok(b", ".to_vec()),
+            Ok(b"World!".to_vec()),
+        ]);
+        let mut buffer = Vec::new();
+
+        let truncated = copy_to_buffer(chunks, &mut buffer, Some(6)).await.unwrap();
+
+        assert_eq!(buffer, b"Hello,");
+        assert_eq!(truncated, 7);
+    }
 }
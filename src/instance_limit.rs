@@ -0,0 +1,208 @@
+//! Capping how many programs a runtime runs at the same time.
+//!
+//! Every instance needs its own worker, linear memory, and filesystem, so a
+//! page which starts a new program each time the user clicks "Run" can
+//! easily run out of memory. With `maxConcurrentInstances` set, programs
+//! started while the runtime is at capacity either wait their turn or fail
+//! straight away.
+
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use futures::channel::oneshot;
+
+use crate::{events::RuntimeEvent, tasks::ThreadPool, utils::Error};
+
+/// What to do with a program started while the runtime is at capacity.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum AtCapacity {
+    /// Wait until another program exits.
+    #[default]
+    Queue,
+    /// Fail with an `InstanceLimitError`.
+    Reject,
+}
+
+impl AtCapacity {
+    pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "queue" => Ok(AtCapacity::Queue),
+            "reject" => Ok(AtCapacity::Reject),
+            other => {
+                let msg = format!("\"{other}\" isn't a valid value for \"whenAtCapacity\"");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+/// Keeps track of a runtime's running instances and everyone waiting to
+/// start one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InstanceLimit(Arc<Mutex<LimitState>>);
+
+#[derive(Debug, Default)]
+struct LimitState {
+    max: Option<NonZeroUsize>,
+    policy: AtCapacity,
+    running: usize,
+    /// Programs waiting for a slot, in the order they were started.
+    queue: VecDeque<Waiter>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    ticket: u64,
+    /// Hands the slot of an instance that exited to this waiter.
+    wake: oneshot::Sender<()>,
+}
+
+impl InstanceLimit {
+    pub(crate) fn configure(&self, max: Option<NonZeroUsize>, policy: AtCapacity) {
+        let mut state = self.0.lock().unwrap();
+        state.max = max;
+        state.policy = policy;
+    }
+
+    /// Wait for a free slot, reporting the program's position in the queue
+    /// to `pool` while it waits.
+    ///
+    /// The slot is given back when the returned [`InstanceSlot`] is dropped.
+    pub(crate) async fn acquire(&self, pool: &ThreadPool) -> Result<InstanceSlot, Error> {
+        let slot = || InstanceSlot {
+            limit: self.clone(),
+            pool: pool.clone(),
+        };
+
+        let (ticket, receiver) = {
+            let mut state = self.0.lock().unwrap();
+
+            match state.max {
+                Some(max) if state.running >= max.get() => {}
+                _ => {
+                    state.running += 1;
+                    return Ok(slot());
+                }
+            }
+
+            if state.policy == AtCapacity::Reject {
+                let msg = format!(
+                    "Unable to start the program because {} instances are already running",
+                    state.running
+                );
+                let error = js_sys::Error::new(&msg);
+                error.set_name("InstanceLimitError");
+                return Err(Error::js(error));
+            }
+
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            let (wake, receiver) = oneshot::channel();
+            state.queue.push_back(Waiter { ticket, wake });
+            let position = state.queue.len();
+            tracing::debug!(ticket, position, "Waiting for an instance slot");
+            pool.emit(RuntimeEvent::InstanceQueued { ticket, position });
+
+            (ticket, receiver)
+        };
+
+        match receiver.await {
+            Ok(()) => {
+                pool.emit(RuntimeEvent::InstanceQueued {
+                    ticket,
+                    position: 0,
+                });
+                Ok(slot())
+            }
+            Err(_) => Err(anyhow::anyhow!("The runtime stopped accepting new instances").into()),
+        }
+    }
+
+    /// Fail every program that is still waiting for a slot.
+    pub(crate) fn reject_waiting(&self) {
+        // Note: dropping the senders wakes the waiters with an error
+        self.0.lock().unwrap().queue.clear();
+    }
+
+    fn release(&self, pool: &ThreadPool) {
+        let mut events = Vec::new();
+
+        {
+            let mut state = self.0.lock().unwrap();
+
+            // Note: the slot goes straight to the next waiter that is still
+            // around, so `running` only drops when nobody is waiting
+            loop {
+                match state.queue.pop_front() {
+                    Some(waiter) if waiter.wake.send(()).is_ok() => break,
+                    Some(_) => continue,
+                    None => {
+                        state.running -= 1;
+                        break;
+                    }
+                }
+            }
+
+            for (i, waiter) in state.queue.iter().enumerate() {
+                events.push(RuntimeEvent::InstanceQueued {
+                    ticket: waiter.ticket,
+                    position: i + 1,
+                });
+            }
+        }
+
+        for event in events {
+            pool.emit(event);
+        }
+    }
+}
+
+/// A running instance's claim on one of the runtime's slots.
+#[derive(Debug)]
+pub(crate) struct InstanceSlot {
+    limit: InstanceLimit,
+    pool: ThreadPool,
+}
+
+impl Drop for InstanceSlot {
+    fn drop(&mut self) {
+        self.limit.release(&self.pool);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn programs_wait_for_a_free_slot() {
+        let pool = ThreadPool::new();
+        let limit = InstanceLimit::default();
+        limit.configure(NonZeroUsize::new(1), AtCapacity::Queue);
+
+        let first = limit.acquire(&pool).await.unwrap();
+        let mut second = Box::pin(limit.acquire(&pool));
+        let mut third = Box::pin(limit.acquire(&pool));
+        assert!((&mut second).now_or_never().is_none());
+        assert!((&mut third).now_or_never().is_none());
+
+        drop(first);
+        let second = second.await.unwrap();
+        assert!((&mut third).now_or_never().is_none());
+        drop(second);
+        drop(third.await.unwrap());
+        assert_eq!(limit.0.lock().unwrap().running, 0);
+
+        limit.configure(NonZeroUsize::new(1), AtCapacity::Reject);
+        let _running = limit.acquire(&pool).await.unwrap();
+        assert!(limit.acquire(&pool).await.is_err());
+        assert_eq!(limit.0.lock().unwrap().running, 1);
+    }
+}
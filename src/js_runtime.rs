@@ -1,11 +1,37 @@
 use std::{
+    num::NonZeroUsize,
     ops::{Deref, DerefMut},
     sync::Arc,
 };
 
-use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
 
-use crate::{runtime::Runtime, tasks::ThreadPool, utils::Error};
+use crate::{
+    arbiter::ArbiterOptions,
+    batch::{BatchOptions, ListOfBatchJob, ListOfBatchResult},
+    capabilities::{Capabilities, Capability},
+    event_log::{EventLogOptions, JsLoggedEvents},
+    events::RuntimeEventListener,
+    identity::{JsUser, UserInit},
+    instance_limit::AtCapacity,
+    memory_pressure::PressureOptions,
+    module_cache::JsModuleCache,
+    net::Transport,
+    pipes::HostPipe,
+    processes::SignalName,
+    proxy::ProxyConfig,
+    registry_auth::TokenSource,
+    runtime::Runtime,
+    shared_memory::SharedSegment,
+    shutdown::ShutdownOptions,
+    storage::JsStorageStatus,
+    supervisor::{SuperviseSpec, Supervisor},
+    tasks::{
+        BackgroundPolicy, BlockedWorkerPolicy, PanicPolicy, PoolOptions, ThreadPool,
+        WatchdogOptions,
+    },
+    utils::Error,
+};
 
 #[derive(Clone, Debug, wasm_bindgen_derive::TryFromJsValue)]
 #[repr(transparent)]
@@ -32,7 +58,33 @@ impl JsRuntime {
 impl JsRuntime {
     #[wasm_bindgen(constructor)]
     pub fn js_new(options: Option<RuntimeOptions>) -> Result<JsRuntime, Error> {
-        let pool = ThreadPool::new();
+        let panic_policy = match options.as_ref().and_then(|opts| opts.on_panic()) {
+            Some(policy) => PanicPolicy::parse(&policy)?,
+            None => PanicPolicy::default(),
+        };
+        let threads = match options.as_ref().and_then(|opts| opts.threads()) {
+            Some(threads) => Some(parse_count("threads", threads)?),
+            None => None,
+        };
+        let background_policy = match options.as_ref().and_then(|opts| opts.when_hidden()) {
+            Some(policy) => BackgroundPolicy::parse(&policy)?,
+            None => BackgroundPolicy::default(),
+        };
+        let watchdog = match options.as_ref().and_then(|opts| opts.deadlock_detection()) {
+            Some(value) => WatchdogOptions::parse(value)?,
+            None => WatchdogOptions::default(),
+        };
+        let on_worker_blocked = match options.as_ref().and_then(|opts| opts.on_worker_blocked()) {
+            Some(policy) => BlockedWorkerPolicy::parse(&policy)?,
+            None => BlockedWorkerPolicy::default(),
+        };
+        let pool = ThreadPool::with_options(PoolOptions {
+            panic_policy,
+            threads,
+            background_policy,
+            watchdog,
+            on_worker_blocked,
+        });
 
         let registry = match options.as_ref().and_then(|opts| opts.registry()) {
             Some(registry_url) => registry_url.resolve(),
@@ -41,16 +93,146 @@ impl JsRuntime {
 
         let mut rt = Runtime::new(pool);
 
+        let capabilities = match options.as_ref().and_then(|opts| opts.capabilities()) {
+            Some(value) => Capabilities::parse(value)?,
+            None => Capabilities::none(),
+        };
+        rt.set_capabilities(capabilities);
+
+        let get_api_key = options.as_ref().and_then(|opts| opts.get_api_key());
         if let Some(registry) = registry.as_deref() {
             let api_key = options.as_ref().and_then(|opts| opts.api_key());
-            rt.set_registry(registry, api_key.as_deref())?;
+            match get_api_key {
+                Some(callback) => {
+                    let tokens =
+                        TokenSource::from_callback(rt.thread_pool().clone(), callback, api_key);
+                    rt.set_registry_with_tokens(registry, tokens)?;
+                }
+                None => rt.set_registry(registry, api_key.as_deref())?,
+            }
+        } else if get_api_key.is_some() {
+            let msg = "\"getApiKey\" can't be used without a registry";
+            return Err(Error::js(js_sys::TypeError::new(msg)));
+        }
+        if let Some(publishers) = options.as_ref().and_then(|opts| opts.trusted_publishers()) {
+            rt.set_trusted_publishers(crate::utils::js_string_array(publishers)?)?;
         }
 
         if let Some(gateway) = options.as_ref().and_then(|opts| opts.network_gateway()) {
-            rt.set_network_gateway(gateway);
+            capabilities.require(Capability::Network, "networkGateway")?;
+            let transport = match options.as_ref().and_then(|opts| opts.network_transport()) {
+                Some(transport) => Transport::parse(&transport)?,
+                None => Transport::default(),
+            };
+            transport.check(&gateway)?;
+            rt.set_network_gateway_with(gateway, transport);
+        }
+
+        if let Some(proxy) = options.as_ref().and_then(|opts| opts.network_proxy()) {
+            if options
+                .as_ref()
+                .and_then(|opts| opts.network_gateway())
+                .is_none()
+            {
+                let msg = "\"networkProxy\" can only be used with \"networkGateway\"";
+                return Err(Error::js(js_sys::TypeError::new(msg)));
+            }
+            let bypass = match proxy.bypass() {
+                Some(bypass) => crate::utils::js_string_array(bypass)?,
+                None => Vec::new(),
+            };
+            let proxy = ProxyConfig::parse(
+                &proxy.url(),
+                proxy.username().as_deref(),
+                proxy.password().as_deref(),
+                &bypass,
+            )?;
+            rt.set_network_proxy(proxy);
+        }
+
+        if let Some(hosts) = options.as_ref().and_then(|opts| opts.hosts()) {
+            for (hostname, address) in crate::utils::js_record_of_strings(&hosts)? {
+                rt.dns().set_host(&hostname, Some(&address))?;
+            }
         }
 
-        Ok(JsRuntime::new(Arc::new(rt)))
+        if let Some(nameservers) = options.as_ref().and_then(|opts| opts.nameservers()) {
+            let nameservers = crate::utils::js_string_array(nameservers)?;
+            rt.dns().set_nameservers(&nameservers)?;
+        }
+
+        if let Some(user) = options.as_ref().and_then(|opts| opts.user()) {
+            rt.identity().set(UserInit::parse(user)?)?;
+        }
+
+        if let Some(host_fetch) = options.as_ref().and_then(|opts| opts.host_fetch()) {
+            capabilities.require(Capability::Network, "hostFetch")?;
+            if let Some(origins) = host_fetch.allowed_origins() {
+                for origin in crate::utils::js_string_array(origins)? {
+                    rt.fetch_policy().allow(&origin)?;
+                }
+            }
+            if let Some(approve) = host_fetch.approve() {
+                rt.fetch_policy().set_approver(approve);
+            }
+            if let Some(max) = host_fetch.max_response_bytes() {
+                let max = parse_count("hostFetch.maxResponseBytes", max)?;
+                rt.fetch_policy().set_max_response_bytes(max.get());
+            }
+        }
+
+        if let Some(callback) = options.as_ref().and_then(|o| o.on_permission_request()) {
+            rt.set_permission_prompter(callback);
+        }
+        if let Some(callback) = options.as_ref().and_then(|o| o.on_credential_request()) {
+            rt.set_credential_helper(callback);
+        }
+
+        if let Some(enabled) = options.as_ref().and_then(|opts| opts.web_crypto()) {
+            rt.set_web_crypto_enabled(enabled);
+        }
+        if let Some(canvas) = options.as_ref().and_then(|opts| opts.gpu_canvas()) {
+            capabilities.require(Capability::Gpu, "gpuCanvas")?;
+            rt.set_gpu_canvas(canvas);
+        }
+        if let Some(shared) = options.as_ref().and_then(|opts| opts.share_downloads()) {
+            rt.set_share_downloads(shared);
+        }
+        let max_instances = match options.as_ref().and_then(|o| o.max_concurrent_instances()) {
+            Some(max) => Some(parse_count("maxConcurrentInstances", max)?),
+            None => None,
+        };
+        let at_capacity = match options.as_ref().and_then(|opts| opts.when_at_capacity()) {
+            Some(policy) => AtCapacity::parse(&policy)?,
+            None => AtCapacity::default(),
+        };
+        rt.set_instance_limit(max_instances, at_capacity);
+
+        if let Some(value) = options.as_ref().and_then(|opts| opts.arbiter()) {
+            if let Some(arbiter) = ArbiterOptions::parse(value)? {
+                rt.join_arbiter(arbiter)?;
+            }
+        }
+
+        let memory_pressure = match options.as_ref().and_then(|opts| opts.memory_pressure()) {
+            Some(value) => PressureOptions::parse(value)?,
+            None => None,
+        };
+
+        let shutdown = match options.as_ref().and_then(|opts| opts.shutdown()) {
+            Some(value) => ShutdownOptions::parse(value)?,
+            None => Some(ShutdownOptions::default()),
+        };
+
+        let rt = Arc::new(rt);
+        if let Some(memory_pressure) = memory_pressure {
+            crate::memory_pressure::watch(Arc::downgrade(&rt), memory_pressure);
+        }
+        if let Some(shutdown) = shutdown {
+            crate::shutdown::watch(Arc::downgrade(&rt), shutdown);
+        }
+
+        Ok(JsRuntime::new(rt))
     }
 
     /// Get a reference to the global runtime, optionally initializing it if
@@ -65,6 +247,303 @@ impl JsRuntime {
             None => Ok(None),
         }
     }
+
+    /// Ask the browser to keep this origin's storage from being evicted and
+    /// find out how much space is available.
+    ///
+    /// Compiled modules saved to IndexedDB, which outlive the page, are
+    /// limited to a fraction of the remaining quota afterwards. If `persisted` is `false`, anything
+    /// stored in the browser may be evicted under storage pressure.
+    ///
+    /// Requires the `storage` capability.
+    #[wasm_bindgen(js_name = "requestPersistentStorage")]
+    pub async fn request_persistent_storage(&self) -> Result<JsStorageStatus, Error> {
+        let status = self.rt.negotiate_storage().await?;
+        let value = serde_wasm_bindgen::to_value(&status).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// The result of the last {@link Runtime.requestPersistentStorage} call,
+    /// if there was one.
+    #[wasm_bindgen(getter, js_name = "storageStatus")]
+    pub fn storage_status(&self) -> Result<Option<JsStorageStatus>, Error> {
+        match self.rt.storage_status() {
+            Some(status) => {
+                let value = serde_wasm_bindgen::to_value(&status).map_err(Error::js)?;
+                Ok(Some(value.unchecked_into()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The number of threads this runtime aims to run in parallel.
+    ///
+    /// This is also what programs see when they ask how many CPUs are
+    /// available.
+    #[wasm_bindgen(getter)]
+    pub fn threads(&self) -> usize {
+        self.rt.thread_pool().parallelism().get()
+    }
+
+    /// Run many short commands, returning one result per job in the same
+    /// order.
+    ///
+    /// Each distinct package is only loaded once and jobs share the
+    /// runtime's workers and compiled modules, which makes this much cheaper
+    /// than calling {@link Wasmer.fromRegistry} and running each command
+    /// separately. A job that fails gets an `error` instead of an `output`
+    /// rather than failing the whole batch.
+    ///
+    /// @example
+    /// ```ts
+    /// const results = await runtime.batch(
+    ///     submissions.map(code => ({ package: "python/python", args: ["-c", code] })),
+    ///     { concurrency: 4 },
+    /// );
+    /// ```
+    pub async fn batch(
+        &self,
+        jobs: ListOfBatchJob,
+        options: Option<BatchOptions>,
+    ) -> Result<ListOfBatchResult, Error> {
+        crate::batch::batch(Arc::clone(&self.rt), jobs, options).await
+    }
+
+    /// The PIDs of every program started with {@link runWasix} on this
+    /// runtime that is still running.
+    pub fn pids(&self) -> Vec<u32> {
+        self.rt.processes().pids()
+    }
+
+    /// Send a signal (e.g. `"SIGTERM"` or `15`) to one of the programs
+    /// running on this runtime, like `kill(1)`.
+    ///
+    /// The signal defaults to `SIGTERM`.
+    pub fn kill(&self, pid: u32, signal: Option<SignalName>) -> Result<(), Error> {
+        let signal = crate::processes::parse_signal(signal.as_deref())?;
+        self.rt.processes().signal(pid, signal)
+    }
+
+    /// Tear down the runtime, killing its programs, aborting in-flight
+    /// network requests, emptying its caches, and terminating its workers.
+    ///
+    /// This is meant for applications which create and discard runtimes
+    /// (e.g. when a component is unmounted). The runtime, and anything
+    /// using it, will stop working afterwards.
+    pub fn dispose(&self) {
+        self.rt.dispose();
+    }
+
+    /// Map a hostname to an IP address in `/etc/hosts`, or remove the
+    /// mapping if `address` is `null`.
+    ///
+    /// Programs that are already running see the change the next time they
+    /// open the file.
+    #[wasm_bindgen(js_name = "setHost")]
+    pub fn set_host(&self, hostname: &str, address: Option<String>) -> Result<(), Error> {
+        self.rt.dns().set_host(hostname, address.as_deref())
+    }
+
+    /// Replace the nameservers listed in `/etc/resolv.conf`.
+    #[wasm_bindgen(js_name = "setNameservers")]
+    pub fn set_nameservers(&self, nameservers: Vec<String>) -> Result<(), Error> {
+        self.rt.dns().set_nameservers(&nameservers)
+    }
+
+    /// Change the user programs run as.
+    ///
+    /// Programs that are already running see the new `/etc/passwd` and
+    /// `/etc/group` the next time they open them, but keep their original
+    /// environment variables.
+    #[wasm_bindgen(js_name = "setUser")]
+    pub fn set_user(&self, user: JsUser) -> Result<(), Error> {
+        self.rt.identity().set(UserInit::parse(user)?)
+    }
+
+    /// Replace a package (e.g. `"sharrattj/bash"`) whenever it gets resolved,
+    /// including when it is pulled in as a dependency.
+    ///
+    /// The replacement is either the bytes of a local `*.webc` file or a
+    /// version requirement (e.g. `"=1.0.12"`) to look up in the registry
+    /// instead.
+    ///
+    /// @example
+    /// ```ts
+    /// const webc = new Uint8Array(await (await fetch("./bash.webc")).arrayBuffer());
+    /// runtime.overridePackage("sharrattj/bash", webc);
+    /// ```
+    #[wasm_bindgen(js_name = "overridePackage")]
+    pub fn override_package(
+        &self,
+        name: &str,
+        replacement: &PackageReplacement,
+    ) -> Result<(), Error> {
+        if let Some(version) = replacement.as_string() {
+            self.rt.override_package_version(name, &version)
+        } else if let Some(webc) = replacement.dyn_ref::<js_sys::Uint8Array>() {
+            self.rt
+                .override_package_with_webc(name, webc.to_vec().into())
+        } else {
+            Err(Error::js(js_sys::TypeError::new(
+                "Expected a Uint8Array or version string",
+            )))
+        }
+    }
+
+    /// Stop overriding a package, returning `false` if it wasn't overridden.
+    #[wasm_bindgen(js_name = "removePackageOverride")]
+    pub fn remove_package_override(&self, name: &str) -> bool {
+        self.rt.remove_package_override(name)
+    }
+
+    /// The modules this runtime has compiled and cached.
+    #[wasm_bindgen(getter, js_name = "moduleCache")]
+    pub fn module_cache(&self) -> JsModuleCache {
+        JsModuleCache(self.rt.modules().clone())
+    }
+
+    /// The PID of the foreground job, if there is one.
+    ///
+    /// Programs started with {@link runWasix} become the foreground job
+    /// unless {@link RunOptions.background} is set.
+    #[wasm_bindgen(getter)]
+    pub fn foreground(&self) -> Option<u32> {
+        self.rt.processes().foreground()
+    }
+
+    /// Suspend the foreground job by sending it `SIGTSTP`, like a terminal
+    /// does when the user presses Ctrl-Z. Returns the job's PID.
+    pub fn suspend(&self) -> Option<u32> {
+        self.rt.processes().suspend_foreground()
+    }
+
+    /// Resume a job with `SIGCONT` and make it the foreground job, like the
+    /// `fg` shell builtin.
+    pub fn fg(&self, pid: u32) -> Result<(), Error> {
+        self.rt.processes().resume(pid, true)
+    }
+
+    /// Resume a job with `SIGCONT`, leaving it in the background, like the
+    /// `bg` shell builtin.
+    pub fn bg(&self, pid: u32) -> Result<(), Error> {
+        self.rt.processes().resume(pid, false)
+    }
+
+    /// Create a pipe for exchanging data with programs started by this
+    /// runtime, in addition to their standard streams.
+    ///
+    /// Guests open the pipe's {@link HostPipe.path} to use it, so pass the
+    /// path along in `args` or `env`.
+    #[wasm_bindgen(js_name = "createPipe")]
+    pub fn create_pipe(&self) -> HostPipe {
+        self.rt.pipes().create()
+    }
+
+    /// Create a named block of memory which programs started by this runtime
+    /// can share with each other and with JavaScript, or open the existing
+    /// segment with that name.
+    ///
+    /// Guests open the segment's {@link SharedSegment.path} under `/dev/shm`.
+    /// Opening an existing segment with a different `size` is an error.
+    #[wasm_bindgen(js_name = "createSharedMemory")]
+    pub fn create_shared_memory(&self, name: String, size: usize) -> Result<SharedSegment, Error> {
+        self.rt.shared_memory().open(&name, size)
+    }
+
+    /// Keep a command running, restarting it according to its restart
+    /// policy whenever it exits.
+    ///
+    /// Lifecycle events (`service-started`, `service-exited` and
+    /// `service-stopped`) are emitted on this runtime.
+    ///
+    /// @example
+    /// ```ts
+    /// const server = runtime.supervise({
+    ///     package: pkg,
+    ///     command: "serve",
+    ///     restart: "on-failure",
+    ///     maxRestarts: 5,
+    ///     onStart: instance => instance.stdout.pipeTo(logs),
+    /// });
+    /// // later...
+    /// await server.stop();
+    /// ```
+    pub fn supervise(&self, spec: SuperviseSpec) -> Result<Supervisor, Error> {
+        Supervisor::start(self.rt.thread_pool().clone(), spec)
+    }
+
+    /// The capabilities this runtime's programs may use, after removing
+    /// anything the current page or worker can't provide.
+    #[wasm_bindgen(getter)]
+    pub fn capabilities(&self) -> JsCapabilities {
+        self.rt.capabilities().to_js().unchecked_into()
+    }
+
+    /// Check whether a capability (e.g. `"clipboard"`) is available, emitting
+    /// a `"capability-denied"` event if it isn't.
+    ///
+    /// This is meant for host extensions that give programs access to
+    /// something outside the runtime, so every feature is governed by
+    /// `RuntimeOptions.capabilities`.
+    #[wasm_bindgen(js_name = "checkCapability")]
+    pub fn check_capability(
+        &self,
+        name: JsCapabilityName,
+        detail: Option<String>,
+    ) -> Result<bool, Error> {
+        let name = name
+            .as_string()
+            .ok_or_else(|| Error::js(js_sys::TypeError::new("Expected a capability name")))?;
+        let capability = Capability::parse(&name)?;
+        Ok(self
+            .rt
+            .capabilities()
+            .check(capability, self.rt.thread_pool(), None, detail))
+    }
+
+    /// Subscribe to events emitted by this runtime.
+    ///
+    /// The listener receives a `CustomEvent` whose `detail` is described by
+    /// {@link RuntimeEventMap}.
+    #[wasm_bindgen(js_name = "addEventListener")]
+    pub fn add_event_listener(
+        &self,
+        ty: &str,
+        listener: &RuntimeEventListener,
+    ) -> Result<(), Error> {
+        self.rt
+            .events()
+            .target()
+            .add_event_listener_with_callback(ty, listener)
+            .map_err(Error::js)
+    }
+
+    /// Remove a listener added with {@link Runtime.addEventListener}.
+    #[wasm_bindgen(js_name = "removeEventListener")]
+    pub fn remove_event_listener(
+        &self,
+        ty: &str,
+        listener: &RuntimeEventListener,
+    ) -> Result<(), Error> {
+        self.rt
+            .events()
+            .target()
+            .remove_event_listener_with_callback(ty, listener)
+            .map_err(Error::js)
+    }
+
+    /// Get the most recent events this runtime emitted, oldest first.
+    ///
+    /// Only the last few hundred events are kept. The result is plain JSON,
+    /// so it can be attached to a bug report with `JSON.stringify()`.
+    #[wasm_bindgen(js_name = "events")]
+    pub fn event_history(&self, options: Option<EventLogOptions>) -> Result<JsLoggedEvents, Error> {
+        let since = match &options {
+            Some(options) => options.since_id()?,
+            None => None,
+        };
+        self.rt.events().with_log(|log| log.to_js(since))
+    }
 }
 
 impl Deref for JsRuntime {
@@ -112,10 +591,337 @@ export type RuntimeOptions = {
      * An optional API key to use when sending requests to the Wasmer registry.
      */
     apiKey?: string;
+    /**
+     * Get an API key for the registry, for registries which only accept
+     * short-lived tokens.
+     *
+     * This is called before the first registry request (unless `apiKey` was
+     * given) and again whenever the registry rejects the current key with a
+     * `401 Unauthorized`, in which case `expired` is the rejected key and the
+     * request is retried with the new one. If several requests are rejected
+     * at once, they share a single call.
+     */
+    getApiKey?: (expired?: string) => string | Promise<string>;
+    /**
+     * Only run packages published under these namespaces (e.g.
+     * `["wasmer", "my-org"]`).
+     *
+     * Packages from any other publisher fail to load, along with anything
+     * that depends on them, and so do packages without a publisher (e.g.
+     * ones loaded from a URL). Packages given to {@link Wasmer.fromFile} or
+     * {@link Runtime.overridePackage} come from the host, so they are always
+     * allowed, but their dependencies are still checked.
+     *
+     * Use {@link Wasmer.provenance} to see where a package came from.
+     */
+    trustedPublishers?: string[];
     /**
      * Enable networking (i.e. TCP and UDP) via a gateway server.
+     *
+     * Requires the `network` capability.
      */
     networkGateway?: string;
+    /**
+     * How to connect to `networkGateway`.
+     *
+     * - `"websocket"` (the default) uses a WebSocket, sending each
+     *   bincode-encoded message as one binary WebSocket message
+     * - `"webtransport"` uses WebTransport (HTTP/3). It is never picked
+     *   automatically, because the gateway has to accept WebTransport
+     *   sessions and speak the framing below
+     *
+     * Over WebTransport, a `wss://` gateway's session is opened at the
+     * matching `https://` URL, and plain `ws://` gateways aren't supported.
+     * The messages are the same bincode-encoded requests and responses as
+     * over a WebSocket, but each one is preceded by its length as a
+     * big-endian `u32`, since streams don't keep message boundaries:
+     *
+     * - the session's first bidirectional stream carries every message that
+     *   isn't about a particular socket (e.g. opening sockets or DNS
+     *   lookups)
+     * - the first time something is sent on a socket, a new bidirectional
+     *   stream is opened for that socket's messages, so a slow connection
+     *   doesn't hold up the rest
+     * - the gateway replies to a socket's messages on that socket's stream,
+     *   and finishes the stream once the socket is closed
+     *
+     * Responses are accepted on any stream, since they carry their own
+     * socket and request IDs. The session ends when the gateway finishes
+     * the first stream.
+     */
+    networkTransport?: "websocket" | "webtransport";
+    /**
+     * Make the TCP connections programs open through `networkGateway` via an
+     * HTTP proxy, for networks where the gateway can't reach the internet
+     * directly.
+     *
+     * Each connection gets its own tunnel, opened with a `CONNECT` request
+     * sent from the gateway to `url` and authenticated with `username` and
+     * `password` (if given). Connections to hosts matching one of the
+     * `bypass` rules are made directly. A rule can be a hostname
+     * (`"localhost"`), a domain and its subdomains (`"*.corp.example"` or
+     * `".corp.example"`), an IP address or network (`"10.0.0.0/8"`), or
+     * `"*"` for everything.
+     *
+     * The gateway's WebSocket is opened by the browser, so it follows the
+     * browser's own proxy settings.
+     */
+    networkProxy?: {
+        url: string;
+        username?: string;
+        password?: string;
+        bypass?: string[];
+    };
+    /**
+     * What to do when a worker panics.
+     *
+     * - `"quarantine"` (the default) terminates the worker that panicked and
+     *   fails whatever it was running, leaving everything else untouched
+     * - `"recover"` fails whatever the worker was running, terminates it and
+     *   starts a replacement straight away, emitting a `"worker-recovered"`
+     *   event once the replacement is ready. A replacement that panics
+     *   before then is quarantined
+     * - `"abort"` terminates every worker and refuses to run anything else,
+     *   for embedders that can't tolerate any state shared with the worker
+     *   being left inconsistent
+     *
+     * Either way, a `"worker-panicked"` event is emitted.
+     */
+    onPanic?: "quarantine" | "recover" | "abort";
+    /**
+     * How many threads programs should run in parallel.
+     *
+     * This is reported to programs as the number of available CPUs. Defaults
+     * to one less than `navigator.hardwareConcurrency` (leaving a core for
+     * the main thread), up to a maximum of 16. Browsers which were slow to
+     * start workers when the SDK first ran get half that (see
+     * {@link environmentFingerprint}).
+     */
+    threads?: number;
+    /**
+     * What to do with background housekeeping while the page is hidden.
+     *
+     * - `"run"` (the default) carries on as normal, although the browser
+     *   will throttle timers on its own
+     * - `"throttle"` runs periodic tasks at most once every 10 seconds
+     * - `"pause"` holds periodic tasks back until the page is shown again
+     *
+     * Programs themselves are never paused. Listen for the
+     * `"visibility-changed"` event to find out when the page is hidden.
+     */
+    whenHidden?: "run" | "throttle" | "pause";
+    /**
+     * Watch for the thread pool deadlocking, which usually happens when
+     * every worker is blocked (e.g. in `Atomics.wait()`) waiting on async
+     * work that is queued behind them.
+     *
+     * When this is detected, a `"deadlock-suspected"` event is emitted with
+     * what each worker was doing. If `boost` is set, that many extra workers
+     * are started for the next 30 seconds so new work has somewhere to run.
+     *
+     * Enabled by default, reporting once every worker has been blocked for
+     * `after` milliseconds (10 seconds unless specified). Set to `false` to
+     * disable.
+     */
+    deadlockDetection?: boolean | { after?: number; boost?: number };
+    /**
+     * What to do when the page's Content Security Policy won't let the
+     * thread pool start workers.
+     *
+     * - `"fail"` (the default) refuses to run anything, reporting a
+     *   {@link WorkerSpawnBlocked} error with hints for fixing the policy
+     * - `"single-threaded"` runs every task on the runtime's own thread
+     *   instead. Simple programs keep working, but anything that spawns
+     *   threads or blocks waiting for input will hang.
+     *
+     * Either way, a `"worker-spawn-blocked"` event is emitted.
+     */
+    onWorkerBlocked?: "fail" | "single-threaded";
+    /**
+     * Static hostname to IP address mappings, written to `/etc/hosts`.
+     *
+     * When either this or `nameservers` is set, programs get generated
+     * `/etc/hosts` and `/etc/resolv.conf` files (hiding anything a package
+     * bundles in `/etc`) unless something else is mounted at `/etc`. See
+     * also {@link Runtime.setHost}.
+     */
+    hosts?: Record<string, string>;
+    /** The nameservers to list in `/etc/resolv.conf`. */
+    nameservers?: string[];
+    /**
+     * The user programs run as. See {@link User} and {@link Runtime.setUser}.
+     */
+    user?: User;
+    /**
+     * Let programs started with {@link runWasix} make HTTP requests through
+     * the host by importing `host_fetch` from the `wasmer_host` module.
+     *
+     * Requests are only made to `allowedOrigins`, or to origins `approve`
+     * says yes to. Its answer is remembered for the rest of the runtime's
+     * life. Without either, every request is denied. Redirects aren't
+     * followed, since they could lead to an origin that wasn't approved.
+     *
+     * Response bodies are streamed to the program as it reads them, and
+     * reading fails with `EFBIG` once a body grows past `maxResponseBytes`
+     * (64 MiB by default).
+     *
+     * Requires the `network` capability.
+     */
+    hostFetch?: {
+        allowedOrigins?: string[];
+        approve?: (origin: string) => boolean | Promise<boolean>;
+        maxResponseBytes?: number;
+    };
+    /**
+     * Let programs started with {@link runWasix} use the browser's Web
+     * Crypto API (SHA-2, HMAC, AES-GCM and secure random numbers) by
+     * importing from the `wasmer_crypto` module. This is usually much faster
+     * than a crypto library compiled to WebAssembly.
+     *
+     * Defaults to `true`. When disabled, programs which import from
+     * `wasmer_crypto` fail to start.
+     */
+    webCrypto?: boolean;
+    /**
+     * A canvas that programs started with {@link runWasix} can draw onto
+     * through the experimental `wasmer_gpu` module, which also lets them run
+     * WebGPU compute shaders (with or without a canvas).
+     *
+     * The canvas is resized to match each frame and must not already have a
+     * 2D or WebGL context. Pass an `OffscreenCanvas` (e.g. from
+     * `transferControlToOffscreen()`) when the runtime lives in a worker.
+     *
+     * Requires the `gpu` capability.
+     */
+    gpuCanvas?: HTMLCanvasElement | OffscreenCanvas;
+    /**
+     * Programs that need a package which is already being downloaded wait
+     * for that download instead of starting another one. By default only
+     * downloads made through this runtime are shared.
+     *
+     * Set to `true` to also share downloads with every other runtime on the
+     * page that opted in. Packages are identified by their hash, so this is
+     * safe even if the runtimes use different registries.
+     */
+    shareDownloads?: boolean;
+    /**
+     * The host features programs may use, all of which are denied by
+     * default. See {@link Capabilities}.
+     */
+    capabilities?: Capabilities;
+    /**
+     * The most programs this runtime runs at the same time. Unlimited by
+     * default.
+     */
+    maxConcurrentInstances?: number;
+    /**
+     * What to do when a program is started while
+     * `maxConcurrentInstances` programs are already running.
+     *
+     * - `"queue"` (the default) waits for one of them to exit, emitting
+     *   `"instance-queued"` events as the program moves up the queue
+     * - `"reject"` fails immediately with an `InstanceLimitError`
+     */
+    whenAtCapacity?: "queue" | "reject";
+    /**
+     * Watch the page's memory use and free memory before the browser runs
+     * out and kills the tab.
+     *
+     * Every `interval` milliseconds (10 seconds unless specified), memory
+     * use is measured with `performance.measureUserAgentSpecificMemory()`
+     * on cross-origin isolated pages, or `performance.memory` elsewhere, and
+     * compared against `limit` (defaulting to the browser's JS heap limit).
+     * Changes are reported with `"memory-pressure"` events. Once use becomes
+     * critical, unpinned modules and cached packages are dropped, idle
+     * workers are terminated and, if `signal` is set, every running program
+     * is sent that signal (e.g. `"SIGUSR1"`) so it can trim its own heap.
+     *
+     * Disabled by default. Browsers that can't measure memory ignore it.
+     */
+    memoryPressure?:
+        | boolean
+        | { interval?: number; limit?: number; signal?: string | number };
+    /**
+     * Give programs a chance to exit cleanly when the page is unloaded.
+     *
+     * On `pagehide`, every running program is sent `SIGTERM` and a
+     * `"shutdown"` event is dispatched. The page then waits up to
+     * `gracePeriod` milliseconds (250 unless specified) for them to exit,
+     * holding up the unload, before killing whatever is left and syncing
+     * open {@link KeyValueStore}s. Stores are also synced whenever the page
+     * is hidden, because browsers often discard hidden tabs without
+     * unloading them.
+     *
+     * Pages going into the back/forward cache are only synced, since their
+     * programs carry on if the user comes back.
+     *
+     * Enabled by default. Only has an effect on a page's main thread.
+     */
+    shutdown?: boolean | { gracePeriod?: number };
+    /**
+     * Share workers and package cache space with the other runtimes on the
+     * page, for pages embedding several independent runtimes (e.g. one per
+     * widget) which would otherwise each size themselves as if they had the
+     * page to themselves.
+     *
+     * Runtimes using the same `channel` (a `BroadcastChannel` name) split
+     * `maxWorkers` (defaulting to `navigator.hardwareConcurrency`) and
+     * `cacheBytes` (defaulting to 256 MiB) evenly between them, using the
+     * smallest totals any of them asked for. Each runtime always gets at
+     * least one worker. The worker share is a soft limit: blocking work
+     * still starts a new worker rather than risking a deadlock, so a busy
+     * runtime can go over its share for a while. Only idle workers beyond
+     * the share are terminated, once any async tasks they are running have
+     * finished. Shares are recalculated as runtimes are created and
+     * disposed.
+     *
+     * Disabled by default.
+     */
+    arbiter?:
+        | boolean
+        | { channel?: string; maxWorkers?: number; cacheBytes?: number };
+    /**
+     * Ask before programs do something sensitive, so unvetted packages can be
+     * run interactively.
+     *
+     * The program is paused until the returned promise resolves. Returning
+     * `{ allow, remember: true }` reuses the decision for the same host or
+     * origin for the rest of the runtime's life, while a plain boolean only
+     * answers this request. Errors count as a denial.
+     *
+     * An approval callback in `hostFetch` takes precedence for fetches.
+     *
+     * @example
+     * ```ts
+     * onPermissionRequest: async (request) => ({
+     *     allow: await showDialog(`Allow connecting to ${request.host}?`),
+     *     remember: true,
+     * })
+     * ```
+     */
+    onPermissionRequest?: (
+        request: PermissionRequest,
+    ) => PermissionDecision | Promise<PermissionDecision>;
+    /**
+     * Answer programs' credential prompts (e.g. git asking for a username
+     * and password) instead of leaving them to the terminal.
+     *
+     * Programs get a `/dev/credentials` directory speaking git's credential
+     * helper protocol, and git is configured to use it. The program is
+     * paused until the returned promise resolves. Returning `null` (or
+     * throwing) leaves the program to ask the user itself.
+     *
+     * @example
+     * ```ts
+     * onCredentialRequest: async (request) =>
+     *     request.action === "get" && request.host === "github.com"
+     *         ? { username: "x-access-token", password: await getToken() }
+     *         : null
+     * ```
+     */
+    onCredentialRequest?: (
+        request: CredentialRequest,
+    ) => CredentialResponse | Promise<CredentialResponse>;
 };
 "#;
 
@@ -130,11 +936,131 @@ extern "C" {
     #[wasm_bindgen(method, getter, js_name = "apiKey")]
     fn api_key(this: &RuntimeOptions) -> Option<String>;
 
+    #[wasm_bindgen(method, getter, js_name = "getApiKey")]
+    fn get_api_key(this: &RuntimeOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(method, getter, js_name = "trustedPublishers")]
+    fn trusted_publishers(this: &RuntimeOptions) -> Option<js_sys::Array>;
+
     #[wasm_bindgen(method, getter, js_name = "networkGateway")]
     fn network_gateway(this: &RuntimeOptions) -> Option<String>;
 
+    #[wasm_bindgen(method, getter, js_name = "networkTransport")]
+    fn network_transport(this: &RuntimeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "networkProxy")]
+    fn network_proxy(this: &RuntimeOptions) -> Option<NetworkProxyOptions>;
+
+    #[wasm_bindgen(method, getter, js_name = "onPanic")]
+    fn on_panic(this: &RuntimeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn threads(this: &RuntimeOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "whenHidden")]
+    fn when_hidden(this: &RuntimeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "deadlockDetection")]
+    fn deadlock_detection(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = "onWorkerBlocked")]
+    fn on_worker_blocked(this: &RuntimeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn hosts(this: &RuntimeOptions) -> Option<js_sys::Object>;
+
+    #[wasm_bindgen(method, getter)]
+    fn nameservers(this: &RuntimeOptions) -> Option<js_sys::Array>;
+
+    #[wasm_bindgen(method, getter)]
+    fn user(this: &RuntimeOptions) -> Option<JsUser>;
+
+    #[wasm_bindgen(method, getter, js_name = "hostFetch")]
+    fn host_fetch(this: &RuntimeOptions) -> Option<HostFetchOptions>;
+
+    #[wasm_bindgen(method, getter, js_name = "webCrypto")]
+    fn web_crypto(this: &RuntimeOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter, js_name = "gpuCanvas")]
+    fn gpu_canvas(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = "shareDownloads")]
+    fn share_downloads(this: &RuntimeOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter)]
+    fn capabilities(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = "maxConcurrentInstances")]
+    fn max_concurrent_instances(this: &RuntimeOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "whenAtCapacity")]
+    fn when_at_capacity(this: &RuntimeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "memoryPressure")]
+    fn memory_pressure(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter)]
+    fn shutdown(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter)]
+    fn arbiter(this: &RuntimeOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = "onPermissionRequest")]
+    fn on_permission_request(this: &RuntimeOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(method, getter, js_name = "onCredentialRequest")]
+    fn on_credential_request(this: &RuntimeOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(typescript_type = "Capabilities")]
+    pub type JsCapabilities;
+
+    #[wasm_bindgen(typescript_type = "CapabilityName")]
+    pub type JsCapabilityName;
+
+    #[wasm_bindgen(typescript_type = "RuntimeOptions['hostFetch']")]
+    type HostFetchOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "allowedOrigins")]
+    fn allowed_origins(this: &HostFetchOptions) -> Option<js_sys::Array>;
+
+    #[wasm_bindgen(method, getter)]
+    fn approve(this: &HostFetchOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(method, getter, js_name = "maxResponseBytes")]
+    fn max_response_bytes(this: &HostFetchOptions) -> Option<f64>;
+
+    #[wasm_bindgen(typescript_type = "RuntimeOptions['networkProxy']")]
+    type NetworkProxyOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn url(this: &NetworkProxyOptions) -> String;
+
+    #[wasm_bindgen(method, getter)]
+    fn username(this: &NetworkProxyOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn password(this: &NetworkProxyOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn bypass(this: &NetworkProxyOptions) -> Option<js_sys::Array>;
+
     #[wasm_bindgen(typescript_type = "string | null | undefined")]
     type MaybeRegistryUrl;
+
+    /// The bytes of a `*.webc` file, or a version requirement.
+    #[wasm_bindgen(typescript_type = "Uint8Array | string")]
+    pub type PackageReplacement;
+}
+
+pub(crate) fn parse_count(name: &str, value: f64) -> Result<NonZeroUsize, Error> {
+    if value.fract() == 0.0 && (1.0..=usize::MAX as f64).contains(&value) {
+        if let Some(value) = NonZeroUsize::new(value as usize) {
+            return Ok(value);
+        }
+    }
+
+    let msg = format!("\"{name}\" must be a positive integer, not {value}");
+    Err(Error::js(js_sys::TypeError::new(&msg)))
 }
 
 impl MaybeRegistryUrl {
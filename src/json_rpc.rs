@@ -0,0 +1,315 @@
+//! A JSON-RPC connection to a language server running inside a WASIX
+//! instance, modelled on `vscode-jsonrpc`'s `MessageConnection` so editors can
+//! plug it in directly.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use futures::{channel::oneshot, StreamExt};
+use js_sys::{Function, Object, Promise, Reflect, JSON};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    framing::{FrameWriter, Framing},
+    utils::Error,
+    Instance,
+};
+
+/// The JSON-RPC error code for a request nobody is listening for.
+const METHOD_NOT_FOUND: i32 = -32601;
+/// The JSON-RPC error code for a request handler which threw.
+const INTERNAL_ERROR: i32 = -32603;
+
+/// A JSON-RPC connection over a program's stdin and stdout, using LSP-style
+/// `Content-Length` framing.
+///
+/// @example
+/// ```ts
+/// const pkg = await Wasmer.fromRegistry("example/rust-analyzer");
+/// const server = await pkg.commands["rust-analyzer"].startLanguageServer();
+/// server.onNotification("textDocument/publishDiagnostics", console.log);
+/// server.listen();
+/// const capabilities = await server.sendRequest("initialize", { ... });
+/// ```
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct LanguageServer {
+    inner: Rc<Inner>,
+    /// The language server's stderr, where most servers write their logs.
+    ///
+    /// This should be consumed (e.g. by piping it to the console) so logs
+    /// don't build up in memory.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub stderr: web_sys::ReadableStream,
+}
+
+#[derive(Debug)]
+struct Inner {
+    writer: FrameWriter,
+    stdout: RefCell<Option<web_sys::ReadableStream>>,
+    next_id: Cell<u64>,
+    pending: RefCell<HashMap<u64, oneshot::Sender<Result<JsValue, JsValue>>>>,
+    request_handlers: RefCell<HashMap<String, Function>>,
+    notification_handlers: RefCell<HashMap<String, Function>>,
+}
+
+impl LanguageServer {
+    /// Talk JSON-RPC to a program which was just started.
+    pub(crate) fn new(instance: Instance) -> Result<Self, Error> {
+        let stdin = instance.stdin.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("A language server can't be started with stdin provided up front")
+        })?;
+
+        Ok(LanguageServer {
+            inner: Rc::new(Inner {
+                writer: FrameWriter::new(Framing::ContentLength, stdin)?,
+                stdout: RefCell::new(Some(instance.stdout.clone())),
+                next_id: Cell::new(0),
+                pending: RefCell::default(),
+                request_handlers: RefCell::default(),
+                notification_handlers: RefCell::default(),
+            }),
+            stderr: instance.stderr.clone(),
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl LanguageServer {
+    /// Start processing messages from the server.
+    ///
+    /// Register handlers with {@link LanguageServer.onRequest} and
+    /// {@link LanguageServer.onNotification} first so no messages are missed.
+    pub fn listen(&self) {
+        let Some(stdout) = self.inner.stdout.borrow_mut().take() else {
+            return;
+        };
+
+        let inner = Rc::clone(&self.inner);
+        wasm_bindgen_futures::spawn_local(async move {
+            let mut messages = crate::framing::decode(Framing::ContentLength, stdout);
+
+            while let Some(message) = messages.next().await {
+                let result = match message {
+                    Ok(message) => inner.dispatch(&message).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    tracing::warn!(error = &*e.into_anyhow(), "Unable to handle a message");
+                }
+            }
+
+            tracing::debug!("The language server closed its stdout");
+            inner.fail_pending("The language server exited");
+        });
+    }
+
+    /// Send a request, resolving with its result or rejecting with the
+    /// JSON-RPC error the server responded with.
+    #[wasm_bindgen(js_name = "sendRequest")]
+    pub async fn send_request(
+        &self,
+        method: String,
+        params: JsonRpcValue,
+    ) -> Result<JsonRpcValue, Error> {
+        let inner = Rc::clone(&self.inner);
+
+        let id = inner.next_id.get();
+        inner.next_id.set(id + 1);
+
+        let (sender, receiver) = oneshot::channel();
+        inner.pending.borrow_mut().insert(id, sender);
+
+        let message = message(&[
+            ("id", JsValue::from(id as f64)),
+            ("method", method.into()),
+            ("params", params.into()),
+        ])?;
+        if let Err(e) = inner.send(&message).await {
+            inner.pending.borrow_mut().remove(&id);
+            return Err(e);
+        }
+
+        match receiver.await {
+            Ok(Ok(result)) => Ok(result.unchecked_into()),
+            Ok(Err(error)) => Err(Error::js(error)),
+            Err(_) => Err(anyhow::anyhow!("The connection was disposed").into()),
+        }
+    }
+
+    /// Send a notification, which has no response.
+    #[wasm_bindgen(js_name = "sendNotification")]
+    pub async fn send_notification(
+        &self,
+        method: String,
+        params: JsonRpcValue,
+    ) -> Result<(), Error> {
+        let inner = Rc::clone(&self.inner);
+        let message = message(&[("method", method.into()), ("params", params.into())])?;
+        inner.send(&message).await
+    }
+
+    /// Handle requests the server sends for `method`.
+    ///
+    /// The handler is called with the request's params and may return a
+    /// promise. If it throws, the server gets an internal error response.
+    #[wasm_bindgen(js_name = "onRequest")]
+    pub fn on_request(&self, method: String, handler: RequestHandler) {
+        self.inner
+            .request_handlers
+            .borrow_mut()
+            .insert(method, handler.unchecked_into());
+    }
+
+    /// Handle notifications the server sends for `method`.
+    #[wasm_bindgen(js_name = "onNotification")]
+    pub fn on_notification(&self, method: String, handler: NotificationHandler) {
+        self.inner
+            .notification_handlers
+            .borrow_mut()
+            .insert(method, handler.unchecked_into());
+    }
+
+    /// Close the server's stdin and reject any requests still waiting for a
+    /// response.
+    pub async fn dispose(&self) -> Result<(), Error> {
+        let inner = Rc::clone(&self.inner);
+        inner.fail_pending("The connection was disposed");
+        inner.writer.close().await
+    }
+}
+
+impl Inner {
+    async fn send(&self, message: &JsValue) -> Result<(), Error> {
+        let json = JSON::stringify(message).map_err(Error::js)?;
+        self.writer.send(String::from(json).as_bytes()).await
+    }
+
+    async fn dispatch(&self, message: &[u8]) -> Result<(), Error> {
+        let text = std::str::from_utf8(message)?;
+        let message = JSON::parse(text).map_err(Error::js)?;
+
+        let get = |key: &str| Reflect::get(&message, &JsValue::from_str(key)).map_err(Error::js);
+        let id = get("id")?;
+        let method = get("method")?.as_string();
+        let params = get("params")?;
+
+        match method {
+            Some(method) if id.is_undefined() => {
+                let handler = self.notification_handlers.borrow().get(&method).cloned();
+                match handler {
+                    Some(handler) => {
+                        handler.call1(&JsValue::NULL, &params).map_err(Error::js)?;
+                    }
+                    None => tracing::trace!(%method, "Ignoring an unhandled notification"),
+                }
+                Ok(())
+            }
+            Some(method) => {
+                let response = match self.handle_request(&method, &params).await {
+                    Ok(result) => message_with_id(id, "result", result)?,
+                    Err(error) => message_with_id(id, "error", error)?,
+                };
+                self.send(&response).await
+            }
+            None => {
+                let Some(id) = id.as_f64() else {
+                    return Err(anyhow::anyhow!("Received a response without an id").into());
+                };
+                let Some(sender) = self.pending.borrow_mut().remove(&(id as u64)) else {
+                    return Err(anyhow::anyhow!("No request is waiting for response {id}").into());
+                };
+
+                let error = get("error")?;
+                let outcome = if error.is_undefined() {
+                    Ok(get("result")?)
+                } else {
+                    Err(error)
+                };
+                let _ = sender.send(outcome);
+                Ok(())
+            }
+        }
+    }
+
+    /// Run a request handler, returning either its result or a JSON-RPC
+    /// error object.
+    async fn handle_request(&self, method: &str, params: &JsValue) -> Result<JsValue, JsValue> {
+        let handler = self.request_handlers.borrow().get(method).cloned();
+        let Some(handler) = handler else {
+            return Err(error_object(
+                METHOD_NOT_FOUND,
+                &format!("Unhandled method {method}"),
+            ));
+        };
+
+        let outcome = match handler.call1(&JsValue::NULL, params) {
+            Ok(value) => match value.dyn_into::<Promise>() {
+                Ok(promise) => JsFuture::from(promise).await,
+                Err(value) => Ok(value),
+            },
+            Err(e) => Err(e),
+        };
+
+        outcome.map_err(|e| {
+            let msg = Reflect::get(&e, &JsValue::from_str("message"))
+                .ok()
+                .and_then(|m| m.as_string())
+                .unwrap_or_else(|| format!("{e:?}"));
+            error_object(INTERNAL_ERROR, &msg)
+        })
+    }
+
+    fn fail_pending(&self, reason: &str) {
+        for (_, sender) in self.pending.borrow_mut().drain() {
+            let _ = sender.send(Err(js_sys::Error::new(reason).into()));
+        }
+    }
+}
+
+/// Create a JSON-RPC 2.0 message with the given fields.
+fn message(fields: &[(&str, JsValue)]) -> Result<JsValue, Error> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"jsonrpc".into(), &"2.0".into()).map_err(Error::js)?;
+    for (key, value) in fields {
+        if !value.is_undefined() {
+            Reflect::set(&obj, &JsValue::from_str(key), value).map_err(Error::js)?;
+        }
+    }
+    Ok(obj.into())
+}
+
+fn message_with_id(id: JsValue, key: &str, value: JsValue) -> Result<JsValue, Error> {
+    // Note: a response must always have a result or an error, even if the
+    // handler returned nothing.
+    let value = if value.is_undefined() {
+        JsValue::NULL
+    } else {
+        value
+    };
+    message(&[("id", id), (key, value)])
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// Anything that survives `JSON.stringify()`.
+    #[wasm_bindgen(typescript_type = "unknown")]
+    pub type JsonRpcValue;
+
+    #[wasm_bindgen(typescript_type = "(params: unknown) => unknown")]
+    pub type RequestHandler;
+
+    #[wasm_bindgen(typescript_type = "(params: unknown) => void")]
+    pub type NotificationHandler;
+}
+
+fn error_object(code: i32, message: &str) -> JsValue {
+    let obj = Object::new();
+    let _ = Reflect::set(&obj, &"code".into(), &code.into());
+    let _ = Reflect::set(&obj, &"message".into(), &message.into());
+    obj.into()
+}
@@ -0,0 +1,530 @@
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    rc::{Rc, Weak},
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use js_sys::{JsString, Uint8Array};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+use virtual_fs::{AsyncWriteExt, FileSystem, Metadata, OpenOptionsConfig, ReadDir, VirtualFile};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use web_sys::{IdbDatabase, IdbTransaction, IdbTransactionMode};
+
+use crate::{utils::Error, Directory, StringOrBytes};
+
+const STORE_NAME: &str = "entries";
+
+thread_local! {
+    /// Every store opened on this thread which is still alive, so they can be
+    /// synced when the page is unloaded.
+    static OPEN_STORES: RefCell<Vec<Weak<StoreState>>> = RefCell::new(Vec::new());
+    static SYNCING: Cell<bool> = Cell::new(false);
+    /// Set when a sync is requested while another is underway.
+    static SYNC_REQUESTED: Cell<bool> = Cell::new(false);
+}
+
+/// A small, persistent key-value store backed by IndexedDB.
+///
+/// Each entry is visible to WASIX programs as a file in
+/// {@link KeyValueStore.directory}, so guests can read and write settings
+/// using normal filesystem operations.
+///
+/// Changes are committed atomically: once `set()`, `delete()` or `sync()`
+/// resolves, the change has been flushed to disk, and if the tab crashes
+/// before then the store is left exactly as it was. A `sync()` is a single
+/// commit, so it never leaves some entries updated and others not. When a
+/// guest calls `fsync()` or `fdatasync()` on one of the store's files, the
+/// whole store is synced before the call returns.
+///
+/// @example
+/// ```ts
+/// import { KeyValueStore, Wasmer } from "@wasmer/sdk";
+///
+/// const store = await KeyValueStore.open("my-app");
+/// await store.set("greeting", "Hello, World!");
+///
+/// const pkg = await Wasmer.fromRegistry("sharrattj/coreutils");
+/// const instance = await pkg.commands["cat"].run({
+///     args: ["/kv/greeting"],
+///     mount: { "/kv": store.directory },
+/// });
+/// await instance.wait();
+///
+/// // Persist anything the guest may have written without calling fsync()
+/// await store.sync();
+/// ```
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct KeyValueStore {
+    state: Rc<StoreState>,
+}
+
+#[derive(Debug)]
+struct StoreState {
+    namespace: String,
+    db: IdbDatabase,
+    dir: Directory,
+    /// The `dir` guests see, which syncs the store when a file is flushed.
+    guest_dir: Directory,
+}
+
+#[wasm_bindgen]
+impl KeyValueStore {
+    /// Open the key-value store for a particular namespace, loading any
+    /// previously saved entries.
+    pub async fn open(namespace: String) -> Result<KeyValueStore, Error> {
+        KeyValueStore::open_namespace(namespace).await
+    }
+
+    /// The namespace this store was opened with.
+    #[wasm_bindgen(getter)]
+    pub fn namespace(&self) -> String {
+        self.state.namespace.clone()
+    }
+
+    /// A {@link Directory} containing one file per entry.
+    ///
+    /// Changes made by the guest are only persisted after
+    /// {@link KeyValueStore.sync} is called, or when the guest calls
+    /// `fsync()` or `fdatasync()` on one of the files.
+    #[wasm_bindgen(getter)]
+    pub fn directory(&self) -> Directory {
+        self.state.guest_dir.clone()
+    }
+
+    /// Look up an entry, returning `undefined` if it doesn't exist.
+    pub async fn get(&self, key: String) -> Result<Option<Uint8Array>, Error> {
+        validate_key(&key)?;
+
+        match self.state.dir._read_file(key).await {
+            Ok(contents) => Ok(Some(Uint8Array::from(contents.as_slice()))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Look up an entry and decode it as a UTF-8 string.
+    #[wasm_bindgen(js_name = "getText")]
+    pub async fn get_text(&self, key: String) -> Result<Option<JsString>, Error> {
+        validate_key(&key)?;
+
+        match self.state.dir._read_file(key).await {
+            Ok(contents) => Ok(Some(String::from_utf8(contents)?.into())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Save an entry.
+    ///
+    /// If a string is provided, it is encoded as UTF-8.
+    pub async fn set(&self, key: String, value: StringOrBytes) -> Result<(), Error> {
+        validate_key(&key)?;
+        let value = value.as_bytes();
+
+        write_entry(&self.state.dir, &key, &value).await?;
+
+        let transaction = crate::idb::durable_transaction(&self.state.db, STORE_NAME)?;
+        put(&transaction, &key, &value)?;
+        crate::idb::commit(&transaction).await
+    }
+
+    /// Remove an entry.
+    pub async fn delete(&self, key: String) -> Result<(), Error> {
+        validate_key(&key)?;
+
+        let _ = FileSystem::remove_file(&self.state.dir, &entry_path(&key));
+
+        let transaction = crate::idb::durable_transaction(&self.state.db, STORE_NAME)?;
+        let store = transaction.object_store(STORE_NAME).map_err(Error::js)?;
+        store.delete(&JsValue::from_str(&key)).map_err(Error::js)?;
+        crate::idb::commit(&transaction).await
+    }
+
+    /// Get the names of all entries.
+    pub async fn keys(&self) -> Result<ListOfKeys, Error> {
+        let keys: js_sys::Array = self.entry_names()?.into_iter().map(JsValue::from).collect();
+        Ok(keys.unchecked_into())
+    }
+
+    /// Write any changes made through {@link KeyValueStore.directory} back to
+    /// IndexedDB, replacing everything that was saved before in a single
+    /// commit.
+    ///
+    /// The entries are read before anything is written, so a guest should
+    /// finish writing (e.g. by writing to a temporary file and renaming it
+    /// into place) before this is called.
+    pub async fn sync(&self) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        for key in self.entry_names()? {
+            let contents = self.state.dir._read_file(key.clone()).await?;
+            entries.push((key, contents));
+        }
+
+        // Note: A transaction commits as soon as it has no pending requests,
+        // so everything has to be queued without awaiting in between
+        let transaction = crate::idb::durable_transaction(&self.state.db, STORE_NAME)?;
+        let store = transaction.object_store(STORE_NAME).map_err(Error::js)?;
+        store.clear().map_err(Error::js)?;
+        for (key, contents) in &entries {
+            put(&transaction, key, contents)?;
+        }
+        crate::idb::commit(&transaction).await?;
+
+        tracing::debug!(entries = entries.len(), namespace = %self.state.namespace, "Synced the key-value store");
+        Ok(())
+    }
+}
+
+impl KeyValueStore {
+    #[tracing::instrument(level = "debug")]
+    async fn open_namespace(namespace: String) -> Result<Self, Error> {
+        let db = crate::idb::open(&format!("wasmer-kv/{namespace}"), STORE_NAME).await?;
+        let dir = Directory::default();
+
+        let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readonly)?;
+        let keys = store.get_all_keys().map_err(Error::js)?;
+        let keys: js_sys::Array = crate::idb::complete(&keys).await?.unchecked_into();
+        let values = store.get_all().map_err(Error::js)?;
+        let values: js_sys::Array = crate::idb::complete(&values).await?.unchecked_into();
+
+        // Note: getAll() and getAllKeys() both return items in key order, so
+        // they will always line up.
+        for (key, value) in keys.iter().zip(values.iter()) {
+            let Some(key) = key.as_string() else {
+                continue;
+            };
+            let Some(value) = value.dyn_ref::<Uint8Array>().map(Uint8Array::to_vec) else {
+                tracing::warn!(%key, %namespace, "Skipping an entry which isn't a byte array");
+                continue;
+            };
+            tracing::trace!(%key, value.len = value.len(), "Loaded an entry");
+            write_entry(&dir, &key, &value).await?;
+        }
+
+        let (syncs, mut requests) = mpsc::unbounded_channel();
+        let guest_dir = Directory::from_filesystem(SyncOnFlush {
+            inner: dir.clone(),
+            syncs,
+        });
+        let state = Rc::new(StoreState {
+            namespace,
+            db,
+            dir,
+            guest_dir,
+        });
+
+        // Note: guests flush their files from a worker, but the database
+        // can only be used from the thread it was opened on
+        let weak = Rc::downgrade(&state);
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(reply) = requests.recv().await {
+                let result = match weak.upgrade() {
+                    Some(state) => KeyValueStore { state }
+                        .sync()
+                        .await
+                        .map_err(|e| e.into_anyhow().to_string()),
+                    None => Err("The key-value store has been closed".to_string()),
+                };
+                reply.send(result).ok();
+            }
+        });
+
+        OPEN_STORES.with(|stores| {
+            let mut stores = stores.borrow_mut();
+            stores.retain(|store| store.strong_count() > 0);
+            stores.push(Rc::downgrade(&state));
+        });
+        let store = KeyValueStore { state };
+
+        Ok(store)
+    }
+
+    fn entry_names(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+
+        for entry in FileSystem::read_dir(&self.state.dir, "/".as_ref())? {
+            let entry = entry?;
+            if entry.file_type().map(|ty| ty.file).unwrap_or(false) {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        Ok(names)
+    }
+}
+
+/// Sync every store opened on this thread.
+///
+/// If a sync is already underway (e.g. because several runtimes saw the page
+/// unload), another one is run once it finishes so changes made in the
+/// meantime aren't missed.
+pub(crate) async fn sync_open_stores() {
+    if SYNCING.with(|syncing| syncing.replace(true)) {
+        SYNC_REQUESTED.with(|requested| requested.set(true));
+        return;
+    }
+
+    loop {
+        SYNC_REQUESTED.with(|requested| requested.set(false));
+
+        let stores: Vec<_> = OPEN_STORES.with(|stores| {
+            stores
+                .borrow()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .map(|state| KeyValueStore { state })
+                .collect()
+        });
+        for store in stores {
+            if let Err(e) = store.sync().await {
+                tracing::warn!(
+                    namespace = %store.state.namespace,
+                    error = &*e.into_anyhow(),
+                    "Unable to sync a key-value store",
+                );
+            }
+        }
+
+        if !SYNC_REQUESTED.with(|requested| requested.get()) {
+            break;
+        }
+    }
+
+    SYNCING.with(|syncing| syncing.set(false));
+}
+
+/// A request to sync a store, answered once the sync is committed.
+type SyncRequest = oneshot::Sender<Result<(), String>>;
+
+/// A [`FileSystem`] whose files sync the key-value store when they are
+/// flushed, which is what `fsync()` and `fdatasync()` do.
+#[derive(Debug)]
+struct SyncOnFlush {
+    inner: Directory,
+    syncs: mpsc::UnboundedSender<SyncRequest>,
+}
+
+impl FileSystem for SyncOnFlush {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        FileSystem::read_dir(&self.inner, path)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        FileSystem::create_dir(&self.inner, path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        FileSystem::remove_dir(&self.inner, path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        FileSystem::rename(&self.inner, from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        FileSystem::metadata(&self.inner, path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        FileSystem::symlink_metadata(&self.inner, path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        FileSystem::remove_file(&self.inner, path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for SyncOnFlush {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let file = self
+            .inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)?;
+
+        Ok(Box::new(SyncOnFlushFile {
+            inner: file,
+            syncs: self.syncs.clone(),
+            pending: None,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct SyncOnFlushFile {
+    inner: Box<dyn VirtualFile + Send + Sync + 'static>,
+    syncs: mpsc::UnboundedSender<SyncRequest>,
+    pending: Option<oneshot::Receiver<Result<(), String>>>,
+}
+
+impl SyncOnFlushFile {
+    fn request_sync(&self) -> oneshot::Receiver<Result<(), String>> {
+        let (reply, result) = oneshot::channel();
+        // If the store is gone, the dropped reply reports it
+        self.syncs.send(reply).ok();
+        result
+    }
+}
+
+impl VirtualFile for SyncOnFlushFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        self.inner.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        self.inner.unlink()
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for SyncOnFlushFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SyncOnFlushFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.pending.is_none() {
+            futures::ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.pending = Some(self.request_sync());
+        }
+
+        let pending = self.pending.as_mut().expect("just set");
+        let result = futures::ready!(Pin::new(pending).poll(cx));
+        self.pending = None;
+
+        match result {
+            Ok(Ok(())) => Poll::Ready(Ok(())),
+            Ok(Err(reason)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, reason))),
+            Err(_) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The key-value store has been closed",
+            ))),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for SyncOnFlushFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+/// Queue up saving an entry as part of `transaction`.
+fn put(transaction: &IdbTransaction, key: &str, value: &[u8]) -> Result<(), Error> {
+    let store = transaction.object_store(STORE_NAME).map_err(Error::js)?;
+    store
+        .put_with_key(&Uint8Array::from(value), &JsValue::from_str(key))
+        .map_err(Error::js)?;
+    Ok(())
+}
+
+async fn write_entry(dir: &Directory, key: &str, value: &[u8]) -> Result<(), Error> {
+    let mut f = dir
+        .new_open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(entry_path(key))?;
+    f.write_all(value).await?;
+    f.flush().await?;
+
+    Ok(())
+}
+
+fn entry_path(key: &str) -> PathBuf {
+    PathBuf::from(format!("/{key}"))
+}
+
+fn validate_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() || key == "." || key == ".." || key.contains('/') {
+        return Err(Error::js(js_sys::TypeError::new(&format!(
+            "\"{key}\" is not a valid key. Keys must be non-empty and can't contain a \"/\""
+        ))));
+    }
+
+    Ok(())
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "string[]")]
+    pub type ListOfKeys;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn keys_cant_escape_the_namespace() {
+        let invalid = ["", ".", "..", "a/b", "/etc/passwd"];
+
+        for key in invalid {
+            assert!(validate_key(key).is_err(), "{key:?}");
+        }
+
+        assert!(validate_key("settings.json").is_ok());
+    }
+}
@@ -3,40 +3,126 @@ wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
 extern crate alloc;
 
+mod abort;
+mod arbiter;
+mod audio;
+mod batch;
+mod bench;
+mod blobs;
+mod blocking;
+mod build_info;
+mod capabilities;
+mod chunking;
+mod crash;
+mod credentials;
+mod descriptors;
+mod dns;
+mod dotenv;
+mod event_log;
+mod events;
+mod faults;
+mod fingerprint;
+mod framing;
 pub mod fs;
+mod group;
+mod host_fetch;
+mod host_info;
+mod idb;
+mod identity;
 mod instance;
+mod instance_limit;
 mod js_runtime;
+mod json_rpc;
+mod kv;
+mod line_endings;
+mod locale;
 mod logging;
+mod manifest;
+mod memory;
+mod memory_pressure;
+mod metering;
+mod module_cache;
+mod module_info;
 mod net;
 mod options;
+mod osc;
+mod overrides;
+mod package_info;
 mod package_loader;
+mod permissions;
+mod pipes;
+mod preload;
+mod processes;
+mod profile;
+mod progress;
+mod proposals;
+mod provenance;
+mod proxy;
+mod reactor;
+mod readline;
+mod recording;
+mod registry_auth;
+mod remote_stdin;
 mod run;
 mod runtime;
+mod scripts;
+mod sequenced_output;
+mod shared_memory;
+mod shutdown;
+mod startup;
+mod storage;
 mod streams;
+mod supervisor;
 mod tasks;
+mod terminal;
+mod thread_audit;
+mod timers;
+mod timing;
+mod trace_context;
+mod usage;
 mod utils;
+mod validation;
 mod wasmer;
+mod web_crypto;
+mod web_gpu;
+mod web_transport;
 mod ws;
 
 use std::sync::Mutex;
 
 pub use crate::{
-    fs::{Directory, DirectoryInit},
+    audio::AudioOutput,
+    blocking::set_blocking_diagnostics,
+    dotenv::{parse_dotenv, stringify_dotenv},
+    fingerprint::environment_fingerprint,
+    fs::{Directory, DirectoryInit, DirectorySnapshot, InstanceFs},
+    group::InstanceGroup,
     instance::{Instance, JsOutput},
     js_runtime::{JsRuntime, RuntimeOptions},
-    logging::initialize_logger,
+    kv::KeyValueStore,
+    logging::{get_log_targets, initialize_logger, set_log_filter},
+    memory::{DumpMemoryOptions, GuestMemory},
     options::{RunOptions, SpawnOptions},
+    pipes::HostPipe,
+    reactor::{instantiate_reactor, Reactor},
+    readline::{CommandHistory, ReadlineOptions},
     run::run_wasix,
+    scripts::Script,
+    shared_memory::SharedSegment,
+    tasks::{SchedulerSimulation, ThreadPool},
+    timing::{get_timing_report, reset_timing_report},
     utils::StringOrBytes,
     wasmer::Wasmer,
 };
 
+use futures::FutureExt;
 use once_cell::sync::Lazy;
 use wasm_bindgen::prelude::wasm_bindgen;
 
 pub(crate) const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 pub(crate) const DEFAULT_RUST_LOG: &[&str] = &["warn"];
 pub(crate) static CUSTOM_WORKER_URL: Lazy<Mutex<Option<String>>> = Lazy::new(Mutex::default);
+pub(crate) static CUSTOM_WORKER_SCRIPT_URL: Lazy<Mutex<Option<String>>> = Lazy::new(Mutex::default);
 pub(crate) const DEFAULT_REGISTRY: &str =
     wasmer_wasix::runtime::resolver::WapmSource::WASMER_PROD_ENDPOINT;
 
@@ -48,16 +134,31 @@ pub fn wat2wasm(wat: String) -> Result<js_sys::Uint8Array, utils::Error> {
     Ok(wasm.as_ref().into())
 }
 
+/// Does the current browser support JavaScript Promise Integration (JSPI)?
+///
+/// JSPI lets WebAssembly suspend while waiting on a JavaScript `Promise`,
+/// which is a prerequisite for running guests without dedicated workers.
+#[wasm_bindgen(js_name = "isJspiSupported")]
+pub fn is_jspi_supported() -> bool {
+    crate::utils::GlobalScope::current().supports_jspi()
+}
+
 #[wasm_bindgen(start, skip_typescript)]
 fn on_start() {
     std::panic::set_hook(Box::new(|p| {
         tracing::error!("{p}");
         console_error_panic_hook::hook(p);
+        crate::tasks::handle_panic(p);
     }));
 
-    if let Some(cross_origin_isolated) =
-        crate::utils::GlobalScope::current().cross_origin_isolated()
-    {
+    let scope = crate::utils::GlobalScope::current();
+
+    // Note: workers load the SDK too, but only the page needs to probe
+    if matches!(scope, crate::utils::GlobalScope::Window(_)) {
+        wasm_bindgen_futures::spawn_local(crate::fingerprint::initialize().map(drop));
+    }
+
+    if let Some(cross_origin_isolated) = scope.cross_origin_isolated() {
         // Note: This will need to be tweaked when we add support for Deno and
         // NodeJS.
         web_sys::console::assert_with_condition_and_data_1(
@@ -71,3 +172,17 @@ fn on_start() {
 pub fn set_worker_url(url: js_sys::JsString) {
     *CUSTOM_WORKER_URL.lock().unwrap() = Some(url.into());
 }
+
+/// Start workers from a prebuilt script rather than generating one on the fly.
+///
+/// By default, each worker is started from a `blob:` URL containing an
+/// embedded copy of the bootstrap script. Pages with a strict
+/// Content-Security-Policy (i.e. a `worker-src` that doesn't allow `blob:`) or
+/// bundlers that need to see the worker as a separate asset can serve the
+/// `dist/worker.js` file shipped with this package and point to it here.
+///
+/// Use {@link verifyWorkerScript} to make sure the script is compatible.
+#[wasm_bindgen(js_name = setWorkerScriptUrl)]
+pub fn set_worker_script_url(url: js_sys::JsString) {
+    *CUSTOM_WORKER_SCRIPT_URL.lock().unwrap() = Some(url.into());
+}
@@ -0,0 +1,536 @@
+//! Translating between `\r\n` and `\n` line endings on a program's standard
+//! streams and inside specific mounts.
+//!
+//! Inputs authored on Windows tend to have CRLF endings while most guests
+//! expect LF (and vice versa for their output), which shows up as subtle
+//! diffs in tests. Translation is opt-in, per stream and per mount.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{FileSystem, Metadata, OpenOptionsConfig, ReadDir, VirtualFile};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+use crate::utils::Error;
+
+type BoxedFile = Box<dyn VirtualFile + Send + Sync + 'static>;
+
+/// The line endings a program should see.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// The parsed `lineEndings` option.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct LineEndings {
+    stdin: Option<LineEnding>,
+    stdout: Option<LineEnding>,
+    stderr: Option<LineEnding>,
+    #[serde(default)]
+    mounts: BTreeMap<String, LineEnding>,
+}
+
+impl LineEndings {
+    pub(crate) fn parse(value: JsValue) -> Result<Self, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(LineEndings::default());
+        }
+
+        serde_wasm_bindgen::from_value(value).map_err(|e| {
+            let msg = format!("Invalid lineEndings: {e}");
+            Error::js(js_sys::TypeError::new(&msg))
+        })
+    }
+
+    /// Make sure every translated mount is actually mounted.
+    pub(crate) fn check_mounts(&self, mount_points: &[String]) -> Result<(), Error> {
+        match self.mounts.keys().find(|path| !mount_points.contains(path)) {
+            Some(path) => {
+                let msg =
+                    format!("\"lineEndings.mounts\" refers to \"{path}\", which isn't mounted");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn stdin(&self, file: BoxedFile) -> BoxedFile {
+        translated(self.stdin, file)
+    }
+
+    pub(crate) fn stdout(&self, file: BoxedFile) -> BoxedFile {
+        translated(self.stdout, file)
+    }
+
+    pub(crate) fn stderr(&self, file: BoxedFile) -> BoxedFile {
+        translated(self.stderr, file)
+    }
+
+    /// Wrap the filesystem mounted at `mount_point`, if its files should be
+    /// translated.
+    pub(crate) fn mount<F>(&self, mount_point: &str, fs: F) -> Arc<dyn FileSystem + Send + Sync>
+    where
+        F: FileSystem + Send + Sync + 'static,
+    {
+        match self.mounts.get(mount_point) {
+            Some(&ending) => Arc::new(TranslatedFileSystem {
+                inner: Arc::new(fs),
+                ending,
+            }),
+            None => Arc::new(fs),
+        }
+    }
+}
+
+fn translated(ending: Option<LineEnding>, file: BoxedFile) -> BoxedFile {
+    match ending {
+        Some(ending) => Box::new(TranslatedFile::stream(file, ending)),
+        None => file,
+    }
+}
+
+/// Incrementally rewrites line endings, coping with a `\r\n` being split
+/// across two chunks.
+#[derive(Debug, Clone)]
+struct Translator {
+    ending: LineEnding,
+    /// Was the last byte we saw a `\r`? When converting to LF, that `\r` is
+    /// held back until we know whether a `\n` follows it.
+    after_cr: bool,
+}
+
+impl Translator {
+    fn new(ending: LineEnding) -> Self {
+        Translator {
+            ending,
+            after_cr: false,
+        }
+    }
+
+    fn translate(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        output.reserve(input.len());
+
+        for &byte in input {
+            match self.ending {
+                LineEnding::Lf => {
+                    if std::mem::take(&mut self.after_cr) && byte != b'\n' {
+                        output.push(b'\r');
+                    }
+                    if byte == b'\r' {
+                        self.after_cr = true;
+                    } else {
+                        output.push(byte);
+                    }
+                }
+                LineEnding::Crlf => {
+                    if byte == b'\n' && !self.after_cr {
+                        output.push(b'\r');
+                    }
+                    output.push(byte);
+                    self.after_cr = byte == b'\r';
+                }
+            }
+        }
+    }
+
+    /// Flush anything that was held back, at the end of the stream.
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        if self.ending == LineEnding::Lf && std::mem::take(&mut self.after_cr) {
+            output.push(b'\r');
+        }
+    }
+}
+
+/// A [`VirtualFile`] which translates everything read from or written to it.
+///
+/// Files that look binary (their first chunk contains a NUL byte) are passed
+/// through untouched. Offsets and sizes are those of the underlying file, so
+/// translation is only meant for files which are read or written
+/// sequentially.
+#[derive(Debug)]
+struct TranslatedFile {
+    inner: BoxedFile,
+    reader: Translator,
+    writer: Translator,
+    /// Is this one of the program's standard streams? A `\r` held back at
+    /// the end of a chunk read from one, or when one is flushed, is released
+    /// straight away so prompts aren't left waiting on the next line.
+    stream: bool,
+    binary: Option<bool>,
+    /// Translated bytes that haven't been read yet.
+    readable: VecDeque<u8>,
+    /// Translated bytes that haven't been written to `inner` yet.
+    unwritten: Vec<u8>,
+    eof: bool,
+}
+
+impl TranslatedFile {
+    fn new(inner: BoxedFile, ending: LineEnding) -> Self {
+        TranslatedFile {
+            inner,
+            reader: Translator::new(ending),
+            writer: Translator::new(ending),
+            stream: false,
+            binary: None,
+            readable: VecDeque::new(),
+            unwritten: Vec::new(),
+            eof: false,
+        }
+    }
+
+    fn stream(inner: BoxedFile, ending: LineEnding) -> Self {
+        TranslatedFile {
+            stream: true,
+            ..TranslatedFile::new(inner, ending)
+        }
+    }
+
+    fn is_binary(&mut self, chunk: &[u8]) -> bool {
+        if chunk.is_empty() {
+            return self.binary.unwrap_or(false);
+        }
+        *self.binary.get_or_insert_with(|| chunk.contains(&0))
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unwritten.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.unwritten) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    self.unwritten.drain(..written);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl VirtualFile for TranslatedFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        self.inner.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        self.inner.unlink()
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if !self.readable.is_empty() {
+            return Poll::Ready(Ok(self.readable.len()));
+        }
+        Pin::new(&mut self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for TranslatedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        while this.readable.is_empty() && !this.eof {
+            let mut chunk = vec![0; buf.remaining().max(1)];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let filled = chunk_buf.filled().to_vec();
+            let mut translated = Vec::new();
+            if filled.is_empty() {
+                this.eof = true;
+                this.reader.finish(&mut translated);
+            } else if this.is_binary(&filled) {
+                translated = filled;
+            } else {
+                this.reader.translate(&filled, &mut translated);
+                if this.stream {
+                    this.reader.finish(&mut translated);
+                }
+            }
+            this.readable.extend(translated);
+        }
+
+        let len = this.readable.len().min(buf.remaining());
+        let (front, back) = this.readable.as_slices();
+        if len <= front.len() {
+            buf.put_slice(&front[..len]);
+        } else {
+            buf.put_slice(front);
+            buf.put_slice(&back[..len - front.len()]);
+        }
+        this.readable.drain(..len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TranslatedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        // Apply backpressure until the previous write has made it through
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map_ok(|_| 0),
+        }
+
+        if this.is_binary(buf) {
+            this.unwritten.extend_from_slice(buf);
+        } else {
+            let mut translated = Vec::new();
+            this.writer.translate(buf, &mut translated);
+            this.unwritten.extend(translated);
+        }
+
+        // The bytes are buffered either way, so errors surface on the next
+        // write or flush
+        let _ = this.poll_drain(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.stream {
+            let mut rest = Vec::new();
+            this.writer.finish(&mut rest);
+            this.unwritten.extend(rest);
+        }
+
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let mut rest = Vec::new();
+        this.writer.finish(&mut rest);
+        this.unwritten.extend(rest);
+
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+impl AsyncSeek for TranslatedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        self.readable.clear();
+        self.eof = false;
+        self.reader.after_cr = false;
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+/// A [`FileSystem`] whose files get their line endings translated.
+#[derive(Debug, Clone)]
+struct TranslatedFileSystem {
+    inner: Arc<dyn FileSystem + Send + Sync>,
+    ending: LineEnding,
+}
+
+impl FileSystem for TranslatedFileSystem {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        self.inner.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for TranslatedFileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let file = self
+            .inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)?;
+        Ok(Box::new(TranslatedFile::new(file, self.ending)))
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const LINE_ENDINGS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Line endings to translate to. See `CommonOptions.lineEndings`.
+ *
+ * - `"lf"` - `\r\n` becomes `\n`
+ * - `"crlf"` - a `\n` that isn't already part of a `\r\n` becomes `\r\n`
+ */
+export type LineEnding = "lf" | "crlf";
+
+/**
+ * Where line endings should be translated. Nothing is translated unless it
+ * is listed here.
+ */
+export type LineEndingOptions = {
+    /** What the program reads from stdin. */
+    stdin?: LineEnding;
+    /** What the program writes to stdout. */
+    stdout?: LineEnding;
+    /** What the program writes to stderr. */
+    stderr?: LineEnding;
+    /**
+     * Files in `mount`ed directories, keyed by mount point (e.g.
+     * `{ "/project": "lf" }`). Both reads and writes are translated.
+     *
+     * Files that look binary (i.e. contain a NUL byte in their first chunk)
+     * are left alone. Offsets and sizes stay those of the untranslated
+     * file, so this is meant for files that are read and written
+     * sequentially.
+     */
+    mounts?: Record<string, LineEnding>;
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::{AsyncReadExt, AsyncWriteExt};
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use wasmer_wasix::runtime::task_manager::InlineWaker;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn line_endings_are_translated_across_chunks() {
+        let mut output = Vec::new();
+        let mut to_lf = Translator::new(LineEnding::Lf);
+        to_lf.translate(b"a\r", &mut output);
+        to_lf.translate(b"\nb\rc\r", &mut output);
+        to_lf.finish(&mut output);
+        assert_eq!(output, b"a\nb\rc\r");
+
+        let mut output = Vec::new();
+        let mut to_crlf = Translator::new(LineEnding::Crlf);
+        to_crlf.translate(b"a\nb\r", &mut output);
+        to_crlf.translate(b"\n", &mut output);
+        assert_eq!(output, b"a\r\nb\r\n");
+
+        let options = LineEndings {
+            mounts: [("/project".to_string(), LineEnding::Lf)].into(),
+            ..Default::default()
+        };
+        let project = virtual_fs::mem_fs::FileSystem::default();
+        let fs = options.mount("/project", project.clone());
+        let contents = InlineWaker::block_on(async {
+            let mut f = project
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open("/notes.txt")?;
+            f.write_all(b"one\r\ntwo\r\n").await?;
+            f.flush().await?;
+
+            let mut f = fs.new_open_options().read(true).open("/notes.txt")?;
+            let mut contents = String::new();
+            f.read_to_string(&mut contents).await?;
+            Ok::<_, io::Error>(contents)
+        })
+        .unwrap();
+        assert_eq!(contents, "one\ntwo\n");
+        assert!(options.check_mounts(&[]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn flushing_a_stream_releases_a_trailing_cr() {
+        let fs = virtual_fs::mem_fs::FileSystem::default();
+        let written = InlineWaker::block_on(async {
+            let file = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open("/stdout")?;
+            let mut stdout = TranslatedFile::stream(file, LineEnding::Lf);
+            stdout.write_all(b"progress: 50%\r").await?;
+            stdout.flush().await?;
+
+            let mut f = fs.new_open_options().read(true).open("/stdout")?;
+            let mut written = String::new();
+            f.read_to_string(&mut written).await?;
+            Ok::<_, io::Error>(written)
+        })
+        .unwrap();
+
+        assert_eq!(written, "progress: 50%\r");
+    }
+}
@@ -0,0 +1,330 @@
+//! Locale and timezone conventions for programs, so they don't have to run
+//! in the `C` locale with UTC-only time.
+//!
+//! Programs get `LANG`, `TZ` and (when ICU data is provided) `ICU_DATA`
+//! variables. The data files those refer to are only fetched the first time
+//! a program opens them, and are cached for the rest of the page's life.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use http::Method;
+use once_cell::sync::Lazy;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasmer_wasix::http::{DynHttpClient, HttpRequest};
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Generator},
+    utils::{Error, GlobalScope},
+};
+
+/// Where libc looks for timezone files.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+/// Where ICU data gets mounted.
+const ICU_DIR: &str = "/usr/share/icu";
+
+/// Data files that have already been downloaded, keyed by URL.
+static DOWNLOADS: Lazy<Mutex<HashMap<String, Arc<Vec<u8>>>>> = Lazy::new(Mutex::default);
+
+/// The locale a program runs with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Locale {
+    /// A POSIX locale name (e.g. `de_DE.UTF-8`).
+    pub(crate) lang: Option<String>,
+    /// An IANA timezone (e.g. `Europe/Berlin`).
+    pub(crate) timezone: Option<String>,
+    /// The base URL timezone files are fetched from.
+    pub(crate) zoneinfo: Option<String>,
+    /// The URL of an ICU data file (e.g. `icudt74l.dat`).
+    pub(crate) icu: Option<String>,
+}
+
+impl Locale {
+    /// Parse the `locale` option, where `true` means "use the host's
+    /// language and timezone".
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Locale>, Error> {
+        if value.is_undefined() || value.is_null() || value.as_bool() == Some(false) {
+            return Ok(None);
+        }
+
+        if value.as_bool() == Some(true) {
+            return Ok(Some(Locale {
+                lang: host_language().map(|tag| posix_locale(&tag)),
+                timezone: host_timezone(),
+                ..Default::default()
+            }));
+        }
+
+        if let Some(lang) = value.as_string() {
+            return Ok(Some(Locale {
+                lang: Some(posix_locale(&lang)),
+                timezone: host_timezone(),
+                ..Default::default()
+            }));
+        }
+
+        let options: &LocaleOptions = value.unchecked_ref();
+        let timezone = match options.timezone() {
+            Some(tz) if tz == "auto" => host_timezone(),
+            Some(tz) => Some(validate_timezone(tz)?),
+            None => host_timezone(),
+        };
+
+        Ok(Some(Locale {
+            lang: options
+                .lang()
+                .or_else(host_language)
+                .map(|tag| posix_locale(&tag)),
+            timezone,
+            zoneinfo: options.zoneinfo(),
+            icu: options.icu(),
+        }))
+    }
+
+    /// The environment variables describing this locale.
+    pub(crate) fn env(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+
+        if let Some(lang) = &self.lang {
+            vars.push(("LANG".to_string(), lang.clone()));
+        }
+        if let Some(tz) = &self.timezone {
+            vars.push(("TZ".to_string(), tz.clone()));
+        }
+        if self.icu.is_some() {
+            vars.push(("ICU_DATA".to_string(), ICU_DIR.to_string()));
+        }
+
+        vars
+    }
+
+    /// Filesystems containing the timezone and ICU data, which are
+    /// downloaded the first time they are read.
+    pub(crate) fn mounts(&self, client: Option<DynHttpClient>) -> Vec<(String, DeviceFileSystem)> {
+        let Some(client) = client else {
+            return Vec::new();
+        };
+
+        self.files()
+            .into_iter()
+            .map(|file| {
+                let fs = DeviceFileSystem::default();
+                fs.insert(&file.name, lazy_download(file.url, client.clone()));
+                (file.dir, fs)
+            })
+            .collect()
+    }
+
+    fn files(&self) -> Vec<DataFile> {
+        let mut files = Vec::new();
+
+        if let (Some(base), Some(tz)) = (&self.zoneinfo, &self.timezone) {
+            let (dir, name) = match tz.rsplit_once('/') {
+                Some((region, name)) => (format!("{ZONEINFO_DIR}/{region}"), name),
+                None => (ZONEINFO_DIR.to_string(), tz.as_str()),
+            };
+            files.push(DataFile {
+                dir,
+                name: name.to_string(),
+                url: format!("{}/{tz}", base.trim_end_matches('/')),
+            });
+        }
+
+        if let Some(url) = &self.icu {
+            let name = url
+                .rsplit('/')
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or("icudt.dat");
+            files.push(DataFile {
+                dir: ICU_DIR.to_string(),
+                name: name.to_string(),
+                url: url.clone(),
+            });
+        }
+
+        files
+    }
+}
+
+/// Would a directory mounted at `mount_point` hide `dir`?
+pub(crate) fn conflicts_with(dir: &str, mount_point: &str) -> bool {
+    let mount_point = mount_point.trim_end_matches('/');
+    mount_point.is_empty() || dir == mount_point || dir.starts_with(&format!("{mount_point}/"))
+}
+
+/// A data file, and the directory it should be mounted in.
+#[derive(Debug, Clone, PartialEq)]
+struct DataFile {
+    dir: String,
+    name: String,
+    url: String,
+}
+
+/// A file whose contents are downloaded (at most once) when it is first
+/// used.
+fn lazy_download(url: String, client: DynHttpClient) -> Device {
+    Device::Generated(Generator::new(move || {
+        if let Some(cached) = DOWNLOADS.lock().unwrap().get(&url) {
+            return cached.as_ref().clone();
+        }
+
+        let request = HttpRequest {
+            url: match url.parse() {
+                Ok(url) => url,
+                Err(_) => return Vec::new(),
+            },
+            method: Method::GET,
+            headers: Default::default(),
+            body: None,
+            options: Default::default(),
+        };
+
+        match crate::blocking::block_on("downloading locale data", client.request(request)) {
+            Ok(response) if response.status.is_success() => {
+                let body = Arc::new(response.body.unwrap_or_default());
+                DOWNLOADS
+                    .lock()
+                    .unwrap()
+                    .insert(url.clone(), Arc::clone(&body));
+                body.as_ref().clone()
+            }
+            Ok(response) => {
+                tracing::warn!(%url, status = %response.status, "Unable to download locale data");
+                Vec::new()
+            }
+            Err(e) => {
+                tracing::warn!(%url, error = &*e, "Unable to download locale data");
+                Vec::new()
+            }
+        }
+    }))
+}
+
+/// Turn a BCP 47 language tag (e.g. `"de-DE"`) into a POSIX locale name
+/// (`"de_DE.UTF-8"`), leaving anything that already looks like one alone.
+pub(crate) fn posix_locale(tag: &str) -> String {
+    if tag == "C" || tag == "POSIX" || tag.contains('.') || tag.contains('_') {
+        return tag.to_string();
+    }
+
+    let mut parts = tag.split('-');
+    let language = parts.next().unwrap_or_default().to_lowercase();
+    match parts.find(|p| p.len() == 2) {
+        Some(region) => format!("{language}_{}.UTF-8", region.to_uppercase()),
+        None => format!("{language}.UTF-8"),
+    }
+}
+
+fn validate_timezone(tz: String) -> Result<String, Error> {
+    let valid = !tz.is_empty()
+        && !tz
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        && tz
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'));
+
+    if valid {
+        Ok(tz)
+    } else {
+        let msg = format!("\"{tz}\" isn't a valid IANA timezone");
+        Err(Error::js(js_sys::TypeError::new(&msg)))
+    }
+}
+
+fn host_language() -> Option<String> {
+    GlobalScope::current()
+        .lookup(&["navigator", "language"])
+        .and_then(|lang| lang.as_string())
+}
+
+fn host_timezone() -> Option<String> {
+    let format = js_sys::Intl::DateTimeFormat::new(&js_sys::Array::new(), &js_sys::Object::new());
+    let options = format.resolved_options();
+    js_sys::Reflect::get(&options, &JsValue::from_str("timeZone"))
+        .ok()
+        .and_then(|tz| tz.as_string())
+        .and_then(|tz| validate_timezone(tz).ok())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const LOCALE_OPTIONS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * The locale and timezone a program runs with. See `CommonOptions.locale`.
+ */
+export type LocaleOptions = {
+    /**
+     * A BCP 47 language tag (e.g. `"de-DE"`) or POSIX locale name, used to
+     * set `LANG`. Defaults to `navigator.language`.
+     */
+    lang?: string;
+    /**
+     * An IANA timezone (e.g. `"Europe/Berlin"`), used to set `TZ`. Defaults
+     * to `"auto"`, the host's timezone.
+     */
+    timezone?: string;
+    /**
+     * The base URL of a `zoneinfo` database (i.e. `${zoneinfo}/Europe/Berlin`
+     * is a TZif file). The program's timezone is mounted under
+     * `/usr/share/zoneinfo`, so timezone math works for more than just UTC.
+     */
+    zoneinfo?: string;
+    /**
+     * The URL of an ICU data file (e.g. `icudt74l.dat`), mounted in
+     * `/usr/share/icu` with `ICU_DATA` pointing to it.
+     */
+    icu?: string;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "LocaleOptions")]
+    pub type LocaleOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn lang(this: &LocaleOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn timezone(this: &LocaleOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn zoneinfo(this: &LocaleOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn icu(this: &LocaleOptions) -> Option<String>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn locale_names_and_mounts() {
+        assert_eq!(posix_locale("de-DE"), "de_DE.UTF-8");
+        assert_eq!(posix_locale("zh-Hant-TW"), "zh_TW.UTF-8");
+        assert_eq!(posix_locale("fr"), "fr.UTF-8");
+        assert_eq!(posix_locale("en_GB.ISO-8859-1"), "en_GB.ISO-8859-1");
+        assert!(validate_timezone("../../etc/passwd".to_string()).is_err());
+
+        let locale = Locale {
+            lang: Some("de_DE.UTF-8".to_string()),
+            timezone: Some("Europe/Berlin".to_string()),
+            zoneinfo: Some("https://example.com/zoneinfo/".to_string()),
+            icu: Some("https://example.com/icudt74l.dat".to_string()),
+        };
+        let files = locale.files();
+
+        assert_eq!(files[0].dir, "/usr/share/zoneinfo/Europe");
+        assert_eq!(files[0].name, "Berlin");
+        assert_eq!(files[0].url, "https://example.com/zoneinfo/Europe/Berlin");
+        assert_eq!(files[1].dir, "/usr/share/icu");
+        assert_eq!(files[1].name, "icudt74l.dat");
+        assert_eq!(locale.env().len(), 3);
+    }
+}
@@ -1,11 +1,30 @@
-use std::io::{ErrorKind, Write};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::{ErrorKind, Write},
+    pin::pin,
+    sync::Mutex,
+};
+
+use futures::future::Either;
 
+use instant::{Duration, Instant};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
 use tokio::sync::mpsc;
+use tracing::{level_filters::LevelFilter, subscriber::Interest, Metadata, Subscriber};
 use tracing_subscriber::{
     fmt::{format::FmtSpan, MakeWriter},
-    EnvFilter,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    timing::TimingLayer,
+    utils::{Error, GlobalScope},
 };
-use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
 /// Initialize the logger used by `@wasmer/wasix`.
 ///
@@ -30,29 +49,218 @@ use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 ///
 /// When no `filter` string is provided, a useful default will be used.
 ///
+/// ## Noisy Programs
+///
+/// A program that logs in a tight loop can make the browser's devtools
+/// unusable. The `options` can be used to collapse repeated messages and cap
+/// how many messages each module may log per second. See
+/// {@link LoggerOptions} for more.
+///
 /// [format]: https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
 #[wasm_bindgen(js_name = "initializeLogger")]
-pub fn initialize_logger(filter: Option<String>) -> Result<(), crate::utils::Error> {
-    let max_level = tracing::level_filters::STATIC_MAX_LEVEL
-        .into_level()
-        .unwrap_or(tracing::Level::ERROR);
+pub fn initialize_logger(
+    filter: Option<String>,
+    options: Option<LoggerOptions>,
+) -> Result<(), crate::utils::Error> {
+    let throttle = match options {
+        Some(options) => LogThrottle::from_options(&options)?,
+        None => LogThrottle::default(),
+    };
 
-    let filter = EnvFilter::builder()
-        .with_regex(false)
-        .with_default_directive(max_level.into())
-        .parse_lossy(filter.unwrap_or_else(|| crate::DEFAULT_RUST_LOG.join(",")));
+    let filter = filter.unwrap_or_else(|| crate::DEFAULT_RUST_LOG.join(","));
 
-    tracing_subscriber::fmt::fmt()
-        .with_writer(ConsoleLogger::spawn())
-        .with_env_filter(filter)
+    let builder = tracing_subscriber::fmt::fmt()
+        .with_writer(ConsoleLogger::spawn(throttle))
+        .with_env_filter(parse_filter(&filter))
         .with_span_events(FmtSpan::CLOSE)
         .without_time()
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+
+    builder
+        .finish()
+        .with(TimingLayer)
+        .with(TargetRecorder)
         .try_init()
         .map_err(|e| anyhow::anyhow!(e))?;
 
+    let _ = RELOAD_FILTER.set(Box::new(move |filter| {
+        handle.reload(filter).map_err(|e| anyhow::anyhow!(e))
+    }));
+    *CURRENT_FILTER.lock().unwrap() = filter;
+
+    Ok(())
+}
+
+/// Replace the filter passed to {@link initializeLogger}, using the same
+/// format.
+///
+/// Use {@link getLogTargets} to find out which targets can be filtered.
+#[wasm_bindgen(js_name = "setLogFilter")]
+pub fn set_log_filter(filter: String) -> Result<(), Error> {
+    let reload = RELOAD_FILTER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("The logger hasn't been initialized"))?;
+
+    reload(parse_filter(&filter))?;
+    *CURRENT_FILTER.lock().unwrap() = filter;
+
     Ok(())
 }
 
+/// List every tracing target that has tried to log something so far, along
+/// with the level the current filter allows it to log at.
+///
+/// Targets are only discovered once code using them runs, so more may
+/// appear over time. Nothing is recorded until {@link initializeLogger} has
+/// been called.
+#[wasm_bindgen(js_name = "getLogTargets")]
+pub fn get_log_targets() -> Result<ListOfLogTargets, Error> {
+    let filter = CURRENT_FILTER.lock().unwrap().clone();
+
+    let targets: Vec<LogTarget> = SEEN_TARGETS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|target| LogTarget {
+            target: target.clone(),
+            level: level_name(effective_level(&filter, target)),
+        })
+        .collect();
+
+    let value = serde_wasm_bindgen::to_value(&targets).map_err(Error::js)?;
+    Ok(value.unchecked_into())
+}
+
+/// Swaps out the active filter. Set by [`initialize_logger()`].
+#[allow(clippy::type_complexity)]
+static RELOAD_FILTER: OnceCell<Box<dyn Fn(EnvFilter) -> Result<(), anyhow::Error> + Send + Sync>> =
+    OnceCell::new();
+
+/// The filter string currently in effect.
+static CURRENT_FILTER: Mutex<String> = Mutex::new(String::new());
+
+/// Every target a callsite has been registered for.
+static SEEN_TARGETS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn max_level() -> LevelFilter {
+    tracing::level_filters::STATIC_MAX_LEVEL
+}
+
+fn parse_filter(filter: &str) -> EnvFilter {
+    let default = max_level().into_level().unwrap_or(tracing::Level::ERROR);
+
+    EnvFilter::builder()
+        .with_regex(false)
+        .with_default_directive(default.into())
+        .parse_lossy(filter)
+}
+
+/// Work out the level `filter` lets `target` log at, mirroring how
+/// [`EnvFilter`] picks the most specific matching directive.
+///
+/// Directives which only apply inside particular spans (e.g.
+/// `wasmer_wasix[run]=trace`) are ignored.
+fn effective_level(filter: &str, target: &str) -> LevelFilter {
+    let mut directives = 0;
+    let mut best: Option<(usize, LevelFilter)> = None;
+
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        directives += 1;
+        if directive.contains('[') {
+            continue;
+        }
+
+        let (prefix, level) = match directive.split_once('=') {
+            Some((prefix, level)) => match level.trim().parse::<LevelFilter>() {
+                Ok(level) => (prefix.trim(), level),
+                Err(_) => continue,
+            },
+            None => match directive.parse::<LevelFilter>() {
+                Ok(level) => ("", level),
+                Err(_) => (directive, LevelFilter::TRACE),
+            },
+        };
+
+        let matches = prefix.is_empty()
+            || target == prefix
+            || target
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with("::"));
+
+        if matches && best.map_or(true, |(len, _)| prefix.len() >= len) {
+            best = Some((prefix.len(), level));
+        }
+    }
+
+    let level = match best {
+        Some((_, level)) => level,
+        None if directives == 0 => max_level(),
+        None => LevelFilter::OFF,
+    };
+
+    level.min(max_level())
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level.into_level() {
+        None => "off",
+        Some(tracing::Level::ERROR) => "error",
+        Some(tracing::Level::WARN) => "warn",
+        Some(tracing::Level::INFO) => "info",
+        Some(tracing::Level::DEBUG) => "debug",
+        Some(tracing::Level::TRACE) => "trace",
+    }
+}
+
+/// Records the target of every callsite that gets hit, whether or not the
+/// filter lets it log.
+struct TargetRecorder;
+
+impl<S: Subscriber> Layer<S> for TargetRecorder {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let mut targets = SEEN_TARGETS.lock().unwrap();
+        if !targets.contains(metadata.target()) {
+            targets.insert(metadata.target().to_string());
+        }
+
+        // Leave the decision to the filter
+        Interest::always()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct LogTarget {
+    target: String,
+    level: &'static str,
+}
+
+/// How many log lines to remember for things like crash reports.
+const MAX_RECENT_LOGS: usize = 256;
+
+/// The most recent log lines, shared by every thread.
+static RECENT_LOGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Get (at most) the last `count` lines that were logged.
+///
+/// Nothing is recorded unless [`initialize_logger()`] has been called.
+pub(crate) fn recent_logs(count: usize) -> Vec<String> {
+    let logs = RECENT_LOGS.lock().unwrap();
+    let skip = logs.len().saturating_sub(count);
+    logs.iter().skip(skip).cloned().collect()
+}
+
+fn remember(text: &str) {
+    let mut logs = RECENT_LOGS.lock().unwrap();
+
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        if logs.len() == MAX_RECENT_LOGS {
+            logs.pop_front();
+        }
+        logs.push_back(line.to_string());
+    }
+}
+
 /// A [`std::io::Write`] implementation which will pass all messages to the main
 /// thread for logging with [`web_sys::console`].
 ///
@@ -61,23 +269,84 @@ pub fn initialize_logger(filter: Option<String>) -> Result<(), crate::utils::Err
 #[derive(Debug)]
 struct ConsoleLogger {
     buffer: Vec<u8>,
-    sender: mpsc::UnboundedSender<String>,
+    target: String,
+    sender: mpsc::UnboundedSender<(String, String)>,
 }
 
 impl ConsoleLogger {
-    fn spawn() -> impl for<'w> MakeWriter<'w> + 'static {
-        let (sender, mut receiver) = mpsc::unbounded_channel();
+    fn spawn(mut throttle: LogThrottle) -> MakeConsoleLogger {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(String, String)>();
 
         wasm_bindgen_futures::spawn_local(async move {
-            while let Some(msg) = receiver.recv().await {
-                let js_string = JsValue::from(msg);
-                web_sys::console::log_1(&js_string);
+            let print = |lines: Vec<String>| {
+                for line in lines {
+                    let js_string = JsValue::from(line);
+                    web_sys::console::log_1(&js_string);
+                }
+            };
+
+            loop {
+                // Note: wake up when a summary is due, so it gets printed
+                // even if nothing else is logged
+                let received = match throttle.next_deadline() {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        let wait = if deadline > now {
+                            deadline.duration_since(now)
+                        } else {
+                            Duration::ZERO
+                        };
+                        let ms = i32::try_from(wait.as_millis()).unwrap_or(i32::MAX);
+                        let timeout = JsFuture::from(GlobalScope::current().sleep(ms));
+                        match futures::future::select(pin!(receiver.recv()), timeout).await {
+                            Either::Left((received, _)) => received,
+                            Either::Right((Ok(_), _)) => {
+                                print(throttle.expire(Instant::now()));
+                                continue;
+                            }
+                            // Without setTimeout(), the summaries have to
+                            // wait for the next message
+                            Either::Right((Err(_), received)) => received.await,
+                        }
+                    }
+                    None => receiver.recv().await,
+                };
+
+                match received {
+                    Some((target, msg)) => print(throttle.process(&target, msg, Instant::now())),
+                    None => {
+                        print(throttle.flush());
+                        break;
+                    }
+                }
             }
         });
 
-        move || ConsoleLogger {
+        MakeConsoleLogger { sender }
+    }
+}
+
+/// Creates a [`ConsoleLogger`] for each log message, remembering which target
+/// the message came from.
+struct MakeConsoleLogger {
+    sender: mpsc::UnboundedSender<(String, String)>,
+}
+
+impl<'a> MakeWriter<'a> for MakeConsoleLogger {
+    type Writer = ConsoleLogger;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ConsoleLogger {
             buffer: Vec::new(),
-            sender: sender.clone(),
+            target: String::new(),
+            sender: self.sender.clone(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        ConsoleLogger {
+            target: meta.target().to_string(),
+            ..self.make_writer()
         }
     }
 }
@@ -94,8 +363,10 @@ impl Write for ConsoleLogger {
         let text = String::from_utf8(buffer)
             .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
 
+        remember(&text);
+
         self.sender
-            .send(text)
+            .send((std::mem::take(&mut self.target), text))
             .map_err(|e| std::io::Error::new(ErrorKind::BrokenPipe, e))?;
 
         Ok(())
@@ -109,3 +380,358 @@ impl Drop for ConsoleLogger {
         }
     }
 }
+
+/// Collapses repeated log messages and limits how fast each target can log.
+#[derive(Debug, Default)]
+struct LogThrottle {
+    /// Identical messages logged within this window of each other are
+    /// collapsed into a "repeated N times" summary.
+    dedupe_window: Option<Duration>,
+    /// The most messages a single target may log each second.
+    max_per_second: Option<u32>,
+    last: Option<LastMessage>,
+    targets: BTreeMap<String, TargetBudget>,
+}
+
+#[derive(Debug)]
+struct LastMessage {
+    text: String,
+    seen_at: Instant,
+    repeats: u32,
+}
+
+#[derive(Debug)]
+struct TargetBudget {
+    window_start: Instant,
+    count: u32,
+    suppressed: u32,
+}
+
+impl LogThrottle {
+    fn from_options(options: &LoggerOptions) -> Result<Self, Error> {
+        let dedupe_window = match options.dedupe_window_ms() {
+            Some(ms) if ms.is_finite() && ms >= 0.0 => Some(Duration::from_secs_f64(ms / 1000.0)),
+            Some(ms) => return Err(invalid_option("dedupeWindowMs", ms)),
+            None => None,
+        };
+
+        let max_per_second = match options.max_messages_per_second() {
+            Some(n) if n.fract() == 0.0 && (1.0..=u32::MAX as f64).contains(&n) => Some(n as u32),
+            Some(n) => return Err(invalid_option("maxMessagesPerSecond", n)),
+            None => None,
+        };
+
+        Ok(LogThrottle {
+            dedupe_window,
+            max_per_second,
+            ..Default::default()
+        })
+    }
+
+    /// Decide what should actually be printed when `target` logs `text`.
+    fn process(&mut self, target: &str, text: String, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if let Some(window) = self.dedupe_window {
+            match &mut self.last {
+                Some(last) if last.text == text && now.duration_since(last.seen_at) <= window => {
+                    last.repeats += 1;
+                    last.seen_at = now;
+                    return lines;
+                }
+                _ => {
+                    if let Some(summary) = self.last.take().and_then(|last| last.summary()) {
+                        lines.push(summary);
+                    }
+                    self.last = Some(LastMessage {
+                        text: text.clone(),
+                        seen_at: now,
+                        repeats: 0,
+                    });
+                }
+            }
+        }
+
+        if let Some(max) = self.max_per_second {
+            let budget = self
+                .targets
+                .entry(target.to_string())
+                .or_insert(TargetBudget {
+                    window_start: now,
+                    count: 0,
+                    suppressed: 0,
+                });
+
+            if now.duration_since(budget.window_start) >= Duration::from_secs(1) {
+                lines.extend(budget.summary(target));
+                budget.window_start = now;
+                budget.count = 0;
+                budget.suppressed = 0;
+            }
+
+            if budget.count >= max {
+                budget.suppressed += 1;
+                return lines;
+            }
+            budget.count += 1;
+        }
+
+        lines.push(text);
+        lines
+    }
+
+    /// When will a summary need to be printed, if nothing else is logged
+    /// before then?
+    fn next_deadline(&self) -> Option<Instant> {
+        let repeats = self
+            .last
+            .as_ref()
+            .filter(|last| last.repeats > 0)
+            .zip(self.dedupe_window)
+            .map(|(last, window)| last.seen_at + window);
+        let suppressed = self
+            .targets
+            .values()
+            .filter(|budget| budget.suppressed > 0)
+            .map(|budget| budget.window_start + Duration::from_secs(1));
+
+        repeats.into_iter().chain(suppressed).min()
+    }
+
+    /// Print the summaries for any windows which have finished by `now`.
+    fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if let (Some(last), Some(window)) = (&self.last, self.dedupe_window) {
+            if now.duration_since(last.seen_at) >= window {
+                lines.extend(self.last.take().and_then(|last| last.summary()));
+            }
+        }
+
+        self.targets.retain(|target, budget| {
+            if now.duration_since(budget.window_start) < Duration::from_secs(1) {
+                return true;
+            }
+            lines.extend(budget.summary(target));
+            false
+        });
+
+        lines
+    }
+
+    /// Print every outstanding summary, e.g. because the logger is shutting
+    /// down.
+    fn flush(&mut self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .last
+            .take()
+            .and_then(|last| last.summary())
+            .into_iter()
+            .collect();
+        for (target, budget) in std::mem::take(&mut self.targets) {
+            lines.extend(budget.summary(&target));
+        }
+        lines
+    }
+}
+
+impl TargetBudget {
+    fn summary(&self, target: &str) -> Option<String> {
+        match self.suppressed {
+            0 => None,
+            n => Some(format!("{n} messages from \"{target}\" were suppressed")),
+        }
+    }
+}
+
+impl LastMessage {
+    fn summary(&self) -> Option<String> {
+        match self.repeats {
+            0 => None,
+            1 => Some("last message repeated 1 time".to_string()),
+            n => Some(format!("last message repeated {n} times")),
+        }
+    }
+}
+
+fn invalid_option(name: &str, value: f64) -> Error {
+    let msg = format!("{value} isn't a valid value for \"{name}\"");
+    Error::js(js_sys::TypeError::new(&msg))
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const LOGGER_OPTIONS_TYPE_DECLARATION: &str = r#"
+/**
+ * Options for taming noisy log output, used by {@link initializeLogger}.
+ */
+export type LoggerOptions = {
+    /**
+     * Collapse identical messages logged within this many milliseconds of
+     * each other into a single "last message repeated N times" line.
+     */
+    dedupeWindowMs?: number;
+    /**
+     * The most messages a single module may log each second. Anything over
+     * the limit is dropped, and a summary of how many messages were
+     * suppressed is logged once the second is up.
+     */
+    maxMessagesPerSecond?: number;
+};
+"#;
+
+#[wasm_bindgen(typescript_custom_section)]
+const LOG_TARGET_TYPE_DECLARATION: &str = r#"
+/**
+ * A tracing target (usually a Rust module path) returned by
+ * {@link getLogTargets}.
+ */
+export type LogTarget = {
+    /** The target, as used in a filter string (e.g. `"wasmer_wasix::fs"`). */
+    target: string;
+    /** The most verbose level the current filter lets this target log at. */
+    level: "off" | "error" | "warn" | "info" | "debug" | "trace";
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "LogTarget[]")]
+    pub type ListOfLogTargets;
+
+    #[wasm_bindgen(typescript_type = "LoggerOptions")]
+    pub type LoggerOptions;
+
+    #[wasm_bindgen(method, getter, js_name = "dedupeWindowMs")]
+    fn dedupe_window_ms(this: &LoggerOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "maxMessagesPerSecond")]
+    fn max_messages_per_second(this: &LoggerOptions) -> Option<f64>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn collapse_repeated_messages() {
+        let mut throttle = LogThrottle {
+            dedupe_window: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(throttle.process("a", "spam".into(), ms(0)), ["spam"]);
+        assert!(throttle.process("a", "spam".into(), ms(10)).is_empty());
+        assert!(throttle.process("a", "spam".into(), ms(20)).is_empty());
+        assert_eq!(
+            throttle.process("a", "done".into(), ms(30)),
+            ["last message repeated 2 times", "done"]
+        );
+        // Outside the window, the message is printed again
+        assert_eq!(throttle.process("a", "done".into(), ms(5000)), ["done"]);
+    }
+
+    #[wasm_bindgen_test]
+    fn most_specific_directive_wins() {
+        let filter = "warn,wasmer_wasix=info,wasmer_wasix::fs=trace,wasmer_js[run]=debug";
+
+        assert_eq!(
+            effective_level(filter, "wasmer_js"),
+            LevelFilter::WARN.min(max_level())
+        );
+        assert_eq!(
+            effective_level(filter, "wasmer_wasix"),
+            LevelFilter::INFO.min(max_level())
+        );
+        assert_eq!(
+            effective_level(filter, "wasmer_wasix::fs::mem"),
+            LevelFilter::TRACE.min(max_level())
+        );
+        // "wasmer_wasix" isn't a module path prefix of "wasmer_wasix_types"
+        assert_eq!(
+            effective_level(filter, "wasmer_wasix_types"),
+            LevelFilter::WARN.min(max_level())
+        );
+        // Without a global directive, unmatched targets are turned off
+        assert_eq!(
+            effective_level("wasmer_js=debug", "virtual_fs"),
+            LevelFilter::OFF
+        );
+        assert_eq!(effective_level("off", "virtual_fs"), LevelFilter::OFF);
+    }
+
+    #[wasm_bindgen_test]
+    fn rate_limit_each_target() {
+        let mut throttle = LogThrottle {
+            max_per_second: Some(2),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(throttle.process("a", "1".into(), ms(0)), ["1"]);
+        assert_eq!(throttle.process("a", "2".into(), ms(1)), ["2"]);
+        assert!(throttle.process("a", "3".into(), ms(2)).is_empty());
+        assert!(throttle.process("a", "4".into(), ms(3)).is_empty());
+        // Other targets have their own budget
+        assert_eq!(throttle.process("b", "5".into(), ms(4)), ["5"]);
+        assert_eq!(
+            throttle.process("a", "6".into(), ms(1500)),
+            ["2 messages from \"a\" were suppressed", "6"]
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn summaries_are_printed_when_the_flood_stops() {
+        let mut throttle = LogThrottle {
+            dedupe_window: Some(Duration::from_secs(1)),
+            max_per_second: Some(1),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(throttle.process("b", "1".into(), ms(0)), ["1"]);
+        assert!(throttle.process("b", "2".into(), ms(10)).is_empty());
+        assert_eq!(throttle.process("a", "spam".into(), ms(20)), ["spam"]);
+        assert!(throttle.process("a", "spam".into(), ms(30)).is_empty());
+        // Nothing else gets logged, so the summaries are all that's left
+        assert_eq!(throttle.next_deadline(), Some(ms(1000)));
+        assert!(throttle.expire(ms(500)).is_empty());
+
+        assert_eq!(
+            throttle.expire(ms(1000)),
+            ["1 messages from \"b\" were suppressed"]
+        );
+        assert_eq!(throttle.next_deadline(), Some(ms(1030)));
+        assert_eq!(throttle.expire(ms(1030)), ["last message repeated 1 time"]);
+        assert_eq!(throttle.next_deadline(), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn flushing_prints_every_outstanding_summary() {
+        let mut throttle = LogThrottle {
+            dedupe_window: Some(Duration::from_secs(1)),
+            max_per_second: Some(1),
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        throttle.process("b", "1".into(), now);
+        throttle.process("b", "2".into(), now);
+        throttle.process("a", "spam".into(), now);
+        throttle.process("a", "spam".into(), now);
+
+        assert_eq!(
+            throttle.flush(),
+            [
+                "last message repeated 1 time",
+                "1 messages from \"b\" were suppressed"
+            ]
+        );
+        assert!(throttle.flush().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasmer_wasix::bin_factory::BinaryPackage;
+use webc::metadata::{annotations::Wasi, Command};
+
+use crate::utils::Error;
+
+/// The parts of a package's manifest that are useful to JavaScript.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackageManifest {
+    name: String,
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entrypoint: Option<String>,
+    commands: BTreeMap<String, CommandManifest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandManifest {
+    runner: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<Vec<String>>,
+    #[serde(skip)]
+    annotations: JsValue,
+}
+
+impl PackageManifest {
+    pub(crate) fn from_package(pkg: &BinaryPackage) -> Result<Self, Error> {
+        PackageManifest::new(
+            &pkg.package_name,
+            &pkg.version.to_string(),
+            pkg.entrypoint_cmd.as_deref(),
+            pkg.commands.iter().map(|cmd| (cmd.name(), cmd.metadata())),
+        )
+    }
+
+    fn new<'a>(
+        name: &str,
+        version: &str,
+        entrypoint: Option<&str>,
+        commands: impl IntoIterator<Item = (&'a str, &'a Command)>,
+    ) -> Result<Self, Error> {
+        let commands = commands
+            .into_iter()
+            .map(|(name, cmd)| Ok((name.to_string(), CommandManifest::from_metadata(cmd)?)))
+            .collect::<Result<_, Error>>()?;
+
+        Ok(PackageManifest {
+            name: name.to_string(),
+            version: version.to_string(),
+            entrypoint: entrypoint.map(String::from),
+            commands,
+        })
+    }
+
+    /// Convert the manifest to a plain JavaScript object.
+    pub(crate) fn to_js(&self) -> Result<JsValue, Error> {
+        let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+        let value = self.serialize(&serializer).map_err(Error::js)?;
+
+        // Note: Annotations are arbitrary CBOR, so they get attached as-is
+        // rather than going through the typed struct.
+        let commands =
+            js_sys::Reflect::get(&value, &JsValue::from_str("commands")).map_err(Error::js)?;
+        for (name, cmd) in &self.commands {
+            let target =
+                js_sys::Reflect::get(&commands, &JsValue::from_str(name)).map_err(Error::js)?;
+            js_sys::Reflect::set(&target, &JsValue::from_str("annotations"), &cmd.annotations)
+                .map_err(Error::js)?;
+        }
+
+        Ok(value)
+    }
+}
+
+impl CommandManifest {
+    fn from_metadata(metadata: &Command) -> Result<Self, Error> {
+        let wasi: Option<Wasi> = metadata.annotation(Wasi::KEY).ok().flatten();
+
+        let annotations = metadata
+            .annotations
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::js)?;
+        let description = js_sys::Reflect::get(&annotations, &JsValue::from_str("description"))
+            .ok()
+            .and_then(|value| value.as_string());
+
+        Ok(CommandManifest {
+            runner: metadata.runner.clone(),
+            description,
+            main_args: wasi.as_ref().and_then(|wasi| wasi.main_args.clone()),
+            env: wasi.and_then(|wasi| wasi.env),
+            annotations,
+        })
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PACKAGE_MANIFEST_TYPE_DECLARATION: &str = r#"
+/**
+ * Metadata from a package's manifest.
+ */
+export type PackageManifest = {
+    /** The package's name (e.g. `"wasmer/python"`). */
+    name: string;
+    version: string;
+    /** The command that gets run when no other command is specified. */
+    entrypoint?: string;
+    commands: Record<string, CommandManifest>;
+};
+
+/**
+ * Metadata about a single command in a {@link PackageManifest}.
+ */
+export type CommandManifest = {
+    /** The URI of the runner used to execute this command. */
+    runner: string;
+    /** A human-readable description, if the package author provided one. */
+    description?: string;
+    /** Arguments that are always passed to the command, before any others. */
+    mainArgs?: string[];
+    /** Environment variables (as `KEY=value`) set for the command. */
+    env?: string[];
+    /** The command's raw annotations. */
+    annotations: Record<string, unknown>;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "PackageManifest")]
+    pub type JsPackageManifest;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    fn command(runner: &str, annotations: &[(&str, JsValue)]) -> Command {
+        let object = js_sys::Object::new();
+        for (key, value) in annotations {
+            js_sys::Reflect::set(&object, &JsValue::from_str(key), value).unwrap();
+        }
+        let command = js_sys::Object::new();
+        js_sys::Reflect::set(&command, &"runner".into(), &runner.into()).unwrap();
+        js_sys::Reflect::set(&command, &"annotations".into(), &object).unwrap();
+
+        serde_wasm_bindgen::from_value(command.into()).unwrap()
+    }
+
+    fn get(value: &JsValue, path: &[&str]) -> JsValue {
+        path.iter().fold(value.clone(), |value, key| {
+            js_sys::Reflect::get(&value, &JsValue::from_str(key)).unwrap()
+        })
+    }
+
+    #[wasm_bindgen_test]
+    fn manifests_include_each_commands_metadata() {
+        let mut wasi = Wasi::new("python");
+        wasi.main_args = Some(vec!["-B".to_string()]);
+        wasi.env = Some(vec!["PYTHONHOME=/usr".to_string()]);
+        let wasi = wasi
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .unwrap();
+        let python = command(
+            "https://webc.org/runner/wasi",
+            &[
+                (Wasi::KEY, wasi),
+                ("description", "The Python interpreter".into()),
+            ],
+        );
+        let pip = command("https://webc.org/runner/wasi", &[]);
+
+        let manifest = PackageManifest::new(
+            "wasmer/python",
+            "3.12.0",
+            Some("python"),
+            [("python", &python), ("pip", &pip)],
+        )
+        .unwrap()
+        .to_js()
+        .unwrap();
+
+        assert_eq!(get(&manifest, &["name"]), "wasmer/python");
+        assert_eq!(get(&manifest, &["version"]), "3.12.0");
+        assert_eq!(get(&manifest, &["entrypoint"]), "python");
+        assert_eq!(
+            get(&manifest, &["commands", "python", "runner"]),
+            "https://webc.org/runner/wasi"
+        );
+        assert_eq!(
+            get(&manifest, &["commands", "python", "description"]),
+            "The Python interpreter"
+        );
+        assert_eq!(
+            get(&manifest, &["commands", "python", "mainArgs", "0"]),
+            "-B"
+        );
+        assert_eq!(
+            get(&manifest, &["commands", "python", "env", "0"]),
+            "PYTHONHOME=/usr"
+        );
+        // The raw annotations are passed through untouched
+        assert_eq!(
+            get(
+                &manifest,
+                &["commands", "python", "annotations", "wasi", "atom"]
+            ),
+            "python"
+        );
+
+        // Missing metadata is left out rather than set to null
+        let pip = get(&manifest, &["commands", "pip"]);
+        assert!(get(&pip, &["description"]).is_undefined());
+        assert!(get(&pip, &["mainArgs"]).is_undefined());
+        assert!(get(&pip, &["env"]).is_undefined());
+    }
+
+    #[wasm_bindgen_test]
+    fn the_entrypoint_is_optional() {
+        let manifest = PackageManifest::new("wasmer/tools", "1.0.0", None, [])
+            .unwrap()
+            .to_js()
+            .unwrap();
+
+        assert!(get(&manifest, &["entrypoint"]).is_undefined());
+        assert_eq!(
+            js_sys::Object::keys(get(&manifest, &["commands"]).unchecked_ref()).length(),
+            0
+        );
+    }
+}
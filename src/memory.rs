@@ -0,0 +1,291 @@
+//! Inspecting a running (or crashed) program's linear memory from
+//! JavaScript.
+//!
+//! The program runs on a worker, so its `WebAssembly.Memory` gets posted back
+//! to the scheduler's thread once it has been instantiated. Shared memories
+//! can be read and written while the program is still running. Anything else
+//! can't be shared between threads, so we copy it when the program exits
+//! and only that copy can be inspected.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use js_sys::{ArrayBuffer, SharedArrayBuffer, Uint8Array, WebAssembly};
+use serde::Deserialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+use crate::{
+    tasks::{SchedulerMessage, ThreadPool},
+    utils::Error,
+};
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    /// Memories posted back from the workers running each program.
+    static MEMORIES: RefCell<HashMap<u32, Contents>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+enum Contents {
+    /// The program's memory, which is shared with the worker running it.
+    Live(WebAssembly::Memory),
+    /// A copy of the program's memory, taken when it exited.
+    Snapshot(Uint8Array),
+}
+
+impl Contents {
+    fn bytes(&self) -> Uint8Array {
+        match self {
+            // Note: the buffer is replaced whenever the memory grows, so we
+            // need to look it up every time.
+            Contents::Live(memory) => Uint8Array::new(&memory.buffer()),
+            Contents::Snapshot(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// Store a memory sent by [`MemoryReporter`].
+///
+/// This must be called on the scheduler's thread.
+pub(crate) fn attached(id: u32, memory: JsValue) {
+    let contents = match memory.dyn_into::<WebAssembly::Memory>() {
+        Ok(memory) => Contents::Live(memory),
+        Err(buffer) => Contents::Snapshot(Uint8Array::new(&buffer)),
+    };
+
+    MEMORIES.with(|m| m.borrow_mut().insert(id, contents));
+}
+
+/// The worker side of a [`GuestMemory`], used to send the program's memory
+/// back to the scheduler's thread.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryReporter {
+    id: u32,
+    pool: ThreadPool,
+}
+
+impl MemoryReporter {
+    /// Called once the program has been instantiated.
+    pub(crate) fn instantiated(&self, memory: &JsValue) {
+        let Some(memory) = memory.dyn_ref::<WebAssembly::Memory>() else {
+            return;
+        };
+
+        if memory.buffer().is_instance_of::<SharedArrayBuffer>() {
+            self.pool.send(SchedulerMessage::AttachMemory {
+                id: self.id,
+                memory: memory.clone().into(),
+            });
+        }
+    }
+
+    /// Called once the program has exited, but before its memory is freed.
+    pub(crate) fn exited(&self, memory: &JsValue) {
+        let Some(memory) = memory.dyn_ref::<WebAssembly::Memory>() else {
+            return;
+        };
+
+        let buffer = memory.buffer();
+        if buffer.is_instance_of::<SharedArrayBuffer>() {
+            // The scheduler already has a live copy
+            return;
+        }
+
+        let snapshot = buffer.unchecked_into::<ArrayBuffer>().slice(0);
+        self.pool.send(SchedulerMessage::AttachMemory {
+            id: self.id,
+            memory: snapshot.into(),
+        });
+    }
+}
+
+/// A program's linear memory.
+///
+/// Memory is only available while the program is running if it uses shared
+/// memory (i.e. it was compiled with threads). Otherwise, a copy is made
+/// when the program exits so it can still be inspected afterwards.
+///
+/// This must be used on the thread the program's {@link Runtime} was
+/// created on.
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct GuestMemory(Rc<Handle>);
+
+#[derive(Debug)]
+struct Handle(u32);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        MEMORIES.with(|m| m.borrow_mut().remove(&self.0));
+    }
+}
+
+impl GuestMemory {
+    pub(crate) fn new(pool: ThreadPool) -> (GuestMemory, MemoryReporter) {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        (
+            GuestMemory(Rc::new(Handle(id))),
+            MemoryReporter { id, pool },
+        )
+    }
+
+    fn contents(&self) -> Result<Contents, Error> {
+        MEMORIES
+            .with(|m| m.borrow().get(&self.0 .0).cloned())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "The program's memory isn't available yet. Programs that don't use shared memory can only be inspected after they exit."
+                )
+                .into()
+            })
+    }
+}
+
+#[wasm_bindgen]
+impl GuestMemory {
+    /// Has the program's memory been received yet?
+    #[wasm_bindgen(getter, js_name = "isAvailable")]
+    pub fn is_available(&self) -> bool {
+        self.contents().is_ok()
+    }
+
+    /// The size of the program's memory, in bytes.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> Result<f64, Error> {
+        Ok(self.contents()?.bytes().length() as f64)
+    }
+
+    /// Copy `length` bytes out of the program's memory, starting at
+    /// `offset`.
+    pub fn read(&self, offset: u32, length: u32) -> Result<Uint8Array, Error> {
+        let bytes = self.contents()?.bytes();
+        let end = check_range(offset, length, bytes.length())?;
+        Ok(bytes.slice(offset, end))
+    }
+
+    /// Overwrite part of the program's memory, starting at `offset`.
+    ///
+    /// Only memory of a running program can be written to.
+    pub fn write(&self, offset: u32, data: Uint8Array) -> Result<(), Error> {
+        let memory = match self.contents()? {
+            Contents::Live(memory) => memory,
+            Contents::Snapshot(_) => {
+                return Err(anyhow::anyhow!(
+                    "The program has exited, so its memory can't be written to"
+                )
+                .into())
+            }
+        };
+
+        let bytes = Uint8Array::new(&memory.buffer());
+        check_range(offset, data.length(), bytes.length())?;
+        bytes.set(&data, offset);
+        Ok(())
+    }
+
+    /// Copy the program's entire memory into a `Blob`, for post-mortem
+    /// analysis.
+    pub fn dump(&self, options: Option<DumpMemoryOptions>) -> Result<web_sys::Blob, Error> {
+        let bytes = self.contents()?.bytes();
+        // Note: Blobs can't be created from shared memory, so we always copy
+        let copy = bytes.slice(0, bytes.length());
+
+        if let Some(ranges) = options.and_then(|opts| opts.redact()) {
+            let ranges: Vec<Range> =
+                serde_wasm_bindgen::from_value(ranges.into()).map_err(Error::js)?;
+            redact(&copy, &ranges)?;
+        }
+
+        web_sys::Blob::new_with_u8_array_sequence_and_options(
+            js_sys::Array::of1(&copy).as_ref(),
+            web_sys::BlobPropertyBag::new().type_("application/octet-stream"),
+        )
+        .map_err(Error::js)
+    }
+}
+
+/// Make sure `offset..offset+length` is inside a memory of `size` bytes,
+/// returning the end of the range.
+fn check_range(offset: u32, length: u32, size: u32) -> Result<u32, Error> {
+    match offset.checked_add(length) {
+        Some(end) if end <= size => Ok(end),
+        _ => {
+            let msg = format!(
+                "Unable to access {length} bytes at offset {offset} in a memory of {size} bytes"
+            );
+            Err(Error::js(js_sys::RangeError::new(&msg)))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+struct Range {
+    offset: u32,
+    length: u32,
+}
+
+/// Zero out parts of a memory dump.
+fn redact(bytes: &Uint8Array, ranges: &[Range]) -> Result<(), Error> {
+    for range in ranges {
+        let end = check_range(range.offset, range.length, bytes.length())?;
+        bytes.fill(0, range.offset, end);
+    }
+
+    Ok(())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const DUMP_MEMORY_OPTIONS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Options for {@link GuestMemory.dump}.
+ */
+export type DumpMemoryOptions = {
+    /**
+     * Parts of memory that should be zeroed out in the dump (e.g. because
+     * they contain secrets).
+     */
+    redact?: { offset: number; length: number }[];
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "DumpMemoryOptions")]
+    pub type DumpMemoryOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn redact(this: &DumpMemoryOptions) -> Option<js_sys::Array>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn redact_ranges_of_a_dump() {
+        let bytes = Uint8Array::from([1_u8; 8].as_slice());
+        let ranges = [
+            Range {
+                offset: 1,
+                length: 2,
+            },
+            Range {
+                offset: 6,
+                length: 2,
+            },
+        ];
+
+        redact(&bytes, &ranges).unwrap();
+
+        assert_eq!(bytes.to_vec(), [1, 0, 0, 1, 1, 1, 0, 0]);
+        assert!(check_range(4, 5, 8).is_err());
+        assert!(check_range(u32::MAX, 1, 8).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Noticing when the page is running out of memory and giving some back
+//! before the browser kills the tab.
+//!
+//! Browsers don't tell pages when memory is tight, so we periodically
+//! measure how much the page is using, with
+//! `performance.measureUserAgentSpecificMemory()` when the page is
+//! cross-origin isolated and Chrome's `performance.memory` otherwise, and
+//! compare that against the heap limit. Once usage becomes critical, the
+//! runtime drops its caches, terminates idle workers and (optionally) sends
+//! programs a signal so they can trim their own heaps.
+
+use std::sync::Weak;
+
+use instant::Duration;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer_wasix::types::wasi::Signal;
+
+use crate::{
+    events::RuntimeEvent,
+    runtime::Runtime,
+    utils::{Error, GlobalScope},
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// The fraction of the limit at which memory use counts as moderate.
+const MODERATE: f64 = 0.7;
+/// The fraction of the limit at which memory use counts as critical.
+const CRITICAL: f64 = 0.9;
+
+/// How close the page is to its memory limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PressureLevel {
+    Normal,
+    Moderate,
+    Critical,
+}
+
+/// The parsed `memoryPressure` option.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PressureOptions {
+    interval: Duration,
+    /// The number of bytes the page may use, if not the browser's JS heap
+    /// limit.
+    limit: Option<u64>,
+    /// Sent to every running program when memory use becomes critical.
+    signal: Option<Signal>,
+}
+
+impl PressureOptions {
+    /// Parse the `memoryPressure` option, returning `None` if monitoring is
+    /// disabled.
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Self>, Error> {
+        if let Some(enabled) = value.as_bool() {
+            return Ok(enabled.then(|| PressureOptions {
+                interval: DEFAULT_INTERVAL,
+                limit: None,
+                signal: None,
+            }));
+        }
+
+        #[derive(Deserialize)]
+        struct Init {
+            interval: Option<f64>,
+            limit: Option<f64>,
+        }
+
+        let signal =
+            js_sys::Reflect::get(&value, &JsValue::from_str("signal")).map_err(Error::js)?;
+        let signal = if signal.is_undefined() {
+            None
+        } else {
+            Some(crate::processes::parse_signal(Some(&signal))?)
+        };
+        let Init { interval, limit } = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+
+        let positive = |name: &str, value: f64| {
+            if value.is_finite() && value > 0.0 {
+                Ok(value)
+            } else {
+                let msg = format!("The memory pressure {name} must be positive, not {value}");
+                Err(Error::js(js_sys::RangeError::new(&msg)))
+            }
+        };
+
+        let interval = match interval {
+            Some(ms) => Duration::from_millis(positive("interval", ms)? as u64),
+            None => DEFAULT_INTERVAL,
+        };
+        let limit = match limit {
+            Some(bytes) => Some(positive("limit", bytes)? as u64),
+            None => None,
+        };
+
+        Ok(Some(PressureOptions {
+            interval,
+            limit,
+            signal,
+        }))
+    }
+}
+
+/// How much memory the page is using.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Reading {
+    used: u64,
+    limit: u64,
+}
+
+impl Reading {
+    fn level(&self) -> PressureLevel {
+        let ratio = self.used as f64 / self.limit as f64;
+
+        if ratio >= CRITICAL {
+            PressureLevel::Critical
+        } else if ratio >= MODERATE {
+            PressureLevel::Moderate
+        } else {
+            PressureLevel::Normal
+        }
+    }
+}
+
+/// Why memory use couldn't be measured.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum MeasureError {
+    /// The browser won't tell us how much memory is used (or we don't know
+    /// the limit to compare it against), so there's no point asking again.
+    Unsupported,
+    /// This measurement failed (e.g. because the page was in the background)
+    /// but the next one might not.
+    Failed,
+}
+
+/// Measure the page's memory use.
+async fn measure(limit: Option<u64>) -> Result<Reading, MeasureError> {
+    let global = js_sys::global();
+    let performance = js_sys::Reflect::get(&global, &"performance".into())
+        .map_err(|_| MeasureError::Unsupported)?;
+    let isolated = js_sys::Reflect::get(&global, &"crossOriginIsolated".into())
+        .map(|v| v.is_truthy())
+        .unwrap_or(false);
+
+    measure_with(&performance, isolated, limit).await
+}
+
+async fn measure_with(
+    performance: &JsValue,
+    isolated: bool,
+    limit: Option<u64>,
+) -> Result<Reading, MeasureError> {
+    if !performance.is_object() {
+        return Err(MeasureError::Unsupported);
+    }
+
+    let get = |target: &JsValue, key: &str| -> Option<f64> {
+        js_sys::Reflect::get(target, &key.into()).ok()?.as_f64()
+    };
+
+    // Note: performance.memory is non-standard and only covers the JS
+    // heap, but it is the only place to get a limit from
+    let heap = js_sys::Reflect::get(performance, &"memory".into())
+        .ok()
+        .filter(|m| m.is_object());
+    let heap_limit = heap.as_ref().and_then(|m| get(m, "jsHeapSizeLimit"));
+    let limit = limit
+        .or_else(|| heap_limit.map(|l| l as u64))
+        .ok_or(MeasureError::Unsupported)?;
+
+    let measure_memory =
+        js_sys::Reflect::get(performance, &"measureUserAgentSpecificMemory".into())
+            .ok()
+            .and_then(|f| f.dyn_into::<js_sys::Function>().ok());
+
+    let used = match measure_memory {
+        Some(measure_memory) if isolated => {
+            let promise = measure_memory
+                .call0(performance)
+                .map_err(|_| MeasureError::Failed)?;
+            let result = JsFuture::from(js_sys::Promise::resolve(&promise))
+                .await
+                .map_err(|_| MeasureError::Failed)?;
+            get(&result, "bytes").ok_or(MeasureError::Failed)?
+        }
+        _ => {
+            let heap = heap.as_ref().ok_or(MeasureError::Unsupported)?;
+            get(heap, "usedJSHeapSize").ok_or(MeasureError::Failed)?
+        }
+    };
+
+    Ok(Reading {
+        used: used as u64,
+        limit,
+    })
+}
+
+/// Keep an eye on memory use for as long as the runtime is alive.
+pub(crate) fn watch(runtime: Weak<Runtime>, options: PressureOptions) {
+    wasm_bindgen_futures::spawn_local(async move {
+        let mut level = PressureLevel::Normal;
+        let ms = i32::try_from(options.interval.as_millis()).unwrap_or(i32::MAX);
+
+        loop {
+            let _ = JsFuture::from(GlobalScope::current().sleep(ms)).await;
+
+            let reading = match measure(options.limit).await {
+                Ok(reading) => reading,
+                Err(MeasureError::Failed) => {
+                    tracing::debug!("Unable to measure memory use, trying again later");
+                    continue;
+                }
+                Err(MeasureError::Unsupported) => {
+                    tracing::debug!(
+                        "Unable to measure memory use, so memory pressure won't be tracked"
+                    );
+                    return;
+                }
+            };
+            let Some(rt) = runtime.upgrade() else {
+                return;
+            };
+
+            let new_level = reading.level();
+            if new_level == level {
+                continue;
+            }
+            level = new_level;
+
+            let shed = level == PressureLevel::Critical;
+            tracing::info!(
+                ?level,
+                used = reading.used,
+                limit = reading.limit,
+                "Memory pressure changed"
+            );
+            if shed {
+                shed_memory(&rt, options.signal);
+            }
+
+            rt.thread_pool().emit(RuntimeEvent::MemoryPressure {
+                level,
+                used_bytes: reading.used,
+                limit_bytes: reading.limit,
+                shed,
+            });
+        }
+    });
+}
+
+/// Give back as much memory as we can without disturbing running programs.
+fn shed_memory(runtime: &Runtime, signal: Option<Signal>) {
+    runtime.shed_memory();
+
+    if let Some(signal) = signal {
+        let processes = runtime.processes();
+        for pid in processes.pids() {
+            if let Err(e) = processes.signal(pid, signal) {
+                tracing::warn!(pid, error = &*e, "Unable to tell a program memory is low");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn usage_is_graded_against_the_limit() {
+        let reading = |used| Reading { used, limit: 100 };
+
+        assert_eq!(reading(10).level(), PressureLevel::Normal);
+        assert_eq!(reading(70).level(), PressureLevel::Moderate);
+        assert_eq!(reading(95).level(), PressureLevel::Critical);
+
+        assert_eq!(PressureOptions::parse(false.into()).unwrap(), None);
+        let options = js_sys::JSON::parse(r#"{"interval": 500, "signal": "SIGUSR1"}"#).unwrap();
+        let options = PressureOptions::parse(options).unwrap().unwrap();
+        assert_eq!(options.interval, Duration::from_millis(500));
+        assert_eq!(options.signal, Some(Signal::Sigusr1));
+        let negative = js_sys::JSON::parse(r#"{"limit": -1}"#).unwrap();
+        assert!(PressureOptions::parse(negative).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn only_a_missing_api_stops_the_measurements() {
+        let performance = |json: &str| js_sys::JSON::parse(json).unwrap();
+
+        // Without performance.memory there's no limit to compare against
+        assert_eq!(
+            measure_with(&performance("{}"), false, None).await,
+            Err(MeasureError::Unsupported)
+        );
+        assert_eq!(
+            measure_with(&JsValue::UNDEFINED, false, Some(100)).await,
+            Err(MeasureError::Unsupported)
+        );
+
+        let heap = performance(r#"{"memory": {"jsHeapSizeLimit": 100, "usedJSHeapSize": 95}}"#);
+        assert_eq!(
+            measure_with(&heap, false, None).await,
+            Ok(Reading {
+                used: 95,
+                limit: 100
+            })
+        );
+
+        // measureUserAgentSpecificMemory() rejects while the page is hidden
+        let rejects = js_sys::Function::new_no_args("return Promise.reject(new Error(\"hidden\"))");
+        js_sys::Reflect::set(&heap, &"measureUserAgentSpecificMemory".into(), &rejects).unwrap();
+        assert_eq!(
+            measure_with(&heap, true, None).await,
+            Err(MeasureError::Failed)
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Deterministic instruction counting, by instrumenting a module before it
+//! is compiled.
+//!
+//! Each function body is split into straight-line segments (everything
+//! between two control flow instructions). At the start of each segment we
+//! subtract the number of instructions it contains from a mutable `i64`
+//! global and trap with `unreachable` once it drops below zero. The global is
+//! exported so the host can see how much gas is left when the program exits.
+//!
+//! New code is only ever inserted, and the global is added after every
+//! existing one, so none of the module's indices change and the original
+//! instructions can be copied across byte-for-byte.
+//!
+//! Globals aren't shared between threads, so every thread would start with
+//! a full counter of its own. Modules which can spawn threads are refused
+//! rather than letting them multiply their budget.
+
+use std::ops::Range;
+
+use js_sys::{BigInt, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use wasmer::{AsJs, AsStoreRef};
+use wasmparser::{CodeSectionReader, GlobalSectionReader, ImportSectionReader, Operator, TypeRef};
+
+use crate::utils::Error;
+
+/// The name the gas counter is exported under.
+pub(crate) const GAS_GLOBAL: &str = "__wasmer_gas";
+
+const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+const SECTION_IMPORT: u8 = 2;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+/// Settings for metering a program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct MeteringOptions {
+    /// How many instructions the program may execute before it is stopped,
+    /// or `None` to only count them.
+    pub(crate) gas_limit: Option<u64>,
+}
+
+impl MeteringOptions {
+    /// Parse the `metering` option, which is either a boolean or an object.
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Self>, Error> {
+        if let Some(enabled) = value.as_bool() {
+            return Ok(enabled.then_some(MeteringOptions { gas_limit: None }));
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Init {
+            gas_limit: Option<f64>,
+        }
+
+        let Init { gas_limit } = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+
+        let gas_limit = match gas_limit {
+            Some(limit) if limit.is_finite() && limit >= 0.0 && limit.fract() == 0.0 => {
+                Some(limit.min(i64::MAX as f64) as u64)
+            }
+            Some(limit) => {
+                let msg = format!("The gas limit must be a non-negative integer, not {limit}");
+                return Err(Error::js(js_sys::RangeError::new(&msg)));
+            }
+            None => None,
+        };
+
+        Ok(Some(MeteringOptions { gas_limit }))
+    }
+
+    /// The value the gas counter starts at.
+    pub(crate) fn initial_gas(&self) -> i64 {
+        self.gas_limit
+            .map_or(i64::MAX, |limit| i64::try_from(limit).unwrap_or(i64::MAX))
+    }
+}
+
+/// How much gas a metered program used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GasUsage {
+    pub(crate) instructions: u64,
+    pub(crate) exhausted: bool,
+}
+
+impl GasUsage {
+    /// Work out how much gas was used, given what was left in the counter.
+    pub(crate) fn from_remaining(options: &MeteringOptions, remaining: i64) -> Self {
+        let initial = options.initial_gas();
+        GasUsage {
+            // Note: the segment that ran out of gas was charged but never
+            // executed.
+            instructions: initial.saturating_sub(remaining.max(0)) as u64,
+            exhausted: remaining < 0,
+        }
+    }
+}
+
+/// Read the gas counter of an instrumented module.
+pub(crate) fn remaining_gas(instance: &wasmer::Instance, store: &impl AsStoreRef) -> Option<i64> {
+    let global = instance.exports.get_global(GAS_GLOBAL).ok()?;
+    // Note: i64 globals are exposed to JavaScript as a BigInt
+    let value = Reflect::get(&global.as_jsvalue(store), &JsValue::from_str("value")).ok()?;
+    i64::try_from(value.dyn_into::<BigInt>().ok()?).ok()
+}
+
+/// Add an instruction counter to a WebAssembly module.
+pub(crate) fn instrument(wasm: &[u8], options: &MeteringOptions) -> Result<Vec<u8>, Error> {
+    if !wasm.starts_with(HEADER) {
+        return Err(anyhow::anyhow!("Only WebAssembly modules can be metered").into());
+    }
+
+    let sections = sections(wasm)?;
+
+    let mut imported_globals = 0;
+    let mut defined_globals = 0;
+    for &(id, ref range) in &sections {
+        match id {
+            SECTION_IMPORT => {
+                let reader = ImportSectionReader::new(&wasm[range.clone()], range.start)?;
+                for import in reader {
+                    let import = import?;
+                    match import.ty {
+                        TypeRef::Global(_) => imported_globals += 1,
+                        TypeRef::Func(_) if spawns_threads(import.module, import.name) => {
+                            return Err(anyhow::anyhow!(
+                                "Programs which spawn threads can't be metered, because each \
+                                 thread would get a gas counter of its own (the module imports \
+                                 \"{}.{}\")",
+                                import.module,
+                                import.name,
+                            )
+                            .into());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            SECTION_GLOBAL => {
+                let reader = GlobalSectionReader::new(&wasm[range.clone()], range.start)?;
+                defined_globals = reader.get_count();
+            }
+            _ => {}
+        }
+    }
+    let gas = imported_globals + defined_globals;
+
+    let mut out = HEADER.to_vec();
+    let mut wrote_global = false;
+    let mut wrote_export = false;
+
+    for (id, range) in sections {
+        let content = &wasm[range.clone()];
+
+        if !wrote_global && comes_after(id, SECTION_GLOBAL) {
+            write_section(&mut out, SECTION_GLOBAL, &globals(None, options));
+            wrote_global = true;
+        }
+        if !wrote_export && comes_after(id, SECTION_EXPORT) {
+            write_section(&mut out, SECTION_EXPORT, &exports(None, gas));
+            wrote_export = true;
+        }
+
+        match id {
+            SECTION_GLOBAL => {
+                write_section(&mut out, id, &globals(Some(content), options));
+                wrote_global = true;
+            }
+            SECTION_EXPORT => {
+                write_section(&mut out, id, &exports(Some(content), gas));
+                wrote_export = true;
+            }
+            SECTION_CODE => {
+                let code = instrument_code(wasm, range, gas)?;
+                write_section(&mut out, id, &code);
+            }
+            _ => write_section(&mut out, id, content),
+        }
+    }
+
+    if !wrote_global {
+        write_section(&mut out, SECTION_GLOBAL, &globals(None, options));
+    }
+    if !wrote_export {
+        write_section(&mut out, SECTION_EXPORT, &exports(None, gas));
+    }
+
+    Ok(out)
+}
+
+/// Is this one of the WASI or WASIX functions for starting a thread?
+fn spawns_threads(module: &str, name: &str) -> bool {
+    match module {
+        "wasi" => name == "thread-spawn",
+        _ if module.starts_with("wasix_") => name.starts_with("thread_spawn"),
+        _ => false,
+    }
+}
+
+/// Split a module into `(id, contents)` pairs.
+fn sections(wasm: &[u8]) -> Result<Vec<(u8, Range<usize>)>, Error> {
+    let mut sections = Vec::new();
+    let mut offset = HEADER.len();
+
+    while offset < wasm.len() {
+        let id = wasm[offset];
+        let (size, len) = read_u32(&wasm[offset + 1..])?;
+        let start = offset + 1 + len;
+        let end = match start.checked_add(size as usize) {
+            Some(end) if end <= wasm.len() => end,
+            _ => {
+                return Err(anyhow::anyhow!("Section {id} at offset {offset} is truncated").into())
+            }
+        };
+        sections.push((id, start..end));
+        offset = end;
+    }
+
+    Ok(sections)
+}
+
+/// Would a section with this `id` need to come after the `other` section?
+fn comes_after(id: u8, other: u8) -> bool {
+    /// Where each known section goes, indexed by section ID. Custom sections
+    /// can go anywhere.
+    const ORDER: [u8; 14] = [0, 1, 2, 3, 4, 5, 7, 8, 9, 10, 12, 13, 11, 6];
+
+    match (ORDER.get(id as usize), ORDER.get(other as usize)) {
+        (Some(0), _) | (None, _) | (_, None) => false,
+        (Some(a), Some(b)) => a > b,
+    }
+}
+
+/// The global section with our gas counter appended.
+fn globals(existing: Option<&[u8]>, options: &MeteringOptions) -> Vec<u8> {
+    let (count, entries) = split_vec(existing);
+
+    let mut section = Vec::new();
+    write_u32(&mut section, count + 1);
+    section.extend_from_slice(entries);
+    // (global (mut i64) (i64.const initial))
+    section.extend_from_slice(&[0x7e, 0x01, 0x42]);
+    write_i64(&mut section, options.initial_gas());
+    section.push(0x0b);
+    section
+}
+
+/// The export section with our gas counter appended.
+fn exports(existing: Option<&[u8]>, gas: u32) -> Vec<u8> {
+    let (count, entries) = split_vec(existing);
+
+    let mut section = Vec::new();
+    write_u32(&mut section, count + 1);
+    section.extend_from_slice(entries);
+    write_u32(&mut section, GAS_GLOBAL.len() as u32);
+    section.extend_from_slice(GAS_GLOBAL.as_bytes());
+    section.push(0x03);
+    write_u32(&mut section, gas);
+    section
+}
+
+/// Split a section's contents into the number of items and their raw bytes.
+fn split_vec(contents: Option<&[u8]>) -> (u32, &[u8]) {
+    match contents {
+        Some(contents) => match read_u32(contents) {
+            Ok((count, len)) => (count, &contents[len..]),
+            Err(_) => (0, &[]),
+        },
+        None => (0, &[]),
+    }
+}
+
+fn instrument_code(wasm: &[u8], range: Range<usize>, gas: u32) -> Result<Vec<u8>, Error> {
+    let reader = CodeSectionReader::new(&wasm[range.clone()], range.start)?;
+
+    let mut section = Vec::new();
+    write_u32(&mut section, reader.get_count());
+
+    for body in reader {
+        let body = body?;
+        let body_range = body.range();
+
+        let mut locals = body.get_locals_reader()?;
+        for _ in 0..locals.get_count() {
+            locals.read()?;
+        }
+        let code_start = locals.original_position();
+
+        let mut operators = body.get_operators_reader()?;
+        let mut offsets = Vec::new();
+        let mut ends_segment = Vec::new();
+        while !operators.eof() {
+            let (op, offset) = operators.read_with_offset()?;
+            offsets.push(offset);
+            ends_segment.push(is_control_flow(&op));
+        }
+        offsets.push(body_range.end);
+
+        let mut instrumented = wasm[body_range.start..code_start].to_vec();
+        let mut segment_start = 0;
+        for i in 0..ends_segment.len() {
+            let last = i + 1 == ends_segment.len();
+            if ends_segment[i] || last {
+                let cost = (i + 1 - segment_start) as i64;
+                charge(&mut instrumented, gas, cost);
+                instrumented.extend_from_slice(&wasm[offsets[segment_start]..offsets[i + 1]]);
+                segment_start = i + 1;
+            }
+        }
+
+        write_u32(&mut section, instrumented.len() as u32);
+        section.extend_from_slice(&instrumented);
+    }
+
+    Ok(section)
+}
+
+/// Does this instruction change where execution goes next?
+fn is_control_flow(op: &Operator<'_>) -> bool {
+    matches!(
+        op,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::Unreachable
+            | Operator::Try { .. }
+            | Operator::Catch { .. }
+            | Operator::CatchAll
+            | Operator::Delegate { .. }
+            | Operator::Throw { .. }
+            | Operator::Rethrow { .. }
+    )
+}
+
+/// Subtract `cost` from the gas counter, trapping if it goes negative.
+fn charge(code: &mut Vec<u8>, gas: u32, cost: i64) {
+    // global.get $gas; i64.const cost; i64.sub; global.set $gas
+    code.push(0x23);
+    write_u32(code, gas);
+    code.push(0x42);
+    write_i64(code, cost);
+    code.push(0x7d);
+    code.push(0x24);
+    write_u32(code, gas);
+    // global.get $gas; i64.const 0; i64.lt_s; if; unreachable; end
+    code.push(0x23);
+    write_u32(code, gas);
+    code.extend_from_slice(&[0x42, 0x00, 0x53, 0x04, 0x40, 0x00, 0x0b]);
+}
+
+fn read_u32(bytes: &[u8]) -> Result<(u32, usize), Error> {
+    let mut value = 0_u32;
+    for (i, byte) in bytes.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+
+    Err(anyhow::anyhow!("Invalid LEB128 integer").into())
+}
+
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_i64(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    write_u32(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn instrumented_modules_still_validate() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (global $g (mut i32) (i32.const 0))
+                (func (export "_start") (local i32)
+                    (loop $top
+                        (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+                        (br_if $top (i32.lt_u (local.get 0) (i32.const 10))))))"#,
+        )
+        .unwrap();
+        let options = MeteringOptions {
+            gas_limit: Some(1000),
+        };
+
+        let instrumented = instrument(&wasm, &options).unwrap();
+
+        let bytes = js_sys::Uint8Array::from(instrumented.as_slice());
+        assert!(js_sys::WebAssembly::validate(&bytes).unwrap());
+        assert!(instrumented.len() > wasm.len());
+    }
+
+    #[wasm_bindgen_test]
+    fn modules_which_spawn_threads_are_refused() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasix_32v1" "thread_spawn_v2" (func (param i32 i32) (result i32)))
+                (func (export "_start")))"#,
+        )
+        .unwrap();
+        let options = MeteringOptions { gas_limit: None };
+
+        let err = instrument(&wasm, &options).unwrap_err();
+
+        assert!(err.to_string().contains("thread_spawn_v2"), "{err}");
+    }
+
+    #[wasm_bindgen_test]
+    fn leb128() {
+        let mut out = Vec::new();
+        write_u32(&mut out, 624485);
+        assert_eq!(out, [0xe5, 0x8e, 0x26]);
+        assert_eq!(read_u32(&out).unwrap(), (624485, 3));
+
+        let mut out = Vec::new();
+        write_i64(&mut out, -123456);
+        assert_eq!(out, [0xc0, 0xbb, 0x78]);
+
+        let options = MeteringOptions {
+            gas_limit: Some(100),
+        };
+        assert_eq!(
+            GasUsage::from_remaining(&options, -3),
+            GasUsage {
+                instructions: 100,
+                exhausted: true
+            }
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn oversized_sections_are_rejected() {
+        let mut wasm = HEADER.to_vec();
+        // A custom section claiming to be 4 GiB long
+        wasm.extend_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+
+        let err = sections(&wasm).unwrap_err();
+
+        assert!(err.to_string().contains("truncated"), "{err}");
+    }
+}
@@ -0,0 +1,553 @@
+//! A module cache which keeps track of what it contains, so hosts can see
+//! which modules are being reused and protect important ones from eviction.
+//!
+//! Compilations are also deduplicated, so when several instances need the
+//! same module at once only the first compiles it and the rest wait for its
+//! result.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::channel::oneshot;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasmer::{Engine, Module};
+use wasmer_wasix::runtime::module_cache::{CacheError, ModuleCache, ModuleHash};
+
+use crate::utils::Error;
+
+/// A [`ModuleCache`] that records metadata about every module it saves.
+///
+/// The compiled modules themselves live in [`MODULES`] because a
+/// `WebAssembly.Module` can't be shared between workers without
+/// `postMessage()`.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackedCache {
+    /// Identifies this cache's modules in [`MODULES`].
+    id: u64,
+    entries: Arc<Mutex<BTreeMap<ModuleHash, Entry>>>,
+    /// Bumped whenever modules are evicted, so each thread knows to drop its
+    /// copies of them.
+    evictions: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+}
+
+impl Default for TrackedCache {
+    fn default() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        TrackedCache {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            entries: Arc::default(),
+            evictions: Arc::default(),
+            stats: Arc::default(),
+        }
+    }
+}
+
+thread_local! {
+    /// The modules each [`TrackedCache`] has compiled on this thread, keyed
+    /// by the cache's ID.
+    static MODULES: RefCell<HashMap<u64, ThreadModules>> = RefCell::default();
+}
+
+#[derive(Debug, Default)]
+struct ThreadModules {
+    /// The cache's eviction count when these modules were last checked.
+    evictions: u64,
+    /// Modules keyed by their hash and the engine's ID.
+    modules: HashMap<(ModuleHash, String), Module>,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    compilations: AtomicU64,
+    /// Requests that were satisfied by waiting on a compilation which was
+    /// already in progress.
+    dedup_hits: AtomicU64,
+}
+
+/// How a compilation went, as reported to anyone waiting on it.
+type CompileResult = Result<(), String>;
+
+/// Compilations in progress on any thread, and who is waiting on them.
+///
+/// A [`Module`] can't be shared between workers without `postMessage()`, so
+/// waiters are only told when the compilation finishes. The compiled module
+/// reaches their thread's cache when it is saved.
+static IN_FLIGHT: Lazy<Mutex<HashMap<ModuleHash, Vec<oneshot::Sender<CompileResult>>>>> =
+    Lazy::new(Mutex::default);
+
+/// Removes a compilation from [`IN_FLIGHT`] even if it gets cancelled, so
+/// anyone waiting on it finds out instead of hanging.
+struct InFlight(ModuleHash);
+
+impl InFlight {
+    fn finish(self, result: &anyhow::Result<Module>) {
+        let waiters = IN_FLIGHT
+            .lock()
+            .unwrap()
+            .remove(&self.0)
+            .unwrap_or_default();
+
+        for waiter in waiters {
+            let result = match result {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("{e:?}")),
+            };
+            let _ = waiter.send(result);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        // Note: dropping the senders lets any remaining waiters know the
+        // compilation was abandoned.
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Entry {
+    /// Whether the module is currently in the cache, as opposed to only
+    /// having been described.
+    cached: bool,
+    size: Option<usize>,
+    hits: u64,
+    origin: Option<Origin>,
+    pinned: bool,
+}
+
+/// Where a cached module came from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Origin {
+    /// The atom behind one of a package's commands.
+    Atom { package: String, command: String },
+    /// A standalone `*.wasm` file.
+    Module,
+}
+
+impl TrackedCache {
+    /// Record where the module with this `hash` came from.
+    ///
+    /// This may be called before the module is compiled, in which case the
+    /// information is kept until it gets saved.
+    pub(crate) fn describe(&self, hash: ModuleHash, size: usize, origin: Origin) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(hash).or_default();
+        entry.size = Some(size);
+        entry.origin = Some(origin);
+    }
+
+    /// Protect a module from [`TrackedCache::evict()`], returning `false` if
+    /// it isn't in the cache.
+    ///
+    /// Hashes are given in the same hex form as [`ModuleCacheEntry::hash`].
+    pub(crate) fn set_pinned(&self, hash: &str, pinned: bool) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .iter_mut()
+            .find(|(key, entry)| entry.cached && key.to_string() == hash);
+
+        match entry {
+            Some((_, entry)) => {
+                entry.pinned = pinned;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict every module that isn't pinned, so it will be recompiled the
+    /// next time it is needed. Returns the number of modules evicted.
+    ///
+    /// Modules are dropped from this thread straight away, and from other
+    /// threads the next time they use the cache.
+    pub(crate) fn evict(&self) -> usize {
+        let mut evicted = 0;
+
+        for entry in self.entries.lock().unwrap().values_mut() {
+            if entry.cached && !entry.pinned {
+                entry.cached = false;
+                entry.hits = 0;
+                evicted += 1;
+            }
+        }
+
+        if evicted > 0 {
+            self.evictions.fetch_add(1, Ordering::SeqCst);
+            self.with_modules(|_| {});
+        }
+
+        evicted
+    }
+
+    /// Access the modules compiled on this thread, first dropping any that
+    /// were evicted since we last looked.
+    fn with_modules<R>(
+        &self,
+        f: impl FnOnce(&mut HashMap<(ModuleHash, String), Module>) -> R,
+    ) -> R {
+        MODULES.with(|modules| {
+            let mut modules = modules.borrow_mut();
+            let ours = modules.entry(self.id).or_default();
+
+            let evictions = self.evictions.load(Ordering::SeqCst);
+            if ours.evictions != evictions {
+                let entries = self.entries.lock().unwrap();
+                ours.modules
+                    .retain(|(hash, _), _| entries.get(hash).map_or(false, |entry| entry.cached));
+                ours.evictions = evictions;
+            }
+
+            f(&mut ours.modules)
+        })
+    }
+
+    /// Load a module, compiling it if it isn't in the cache.
+    ///
+    /// If the same module is already being compiled (on any thread), this
+    /// waits for that compilation instead of starting another one.
+    pub(crate) async fn load_or_compile<F, Fut>(
+        &self,
+        hash: ModuleHash,
+        engine: &Engine,
+        compile: F,
+    ) -> anyhow::Result<Module>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Module>>,
+    {
+        match self.load(hash, engine).await {
+            Ok(module) => return Ok(module),
+            Err(CacheError::NotFound) => {}
+            Err(e) => tracing::warn!(%hash, error = %e, "Unable to load the module from the cache"),
+        }
+
+        let waiting = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            match in_flight.get_mut(&hash) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    in_flight.insert(hash, Vec::new());
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = waiting {
+            tracing::debug!(%hash, "Waiting for a compilation that is already in progress");
+            self.stats.dedup_hits.fetch_add(1, Ordering::Relaxed);
+            match receiver.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(anyhow::anyhow!(e)),
+                Err(_) => return Err(anyhow::anyhow!("The module's compilation was cancelled")),
+            }
+
+            // Note: if the module was compiled on another thread, it may not
+            // have been broadcast to this one yet
+            if let Ok(module) = self.load(hash, engine).await {
+                return Ok(module);
+            }
+            tracing::debug!(%hash, "The compiled module hasn't reached this thread yet");
+            self.stats.compilations.fetch_add(1, Ordering::Relaxed);
+            return compile().await;
+        }
+
+        let guard = InFlight(hash);
+        self.stats.compilations.fetch_add(1, Ordering::Relaxed);
+        let result = compile().await;
+
+        if let Ok(module) = &result {
+            if let Err(e) = self.save(hash, engine, module).await {
+                tracing::warn!(%hash, error = %e, "Unable to cache the module");
+            }
+        }
+        guard.finish(&result);
+
+        result
+    }
+
+    /// Forget about every module, pinned or not.
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+        self.evictions.fetch_add(1, Ordering::SeqCst);
+        self.with_modules(|_| {});
+    }
+
+    fn summaries(&self) -> Vec<ModuleCacheEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.cached)
+            .map(|(hash, entry)| ModuleCacheEntry::new(hash, entry))
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl ModuleCache for TrackedCache {
+    async fn load(&self, key: ModuleHash, engine: &Engine) -> Result<Module, CacheError> {
+        // Note: another thread may not have dropped an evicted module yet,
+        // so we need to check our own records first.
+        let cached = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map_or(false, |entry| entry.cached);
+        if !cached {
+            return Err(CacheError::NotFound);
+        }
+
+        let engine_id = engine.deterministic_id().to_string();
+        let module = self
+            .with_modules(|modules| modules.get(&(key, engine_id)).cloned())
+            .ok_or(CacheError::NotFound)?;
+
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&key) {
+            entry.hits += 1;
+        }
+
+        Ok(module)
+    }
+
+    async fn save(
+        &self,
+        key: ModuleHash,
+        engine: &Engine,
+        module: &Module,
+    ) -> Result<(), CacheError> {
+        let engine_id = engine.deterministic_id().to_string();
+        self.with_modules(|modules| modules.insert((key, engine_id), module.clone()));
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key).or_default();
+        entry.cached = true;
+        if entry.size.is_none() {
+            entry.size = module.serialize().ok().map(|bytes| bytes.len());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleCacheEntry {
+    hash: String,
+    size: Option<usize>,
+    hits: u64,
+    origin: Option<String>,
+    package: Option<String>,
+    command: Option<String>,
+    pinned: bool,
+}
+
+impl ModuleCacheEntry {
+    fn new(hash: &ModuleHash, entry: &Entry) -> Self {
+        let (origin, package, command) = match &entry.origin {
+            Some(Origin::Atom { package, command }) => {
+                (Some("atom"), Some(package.clone()), Some(command.clone()))
+            }
+            Some(Origin::Module) => (Some("module"), None, None),
+            None => (None, None, None),
+        };
+
+        ModuleCacheEntry {
+            hash: hash.to_string(),
+            size: entry.size,
+            hits: entry.hits,
+            origin: origin.map(String::from),
+            package,
+            command,
+            pinned: entry.pinned,
+        }
+    }
+}
+
+/// A read-only view of the modules a runtime has compiled.
+#[derive(Debug, Clone)]
+#[wasm_bindgen(js_name = "ModuleCache")]
+pub struct JsModuleCache(pub(crate) TrackedCache);
+
+#[wasm_bindgen(js_class = "ModuleCache")]
+impl JsModuleCache {
+    /// Every module in the cache.
+    pub fn entries(&self) -> Result<ListOfModuleCacheEntries, Error> {
+        let value = serde_wasm_bindgen::to_value(&self.0.summaries()).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// Protect a module from {@link ModuleCache.evict}, returning `false` if
+    /// there is no module with that hash.
+    pub fn pin(&self, hash: &str) -> bool {
+        self.0.set_pinned(hash, true)
+    }
+
+    /// Undo {@link ModuleCache.pin}.
+    pub fn unpin(&self, hash: &str) -> bool {
+        self.0.set_pinned(hash, false)
+    }
+
+    /// Evict every module that hasn't been pinned, returning how many were
+    /// evicted. Evicted modules are dropped so their memory can be
+    /// reclaimed, and are recompiled the next time they are used.
+    pub fn evict(&self) -> usize {
+        self.0.evict()
+    }
+
+    /// Counters describing how effective the cache has been.
+    pub fn stats(&self) -> Result<JsModuleCacheStats, Error> {
+        let hits = self
+            .0
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.hits)
+            .sum();
+        let stats = ModuleCacheStats {
+            hits,
+            compilations: self.0.stats.compilations.load(Ordering::Relaxed),
+            dedup_hits: self.0.stats.dedup_hits.load(Ordering::Relaxed),
+        };
+
+        let value = serde_wasm_bindgen::to_value(&stats).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleCacheStats {
+    hits: u64,
+    compilations: u64,
+    dedup_hits: u64,
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const MODULE_CACHE_ENTRY_TYPE_DECLARATION: &str = r#"
+/**
+ * A module that was compiled and cached by a {@link Runtime}.
+ */
+export type ModuleCacheEntry = {
+    /** The module's hash, as accepted by {@link ModuleCache.pin}. */
+    hash: string;
+    /** The size of the module's `*.wasm` file in bytes, if known. */
+    size?: number;
+    /** How many times the module was reused instead of being recompiled. */
+    hits: number;
+    /** Whether the module came from a package's atom or a `*.wasm` file. */
+    origin?: "atom" | "module";
+    /** The package the atom belongs to (e.g. `"sharrattj/bash@1.0.12"`). */
+    package?: string;
+    /** The command the atom is used by. */
+    command?: string;
+    pinned: boolean;
+};
+
+/**
+ * Counters from {@link ModuleCache.stats}.
+ */
+export type ModuleCacheStats = {
+    /** How many times a cached module was reused. */
+    hits: number;
+    /** How many modules were compiled. */
+    compilations: number;
+    /**
+     * How many times a module was needed while it was already being
+     * compiled, and the result of that compilation was shared instead of
+     * compiling it again.
+     */
+    dedupHits: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ModuleCacheEntry[]")]
+    pub type ListOfModuleCacheEntries;
+
+    #[wasm_bindgen(typescript_type = "ModuleCacheStats")]
+    pub type JsModuleCacheStats;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn pinned_modules_survive_eviction() {
+        let cache = TrackedCache::default();
+        let bash = ModuleHash::hash(b"bash");
+        let ls = ModuleHash::hash(b"ls");
+        for hash in [bash, ls] {
+            cache.describe(hash, 4, Origin::Module);
+            cache.entries.lock().unwrap().get_mut(&hash).unwrap().cached = true;
+        }
+
+        assert!(cache.set_pinned(&bash.to_string(), true));
+        assert_eq!(cache.evict(), 1);
+
+        let remaining: Vec<_> = cache.summaries().into_iter().map(|e| e.hash).collect();
+        assert_eq!(remaining, [bash.to_string()]);
+        assert!(!cache.set_pinned(&ls.to_string(), true));
+    }
+
+    #[wasm_bindgen_test]
+    async fn evicted_modules_are_dropped() {
+        let cache = TrackedCache::default();
+        let engine = Engine::default();
+        let hash = ModuleHash::hash(b"trivial");
+        let module = Module::new(&engine, "(module)").unwrap();
+
+        cache.save(hash, &engine, &module).await.unwrap();
+        assert_eq!(cache.with_modules(|modules| modules.len()), 1);
+
+        assert_eq!(cache.evict(), 1);
+
+        assert_eq!(cache.with_modules(|modules| modules.len()), 0);
+        assert!(cache.load(hash, &engine).await.is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn concurrent_compilations_are_deduplicated() {
+        let cache = TrackedCache::default();
+        let engine = Engine::default();
+        let hash = ModuleHash::hash(b"trivial");
+        let (ready_tx, ready_rx) = oneshot::channel::<()>();
+
+        let first = cache.load_or_compile(hash, &engine, move || async move {
+            ready_rx.await.unwrap();
+            Ok(Module::new(&Engine::default(), "(module)")?)
+        });
+        let second = cache.load_or_compile(hash, &engine, || {
+            futures::future::ready(Err(anyhow::anyhow!("Compiled a second time")))
+        });
+        // Only let the first compilation finish once the second is waiting
+        let release = async { ready_tx.send(()).unwrap() };
+        let (first, second, _) = futures::join!(first, second, release);
+
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        assert_eq!(cache.stats.compilations.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats.dedup_hits.load(Ordering::Relaxed), 1);
+    }
+}
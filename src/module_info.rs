@@ -0,0 +1,309 @@
+//! Describing a WebAssembly module without compiling or instantiating it,
+//! so hosts can explain (or refuse) a module which won't work before
+//! committing a worker and a memory to it.
+//!
+//! Inspecting a module never throws because the module is broken. Problems
+//! are listed in the report's `errors` instead.
+
+use std::borrow::Cow;
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasmparser::{ExternalKind, MemoryType, Parser, Payload, TableType, TypeRef};
+
+use crate::{
+    proposals::Proposal,
+    utils::Error,
+    validation::{Feature, Violation},
+};
+
+/// Everything we could find out about a module.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModuleReport {
+    pub(crate) valid: bool,
+    errors: Vec<Violation>,
+    pub(crate) imports: Vec<ImportInfo>,
+    pub(crate) exports: Vec<ExportInfo>,
+    pub(crate) memories: Vec<MemoryInfo>,
+    tables: Vec<TableInfo>,
+    features: Vec<&'static str>,
+    /// Required features this browser (or page) can't provide.
+    unsupported: Vec<&'static str>,
+    has_start: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ImportInfo {
+    pub(crate) module: String,
+    pub(crate) name: String,
+    pub(crate) kind: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ExportInfo {
+    pub(crate) name: String,
+    pub(crate) kind: &'static str,
+}
+
+/// A memory's size limits, in 64 KiB pages.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MemoryInfo {
+    initial: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum: Option<u64>,
+    pub(crate) shared: bool,
+    memory64: bool,
+    pub(crate) imported: bool,
+}
+
+impl MemoryInfo {
+    fn new(ty: MemoryType, imported: bool) -> Self {
+        MemoryInfo {
+            initial: ty.initial,
+            maximum: ty.maximum,
+            shared: ty.shared,
+            memory64: ty.memory64,
+            imported,
+        }
+    }
+}
+
+/// A table's size limits, in elements.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TableInfo {
+    element: String,
+    initial: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum: Option<u32>,
+    imported: bool,
+}
+
+impl TableInfo {
+    fn new(ty: TableType, imported: bool) -> Self {
+        TableInfo {
+            element: format!("{:?}", ty.element_type).to_ascii_lowercase(),
+            initial: ty.initial,
+            maximum: ty.maximum,
+            imported,
+        }
+    }
+}
+
+/// Inspect `wasm`, a binary or `*.wat` text.
+pub(crate) fn inspect(wasm: &[u8]) -> ModuleReport {
+    let wasm = wasmer::wat2wasm(wasm).unwrap_or(Cow::Borrowed(wasm));
+
+    let mut report = ModuleReport {
+        errors: crate::validation::errors(&wasm),
+        ..Default::default()
+    };
+    report.valid = report.errors.is_empty();
+    // Note: the structure is still worth reporting for invalid modules, so
+    // we go as far as we can and ignore whatever we can't parse
+    let _ = read_sections(&wasm, &mut report);
+
+    if report.valid {
+        report.features = crate::validation::required_features(&wasm)
+            .into_iter()
+            .map(feature_name)
+            .collect();
+    }
+    if crate::package_info::uses_gc_types(&wasm) {
+        report.features.push("gc");
+    }
+    report.unsupported = report
+        .features
+        .iter()
+        .copied()
+        .filter(|feature| !is_supported(feature))
+        .collect();
+
+    report
+}
+
+fn read_sections(
+    wasm: &[u8],
+    report: &mut ModuleReport,
+) -> Result<(), wasmparser::BinaryReaderError> {
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    let kind = match import.ty {
+                        TypeRef::Func(_) => "function",
+                        TypeRef::Table(ty) => {
+                            report.tables.push(TableInfo::new(ty, true));
+                            "table"
+                        }
+                        TypeRef::Memory(ty) => {
+                            report.memories.push(MemoryInfo::new(ty, true));
+                            "memory"
+                        }
+                        TypeRef::Global(_) => "global",
+                        TypeRef::Tag(_) => "tag",
+                    };
+                    report.imports.push(ImportInfo {
+                        module: import.module.to_string(),
+                        name: import.name.to_string(),
+                        kind,
+                    });
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    report.tables.push(TableInfo::new(table?, false));
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    report.memories.push(MemoryInfo::new(memory?, false));
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    let kind = match export.kind {
+                        ExternalKind::Func => "function",
+                        ExternalKind::Table => "table",
+                        ExternalKind::Memory => "memory",
+                        ExternalKind::Global => "global",
+                        ExternalKind::Tag => "tag",
+                    };
+                    report.exports.push(ExportInfo {
+                        name: export.name.to_string(),
+                        kind,
+                    });
+                }
+            }
+            Payload::StartSection { .. } => report.has_start = true,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn feature_name(feature: Feature) -> &'static str {
+    match feature {
+        Feature::Threads => "threads",
+        Feature::Simd => "simd",
+        Feature::RelaxedSimd => "relaxed-simd",
+        Feature::Exceptions => "exceptions",
+        Feature::TailCalls => "tail-calls",
+        Feature::Memory64 => "memory64",
+        Feature::MultiMemory => "multi-memory",
+        Feature::ExtendedConst => "extended-const",
+        Feature::ReferenceTypes => "reference-types",
+        Feature::BulkMemory => "bulk-memory",
+    }
+}
+
+/// Can this page run modules which need `feature`, as far as we can tell?
+pub(crate) fn is_supported(feature: &str) -> bool {
+    match feature {
+        "tail-calls" => Proposal::TailCalls.is_supported(),
+        "gc" => Proposal::Gc.is_supported(),
+        // Shared memories need SharedArrayBuffer, which is only available
+        // to cross-origin isolated pages
+        "threads" => js_sys::Reflect::get(&js_sys::global(), &"crossOriginIsolated".into())
+            .map(|isolated| isolated.is_truthy())
+            .unwrap_or(false),
+        _ => true,
+    }
+}
+
+impl ModuleReport {
+    pub(crate) fn to_js(&self) -> Result<JsModuleReport, Error> {
+        let value = serde_wasm_bindgen::to_value(self).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const MODULE_REPORT_TYPE_DEFS: &'static str = r#"
+/** The kind of thing a module imports or exports. */
+export type ExternKind = "function" | "table" | "memory" | "global" | "tag";
+
+/**
+ * What {@link Wasmer.inspect} found out about a module.
+ */
+export type ModuleReport = {
+    /** Whether the module is valid WebAssembly. */
+    valid: boolean;
+    /** Everything that makes the module invalid. */
+    errors: ValidationViolation[];
+    imports: { module: string; name: string; kind: ExternKind }[];
+    exports: { name: string; kind: ExternKind }[];
+    /** Every memory the module imports or defines, sized in 64 KiB pages. */
+    memories: {
+        initial: number;
+        maximum?: number;
+        shared: boolean;
+        memory64: boolean;
+        imported: boolean;
+    }[];
+    /** Every table the module imports or defines, sized in elements. */
+    tables: {
+        element: string;
+        initial: number;
+        maximum?: number;
+        imported: boolean;
+    }[];
+    /**
+     * The post-MVP features the module needs, as {@link WasmFeature}s plus
+     * `"gc"`. This is only worked out for valid modules, apart from `"gc"`.
+     */
+    features: (WasmFeature | "gc")[];
+    /**
+     * Required features this page can't provide, e.g. `"threads"` on a page
+     * which isn't cross-origin isolated. A module needing any of these would
+     * fail to start.
+     */
+    unsupported: (WasmFeature | "gc")[];
+    /** Whether the module has a start function, which runs on instantiation. */
+    hasStart: boolean;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ModuleReport")]
+    pub type JsModuleReport;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn report_a_modules_structure() {
+        let report = inspect(
+            br#"(module
+                (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1 16)
+                (func $main (local v128))
+                (start $main)
+                (export "_start" (func $main)))"#,
+        );
+
+        assert!(report.valid, "{:?}", report.errors);
+        assert_eq!(report.imports.len(), 1);
+        assert_eq!(report.imports[0].kind, "function");
+        let exports: Vec<_> = report.exports.iter().map(|e| (&*e.name, e.kind)).collect();
+        assert_eq!(exports, [("memory", "memory"), ("_start", "function")]);
+        assert_eq!(report.memories[0].maximum, Some(16));
+        assert!(!report.memories[0].imported);
+        assert_eq!(report.features, ["simd"]);
+        assert!(report.has_start);
+
+        let broken = inspect(b"\0asm\x01\0\0\0\x05");
+        assert!(!broken.valid);
+        assert!(!broken.errors.is_empty());
+    }
+}
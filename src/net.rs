@@ -1,5 +1,5 @@
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicU64, AtomicUsize, Ordering},
     Arc,
 };
 
@@ -7,9 +7,107 @@ use tokio::sync::mpsc;
 use virtual_net::{meta::MessageRequest, RemoteNetworkingClient};
 use wasm_bindgen_futures::JsFuture;
 
-use crate::{utils::GlobalScope, ws::WebSocket};
+use crate::{
+    utils::{Error, GlobalScope},
+    web_transport::{self, WebTransportTunnel},
+    ws::WebSocket,
+};
+
+/// How we connect to the networking gateway.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) enum Transport {
+    #[default]
+    WebSocket,
+    /// Only used when explicitly asked for, since the gateway needs to
+    /// support it.
+    WebTransport,
+}
+
+impl Transport {
+    pub(crate) fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "websocket" => Ok(Transport::WebSocket),
+            "webtransport" => Ok(Transport::WebTransport),
+            other => {
+                let msg = format!(
+                    "Unknown network transport, \"{other}\" (expected \"websocket\" or \"webtransport\")"
+                );
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+
+    /// Make sure `gateway` can be reached using this transport.
+    pub(crate) fn check(self, gateway: &str) -> Result<(), Error> {
+        if self != Transport::WebTransport {
+            return Ok(());
+        }
+
+        if !WebTransportTunnel::is_supported() {
+            let msg = "This browser doesn't support WebTransport";
+            return Err(Error::js(js_sys::TypeError::new(msg)));
+        }
+        if web_transport::session_url(gateway).is_none() {
+            let msg = format!(
+                "WebTransport needs a secure (\"wss://\" or \"https://\") gateway, not \"{gateway}\""
+            );
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        Ok(())
+    }
+}
+
+/// How many bytes have been sent to and received from the networking
+/// gateway.
+///
+/// Every command using the runtime shares the same gateway connection, so
+/// these cover all of them. The counts are of the messages on the wire, so
+/// they include the tunnel's own framing.
+#[derive(Debug, Default)]
+pub(crate) struct NetworkCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl NetworkCounters {
+    pub(crate) fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A connection to the networking gateway.
+enum Tunnel {
+    WebSocket(WebSocket),
+    WebTransport(WebTransportTunnel),
+}
+
+impl Tunnel {
+    async fn send(&mut self, request: &MessageRequest, data: Vec<u8>) -> Result<(), String> {
+        match self {
+            Tunnel::WebSocket(ws) => ws.send(data),
+            Tunnel::WebTransport(wt) => wt.send(request, data).await.map_err(|e| e.to_string()),
+        }
+    }
+}
 
-pub(crate) fn connect_networking(connect: String) -> RemoteNetworkingClient {
+pub(crate) fn connect_networking(
+    connect: String,
+    transport: Transport,
+    counters: Arc<NetworkCounters>,
+) -> RemoteNetworkingClient {
     let (recv_tx, recv_rx) = mpsc::channel(100);
     let (send_tx, send_rx) = mpsc::channel(100);
     let send_tx2 = send_tx.clone();
@@ -21,6 +119,7 @@ pub(crate) fn connect_networking(connect: String) -> RemoteNetworkingClient {
 
     wasm_bindgen_futures::spawn_local(async move {
         let backoff = Arc::new(AtomicUsize::new(0));
+
         loop {
             // Exponential backoff prevents thrashing of the connection
             let backoff_ms = backoff.load(Ordering::SeqCst);
@@ -31,58 +130,43 @@ pub(crate) fn connect_networking(connect: String) -> RemoteNetworkingClient {
             let new_backoff = 8000usize.min((backoff_ms * 2) + 100);
             backoff.store(new_backoff, Ordering::SeqCst);
 
-            // Establish a websocket connection to the edge network
-            let mut ws = match WebSocket::new(connect.as_str()) {
-                Ok(ws) => ws,
-                Err(err) => {
-                    tracing::error!("failed to establish web socket connection - {}", err);
-                    continue;
-                }
-            };
-
-            // Wire up the events
             let (relay_tx, mut relay_rx) = mpsc::unbounded_channel();
-            let (connected_tx, mut connected_rx) = mpsc::unbounded_channel();
-            ws.set_onopen({
-                let connect = connect.clone();
-                let connected_tx = connected_tx.clone();
-                Box::new(move || {
-                    tracing::debug!(url = connect, "networking web-socket opened");
-                    connected_tx.send(true).ok();
-                })
-            });
-            ws.set_onclose({
-                let connect = connect.clone();
-
-                let connected_tx = connected_tx.clone();
-                let relay_tx = relay_tx.clone();
-                Box::new(move || {
-                    tracing::debug!(url = connect, "networking web-socket closed");
-                    relay_tx.send(Vec::new()).ok();
-                    connected_tx.send(false).ok();
-                })
-            });
-            ws.set_onmessage({
-                Box::new(move |data| {
-                    relay_tx.send(data).unwrap();
-                })
-            });
 
-            // Wait for it to connect and setup the rest of the callbacks
-            if !connected_rx.recv().await.unwrap_or_default() {
-                continue;
-            }
+            let session_url = web_transport::session_url(&connect)
+                .filter(|_| transport == Transport::WebTransport);
+            let mut tunnel = match session_url {
+                Some(url) => match WebTransportTunnel::open(&url, relay_tx).await {
+                    Ok(wt) => {
+                        tracing::debug!(url, "networking WebTransport session opened");
+                        Tunnel::WebTransport(wt)
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            url,
+                            error = &*e.into_anyhow(),
+                            "Unable to open a WebTransport session"
+                        );
+                        continue;
+                    }
+                },
+                None => match open_websocket(&connect, relay_tx).await {
+                    Some(ws) => Tunnel::WebSocket(ws),
+                    None => continue,
+                },
+            };
             backoff.store(100, Ordering::SeqCst);
 
             // We process any backends
             wasm_bindgen_futures::spawn_local({
                 let send_tx2 = send_tx2.clone();
                 let recv_tx = recv_tx.clone();
+                let counters = Arc::clone(&counters);
                 async move {
                     while let Some(data) = relay_rx.recv().await {
                         if data.is_empty() {
                             break;
                         }
+                        counters.record_received(data.len());
                         let data = match bincode::deserialize(&data) {
                             Ok(d) => d,
                             Err(err) => {
@@ -103,22 +187,74 @@ pub(crate) fn connect_networking(connect: String) -> RemoteNetworkingClient {
 
             while let Some(data) = send_rx.lock().await.recv().await {
                 if let MessageRequest::Reconnect = &data {
-                    tracing::info!("websocket will reconnect");
+                    tracing::info!("networking tunnel will reconnect");
                     break;
                 }
-                let data = match bincode::serialize(&data) {
+                let request = data;
+                let data = match bincode::serialize(&request) {
                     Ok(d) => d,
                     Err(err) => {
                         tracing::error!("failed to serialize networking message - {}", err);
                         break;
                     }
                 };
-                if let Err(err) = ws.send(data) {
-                    tracing::error!("websocket has failed - {}", err);
+                let len = data.len();
+                if let Err(err) = tunnel.send(&request, data).await {
+                    tracing::error!("networking tunnel has failed - {}", err);
                     break;
                 }
+                counters.record_sent(len);
             }
         }
     });
     client
 }
+
+/// Establish a websocket connection to the edge network, returning once it
+/// has opened.
+async fn open_websocket(
+    connect: &str,
+    relay_tx: mpsc::UnboundedSender<Vec<u8>>,
+) -> Option<WebSocket> {
+    let mut ws = match WebSocket::new(connect) {
+        Ok(ws) => ws,
+        Err(err) => {
+            tracing::error!("failed to establish web socket connection - {}", err);
+            return None;
+        }
+    };
+
+    // Wire up the events
+    let (connected_tx, mut connected_rx) = mpsc::unbounded_channel();
+    ws.set_onopen({
+        let connect = connect.to_string();
+        let connected_tx = connected_tx.clone();
+        Box::new(move || {
+            tracing::debug!(url = connect, "networking web-socket opened");
+            connected_tx.send(true).ok();
+        })
+    });
+    ws.set_onclose({
+        let connect = connect.to_string();
+
+        let connected_tx = connected_tx.clone();
+        let relay_tx = relay_tx.clone();
+        Box::new(move || {
+            tracing::debug!(url = connect, "networking web-socket closed");
+            relay_tx.send(Vec::new()).ok();
+            connected_tx.send(false).ok();
+        })
+    });
+    ws.set_onmessage({
+        Box::new(move |data| {
+            relay_tx.send(data).unwrap();
+        })
+    });
+
+    // Wait for it to connect and setup the rest of the callbacks
+    if !connected_rx.recv().await.unwrap_or_default() {
+        return None;
+    }
+
+    Some(ws)
+}
@@ -1,12 +1,36 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
 
-use anyhow::Context;
 use js_sys::Array;
 use virtual_fs::TmpFileSystem;
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue, UnwrapThrowExt};
 use wasmer_wasix::WasiEnvBuilder;
 
-use crate::{runtime::Runtime, utils::Error, Directory, DirectoryInit, JsRuntime, StringOrBytes};
+use crate::{
+    events::MountKind,
+    faults::Faults,
+    fs::{InlineFile, MountTable, Sandbox, SandboxMode},
+    host_info::{self, HostInfo},
+    line_endings::LineEndings,
+    locale::Locale,
+    metering::MeteringOptions,
+    profile::Profile,
+    recording::SessionRecording,
+    runtime::Runtime,
+    sequenced_output::{OutputLog, OutputStream},
+    startup::StartupProgress,
+    streams::StdinHandle,
+    tasks::ThreadPool,
+    terminal::{EchoControl, TtyMode},
+    trace_context::TraceContext,
+    usage::ResourceUsage,
+    utils::Error,
+    validation::ModuleValidation,
+    Directory, DirectoryInit, JsRuntime, StringOrBytes,
+};
 
 #[wasm_bindgen]
 extern "C" {
@@ -28,8 +52,23 @@ type CommonOptions = {
     args?: string[];
     /** Environment variables to set. */
     env?: Record<string, string>;
+    /**
+     * The path to a `.env` file in one of the `mount`ed directories (e.g.
+     * `"/app/.env"`) to load environment variables from before starting the
+     * program. Anything in `env` takes precedence. See {@link parseDotenv}
+     * for the syntax.
+     */
+    envFile?: string;
     /** The standard input stream. */
     stdin?: string | Uint8Array;
+    /**
+     * What the program sees on stdin when `stdin` isn't provided.
+     *
+     * - `"pipe"` (the default) connects stdin to {@link Instance.stdin}, so
+     *   reads block until data is written or stdin is closed
+     * - `"eof"` leaves stdin empty, so the first read returns EOF
+     */
+    emptyStdin?: "pipe" | "eof";
     /**
      * Directories that should be mounted inside the WASIX instance.
      *
@@ -39,8 +78,126 @@ type CommonOptions = {
      *
      * Avoid mounting directly to `"/"` as it may clobber a package's bundled
      * files.
+     *
+     * Unless something else is mounted there, `/proc/host` contains
+     * `key=value` lines describing the host (`user_agent_family`, `cores`,
+     * `cross_origin_isolated`, `runtime_version`, and `features`) so
+     * programs can adapt to their environment. There are also minimal
+     * `/proc/cpuinfo`, `/proc/meminfo` and `/proc/self/stat` files for
+     * tools that size their heaps or thread pools from them.
      */
     mount?: Record<string, DirectoryInit | Directory>;
+    /**
+     * Small files to create before the program starts, mapping absolute
+     * paths to their contents. Anything other than a string or `Uint8Array`
+     * is serialized as JSON.
+     *
+     * Files inside a `mount`ed directory are layered on top of it, so the
+     * program sees them but the {@link Directory} itself is left unchanged
+     * (along with any changes the program makes to those files). Commands
+     * from a package can't have files created directly in `/`.
+     *
+     * @example
+     * ```ts
+     * files: { "/input/data.txt": "hello", "/input/config.json": { debug: true } }
+     * ```
+     */
+    files?: Record<string, string | Uint8Array | unknown>;
+    /** Send a {@link CrashReport} if the program traps or exits abnormally. */
+    crashReport?: CrashReportOptions;
+    /**
+     * Also deliver stdout and stderr on {@link Instance.output} as a single
+     * stream of {@link OutputChunk}s, each stamped with a sequence number and
+     * timestamp when it was written.
+     *
+     * Chunks are buffered until they are read, so only enable this if you
+     * are going to consume {@link Instance.output}.
+     */
+    sequencedOutput?: boolean;
+    /**
+     * A W3C `traceparent` (e.g.
+     * `"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"`) for the
+     * host operation this program is part of.
+     *
+     * It is attached to the runtime's spans for the program and HTTP
+     * requests made on its behalf get a `traceparent` header in the same
+     * trace, so they show up in the host's distributed traces.
+     */
+    traceparent?: string;
+    /**
+     * Run the program with a locale and timezone instead of the `C` locale
+     * and UTC, by setting `LANG` and `TZ` (and `ICU_DATA`, when ICU data is
+     * provided). Anything in `env` takes precedence.
+     *
+     * `true` uses the host's language and timezone, and a string is a
+     * language tag (e.g. `"de-DE"`). See {@link LocaleOptions} for mounting
+     * timezone and ICU data, which is only downloaded once a program reads
+     * it.
+     */
+    locale?: boolean | string | LocaleOptions;
+    /**
+     * Translate between `\r\n` and `\n` line endings on the program's
+     * standard streams and in specific `mount`s, so inputs authored on
+     * Windows (or programs expecting them) don't cause spurious diffs.
+     * Nothing is translated by default.
+     *
+     * @example
+     * ```ts
+     * lineEndings: { stdin: "lf", mounts: { "/project": "lf" } }
+     * ```
+     */
+    lineEndings?: LineEndingOptions;
+    /**
+     * Make the program's I/O fail on purpose, for testing how it copes with
+     * errors like a full disk. Each {@link FaultRule} applies to the
+     * standard streams and files in `mount`ed directories.
+     *
+     * @example
+     * ```ts
+     * faults: [{ syscall: "write", errno: "ENOSPC", path: "/data", every: 3 }]
+     * ```
+     */
+    faults?: FaultRule[];
+    /**
+     * Validate the program's module before it is compiled, optionally
+     * rejecting it if it uses certain WebAssembly features. Useful when
+     * running untrusted packages.
+     *
+     * Violations are reported as a `ModuleValidationError` whose
+     * `violations` property lists each {@link ValidationViolation}.
+     *
+     * @example
+     * ```ts
+     * validation: { deny: ["threads", "simd"] }
+     * ```
+     */
+    validation?: ModuleValidation;
+    /**
+     * How closely the program's paths are checked against the directories
+     * mounted into it.
+     *
+     * - `"enforce"` (the default) resolves `.` and `..` and refuses paths
+     *   that would climb out of a mount or follow a symlink (which could
+     *   lead anywhere)
+     * - `"strict"` also refuses any `..` or path naming a symlink, and
+     *   reports every refusal as a `"sandbox-violation"` event
+     * - `"off"` passes paths through untouched
+     *
+     * Refused operations fail with `EACCES`.
+     */
+    sandbox?: "off" | "enforce" | "strict";
+    /**
+     * Make the environment look like a particular Linux distribution, so
+     * configure scripts and tools that probe the OS behave predictably.
+     *
+     * A profile provisions files like `/etc/os-release`, common directories
+     * under `/usr` and `/var`, a `PATH`, and stub executables (e.g.
+     * `/sbin/ldconfig`) that exit successfully. Anything in `env` and
+     * `files` takes precedence. Commands from a package only get the files
+     * in `/etc` and `mount`ed directories, so the package's own directories
+     * aren't hidden.
+     */
+    profile?: "debian-like" | "alpine-like" | EmulationProfile;
 };
 
 /**
@@ -49,6 +206,32 @@ type CommonOptions = {
 export type RunOptions = CommonOptions & {
     /** The name of the program being run (passed in as arg 0) */
     program?: string;
+    /**
+     * The command to run when {@link runWasix} is given a {@link Wasmer}
+     * package. Defaults to the package's entrypoint.
+     */
+    command?: string;
+    /**
+     * Start the program as a background job instead of making it the
+     * runtime's foreground job. See {@link Runtime.suspend}.
+     */
+    background?: boolean;
+    /**
+     * Called as the program moves through each {@link StartupPhase}, so
+     * applications can show how far along startup is.
+     */
+    onProgress?: (progress: StartupProgress) => void;
+    /**
+     * Count the WebAssembly instructions the program executes, optionally
+     * stopping it once `gasLimit` instructions have run. The count is
+     * reported in {@link ResourceUsage.gas}.
+     *
+     * The module is instrumented before it is compiled, so this only works
+     * when {@link runWasix} is given the module's bytes. Programs which can
+     * spawn threads are refused, since each thread would get a budget of its
+     * own.
+     */
+    metering?: boolean | { gasLimit?: number };
     /**
      * The WASIX runtime to use.
      *
@@ -68,6 +251,29 @@ export type SpawnOptions = CommonOptions & {
      * Packages that should also be loaded into the WASIX environment.
      */
     uses?: string[];
+    /**
+     * Command history and tab completion for interactive programs that rely
+     * on the TTY's line buffering. Ignored when `stdin` is provided.
+     */
+    readline?: ReadlineOptions;
+    /**
+     * Whether the program is attached to a terminal. Ignored when `stdin` is
+     * provided.
+     *
+     * - `"auto"` (the default) tells the program it has a terminal, but only
+     *   sets one up (echo, line buffering, `readline`) the first time the
+     *   program queries or configures it, so programs that never ask get
+     *   plain pipes
+     * - `"always"` sets up the terminal straight away
+     * - `"never"` uses plain pipes and `isatty()` returns false
+     */
+    tty?: "auto" | "always" | "never";
+    /**
+     * Record the terminal session (output, and input as it was typed, with
+     * timings) so it can be exported with {@link Instance.exportRecording}.
+     * Ignored when the program isn't attached to a terminal.
+     */
+    record?: boolean;
 }
 "#;
 
@@ -82,11 +288,47 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     fn env(this: &CommonOptions) -> JsValue;
 
+    #[wasm_bindgen(method, getter, js_name = "envFile")]
+    fn env_file(this: &CommonOptions) -> Option<String>;
+
     #[wasm_bindgen(method, getter)]
     fn stdin(this: &CommonOptions) -> Option<StringOrBytes>;
 
+    #[wasm_bindgen(method, getter, js_name = "emptyStdin")]
+    fn empty_stdin(this: &CommonOptions) -> Option<String>;
+
     #[wasm_bindgen(method, getter)]
     fn mount(this: &CommonOptions) -> OptionalDirectories;
+
+    #[wasm_bindgen(method, getter, js_name = "files")]
+    fn files_raw(this: &CommonOptions) -> Option<js_sys::Object>;
+
+    #[wasm_bindgen(method, getter, js_name = "crashReport")]
+    pub(crate) fn crash_report(this: &CommonOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter, js_name = "sequencedOutput")]
+    fn sequenced_output(this: &CommonOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter)]
+    fn traceparent(this: &CommonOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "locale")]
+    fn locale_raw(this: &CommonOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter, js_name = "lineEndings")]
+    fn line_endings_raw(this: &CommonOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter, js_name = "faults")]
+    fn faults_raw(this: &CommonOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter, js_name = "validation")]
+    fn validation_raw(this: &CommonOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter, js_name = "sandbox")]
+    fn sandbox_raw(this: &CommonOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "profile")]
+    fn profile_raw(this: &CommonOptions) -> JsValue;
 }
 
 impl CommonOptions {
@@ -98,17 +340,135 @@ impl CommonOptions {
     }
 
     pub(crate) fn parse_env(&self) -> Result<BTreeMap<String, String>, Error> {
-        match self.env().dyn_ref() {
-            Some(env) => {
-                let vars = crate::utils::js_record_of_strings(env)?;
-                Ok(vars.into_iter().collect())
-            }
-            None => Ok(BTreeMap::new()),
+        let mut vars = BTreeMap::new();
+
+        if let Some(path) = self.env_file() {
+            vars.extend(self.read_env_file(&path)?);
         }
+        if let Some(env) = self.env().dyn_ref() {
+            vars.extend(crate::utils::js_record_of_strings(env)?);
+        }
+
+        Ok(vars)
+    }
+
+    /// The variables a program should be started with, including defaults
+    /// from the runtime (e.g. `HOME`) that weren't set explicitly.
+    pub(crate) fn effective_env(
+        &self,
+        runtime: &Runtime,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        let mut vars = BTreeMap::new();
+        if let Some(profile) = self.profile()? {
+            vars.extend(profile.env);
+        }
+        vars.extend(runtime.identity().env());
+        if let Some(locale) = self.locale()? {
+            vars.extend(locale.env());
+        }
+        vars.extend(self.parse_env()?);
+        runtime.credentials().add_git_config(&mut vars);
+        Ok(vars)
+    }
+
+    /// Load variables from `envFile`, using whichever mounted directory
+    /// contains it.
+    fn read_env_file(&self, path: &str) -> Result<Vec<(String, String)>, Error> {
+        let (mount_point, dir) = self
+            .mounted_directories()?
+            .into_iter()
+            .filter(|(mount_point, _)| Path::new(path).starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.len())
+            .ok_or_else(|| {
+                let msg = format!("\"envFile\" ({path}) isn't inside a mounted directory");
+                Error::js(js_sys::TypeError::new(&msg))
+            })?;
+
+        let relative = Path::new(path)
+            .strip_prefix(&mount_point)
+            .unwrap_or(Path::new(path));
+        let contents = crate::blocking::block_on(
+            "reading envFile",
+            dir._read_file(relative.display().to_string()),
+        )
+        .map_err(|e| {
+            e.into_anyhow()
+                .context(format!("Unable to read \"{path}\""))
+        })?;
+
+        crate::dotenv::parse(&String::from_utf8_lossy(&contents))
     }
 
+    /// The bytes to use as stdin, or `None` if stdin should be connected to
+    /// [`Stdio::stdin`].
     pub(crate) fn read_stdin(&self) -> Option<Vec<u8>> {
-        self.stdin().map(|s| s.as_bytes())
+        match self.stdin() {
+            Some(s) => Some(s.as_bytes()),
+            None if self.empty_stdin().as_deref() == Some("eof") => Some(Vec::new()),
+            None => None,
+        }
+    }
+
+    /// The files to create before the program starts.
+    pub(crate) fn files(&self) -> Result<Vec<InlineFile>, Error> {
+        match self.files_raw() {
+            Some(record) => crate::fs::inline_files::parse(&record),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub(crate) fn profile(&self) -> Result<Option<Profile>, Error> {
+        Profile::parse(self.profile_raw())
+    }
+
+    pub(crate) fn locale(&self) -> Result<Option<Locale>, Error> {
+        Locale::parse(self.locale_raw())
+    }
+
+    pub(crate) fn line_endings(&self) -> Result<LineEndings, Error> {
+        let line_endings = LineEndings::parse(self.line_endings_raw())?;
+        line_endings.check_mounts(&self.mount_points())?;
+        Ok(line_endings)
+    }
+
+    pub(crate) fn faults(&self) -> Result<Faults, Error> {
+        Faults::parse(self.faults_raw())
+    }
+
+    pub(crate) fn validation(&self) -> Result<ModuleValidation, Error> {
+        ModuleValidation::parse(self.validation_raw())
+    }
+
+    /// The sandbox for the program's mounts, reporting violations on
+    /// `pool`'s event channel.
+    pub(crate) fn sandbox(&self, pool: &ThreadPool) -> Result<Sandbox, Error> {
+        let mode = SandboxMode::parse(self.sandbox_raw().as_deref())?;
+        Ok(Sandbox::new(mode, Some(pool.clone())))
+    }
+
+    pub(crate) fn trace_context(&self) -> Result<Option<TraceContext>, Error> {
+        self.traceparent().map(|t| t.parse()).transpose()
+    }
+
+    /// Create the [`OutputLog`] used for [`Stdio::output`], if requested.
+    pub(crate) fn output_log(&self) -> Option<(OutputLog, web_sys::ReadableStream)> {
+        if self.sequenced_output().unwrap_or(false) {
+            Some(OutputLog::new())
+        } else {
+            None
+        }
+    }
+
+    /// The locations directories will be mounted at, without creating the
+    /// directories themselves.
+    pub(crate) fn mount_points(&self) -> Vec<String> {
+        match self.mount().dyn_into::<js_sys::Object>() {
+            Ok(obj) => js_sys::Object::keys(&obj)
+                .iter()
+                .filter_map(|key| key.as_string())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
     }
 
     pub(crate) fn mounted_directories(&self) -> Result<Vec<(String, Directory)>, Error> {
@@ -162,22 +522,47 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub(crate) fn runtime(this: &RunOptions) -> OptionalRuntime;
 
+    #[wasm_bindgen(method, getter)]
+    pub(crate) fn command(this: &RunOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "background")]
+    fn background_raw(this: &RunOptions) -> Option<bool>;
+
+    #[wasm_bindgen(method, getter, js_name = "onProgress")]
+    fn on_progress(this: &RunOptions) -> Option<js_sys::Function>;
+
+    #[wasm_bindgen(method, getter, js_name = "metering")]
+    fn metering_raw(this: &RunOptions) -> Option<JsValue>;
 }
 
 impl RunOptions {
+    /// Should the program be started as a background job?
+    pub(crate) fn background(&self) -> bool {
+        self.background_raw().unwrap_or(false)
+    }
+
+    pub(crate) fn metering(&self) -> Result<Option<MeteringOptions>, Error> {
+        match self.metering_raw() {
+            Some(value) if !value.is_undefined() && !value.is_null() => {
+                MeteringOptions::parse(value)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn startup_progress(&self) -> StartupProgress {
+        StartupProgress::new(self.on_progress())
+    }
+
     /// Propagate any provided options to the [`WasiEnvBuilder`], returning
-    /// streams that can be used for stdin/stdout/stderr.
+    /// streams that can be used for stdin/stdout/stderr and the table of
+    /// mounted directories.
     pub(crate) fn configure_builder(
         &self,
         builder: &mut WasiEnvBuilder,
-    ) -> Result<
-        (
-            Option<web_sys::WritableStream>,
-            web_sys::ReadableStream,
-            web_sys::ReadableStream,
-        ),
-        Error,
-    > {
+        usage: &Arc<ResourceUsage>,
+        pool: &ThreadPool,
+    ) -> Result<(Stdio, MountTable), Error> {
         for arg in self.parse_args()? {
             builder.add_arg(arg);
         }
@@ -186,49 +571,131 @@ impl RunOptions {
             builder.add_env(key, value);
         }
 
-        let stdin = match self.read_stdin() {
+        let line_endings = self.line_endings()?;
+        let faults = self.faults()?;
+
+        let (stdin, stdin_handle) = match self.read_stdin() {
             Some(stdin) => {
+                usage
+                    .stdin_bytes
+                    .fetch_add(stdin.len() as u64, Ordering::Relaxed);
                 let f = virtual_fs::StaticFile::new(stdin);
-                builder.set_stdin(Box::new(f));
-                None
+                builder.set_stdin(faults.stdin(line_endings.stdin(Box::new(f))));
+                (None, None)
             }
             None => {
-                let (f, stdin) = crate::streams::input_pipe();
-                builder.set_stdin(Box::new(f));
-                Some(stdin)
+                let (f, stdin, handle) =
+                    crate::streams::closable_input_pipe(usage.stdin_bytes.clone());
+                builder.set_stdin(faults.stdin(line_endings.stdin(Box::new(f))));
+                (Some(stdin), Some(handle))
             }
         };
 
-        let (stdout_file, stdout) = crate::streams::output_pipe();
-        builder.set_stdout(Box::new(stdout_file));
+        let log = self.output_log();
+
+        let (stdout_file, stdout) = crate::streams::counted_output_pipe(
+            usage.stdout_bytes.clone(),
+            usage.stdout_chunks.clone(),
+        );
+        match &log {
+            Some((log, _)) => {
+                builder.set_stdout(faults.stdout(
+                    line_endings.stdout(Box::new(log.tee(OutputStream::Stdout, stdout_file))),
+                ))
+            }
+            None => builder.set_stdout(faults.stdout(line_endings.stdout(Box::new(stdout_file)))),
+        }
 
-        let (stderr_file, stderr) = crate::streams::output_pipe();
-        builder.set_stderr(Box::new(stderr_file));
+        let (stderr_file, stderr) = crate::streams::counted_output_pipe(
+            usage.stderr_bytes.clone(),
+            usage.stderr_chunks.clone(),
+        );
+        match &log {
+            Some((log, _)) => {
+                builder.set_stderr(faults.stderr(
+                    line_endings.stderr(Box::new(log.tee(OutputStream::Stderr, stderr_file))),
+                ))
+            }
+            None => builder.set_stderr(faults.stderr(line_endings.stderr(Box::new(stderr_file)))),
+        }
 
-        let fs = self.filesystem()?;
-        builder.set_fs(Box::new(fs));
+        let sandbox = self.sandbox(pool)?;
+        let mounts = self.filesystem(&line_endings, &faults, &sandbox, usage)?;
+        builder.set_fs(Box::new(mounts.root().clone()));
         builder.add_preopen_dir("/")?;
 
-        Ok((stdin, stdout, stderr))
+        let stdio = Stdio {
+            stdin,
+            stdin_handle,
+            stdout,
+            stderr,
+            output: log.map(|(_, stream)| stream),
+            echo: None,
+            recording: None,
+        };
+
+        Ok((stdio, mounts))
     }
 
-    pub(crate) fn filesystem(&self) -> Result<TmpFileSystem, Error> {
-        let root = TmpFileSystem::new();
+    pub(crate) fn filesystem(
+        &self,
+        line_endings: &LineEndings,
+        faults: &Faults,
+        sandbox: &Sandbox,
+        usage: &Arc<ResourceUsage>,
+    ) -> Result<MountTable, Error> {
+        let mounts = MountTable::new(TmpFileSystem::new());
+
+        let (mut files, dirs) = match self.profile()? {
+            Some(profile) => (profile.files, profile.dirs),
+            None => (Vec::new(), Vec::new()),
+        };
+        files.extend(self.files()?);
+        let mounted = crate::fs::inline_files::layer(&files, &dirs, self.mounted_directories()?)?;
 
-        for (dest, fs) in self.mounted_directories()? {
+        for (dest, fs) in mounted {
             tracing::trace!(%dest, ?fs, "Mounting directory");
+            let fs = sandbox.mount(&dest, faults.mount(&dest, line_endings.mount(&dest, fs)));
+            mounts.mount(dest.as_ref(), fs, MountKind::Directory)?;
+        }
 
-            let fs = Arc::new(fs) as Arc<_>;
-            root.mount(dest.as_str().into(), &fs, "/".into())
-                .with_context(|| format!("Unable to mount to \"{dest}\""))?;
+        if !self
+            .mount_points()
+            .iter()
+            .any(|p| p == host_info::MOUNT_POINT)
+        {
+            let proc = HostInfo::current().filesystem(Arc::clone(usage));
+            mounts.mount(
+                host_info::MOUNT_POINT.as_ref(),
+                Arc::new(proc),
+                MountKind::Generated,
+            )?;
         }
 
-        tracing::trace!(?root, "Initialized the filesystem");
+        tracing::trace!(?mounts, "Initialized the filesystem");
 
-        Ok(root)
+        Ok(mounts)
     }
 }
 
+/// The JavaScript side of a program's standard streams.
+#[derive(Debug)]
+pub(crate) struct Stdio {
+    /// Where JavaScript can write stdin, unless it was provided up front.
+    pub(crate) stdin: Option<web_sys::WritableStream>,
+    /// Used to close [`Stdio::stdin`] even when it is locked to a writer.
+    pub(crate) stdin_handle: Option<StdinHandle>,
+    pub(crate) stdout: web_sys::ReadableStream,
+    pub(crate) stderr: web_sys::ReadableStream,
+    /// stdout and stderr as a single stream of sequenced chunks, if
+    /// requested.
+    pub(crate) output: Option<web_sys::ReadableStream>,
+    /// Controls the TTY's input echo, if the program has a TTY.
+    pub(crate) echo: Option<EchoControl>,
+    /// The terminal session, if it is being recorded.
+    pub(crate) recording: Option<SessionRecording>,
+}
+
 impl OptionalRuntime {
     pub(crate) fn as_runtime(&self) -> Option<JsRuntime> {
         let js_value: &JsValue = self.as_ref();
@@ -263,6 +730,21 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub(crate) fn uses(this: &SpawnOptions) -> Option<js_sys::Array>;
 
+    #[wasm_bindgen(method, getter)]
+    pub(crate) fn readline(this: &SpawnOptions) -> Option<crate::readline::ReadlineOptions>;
+
     #[wasm_bindgen(method, getter)]
     pub(crate) fn runtime(this: &SpawnOptions) -> OptionalRuntime;
+
+    #[wasm_bindgen(method, getter, js_name = "tty")]
+    fn tty_raw(this: &SpawnOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    pub(crate) fn record(this: &SpawnOptions) -> Option<bool>;
+}
+
+impl SpawnOptions {
+    pub(crate) fn tty(&self) -> Result<TtyMode, Error> {
+        TtyMode::parse(self.tty_raw().as_deref())
+    }
 }
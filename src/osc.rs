@@ -0,0 +1,253 @@
+//! Picking hyperlinks, window titles and notifications out of a program's
+//! terminal output.
+//!
+//! Real terminals act on OSC ("operating system command") escape sequences
+//! themselves, but web UIs usually render output with something that doesn't
+//! know about them. When a program is attached to a TTY we watch its stdout
+//! for the handful of sequences UIs care about and report them as runtime
+//! events. The output itself is passed through untouched, so terminal
+//! emulators like xterm.js still see every byte.
+//!
+//! Recognised sequences:
+//!
+//! - `OSC 8 ; params ; URI ST` hyperlinks, reported once the link is closed
+//!   so the event can include the link's text
+//! - `OSC 0 ; title ST` and `OSC 2 ; title ST` window titles
+//! - `OSC 9 ; message ST` and `OSC 777 ; notify ; title ; body ST`
+//!   notifications
+//!
+//! where `ST` is either `BEL` or `ESC \`.
+
+use crate::{events::RuntimeEvent, streams::BoxedFile, tasks::ThreadPool};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Sequences longer than this are dropped rather than buffered forever.
+const MAX_PAYLOAD: usize = 4096;
+/// How much of a hyperlink's text we keep for the event.
+const MAX_TEXT: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// Just after an `ESC`.
+    Escape,
+    /// Inside a CSI sequence (e.g. colours), which isn't part of any text.
+    Csi,
+    Osc,
+    /// An `ESC` inside an OSC sequence, which is normally the start of `ST`.
+    OscEscape,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct OpenLink {
+    uri: String,
+    id: Option<String>,
+    text: Vec<u8>,
+}
+
+/// Incrementally scans output for OSC sequences, coping with sequences that
+/// are split across writes.
+#[derive(Debug, Clone)]
+pub(crate) struct OscScanner {
+    state: State,
+    payload: Vec<u8>,
+    /// Did the current sequence get too long to keep?
+    overflowed: bool,
+    link: Option<OpenLink>,
+}
+
+impl Default for OscScanner {
+    fn default() -> Self {
+        OscScanner {
+            state: State::Ground,
+            payload: Vec::new(),
+            overflowed: false,
+            link: None,
+        }
+    }
+}
+
+impl OscScanner {
+    /// Scan another chunk of output, adding any events it completes to
+    /// `events`.
+    pub(crate) fn scan(&mut self, data: &[u8], events: &mut Vec<RuntimeEvent>) {
+        for &byte in data {
+            match self.state {
+                State::Ground if byte == ESC => self.state = State::Escape,
+                State::Ground => {
+                    if let Some(link) = &mut self.link {
+                        if !byte.is_ascii_control() && link.text.len() < MAX_TEXT {
+                            link.text.push(byte);
+                        }
+                    }
+                }
+                State::Escape => self.escape(byte),
+                State::Csi => {
+                    if (0x40..=0x7e).contains(&byte) {
+                        self.state = State::Ground;
+                    }
+                }
+                State::Osc => match byte {
+                    BEL => self.finish(events),
+                    ESC => self.state = State::OscEscape,
+                    _ if self.payload.len() < MAX_PAYLOAD => self.payload.push(byte),
+                    _ => self.overflowed = true,
+                },
+                State::OscEscape if byte == b'\\' => self.finish(events),
+                State::OscEscape => {
+                    // An unterminated sequence followed by another escape,
+                    // which terminals treat as abandoning the sequence
+                    tracing::trace!("Dropping an unterminated OSC sequence");
+                    self.escape(byte);
+                }
+            }
+        }
+    }
+
+    /// Handle the byte after an `ESC`.
+    fn escape(&mut self, byte: u8) {
+        self.state = match byte {
+            b']' => {
+                self.payload.clear();
+                self.overflowed = false;
+                State::Osc
+            }
+            b'[' => State::Csi,
+            ESC => State::Escape,
+            _ => State::Ground,
+        };
+    }
+
+    fn finish(&mut self, events: &mut Vec<RuntimeEvent>) {
+        self.state = State::Ground;
+        if std::mem::take(&mut self.overflowed) {
+            tracing::debug!("Ignoring an oversized OSC sequence");
+            return;
+        }
+
+        let payload = std::mem::take(&mut self.payload);
+        let payload = String::from_utf8_lossy(&payload);
+        let Some((code, rest)) = payload.split_once(';') else {
+            return;
+        };
+
+        match code {
+            "8" => {
+                let (params, uri) = rest.split_once(';').unwrap_or((rest, ""));
+                // Opening a link implicitly closes the previous one
+                events.extend(self.close_link());
+                if !uri.is_empty() {
+                    self.link = Some(OpenLink {
+                        uri: uri.to_string(),
+                        id: link_id(params),
+                        text: Vec::new(),
+                    });
+                }
+            }
+            "0" | "2" => events.push(RuntimeEvent::TerminalTitle {
+                title: rest.to_string(),
+            }),
+            "9" if is_conemu_command(rest) => {}
+            "9" => events.push(RuntimeEvent::TerminalNotification {
+                title: None,
+                body: rest.to_string(),
+            }),
+            "777" => {
+                if let Some(notification) = rest.strip_prefix("notify;") {
+                    let (title, body) = notification.split_once(';').unwrap_or((notification, ""));
+                    events.push(RuntimeEvent::TerminalNotification {
+                        title: Some(title.to_string()),
+                        body: body.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn close_link(&mut self) -> Option<RuntimeEvent> {
+        let OpenLink { uri, id, text } = self.link.take()?;
+        Some(RuntimeEvent::TerminalHyperlink {
+            uri,
+            id,
+            text: String::from_utf8_lossy(&text).into_owned(),
+        })
+    }
+}
+
+/// Pull the `id=...` out of an OSC 8 link's `key=value:key=value` params.
+fn link_id(params: &str) -> Option<String> {
+    params
+        .split(':')
+        .find_map(|param| param.strip_prefix("id="))
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+}
+
+/// ConEmu overloads `OSC 9` with numbered commands (e.g. `OSC 9 ; 4 ; ...`
+/// for progress), which aren't notifications.
+fn is_conemu_command(rest: &str) -> bool {
+    let (number, _) = rest.split_once(';').unwrap_or((rest, ""));
+    !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Wrap a program's stdout so the OSC sequences it writes are reported as
+/// events on `pool`'s runtime.
+pub(crate) fn watch_stdout(inner: BoxedFile, pool: ThreadPool) -> BoxedFile {
+    let mut scanner = OscScanner::default();
+    crate::streams::watch_writes(inner, move |data| {
+        let mut events = Vec::new();
+        scanner.scan(data, &mut events);
+        for event in events {
+            pool.emit(event);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn sequences_split_across_writes_are_reported() {
+        let output: &[&[u8]] = &[
+            b"see \x1b]8;id=docs;https://wasmer.io\x1b",
+            b"\\the \x1b[1mdocs\x1b[0m\x1b]8;;\x07\n",
+            b"\x1b]2;build: ok\x07\x1b]9;4;1;50\x07",
+            b"\x1b]777;notify;Done;3 tests passed\x1b\\",
+            b"\x1b]9;Finished\x07\x1b]0;unterminated\x1b[m",
+        ];
+        let mut scanner = OscScanner::default();
+        let mut events = Vec::new();
+        for chunk in output {
+            scanner.scan(chunk, &mut events);
+        }
+
+        assert_eq!(
+            events,
+            [
+                RuntimeEvent::TerminalHyperlink {
+                    uri: "https://wasmer.io".to_string(),
+                    id: Some("docs".to_string()),
+                    text: "the docs".to_string(),
+                },
+                RuntimeEvent::TerminalTitle {
+                    title: "build: ok".to_string(),
+                },
+                RuntimeEvent::TerminalNotification {
+                    title: Some("Done".to_string()),
+                    body: "3 tests passed".to_string(),
+                },
+                RuntimeEvent::TerminalNotification {
+                    title: None,
+                    body: "Finished".to_string(),
+                },
+            ]
+        );
+        assert_eq!(scanner.state, State::Ground);
+    }
+}
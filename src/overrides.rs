@@ -0,0 +1,164 @@
+//! Overriding packages during dependency resolution, so unpublished packages
+//! can be tested against the packages that depend on them.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use wasmer_wasix::runtime::resolver::{
+    DistributionInfo, PackageInfo, PackageSpecifier, PackageSummary, QueryError, Source, WebcHash,
+};
+use webc::Container;
+
+use crate::utils::Error;
+
+/// Replacements for packages, keyed by their full name (e.g.
+/// `"sharrattj/bash"`).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PackageOverrides(Arc<Mutex<BTreeMap<String, Override>>>);
+
+#[derive(Debug, Clone)]
+enum Override {
+    /// Use a local `*.webc` file.
+    Local(PackageSummary),
+    /// Use a different version from the registry.
+    Version(PackageSpecifier),
+}
+
+impl PackageOverrides {
+    /// Replace `name` with a local `*.webc` file, returning its summary so the
+    /// bytes can be made available to the package loader.
+    pub(crate) fn set_local(&self, name: &str, webc: &Bytes) -> Result<PackageSummary, Error> {
+        let container = Container::from_bytes(webc.clone())?;
+        let pkg = PackageInfo::from_manifest(container.manifest())?;
+
+        if pkg.name != name {
+            return Err(anyhow::anyhow!(
+                "Expected the override for \"{name}\" to be a \"{name}\" package, not \"{}\"",
+                pkg.name
+            )
+            .into());
+        }
+
+        let webc_sha256 = WebcHash::sha256(webc.as_ref());
+        let url = format!("webc-override://{webc_sha256}/{name}");
+        let summary = PackageSummary {
+            pkg,
+            dist: DistributionInfo {
+                webc: url.parse()?,
+                webc_sha256,
+            },
+        };
+
+        self.insert(name, Override::Local(summary.clone()));
+        Ok(summary)
+    }
+
+    /// Resolve `name` to a different version (e.g. `"1.2.x"`) instead.
+    pub(crate) fn set_version(&self, name: &str, version: &str) -> Result<(), Error> {
+        let specifier: PackageSpecifier = format!("{name}@{version}").parse()?;
+        self.insert(name, Override::Version(specifier));
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> bool {
+        self.0.lock().unwrap().remove(name).is_some()
+    }
+
+    /// The hash of the local `*.webc` file `name` is currently replaced
+    /// with, if any.
+    pub(crate) fn local_webc(&self, name: &str) -> Option<WebcHash> {
+        match self.0.lock().unwrap().get(name) {
+            Some(Override::Local(summary)) => Some(summary.dist.webc_sha256),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, name: &str, value: Override) {
+        tracing::debug!(%name, ?value, "Overriding a package");
+        self.0.lock().unwrap().insert(name.to_string(), value);
+    }
+
+    fn get(&self, specifier: &PackageSpecifier) -> Option<Override> {
+        let PackageSpecifier::Registry { full_name, .. } = specifier else {
+            return None;
+        };
+
+        self.0.lock().unwrap().get(full_name).cloned()
+    }
+}
+
+/// A [`Source`] which applies [`PackageOverrides`] before falling back to
+/// another source.
+#[derive(Debug, Clone)]
+pub(crate) struct OverridingSource<S> {
+    pub(crate) overrides: PackageOverrides,
+    pub(crate) fallback: S,
+}
+
+#[async_trait::async_trait]
+impl<S> Source for OverridingSource<S>
+where
+    S: Source + Send + Sync,
+{
+    async fn query(&self, package: &PackageSpecifier) -> Result<Vec<PackageSummary>, QueryError> {
+        match self.overrides.get(package) {
+            Some(Override::Local(summary)) => Ok(vec![summary]),
+            Some(Override::Version(specifier)) => self.fallback.query(&specifier).await,
+            None => self.fallback.query(package).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    /// A [`Source`] which remembers every query it was sent.
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    #[async_trait::async_trait]
+    impl Source for Recorder {
+        async fn query(
+            &self,
+            package: &PackageSpecifier,
+        ) -> Result<Vec<PackageSummary>, QueryError> {
+            self.0.lock().unwrap().push(package.to_string());
+            Err(QueryError::Unsupported)
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn override_a_package_version() {
+        let source = OverridingSource {
+            overrides: PackageOverrides::default(),
+            fallback: Recorder::default(),
+        };
+        let bash: PackageSpecifier = "sharrattj/bash@^1.0".parse().unwrap();
+        let coreutils: PackageSpecifier = "sharrattj/coreutils@^1.0".parse().unwrap();
+
+        source
+            .overrides
+            .set_version("sharrattj/bash", "=1.0.12")
+            .unwrap();
+        let _ = source.query(&bash).await;
+        let _ = source.query(&coreutils).await;
+        assert!(source.overrides.remove("sharrattj/bash"));
+        let _ = source.query(&bash).await;
+
+        let expected: Vec<String> = [
+            "sharrattj/bash@=1.0.12",
+            "sharrattj/coreutils@^1.0",
+            "sharrattj/bash@^1.0",
+        ]
+        .iter()
+        .map(|s| s.parse::<PackageSpecifier>().unwrap().to_string())
+        .collect();
+        assert_eq!(*source.fallback.0.lock().unwrap(), expected);
+    }
+}
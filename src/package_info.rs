@@ -0,0 +1,361 @@
+//! Inspecting the atoms and volumes inside a package without running it.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasmer_wasix::runtime::module_cache::ModuleHash;
+use webc::{compat::Metadata, Container};
+
+/// A WebAssembly module bundled with a package.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AtomInfo {
+    pub(crate) name: String,
+    pub(crate) size: usize,
+    pub(crate) hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) kind: Option<String>,
+    pub(crate) features: Vec<&'static str>,
+}
+
+/// A summary of one of a package's volumes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VolumeInfo {
+    pub(crate) name: String,
+    pub(crate) file_count: usize,
+    pub(crate) directory_count: usize,
+    /// The total size of every file in the volume, in bytes.
+    pub(crate) size: usize,
+}
+
+pub(crate) fn atoms(container: &Container) -> Vec<AtomInfo> {
+    let manifest = container.manifest();
+
+    container
+        .atoms()
+        .into_iter()
+        .map(|(name, bytes)| AtomInfo {
+            kind: manifest.atoms.get(&name).map(|atom| atom.kind.to_string()),
+            size: bytes.len(),
+            hash: ModuleHash::hash(&bytes[..]).to_string(),
+            features: wasm_features(&bytes),
+            name,
+        })
+        .collect()
+}
+
+pub(crate) fn volumes(container: &Container) -> Vec<VolumeInfo> {
+    let mut volumes = Vec::new();
+
+    for (name, volume) in container.volumes() {
+        let mut info = VolumeInfo {
+            name,
+            file_count: 0,
+            directory_count: 0,
+            size: 0,
+        };
+        let mut to_visit = vec!["/".to_string()];
+
+        while let Some(dir) = to_visit.pop() {
+            for (segment, metadata) in volume.read_dir(dir.as_str()).unwrap_or_default() {
+                let path = format!("{}/{segment}", dir.trim_end_matches('/'));
+                match metadata {
+                    Metadata::Dir => {
+                        info.directory_count += 1;
+                        to_visit.push(path);
+                    }
+                    Metadata::File { length } => {
+                        info.file_count += 1;
+                        info.size += length;
+                    }
+                }
+            }
+        }
+
+        volumes.push(info);
+    }
+
+    volumes
+}
+
+/// Read a single file out of one of the package's volumes.
+pub(crate) fn read_file(container: &Container, volume: &str, path: &str) -> Option<Vec<u8>> {
+    let volume = container.volumes().swap_remove(volume)?;
+    let file = volume.read_file(path)?;
+    Some(file.to_vec())
+}
+
+/// Guess which non-standard features a WebAssembly module needs by looking at
+/// what it imports.
+fn wasm_features(wasm: &[u8]) -> Vec<&'static str> {
+    let mut features = Vec::new();
+    let mut add = |feature| {
+        if !features.contains(&feature) {
+            features.push(feature);
+        }
+    };
+
+    if uses_gc_types(wasm) {
+        add("gc");
+    }
+
+    for import in imports(wasm).unwrap_or_default() {
+        match import.module {
+            "wasi_snapshot_preview1" | "wasi_unstable" => add("wasi"),
+            "wasix_32v1" | "wasix_64v1" => add("wasix"),
+            _ => {}
+        }
+        if import.shared_memory {
+            add("threads");
+        }
+        if import.memory64 {
+            add("memory64");
+        }
+    }
+
+    features
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Import<'a> {
+    module: &'a str,
+    shared_memory: bool,
+    memory64: bool,
+}
+
+/// A minimal parser for the import section of a WebAssembly binary.
+fn imports(wasm: &[u8]) -> Option<Vec<Import<'_>>> {
+    const IMPORT_SECTION: u8 = 2;
+
+    let mut reader = Reader(wasm.strip_prefix(b"\0asm")?.get(4..)?);
+    let mut imports = Vec::new();
+
+    while !reader.0.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.leb()? as usize;
+        let mut section = Reader(reader.take(len)?);
+        if id != IMPORT_SECTION {
+            continue;
+        }
+
+        for _ in 0..section.leb()? {
+            let module = section.name()?;
+            let _field = section.name()?;
+            let mut import = Import {
+                module,
+                shared_memory: false,
+                memory64: false,
+            };
+
+            match section.byte()? {
+                // function
+                0x00 => {
+                    section.leb()?;
+                }
+                // table
+                0x01 => {
+                    section.val_type()?;
+                    section.limits()?;
+                }
+                // memory
+                0x02 => {
+                    let flags = section.limits()?;
+                    import.shared_memory = flags & 0x02 != 0;
+                    import.memory64 = flags & 0x04 != 0;
+                }
+                // global
+                0x03 => {
+                    section.val_type()?;
+                    section.byte()?;
+                }
+                // tag
+                0x04 => {
+                    section.byte()?;
+                    section.leb()?;
+                }
+                _ => return None,
+            }
+
+            imports.push(import);
+        }
+
+        break;
+    }
+
+    Some(imports)
+}
+
+/// Does the module define any types from the GC proposal (structs, arrays,
+/// recursive groups, subtypes, or typed references)?
+pub(crate) fn uses_gc_types(wasm: &[u8]) -> bool {
+    const TYPE_SECTION: u8 = 1;
+
+    let check = || -> Option<bool> {
+        let mut reader = Reader(wasm.strip_prefix(b"\0asm")?.get(4..)?);
+
+        while !reader.0.is_empty() {
+            let id = reader.byte()?;
+            let len = reader.leb()? as usize;
+            let mut section = Reader(reader.take(len)?);
+            if id != TYPE_SECTION {
+                continue;
+            }
+
+            for _ in 0..section.leb()? {
+                // Anything other than a plain function type needs GC
+                if section.byte()? != 0x60 {
+                    return Some(true);
+                }
+                for _ in 0..2 {
+                    for _ in 0..section.leb()? {
+                        if section.val_type()? {
+                            return Some(true);
+                        }
+                    }
+                }
+            }
+
+            break;
+        }
+
+        Some(false)
+    };
+
+    check().unwrap_or(false)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn leb(&mut self) -> Option<u64> {
+        let mut result = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            result |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let len = self.leb()? as usize;
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+
+    /// Read a value type, returning whether it is a typed reference (i.e.
+    /// `(ref $t)` or `(ref null $t)`).
+    fn val_type(&mut self) -> Option<bool> {
+        match self.byte()? {
+            0x63 | 0x64 => {
+                // The heap type is a signed LEB128 integer
+                self.leb()?;
+                Some(true)
+            }
+            _ => Some(false),
+        }
+    }
+
+    /// Read a set of limits, returning the flags.
+    fn limits(&mut self) -> Option<u8> {
+        let flags = self.byte()?;
+        self.leb()?;
+        if flags & 0x01 != 0 {
+            self.leb()?;
+        }
+        Some(flags)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PACKAGE_INFO_TYPE_DECLARATIONS: &str = r#"
+/**
+ * A WebAssembly module bundled with a package.
+ */
+export type AtomInfo = {
+    name: string;
+    /** The size of the module, in bytes. */
+    size: number;
+    /** The module's hash, as used by the module cache. */
+    hash: string;
+    /** The atom's kind, as recorded in the package manifest. */
+    kind?: string;
+    /**
+     * Features the module needs, guessed from its imports and types (e.g.
+     * `"wasi"`, `"wasix"`, `"threads"`, `"memory64"`, or `"gc"`).
+     */
+    features: string[];
+};
+
+/**
+ * A summary of one of a package's volumes.
+ */
+export type VolumeInfo = {
+    name: string;
+    fileCount: number;
+    directoryCount: number;
+    /** The total size of every file in the volume, in bytes. */
+    size: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "AtomInfo[]")]
+    pub type ListOfAtomInfo;
+
+    #[wasm_bindgen(typescript_type = "VolumeInfo[]")]
+    pub type ListOfVolumeInfo;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn detect_features_from_imports() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (import "env" "memory" (memory 1 1 shared))
+            )"#,
+        )
+        .unwrap();
+
+        assert_eq!(wasm_features(&wasm), ["wasi", "threads"]);
+    }
+
+    #[wasm_bindgen_test]
+    fn detect_gc_types() {
+        // (module (type (struct)))
+        let gc = b"\0asm\x01\0\0\0\x01\x03\x01\x5f\x00";
+        let plain = wasmer::wat2wasm(b"(module (type (func (param i32) (result i64))))").unwrap();
+
+        assert!(uses_gc_types(gc));
+        assert_eq!(wasm_features(gc), ["gc"]);
+        assert!(!uses_gc_types(&plain));
+    }
+
+    #[wasm_bindgen_test]
+    fn modules_without_imports_need_nothing() {
+        let wasm = wasmer::wat2wasm(b"(module)").unwrap();
+
+        assert!(wasm_features(&wasm).is_empty());
+    }
+}
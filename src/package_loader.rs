@@ -1,11 +1,16 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::{Context, Error};
 use bytes::Bytes;
+use futures::channel::oneshot;
 use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use once_cell::sync::Lazy;
 use wasmer_wasix::{
     bin_factory::BinaryPackage,
     http::{HttpClient, HttpRequest, HttpResponse},
@@ -13,6 +18,8 @@ use wasmer_wasix::{
 };
 use webc::Container;
 
+use crate::blobs::{Blob, BlobStore};
+
 /// A package loader that uses the browser's native APIs to download packages.
 ///
 /// Downloads will be cached based on the [`default`] caching behaviour.
@@ -22,12 +29,84 @@ use webc::Container;
 pub struct PackageLoader {
     client: Arc<dyn HttpClient + Send + Sync>,
     cache: Arc<Cache>,
+    /// Packages provided locally (e.g. to override a dependency), which are
+    /// never evicted.
+    pinned: Arc<Mutex<HashMap<WebcHash, Blob>>>,
+    /// Downloads this loader is making.
+    in_flight: InFlightDownloads,
+    /// Join downloads made by any runtime on the page instead of only our
+    /// own.
+    share_downloads: Arc<AtomicBool>,
 }
 
 impl PackageLoader {
     pub fn new(client: Arc<dyn HttpClient + Send + Sync>) -> Self {
         let cache = Arc::new(Cache::default());
-        PackageLoader { client, cache }
+        PackageLoader {
+            client,
+            cache,
+            pinned: Arc::default(),
+            in_flight: InFlightDownloads::default(),
+            share_downloads: Arc::default(),
+        }
+    }
+
+    /// Share in-flight downloads with every other runtime on the page, not
+    /// just instances using this one.
+    ///
+    /// Packages are identified by their hash, so this is safe even when the
+    /// runtimes use different registries.
+    pub(crate) fn set_share_downloads(&self, shared: bool) {
+        self.share_downloads.store(shared, Ordering::Relaxed);
+    }
+
+    fn in_flight(&self) -> &InFlightDownloads {
+        if self.share_downloads.load(Ordering::Relaxed) {
+            &SHARED_DOWNLOADS
+        } else {
+            &self.in_flight
+        }
+    }
+
+    /// Make a package's `*.webc` file available without downloading it.
+    pub(crate) fn pin(&self, hash: WebcHash, webc: Bytes) -> Result<(), Error> {
+        let blob = BlobStore::global().insert_with_hash(hash, webc)?;
+        self.pinned.lock().unwrap().insert(hash, blob);
+        Ok(())
+    }
+
+    /// Stop making a package provided with [`PackageLoader::pin()`]
+    /// available, freeing it if nothing else is using it.
+    pub(crate) fn unpin(&self, hash: &WebcHash) {
+        if self.pinned.lock().unwrap().remove(hash).is_some() {
+            BlobStore::global().collect_garbage();
+        }
+    }
+
+    /// Drop every cached and pinned package.
+    pub(crate) fn clear(&self) {
+        self.pinned.lock().unwrap().clear();
+        self.evict();
+    }
+
+    /// Drop every cached package, keeping the pinned ones, and return how
+    /// many bytes were freed.
+    pub(crate) fn evict(&self) -> u64 {
+        if let Ok(mut cache) = self.cache.0.lock() {
+            cache.entries.clear();
+            cache.order.clear();
+            cache.size = 0;
+        }
+        BlobStore::global().collect_garbage()
+    }
+
+    /// Limit the cache to this runtime's share of the space being split
+    /// between several runtimes, evicting the oldest packages if the cache is
+    /// already too big.
+    ///
+    /// Passing `None` removes the limit.
+    pub(crate) fn set_cache_share(&self, share: Option<u64>) {
+        self.cache.set_share(share);
     }
 
     async fn download(&self, dist: &DistributionInfo) -> Result<Bytes, Error> {
@@ -72,21 +151,137 @@ impl PackageLoader {
     pub(crate) async fn download_cached(&self, dist: &DistributionInfo) -> Result<Bytes, Error> {
         let webc_hash = dist.webc_sha256;
 
+        if let Some(webc) = self.pinned.lock().unwrap().get(&webc_hash) {
+            return Ok(webc.bytes());
+        }
+
         let body = match self.cache.load(&webc_hash) {
             Some(body) => {
                 tracing::debug!("Cache Hit!");
                 body
             }
-            None => {
-                tracing::debug!("Cache Miss");
-                let bytes = self.download(dist).await?;
-                self.cache.save(webc_hash, bytes.clone());
-                bytes
-            }
+            // Note: another runtime on the page may have already loaded it
+            None => match BlobStore::global().get(&webc_hash) {
+                Some(blob) => {
+                    tracing::debug!("Reusing a package loaded elsewhere");
+                    let body = blob.bytes();
+                    self.cache.save(blob);
+                    body
+                }
+                None => {
+                    tracing::debug!("Cache Miss");
+                    self.download_once(dist).await?
+                }
+            },
         };
 
         Ok(body)
     }
+
+    /// Download a package, or wait for the download if someone else is
+    /// already fetching it.
+    async fn download_once(&self, dist: &DistributionInfo) -> Result<Bytes, Error> {
+        let hash = dist.webc_sha256;
+
+        while let Some(receiver) = self.in_flight().join(hash) {
+            tracing::debug!(%hash, "Waiting for a download that is already in progress");
+            match receiver.await {
+                Ok(result) => {
+                    let bytes = result.map_err(Error::msg)?;
+                    let blob = BlobStore::global().insert_with_hash(hash, bytes)?;
+                    let bytes = blob.bytes();
+                    self.cache.save(blob);
+                    return Ok(bytes);
+                }
+                // Whoever was downloading it gave up, so try again
+                Err(_) => continue,
+            }
+        }
+
+        let guard = PendingDownload {
+            hash,
+            in_flight: self.in_flight().clone(),
+        };
+        let result = self.download(dist).await;
+
+        // Note: hand out the stored copy, in case it was already there
+        let result = result.and_then(|bytes| {
+            let url = &dist.webc;
+            let blob = BlobStore::global()
+                .insert_with_hash(hash, bytes)
+                .with_context(|| format!("The package downloaded from \"{url}\" was corrupted"))?;
+            let bytes = blob.bytes();
+            self.cache.save(blob);
+            Ok(bytes)
+        });
+        guard.finish(&result);
+
+        result
+    }
+}
+
+type DownloadResult = Result<Bytes, String>;
+
+/// Downloads in progress, and who is waiting on them.
+#[derive(Debug, Clone, Default)]
+struct InFlightDownloads(Arc<Mutex<HashMap<WebcHash, Vec<oneshot::Sender<DownloadResult>>>>>);
+
+/// Downloads shared by every runtime that opted in with
+/// [`PackageLoader::set_share_downloads()`].
+static SHARED_DOWNLOADS: Lazy<InFlightDownloads> = Lazy::new(InFlightDownloads::default);
+
+impl InFlightDownloads {
+    /// Wait for the package with this `hash` if it is already being
+    /// downloaded, otherwise mark it as in progress and return `None`.
+    fn join(&self, hash: WebcHash) -> Option<oneshot::Receiver<DownloadResult>> {
+        let mut in_flight = self.0.lock().unwrap();
+        match in_flight.get_mut(&hash) {
+            Some(waiters) => {
+                let (sender, receiver) = oneshot::channel();
+                waiters.push(sender);
+                Some(receiver)
+            }
+            None => {
+                in_flight.insert(hash, Vec::new());
+                None
+            }
+        }
+    }
+}
+
+/// Removes a download from its [`InFlightDownloads`] even if it gets
+/// cancelled, so anyone waiting on it can try again instead of hanging.
+struct PendingDownload {
+    hash: WebcHash,
+    in_flight: InFlightDownloads,
+}
+
+impl PendingDownload {
+    fn finish(self, result: &Result<Bytes, Error>) {
+        let waiters = self
+            .in_flight
+            .0
+            .lock()
+            .unwrap()
+            .remove(&self.hash)
+            .unwrap_or_default();
+
+        for waiter in waiters {
+            let result = match result {
+                Ok(bytes) => Ok(bytes.clone()),
+                Err(e) => Err(format!("{e:?}")),
+            };
+            let _ = waiter.send(result);
+        }
+    }
+}
+
+impl Drop for PendingDownload {
+    fn drop(&mut self) {
+        // Note: dropping the senders lets any remaining waiters know the
+        // download was abandoned.
+        self.in_flight.0.lock().unwrap().remove(&self.hash);
+    }
 }
 
 #[async_trait::async_trait]
@@ -137,26 +332,176 @@ pub(crate) fn http_error(response: &HttpResponse) -> Error {
 
 /// A quick'n'dirty cache for downloaded packages.
 ///
+/// The bytes themselves live in the global [`BlobStore`], so packages cached
+/// by several runtimes are only kept in memory once.
+///
 /// This makes no attempt at verifying a cached
 #[derive(Debug, Default)]
-struct Cache(Mutex<HashMap<WebcHash, Bytes>>);
+struct Cache(Mutex<CacheState>);
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<WebcHash, Blob>,
+    /// Cached packages, oldest first.
+    order: VecDeque<WebcHash>,
+    size: u64,
+    /// Our share of the bytes an [`crate::arbiter`] is splitting between
+    /// several runtimes, if any.
+    share: Option<u64>,
+}
+
+impl CacheState {
+    /// Evict the oldest entries until we are back under budget.
+    fn shrink(&mut self) {
+        let Some(budget) = self.share else {
+            return;
+        };
+
+        while self.size > budget {
+            let Some(hash) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(blob) = self.entries.remove(&hash) {
+                tracing::debug!(%hash, size = blob.len(), budget, "Evicting a cached package");
+                self.size -= blob.len() as u64;
+            }
+        }
+    }
+}
 
 impl Cache {
     fn load(&self, hash: &WebcHash) -> Option<Bytes> {
         let cache = self.0.lock().ok()?;
-        let bytes = cache.get(hash)?;
-        Some(bytes.clone())
+        let blob = cache.entries.get(hash)?;
+        Some(blob.bytes())
     }
 
-    fn save(&self, hash: WebcHash, bytes: Bytes) {
-        debug_assert_eq!(
-            hash,
-            WebcHash::sha256(bytes.as_ref()),
-            "Mismatched webc hash"
-        );
+    fn save(&self, blob: Blob) {
+        let hash = blob.hash();
+
+        if let Ok(mut cache) = self.0.lock() {
+            if cache.share.is_some_and(|budget| blob.len() as u64 > budget) {
+                tracing::debug!(%hash, size = blob.len(), "Package is too big to cache");
+                return;
+            }
+
+            let size = blob.len() as u64;
+            if let Some(previous) = cache.entries.insert(hash, blob) {
+                cache.size -= previous.len() as u64;
+                cache.order.retain(|h| *h != hash);
+            }
+            cache.size += size;
+            cache.order.push_back(hash);
+            cache.shrink();
+        }
+    }
 
+    fn set_share(&self, share: Option<u64>) {
         if let Ok(mut cache) = self.0.lock() {
-            cache.insert(hash, bytes);
+            cache.share = share;
+            cache.shrink();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use futures::future::BoxFuture;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    /// Holds the first response back until it is released.
+    #[derive(Debug)]
+    struct GatedClient {
+        requests: AtomicUsize,
+        gate: Mutex<Option<oneshot::Receiver<()>>>,
+        body: Vec<u8>,
+    }
+
+    impl HttpClient for GatedClient {
+        fn request(&self, _: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let gate = self.gate.lock().unwrap().take();
+
+            Box::pin(async move {
+                if let Some(gate) = gate {
+                    let _ = gate.await;
+                }
+                Ok(HttpResponse {
+                    body: Some(self.body.clone()),
+                    redirected: false,
+                    status: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                })
+            })
+        }
+    }
+
+    fn save(cache: &Cache, len: usize) -> WebcHash {
+        let blob = BlobStore::default().insert(Bytes::from(vec![len as u8; len]));
+        let hash = blob.hash();
+        cache.save(blob);
+        hash
+    }
+
+    #[wasm_bindgen_test]
+    fn the_oldest_packages_are_evicted_when_over_budget() {
+        let cache = Cache::default();
+        let first = save(&cache, 10);
+        let second = save(&cache, 20);
+
+        cache.set_share(Some(25));
+
+        assert!(cache.load(&first).is_none());
+        assert!(cache.load(&second).is_some());
+        // Packages bigger than our whole share are never cached
+        let huge = save(&cache, 30);
+        assert!(cache.load(&huge).is_none());
+        assert!(cache.load(&second).is_some());
+    }
+
+    #[wasm_bindgen_test]
+    async fn concurrent_downloads_share_one_request() {
+        let (release, gate) = oneshot::channel();
+        let body = b"not really a webc".to_vec();
+        let client = Arc::new(GatedClient {
+            requests: AtomicUsize::new(0),
+            gate: Mutex::new(Some(gate)),
+            body: body.clone(),
+        });
+        let loader = PackageLoader::new(client.clone());
+        let dist = DistributionInfo {
+            webc: "https://example.com/python.webc".parse().unwrap(),
+            webc_sha256: WebcHash::sha256(&body),
+        };
+
+        let first = loader.download_cached(&dist);
+        let second = loader.download_cached(&dist);
+        let release = async { release.send(()).unwrap() };
+        let (first, second, _) = futures::join!(first, second, release);
+
+        assert_eq!(first.unwrap(), body);
+        assert_eq!(second.unwrap(), body);
+        assert_eq!(client.requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn unpinned_packages_are_forgotten() {
+        let loader = PackageLoader::new(Arc::new(GatedClient {
+            requests: AtomicUsize::new(0),
+            gate: Mutex::new(None),
+            body: Vec::new(),
+        }));
+        let webc = Bytes::from_static(b"an overridden package");
+        let hash = WebcHash::sha256(&webc);
+
+        loader.pin(hash, webc).unwrap();
+        assert!(loader.pinned.lock().unwrap().contains_key(&hash));
+
+        loader.unpin(&hash);
+        assert!(loader.pinned.lock().unwrap().is_empty());
+    }
+}
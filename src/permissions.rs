@@ -0,0 +1,407 @@
+//! Asking the user before a program does something sensitive.
+//!
+//! With `onPermissionRequest`, a program is paused the first time it tries
+//! to connect to a new host (or fetch from an origin `hostFetch` hasn't
+//! approved) while the host asks the user whether to allow it. The callback
+//! can show whatever UI it likes and return a promise. Its answer can ask
+//! for the decision to be remembered, in which case the same question isn't
+//! asked again for the rest of the runtime's life.
+//!
+//! Connections which are denied fail with "permission denied", and so do
+//! fetches (with `EACCES`).
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::channel::oneshot;
+use js_sys::{Function, Promise};
+use serde::{Deserialize, Serialize};
+use virtual_net::{
+    IpCidr, IpRoute, NetworkError, StreamSecurity, VirtualIcmpSocket, VirtualNetworking,
+    VirtualRawSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::tasks::ThreadPool;
+
+thread_local! {
+    /// `onPermissionRequest` callbacks, which live on the scheduler's thread.
+    static PROMPTERS: RefCell<BTreeMap<u32, Function>> = RefCell::default();
+}
+
+/// Something a program wants to do which the user should agree to first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum PermissionRequest {
+    /// Opening a TCP connection to a host for the first time.
+    Connect { host: String, port: u16 },
+    /// Making an HTTP request through `host_fetch` to an origin the host
+    /// hasn't approved.
+    Fetch { origin: String },
+}
+
+impl PermissionRequest {
+    /// Decisions are remembered per host (or origin), regardless of port.
+    fn key(&self) -> (&'static str, String) {
+        match self {
+            PermissionRequest::Connect { host, .. } => ("connect", host.clone()),
+            PermissionRequest::Fetch { origin } => ("fetch", origin.clone()),
+        }
+    }
+}
+
+/// The callback's answer.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize)]
+struct Decision {
+    allow: bool,
+    #[serde(default)]
+    remember: bool,
+}
+
+impl Decision {
+    /// Interpret whatever the callback returned, denying anything we don't
+    /// understand.
+    fn parse(value: JsValue) -> Decision {
+        if let Some(allow) = value.as_bool() {
+            return Decision {
+                allow,
+                remember: false,
+            };
+        }
+
+        serde_wasm_bindgen::from_value(value).unwrap_or_default()
+    }
+}
+
+/// Asks the host about sensitive operations, remembering its decisions when
+/// asked to.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PermissionPrompt(Arc<Mutex<PromptState>>);
+
+#[derive(Debug, Default)]
+struct PromptState {
+    prompter: Option<u32>,
+    remembered: BTreeMap<(&'static str, String), bool>,
+}
+
+impl PermissionPrompt {
+    /// Ask `callback` about every sensitive operation.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn set_prompter(&self, callback: Function) {
+        static NEXT_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        PROMPTERS.with(|prompters| prompters.borrow_mut().insert(id, callback));
+        self.0.lock().unwrap().prompter = Some(id);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.lock().unwrap().prompter.is_some()
+    }
+
+    /// Should `request` be allowed?
+    ///
+    /// Without a callback, everything is allowed.
+    pub(crate) async fn check(&self, request: PermissionRequest, pool: &ThreadPool) -> bool {
+        let key = request.key();
+        let prompter = {
+            let state = self.0.lock().unwrap();
+            if let Some(&allow) = state.remembered.get(&key) {
+                return allow;
+            }
+            state.prompter
+        };
+
+        let Some(prompter) = prompter else {
+            return true;
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let asked = request.clone();
+        pool.run_on_scheduler(Box::new(move || {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = sender.send(ask(prompter, &asked).await);
+            });
+        }));
+
+        let decision = receiver.await.unwrap_or_default();
+        tracing::debug!(?request, ?decision, "Asked the host for permission");
+        if decision.remember {
+            self.0
+                .lock()
+                .unwrap()
+                .remembered
+                .insert(key, decision.allow);
+        }
+        decision.allow
+    }
+}
+
+/// Call an `onPermissionRequest` callback, treating errors as a denial.
+async fn ask(prompter: u32, request: &PermissionRequest) -> Decision {
+    let Some(callback) = PROMPTERS.with(|prompters| prompters.borrow().get(&prompter).cloned())
+    else {
+        return Decision::default();
+    };
+    let Ok(request) = serde_wasm_bindgen::to_value(request) else {
+        return Decision::default();
+    };
+
+    let outcome = match callback.call1(&JsValue::NULL, &request) {
+        Ok(value) => match value.dyn_into::<Promise>() {
+            Ok(promise) => JsFuture::from(promise).await,
+            Err(value) => Ok(value),
+        },
+        Err(e) => Err(e),
+    };
+
+    match outcome {
+        Ok(value) => Decision::parse(value),
+        Err(e) => {
+            tracing::warn!(error = ?e, "The onPermissionRequest callback failed");
+            Decision::default()
+        }
+    }
+}
+
+/// [`VirtualNetworking`] which asks for permission before connecting to a
+/// new host.
+#[derive(Debug)]
+pub(crate) struct PromptingNetworking {
+    inner: Arc<dyn VirtualNetworking>,
+    prompt: PermissionPrompt,
+    pool: ThreadPool,
+    /// The hostnames guests looked addresses up for, so the user is asked
+    /// about `example.com` rather than an IP address where possible.
+    hostnames: Mutex<HashMap<IpAddr, String>>,
+}
+
+impl PromptingNetworking {
+    pub(crate) fn new(
+        inner: Arc<dyn VirtualNetworking>,
+        prompt: PermissionPrompt,
+        pool: ThreadPool,
+    ) -> Self {
+        PromptingNetworking {
+            inner,
+            prompt,
+            pool,
+            hostnames: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for PromptingNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> virtual_net::Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> virtual_net::Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> virtual_net::Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> virtual_net::Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> virtual_net::Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> virtual_net::Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> virtual_net::Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> virtual_net::Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> virtual_net::Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> virtual_net::Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> virtual_net::Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> virtual_net::Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> virtual_net::Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> virtual_net::Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> virtual_net::Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> virtual_net::Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn bind_icmp(
+        &self,
+        addr: IpAddr,
+    ) -> virtual_net::Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> virtual_net::Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let host = self
+            .hostnames
+            .lock()
+            .unwrap()
+            .get(&peer.ip())
+            .cloned()
+            .unwrap_or_else(|| peer.ip().to_string());
+        let request = PermissionRequest::Connect {
+            host,
+            port: peer.port(),
+        };
+
+        if !self.prompt.check(request, &self.pool).await {
+            tracing::debug!(%peer, "Denied a guest connection");
+            return Err(NetworkError::PermissionDenied);
+        }
+
+        self.inner.connect_tcp(addr, peer).await
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> virtual_net::Result<Vec<IpAddr>> {
+        let addresses = self.inner.resolve(host, port, dns_server).await?;
+
+        if host.parse::<IpAddr>().is_err() {
+            let mut hostnames = self.hostnames.lock().unwrap();
+            for address in &addresses {
+                hostnames.insert(*address, host.to_ascii_lowercase());
+            }
+        }
+
+        Ok(addresses)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PERMISSION_TYPE_DEFS: &'static str = r#"
+/**
+ * Something a program wants to do, passed to
+ * {@link RuntimeOptions.onPermissionRequest}.
+ *
+ * - `"connect"` is the first TCP connection to `host` (a hostname if the
+ *   program looked one up, otherwise an IP address)
+ * - `"fetch"` is a `host_fetch` request to an origin which `hostFetch`
+ *   hasn't approved
+ */
+export type PermissionRequest =
+    | { kind: "connect"; host: string; port: number }
+    | { kind: "fetch"; origin: string };
+
+/**
+ * The answer to a {@link PermissionRequest}.
+ *
+ * A boolean only applies to this request. Set `remember` to reuse the
+ * decision for every later request for the same host or origin.
+ */
+export type PermissionDecision = boolean | { allow: boolean; remember?: boolean };
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn remembered_decisions_are_reused() {
+        let prompt = PermissionPrompt::default();
+        let pool = ThreadPool::new();
+        let connect = |host: &str| PermissionRequest::Connect {
+            host: host.to_string(),
+            port: 443,
+        };
+
+        // Without a callback, nothing is held up
+        assert!(prompt.check(connect("example.com"), &pool).await);
+
+        prompt.set_prompter(Function::new_with_args(
+            "request",
+            "globalThis.permissionPrompts = (globalThis.permissionPrompts ?? 0) + 1;
+             return { allow: request.host === 'example.com', remember: true };",
+        ));
+        assert!(prompt.check(connect("example.com"), &pool).await);
+        assert!(!prompt.check(connect("evil.example"), &pool).await);
+        assert!(prompt.check(connect("example.com"), &pool).await);
+
+        let prompts = js_sys::Reflect::get(&js_sys::global(), &"permissionPrompts".into()).unwrap();
+        assert_eq!(prompts, 2);
+
+        assert_eq!(
+            Decision::parse(JsValue::TRUE),
+            Decision {
+                allow: true,
+                remember: false
+            }
+        );
+        assert_eq!(Decision::parse("yes".into()), Decision::default());
+    }
+}
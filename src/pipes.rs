@@ -0,0 +1,259 @@
+//! Extra data channels between JavaScript and guests, beyond the three
+//! standard streams.
+//!
+//! Each pipe created with `runtime.createPipe()` shows up as a device under
+//! `/dev/pipes` in every program started with that runtime. Anything the
+//! guest writes to the device can be read from the pipe's `readable`, and
+//! anything written to its `writable` can be read by the guest.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{Pipe, VirtualFile};
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::fs::{Device, DeviceFileSystem, Opener};
+
+/// Where the pipe devices get mounted.
+pub(crate) const MOUNT_POINT: &str = "/dev/pipes";
+
+/// Would mounting the pipes clash with something the user mounted at
+/// `mount_point`?
+pub(crate) fn conflicts_with(mount_point: &str) -> bool {
+    crate::locale::conflicts_with(MOUNT_POINT, mount_point)
+}
+
+/// Every pipe created by a runtime.
+///
+/// The same [`DeviceFileSystem`] is mounted into each program, so pipes
+/// created after a program started are visible to it too.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PipeTable {
+    fs: DeviceFileSystem,
+    next_id: Arc<AtomicU32>,
+}
+
+impl PipeTable {
+    pub(crate) fn create(&self) -> HostPipe {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let name = id.to_string();
+
+        let (guest_input, writable) = crate::streams::input_pipe();
+        let (guest_output, readable) = crate::streams::output_pipe();
+        let file = DuplexFile {
+            input: guest_input,
+            output: guest_output,
+        };
+
+        let opener = Opener::new({
+            let file = file.clone();
+            move || Box::new(file.clone())
+        });
+        self.fs.insert(&name, Device::Opener(opener));
+
+        HostPipe {
+            path: format!("{MOUNT_POINT}/{name}"),
+            name,
+            readable,
+            writable,
+            file,
+            table: self.clone(),
+        }
+    }
+
+    /// A directory containing each pipe's device, ready to be mounted at
+    /// [`MOUNT_POINT`].
+    pub(crate) fn filesystem(&self) -> DeviceFileSystem {
+        self.fs.clone()
+    }
+}
+
+/// A connected pair of streams for exchanging data with guests, created by
+/// {@link Runtime.createPipe}.
+///
+/// Programs open the pipe using its {@link HostPipe.path}, which is usually
+/// passed to them as an argument or environment variable.
+///
+/// @example
+/// ```ts
+/// const pipe = runtime.createPipe();
+/// const instance = await pkg.entrypoint.run({
+///     runtime,
+///     args: ["--events", pipe.path],
+/// });
+/// for await (const chunk of pipe.readable) { ... }
+/// ```
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct HostPipe {
+    name: String,
+    path: String,
+    readable: web_sys::ReadableStream,
+    writable: web_sys::WritableStream,
+    file: DuplexFile,
+    table: PipeTable,
+}
+
+#[wasm_bindgen]
+impl HostPipe {
+    /// Where guests can open the pipe (e.g. `/dev/pipes/0`).
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// Data written to the pipe by guests.
+    #[wasm_bindgen(getter)]
+    pub fn readable(&self) -> web_sys::ReadableStream {
+        self.readable.clone()
+    }
+
+    /// Data that guests will read from the pipe.
+    #[wasm_bindgen(getter)]
+    pub fn writable(&self) -> web_sys::WritableStream {
+        self.writable.clone()
+    }
+
+    /// Remove the pipe from `/dev/pipes` and signal EOF in both directions.
+    ///
+    /// Guests which already have the pipe open will see EOF, and new
+    /// programs won't be able to open it.
+    pub fn close(&self) {
+        self.table.fs.remove(&self.name);
+        self.file.input.close();
+        self.file.output.close();
+    }
+}
+
+/// The guest's side of a [`HostPipe`], reading what JavaScript wrote and
+/// writing what JavaScript will read.
+#[derive(Debug, Clone)]
+struct DuplexFile {
+    input: Pipe,
+    output: Pipe,
+}
+
+impl VirtualFile for DuplexFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(virtual_fs::FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.output).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for DuplexFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DuplexFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.output).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.output).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.output).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for DuplexFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use js_sys::Uint8Array;
+    use virtual_fs::{AsyncReadExt, AsyncWriteExt, FileSystem};
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn guests_and_javascript_can_talk_over_a_pipe() {
+        let pipes = PipeTable::default();
+        let pipe = pipes.create();
+        assert_eq!(pipe.path(), "/dev/pipes/0");
+
+        let mut guest = pipes
+            .filesystem()
+            .new_open_options()
+            .read(true)
+            .write(true)
+            .open("/0")
+            .unwrap();
+
+        let writer = pipe.writable().get_writer().unwrap();
+        let chunk = Uint8Array::from(b"ping".as_ref());
+        JsFuture::from(writer.write_with_chunk(&chunk))
+            .await
+            .unwrap();
+        let mut request = [0; 4];
+        guest.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"ping");
+
+        guest.write_all(b"pong").await.unwrap();
+        pipe.close();
+        let response = crate::streams::read_to_end(pipe.readable())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(response, b"pong");
+        assert!(pipes.filesystem().metadata("/0".as_ref()).is_err());
+    }
+}
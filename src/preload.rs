@@ -0,0 +1,252 @@
+//! Telling the browser about the assets the SDK needs ahead of time.
+//!
+//! Starting the first worker means fetching the `wasm-bindgen` glue code (and
+//! the worker script, if one was set with `setWorkerScriptUrl()`), which
+//! normally only happens once a program is run. Knowing the URLs up front
+//! lets pages preload them while everything else is loading.
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::utils::{Error, GlobalScope};
+
+/// The name `wasm-bindgen` gives our WebAssembly binary, which lives next to
+/// the glue code.
+const WASM_FILE_NAME: &str = "wasmer_js_bg.wasm";
+
+/// A URL that should be preloaded, in a form that can be turned into a
+/// `<link>` element.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreloadHint {
+    pub(crate) href: String,
+    pub(crate) rel: String,
+    #[serde(rename = "as")]
+    pub(crate) destination: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) crossorigin: Option<String>,
+    /// Changes whenever the asset does, so it can be used to version the URL
+    /// and serve it with a long-lived `Cache-Control` header.
+    ///
+    /// This is only known for assets the SDK ships itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_key: Option<String>,
+}
+
+impl PreloadHint {
+    fn new(href: String, rel: &str, destination: &str, cache_key: Option<String>) -> Self {
+        PreloadHint {
+            href,
+            rel: rel.to_string(),
+            destination: destination.to_string(),
+            crossorigin: Some("anonymous".to_string()),
+            cache_key,
+        }
+    }
+}
+
+/// Work out what should be preloaded.
+///
+/// `worker_script` is only included when it isn't the embedded `blob:` URL,
+/// which never touches the network.
+pub(crate) fn hints(
+    glue_url: &str,
+    wasm_url: Option<&str>,
+    worker_script: Option<&str>,
+) -> Vec<PreloadHint> {
+    let version = Some(env!("CARGO_PKG_VERSION").to_string());
+    let mut hints = vec![PreloadHint::new(
+        glue_url.to_string(),
+        "modulepreload",
+        "script",
+        version.clone(),
+    )];
+
+    if let Some(wasm) = wasm_url {
+        hints.push(PreloadHint::new(
+            wasm.to_string(),
+            "preload",
+            "fetch",
+            version,
+        ));
+    }
+
+    if let Some(script) = worker_script {
+        // Note: the page serves custom worker scripts itself and may have
+        // changed them, so we can't tell when their contents change
+        hints.push(PreloadHint::new(
+            script.to_string(),
+            "preload",
+            "script",
+            None,
+        ));
+    }
+
+    hints
+}
+
+/// Where the WebAssembly binary is served from, preferring the URL the
+/// bundled SDK was built with.
+fn wasm_url(glue_url: &str) -> Option<String> {
+    let scope = GlobalScope::current();
+    if let Some(url) = scope.lookup(&["wasmUrl"]).and_then(|url| url.as_string()) {
+        return Some(url);
+    }
+
+    let glue: url::Url = glue_url.parse().ok()?;
+    glue.join(WASM_FILE_NAME).ok().map(String::from)
+}
+
+fn current_hints() -> Vec<PreloadHint> {
+    let glue = crate::tasks::import_meta_url();
+    let wasm = wasm_url(&glue);
+    let worker_script = crate::CUSTOM_WORKER_SCRIPT_URL.lock().unwrap().clone();
+
+    hints(&glue, wasm.as_deref(), worker_script.as_deref())
+}
+
+/// Add a `<link>` for each hint that the document doesn't already have.
+fn inject(document: &web_sys::Document, hints: &[PreloadHint]) -> Result<(), Error> {
+    let Some(head) = document.head() else {
+        return Ok(());
+    };
+
+    for hint in hints {
+        let selector = format!("link[href=\"{}\"]", hint.href.replace('"', "\\\""));
+        if document
+            .query_selector(&selector)
+            .map_err(Error::js)?
+            .is_some()
+        {
+            continue;
+        }
+
+        let link = document.create_element("link").map_err(Error::js)?;
+        link.set_attribute("rel", &hint.rel).map_err(Error::js)?;
+        link.set_attribute("href", &hint.href).map_err(Error::js)?;
+        link.set_attribute("as", &hint.destination)
+            .map_err(Error::js)?;
+        if let Some(crossorigin) = &hint.crossorigin {
+            link.set_attribute("crossorigin", crossorigin)
+                .map_err(Error::js)?;
+        }
+        head.append_child(&link).map_err(Error::js)?;
+    }
+
+    Ok(())
+}
+
+/// Warm the HTTP cache directly when there's no document to add `<link>`s
+/// to (i.e. inside a worker).
+fn prefetch(scope: &GlobalScope, hints: &[PreloadHint]) {
+    for hint in hints {
+        let mut init = web_sys::RequestInit::new();
+        init.cache(web_sys::RequestCache::ForceCache);
+        init.mode(web_sys::RequestMode::Cors);
+
+        let Ok(request) = web_sys::Request::new_with_str_and_init(&hint.href, &init) else {
+            continue;
+        };
+        let href = hint.href.clone();
+        let response = wasm_bindgen_futures::JsFuture::from(scope.fetch(&request));
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Err(e) = response.await {
+                tracing::debug!(%href, error = ?e, "Unable to prefetch");
+            }
+        });
+    }
+}
+
+/// See [`crate::Wasmer::preload()`].
+pub(crate) fn preload(options: Option<PreloadOptions>) -> Result<ListOfPreloadHint, Error> {
+    let hints = current_hints();
+
+    if options.and_then(|opts| opts.fetch()).unwrap_or(true) {
+        match GlobalScope::current() {
+            GlobalScope::Window(window) => {
+                if let Some(document) = window.document() {
+                    inject(&document, &hints)?;
+                }
+            }
+            scope => prefetch(&scope, &hints),
+        }
+    }
+
+    let value = serde_wasm_bindgen::to_value(&hints).map_err(Error::js)?;
+    Ok(value.unchecked_into())
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PRELOAD_TYPE_DEFINITIONS: &'static str = r#"
+/**
+ * An asset that should be preloaded, with the attributes for its `<link>`
+ * element.
+ */
+export type PreloadHint = {
+    href: string;
+    rel: "preload" | "modulepreload";
+    as: "script" | "fetch";
+    crossorigin?: "anonymous";
+    /**
+     * Changes whenever the asset's contents do (i.e. the SDK version), so it
+     * can be added to the URL and served with a long-lived
+     * `Cache-Control: immutable` header.
+     *
+     * Missing for a custom worker script, because the SDK can't tell when
+     * the page changes it.
+     */
+    cacheKey?: string;
+};
+
+/**
+ * Options for {@link Wasmer.preload}.
+ */
+export type PreloadOptions = {
+    /**
+     * Start fetching the assets straight away, by adding `<link>` elements
+     * to the document or (inside a worker) calling `fetch()`.
+     *
+     * Defaults to `true`. Set to `false` to just get the hints (e.g. to
+     * render them into a page's HTML on the server).
+     */
+    fetch?: boolean;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "PreloadOptions")]
+    pub type PreloadOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn fetch(this: &PreloadOptions) -> Option<bool>;
+
+    #[wasm_bindgen(typescript_type = "PreloadHint[]")]
+    pub type ListOfPreloadHint;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_custom_worker_scripts_are_preloaded() {
+        let glue = "https://unpkg.com/@wasmer/sdk/dist/WasmerSDK.js";
+        let wasm = wasm_url(glue);
+
+        let embedded = hints(glue, wasm.as_deref(), None);
+        let custom = hints(glue, wasm.as_deref(), Some("/worker.js"));
+
+        assert_eq!(embedded[0].rel, "modulepreload");
+        assert_eq!(
+            embedded[1].href,
+            "https://unpkg.com/@wasmer/sdk/dist/wasmer_js_bg.wasm"
+        );
+        assert_eq!(embedded.len(), 2);
+        assert_eq!(custom.len(), 3);
+        assert!(embedded[0].cache_key.is_some());
+        assert_eq!(custom[2].cache_key, None);
+    }
+}
@@ -0,0 +1,264 @@
+//! A runtime-wide table of running programs which can be sent signals.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasmer_wasix::{types::wasi::Signal, WasiProcess};
+
+use crate::utils::Error;
+
+/// Signal names, as used by `kill(1)`.
+const SIGNALS: &[(&str, Signal)] = &[
+    ("SIGHUP", Signal::Sighup),
+    ("SIGINT", Signal::Sigint),
+    ("SIGQUIT", Signal::Sigquit),
+    ("SIGKILL", Signal::Sigkill),
+    ("SIGUSR1", Signal::Sigusr1),
+    ("SIGUSR2", Signal::Sigusr2),
+    ("SIGPIPE", Signal::Sigpipe),
+    ("SIGALRM", Signal::Sigalrm),
+    ("SIGTERM", Signal::Sigterm),
+    ("SIGCHLD", Signal::Sigchld),
+    ("SIGCONT", Signal::Sigcont),
+    ("SIGSTOP", Signal::Sigstop),
+    ("SIGTSTP", Signal::Sigtstp),
+    ("SIGTTIN", Signal::Sigttin),
+    ("SIGTTOU", Signal::Sigttou),
+    ("SIGWINCH", Signal::Sigwinch),
+];
+
+/// Every program started on a runtime, keyed by PID.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProcessTable(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    processes: BTreeMap<u32, Process>,
+    /// The job currently in the foreground, in the shell sense.
+    foreground: Option<u32>,
+}
+
+#[derive(Debug)]
+enum Process {
+    /// The program hasn't been instantiated yet, so any signals it gets sent
+    /// are held until it starts.
+    Starting(Vec<Signal>),
+    Running(WasiProcess),
+}
+
+impl ProcessTable {
+    /// Allocate a PID for a program that is about to start.
+    ///
+    /// Programs that aren't started in the background become the foreground
+    /// job.
+    pub(crate) fn reserve(&self, background: bool) -> u32 {
+        static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.0.lock().unwrap();
+        state.processes.insert(pid, Process::Starting(Vec::new()));
+        if !background {
+            state.foreground = Some(pid);
+        }
+        pid
+    }
+
+    /// Record that the program with this `pid` is now running, delivering
+    /// anything that was sent while it was starting up.
+    pub(crate) fn attach(&self, pid: u32, process: WasiProcess) {
+        let processes = &mut self.0.lock().unwrap().processes;
+
+        let pending = match processes.get_mut(&pid) {
+            Some(Process::Starting(pending)) => std::mem::take(pending),
+            _ => return,
+        };
+
+        for signal in pending {
+            process.signal_process(signal);
+        }
+        processes.insert(pid, Process::Running(process));
+    }
+
+    /// Forget about a program which has exited.
+    pub(crate) fn remove(&self, pid: u32) {
+        let mut state = self.0.lock().unwrap();
+        state.processes.remove(&pid);
+        if state.foreground == Some(pid) {
+            state.foreground = None;
+        }
+    }
+
+    /// The PIDs of every program that is still running.
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        self.0.lock().unwrap().processes.keys().copied().collect()
+    }
+
+    /// The PID of the foreground job, if there is one.
+    pub(crate) fn foreground(&self) -> Option<u32> {
+        self.0.lock().unwrap().foreground
+    }
+
+    /// Suspend the foreground job with `SIGTSTP` (i.e. what a terminal does
+    /// when the user presses Ctrl-Z), returning its PID.
+    ///
+    /// The job stops being the foreground job until it is resumed with
+    /// [`ProcessTable::resume()`].
+    pub(crate) fn suspend_foreground(&self) -> Option<u32> {
+        let pid = self.0.lock().unwrap().foreground?;
+        self.signal(pid, Signal::Sigtstp).ok()?;
+
+        // Note: the job may have been replaced while we were signalling it
+        let mut state = self.0.lock().unwrap();
+        if state.foreground == Some(pid) {
+            state.foreground = None;
+        }
+        Some(pid)
+    }
+
+    /// Resume a job with `SIGCONT`, like the `fg` and `bg` shell builtins.
+    pub(crate) fn resume(&self, pid: u32, foreground: bool) -> Result<(), Error> {
+        self.signal(pid, Signal::Sigcont)?;
+
+        let mut state = self.0.lock().unwrap();
+        if foreground {
+            state.foreground = Some(pid);
+        } else if state.foreground == Some(pid) {
+            state.foreground = None;
+        }
+
+        Ok(())
+    }
+
+    /// Send a signal to a program, using the WASIX signal machinery to either
+    /// invoke the program's handler or apply the signal's default action.
+    pub(crate) fn signal(&self, pid: u32, signal: Signal) -> Result<(), Error> {
+        let processes = &mut self.0.lock().unwrap().processes;
+
+        match processes.get_mut(&pid) {
+            Some(Process::Starting(pending)) => {
+                pending.push(signal);
+                Ok(())
+            }
+            Some(Process::Running(process)) => {
+                tracing::debug!(pid, ?signal, "Sending a signal");
+                process.signal_process(signal);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("No such process: {pid}").into()),
+        }
+    }
+
+    /// The signals held for a program which hasn't started yet.
+    #[cfg(test)]
+    pub(crate) fn pending_signals(&self, pid: u32) -> Option<Vec<Signal>> {
+        match self.0.lock().unwrap().processes.get(&pid)? {
+            Process::Starting(pending) => Some(pending.clone()),
+            Process::Running(_) => None,
+        }
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// A signal's name (e.g. `"SIGTERM"`) or number.
+    #[wasm_bindgen(typescript_type = "string | number")]
+    pub type SignalName;
+}
+
+/// Parse a signal given as either a name (`"SIGTERM"` or `"TERM"`) or a
+/// number, defaulting to `SIGTERM`.
+pub(crate) fn parse_signal(value: Option<&JsValue>) -> Result<Signal, Error> {
+    let Some(value) = value else {
+        return Ok(Signal::Sigterm);
+    };
+
+    let signal = if let Some(name) = value.as_string() {
+        let name = name.to_ascii_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(candidate, _)| &candidate[3..] == name)
+            .map(|&(_, signal)| signal)
+    } else if let Some(number) = value.as_f64() {
+        SIGNALS
+            .iter()
+            .map(|&(_, signal)| signal)
+            .find(|&signal| signal as u8 as f64 == number)
+    } else {
+        None
+    };
+
+    signal.ok_or_else(|| {
+        let msg = format!("{value:?} isn't a valid signal");
+        Error::js(js_sys::TypeError::new(&msg))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn parse_signal_names_and_numbers() {
+        let parse = |value: JsValue| parse_signal(Some(&value));
+
+        assert_eq!(parse_signal(None).unwrap(), Signal::Sigterm);
+        assert_eq!(parse("SIGKILL".into()).unwrap(), Signal::Sigkill);
+        assert_eq!(parse("int".into()).unwrap(), Signal::Sigint);
+        assert_eq!(parse(9.into()).unwrap(), Signal::Sigkill);
+        assert!(parse("SIGNOPE".into()).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn signals_are_held_until_the_process_starts() {
+        let table = ProcessTable::default();
+        let pid = table.reserve(false);
+
+        table.signal(pid, Signal::Sigint).unwrap();
+
+        match &table.0.lock().unwrap().processes[&pid] {
+            Process::Starting(pending) => assert_eq!(pending, &[Signal::Sigint]),
+            other => panic!("Unexpected state: {other:?}"),
+        }
+
+        table.remove(pid);
+        assert!(table.signal(pid, Signal::Sigint).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn job_control() {
+        let table = ProcessTable::default();
+        let shell = table.reserve(false);
+        let job = table.reserve(true);
+        assert_eq!(table.foreground(), Some(shell));
+
+        // Ctrl-Z
+        assert_eq!(table.suspend_foreground(), Some(shell));
+        assert_eq!(table.foreground(), None);
+
+        // fg
+        table.resume(job, true).unwrap();
+        assert_eq!(table.foreground(), Some(job));
+
+        // bg
+        table.resume(job, false).unwrap();
+        assert_eq!(table.foreground(), None);
+
+        // Exited jobs can't be resumed
+        table.remove(shell);
+        assert!(table.resume(shell, true).is_err());
+
+        // A job that couldn't be suspended stays in the foreground
+        table.0.lock().unwrap().foreground = Some(shell);
+        assert_eq!(table.suspend_foreground(), None);
+        assert_eq!(table.foreground(), Some(shell));
+    }
+}
@@ -0,0 +1,246 @@
+//! Emulation profiles which make a program's environment look like a
+//! particular Linux distribution.
+//!
+//! Configure scripts and build tools often probe the OS (reading
+//! `/etc/os-release`, checking `PATH`, running `ldconfig`) and give up when
+//! things aren't where they expect. A profile provisions those files,
+//! directories and variables up front so the probes behave predictably.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+use crate::{
+    fs::{inline_files, InlineFile},
+    utils::Error,
+    Directory,
+};
+
+/// A WebAssembly module which exits successfully without doing anything.
+///
+/// `(module (memory (export "memory") 1) (func (export "_start")))`
+const STUB: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // types: [() -> ()]
+    0x03, 0x02, 0x01, 0x00, // functions: [type 0]
+    0x05, 0x03, 0x01, 0x00, 0x01, // memories: [min 1 page]
+    0x07, 0x13, 0x02, // exports: 2 entries
+    0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00, // memory 0
+    0x06, b'_', b's', b't', b'a', b'r', b't', 0x00, 0x00, // func 0
+    0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code: [empty body]
+];
+
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+const DIRS: &[&str] = &[
+    "/opt",
+    "/usr/local/bin",
+    "/usr/local/lib",
+    "/usr/share/doc",
+    "/usr/share/man",
+    "/var/tmp",
+];
+
+const STUBS: &[&str] = &["/bin/true", "/sbin/ldconfig"];
+
+const DEBIAN_OS_RELEASE: &str = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+VERSION="12 (bookworm)"
+VERSION_CODENAME=bookworm
+ID=debian
+HOME_URL="https://www.debian.org/"
+"#;
+
+const ALPINE_OS_RELEASE: &str = r#"NAME="Alpine Linux"
+ID=alpine
+VERSION_ID=3.19.1
+PRETTY_NAME="Alpine Linux v3.19"
+HOME_URL="https://alpinelinux.org/"
+"#;
+
+/// The files, directories and environment variables a program is started
+/// with to emulate a particular OS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Profile {
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) files: Vec<InlineFile>,
+    pub(crate) dirs: Vec<PathBuf>,
+}
+
+impl Profile {
+    /// Parse the `profile` option, which is either the name of a built-in
+    /// profile or an {@link EmulationProfile}.
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Profile>, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+
+        if let Some(name) = value.as_string() {
+            return Profile::builtin(&name).map(Some);
+        }
+
+        #[derive(Deserialize)]
+        struct Custom {
+            extends: Option<String>,
+            #[serde(default)]
+            env: BTreeMap<String, String>,
+            #[serde(default)]
+            files: BTreeMap<String, String>,
+            #[serde(default)]
+            dirs: Vec<String>,
+            #[serde(default)]
+            stubs: Vec<String>,
+        }
+
+        let custom: Custom = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+        let mut profile = match custom.extends {
+            Some(name) => Profile::builtin(&name)?,
+            None => Profile::default(),
+        };
+
+        profile.env.extend(custom.env);
+        for (path, contents) in custom.files {
+            profile.add(inline_files::validate_path(&path)?, contents.into_bytes());
+        }
+        for path in custom.stubs {
+            profile.add(inline_files::validate_path(&path)?, STUB.to_vec());
+        }
+        for dir in custom.dirs {
+            if !Path::new(&dir).is_absolute() {
+                let msg = format!("The profile directory \"{dir}\" must be an absolute path");
+                return Err(Error::js(js_sys::TypeError::new(&msg)));
+            }
+            profile.dirs.push(dir.into());
+        }
+
+        Ok(Some(profile))
+    }
+
+    fn builtin(name: &str) -> Result<Profile, Error> {
+        let (os_release, version_file) = match name {
+            "debian-like" => (DEBIAN_OS_RELEASE, ("/etc/debian_version", "12.5\n")),
+            "alpine-like" => (ALPINE_OS_RELEASE, ("/etc/alpine-release", "3.19.1\n")),
+            other => {
+                let msg = format!(
+                    "\"{other}\" isn't a known profile, expected \"debian-like\" or \"alpine-like\""
+                );
+                return Err(Error::js(js_sys::TypeError::new(&msg)));
+            }
+        };
+
+        let mut profile = Profile {
+            env: [("PATH".to_string(), PATH.to_string())].into(),
+            files: Vec::new(),
+            dirs: DIRS.iter().map(PathBuf::from).collect(),
+        };
+        profile.add("/etc/os-release".into(), os_release.into());
+        profile.add(version_file.0.into(), version_file.1.into());
+        for stub in STUBS {
+            profile.add(stub.into(), STUB.to_vec());
+        }
+
+        Ok(profile)
+    }
+
+    /// Add a file, replacing any existing file with the same path.
+    fn add(&mut self, path: PathBuf, contents: Vec<u8>) {
+        self.files.retain(|f| f.path != path);
+        self.files.push(InlineFile { path, contents });
+    }
+
+    /// Only keep what can be provisioned for a package's command.
+    ///
+    /// A package's own directories (e.g. `/usr`) would be hidden if we
+    /// mounted something over them, so only `/etc` and paths inside the
+    /// program's `mount`ed directories are used.
+    pub(crate) fn for_package(mut self, mounted: &[(String, Directory)]) -> Profile {
+        let keep = |path: &Path| {
+            path.starts_with(crate::dns::MOUNT_POINT)
+                || mounted.iter().any(|(dest, _)| path.starts_with(dest))
+        };
+
+        self.files.retain(|f| {
+            let kept = keep(&f.path);
+            if !kept {
+                tracing::debug!(path = %f.path.display(), "Skipping a profile file outside /etc");
+            }
+            kept
+        });
+        self.dirs.retain(|d| keep(d));
+        self
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const EMULATION_PROFILE_TYPE_DEF: &'static str = r#"
+/**
+ * A custom emulation profile for `CommonOptions.profile`.
+ *
+ * @example
+ * ```ts
+ * profile: {
+ *     extends: "debian-like",
+ *     env: { CC: "clang" },
+ *     files: { "/etc/hostname": "builder\n" },
+ *     stubs: ["/usr/bin/sudo"],
+ * }
+ * ```
+ */
+export type EmulationProfile = {
+    /** A built-in profile to start from. */
+    extends?: "debian-like" | "alpine-like";
+    /** Environment variables to add. */
+    env?: Record<string, string>;
+    /** Files to create, keyed by absolute path. */
+    files?: Record<string, string>;
+    /** Directories to create. */
+    dirs?: string[];
+    /**
+     * Paths to create stub executables at. Running a stub does nothing and
+     * exits with `0`.
+     */
+    stubs?: string[];
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn custom_profiles_extend_the_builtins() {
+        let value = js_sys::JSON::parse(
+            r#"{
+                "extends": "alpine-like",
+                "env": {"CC": "clang"},
+                "files": {"/etc/os-release": "ID=custom\n"},
+                "stubs": ["/usr/bin/sudo"]
+            }"#,
+        )
+        .unwrap();
+        let profile = Profile::parse(value).unwrap().unwrap();
+
+        assert_eq!(profile.env["PATH"], PATH);
+        assert_eq!(profile.env["CC"], "clang");
+        let file = |path: &str| profile.files.iter().find(|f| f.path == Path::new(path));
+        assert_eq!(file("/etc/os-release").unwrap().contents, b"ID=custom\n");
+        assert_eq!(file("/usr/bin/sudo").unwrap().contents, STUB);
+        assert!(file("/etc/alpine-release").is_some());
+        assert!(wasmparser::validate(STUB).is_ok());
+
+        let package = profile.for_package(&[]);
+        assert!(file_paths(&package).all(|p| p.starts_with("/etc")));
+        assert!(Profile::parse("windows-like".into()).is_err());
+    }
+
+    fn file_paths(profile: &Profile) -> impl Iterator<Item = &Path> {
+        profile.files.iter().map(|f| f.path.as_path())
+    }
+}
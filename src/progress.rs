@@ -0,0 +1,267 @@
+//! A channel for guests to report how far along they are.
+//!
+//! Scraping a program's output for progress bars is fragile, so every
+//! instance gets a `/dev/status/progress` device instead. Each line written
+//! to it is one report, and is re-emitted as a `guest-progress` runtime event:
+//!
+//! - `42 Compiling foo.c` (or `42% Compiling foo.c`) is a percentage,
+//!   optionally followed by a message
+//! - `Linking` is just a message, for when progress can't be measured
+//! - `{"percent": 42, "message": "Compiling foo.c"}` is the same as JSON
+//!
+//! Reading the device always returns end-of-file.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::VirtualFile;
+
+use crate::{
+    events::RuntimeEvent,
+    fs::{Device, DeviceFileSystem, Opener},
+    tasks::ThreadPool,
+};
+
+/// Where the progress device gets mounted.
+pub(crate) const MOUNT_POINT: &str = "/dev/status";
+
+/// Would mounting the progress device clash with something the user mounted
+/// at `mount_point`?
+pub(crate) fn conflicts_with(mount_point: &str) -> bool {
+    crate::locale::conflicts_with(MOUNT_POINT, mount_point)
+}
+
+/// A directory containing the `progress` device, ready to be mounted at
+/// [`MOUNT_POINT`].
+pub(crate) fn filesystem(pool: &ThreadPool, pid: Option<u32>) -> DeviceFileSystem {
+    let fs = DeviceFileSystem::default();
+
+    let pool = pool.clone();
+    let progress = Opener::new(move || Box::new(ProgressFile::new(pool.clone(), pid)));
+    fs.insert("progress", Device::Opener(progress));
+
+    fs
+}
+
+/// One progress report.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+struct Report {
+    percent: Option<f64>,
+    message: Option<String>,
+}
+
+impl Report {
+    fn parse(line: &str) -> Option<Report> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+
+        let mut report = if line.starts_with('{') {
+            let value = js_sys::JSON::parse(line).ok()?;
+            serde_wasm_bindgen::from_value(value).ok()?
+        } else {
+            let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match first.trim_end_matches('%').parse::<f64>() {
+                Ok(percent) => Report {
+                    percent: Some(percent),
+                    message: Some(rest.trim().to_string()).filter(|m| !m.is_empty()),
+                },
+                Err(_) => Report {
+                    percent: None,
+                    message: Some(line.to_string()),
+                },
+            }
+        };
+
+        report.percent = report
+            .percent
+            .filter(|p| p.is_finite())
+            .map(|p| p.clamp(0.0, 100.0));
+        Some(report)
+    }
+}
+
+/// A handle to the progress device, created whenever it is opened.
+#[derive(Debug)]
+struct ProgressFile {
+    pool: ThreadPool,
+    pid: Option<u32>,
+    /// The start of a line which hasn't been terminated yet.
+    partial: Vec<u8>,
+}
+
+impl ProgressFile {
+    fn new(pool: ThreadPool, pid: Option<u32>) -> Self {
+        ProgressFile {
+            pool,
+            pid,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Split off every complete line written so far.
+    fn complete_lines(&mut self, data: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(data);
+
+        let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let complete: Vec<u8> = self.partial.drain(..=end).collect();
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn report(&self, line: &str) {
+        match Report::parse(line) {
+            Some(Report { percent, message }) => self.pool.emit(RuntimeEvent::GuestProgress {
+                pid: self.pid,
+                percent,
+                message,
+            }),
+            None => tracing::trace!(line, "Ignoring an empty progress report"),
+        }
+    }
+
+    /// Report whatever is left of an unterminated line.
+    fn flush_partial(&mut self) {
+        if self.partial.is_empty() {
+            return;
+        }
+
+        let line = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial.clear();
+        self.report(&line);
+    }
+}
+
+impl Drop for ProgressFile {
+    fn drop(&mut self) {
+        self.flush_partial();
+    }
+}
+
+impl VirtualFile for ProgressFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(virtual_fs::FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Err(virtual_fs::FsError::PermissionDenied) })
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(1))
+    }
+}
+
+impl AsyncRead for ProgressFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ProgressFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        for line in self.complete_lines(buf) {
+            self.report(&line);
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flush_partial();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for ProgressFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The progress device can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn parse_progress_reports() {
+        let report = |percent, message: Option<&str>| {
+            Some(Report {
+                percent,
+                message: message.map(String::from),
+            })
+        };
+
+        assert_eq!(
+            Report::parse("42% Compiling foo.c\n"),
+            report(Some(42.0), Some("Compiling foo.c"))
+        );
+        assert_eq!(Report::parse("7.5"), report(Some(7.5), None));
+        assert_eq!(Report::parse("Linking"), report(None, Some("Linking")));
+        assert_eq!(Report::parse("150 Done"), report(Some(100.0), Some("Done")));
+        assert_eq!(
+            Report::parse(r#"{"percent": 10, "message": "Fetching"}"#),
+            report(Some(10.0), Some("Fetching"))
+        );
+        assert_eq!(Report::parse("   "), None);
+
+        let mut file = ProgressFile::new(ThreadPool::new(), None);
+        assert!(file.complete_lines(b"10 Down").is_empty());
+        assert_eq!(file.complete_lines(b"loading\n20"), ["10 Downloading"]);
+        assert_eq!(file.partial, b"20");
+        // Don't report the leftover when the file is dropped
+        file.partial.clear();
+    }
+}
@@ -0,0 +1,105 @@
+//! Detecting which post-MVP WebAssembly proposals the browser supports, so
+//! modules which need a missing one fail with an error naming it instead of
+//! an opaque `CompileError`.
+
+use std::fmt;
+
+/// A WebAssembly proposal that browsers have shipped at different times.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Proposal {
+    TailCalls,
+    Gc,
+}
+
+impl Proposal {
+    const ALL: [Proposal; 2] = [Proposal::TailCalls, Proposal::Gc];
+
+    /// The smallest module which is only valid if the proposal is supported.
+    fn probe(self) -> &'static [u8] {
+        match self {
+            // (module (func return_call 0))
+            Proposal::TailCalls => &[
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+                0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+                0x03, 0x02, 0x01, 0x00, // function section
+                0x0a, 0x06, 0x01, 0x04, 0x00, 0x12, 0x00, 0x0b, // code section
+            ],
+            // (module (type (struct)))
+            Proposal::Gc => &[
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+                0x01, 0x03, 0x01, 0x5f, 0x00, // type section
+            ],
+        }
+    }
+
+    /// Does the current browser support this proposal?
+    pub(crate) fn is_supported(self) -> bool {
+        let probe = unsafe { js_sys::Uint8Array::view(self.probe()) };
+        js_sys::WebAssembly::validate(&probe).unwrap_or(false)
+    }
+
+    /// Are we confident `wasm` needs this proposal, given the error the
+    /// browser gave when compiling it?
+    fn is_needed_by(self, wasm: &[u8], message: &str) -> bool {
+        match self {
+            Proposal::Gc => crate::package_info::uses_gc_types(wasm),
+            // Note: finding return_call instructions would mean decoding every
+            // function body, so we rely on the browser's error mentioning
+            // the opcodes (0x12 and 0x13) or the instructions instead.
+            Proposal::TailCalls => {
+                let message = message.to_ascii_lowercase();
+                ["return_call", "tail call", "opcode 0x12", "opcode 0x13"]
+                    .iter()
+                    .any(|needle| message.contains(needle))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Proposal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proposal::TailCalls => write!(f, "tail calls"),
+            Proposal::Gc => write!(f, "garbage collection (wasm-gc)"),
+        }
+    }
+}
+
+/// Explain why the browser rejected `wasm` if it is because of a proposal the
+/// browser doesn't support, otherwise return the original error.
+pub(crate) fn explain_compile_error(wasm: &[u8], error: anyhow::Error) -> anyhow::Error {
+    let message = error.to_string();
+
+    let missing: Vec<String> = Proposal::ALL
+        .into_iter()
+        .filter(|p| p.is_needed_by(wasm, &message) && !p.is_supported())
+        .map(|p| p.to_string())
+        .collect();
+
+    if missing.is_empty() {
+        return error;
+    }
+
+    error.context(format!(
+        "The module uses the WebAssembly {} proposal, which this browser doesn't support",
+        missing.join(" and ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn detect_which_proposal_a_module_needs() {
+        assert!(Proposal::Gc.is_needed_by(Proposal::Gc.probe(), ""));
+        assert!(!Proposal::Gc.is_needed_by(Proposal::TailCalls.probe(), ""));
+        assert!(Proposal::TailCalls.is_needed_by(
+            Proposal::TailCalls.probe(),
+            "WebAssembly.Module(): Compiling function #0 failed: Invalid opcode 0x12"
+        ));
+        assert!(!Proposal::TailCalls.is_needed_by(&[], "invalid magic number"));
+    }
+}
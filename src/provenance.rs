@@ -0,0 +1,225 @@
+//! Where a package came from, and which publishers are trusted to provide
+//! packages.
+//!
+//! The Wasmer registry doesn't currently return signing identities or build
+//! attestations, so a package is identified by its publisher (the namespace
+//! it was published under) and the hash of its `*.webc` file.
+//!
+//! With `trustedPublishers`, packages from any other namespace fail to
+//! resolve, and so does anything depending on them.
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasmer_wasix::runtime::resolver::{
+    PackageSpecifier, PackageSummary, QueryError, Source, WebcHash,
+};
+
+use crate::utils::Error;
+
+/// The namespace part of a package name (e.g. `"wasmer"` for
+/// `"wasmer/python"`).
+fn publisher(full_name: &str) -> Option<&str> {
+    full_name
+        .split_once('/')
+        .map(|(namespace, _)| namespace)
+        .filter(|namespace| !namespace.is_empty())
+}
+
+/// Where a package came from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Provenance {
+    publisher: Option<String>,
+    name: String,
+    version: String,
+    source: ProvenanceSource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webc_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webc_sha256: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProvenanceSource {
+    /// Looked up in the registry.
+    Registry,
+    /// Replaced with a local file using `overridePackage()`.
+    Override,
+    /// Loaded with `Wasmer.fromFile()`.
+    File,
+}
+
+impl Provenance {
+    /// A package resolved using `summary`.
+    pub(crate) fn from_summary(summary: &PackageSummary) -> Self {
+        let url = &summary.dist.webc;
+        let source = if url.scheme() == "webc-override" {
+            ProvenanceSource::Override
+        } else {
+            ProvenanceSource::Registry
+        };
+
+        Provenance {
+            publisher: publisher(&summary.pkg.name).map(String::from),
+            name: summary.pkg.name.clone(),
+            version: summary.pkg.version.to_string(),
+            source,
+            webc_url: (source == ProvenanceSource::Registry).then(|| url.to_string()),
+            webc_sha256: Some(summary.dist.webc_sha256.to_string()),
+        }
+    }
+
+    /// A package loaded from a file the host provided.
+    pub(crate) fn from_file(name: &str, version: &str, webc_sha256: WebcHash) -> Self {
+        Provenance {
+            publisher: publisher(name).map(String::from),
+            name: name.to_string(),
+            version: version.to_string(),
+            source: ProvenanceSource::File,
+            webc_url: None,
+            webc_sha256: Some(webc_sha256.to_string()),
+        }
+    }
+
+    /// A registry package whose summary couldn't be looked up again.
+    pub(crate) fn unknown(name: &str, version: &str) -> Self {
+        Provenance {
+            publisher: publisher(name).map(String::from),
+            name: name.to_string(),
+            version: version.to_string(),
+            source: ProvenanceSource::Registry,
+            webc_url: None,
+            webc_sha256: None,
+        }
+    }
+}
+
+/// The publishers packages may be resolved from, if the host restricted
+/// them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PublisherPolicy(Arc<Mutex<Option<BTreeSet<String>>>>);
+
+impl PublisherPolicy {
+    /// Only resolve packages published by one of `publishers`.
+    pub(crate) fn trust_only(&self, publishers: Vec<String>) -> Result<(), Error> {
+        if let Some(invalid) = publishers.iter().find(|p| p.is_empty() || p.contains('/')) {
+            let msg = format!("\"{invalid}\" isn't a publisher's namespace");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        tracing::debug!(?publishers, "Restricting packages to trusted publishers");
+        *self.0.lock().unwrap() = Some(publishers.into_iter().collect());
+        Ok(())
+    }
+
+    /// Make sure the package named by `specifier` comes from a trusted
+    /// publisher.
+    fn check(&self, specifier: &PackageSpecifier) -> Result<(), String> {
+        let trusted = self.0.lock().unwrap();
+        let Some(trusted) = trusted.as_ref() else {
+            return Ok(());
+        };
+
+        let PackageSpecifier::Registry { full_name, .. } = specifier else {
+            return Err(format!(
+                "\"{specifier}\" doesn't have a publisher, so it can't be trusted"
+            ));
+        };
+
+        match publisher(full_name) {
+            Some(namespace) if trusted.contains(namespace) => Ok(()),
+            Some(namespace) => Err(format!(
+                "\"{full_name}\" was published by \"{namespace}\", which isn't a trusted publisher"
+            )),
+            None => Err(format!(
+                "\"{full_name}\" doesn't have a publisher, so it can't be trusted"
+            )),
+        }
+    }
+}
+
+/// A [`Source`] which refuses to look up packages from untrusted publishers.
+#[derive(Debug, Clone)]
+pub(crate) struct TrustedSource<S> {
+    pub(crate) policy: PublisherPolicy,
+    pub(crate) inner: S,
+}
+
+#[async_trait::async_trait]
+impl<S> Source for TrustedSource<S>
+where
+    S: Source + Send + Sync,
+{
+    async fn query(&self, package: &PackageSpecifier) -> Result<Vec<PackageSummary>, QueryError> {
+        if let Err(reason) = self.policy.check(package) {
+            tracing::warn!(%package, %reason, "Refusing to resolve a package");
+            return Err(QueryError::Other(anyhow::Error::msg(reason)));
+        }
+
+        self.inner.query(package).await
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PROVENANCE_TYPE_DEFS: &'static str = r#"
+/**
+ * Where a package came from, as returned by {@link Wasmer.provenance}.
+ *
+ * The registry doesn't currently provide signing identities or build
+ * attestations, so packages are identified by who published them and the
+ * hash of their contents.
+ */
+export type Provenance = {
+    /** The namespace the package was published under (e.g. `"wasmer"`). */
+    publisher: string | null;
+    /** The package's full name (e.g. `"wasmer/python"`). */
+    name: string;
+    version: string;
+    /**
+     * `"registry"` if the package was looked up in the registry,
+     * `"override"` if it was replaced with {@link Runtime.overridePackage},
+     * or `"file"` if it was loaded with {@link Wasmer.fromFile}.
+     */
+    source: "registry" | "override" | "file";
+    /** Where the registry said the package could be downloaded from. */
+    webcUrl?: string;
+    /** The SHA-256 hash of the package's `*.webc` file, if known. */
+    webcSha256?: string;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Provenance")]
+    pub type JsProvenance;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_trusted_publishers_are_resolved() {
+        let policy = PublisherPolicy::default();
+        let python: PackageSpecifier = "wasmer/python@^3.12".parse().unwrap();
+        let bash: PackageSpecifier = "sharrattj/bash".parse().unwrap();
+
+        assert!(policy.check(&bash).is_ok());
+
+        policy.trust_only(vec!["wasmer".to_string()]).unwrap();
+        assert!(policy.check(&python).is_ok());
+        assert!(policy.check(&bash).is_err());
+
+        assert!(policy
+            .trust_only(vec!["wasmer/python".to_string()])
+            .is_err());
+    }
+}
@@ -0,0 +1,552 @@
+//! Sending guest TCP connections through an HTTP proxy.
+//!
+//! Enterprise networks often only let traffic out through a proxy. When
+//! `networkProxy` is set, every TCP connection a guest makes through the
+//! gateway is actually made to the proxy, and an HTTP `CONNECT` request asks
+//! it to open a tunnel to the real destination. Hosts matching one of the
+//! bypass rules are connected to directly.
+//!
+//! The gateway's own WebSocket is opened by the browser, which applies its
+//! own proxy settings to it.
+
+use std::{
+    collections::VecDeque,
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::Engine;
+use futures::future::poll_fn;
+use virtual_net::{
+    IpCidr, IpRoute, NetworkError, StreamSecurity, VirtualIcmpSocket, VirtualNetworking,
+    VirtualRawSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+use crate::utils::Error;
+
+/// The most we'll read while waiting for the end of the proxy's response.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+/// The most hostname lookups we remember while waiting for guests to connect
+/// to the addresses they returned.
+const MAX_LOOKUPS: usize = 256;
+
+/// A parsed `networkProxy` option.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ProxyConfig {
+    host: String,
+    port: u16,
+    /// The `Proxy-Authorization` header's value, if credentials were given.
+    authorization: Option<String>,
+    bypass: Vec<BypassRule>,
+}
+
+impl ProxyConfig {
+    pub(crate) fn parse(
+        url: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        bypass: &[String],
+    ) -> Result<Self, Error> {
+        let invalid = |reason: &str| {
+            let msg = format!("\"{url}\" isn't a valid proxy URL: {reason}");
+            Error::js(js_sys::TypeError::new(&msg))
+        };
+
+        let parsed = url::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        if parsed.scheme() != "http" {
+            return Err(invalid("only \"http:\" proxies are supported"));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| invalid("it has no host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = parsed.port_or_known_default().unwrap_or(80);
+
+        let authorization = match (username, password) {
+            (None, None) => None,
+            (username, password) => {
+                let credentials = format!(
+                    "{}:{}",
+                    username.unwrap_or_default(),
+                    password.unwrap_or_default()
+                );
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                Some(format!("Basic {encoded}"))
+            }
+        };
+
+        let bypass = bypass
+            .iter()
+            .map(|rule| BypassRule::parse(rule))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ProxyConfig {
+            host,
+            port,
+            authorization,
+            bypass,
+        })
+    }
+
+    /// Should connections to this host skip the proxy?
+    fn bypasses(&self, hostname: Option<&str>, address: IpAddr) -> bool {
+        self.bypass
+            .iter()
+            .any(|rule| rule.matches(hostname, address))
+    }
+
+    /// The request asking the proxy to open a tunnel to `target`.
+    fn connect_request(&self, target: &str) -> String {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+        }
+        request.push_str("\r\n");
+        request
+    }
+}
+
+/// A host (or set of hosts) that is connected to without the proxy.
+#[derive(Debug, Clone, PartialEq)]
+enum BypassRule {
+    /// `"*"`
+    Everything,
+    /// `"internal.example.com"`
+    Hostname(String),
+    /// `"*.example.com"` or `".example.com"`, stored with the leading dot.
+    Domain(String),
+    /// `"10.0.0.0/8"`, or a single address.
+    Network(IpAddr, u8),
+}
+
+impl BypassRule {
+    fn parse(rule: &str) -> Result<Self, Error> {
+        let rule = rule.trim().to_ascii_lowercase();
+
+        if rule == "*" {
+            return Ok(BypassRule::Everything);
+        }
+        if let Some(domain) = rule.strip_prefix('*') {
+            if domain.starts_with('.') {
+                return Ok(BypassRule::Domain(domain.to_string()));
+            }
+        } else if rule.starts_with('.') {
+            return Ok(BypassRule::Domain(rule));
+        }
+
+        let (address, prefix) = match rule.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (rule.as_str(), None),
+        };
+        if let Ok(address) = address
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            let max = if address.is_ipv4() { 32 } else { 128 };
+            return match prefix.map(str::parse::<u8>) {
+                None => Ok(BypassRule::Network(address, max)),
+                Some(Ok(prefix)) if prefix <= max => Ok(BypassRule::Network(address, prefix)),
+                Some(_) => {
+                    let msg = format!("\"{rule}\" isn't a valid network for the proxy bypass list");
+                    Err(Error::js(js_sys::TypeError::new(&msg)))
+                }
+            };
+        }
+
+        if rule.is_empty() || prefix.is_some() || rule.contains('*') {
+            let msg = format!("\"{rule}\" isn't a valid proxy bypass rule");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        Ok(BypassRule::Hostname(rule))
+    }
+
+    fn matches(&self, hostname: Option<&str>, address: IpAddr) -> bool {
+        match self {
+            BypassRule::Everything => true,
+            BypassRule::Hostname(name) => hostname == Some(name.as_str()),
+            BypassRule::Domain(domain) => hostname.map_or(false, |hostname| {
+                hostname.ends_with(domain.as_str()) || hostname == &domain[1..]
+            }),
+            BypassRule::Network(network, prefix) => in_network(address, *network, *prefix),
+        }
+    }
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (address, network, bits) = match (address, network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => (u32::from(a) as u128, u32::from(n) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(n)) => (u128::from(a), u128::from(n), 128),
+        _ => return false,
+    };
+
+    let shift = bits - u32::from(prefix);
+    shift >= bits || (address >> shift) == (network >> shift)
+}
+
+/// A hostname a guest looked up, waiting for the connection it was looked
+/// up for.
+#[derive(Debug)]
+struct Lookup {
+    id: u64,
+    hostname: String,
+    addresses: Vec<IpAddr>,
+}
+
+/// The hostnames guests looked addresses up for, so we can ask the proxy for
+/// a tunnel by name and check the bypass rules against it.
+///
+/// Each lookup is used by the next connection to one of its addresses, and
+/// forgotten once that connection has been made. That way several names
+/// resolving to the same address (e.g. sites behind a CDN) each go to the
+/// connection they were looked up for.
+#[derive(Debug, Default)]
+struct Lookups {
+    next_id: u64,
+    /// Lookups that haven't been connected to yet, oldest first.
+    pending: VecDeque<Lookup>,
+}
+
+impl Lookups {
+    fn record(&mut self, hostname: String, addresses: Vec<IpAddr>) {
+        self.next_id += 1;
+        self.pending.push_back(Lookup {
+            id: self.next_id,
+            hostname,
+            addresses,
+        });
+
+        if self.pending.len() > MAX_LOOKUPS {
+            self.pending.pop_front();
+        }
+    }
+
+    /// The oldest pending lookup that returned `address`.
+    fn find(&self, address: IpAddr) -> Option<(u64, String)> {
+        self.pending
+            .iter()
+            .find(|lookup| lookup.addresses.contains(&address))
+            .map(|lookup| (lookup.id, lookup.hostname.clone()))
+    }
+
+    /// Forget about a lookup once a connection has been made with it.
+    ///
+    /// Lookups aren't forgotten when a connection fails, so falling back to
+    /// another one of its addresses still uses the hostname.
+    fn finish(&mut self, id: u64) {
+        self.pending.retain(|lookup| lookup.id != id);
+    }
+}
+
+/// [`VirtualNetworking`] which tunnels TCP connections through a proxy.
+#[derive(Debug)]
+pub(crate) struct ProxiedNetworking {
+    inner: Arc<dyn VirtualNetworking>,
+    proxy: ProxyConfig,
+    lookups: Mutex<Lookups>,
+}
+
+impl ProxiedNetworking {
+    pub(crate) fn new(inner: Arc<dyn VirtualNetworking>, proxy: ProxyConfig) -> Self {
+        ProxiedNetworking {
+            inner,
+            proxy,
+            lookups: Mutex::new(Lookups::default()),
+        }
+    }
+
+    async fn proxy_address(&self) -> virtual_net::Result<SocketAddr> {
+        let ip = match self.proxy.host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => *self
+                .inner
+                .resolve(&self.proxy.host, Some(self.proxy.port), None)
+                .await?
+                .first()
+                .ok_or(NetworkError::AddressNotAvailable)?,
+        };
+
+        Ok(SocketAddr::new(ip, self.proxy.port))
+    }
+
+    async fn connect(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+        hostname: Option<&str>,
+    ) -> virtual_net::Result<Box<dyn VirtualTcpSocket + Sync>> {
+        if self.proxy.bypasses(hostname, peer.ip()) {
+            tracing::debug!(%peer, ?hostname, "Connecting without the proxy");
+            return self.inner.connect_tcp(addr, peer).await;
+        }
+
+        let target = match hostname {
+            Some(hostname) => format!("{hostname}:{}", peer.port()),
+            None => peer.to_string(),
+        };
+        let proxy = self.proxy_address().await?;
+        tracing::debug!(%target, %proxy, "Connecting through the proxy");
+
+        let mut socket = self.inner.connect_tcp(addr, proxy).await?;
+        let request = self.proxy.connect_request(&target);
+        open_tunnel(&mut socket, request.as_bytes()).await?;
+
+        Ok(socket)
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for ProxiedNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> virtual_net::Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> virtual_net::Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> virtual_net::Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> virtual_net::Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> virtual_net::Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> virtual_net::Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> virtual_net::Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> virtual_net::Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> virtual_net::Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> virtual_net::Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> virtual_net::Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> virtual_net::Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> virtual_net::Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> virtual_net::Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> virtual_net::Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> virtual_net::Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn bind_icmp(
+        &self,
+        addr: IpAddr,
+    ) -> virtual_net::Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> virtual_net::Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let lookup = self.lookups.lock().unwrap().find(peer.ip());
+        let hostname = lookup.as_ref().map(|(_, hostname)| hostname.as_str());
+
+        let socket = self.connect(addr, peer, hostname).await?;
+        if let Some((id, _)) = lookup {
+            self.lookups.lock().unwrap().finish(id);
+        }
+
+        Ok(socket)
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> virtual_net::Result<Vec<IpAddr>> {
+        let addresses = self.inner.resolve(host, port, dns_server).await?;
+
+        if host.parse::<IpAddr>().is_err() && !addresses.is_empty() {
+            self.lookups
+                .lock()
+                .unwrap()
+                .record(host.to_ascii_lowercase(), addresses.clone());
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// Send a `CONNECT` request and wait for the proxy to accept it.
+async fn open_tunnel(
+    socket: &mut Box<dyn VirtualTcpSocket + Sync>,
+    mut request: &[u8],
+) -> virtual_net::Result<()> {
+    while !request.is_empty() {
+        poll_fn(|cx| socket.poll_write_ready(cx)).await?;
+        match socket.try_send(request) {
+            Ok(0) => return Err(NetworkError::WriteZero),
+            Ok(bytes_written) => request = &request[bytes_written..],
+            Err(NetworkError::WouldBlock) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Note: the response is read a byte at a time so we don't swallow
+    // anything the destination sends as soon as the tunnel is open
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(NetworkError::InvalidData);
+        }
+
+        poll_fn(|cx| socket.poll_read_ready(cx)).await?;
+        let mut byte = [MaybeUninit::new(0_u8)];
+        match socket.try_recv(&mut byte) {
+            Ok(0) => return Err(NetworkError::ConnectionReset),
+            // Safety: the byte was initialized when it was created
+            Ok(_) => response.push(unsafe { byte[0].assume_init() }),
+            Err(NetworkError::WouldBlock) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+
+    match status {
+        Some(200..=299) => Ok(()),
+        Some(407) => {
+            tracing::warn!(%status_line, "The proxy rejected our credentials");
+            Err(NetworkError::PermissionDenied)
+        }
+        _ => {
+            tracing::warn!(%status_line, "The proxy refused to open a tunnel");
+            Err(NetworkError::ConnectionRefused)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn bypass_rules_and_connect_requests() {
+        let bypass = ["localhost", "*.corp.example", "10.0.0.0/8", "::1"].map(String::from);
+        let proxy = ProxyConfig::parse(
+            "http://proxy.corp.example:3128",
+            Some("alice"),
+            Some("s3cret"),
+            &bypass,
+        )
+        .unwrap();
+        let public: IpAddr = "93.184.216.34".parse().unwrap();
+
+        assert!(proxy.bypasses(Some("localhost"), public));
+        assert!(proxy.bypasses(Some("git.corp.example"), public));
+        assert!(proxy.bypasses(Some("corp.example"), public));
+        assert!(proxy.bypasses(None, "10.1.2.3".parse().unwrap()));
+        assert!(proxy.bypasses(None, "::1".parse().unwrap()));
+        assert!(!proxy.bypasses(Some("notcorp.example"), public));
+        assert!(!proxy.bypasses(None, "11.0.0.1".parse().unwrap()));
+
+        assert_eq!(
+            proxy.connect_request("example.com:443"),
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic YWxpY2U6czNjcmV0\r\n\r\n",
+        );
+        assert!(ProxyConfig::parse("socks5://proxy:1080", None, None, &[]).is_err());
+        assert!(ProxyConfig::parse("http://proxy", None, None, &["10.0.0.0/33".into()]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn each_lookup_goes_to_its_own_connection() {
+        let cdn: IpAddr = "151.101.1.1".parse().unwrap();
+        let other: IpAddr = "151.101.65.1".parse().unwrap();
+        let mut lookups = Lookups::default();
+
+        lookups.record("a.example".to_string(), vec![cdn, other]);
+        lookups.record("b.example".to_string(), vec![cdn]);
+
+        // Connecting doesn't forget the name until it succeeds, so a failed
+        // attempt can fall back to another address
+        let (first, hostname) = lookups.find(cdn).unwrap();
+        assert_eq!(hostname, "a.example");
+        assert_eq!(lookups.find(other).unwrap().1, "a.example");
+        lookups.finish(first);
+        // The second name wasn't overwritten by the first
+        let (second, hostname) = lookups.find(cdn).unwrap();
+        assert_eq!(hostname, "b.example");
+        lookups.finish(second);
+        assert_eq!(lookups.find(cdn), None);
+        assert_eq!(lookups.find(other), None);
+
+        for i in 0..MAX_LOOKUPS + 1 {
+            lookups.record(format!("{i}.example"), vec![cdn]);
+        }
+        assert_eq!(lookups.pending.len(), MAX_LOOKUPS);
+        assert_eq!(lookups.find(cdn).unwrap().1, "1.example");
+    }
+}
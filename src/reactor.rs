@@ -0,0 +1,658 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{mpsc, Arc},
+};
+
+use futures::channel::oneshot;
+use js_sys::{Array, BigInt, Uint8Array};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasmer::{Type, Value};
+use wasmer_wasix::{
+    types::wasi::{Errno, ExitCode},
+    Runtime as _, WasiEnvBuilder,
+};
+
+use crate::{
+    run::{WasmModule, DEFAULT_PROGRAM_NAME},
+    usage::ResourceUsage,
+    utils::Error,
+    RunOptions,
+};
+
+/// A WASI reactor (i.e. a library) that has been instantiated on its own
+/// worker.
+///
+/// Unlike commands, reactors don't have a `_start` function. Instead, they
+/// export an `_initialize` function that gets called once, after which the
+/// rest of their exports can be called as many times as you like.
+///
+/// @example
+/// ```ts
+/// const reactor = await instantiateReactor(wasm);
+/// const sum = await reactor.call("add", [1, 2]);
+/// reactor.close();
+/// ```
+#[derive(Debug)]
+#[wasm_bindgen]
+pub struct Reactor {
+    requests: RefCell<Option<mpsc::Sender<Request>>>,
+    exports: Vec<String>,
+    /// The reactor's standard output.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub stdout: web_sys::ReadableStream,
+    /// The reactor's standard error.
+    #[wasm_bindgen(getter_with_clone, readonly)]
+    pub stderr: web_sys::ReadableStream,
+}
+
+/// Instantiate a WASI reactor and call its `_initialize` function.
+///
+/// The instance is kept alive on a dedicated worker until
+/// {@link Reactor.close} is called.
+#[wasm_bindgen(js_name = "instantiateReactor")]
+pub async fn instantiate_reactor(
+    wasm_module: WasmModule,
+    config: Option<RunOptions>,
+) -> Result<Reactor, Error> {
+    let config = config.unwrap_or_default();
+    let runtime = config.runtime().resolve()?.into_inner();
+
+    let program_name = config
+        .program()
+        .as_string()
+        .unwrap_or_else(|| DEFAULT_PROGRAM_NAME.to_string());
+
+    let mut builder = WasiEnvBuilder::new(program_name).runtime(runtime.clone());
+    let usage = Arc::new(ResourceUsage::default());
+    let (stdio, _mounts) = config.configure_builder(&mut builder, &usage, runtime.thread_pool())?;
+
+    let module = wasm_module.to_module(&*runtime).await?;
+
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (requests_tx, requests_rx) = mpsc::channel();
+
+    runtime.task_manager().spawn_with_module(
+        module,
+        Box::new(move |module| {
+            let _span = tracing::debug_span!("reactor").entered();
+            serve(builder, module, ready_tx, requests_rx);
+        }),
+    )?;
+
+    let exports = ready_rx
+        .await
+        .map_err(|_| anyhow::anyhow!("The reactor's worker exited unexpectedly"))?
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(Reactor {
+        requests: RefCell::new(Some(requests_tx)),
+        exports,
+        stdout: stdio.stdout,
+        stderr: stdio.stderr,
+    })
+}
+
+#[wasm_bindgen]
+impl Reactor {
+    /// The names of the functions this reactor exports.
+    #[wasm_bindgen(getter)]
+    pub fn exports(&self) -> Array {
+        self.exports
+            .iter()
+            .map(|name| JsValue::from_str(name))
+            .collect()
+    }
+
+    /// Call one of the reactor's exported functions.
+    ///
+    /// Arguments are converted based on the function's signature, so `i64`
+    /// parameters accept a `number` or a `bigint`. A function with no
+    /// results returns `undefined`, one result is returned directly, and
+    /// multiple results are returned as an array. `i64` results are always
+    /// returned as a `bigint`.
+    ///
+    /// Strings and `Uint8Array`s are copied into the guest's memory using its
+    /// `malloc()` (or `cabi_realloc()`) export and passed as a pointer and a
+    /// length. Anything allocated with `malloc()` is freed when the call
+    /// returns.
+    ///
+    /// Setting {@link CallOptions.returns} to `"string"` or `"bytes"` reads
+    /// the result back out of guest memory. The function should return a
+    /// pointer and a length, either as two `i32`s or packed into a single
+    /// `i64` with the pointer in the low 32 bits.
+    ///
+    /// The returned memory still belongs to the guest. If it exports
+    /// `cabi_post_<name>()`, that is called with the results afterwards so
+    /// the guest can clean up. Otherwise the memory is left alone unless
+    /// {@link CallOptions.freeResult} is set, in which case it is passed to
+    /// the guest's `free()`.
+    pub async fn call(
+        &self,
+        name: String,
+        args: Option<Array>,
+        options: Option<CallOptions>,
+    ) -> Result<CallResult, Error> {
+        let args = args
+            .map(|args| args.iter().map(Arg::from_js).collect::<Result<Vec<_>, _>>())
+            .transpose()?
+            .unwrap_or_default();
+        let free_result = options
+            .as_ref()
+            .and_then(|opts| opts.free_result())
+            .unwrap_or(false);
+        let returns = match options.and_then(|opts| opts.returns()).as_deref() {
+            None | Some("values") => Returns::Values,
+            Some("string") => Returns::String,
+            Some("bytes") => Returns::Bytes,
+            Some(other) => {
+                let msg = format!("\"{other}\" isn't a valid value for \"returns\"");
+                return Err(Error::js(js_sys::TypeError::new(&msg)));
+            }
+        };
+
+        let (reply, response) = oneshot::channel();
+        self.send(Request::Call {
+            name,
+            args,
+            returns,
+            free_result,
+            reply,
+        })?;
+
+        let reply = response
+            .await
+            .map_err(|_| anyhow::anyhow!("The reactor's worker exited unexpectedly"))?
+            .map_err(anyhow::Error::msg)?;
+
+        match (reply, returns) {
+            (Reply::Values(results), _) => {
+                let results: Vec<JsValue> = results.into_iter().map(JsValue::from).collect();
+                let value = match results.len() {
+                    0 => JsValue::UNDEFINED,
+                    1 => results.into_iter().next().unwrap(),
+                    _ => results.into_iter().collect::<Array>().into(),
+                };
+                Ok(value.unchecked_into())
+            }
+            (Reply::Bytes(bytes), Returns::String) => {
+                let s = String::from_utf8(bytes)
+                    .map_err(|_| anyhow::anyhow!("The function didn't return valid UTF-8"))?;
+                Ok(JsValue::from(s).unchecked_into())
+            }
+            (Reply::Bytes(bytes), _) => Ok(Uint8Array::from(bytes.as_slice()).unchecked_into()),
+        }
+    }
+
+    /// Shut the reactor down, freeing up its worker.
+    ///
+    /// Any further calls will fail.
+    pub fn close(&self) {
+        self.requests.borrow_mut().take();
+    }
+}
+
+impl Reactor {
+    fn send(&self, request: Request) -> Result<(), Error> {
+        let requests = self.requests.borrow();
+        let requests = requests
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The reactor has been closed"))?;
+        requests
+            .send(request)
+            .map_err(|_| anyhow::anyhow!("The reactor's worker exited unexpectedly"))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Request {
+    Call {
+        name: String,
+        args: Vec<Arg>,
+        returns: Returns,
+        /// Pass the memory a string or byte result was read from to
+        /// `free()`.
+        free_result: bool,
+        reply: oneshot::Sender<Result<Reply, String>>,
+    },
+}
+
+/// How a function's results should be handed back to JavaScript.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Returns {
+    /// Pass the raw values through.
+    Values,
+    /// Read a UTF-8 string out of guest memory.
+    String,
+    /// Read a byte buffer out of guest memory.
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Values(Vec<Ret>),
+    Bytes(Vec<u8>),
+}
+
+/// A `postMessage()`-free representation of a JavaScript argument.
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Number(f64),
+    BigInt(i64),
+    /// A string or `Uint8Array` that needs to be copied into guest memory.
+    Bytes(Vec<u8>),
+}
+
+impl Arg {
+    fn from_js(value: JsValue) -> Result<Self, Error> {
+        if let Some(n) = value.as_f64() {
+            Ok(Arg::Number(n))
+        } else if let Some(big) = value.dyn_ref::<BigInt>() {
+            let n = i64::try_from(big.clone())
+                .map_err(|_| anyhow::anyhow!("{big:?} doesn't fit in an i64"))?;
+            Ok(Arg::BigInt(n))
+        } else if let Some(s) = value.as_string() {
+            Ok(Arg::Bytes(s.into_bytes()))
+        } else if let Some(buffer) = value.dyn_ref::<Uint8Array>() {
+            Ok(Arg::Bytes(buffer.to_vec()))
+        } else {
+            Err(Error::js(js_sys::TypeError::new(
+                "Reactor arguments must be numbers, bigints, strings, or Uint8Arrays",
+            )))
+        }
+    }
+
+    /// How many WebAssembly parameters this argument is lowered to.
+    fn arity(&self) -> usize {
+        match self {
+            Arg::Bytes(_) => 2,
+            _ => 1,
+        }
+    }
+
+    fn to_value(&self, ty: Type) -> Result<Value, String> {
+        let value = match (ty, self) {
+            (Type::I32, &Arg::Number(n)) => Value::I32(n as i32),
+            (Type::I32, &Arg::BigInt(n)) => Value::I32(n as i32),
+            (Type::I64, &Arg::Number(n)) => Value::I64(n as i64),
+            (Type::I64, &Arg::BigInt(n)) => Value::I64(n),
+            (Type::F32, &Arg::Number(n)) => Value::F32(n as f32),
+            (Type::F32, &Arg::BigInt(n)) => Value::F32(n as f32),
+            (Type::F64, &Arg::Number(n)) => Value::F64(n),
+            (Type::F64, &Arg::BigInt(n)) => Value::F64(n as f64),
+            (_, Arg::Bytes(_)) => {
+                return Err("Strings and byte arrays must be passed as (i32, i32)".to_string())
+            }
+            (other, _) => {
+                return Err(format!(
+                    "Unable to pass a {other} parameter from JavaScript"
+                ))
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+/// A `postMessage()`-free representation of a function's result.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Ret {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Ret {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        match *value {
+            Value::I32(n) => Ok(Ret::I32(n)),
+            Value::I64(n) => Ok(Ret::I64(n)),
+            Value::F32(n) => Ok(Ret::F32(n)),
+            Value::F64(n) => Ok(Ret::F64(n)),
+            ref other => Err(format!("Unable to return a {} to JavaScript", other.ty())),
+        }
+    }
+}
+
+impl From<Ret> for JsValue {
+    fn from(value: Ret) -> Self {
+        match value {
+            Ret::I32(n) => JsValue::from(n),
+            Ret::I64(n) => BigInt::from(n).into(),
+            Ret::F32(n) => JsValue::from(n),
+            Ret::F64(n) => JsValue::from(n),
+        }
+    }
+}
+
+/// Instantiate the reactor and handle requests until the [`Reactor`] is
+/// closed. This blocks the current worker.
+fn serve(
+    builder: WasiEnvBuilder,
+    module: wasmer::Module,
+    ready: oneshot::Sender<Result<Vec<String>, String>>,
+    requests: mpsc::Receiver<Request>,
+) {
+    let mut store = wasmer::Store::default();
+
+    let (instance, env) = match builder.instantiate(module, &mut store) {
+        Ok(pair) => pair,
+        Err(e) => {
+            let _ = ready.send(Err(format!("Unable to instantiate the reactor: {e}")));
+            return;
+        }
+    };
+
+    env.data(&store).thread.set_status_running();
+
+    if let Ok(initialize) = instance.exports.get_function("_initialize") {
+        if let Err(e) = initialize.call(&mut store, &[]) {
+            let _ = ready.send(Err(format!("Unable to initialize the reactor: {e}")));
+            return;
+        }
+    }
+
+    let exports = instance
+        .exports
+        .iter()
+        .functions()
+        .map(|(name, _)| name.clone())
+        .filter(|name| name != "_initialize")
+        .collect();
+    if ready.send(Ok(exports)).is_err() {
+        return;
+    }
+
+    // Note: this loop ends once the Reactor (and therefore the sender) is
+    // dropped.
+    for request in requests {
+        match request {
+            Request::Call {
+                name,
+                args,
+                returns,
+                free_result,
+                reply,
+            } => {
+                // Make sure the caller hears about it if the call panics
+                let reply = Rc::new(Cell::new(Some(reply)));
+                let _panic = crate::tasks::on_panic({
+                    let reply = Rc::clone(&reply);
+                    move |report| {
+                        if let Some(reply) = reply.take() {
+                            let _ = reply
+                                .send(Err(format!("The reactor panicked: {}", report.message)));
+                        }
+                    }
+                });
+
+                let result = call(&mut store, &instance, &name, &args, returns, free_result);
+                if let Some(reply) = reply.take() {
+                    let _ = reply.send(result);
+                }
+            }
+        }
+    }
+
+    env.cleanup(&mut store, Some(ExitCode::Errno(Errno::Success)));
+    tracing::debug!("Reactor closed");
+}
+
+fn call(
+    store: &mut wasmer::Store,
+    instance: &wasmer::Instance,
+    name: &str,
+    args: &[Arg],
+    returns: Returns,
+    free_result: bool,
+) -> Result<Reply, String> {
+    let function = instance
+        .exports
+        .get_function(name)
+        .map_err(|_| format!("The reactor doesn't export a \"{name}\" function"))?;
+
+    let ty = function.ty(&*store);
+    let arity: usize = args.iter().map(Arg::arity).sum();
+    if ty.params().len() != arity {
+        return Err(format!(
+            "\"{name}\" expects {} parameters, but the arguments provided make up {arity}",
+            ty.params().len(),
+        ));
+    }
+
+    let guest = GuestMemory::new(instance);
+    let mut allocations = Vec::new();
+    let lowered = lower(store, &guest, ty.params(), args, &mut allocations);
+
+    let results = lowered.and_then(|params| {
+        function
+            .call(store, &params)
+            .map_err(|e| format!("\"{name}\" failed: {e}"))
+    });
+
+    for ptr in allocations {
+        guest.free(store, ptr)?;
+    }
+
+    let results = results?;
+
+    match returns {
+        Returns::Values => results
+            .iter()
+            .map(Ret::from_value)
+            .collect::<Result<_, _>>()
+            .map(Reply::Values),
+        Returns::String | Returns::Bytes => {
+            let (ptr, len) = match *results {
+                [Value::I32(ptr), Value::I32(len)] => (ptr as u32, len as u32),
+                [Value::I64(packed)] => (packed as u32, (packed >> 32) as u32),
+                _ => return Err(format!("\"{name}\" didn't return a pointer and length")),
+            };
+            let bytes = guest.read(store, ptr, len)?;
+
+            // Give the guest a chance to clean up after itself. The pointer
+            // may be static or borrowed, so we only free it when asked to
+            if let Ok(post_return) = instance.exports.get_function(&format!("cabi_post_{name}")) {
+                post_return
+                    .call(store, &results)
+                    .map_err(|e| format!("Cleaning up after \"{name}\" failed: {e}"))?;
+            } else if free_result {
+                guest.free(store, ptr)?;
+            }
+
+            Ok(Reply::Bytes(bytes))
+        }
+    }
+}
+
+/// Convert arguments into WebAssembly parameters, copying strings and byte
+/// arrays into guest memory.
+///
+/// Any pointers which need to be freed afterwards are added to
+/// `allocations`.
+fn lower(
+    store: &mut wasmer::Store,
+    guest: &GuestMemory,
+    params: &[Type],
+    args: &[Arg],
+    allocations: &mut Vec<u32>,
+) -> Result<Vec<Value>, String> {
+    let mut params = params.iter().copied();
+    let mut values = Vec::new();
+
+    for arg in args {
+        match arg {
+            Arg::Bytes(bytes) => {
+                let (Some(Type::I32), Some(Type::I32)) = (params.next(), params.next()) else {
+                    return Err("Strings and byte arrays must be passed as (i32, i32)".to_string());
+                };
+                let (ptr, owned_by_guest) = guest.alloc(store, bytes.len() as u32)?;
+                if !owned_by_guest {
+                    allocations.push(ptr);
+                }
+                guest.write(store, ptr, bytes)?;
+                values.push(Value::I32(ptr as i32));
+                values.push(Value::I32(bytes.len() as i32));
+            }
+            scalar => {
+                let ty = params.next().expect("checked by the caller");
+                values.push(scalar.to_value(ty)?);
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+/// Access to a guest's linear memory and allocator exports.
+struct GuestMemory<'a> {
+    instance: &'a wasmer::Instance,
+}
+
+impl<'a> GuestMemory<'a> {
+    fn new(instance: &'a wasmer::Instance) -> Self {
+        GuestMemory { instance }
+    }
+
+    fn memory(&self) -> Result<&'a wasmer::Memory, String> {
+        self.instance
+            .exports
+            .get_memory("memory")
+            .map_err(|_| "The reactor doesn't export its memory".to_string())
+    }
+
+    /// Allocate `len` bytes, returning the pointer and whether ownership
+    /// passes to the guest (as is the case with the canonical ABI's
+    /// `cabi_realloc()`).
+    fn alloc(&self, store: &mut wasmer::Store, len: u32) -> Result<(u32, bool), String> {
+        let exports = &self.instance.exports;
+
+        let (result, owned_by_guest) = if let Ok(malloc) = exports.get_function("malloc") {
+            (malloc.call(store, &[Value::I32(len as i32)]), false)
+        } else if let Ok(realloc) = exports.get_function("cabi_realloc") {
+            let args = [
+                Value::I32(0),
+                Value::I32(0),
+                Value::I32(1),
+                Value::I32(len as i32),
+            ];
+            (realloc.call(store, &args), true)
+        } else {
+            return Err(
+                "Passing strings or byte arrays requires the reactor to export \"malloc\" or \"cabi_realloc\""
+                    .to_string(),
+            );
+        };
+
+        match result.map_err(|e| format!("Unable to allocate {len} bytes: {e}"))?[..] {
+            [Value::I32(0)] if len > 0 => Err(format!("Unable to allocate {len} bytes")),
+            [Value::I32(ptr)] => Ok((ptr as u32, owned_by_guest)),
+            _ => Err("The allocator returned an unexpected value".to_string()),
+        }
+    }
+
+    /// Free something allocated with `malloc()`, if the guest lets us.
+    fn free(&self, store: &mut wasmer::Store, ptr: u32) -> Result<(), String> {
+        if let Ok(free) = self.instance.exports.get_function("free") {
+            free.call(store, &[Value::I32(ptr as i32)])
+                .map_err(|e| format!("Unable to free {ptr:#x}: {e}"))?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, store: &wasmer::Store, ptr: u32, bytes: &[u8]) -> Result<(), String> {
+        self.memory()?
+            .view(store)
+            .write(ptr.into(), bytes)
+            .map_err(|e| format!("Unable to write to guest memory: {e}"))
+    }
+
+    fn read(&self, store: &wasmer::Store, ptr: u32, len: u32) -> Result<Vec<u8>, String> {
+        let mut buffer = vec![0; len as usize];
+        self.memory()?
+            .view(store)
+            .read(ptr.into(), &mut buffer)
+            .map_err(|e| format!("Unable to read from guest memory: {e}"))?;
+        Ok(buffer)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const REACTOR_TYPE_DECLARATIONS: &str = r#"
+/**
+ * Options for {@link Reactor.call}.
+ */
+export type CallOptions = {
+    /**
+     * How the function's results should be returned.
+     *
+     * - `"values"` (the default) returns the raw WebAssembly values
+     * - `"string"` reads a UTF-8 string out of guest memory
+     * - `"bytes"` reads a `Uint8Array` out of guest memory
+     */
+    returns?: "values" | "string" | "bytes";
+    /**
+     * Pass the memory a `"string"` or `"bytes"` result was read from to the
+     * guest's `free()` afterwards, for functions which return memory they
+     * allocated with `malloc()`.
+     *
+     * Defaults to `false`, because the result may point at static or
+     * borrowed memory. Ignored if the guest exports `cabi_post_<name>()`,
+     * which is always called instead.
+     */
+    freeResult?: boolean;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "CallOptions")]
+    pub type CallOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn returns(this: &CallOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "freeResult")]
+    fn free_result(this: &CallOptions) -> Option<bool>;
+
+    #[wasm_bindgen(
+        typescript_type = "number | bigint | string | Uint8Array | (number | bigint)[] | undefined"
+    )]
+    pub type CallResult;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn arguments_are_converted_using_the_parameter_type() {
+        assert_eq!(Arg::Number(42.9).to_value(Type::I32), Ok(Value::I32(42)));
+        assert_eq!(Arg::Number(42.0).to_value(Type::I64), Ok(Value::I64(42)));
+        assert_eq!(Arg::BigInt(-1).to_value(Type::F64), Ok(Value::F64(-1.0)));
+        assert!(Arg::Number(1.0).to_value(Type::V128).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn strings_and_bytes_are_lowered_to_a_pointer_and_length() {
+        let string = Arg::from_js(JsValue::from_str("hi")).unwrap();
+        let bytes = Arg::from_js(Uint8Array::from([1_u8, 2, 3].as_slice()).into()).unwrap();
+
+        assert_eq!(string, Arg::Bytes(b"hi".to_vec()));
+        assert_eq!(bytes, Arg::Bytes(vec![1, 2, 3]));
+        assert_eq!(string.arity(), 2);
+        assert_eq!(Arg::Number(1.0).arity(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn i64_results_become_bigints() {
+        let value = JsValue::from(Ret::I64(i64::MAX));
+
+        assert!(value.is_bigint());
+    }
+}
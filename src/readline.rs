@@ -0,0 +1,497 @@
+//! Line editing conveniences (history and tab completion) for interactive
+//! programs that rely on the TTY's line buffering instead of their own
+//! readline implementation.
+//!
+//! While the TTY is in line-buffered mode, we sit between the user's stdin
+//! and the TTY, watching what gets typed. The arrow keys recall earlier
+//! commands by erasing the current line (with backspaces) and typing the old
+//! one, and tab asks a JavaScript callback for completions. Programs that
+//! switch the TTY into raw mode get their input untouched.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use js_sys::{Array, Function, JsString, Promise};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbTransactionMode};
+
+use crate::utils::Error;
+
+const STORE_NAME: &str = "history";
+const KEY: &str = "lines";
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+const BACKSPACE: u8 = 0x7f;
+const CTRL_H: u8 = 0x08;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const ESC: u8 = 0x1b;
+const TAB: u8 = b'\t';
+
+/// A persistent list of commands entered into an interactive program, stored
+/// in IndexedDB so it survives page reloads.
+///
+/// @example
+/// ```ts
+/// const history = await CommandHistory.open("bash");
+/// const instance = await pkg.entrypoint.run({ readline: { history } });
+/// ```
+#[derive(Debug, Clone, wasm_bindgen_derive::TryFromJsValue)]
+#[wasm_bindgen]
+pub struct CommandHistory {
+    name: String,
+    db: IdbDatabase,
+    max_entries: usize,
+    entries: Rc<RefCell<VecDeque<String>>>,
+}
+
+#[wasm_bindgen]
+impl CommandHistory {
+    /// Open the history called `name`, loading previously saved commands.
+    ///
+    /// Only the most recent `maxEntries` commands (1000 by default) are
+    /// kept.
+    pub async fn open(name: String, max_entries: Option<u32>) -> Result<CommandHistory, Error> {
+        let db = crate::idb::open(&format!("wasmer-history/{name}"), STORE_NAME).await?;
+
+        let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readonly)?;
+        let request = store.get(&JsValue::from_str(KEY)).map_err(Error::js)?;
+        let saved = crate::idb::complete(&request).await?;
+        let entries: VecDeque<String> = match saved.dyn_into::<Array>() {
+            Ok(lines) => lines.iter().filter_map(|line| line.as_string()).collect(),
+            Err(_) => VecDeque::new(),
+        };
+        tracing::debug!(%name, entries = entries.len(), "Loaded the command history");
+
+        Ok(CommandHistory {
+            name,
+            db,
+            max_entries: max_entries.map_or(DEFAULT_MAX_ENTRIES, |n| n as usize),
+            entries: Rc::new(RefCell::new(entries)),
+        })
+    }
+
+    /// The name this history was opened with.
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Every saved command, oldest first.
+    pub fn entries(&self) -> ListOfCommands {
+        let entries: Array = self
+            .entries
+            .borrow()
+            .iter()
+            .map(|line| JsValue::from(line.as_str()))
+            .collect();
+        entries.unchecked_into()
+    }
+
+    /// Save a command.
+    ///
+    /// Blank lines and repeats of the previous command are ignored.
+    pub async fn add(&self, line: String) -> Result<(), Error> {
+        if self.push(&line) {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Forget every saved command.
+    pub async fn clear(&self) -> Result<(), Error> {
+        self.entries.borrow_mut().clear();
+        self.save().await
+    }
+}
+
+impl CommandHistory {
+    /// Add a command without saving it, returning `false` if it was ignored.
+    fn push(&self, line: &str) -> bool {
+        let line = line.trim_end();
+        let mut entries = self.entries.borrow_mut();
+
+        if line.trim().is_empty() || entries.back().map(|s| s.as_str()) == Some(line) {
+            return false;
+        }
+
+        entries.push_back(line.to_string());
+        while entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+
+        true
+    }
+
+    fn get(&self, index: usize) -> Option<String> {
+        self.entries.borrow().get(index).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    async fn save(&self) -> Result<(), Error> {
+        let lines: Array = self
+            .entries
+            .borrow()
+            .iter()
+            .map(|line| JsValue::from(line.as_str()))
+            .collect();
+
+        let store = crate::idb::object_store(&self.db, STORE_NAME, IdbTransactionMode::Readwrite)?;
+        let request = store
+            .put_with_key(&lines, &JsValue::from_str(KEY))
+            .map_err(Error::js)?;
+        crate::idb::complete(&request).await?;
+
+        Ok(())
+    }
+}
+
+/// Tracks the line being typed and turns history and completion keys into
+/// edits.
+#[derive(Debug, Default)]
+pub(crate) struct LineEditor {
+    history: Option<CommandHistory>,
+    complete: Option<Function>,
+    /// What we think the TTY's current line contains.
+    line: String,
+    /// Which history entry is being shown, if any.
+    recalled: Option<usize>,
+    /// The line that was being typed before we started recalling history.
+    draft: String,
+    /// The start of an escape sequence split across reads.
+    pending: Vec<u8>,
+}
+
+/// Something the [`LineEditor`] wants done with a chunk of input.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Edit {
+    /// Pass these bytes on to the TTY.
+    Forward(Vec<u8>),
+    /// A line was entered and should be saved to the history.
+    Submitted(String),
+    /// Tab was pressed and completions for the line should be looked up.
+    Complete(String),
+}
+
+impl LineEditor {
+    pub(crate) fn new(history: Option<CommandHistory>, complete: Option<Function>) -> Self {
+        LineEditor {
+            history,
+            complete,
+            ..Default::default()
+        }
+    }
+
+    /// Process input typed by the user.
+    ///
+    /// When `line_buffered` is `false`, the program is doing its own line
+    /// editing, so the input is passed through as-is.
+    pub(crate) fn process(&mut self, data: &[u8], line_buffered: bool) -> Vec<Edit> {
+        if !line_buffered {
+            self.reset();
+            let mut input = std::mem::take(&mut self.pending);
+            input.extend_from_slice(data);
+            return vec![Edit::Forward(input)];
+        }
+
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut edits = Vec::new();
+        let mut forward = Vec::new();
+        let mut i = 0;
+
+        while i < input.len() {
+            let byte = input[i];
+
+            match byte {
+                ESC => {
+                    let rest = &input[i..];
+                    match parse_escape(rest) {
+                        Escape::Incomplete => {
+                            self.pending = rest.to_vec();
+                            break;
+                        }
+                        Escape::Up(len) => {
+                            self.recall_previous(&mut forward);
+                            i += len;
+                            continue;
+                        }
+                        Escape::Down(len) => {
+                            self.recall_next(&mut forward);
+                            i += len;
+                            continue;
+                        }
+                        Escape::Other(len) => {
+                            forward.extend_from_slice(&rest[..len]);
+                            i += len;
+                            continue;
+                        }
+                    }
+                }
+                b'\r' | b'\n' => {
+                    forward.push(byte);
+                    flush(&mut edits, &mut forward);
+                    let line = std::mem::take(&mut self.line);
+                    self.recalled = None;
+                    edits.push(Edit::Submitted(line));
+                }
+                BACKSPACE | CTRL_H => {
+                    self.line.pop();
+                    forward.push(byte);
+                }
+                CTRL_C | CTRL_U => {
+                    self.reset();
+                    forward.push(byte);
+                }
+                TAB if self.complete.is_some() => {
+                    flush(&mut edits, &mut forward);
+                    edits.push(Edit::Complete(self.line.clone()));
+                }
+                _ => {
+                    // Note: Multi-byte UTF-8 characters are re-assembled
+                    // lossily, which only matters for how many backspaces we
+                    // send when recalling history.
+                    if (0x20..0x80).contains(&byte) {
+                        self.line.push(byte as char);
+                    } else if byte >= 0xc0 {
+                        self.line.push('\u{fffd}');
+                    }
+                    forward.push(byte);
+                }
+            }
+
+            i += 1;
+        }
+
+        flush(&mut edits, &mut forward);
+        edits
+    }
+
+    /// Type out the rest of a completion, returning the bytes to send to the
+    /// TTY.
+    pub(crate) fn insert(&mut self, text: &str) -> Vec<u8> {
+        self.line.push_str(text);
+        text.as_bytes().to_vec()
+    }
+
+    /// Ask the completion callback for candidates for `line`.
+    pub(crate) async fn completions(&self, line: &str) -> Result<Vec<String>, Error> {
+        let Some(complete) = &self.complete else {
+            return Ok(Vec::new());
+        };
+
+        let mut result = complete
+            .call1(&JsValue::NULL, &JsValue::from(line))
+            .map_err(Error::js)?;
+        if let Some(promise) = result.dyn_ref::<Promise>() {
+            result = JsFuture::from(promise.clone()).await.map_err(Error::js)?;
+        }
+
+        let candidates = result
+            .dyn_into::<Array>()
+            .map_err(|_| anyhow::anyhow!("The completion callback must return an array"))?;
+
+        Ok(candidates
+            .iter()
+            .filter_map(|c| c.dyn_into::<JsString>().ok())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Save a command to the history, if there is one.
+    pub(crate) async fn save(&self, line: &str) -> Result<(), Error> {
+        match &self.history {
+            Some(history) => history.add(line.to_string()).await,
+            None => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.draft.clear();
+        self.recalled = None;
+    }
+
+    fn recall_previous(&mut self, forward: &mut Vec<u8>) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let index = match self.recalled {
+            None if history.len() == 0 => return,
+            None => {
+                self.draft = self.line.clone();
+                history.len() - 1
+            }
+            Some(0) => return,
+            Some(i) => i - 1,
+        };
+
+        if let Some(entry) = history.get(index) {
+            self.recalled = Some(index);
+            self.replace_line(entry, forward);
+        }
+    }
+
+    fn recall_next(&mut self, forward: &mut Vec<u8>) {
+        let (Some(history), Some(index)) = (&self.history, self.recalled) else {
+            return;
+        };
+
+        match history.get(index + 1) {
+            Some(entry) => {
+                self.recalled = Some(index + 1);
+                self.replace_line(entry, forward);
+            }
+            None => {
+                self.recalled = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.replace_line(draft, forward);
+            }
+        }
+    }
+
+    /// Erase the current line and type `new_line` in its place.
+    fn replace_line(&mut self, new_line: String, forward: &mut Vec<u8>) {
+        forward.extend(std::iter::repeat(BACKSPACE).take(self.line.chars().count()));
+        forward.extend_from_slice(new_line.as_bytes());
+        self.line = new_line;
+    }
+}
+
+fn flush(edits: &mut Vec<Edit>, forward: &mut Vec<u8>) {
+    if !forward.is_empty() {
+        edits.push(Edit::Forward(std::mem::take(forward)));
+    }
+}
+
+/// The longest prefix every completion shares, minus what was already typed.
+pub(crate) fn common_suffix(line: &str, candidates: &[String]) -> String {
+    let word_start = line.rfind(' ').map_or(0, |i| i + 1);
+    let word = &line[word_start..];
+
+    let mut matching = candidates.iter().filter(|c| c.starts_with(word));
+    let Some(first) = matching.next() else {
+        return String::new();
+    };
+
+    let mut prefix = first.as_str();
+    for candidate in matching {
+        let shared = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(candidate.len()), |((i, _), _)| i);
+        prefix = &prefix[..shared];
+    }
+
+    prefix[word.len()..].to_string()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Escape {
+    Incomplete,
+    Up(usize),
+    Down(usize),
+    Other(usize),
+}
+
+/// Recognise the up and down arrow keys (`ESC [ A` and `ESC [ B`, or the
+/// `ESC O` variants used in application cursor mode).
+fn parse_escape(bytes: &[u8]) -> Escape {
+    match bytes {
+        [ESC] | [ESC, b'[' | b'O'] => Escape::Incomplete,
+        [ESC, b'[' | b'O', b'A', ..] => Escape::Up(3),
+        [ESC, b'[' | b'O', b'B', ..] => Escape::Down(3),
+        [ESC, b'[', rest @ ..] => {
+            // CSI sequences end with a byte in the range 0x40..=0x7e
+            match rest.iter().position(|b| (0x40..=0x7e).contains(b)) {
+                Some(end) => Escape::Other(end + 3),
+                None => Escape::Incomplete,
+            }
+        }
+        [ESC, _, ..] => Escape::Other(2),
+        _ => Escape::Other(1),
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const READLINE_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Line editing for interactive programs that use the TTY's line buffering
+ * (e.g. simple REPLs), rather than their own readline implementation.
+ */
+export type ReadlineOptions = {
+    /**
+     * Where entered commands are saved. The up and down arrow keys recall
+     * earlier commands.
+     */
+    history?: CommandHistory;
+    /**
+     * Called when tab is pressed with the line typed so far, returning
+     * possible completions for its last word. The longest prefix they share
+     * is typed out.
+     */
+    complete?: (line: string) => string[] | Promise<string[]>;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ReadlineOptions")]
+    pub type ReadlineOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn history(this: &ReadlineOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn complete(this: &ReadlineOptions) -> Option<Function>;
+
+    #[wasm_bindgen(typescript_type = "string[]")]
+    pub type ListOfCommands;
+}
+
+impl ReadlineOptions {
+    pub(crate) fn editor(&self) -> Result<LineEditor, Error> {
+        let history = self.history();
+        let history = if history.is_undefined() || history.is_null() {
+            None
+        } else {
+            Some(CommandHistory::try_from(&history).map_err(|_| {
+                Error::js(js_sys::TypeError::new(
+                    "\"readline.history\" must be a CommandHistory",
+                ))
+            })?)
+        };
+
+        Ok(LineEditor::new(history, self.complete()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn escape_sequences() {
+        assert_eq!(parse_escape(b"\x1b"), Escape::Incomplete);
+        assert_eq!(parse_escape(b"\x1b[A"), Escape::Up(3));
+        assert_eq!(parse_escape(b"\x1bOB..."), Escape::Down(3));
+        assert_eq!(parse_escape(b"\x1b[1;5C"), Escape::Other(6));
+        assert_eq!(parse_escape(b"\x1b[1;5"), Escape::Incomplete);
+    }
+
+    #[wasm_bindgen_test]
+    fn complete_the_shared_prefix() {
+        let candidates = ["install".to_string(), "instance".to_string()];
+
+        assert_eq!(common_suffix("npm in", &candidates), "st");
+        assert_eq!(common_suffix("npm x", &candidates), "");
+        assert_eq!(common_suffix("npm install", &candidates), "");
+    }
+}
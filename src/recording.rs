@@ -0,0 +1,220 @@
+//! Recording interactive terminal sessions as [asciicast v2][asciicast]
+//! files, so demos and bug reports can be replayed exactly as they
+//! happened (e.g. with `asciinema play`).
+//!
+//! Only programs attached to a TTY are recorded. Input is recorded as the
+//! user typed it, before the TTY or `readline` gets a chance to edit it.
+//!
+//! [asciicast]: https://docs.asciinema.org/manual/asciicast/v2/
+
+use std::{
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+};
+
+use js_sys::{Array, JsString};
+
+use crate::{streams::BoxedFile, utils::Error};
+
+/// Stop recording once this much has been captured, so a long-running
+/// session can't eat all the page's memory.
+const MAX_RECORDED_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum EventKind {
+    Output,
+    Input,
+}
+
+impl EventKind {
+    fn code(self) -> &'static str {
+        match self {
+            EventKind::Output => "o",
+            EventKind::Input => "i",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Event {
+    /// Milliseconds since the recording started.
+    elapsed_ms: f64,
+    kind: EventKind,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct RecordingState {
+    /// When the recording started, in milliseconds since the Unix epoch.
+    started_at: f64,
+    width: u32,
+    height: u32,
+    events: Vec<Event>,
+    bytes: usize,
+    truncated: bool,
+}
+
+/// Everything written to and typed into a terminal, with timings.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionRecording(Arc<Mutex<RecordingState>>);
+
+impl SessionRecording {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        SessionRecording::starting_at(crate::sequenced_output::now(), width, height)
+    }
+
+    fn starting_at(started_at: f64, width: u32, height: u32) -> Self {
+        SessionRecording(Arc::new(Mutex::new(RecordingState {
+            started_at,
+            width,
+            height,
+            events: Vec::new(),
+            bytes: 0,
+            truncated: false,
+        })))
+    }
+
+    pub(crate) fn record(&self, kind: EventKind, data: &[u8]) {
+        self.record_at(crate::sequenced_output::now(), kind, data);
+    }
+
+    fn record_at(&self, timestamp: f64, kind: EventKind, data: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+        if state.truncated || data.is_empty() {
+            return;
+        }
+        if state.bytes + data.len() > MAX_RECORDED_BYTES {
+            tracing::warn!(
+                limit = MAX_RECORDED_BYTES,
+                "The session recording is full, so the rest of the session won't be recorded"
+            );
+            state.truncated = true;
+            return;
+        }
+
+        state.bytes += data.len();
+        let elapsed_ms = (timestamp - state.started_at).max(0.0);
+        state.events.push(Event {
+            elapsed_ms,
+            kind,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Wrap one of the program's output streams so everything written to it
+    /// is recorded.
+    pub(crate) fn watch(&self, file: BoxedFile) -> BoxedFile {
+        let recording = self.clone();
+        crate::streams::watch_writes(file, move |data| recording.record(EventKind::Output, data))
+    }
+
+    /// Render the recording as an asciicast v2 file.
+    pub(crate) fn to_asciicast(&self) -> String {
+        let state = self.0.lock().unwrap();
+        let mut cast = format!(
+            r#"{{"version": 2, "width": {}, "height": {}, "timestamp": {}}}"#,
+            state.width,
+            state.height,
+            (state.started_at / 1000.0).floor(),
+        );
+        cast.push('\n');
+
+        // Note: a chunk can end part-way through a UTF-8 character, so the
+        // remainder is carried over to the stream's next chunk
+        let mut carried: [Vec<u8>; 2] = Default::default();
+        for event in &state.events {
+            let carry = &mut carried[event.kind as usize];
+            carry.extend_from_slice(&event.data);
+            let complete = carry.len() - incomplete_tail(carry);
+            let text = String::from_utf8_lossy(&carry[..complete]).into_owned();
+            carry.drain(..complete);
+            if text.is_empty() {
+                continue;
+            }
+
+            let _ = write!(
+                cast,
+                "[{:.6}, \"{}\", ",
+                event.elapsed_ms / 1000.0,
+                event.kind.code()
+            );
+            push_json_string(&mut cast, &text);
+            cast.push_str("]\n");
+        }
+
+        cast
+    }
+
+    pub(crate) fn to_blob(&self) -> Result<web_sys::Blob, Error> {
+        web_sys::Blob::new_with_str_sequence_and_options(
+            Array::of1(&JsString::from(self.to_asciicast())).as_ref(),
+            web_sys::BlobPropertyBag::new().type_("application/x-asciicast"),
+        )
+        .map_err(Error::js)
+    }
+}
+
+/// How many bytes at the end of `bytes` are the start of a UTF-8 character
+/// which hasn't been finished yet.
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - len];
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            // A continuation byte, so keep looking for the leading byte
+            continue;
+        }
+        let expected = byte.leading_ones() as usize;
+        return if expected > len { len } else { 0 };
+    }
+    0
+}
+
+fn push_json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn render_an_asciicast() {
+        let recording = SessionRecording::starting_at(1_700_000_000_000.0, 80, 24);
+        recording.record_at(1_700_000_000_250.0, EventKind::Output, b"\x1b[1m$\x1b[0m ");
+        recording.record_at(1_700_000_001_000.0, EventKind::Input, b"echo \"h\xc3");
+        recording.record_at(1_700_000_001_500.0, EventKind::Input, b"\xa9\"\r");
+        recording.record_at(1_700_000_001_750.0, EventKind::Output, b"h\xc3\xa9\r\n");
+
+        assert_eq!(
+            recording.to_asciicast(),
+            concat!(
+                r#"{"version": 2, "width": 80, "height": 24, "timestamp": 1700000000}"#,
+                "\n",
+                r#"[0.250000, "o", "\u001b[1m$\u001b[0m "]"#,
+                "\n",
+                r#"[1.000000, "i", "echo \"h"]"#,
+                "\n",
+                r#"[1.500000, "i", "é\"\r"]"#,
+                "\n",
+                r#"[1.750000, "o", "hé\r\n"]"#,
+                "\n",
+            )
+        );
+    }
+}
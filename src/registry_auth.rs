@@ -0,0 +1,292 @@
+//! Authenticating with registries which hand out short-lived tokens.
+//!
+//! Instead of a fixed `apiKey`, the host can provide a `getApiKey()`
+//! callback. It is asked for a token before the first registry request and
+//! again whenever the registry rejects the current one with a
+//! `401 Unauthorized`, after which the request is retried once.
+//!
+//! Refreshes are single-flight: if several requests get a `401` at the same
+//! time, the callback is only called once and they all retry with its
+//! result.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use http::{HeaderValue, StatusCode};
+use js_sys::{Function, Promise};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer_wasix::http::{DynHttpClient, HttpClient, HttpRequest, HttpResponse};
+
+use crate::tasks::ThreadPool;
+
+thread_local! {
+    /// `getApiKey()` callbacks, which live on the scheduler's thread.
+    static PROVIDERS: RefCell<BTreeMap<u32, Function>> = RefCell::default();
+}
+
+type Refresh =
+    Arc<dyn Fn(Option<String>) -> BoxFuture<'static, Result<String, String>> + Send + Sync>;
+type PendingRefresh = Shared<BoxFuture<'static, Result<String, String>>>;
+
+/// The registry token currently in use, and a way to get a new one.
+#[derive(Clone, derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct TokenSource {
+    state: Arc<Mutex<TokenState>>,
+    #[derivative(Debug = "ignore")]
+    refresh: Refresh,
+}
+
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+struct TokenState {
+    token: Option<String>,
+    #[derivative(Debug = "ignore")]
+    refreshing: Option<PendingRefresh>,
+}
+
+impl TokenSource {
+    fn new(initial: Option<String>, refresh: Refresh) -> Self {
+        TokenSource {
+            state: Arc::new(Mutex::new(TokenState {
+                token: initial,
+                refreshing: None,
+            })),
+            refresh,
+        }
+    }
+
+    /// Get tokens by calling `callback` on the scheduler's thread, starting
+    /// with `initial` if the host already has one.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn from_callback(
+        pool: ThreadPool,
+        callback: Function,
+        initial: Option<String>,
+    ) -> Self {
+        static NEXT_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        PROVIDERS.with(|providers| providers.borrow_mut().insert(id, callback));
+
+        let refresh: Refresh = Arc::new(move |expired| {
+            let (sender, receiver) = oneshot::channel();
+            pool.run_on_scheduler(Box::new(move || {
+                wasm_bindgen_futures::spawn_local(async move {
+                    let _ = sender.send(ask(id, expired).await);
+                });
+            }));
+
+            Box::pin(async move {
+                receiver
+                    .await
+                    .unwrap_or_else(|_| Err("The runtime was shut down".to_string()))
+            })
+        });
+
+        TokenSource::new(initial, refresh)
+    }
+
+    /// The token to send, fetching one if we don't have it yet.
+    async fn current(&self) -> Result<String, String> {
+        let token = self.state.lock().unwrap().token.clone();
+        match token {
+            Some(token) => Ok(token),
+            None => self.refresh(None).await,
+        }
+    }
+
+    /// Replace `expired` with a new token.
+    ///
+    /// If the token has already been replaced since `expired` was handed
+    /// out, the replacement is used instead of asking again.
+    async fn refresh(&self, expired: Option<String>) -> Result<String, String> {
+        let (pending, started) = {
+            let mut state = self.state.lock().unwrap();
+            match (&state.token, &state.refreshing) {
+                (Some(token), _) if Some(token) != expired.as_ref() => {
+                    return Ok(token.clone());
+                }
+                (_, Some(pending)) => (pending.clone(), false),
+                _ => {
+                    tracing::debug!("Asking the host for a new registry token");
+                    let pending = (self.refresh)(expired).shared();
+                    state.refreshing = Some(pending.clone());
+                    (pending, true)
+                }
+            }
+        };
+
+        let result = pending.await;
+
+        if started {
+            let mut state = self.state.lock().unwrap();
+            state.refreshing = None;
+            if let Ok(token) = &result {
+                state.token = Some(token.clone());
+            }
+        }
+
+        result
+    }
+}
+
+/// Call a `getApiKey()` callback, waiting for it if it returns a promise.
+async fn ask(id: u32, expired: Option<String>) -> Result<String, String> {
+    let Some(callback) = PROVIDERS.with(|providers| providers.borrow().get(&id).cloned()) else {
+        return Err("The getApiKey() callback is gone".to_string());
+    };
+
+    let expired = expired.map(JsValue::from).unwrap_or(JsValue::UNDEFINED);
+    let value = match callback.call1(&JsValue::NULL, &expired) {
+        Ok(value) => match value.dyn_into::<Promise>() {
+            Ok(promise) => JsFuture::from(promise).await,
+            Err(value) => Ok(value),
+        },
+        Err(e) => Err(e),
+    };
+
+    match value {
+        Ok(value) => value
+            .as_string()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| "getApiKey() didn't return a token".to_string()),
+        Err(e) => {
+            tracing::warn!(error = ?e, "The getApiKey() callback failed");
+            Err(format!("getApiKey() failed: {e:?}"))
+        }
+    }
+}
+
+/// An [`HttpClient`] which authenticates every request with the current
+/// token, refreshing it and retrying once if the server responds with a
+/// `401 Unauthorized`.
+#[derive(Debug, Clone)]
+pub(crate) struct AuthenticatedHttpClient {
+    pub(crate) inner: DynHttpClient,
+    pub(crate) tokens: TokenSource,
+}
+
+impl AuthenticatedHttpClient {
+    async fn send(
+        &self,
+        request: &HttpRequest,
+        token: &str,
+    ) -> Result<HttpResponse, anyhow::Error> {
+        let mut headers = request.headers.clone();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}"))?,
+        );
+
+        let request = HttpRequest {
+            url: request.url.clone(),
+            method: request.method.clone(),
+            headers,
+            body: request.body.clone(),
+            options: request.options.clone(),
+        };
+        self.inner.request(request).await
+    }
+}
+
+impl HttpClient for AuthenticatedHttpClient {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, anyhow::Error>> {
+        Box::pin(async move {
+            let token = self.tokens.current().await.map_err(anyhow::Error::msg)?;
+            let response = self.send(&request, &token).await?;
+            if response.status != StatusCode::UNAUTHORIZED {
+                return Ok(response);
+            }
+
+            tracing::debug!(url = %request.url, "The registry rejected our token");
+            let token = self
+                .tokens
+                .refresh(Some(token))
+                .await
+                .map_err(anyhow::Error::msg)?;
+            self.send(&request, &token).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use http::HeaderMap;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    /// Only accepts requests made with the token called `"fresh"`.
+    #[derive(Debug, Default)]
+    struct Registry {
+        requests: AtomicUsize,
+    }
+
+    impl HttpClient for Registry {
+        fn request(
+            &self,
+            request: HttpRequest,
+        ) -> BoxFuture<'_, Result<HttpResponse, anyhow::Error>> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let authorized = request.headers.get("Authorization")
+                == Some(&HeaderValue::from_static("Bearer fresh"));
+
+            Box::pin(async move {
+                Ok(HttpResponse {
+                    body: None,
+                    redirected: false,
+                    status: if authorized {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::UNAUTHORIZED
+                    },
+                    headers: HeaderMap::new(),
+                })
+            })
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn concurrent_401s_only_refresh_once() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let refresh: Refresh = Arc::new({
+            let refreshes = Arc::clone(&refreshes);
+            move |expired| {
+                assert_eq!(expired.as_deref(), Some("stale"));
+                refreshes.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Ok("fresh".to_string()) })
+            }
+        });
+        let registry = Arc::new(Registry::default());
+        let client = AuthenticatedHttpClient {
+            inner: registry.clone(),
+            tokens: TokenSource::new(Some("stale".to_string()), refresh),
+        };
+        let get = || HttpRequest {
+            url: "https://registry.example/graphql".parse().unwrap(),
+            method: http::Method::POST,
+            headers: HeaderMap::new(),
+            body: None,
+            options: Default::default(),
+        };
+
+        let (first, second) = futures::join!(client.request(get()), client.request(get()));
+
+        assert_eq!(first.unwrap().status, StatusCode::OK);
+        assert_eq!(second.unwrap().status, StatusCode::OK);
+        assert_eq!(refreshes.load(Ordering::Relaxed), 1);
+        assert_eq!(registry.requests.load(Ordering::Relaxed), 4);
+    }
+}
@@ -0,0 +1,228 @@
+//! Streaming a remote resource into a program's stdin, resuming with `Range`
+//! requests if the download fails part way through.
+
+use futures::StreamExt;
+use js_sys::Uint8Array;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Request, RequestInit, Response, WritableStreamDefaultWriter};
+
+use crate::utils::{Error, GlobalScope};
+
+const DEFAULT_RETRIES: u32 = 3;
+const INITIAL_BACKOFF_MS: u32 = 250;
+const MAX_BACKOFF_MS: u32 = 5_000;
+
+/// How to download the resource.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RemoteStdin {
+    pub(crate) url: String,
+    /// How many times in a row a download may fail before giving up.
+    pub(crate) retries: u32,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+/// Why a single download attempt stopped early.
+#[derive(Debug)]
+enum Failure {
+    /// Something that might work if we try again (e.g. a dropped
+    /// connection or a 503).
+    Transient(Error),
+    /// Something that won't get better (e.g. a 404, or stdin being closed).
+    Fatal(Error),
+}
+
+impl RemoteStdin {
+    pub(crate) fn new(url: String, options: Option<StdinFromUrlOptions>) -> Result<Self, Error> {
+        let headers = match options.as_ref().and_then(|opts| opts.headers()) {
+            Some(headers) => crate::utils::js_record_of_strings(&headers)?,
+            None => Vec::new(),
+        };
+
+        Ok(RemoteStdin {
+            url,
+            retries: options
+                .as_ref()
+                .and_then(|opts| opts.retries())
+                .map(|r| r.max(0.0) as u32)
+                .unwrap_or(DEFAULT_RETRIES),
+            headers,
+        })
+    }
+
+    /// Copy the whole resource to `writer`, returning how many bytes were
+    /// written.
+    ///
+    /// Each chunk is only requested once the stream is ready for more, so a
+    /// program that reads slowly slows the download down rather than having
+    /// it buffered in memory.
+    pub(crate) async fn copy_to(&self, writer: &WritableStreamDefaultWriter) -> Result<u64, Error> {
+        let mut offset = 0;
+        let mut failures = 0;
+
+        loop {
+            let started_at = offset;
+
+            match self.attempt(&mut offset, writer).await {
+                Ok(()) => return Ok(offset),
+                Err(Failure::Fatal(e)) => return Err(e),
+                Err(Failure::Transient(e)) => {
+                    if offset > started_at {
+                        // We made progress, so only count consecutive failures
+                        failures = 0;
+                    }
+                    if failures >= self.retries {
+                        return Err(e);
+                    }
+
+                    let delay = backoff(failures);
+                    tracing::debug!(
+                        error = &*e.into_anyhow(),
+                        url = %self.url,
+                        offset,
+                        delay_ms = delay,
+                        "Download failed, resuming shortly",
+                    );
+                    failures += 1;
+                    let _ = JsFuture::from(GlobalScope::current().sleep(delay as i32)).await;
+                }
+            }
+        }
+    }
+
+    async fn attempt(
+        &self,
+        offset: &mut u64,
+        writer: &WritableStreamDefaultWriter,
+    ) -> Result<(), Failure> {
+        let response = self.fetch(*offset).await.map_err(Failure::Transient)?;
+
+        let status = response.status();
+        let mut to_skip = match status {
+            206 => 0,
+            // The server ignored our Range header, so we need to throw away
+            // everything we've already written.
+            200 => *offset,
+            // We asked for everything after the end, so there's nothing left
+            416 if *offset > 0 => return Ok(()),
+            500..=599 | 408 | 429 => {
+                let msg = format!("{} responded with {status}", self.url);
+                return Err(Failure::Transient(anyhow::anyhow!(msg).into()));
+            }
+            _ => {
+                let msg = format!("{} responded with {status}", self.url);
+                return Err(Failure::Fatal(anyhow::anyhow!(msg).into()));
+            }
+        };
+
+        let Some(body) = response.body() else {
+            return Ok(());
+        };
+        let mut chunks = Box::pin(crate::streams::read_to_end(body));
+
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(Failure::Transient)?;
+            let chunk = skip_prefix(&chunk, &mut to_skip);
+            if chunk.is_empty() {
+                continue;
+            }
+
+            JsFuture::from(writer.ready())
+                .await
+                .map_err(|e| Failure::Fatal(Error::js(e)))?;
+            JsFuture::from(writer.write_with_chunk(&Uint8Array::from(chunk)))
+                .await
+                .map_err(|e| Failure::Fatal(Error::js(e)))?;
+            *offset += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    async fn fetch(&self, offset: u64) -> Result<Response, Error> {
+        let headers = Headers::new().map_err(Error::js)?;
+        for (name, value) in &self.headers {
+            headers.set(name, value).map_err(Error::js)?;
+        }
+        if offset > 0 {
+            headers
+                .set("Range", &format!("bytes={offset}-"))
+                .map_err(Error::js)?;
+        }
+
+        let mut init = RequestInit::new();
+        init.headers(&headers);
+        let request = Request::new_with_str_and_init(&self.url, &init).map_err(Error::js)?;
+
+        let response = JsFuture::from(GlobalScope::current().fetch(&request))
+            .await
+            .map_err(Error::js)?;
+        Ok(response.unchecked_into())
+    }
+}
+
+/// Drop the first `to_skip` bytes of a stream, one chunk at a time.
+fn skip_prefix<'a>(chunk: &'a [u8], to_skip: &mut u64) -> &'a [u8] {
+    let skipped = chunk
+        .len()
+        .min(usize::try_from(*to_skip).unwrap_or(usize::MAX));
+    *to_skip -= skipped as u64;
+    &chunk[skipped..]
+}
+
+/// How long to wait (in milliseconds) after the `failures`-th consecutive
+/// failure.
+fn backoff(failures: u32) -> u32 {
+    INITIAL_BACKOFF_MS
+        .saturating_mul(1 << failures.min(16))
+        .min(MAX_BACKOFF_MS)
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const STDIN_FROM_URL_OPTIONS_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Options for {@link Instance.stdinFromUrl}.
+ */
+export type StdinFromUrlOptions = {
+    /**
+     * How many times in a row the download may fail before giving up.
+     * Defaults to `3`.
+     */
+    retries?: number;
+    /** Extra headers to send with each request. */
+    headers?: Record<string, string>;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "StdinFromUrlOptions")]
+    pub type StdinFromUrlOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn retries(this: &StdinFromUrlOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter)]
+    fn headers(this: &StdinFromUrlOptions) -> Option<js_sys::Object>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn resuming_without_range_support_skips_what_was_written() {
+        let mut to_skip = 5;
+
+        assert_eq!(skip_prefix(b"abc", &mut to_skip), b"");
+        assert_eq!(skip_prefix(b"defgh", &mut to_skip), b"fgh");
+        assert_eq!(skip_prefix(b"ijk", &mut to_skip), b"ijk");
+        assert_eq!(to_skip, 0);
+
+        assert_eq!(backoff(0), INITIAL_BACKOFF_MS);
+        assert_eq!(backoff(1), INITIAL_BACKOFF_MS * 2);
+        assert_eq!(backoff(30), MAX_BACKOFF_MS);
+    }
+}
@@ -1,13 +1,44 @@
+use anyhow::Context;
 use futures::channel::oneshot;
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
-use wasmer_wasix::{Runtime as _, WasiEnvBuilder};
+use wasmer::AsJs;
+use wasmer_wasix::{
+    bin_factory::BinaryPackage,
+    runners::wasi::WasiRunner,
+    runtime::module_cache::ModuleHash,
+    types::wasi::{Errno, ExitCode},
+    Runtime as _, WasiEnvBuilder, WasiFunctionEnv, WasiProcess, WasiRuntimeError,
+};
+use webc::metadata::annotations::Wasi;
 
-use crate::{instance::ExitCondition, utils::Error, Instance, RunOptions};
+use std::{path::Path, sync::Arc};
 
-const DEFAULT_PROGRAM_NAME: &str = "wasm";
+use crate::{
+    crash::{CrashContext, CrashReporter},
+    descriptors::DescriptorTable,
+    events::{MountKind, RuntimeEvent},
+    fs::{report_shadowed, InstanceFs},
+    host_fetch::HostFetch,
+    instance::{ExitCondition, ExitSender},
+    memory::{GuestMemory, MemoryReporter},
+    metering::{GasUsage, MeteringOptions},
+    module_cache::Origin,
+    startup::{Phase, StartupProgress},
+    trace_context::TraceContext,
+    usage::ResourceUsage,
+    utils::Error,
+    web_crypto::WebCrypto,
+    web_gpu::WebGpu,
+    Instance, RunOptions, Wasmer,
+};
+
+pub(crate) const DEFAULT_PROGRAM_NAME: &str = "wasm";
 
 /// Run a WASIX program.
 ///
+/// Passing a {@link Wasmer} package runs the command named by
+/// `config.command`, or the package's entrypoint if no command is given.
+///
 /// # WASI Compatibility
 ///
 /// The WASIX standard is a superset of [WASI preview 1][preview-1], so programs
@@ -22,53 +53,524 @@ const DEFAULT_PROGRAM_NAME: &str = "wasm";
 /// [component-model]: https://github.com/WebAssembly/component-model
 #[wasm_bindgen(js_name = "runWasix")]
 pub async fn run_wasix(wasm_module: WasmModule, config: RunOptions) -> Result<Instance, Error> {
-    run_wasix_inner(wasm_module, config).await
+    let progress = config.startup_progress();
+
+    if let Ok(pkg) = Wasmer::try_from(&*wasm_module) {
+        return run_package(pkg, config, progress).await;
+    }
+
+    run_wasix_inner(wasm_module, config, progress).await
 }
 
-#[tracing::instrument(level = "debug", skip_all)]
-async fn run_wasix_inner(wasm_module: WasmModule, config: RunOptions) -> Result<Instance, Error> {
+/// Run one of a package's commands, falling back to its entrypoint.
+async fn run_package(
+    pkg: Wasmer,
+    config: RunOptions,
+    progress: StartupProgress,
+) -> Result<Instance, Error> {
+    let resolving = progress.start(Phase::Resolving);
+    let cmd = match config.command() {
+        Some(name) => pkg
+            .command(&name)
+            .ok_or_else(|| anyhow::anyhow!("The package doesn't contain a \"{name}\" command"))?,
+        None => pkg.entrypoint.clone().ok_or_else(|| {
+            anyhow::anyhow!(
+                "The package doesn't have an entrypoint, so a command must be specified"
+            )
+        })?,
+    };
+
+    resolving.finish();
+
+    if config.metering()?.is_some() {
+        return Err(Error::js(js_sys::TypeError::new(
+            "Metering is only supported when running a module's bytes",
+        )));
+    }
+
+    let instance = cmd.run(Some(config.unchecked_into())).await?;
+    progress.start(Phase::Running);
+    Ok(instance)
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(traceparent))]
+async fn run_wasix_inner(
+    wasm_module: WasmModule,
+    config: RunOptions,
+    progress: StartupProgress,
+) -> Result<Instance, Error> {
+    let resolving = progress.start(Phase::Resolving);
     let runtime = config.runtime().resolve()?.into_inner();
+    let slot = runtime.acquire_instance_slot().await?;
+    let trace = config.trace_context()?;
+    TraceContext::record(trace.as_ref(), &tracing::Span::current());
 
     let program_name = config
         .program()
         .as_string()
         .unwrap_or_else(|| DEFAULT_PROGRAM_NAME.to_string());
 
-    let mut builder = WasiEnvBuilder::new(program_name).runtime(runtime.clone());
-    let (stdin, stdout, stderr) = config.configure_builder(&mut builder)?;
+    // Note: anything the program spawns (e.g. threads) goes through a
+    // scoped runtime so it can be cancelled once the program exits.
+    let (mut scoped_runtime, scope) = runtime.scoped();
+    if let Some(trace) = trace.clone() {
+        scoped_runtime = scoped_runtime.with_trace_context(trace);
+    }
+    let scoped_http_client = scoped_runtime.http_client().cloned();
+    let mut builder = WasiEnvBuilder::new(&program_name).runtime(Arc::new(scoped_runtime));
+    let usage = Arc::new(ResourceUsage::default());
+    usage.track_network(runtime.network_counters());
 
     let (exit_code_tx, exit_code_rx) = oneshot::channel();
 
-    let module: wasmer::Module = wasm_module.to_module(&*runtime).await?;
+    let reporter = CrashReporter::new(
+        &config,
+        || {
+            Ok(CrashContext {
+                module_hash: wasm_module
+                    .dyn_ref::<js_sys::Uint8Array>()
+                    .map(|bytes| ModuleHash::hash(bytes.to_vec())),
+                command: Some(program_name.clone()),
+                ..CrashContext::from_options(&config)?
+            })
+        },
+        runtime.http_client(),
+    )?;
+    let crashes = CrashReporter::spawn(reporter);
+
+    if let Some(bytes) = wasm_module.dyn_ref::<js_sys::Uint8Array>() {
+        let hash = ModuleHash::hash(bytes.to_vec());
+        let size = bytes.length() as usize;
+        runtime.modules().describe(hash, size, Origin::Module);
+    }
+
+    resolving.finish();
+
+    let metering = config.metering()?;
+    let validation = config.validation()?;
+
+    let compiling = progress.start(Phase::Compiling);
+    if validation.is_enabled() {
+        let bytes = wasm_module.dyn_ref::<js_sys::Uint8Array>().ok_or_else(|| {
+            Error::js(js_sys::TypeError::new(
+                "Validation requires the module's bytes, not a WebAssembly.Module",
+            ))
+        })?;
+        validation.check(&bytes.to_vec())?;
+    }
+    let module: wasmer::Module = match &metering {
+        Some(metering) => {
+            let bytes = wasm_module.dyn_ref::<js_sys::Uint8Array>().ok_or_else(|| {
+                Error::js(js_sys::TypeError::new(
+                    "Metering requires the module's bytes, not a WebAssembly.Module",
+                ))
+            })?;
+            let instrumented = crate::metering::instrument(&bytes.to_vec(), metering)?;
+            runtime.load_module(&instrumented).await?
+        }
+        None => wasm_module.to_module(&*runtime).await?,
+    };
+    compiling.finish();
+
+    let fs_setup = progress.start(Phase::FsSetup);
+    // Note: configure_builder() adds the variables that were set explicitly
+    let explicit = config.parse_env()?;
+    let env = config.effective_env(&runtime)?;
+    for (key, value) in &env {
+        if !explicit.contains_key(key) {
+            builder.add_env(key, value);
+        }
+    }
+    let (stdio, mounts) = config.configure_builder(&mut builder, &usage, runtime.thread_pool())?;
+    let mount_points = config.mount_points();
+    let pool = runtime.thread_pool();
+    let processes = runtime.processes().clone();
+    let pid = processes.reserve(config.background());
+
+    if let Some(p) = mount_points
+        .iter()
+        .find(|p| *p == crate::host_info::MOUNT_POINT)
+    {
+        report_shadowed(pool, Some(pid), crate::host_info::MOUNT_POINT, p);
+    }
+    if let Some(etc) = runtime.etc_filesystem() {
+        match mount_points.iter().find(|p| *p == crate::dns::MOUNT_POINT) {
+            Some(p) => report_shadowed(pool, Some(pid), crate::dns::MOUNT_POINT, p),
+            None => mounts.mount(
+                crate::dns::MOUNT_POINT.as_ref(),
+                Arc::new(etc),
+                MountKind::Generated,
+            )?,
+        }
+    }
+    runtime.identity().create_home(mounts.root());
+    // Note: files inside mounted directories were already layered on top of
+    // them by configure_builder()
+    let unmounted = |path: &Path| !mount_points.iter().any(|p| path.starts_with(p));
+    let mut files = Vec::new();
+    if let Some(profile) = config.profile()? {
+        let dirs: Vec<_> = profile.dirs.into_iter().filter(|d| unmounted(d)).collect();
+        crate::fs::inline_files::create_dirs(mounts.root(), &dirs)?;
+        files = profile.files;
+    }
+    files.extend(config.files()?);
+    files.retain(|f| unmounted(&f.path));
+    crate::fs::inline_files::write_all(mounts.root(), &files)?;
+    match mount_points
+        .iter()
+        .find(|p| crate::timers::conflicts_with(p))
+    {
+        Some(p) => report_shadowed(pool, Some(pid), crate::timers::MOUNT_POINT, p),
+        None => {
+            let timers = crate::timers::filesystem(pool);
+            mounts.mount(
+                crate::timers::MOUNT_POINT.as_ref(),
+                Arc::new(timers),
+                MountKind::Generated,
+            )?;
+        }
+    }
+    if runtime.credentials().is_enabled() {
+        match mount_points
+            .iter()
+            .find(|p| crate::credentials::conflicts_with(p))
+        {
+            Some(p) => report_shadowed(pool, Some(pid), crate::credentials::MOUNT_POINT, p),
+            None => mounts.mount(
+                crate::credentials::MOUNT_POINT.as_ref(),
+                Arc::new(runtime.credentials().filesystem(pool)),
+                MountKind::Generated,
+            )?,
+        }
+    }
+    match mount_points
+        .iter()
+        .find(|p| crate::progress::conflicts_with(p))
+    {
+        Some(p) => report_shadowed(pool, Some(pid), crate::progress::MOUNT_POINT, p),
+        None => mounts.mount(
+            crate::progress::MOUNT_POINT.as_ref(),
+            Arc::new(crate::progress::filesystem(pool, Some(pid))),
+            MountKind::Generated,
+        )?,
+    }
+    match mount_points
+        .iter()
+        .find(|p| crate::pipes::conflicts_with(p))
+    {
+        Some(p) => report_shadowed(pool, Some(pid), crate::pipes::MOUNT_POINT, p),
+        None => mounts.mount(
+            crate::pipes::MOUNT_POINT.as_ref(),
+            Arc::new(runtime.pipes().filesystem()),
+            MountKind::Generated,
+        )?,
+    }
+    match mount_points
+        .iter()
+        .find(|p| crate::shared_memory::conflicts_with(p))
+    {
+        Some(p) => report_shadowed(pool, Some(pid), crate::shared_memory::MOUNT_POINT, p),
+        None => mounts.mount(
+            crate::shared_memory::MOUNT_POINT.as_ref(),
+            Arc::new(runtime.shared_memory().filesystem()),
+            MountKind::Generated,
+        )?,
+    }
+    if let Some(locale) = config.locale()? {
+        for (dir, fs) in locale.mounts(scoped_http_client) {
+            match mount_points
+                .iter()
+                .find(|p| crate::locale::conflicts_with(&dir, p))
+            {
+                Some(p) => report_shadowed(pool, Some(pid), &dir, p),
+                None => mounts.mount(dir.as_ref(), Arc::new(fs), MountKind::Generated)?,
+            }
+        }
+    }
+    mounts.report_to(pool.clone(), Some(pid));
+    fs_setup.finish();
+
+    let descriptors = DescriptorTable::default();
+    let (memory, memory_reporter) = GuestMemory::new(pool.clone());
+
+    // Note: Running the program blocks, so we need to do it on the thread
+    // pool.
+    let host_fetch = HostFetch {
+        policy: runtime.fetch_policy().clone(),
+        pool: runtime.thread_pool().clone(),
+        capabilities: runtime.capabilities(),
+        pid,
+    };
+    let web_crypto = runtime.web_crypto_enabled().then(|| WebCrypto {
+        pool: runtime.thread_pool().clone(),
+    });
+    let web_gpu = WebGpu {
+        pool: runtime.thread_pool().clone(),
+        capabilities: runtime.capabilities(),
+        canvas: runtime.gpu_canvas(),
+        pid,
+    };
 
-    // Note: The WasiEnvBuilder::run() method blocks, so we need to run it on
-    // the thread pool.
     let tasks = runtime.task_manager().clone();
     tasks.spawn_with_module(
         module,
-        Box::new(move |module| {
-            let _span = tracing::debug_span!("run").entered();
-            let result = builder.run(module).map_err(anyhow::Error::new);
-            let _ = exit_code_tx.send(ExitCondition::from_result(result));
+        Box::new({
+            let descriptors = descriptors.clone();
+            let usage = Arc::clone(&usage);
+            let scope = scope.clone();
+            let processes = processes.clone();
+            let progress = progress.clone();
+            let mounts = mounts.clone();
+            let pool = pool.clone();
+            move |module| {
+                let span = tracing::debug_span!("run", pid, traceparent = tracing::field::Empty);
+                TraceContext::record(trace.as_ref(), &span);
+                let _span = span.entered();
+                let exit = ExitSender::new(exit_code_tx);
+                let _busy = usage.busy();
+                let result = run(
+                    builder,
+                    module,
+                    host_fetch,
+                    web_crypto,
+                    web_gpu,
+                    &descriptors,
+                    &progress,
+                    &memory_reporter,
+                    metering.map(|m| (m, &*usage)),
+                    |process| {
+                        processes.attach(pid, process.clone());
+                        pool.emit(RuntimeEvent::ProcessStarted {
+                            pid,
+                            program: program_name,
+                        });
+                    },
+                )
+                .map_err(anyhow::Error::new);
+                usage.stop_tracking_network();
+                processes.remove(pid);
+                drop(slot);
+                mounts.program_exited();
+                scope.cancel();
+                crashes.notify(&result);
+                let condition = ExitCondition::from_result(result);
+                pool.emit(RuntimeEvent::ProcessExited {
+                    pid,
+                    exit_code: condition.0,
+                });
+                exit.send(condition);
+            }
         }),
     )?;
 
     Ok(Instance {
-        stdin,
-        stdout,
-        stderr,
+        stdin: stdio.stdin,
+        stdin_handle: stdio.stdin_handle,
+        stdout: stdio.stdout,
+        stderr: stdio.stderr,
+        output: stdio.output,
+        echo: stdio.echo,
+        recording: stdio.recording,
         exit: exit_code_rx,
+        fs: Some(InstanceFs::new(mounts, descriptors.clone())),
+        descriptors: Some(descriptors),
+        usage,
+        tasks: Some(scope),
+        process: Some((pid, processes)),
+        env,
+        memory: Some(memory),
     })
 }
 
+/// The equivalent of [`WasiEnvBuilder::run()`], except the instance's file
+/// descriptors are attached to `descriptors`, its memory is sent to `memory`
+/// and its process is passed to `on_start` before it starts executing.
+///
+/// This uses the same [`WasiEnvBuilder::instantiate()`] and
+/// [`wasmer_wasix::run_wasi_func_start()`] that [`WasiEnvBuilder::run()`]
+/// does, so only the work between them is ours.
+///
+/// If the module was instrumented for `metering`, the gas it used is recorded
+/// in the [`ResourceUsage`].
+///
+/// Instantiating and running are reported to `progress`.
+///
+/// The `host_fetch`, `wasmer_crypto` and `wasmer_gpu` functions are only
+/// provided if the module imports them.
+#[allow(clippy::too_many_arguments)]
+fn run(
+    mut builder: WasiEnvBuilder,
+    module: wasmer::Module,
+    host_fetch: HostFetch,
+    web_crypto: Option<WebCrypto>,
+    web_gpu: WebGpu,
+    descriptors: &DescriptorTable,
+    progress: &StartupProgress,
+    memory: &MemoryReporter,
+    metering: Option<(MeteringOptions, &ResourceUsage)>,
+    on_start: impl FnOnce(&WasiProcess),
+) -> Result<(), WasiRuntimeError> {
+    let instantiating = progress.start(Phase::Instantiating);
+    let mut store = wasmer::Store::default();
+
+    let wants_host_fetch = module
+        .imports()
+        .any(|import| import.module() == crate::host_fetch::NAMESPACE);
+    let host_fetch = if wants_host_fetch {
+        let (imports, memory) = host_fetch.imports(&mut store);
+        builder.add_imports(&imports);
+        Some(memory)
+    } else {
+        None
+    };
+
+    let wants_web_crypto = module
+        .imports()
+        .any(|import| import.module() == crate::web_crypto::NAMESPACE);
+    let web_crypto = match web_crypto {
+        Some(web_crypto) if wants_web_crypto => {
+            let (imports, memory) = web_crypto.imports(&mut store);
+            builder.add_imports(&imports);
+            Some(memory)
+        }
+        _ => None,
+    };
+
+    let wants_web_gpu = module
+        .imports()
+        .any(|import| import.module() == crate::web_gpu::NAMESPACE);
+    let web_gpu = if wants_web_gpu {
+        let (imports, memory) = web_gpu.imports(&mut store);
+        builder.add_imports(&imports);
+        Some(memory)
+    } else {
+        None
+    };
+
+    let (instance, env) = builder.instantiate(module, &mut store)?;
+    if let Some(host_fetch) = host_fetch {
+        let memory = instance.exports.get_memory("memory")?.clone();
+        host_fetch.attach(&mut store, memory);
+    }
+    if let Some(web_crypto) = web_crypto {
+        let memory = instance.exports.get_memory("memory")?.clone();
+        web_crypto.attach(&mut store, memory);
+    }
+    if let Some(web_gpu) = web_gpu {
+        let memory = instance.exports.get_memory("memory")?.clone();
+        web_gpu.attach(&mut store, memory);
+    }
+    descriptors.attach(env.data(&store).state().fs.fd_map.clone());
+    let guest_memory = instance
+        .exports
+        .get_memory("memory")
+        .ok()
+        .map(|m| m.as_jsvalue(&store));
+    if let Some(guest_memory) = &guest_memory {
+        memory.instantiated(guest_memory);
+    }
+    on_start(&env.data(&store).process);
+    instantiating.finish();
+
+    progress.start(Phase::Running);
+
+    let mut result = call_start(&instance, &env, &mut store);
+
+    if let Some((metering, usage)) = metering {
+        if let Some(remaining) = crate::metering::remaining_gas(&instance, &store) {
+            let gas = GasUsage::from_remaining(&metering, remaining);
+            tracing::debug!(?gas, "Metered program finished");
+            if gas.exhausted {
+                let msg = format!(
+                    "Exceeded the gas limit of {} instructions",
+                    gas.instructions
+                );
+                result = Err(WasiRuntimeError::Runtime(wasmer::RuntimeError::new(msg)));
+            }
+            *usage.gas.lock().unwrap() = Some(gas);
+        }
+    }
+
+    let exit_code = exit_code(&mut result);
+    if let Some(guest_memory) = &guest_memory {
+        memory.exited(guest_memory);
+    }
+    env.cleanup(&mut store, Some(exit_code));
+
+    result
+}
+
+/// The equivalent of [`WasiRunner::run_command()`], except the program's file
+/// descriptors are attached to `descriptors` before it starts executing.
+///
+/// The environment is set up by [`WasiRunner::prepare_webc_env()`], exactly
+/// like the runner would.
+pub(crate) fn run_command(
+    runner: &WasiRunner,
+    command_name: &str,
+    pkg: &BinaryPackage,
+    runtime: Arc<dyn wasmer_wasix::Runtime + Send + Sync>,
+    descriptors: &DescriptorTable,
+) -> Result<(), anyhow::Error> {
+    let cmd = pkg
+        .get_command(command_name)
+        .with_context(|| format!("The package doesn't contain a \"{command_name}\" command"))?;
+    let wasi = cmd
+        .metadata()
+        .annotation("wasi")?
+        .unwrap_or_else(|| Wasi::new(command_name));
+    let exec_name = wasi.exec_name.as_deref().unwrap_or(command_name);
+
+    let builder = runner
+        .prepare_webc_env(exec_name, &wasi, Some(pkg), Arc::clone(&runtime), None)
+        .context("Unable to prepare the WASI environment")?;
+    let module = runtime.load_module_sync(cmd.atom())?;
+
+    let mut store = runtime.new_store();
+    let (instance, env) = builder.instantiate(module, &mut store)?;
+    descriptors.attach(env.data(&store).state().fs.fd_map.clone());
+
+    let mut result = call_start(&instance, &env, &mut store);
+    let exit_code = exit_code(&mut result);
+    env.cleanup(&mut store, Some(exit_code));
+
+    result.map_err(anyhow::Error::new)
+}
+
+fn call_start(
+    instance: &wasmer::Instance,
+    env: &WasiFunctionEnv,
+    store: &mut wasmer::Store,
+) -> Result<(), WasiRuntimeError> {
+    let start = instance.exports.get_function("_start")?;
+    env.data(&*store).thread.set_status_running();
+    wasmer_wasix::run_wasi_func_start(start, store)
+}
+
+/// Work out the exit code for a finished program, treating an explicit
+/// `exit(0)` as success like [`WasiEnvBuilder::run()`] does.
+fn exit_code(result: &mut Result<(), WasiRuntimeError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::Errno(Errno::Success),
+        Err(e) => match e.as_exit_code() {
+            Some(code) if code.is_success() => {
+                *result = Ok(());
+                ExitCode::Errno(Errno::Success)
+            }
+            Some(code) => code,
+            None => ExitCode::Errno(Errno::Noexec),
+        },
+    }
+}
+
 #[wasm_bindgen]
 extern "C" {
-    #[wasm_bindgen(typescript_type = "WebAssembly.Module | Uint8Array")]
+    #[wasm_bindgen(typescript_type = "WebAssembly.Module | Uint8Array | Wasmer")]
     pub type WasmModule;
 }
 
 impl WasmModule {
-    async fn to_module(
+    pub(crate) async fn to_module(
         &self,
         runtime: &dyn wasmer_wasix::Runtime,
     ) -> Result<wasmer::Module, Error> {
@@ -79,7 +581,9 @@ impl WasmModule {
             let module = runtime.load_module(&buffer).await?;
             Ok(module)
         } else {
-            unreachable!();
+            Err(Error::js(js_sys::TypeError::new(
+                "Expected a WebAssembly.Module or Uint8Array",
+            )))
         }
     }
 }
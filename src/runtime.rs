@@ -1,20 +1,54 @@
-use std::sync::{atomic::AtomicBool, Arc, Mutex, Weak};
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::AtomicBool, Arc, Mutex, Weak},
+};
 
+use bytes::Bytes;
+use futures::{channel::oneshot, future::BoxFuture};
 use http::HeaderValue;
 use once_cell::sync::Lazy;
 use virtual_net::VirtualNetworking;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
 use wasmer_wasix::{
     http::{HttpClient, WebHttpClient},
     os::{TtyBridge, TtyOptions},
     runtime::{
-        module_cache::ThreadLocalCache,
+        module_cache::ModuleHash,
         package_loader::PackageLoader,
         resolver::{PackageSpecifier, PackageSummary, QueryError, Source, WapmSource},
     },
+    types::wasi::Signal,
     VirtualTaskManager, WasiTtyState,
 };
 
-use crate::{tasks::ThreadPool, utils::Error};
+use crate::{
+    abort::{AbortableHttpClient, InFlightRequests},
+    arbiter::{ArbiterOptions, Membership},
+    capabilities::{Capabilities, Capability},
+    credentials::CredentialHelper,
+    dns::DnsConfig,
+    events::EventChannel,
+    fs::DeviceFileSystem,
+    host_fetch::FetchPolicy,
+    identity::IdentityConfig,
+    instance_limit::{AtCapacity, InstanceLimit, InstanceSlot},
+    module_cache::TrackedCache,
+    net::{NetworkCounters, Transport},
+    overrides::{OverridingSource, PackageOverrides},
+    permissions::{PermissionPrompt, PromptingNetworking},
+    pipes::PipeTable,
+    processes::ProcessTable,
+    provenance::{PublisherPolicy, TrustedSource},
+    proxy::{ProxiedNetworking, ProxyConfig},
+    registry_auth::{AuthenticatedHttpClient, TokenSource},
+    shared_memory::SharedMemoryTable,
+    storage::StorageStatus,
+    tasks::{ScopedHttpClient, TaskScope, ThreadPool},
+    terminal::{EchoControl, EchoSource, TtyDemand},
+    trace_context::{TraceContext, TracedHttpClient},
+    utils::{Error, GlobalScope},
+};
 
 /// A weak reference to the global [`Runtime`].
 static GLOBAL_RUNTIME: Lazy<Mutex<Weak<Runtime>>> = Lazy::new(Mutex::default);
@@ -26,12 +60,43 @@ pub struct Runtime {
     pool: ThreadPool,
     task_manager: Arc<dyn VirtualTaskManager>,
     networking: Arc<dyn VirtualNetworking>,
+    /// Traffic through the networking gateway, if there is one.
+    network_counters: Arc<NetworkCounters>,
     source: Option<Arc<WapmSource>>,
     http_client: Arc<dyn HttpClient + Send + Sync>,
+    in_flight: InFlightRequests,
     package_loader: Arc<crate::package_loader::PackageLoader>,
-    module_cache: Arc<ThreadLocalCache>,
+    module_cache: TrackedCache,
     tty: TtyOptions,
     connected_to_tty: Arc<AtomicBool>,
+    /// Set by the first terminal query when the TTY is created on demand.
+    tty_demand: Arc<Mutex<Option<TtyDemand>>>,
+    /// The outcome of the most recent [`Runtime::negotiate_storage()`].
+    storage: Arc<Mutex<Option<StorageStatus>>>,
+    processes: ProcessTable,
+    /// Limits how many programs may run at once.
+    instances: InstanceLimit,
+    pipes: PipeTable,
+    shared_memory: SharedMemoryTable,
+    overrides: PackageOverrides,
+    dns: DnsConfig,
+    identity: IdentityConfig,
+    fetch_policy: FetchPolicy,
+    /// Can guests use the `wasmer_crypto` extension?
+    web_crypto: bool,
+    /// The canvas `wasmer_gpu` draws onto, registered on the scheduler's
+    /// thread.
+    gpu_canvas: Option<u32>,
+    /// The host features programs have been granted.
+    capabilities: Capabilities,
+    /// Set when workers and cache space are shared with other runtimes.
+    arbiter: Option<Membership>,
+    /// Asks the user before programs do anything sensitive.
+    permissions: PermissionPrompt,
+    /// Answers programs' credential prompts.
+    credentials: CredentialHelper,
+    /// The publishers packages may be resolved from.
+    publishers: PublisherPolicy,
 }
 
 impl Runtime {
@@ -51,6 +116,7 @@ impl Runtime {
                     tracing::debug!("Initializing the global runtime");
                     let rt = Arc::new(Runtime::with_defaults()?);
                     *guard = Arc::downgrade(&rt);
+                    crate::shutdown::watch(Arc::downgrade(&rt), Default::default());
 
                     Ok(rt)
                 }
@@ -60,6 +126,7 @@ impl Runtime {
 
                 let rt = Arc::new(Runtime::with_defaults()?);
                 **e.get_mut() = Arc::downgrade(&rt);
+                crate::shutdown::watch(Arc::downgrade(&rt), Default::default());
 
                 // FIXME: Use this when it becomes stable
                 // GLOBAL_RUNTIME.clear_poison();
@@ -88,21 +155,43 @@ impl Runtime {
                 HeaderValue::from_static(crate::USER_AGENT),
             )
             .with_task_manager(task_manager.clone());
-        let http_client = Arc::new(http_client);
+        let in_flight = InFlightRequests::default();
+        let http_client = Arc::new(AbortableHttpClient {
+            inner: Arc::new(http_client),
+            in_flight: in_flight.clone(),
+        });
 
-        let module_cache = ThreadLocalCache::default();
         let package_loader = crate::package_loader::PackageLoader::new(http_client.clone());
 
         Runtime {
             pool,
             task_manager: Arc::new(task_manager),
             networking: Arc::new(virtual_net::UnsupportedVirtualNetworking::default()),
+            network_counters: Arc::default(),
             source: None,
-            http_client: Arc::new(http_client),
+            http_client,
+            in_flight,
             package_loader: Arc::new(package_loader),
-            module_cache: Arc::new(module_cache),
+            module_cache: TrackedCache::default(),
             tty: TtyOptions::default(),
             connected_to_tty: Arc::new(AtomicBool::new(false)),
+            tty_demand: Arc::default(),
+            storage: Arc::default(),
+            processes: ProcessTable::default(),
+            instances: InstanceLimit::default(),
+            pipes: PipeTable::default(),
+            shared_memory: SharedMemoryTable::default(),
+            overrides: PackageOverrides::default(),
+            dns: DnsConfig::default(),
+            identity: IdentityConfig::default(),
+            fetch_policy: FetchPolicy::default(),
+            web_crypto: true,
+            gpu_canvas: None,
+            capabilities: Capabilities::none(),
+            arbiter: None,
+            permissions: PermissionPrompt::default(),
+            credentials: CredentialHelper::default(),
+            publishers: PublisherPolicy::default(),
         }
     }
 
@@ -119,22 +208,386 @@ impl Runtime {
         Ok(())
     }
 
+    /// Set the registry that packages will be fetched from, authenticating
+    /// with tokens from `tokens`.
+    pub(crate) fn set_registry_with_tokens(
+        &mut self,
+        url: &str,
+        tokens: TokenSource,
+    ) -> Result<(), Error> {
+        let url = url.parse().map_err(Error::from)?;
+
+        let client = AuthenticatedHttpClient {
+            inner: self.http_client.clone(),
+            tokens,
+        };
+        self.source = Some(Arc::new(WapmSource::new(url, Arc::new(client))));
+
+        Ok(())
+    }
+
     /// Enable networking (i.e. TCP and UDP) via a gateway server.
     pub fn set_network_gateway(&mut self, gateway_url: String) {
-        let networking = crate::net::connect_networking(gateway_url);
+        self.set_network_gateway_with(gateway_url, Transport::default());
+    }
+
+    /// Enable networking via a gateway server, choosing how to connect to it.
+    pub(crate) fn set_network_gateway_with(&mut self, gateway_url: String, transport: Transport) {
+        let networking = crate::net::connect_networking(
+            gateway_url,
+            transport,
+            Arc::clone(&self.network_counters),
+        );
+        self.networking = Arc::new(networking);
+    }
+
+    /// Tunnel TCP connections made through the gateway via an HTTP proxy.
+    pub(crate) fn set_network_proxy(&mut self, proxy: ProxyConfig) {
+        let networking = ProxiedNetworking::new(self.networking.clone(), proxy);
+        self.networking = Arc::new(networking);
+    }
+
+    /// Ask `callback` before programs connect to a new host or fetch from an
+    /// origin that hasn't been approved.
+    ///
+    /// This must be called after the networking has been configured, on the
+    /// scheduler's thread.
+    pub(crate) fn set_permission_prompter(&mut self, callback: js_sys::Function) {
+        self.permissions.set_prompter(callback);
+        self.fetch_policy.set_prompt(self.permissions.clone());
+
+        let networking = PromptingNetworking::new(
+            self.networking.clone(),
+            self.permissions.clone(),
+            self.pool.clone(),
+        );
         self.networking = Arc::new(networking);
     }
+
+    /// Refuse to resolve packages (including dependencies) unless they were
+    /// published under one of the `publishers` namespaces.
+    pub(crate) fn set_trusted_publishers(&mut self, publishers: Vec<String>) -> Result<(), Error> {
+        self.publishers.trust_only(publishers)
+    }
+
+    /// Answer programs' credential prompts with `callback`.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn set_credential_helper(&mut self, callback: js_sys::Function) {
+        self.credentials.set_callback(callback);
+    }
 }
 
 impl Runtime {
+    /// The channel this runtime's events are dispatched on.
+    pub(crate) fn events(&self) -> EventChannel {
+        self.pool.events()
+    }
+
+    /// Use a local `*.webc` file whenever the package called `name` is
+    /// resolved, including when it is a dependency of another package.
+    pub(crate) fn override_package_with_webc(&self, name: &str, webc: Bytes) -> Result<(), Error> {
+        let previous = self.overrides.local_webc(name);
+        let summary = self.overrides.set_local(name, &webc)?;
+        self.package_loader.pin(summary.dist.webc_sha256, webc)?;
+        if let Some(previous) = previous.filter(|&hash| hash != summary.dist.webc_sha256) {
+            self.package_loader.unpin(&previous);
+        }
+        Ok(())
+    }
+
+    /// Resolve the package called `name` to a different version.
+    pub(crate) fn override_package_version(&self, name: &str, version: &str) -> Result<(), Error> {
+        let previous = self.overrides.local_webc(name);
+        self.overrides.set_version(name, version)?;
+        if let Some(previous) = previous {
+            self.package_loader.unpin(&previous);
+        }
+        Ok(())
+    }
+
+    /// Stop overriding the package called `name`.
+    pub(crate) fn remove_package_override(&self, name: &str) -> bool {
+        let local = self.overrides.local_webc(name);
+        let removed = self.overrides.remove(name);
+        if let Some(local) = local {
+            self.package_loader.unpin(&local);
+        }
+        removed
+    }
+
+    /// Every program started with this runtime that is still running.
+    pub(crate) fn processes(&self) -> &ProcessTable {
+        &self.processes
+    }
+
+    /// Wait until this runtime may start another program.
+    pub(crate) async fn acquire_instance_slot(&self) -> Result<InstanceSlot, Error> {
+        self.instances.acquire(&self.pool).await
+    }
+
+    /// Share workers and cache space with the other runtimes on the page.
+    pub(crate) fn join_arbiter(&mut self, options: ArbiterOptions) -> Result<(), Error> {
+        let membership =
+            crate::arbiter::join(self.pool.clone(), Arc::clone(&self.package_loader), options)?;
+        self.arbiter = Some(membership);
+        Ok(())
+    }
+
+    pub(crate) fn set_instance_limit(&self, max: Option<NonZeroUsize>, policy: AtCapacity) {
+        self.instances.configure(max, policy);
+    }
+
+    /// Pipes created with `runtime.createPipe()`.
+    pub(crate) fn pipes(&self) -> &PipeTable {
+        &self.pipes
+    }
+
+    /// Segments created with `runtime.createSharedMemory()`.
+    pub(crate) fn shared_memory(&self) -> &SharedMemoryTable {
+        &self.shared_memory
+    }
+
+    /// The host mappings and nameservers written to `/etc/hosts` and
+    /// `/etc/resolv.conf`.
+    pub(crate) fn dns(&self) -> &DnsConfig {
+        &self.dns
+    }
+
+    pub(crate) fn identity(&self) -> &IdentityConfig {
+        &self.identity
+    }
+
+    pub(crate) fn credentials(&self) -> &CredentialHelper {
+        &self.credentials
+    }
+
+    /// The generated files programs see in `/etc`, if there is anything to
+    /// put there.
+    pub(crate) fn etc_filesystem(&self) -> Option<DeviceFileSystem> {
+        if !self.dns.is_configured() && !self.identity.is_configured() {
+            return None;
+        }
+
+        let fs = DeviceFileSystem::default();
+        if self.dns.is_configured() {
+            self.dns.insert_files(&fs);
+        }
+        if self.identity.is_configured() {
+            self.identity.insert_files(&fs);
+        }
+
+        Some(fs)
+    }
+
+    /// How much traffic has gone through the networking gateway.
+    pub(crate) fn network_counters(&self) -> Arc<NetworkCounters> {
+        Arc::clone(&self.network_counters)
+    }
+
+    /// The origins guests may reach with `host_fetch`.
+    pub(crate) fn fetch_policy(&self) -> &FetchPolicy {
+        &self.fetch_policy
+    }
+
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub(crate) fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub(crate) fn web_crypto_enabled(&self) -> bool {
+        self.web_crypto
+    }
+
+    pub(crate) fn set_web_crypto_enabled(&mut self, enabled: bool) {
+        self.web_crypto = enabled;
+    }
+
+    pub(crate) fn gpu_canvas(&self) -> Option<u32> {
+        self.gpu_canvas
+    }
+
+    /// Let programs draw onto `canvas` with `wasmer_gpu`.
+    ///
+    /// This must be called on the scheduler's thread.
+    pub(crate) fn set_gpu_canvas(&mut self, canvas: JsValue) {
+        if let Some(previous) = self.gpu_canvas.take() {
+            crate::web_gpu::unregister_canvas(previous);
+        }
+        self.gpu_canvas = Some(crate::web_gpu::register_canvas(canvas));
+    }
+
+    /// Join package downloads started by other runtimes on the page.
+    pub(crate) fn set_share_downloads(&self, shared: bool) {
+        self.package_loader.set_share_downloads(shared);
+    }
+
+    /// The cache compiled modules are stored in.
+    pub(crate) fn modules(&self) -> &TrackedCache {
+        &self.module_cache
+    }
+
+    /// The thread pool this runtime runs work on.
+    pub(crate) fn thread_pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    /// Create a copy of this runtime where everything spawned on its task
+    /// manager (and every HTTP request made through it) is tracked by a new
+    /// [`TaskScope`].
+    pub(crate) fn scoped(&self) -> (Runtime, TaskScope) {
+        let scope = TaskScope::new(&self.pool);
+        let mut runtime = self.clone();
+        runtime.task_manager = Arc::new(scope.clone());
+        runtime.http_client = Arc::new(ScopedHttpClient {
+            inner: Arc::clone(&self.http_client),
+            scope: scope.clone(),
+        });
+        (runtime, scope)
+    }
+
+    /// Send a `traceparent` header in `context`'s trace with every HTTP
+    /// request made through this runtime.
+    pub(crate) fn with_trace_context(mut self, context: TraceContext) -> Runtime {
+        self.http_client = Arc::new(TracedHttpClient {
+            inner: self.http_client,
+            context,
+        });
+        self
+    }
+
+    /// Tear down everything this runtime owns.
+    ///
+    /// Running programs are killed, in-flight HTTP requests are aborted,
+    /// caches are emptied, and every worker is terminated (releasing the
+    /// memories they were using). The runtime can't be used afterwards.
+    pub(crate) fn dispose(&self) {
+        tracing::debug!("Disposing of the runtime");
+
+        for pid in self.processes.pids() {
+            let _ = self.processes.signal(pid, Signal::Sigkill);
+        }
+        self.in_flight.abort_all();
+        self.instances.reject_waiting();
+        if let Some(arbiter) = &self.arbiter {
+            arbiter.leave();
+        }
+        self.module_cache.clear();
+        self.package_loader.clear();
+        self.pool.shutdown();
+
+        if let Ok(mut global) = GLOBAL_RUNTIME.lock() {
+            if global
+                .upgrade()
+                .is_some_and(|rt| std::ptr::eq(Arc::as_ptr(&rt), self))
+            {
+                *global = Weak::new();
+            }
+        }
+    }
+
+    /// Drop everything we only keep around to make the next program start
+    /// faster: unpinned modules, cached packages, and idle workers.
+    pub(crate) fn shed_memory(&self) {
+        let (modules, package_bytes) = self.evict_caches();
+        self.pool.shed_idle_workers();
+        tracing::info!(modules, package_bytes, "Dropped caches to free up memory");
+    }
+
+    /// Drop unpinned modules and cached packages, returning how many modules
+    /// and package bytes were dropped.
+    pub(crate) fn evict_caches(&self) -> (usize, u64) {
+        (self.module_cache.evict(), self.package_loader.evict())
+    }
+
+    /// Ask the browser for persistent storage and limit the modules we save to
+    /// IndexedDB relative to the quota we were given.
+    pub(crate) async fn negotiate_storage(&self) -> Result<StorageStatus, Error> {
+        self.capabilities
+            .require(Capability::Storage, "requestPersistentStorage()")?;
+
+        let status = StorageStatus::negotiate().await?;
+        tracing::debug!(?status, "Negotiated browser storage");
+
+        crate::tasks::set_module_store_budget(status.cache_budget());
+        *self.storage.lock().unwrap() = Some(status.clone());
+
+        Ok(status)
+    }
+
+    /// The result of the last [`Runtime::negotiate_storage()`] call.
+    pub(crate) fn storage_status(&self) -> Option<StorageStatus> {
+        self.storage.lock().unwrap().clone()
+    }
+
     pub(crate) fn tty_options(&self) -> &TtyOptions {
         &self.tty
     }
 
+    /// Turns echo on this runtime's TTY on and off.
+    pub(crate) fn echo(&self) -> EchoControl {
+        EchoControl::new(self.tty.clone(), self.pool.clone())
+    }
+
     pub(crate) fn set_connected_to_tty(&self, state: bool) {
         self.connected_to_tty
             .store(state, std::sync::atomic::Ordering::SeqCst);
     }
+
+    /// Notify `demand` the next time a program queries or configures its
+    /// terminal.
+    pub(crate) fn set_tty_demand(&self, demand: Option<TtyDemand>) {
+        *self.tty_demand.lock().unwrap() = demand;
+    }
+
+    fn request_tty(&self) {
+        if let Some(demand) = &*self.tty_demand.lock().unwrap() {
+            demand.request();
+        }
+    }
+
+    /// Compile a module (or its `*.wat` text).
+    ///
+    /// The main thread compiles asynchronously so it doesn't block the page.
+    /// Workers use the synchronous API because they may be blocked inside a
+    /// guest, where the browser's asynchronous compile would never complete.
+    fn compile_module<'a>(
+        &'a self,
+        wasm: &'a [u8],
+    ) -> BoxFuture<'a, Result<wasmer::Module, anyhow::Error>> {
+        let wasm = match wasmer::wat2wasm(wasm) {
+            Ok(wasm) => wasm.into_owned(),
+            Err(e) => return Box::pin(futures::future::ready(Err(e.into()))),
+        };
+
+        if !matches!(GlobalScope::current(), GlobalScope::Window(_)) {
+            return Box::pin(futures::future::ready(
+                wasmer_wasix::runtime::Runtime::load_module_sync(self, &wasm),
+            ));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        wasm_bindgen_futures::spawn_local(async move {
+            let bytes = js_sys::Uint8Array::from(&wasm[..]);
+            let result = match JsFuture::from(js_sys::WebAssembly::compile(&bytes)).await {
+                Ok(module) => Ok(wasmer::Module::from((module.unchecked_into(), wasm))),
+                Err(e) => Err(crate::proposals::explain_compile_error(
+                    &wasm,
+                    crate::utils::js_error(e),
+                )),
+            };
+            let _ = sender.send(result);
+        });
+
+        Box::pin(async move {
+            receiver
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("The module's compilation was cancelled")))
+        })
+    }
 }
 
 impl wasmer_wasix::runtime::Runtime for Runtime {
@@ -147,10 +600,20 @@ impl wasmer_wasix::runtime::Runtime for Runtime {
     }
 
     fn source(&self) -> Arc<dyn wasmer_wasix::runtime::resolver::Source + Send + Sync> {
-        match &self.source {
+        let registry: Arc<dyn Source + Send + Sync> = match &self.source {
             Some(wapm) => Arc::clone(wapm) as _,
             None => Arc::new(UnsupportedSource),
-        }
+        };
+        // Note: local overrides come from the host, so they are trusted
+        let fallback = TrustedSource {
+            policy: self.publishers.clone(),
+            inner: registry,
+        };
+
+        Arc::new(OverridingSource {
+            overrides: self.overrides.clone(),
+            fallback,
+        })
     }
 
     fn http_client(&self) -> Option<&wasmer_wasix::http::DynHttpClient> {
@@ -164,12 +627,28 @@ impl wasmer_wasix::runtime::Runtime for Runtime {
     fn module_cache(
         &self,
     ) -> Arc<dyn wasmer_wasix::runtime::module_cache::ModuleCache + Send + Sync> {
-        self.module_cache.clone()
+        Arc::new(self.module_cache.clone())
+    }
+
+    fn load_module<'a>(
+        &'a self,
+        wasm: &'a [u8],
+    ) -> BoxFuture<'a, Result<wasmer::Module, anyhow::Error>> {
+        let hash = ModuleHash::hash(wasm);
+        let engine = self.engine();
+
+        Box::pin(async move {
+            self.module_cache
+                .load_or_compile(hash, &engine, || self.compile_module(wasm))
+                .await
+        })
     }
 
     fn load_module_sync(&self, wasm: &[u8]) -> Result<wasmer::Module, anyhow::Error> {
-        let wasm = unsafe { js_sys::Uint8Array::view(wasm) };
-        let module = js_sys::WebAssembly::Module::new(&wasm).map_err(crate::utils::js_error)?;
+        let bytes = unsafe { js_sys::Uint8Array::view(wasm) };
+        let module = js_sys::WebAssembly::Module::new(&bytes).map_err(|e| {
+            crate::proposals::explain_compile_error(wasm, crate::utils::js_error(e))
+        })?;
         // Note: We need to use this From impl because it will use the
         // wasm-types-polyfill to parse the *.wasm file's import section.
         //
@@ -180,7 +659,7 @@ impl wasmer_wasix::runtime::Runtime for Runtime {
         // error.
         //
         // https://github.com/wasmerio/wasmer/blob/8ec4f1d76062e2a612ac2f70f4a73eaf59f8fe9f/lib/api/src/js/module.rs#L323-L328
-        Ok(wasmer::Module::from((module, wasm.to_vec())))
+        Ok(wasmer::Module::from((module, bytes.to_vec())))
     }
 
     fn tty(&self) -> Option<&(dyn wasmer_wasix::os::TtyBridge + Send + Sync)> {
@@ -191,7 +670,7 @@ impl wasmer_wasix::runtime::Runtime for Runtime {
 impl TtyBridge for Runtime {
     #[tracing::instrument(level = "debug", skip_all)]
     fn reset(&self) {
-        self.tty.set_echo(true);
+        self.echo().set(true, EchoSource::Program);
         self.tty.set_line_buffering(true);
         self.tty.set_line_feeds(true);
         self.set_connected_to_tty(false);
@@ -199,6 +678,7 @@ impl TtyBridge for Runtime {
 
     #[tracing::instrument(level = "debug", skip(self), ret)]
     fn tty_get(&self) -> WasiTtyState {
+        self.request_tty();
         let connected_to_tty = self
             .connected_to_tty
             .load(std::sync::atomic::Ordering::SeqCst);
@@ -219,9 +699,10 @@ impl TtyBridge for Runtime {
 
     #[tracing::instrument(level = "debug", skip(self))]
     fn tty_set(&self, tty_state: WasiTtyState) {
+        self.request_tty();
         self.tty.set_cols(tty_state.cols);
         self.tty.set_rows(tty_state.rows);
-        self.tty.set_echo(tty_state.echo);
+        self.echo().set(tty_state.echo, EchoSource::Program);
         self.tty.set_line_buffering(tty_state.line_buffered);
         self.tty.set_line_feeds(tty_state.line_feeds);
         self.set_connected_to_tty(
@@ -0,0 +1,266 @@
+//! Named presets for running one of a package's commands, similar to
+//! `npm run`.
+//!
+//! Scripts are declared in the package manifest's `scripts` annotation, and
+//! are either a command line or a command with arguments and environment
+//! variables:
+//!
+//! ```toml
+//! [package.annotations.scripts]
+//! test = "python -m pytest -q"
+//! build = { command = "python", args = ["build.py"], env = { MODE = "release" } }
+//! ```
+//!
+//! A command line is split on whitespace without going through a shell, so
+//! it can't contain quotes or backslashes. Arguments with spaces in them
+//! need the detailed form.
+
+use std::collections::BTreeMap;
+
+use js_sys::{Array, Object, Reflect};
+use serde::Deserialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use webc::Container;
+
+use crate::{
+    dotenv::EnvRecord, instance::Instance, options::SpawnOptions, utils::Error, wasmer::Command,
+};
+
+/// The annotation scripts are read from.
+const SCRIPTS_ANNOTATION: &str = "scripts";
+
+/// A script as it is written in the manifest.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum ScriptDefinition {
+    CommandLine(String),
+    Detailed {
+        /// Defaults to the package's entrypoint.
+        command: Option<String>,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+}
+
+/// The command, arguments, and environment a script runs with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ScriptPreset {
+    pub(crate) command: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: BTreeMap<String, String>,
+}
+
+impl ScriptPreset {
+    fn resolve(
+        definition: ScriptDefinition,
+        entrypoint: Option<&str>,
+    ) -> Result<Self, &'static str> {
+        match definition {
+            ScriptDefinition::CommandLine(line) => {
+                // Note: quoting would silently be passed through as part of
+                // the arguments, so refuse it rather than guessing
+                if line.contains(['"', '\'', '\\']) {
+                    return Err("command lines can't contain quotes or backslashes");
+                }
+
+                let mut words = line.split_whitespace().map(String::from);
+                Ok(ScriptPreset {
+                    command: words.next().ok_or("it doesn't specify a command")?,
+                    args: words.collect(),
+                    env: BTreeMap::new(),
+                })
+            }
+            ScriptDefinition::Detailed { command, args, env } => Ok(ScriptPreset {
+                command: command
+                    .or_else(|| entrypoint.map(String::from))
+                    .ok_or("it doesn't specify a command")?,
+                args,
+                env,
+            }),
+        }
+    }
+
+    /// The options a script should run with, where the caller's arguments
+    /// come after the script's and the caller's environment variables take
+    /// precedence.
+    fn apply(&self, options: &SpawnOptions) -> Result<SpawnOptions, Error> {
+        let mut args = self.args.clone();
+        args.extend(options.parse_args()?);
+        let mut env = self.env.clone();
+        env.extend(options.parse_env()?);
+
+        let merged = Object::assign(&Object::new(), options);
+        let args: Array = args.iter().map(|arg| JsValue::from_str(arg)).collect();
+        Reflect::set(&merged, &JsValue::from_str("args"), &args).map_err(Error::js)?;
+
+        let vars = Object::new();
+        for (key, value) in &env {
+            Reflect::set(&vars, &JsValue::from_str(key), &JsValue::from_str(value))
+                .map_err(Error::js)?;
+        }
+        Reflect::set(&merged, &JsValue::from_str("env"), &vars).map_err(Error::js)?;
+
+        Ok(merged.unchecked_into())
+    }
+}
+
+/// Read the scripts declared in a package's manifest, skipping any that
+/// don't say which command to run or can't be split into arguments.
+pub(crate) fn presets(
+    container: &Container,
+    entrypoint: Option<&str>,
+) -> Result<BTreeMap<String, ScriptPreset>, Error> {
+    let definitions: BTreeMap<String, ScriptDefinition> = container
+        .manifest()
+        .package_annotation(SCRIPTS_ANNOTATION)?
+        .unwrap_or_default();
+
+    let mut presets = BTreeMap::new();
+    for (name, definition) in definitions {
+        match ScriptPreset::resolve(definition, entrypoint) {
+            Ok(preset) => {
+                presets.insert(name, preset);
+            }
+            Err(reason) => tracing::warn!(%name, reason, "Ignoring a script"),
+        }
+    }
+
+    Ok(presets)
+}
+
+/// A named preset for running one of a package's commands, declared in the
+/// package's manifest.
+///
+/// @example
+/// ```ts
+/// const pkg = await Wasmer.fromRegistry("my/project");
+/// const instance = await pkg.scripts.build.run({ args: ["--verbose"] });
+/// ```
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct Script {
+    name: String,
+    preset: ScriptPreset,
+    /// `None` when the script refers to a command the package doesn't have.
+    target: Option<Command>,
+}
+
+impl Script {
+    pub(crate) fn new(name: String, preset: ScriptPreset, target: Option<Command>) -> Self {
+        Script {
+            name,
+            preset,
+            target,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Script {
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The name of the command this script runs.
+    #[wasm_bindgen(getter)]
+    pub fn command(&self) -> String {
+        self.preset.command.clone()
+    }
+
+    /// Arguments passed to the command before any given to {@link Script.run}.
+    #[wasm_bindgen(getter)]
+    pub fn args(&self) -> Array {
+        self.preset
+            .args
+            .iter()
+            .map(|arg| JsValue::from_str(arg))
+            .collect()
+    }
+
+    /// Environment variables the script sets. Anything passed to
+    /// {@link Script.run} takes precedence.
+    #[wasm_bindgen(getter)]
+    pub fn env(&self) -> Result<EnvRecord, Error> {
+        let vars = Object::new();
+        for (key, value) in &self.preset.env {
+            Reflect::set(&vars, &JsValue::from_str(key), &JsValue::from_str(value))
+                .map_err(Error::js)?;
+        }
+        Ok(vars.unchecked_into())
+    }
+
+    /// Run the script's command, appending `options.args` to the script's
+    /// arguments and merging `options.env` into its environment.
+    pub async fn run(&self, options: Option<SpawnOptions>) -> Result<Instance, Error> {
+        let Some(target) = &self.target else {
+            let msg = format!(
+                "The \"{}\" script runs \"{}\", which isn't one of the package's commands",
+                self.name, self.preset.command,
+            );
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        };
+
+        let options = self.preset.apply(&options.unwrap_or_default())?;
+        target.run(Some(options)).await
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Record<string, Script>", extends = js_sys::Object)]
+    #[derive(Clone, Default, Debug)]
+    pub type Scripts;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn caller_options_extend_the_preset() {
+        let line = ScriptDefinition::CommandLine("python -m pytest -q".to_string());
+        let preset = ScriptPreset::resolve(line, None).unwrap();
+        assert_eq!(preset.command, "python");
+        assert_eq!(preset.args, ["-m", "pytest", "-q"]);
+
+        let detailed = ScriptDefinition::Detailed {
+            command: None,
+            args: vec!["build.py".to_string()],
+            env: [
+                ("MODE".to_string(), "release".to_string()),
+                ("CI".to_string(), "1".to_string()),
+            ]
+            .into(),
+        };
+        assert!(ScriptPreset::resolve(detailed.clone(), None).is_err());
+        let preset = ScriptPreset::resolve(detailed, Some("python")).unwrap();
+
+        let options: SpawnOptions =
+            js_sys::JSON::parse(r#"{"args": ["--verbose"], "env": {"MODE": "debug"}}"#)
+                .unwrap()
+                .unchecked_into();
+        let merged = preset.apply(&options).unwrap();
+
+        assert_eq!(merged.parse_args().unwrap(), ["build.py", "--verbose"]);
+        let env = merged.parse_env().unwrap();
+        assert_eq!(env["MODE"], "debug");
+        assert_eq!(env["CI"], "1");
+    }
+
+    #[wasm_bindgen_test]
+    fn quoted_command_lines_are_rejected() {
+        for line in [
+            r#"echo "Hello, World!""#,
+            "echo 'Hello, World!'",
+            r"echo Hello,\ World!",
+        ] {
+            let definition = ScriptDefinition::CommandLine(line.to_string());
+            assert!(ScriptPreset::resolve(definition, None).is_err(), "{line}");
+        }
+    }
+}
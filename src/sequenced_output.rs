@@ -0,0 +1,324 @@
+//! A single stream of stdout and stderr chunks, stamped at the moment they
+//! were written so hosts can reconstruct how the two were interleaved.
+
+use std::{
+    cell::RefCell,
+    io,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    StreamExt,
+};
+use js_sys::{Object, Promise, Reflect, Uint8Array};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::{Pipe, VirtualFile};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use web_sys::{ReadableStream, ReadableStreamDefaultController};
+
+/// Which of the program's output streams a chunk was written to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn name(self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct OutputChunk {
+    stream: OutputStream,
+    seq: u64,
+    timestamp: f64,
+    data: Vec<u8>,
+}
+
+/// Assigns sequence numbers and timestamps to everything written to stdout
+/// and stderr.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputLog(Arc<Mutex<LogState>>);
+
+#[derive(Debug)]
+struct LogState {
+    next_seq: u64,
+    last_timestamp: f64,
+    sender: UnboundedSender<OutputChunk>,
+}
+
+impl OutputLog {
+    /// Create a new log and the `ReadableStream` its chunks are delivered on.
+    pub(crate) fn new() -> (OutputLog, ReadableStream) {
+        let (sender, receiver) = mpsc::unbounded();
+        let log = OutputLog(Arc::new(Mutex::new(LogState {
+            next_seq: 0,
+            last_timestamp: 0.0,
+            sender,
+        })));
+
+        let source = JsValue::from(ChunkSource {
+            receiver: Rc::new(RefCell::new(Some(receiver))),
+        });
+        let stream = ReadableStream::new_with_underlying_source(source.unchecked_ref()).unwrap();
+
+        (log, stream)
+    }
+
+    /// Wrap `pipe` so everything written to it is also recorded in the log.
+    pub(crate) fn tee(&self, stream: OutputStream, pipe: Pipe) -> SequencedFile {
+        SequencedFile {
+            inner: pipe,
+            stream,
+            log: self.clone(),
+        }
+    }
+
+    fn record(&self, stream: OutputStream, data: &[u8]) {
+        // Note: the sequence number, timestamp, and send all happen under the
+        // same lock so chunks are delivered in the order they were written,
+        // even when stdout and stderr are written from different threads.
+        let mut state = self.0.lock().unwrap();
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let timestamp = now().max(state.last_timestamp);
+        state.last_timestamp = timestamp;
+
+        let _ = state.sender.unbounded_send(OutputChunk {
+            stream,
+            seq,
+            timestamp,
+            data: data.to_vec(),
+        });
+    }
+}
+
+/// A high resolution timestamp, in milliseconds since the Unix epoch.
+///
+/// Unlike `performance.now()` on its own, this is comparable between workers
+/// because each worker has a different time origin.
+pub(crate) fn now() -> f64 {
+    let performance = Reflect::get(&js_sys::global(), &JsValue::from_str("performance"))
+        .ok()
+        .filter(|p| p.is_object());
+
+    let Some(performance) = performance else {
+        return js_sys::Date::now();
+    };
+
+    let origin = Reflect::get(&performance, &JsValue::from_str("timeOrigin"))
+        .ok()
+        .and_then(|t| t.as_f64());
+    let elapsed = Reflect::get(&performance, &JsValue::from_str("now"))
+        .ok()
+        .and_then(|f| f.dyn_into::<js_sys::Function>().ok())
+        .and_then(|f| f.call0(&performance).ok())
+        .and_then(|t| t.as_f64());
+
+    match (origin, elapsed) {
+        (Some(origin), Some(elapsed)) => origin + elapsed,
+        _ => js_sys::Date::now(),
+    }
+}
+
+#[derive(Debug)]
+#[wasm_bindgen(skip_typescript)]
+struct ChunkSource {
+    receiver: Rc<RefCell<Option<UnboundedReceiver<OutputChunk>>>>,
+}
+
+#[wasm_bindgen]
+impl ChunkSource {
+    pub fn pull(&mut self, controller: ReadableStreamDefaultController) -> Promise {
+        let slot = Rc::clone(&self.receiver);
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            // Note: the stream won't call pull() again until this promise
+            // resolves, so nobody else can be using the receiver.
+            let Some(mut receiver) = slot.borrow_mut().take() else {
+                return Ok(JsValue::UNDEFINED);
+            };
+
+            match receiver.next().await {
+                Some(chunk) => {
+                    controller.enqueue_with_chunk(&chunk.to_js()?)?;
+                    *slot.borrow_mut() = Some(receiver);
+                }
+                None => controller.close()?,
+            }
+
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    pub fn cancel(&mut self) {
+        self.receiver.borrow_mut().take();
+    }
+}
+
+impl OutputChunk {
+    fn to_js(&self) -> Result<JsValue, JsValue> {
+        let obj = Object::new();
+        Reflect::set(&obj, &"stream".into(), &self.stream.name().into())?;
+        Reflect::set(&obj, &"seq".into(), &JsValue::from(self.seq as f64))?;
+        Reflect::set(&obj, &"timestamp".into(), &self.timestamp.into())?;
+        Reflect::set(&obj, &"data".into(), &Uint8Array::from(&self.data[..]))?;
+        Ok(obj.into())
+    }
+}
+
+/// A [`Pipe`] which records everything written to it in an [`OutputLog`].
+#[derive(Debug)]
+pub(crate) struct SequencedFile {
+    inner: Pipe,
+    stream: OutputStream,
+    log: OutputLog,
+}
+
+impl VirtualFile for SequencedFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        self.inner.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        self.inner.unlink()
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for SequencedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SequencedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                self.log.record(self.stream, &buf[..written]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for SequencedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const OUTPUT_CHUNK_TYPE_DECLARATION: &str = r#"
+/**
+ * A chunk of output from {@link Instance.output}.
+ */
+export type OutputChunk = {
+    /** The stream the chunk was written to. */
+    stream: "stdout" | "stderr";
+    /**
+     * A sequence number shared by stdout and stderr, reflecting the order
+     * chunks were written in.
+     */
+    seq: number;
+    /**
+     * When the chunk was written, in milliseconds since the Unix epoch. This
+     * never goes backwards.
+     */
+    timestamp: number;
+    data: Uint8Array;
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn chunks_are_ordered_across_streams() {
+        let (sender, mut receiver) = mpsc::unbounded();
+        let log = OutputLog(Arc::new(Mutex::new(LogState {
+            next_seq: 0,
+            last_timestamp: 0.0,
+            sender,
+        })));
+
+        log.record(OutputStream::Stdout, b"first");
+        log.record(OutputStream::Stderr, b"second");
+        log.record(OutputStream::Stdout, b"third");
+        drop(log);
+
+        let chunks: Vec<_> = receiver.by_ref().collect().await;
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|c| (c.seq, c.stream, c.data.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0, OutputStream::Stdout, &b"first"[..]),
+                (1, OutputStream::Stderr, &b"second"[..]),
+                (2, OutputStream::Stdout, &b"third"[..]),
+            ]
+        );
+        assert!(chunks.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    }
+}
@@ -0,0 +1,367 @@
+//! Named blocks of memory that several programs (and JavaScript) can use at
+//! the same time, similar to POSIX shared memory.
+//!
+//! Each segment created with `runtime.createSharedMemory()` shows up as a
+//! device under `/dev/shm` in every program started with that runtime. The
+//! bytes live in the SDK's own memory, which is a `SharedArrayBuffer`, so
+//! JavaScript gets a view of the segment without copying it and every
+//! instance reading or writing the device sees the other instances' changes
+//! immediately.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::VirtualFile;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Opener},
+    utils::Error,
+};
+
+/// Where the segment devices get mounted.
+pub(crate) const MOUNT_POINT: &str = "/dev/shm";
+
+/// Would mounting the segments clash with something the user mounted at
+/// `mount_point`?
+pub(crate) fn conflicts_with(mount_point: &str) -> bool {
+    crate::locale::conflicts_with(MOUNT_POINT, mount_point)
+}
+
+/// Every shared memory segment created by a runtime.
+///
+/// The same [`DeviceFileSystem`] is mounted into each program, so segments
+/// created after a program started are visible to it too.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedMemoryTable {
+    fs: DeviceFileSystem,
+    segments: Arc<Mutex<BTreeMap<String, Arc<Segment>>>>,
+}
+
+impl SharedMemoryTable {
+    /// Create the segment called `name`, or get a handle to it if it already
+    /// exists with the same size.
+    pub(crate) fn open(&self, name: &str, size: usize) -> Result<SharedSegment, Error> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            let msg = format!("\"{name}\" isn't a valid shared memory segment name");
+            return Err(Error::js(js_sys::TypeError::new(&msg)));
+        }
+
+        let mut segments = self.segments.lock().unwrap();
+
+        let segment = match segments.get(name) {
+            Some(existing) if existing.len() == size => Arc::clone(existing),
+            Some(existing) => {
+                let msg = format!(
+                    "The \"{name}\" segment is {} bytes, not {size}",
+                    existing.len()
+                );
+                return Err(Error::js(js_sys::RangeError::new(&msg)));
+            }
+            None => {
+                let segment = Arc::new(Segment::zeroed(size));
+                let opener = Opener::new({
+                    let segment = Arc::clone(&segment);
+                    move || Box::new(SegmentFile::new(Arc::clone(&segment)))
+                });
+                self.fs.insert(name, Device::Opener(opener));
+                segments.insert(name.to_string(), Arc::clone(&segment));
+                segment
+            }
+        };
+
+        Ok(SharedSegment {
+            name: name.to_string(),
+            path: format!("{MOUNT_POINT}/{name}"),
+            segment,
+            table: self.clone(),
+        })
+    }
+
+    fn remove(&self, name: &str) {
+        self.segments.lock().unwrap().remove(name);
+        self.fs.remove(name);
+    }
+
+    /// A directory containing each segment's device, ready to be mounted at
+    /// [`MOUNT_POINT`].
+    pub(crate) fn filesystem(&self) -> DeviceFileSystem {
+        self.fs.clone()
+    }
+}
+
+/// The bytes in a segment.
+///
+/// Segments never change size, so the views handed to JavaScript stay valid
+/// for as long as the segment is alive.
+#[derive(Debug)]
+struct Segment(Box<[AtomicU8]>);
+
+impl Segment {
+    fn zeroed(size: usize) -> Self {
+        Segment((0..size).map(|_| AtomicU8::new(0)).collect())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Copy bytes starting at `offset` into `buf`, returning how many were
+    /// copied.
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let src = self.0.get(offset..).unwrap_or_default();
+        let len = src.len().min(buf.len());
+        for (dest, byte) in buf.iter_mut().zip(&src[..len]) {
+            *dest = byte.load(Ordering::Relaxed);
+        }
+        len
+    }
+
+    /// Copy `buf` into the segment starting at `offset`, returning how many
+    /// bytes fit.
+    fn write(&self, offset: usize, buf: &[u8]) -> usize {
+        let dest = self.0.get(offset..).unwrap_or_default();
+        let len = dest.len().min(buf.len());
+        for (byte, value) in dest[..len].iter().zip(buf) {
+            byte.store(*value, Ordering::Relaxed);
+        }
+        len
+    }
+
+    fn view(&self) -> js_sys::Uint8Array {
+        // Safety: AtomicU8 has the same layout as u8, and the allocation is
+        // never resized. Growing the SDK's memory doesn't invalidate the view
+        // either, because a SharedArrayBuffer can't be detached.
+        unsafe {
+            let bytes = std::slice::from_raw_parts(self.0.as_ptr().cast::<u8>(), self.0.len());
+            js_sys::Uint8Array::view(bytes)
+        }
+    }
+}
+
+/// A named block of memory shared by JavaScript and every program started
+/// with the same runtime, created by {@link Runtime.createSharedMemory}.
+///
+/// Programs open the segment using its {@link SharedSegment.path} and use
+/// ordinary reads, writes, and seeks. JavaScript can use
+/// {@link SharedSegment.view} (including with `Atomics`) to exchange data
+/// with them without copying.
+///
+/// @example
+/// ```ts
+/// const frames = runtime.createSharedMemory("frames", 1024 * 1024);
+/// const producer = await render.run({ runtime, args: ["--out", frames.path] });
+/// const consumer = await encode.run({ runtime, args: ["--in", frames.path] });
+/// ```
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct SharedSegment {
+    name: String,
+    path: String,
+    segment: Arc<Segment>,
+    table: SharedMemoryTable,
+}
+
+#[wasm_bindgen]
+impl SharedSegment {
+    /// The segment's name.
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Where guests can open the segment (e.g. `/dev/shm/frames`).
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// The segment's size in bytes.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> usize {
+        self.segment.len()
+    }
+
+    /// A `Uint8Array` over the segment's bytes.
+    ///
+    /// The array's `buffer` is a `SharedArrayBuffer`, so writes are visible
+    /// to programs immediately. Don't use the view after calling
+    /// {@link SharedSegment.unlink} and dropping every other handle.
+    pub fn view(&self) -> js_sys::Uint8Array {
+        self.segment.view()
+    }
+
+    /// Remove the segment from `/dev/shm`, like `shm_unlink()`.
+    ///
+    /// Programs which already have it open can keep using it, but new
+    /// programs won't be able to open it and the next
+    /// {@link Runtime.createSharedMemory} with this name creates a new one.
+    pub fn unlink(&self) {
+        self.table.remove(&self.name);
+    }
+}
+
+/// An open handle to a [`Segment`].
+#[derive(Debug)]
+struct SegmentFile {
+    segment: Arc<Segment>,
+    position: u64,
+}
+
+impl SegmentFile {
+    fn new(segment: Arc<Segment>) -> Self {
+        SegmentFile {
+            segment,
+            position: 0,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        usize::try_from(self.position).unwrap_or(usize::MAX)
+    }
+}
+
+impl VirtualFile for SegmentFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        self.segment.len() as u64
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        // Note: ftruncate() to the existing size is how shm_open() callers
+        // usually "allocate" a segment, so only resizing is an error.
+        if new_size == self.size() {
+            Ok(())
+        } else {
+            Err(virtual_fs::FsError::PermissionDenied)
+        }
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let remaining = self.segment.len().saturating_sub(self.offset());
+        Poll::Ready(Ok(remaining))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let remaining = self.segment.len().saturating_sub(self.offset());
+        Poll::Ready(Ok(remaining))
+    }
+}
+
+impl AsyncRead for SegmentFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = self.segment.read(self.offset(), buf.initialize_unfilled());
+        buf.advance(read);
+        self.position += read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SegmentFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self.segment.write(self.offset(), buf);
+        self.position += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for SegmentFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let position = match position {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, FileSystem};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn instances_and_javascript_see_the_same_bytes() {
+        let table = SharedMemoryTable::default();
+        let segment = table.open("frames", 8).unwrap();
+        assert_eq!(segment.path(), "/dev/shm/frames");
+        assert!(table.open("frames", 16).is_err());
+
+        let open = || {
+            table
+                .filesystem()
+                .new_open_options()
+                .read(true)
+                .write(true)
+                .open("/frames")
+                .unwrap()
+        };
+        let mut producer = open();
+        let mut consumer = open();
+
+        producer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        consumer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(segment.view().get_index(1), b'i');
+
+        segment.view().set_index(7, b'!');
+        consumer.seek(io::SeekFrom::End(-1)).await.unwrap();
+        let mut last = Vec::new();
+        consumer.read_to_end(&mut last).await.unwrap();
+        assert_eq!(last, b"!");
+
+        assert_eq!(producer.write(b"overflow").await.unwrap(), 4);
+        segment.unlink();
+        assert!(table.filesystem().metadata("/frames".as_ref()).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Giving programs a chance to shut down cleanly when the page goes away.
+//!
+//! When the page is unloaded (`pagehide`, without going into the
+//! back/forward cache), every running program is sent `SIGTERM`. Timers never
+//! fire once the page has gone, so the main thread waits out the grace period
+//! there and then (programs run on workers, so they can still exit in the
+//! meantime), kills whatever is left, and syncs any open
+//! [`KeyValueStore`][crate::KeyValueStore]s.
+//!
+//! Browsers often discard hidden tabs without unloading them, especially on
+//! mobile, so stores are also synced whenever the page is hidden. Programs
+//! aren't signalled then, because the user may well come back.
+
+use std::sync::Weak;
+
+use instant::Duration;
+use serde::Deserialize;
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasmer_wasix::types::wasi::Signal;
+
+use crate::{
+    events::RuntimeEvent,
+    runtime::Runtime,
+    utils::{Error, GlobalScope},
+};
+
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_millis(250);
+
+/// The parsed `shutdown` option.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShutdownOptions {
+    /// How long programs get to exit after `SIGTERM` before being killed.
+    grace_period: Duration,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        ShutdownOptions {
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+impl ShutdownOptions {
+    /// Parse the `shutdown` option, returning `None` if it is disabled.
+    pub(crate) fn parse(value: JsValue) -> Result<Option<Self>, Error> {
+        if let Some(enabled) = value.as_bool() {
+            return Ok(enabled.then(ShutdownOptions::default));
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Init {
+            grace_period: Option<f64>,
+        }
+
+        let Init { grace_period } = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+        let grace_period = match grace_period {
+            Some(ms) if ms.is_finite() && ms >= 0.0 => Duration::from_millis(ms as u64),
+            Some(ms) => {
+                let msg = format!("The shutdown grace period must be positive, not {ms}");
+                return Err(Error::js(js_sys::RangeError::new(&msg)));
+            }
+            None => DEFAULT_GRACE_PERIOD,
+        };
+
+        Ok(Some(ShutdownOptions { grace_period }))
+    }
+}
+
+/// Listen for the page being hidden or unloaded, for as long as the runtime
+/// is alive.
+///
+/// This only does anything on a page's main thread.
+pub(crate) fn watch(runtime: Weak<Runtime>, options: ShutdownOptions) {
+    let GlobalScope::Window(window) = GlobalScope::current() else {
+        return;
+    };
+    let Some(document) = window.document() else {
+        return;
+    };
+
+    let on_pagehide = Closure::<dyn FnMut(web_sys::Event)>::new({
+        let runtime = runtime.clone();
+        move |event: web_sys::Event| {
+            // Note: a page going into the back/forward cache is only frozen,
+            // and its programs carry on if the user comes back
+            let persisted = js_sys::Reflect::get(&event, &"persisted".into())
+                .map(|p| p.is_truthy())
+                .unwrap_or(false);
+
+            if persisted {
+                flush();
+            } else if let Some(rt) = runtime.upgrade() {
+                terminate(&rt, options.grace_period);
+            }
+        }
+    });
+    let on_visibilitychange = Closure::<dyn FnMut()>::new({
+        let document = document.clone();
+        move || {
+            if document.hidden() && runtime.strong_count() > 0 {
+                flush();
+            }
+        }
+    });
+
+    let listening = window
+        .add_event_listener_with_callback("pagehide", on_pagehide.as_ref().unchecked_ref())
+        .and_then(|_| {
+            document.add_event_listener_with_callback(
+                "visibilitychange",
+                on_visibilitychange.as_ref().unchecked_ref(),
+            )
+        });
+    if let Err(e) = listening {
+        tracing::warn!(error = ?e, "Unable to listen for the page being unloaded");
+        return;
+    }
+
+    // Note: the listeners do nothing once the runtime has been dropped
+    on_pagehide.forget();
+    on_visibilitychange.forget();
+}
+
+/// Ask every running program to exit, killing whatever is left once the
+/// grace period is up.
+fn terminate(runtime: &Runtime, grace_period: Duration) {
+    let processes = runtime.processes();
+    let pids = processes.pids();
+    tracing::info!(?pids, ?grace_period, "The page is being unloaded");
+
+    for &pid in &pids {
+        if let Err(e) = processes.signal(pid, Signal::Sigterm) {
+            tracing::warn!(pid, error = &*e, "Unable to ask a program to exit");
+        }
+    }
+
+    // Note: the page is going away, so the event has to be dispatched now
+    // rather than queued for the scheduler
+    let event = RuntimeEvent::Shutdown {
+        pids: pids.clone(),
+        grace_period_ms: grace_period.as_millis() as u64,
+    };
+    if let Err(e) = runtime.events().dispatch(&event) {
+        tracing::warn!(
+            error = &*e.into_anyhow(),
+            "Unable to dispatch the shutdown event"
+        );
+    }
+
+    // Note: the page is going away, so a timer would never fire. Busy-wait
+    // instead, which holds up the unload by at most the grace period.
+    let deadline = js_sys::Date::now() + grace_period.as_secs_f64() * 1000.0;
+    let mut remaining = pids;
+    while !remaining.is_empty() && js_sys::Date::now() < deadline {
+        std::hint::spin_loop();
+        let running = processes.pids();
+        remaining.retain(|pid| running.contains(pid));
+    }
+
+    for pid in remaining {
+        tracing::debug!(pid, "Killing a program which didn't exit in time");
+        let _ = processes.signal(pid, Signal::Sigkill);
+    }
+    flush();
+}
+
+fn flush() {
+    wasm_bindgen_futures::spawn_local(crate::kv::sync_open_stores());
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn parse_the_shutdown_option() {
+        assert_eq!(ShutdownOptions::parse(false.into()).unwrap(), None);
+        assert_eq!(
+            ShutdownOptions::parse(true.into()).unwrap(),
+            Some(ShutdownOptions::default())
+        );
+
+        let options = js_sys::JSON::parse(r#"{"gracePeriod": 1000}"#).unwrap();
+        let options = ShutdownOptions::parse(options).unwrap().unwrap();
+        assert_eq!(options.grace_period, Duration::from_secs(1));
+
+        let negative = js_sys::JSON::parse(r#"{"gracePeriod": -1}"#).unwrap();
+        assert!(ShutdownOptions::parse(negative).is_err());
+    }
+}
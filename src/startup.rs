@@ -0,0 +1,177 @@
+//! Reporting how far `runWasix()` has got with starting a program, so
+//! applications can show multi-stage progress rather than a spinner.
+
+use futures::{channel::mpsc, StreamExt};
+use instant::{Duration, Instant};
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+/// One of the steps involved in starting a program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Phase {
+    Resolving,
+    Compiling,
+    FsSetup,
+    Instantiating,
+    Running,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Started,
+    Finished,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct ProgressEvent {
+    phase: Phase,
+    status: Status,
+    /// Milliseconds since the program started being launched.
+    elapsed: f64,
+    /// How long the phase took, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+}
+
+#[derive(Debug)]
+struct Update {
+    phase: Phase,
+    duration: Option<Duration>,
+}
+
+/// Sends progress updates to an `onProgress` callback.
+///
+/// Updates may come from any thread, but the callback is always invoked on
+/// the thread this was created on.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StartupProgress {
+    updates: Option<mpsc::UnboundedSender<Update>>,
+}
+
+impl StartupProgress {
+    pub(crate) fn new(callback: Option<js_sys::Function>) -> Self {
+        let Some(callback) = callback else {
+            return StartupProgress::default();
+        };
+
+        let (sender, mut receiver) = mpsc::unbounded();
+        let launched = Instant::now();
+
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(Update { phase, duration }) = receiver.next().await {
+                let event = ProgressEvent {
+                    phase,
+                    status: match duration {
+                        Some(_) => Status::Finished,
+                        None => Status::Started,
+                    },
+                    elapsed: launched.elapsed().as_secs_f64() * 1000.0,
+                    duration: duration.map(|d| d.as_secs_f64() * 1000.0),
+                };
+
+                let result = serde_wasm_bindgen::to_value(&event)
+                    .map_err(JsValue::from)
+                    .and_then(|event| callback.call1(&JsValue::NULL, &event));
+                if let Err(e) = result {
+                    tracing::warn!(error = ?e, ?phase, "The onProgress callback failed");
+                }
+            }
+        });
+
+        StartupProgress {
+            updates: Some(sender),
+        }
+    }
+
+    /// Report that a phase has started.
+    ///
+    /// Dropping the timer without calling [`PhaseTimer::finish()`] (e.g.
+    /// because the phase failed) means no "finished" update is sent.
+    pub(crate) fn start(&self, phase: Phase) -> PhaseTimer {
+        self.send(phase, None);
+
+        PhaseTimer {
+            progress: self.clone(),
+            phase,
+            started: Instant::now(),
+        }
+    }
+
+    fn send(&self, phase: Phase, duration: Option<Duration>) {
+        if let Some(updates) = &self.updates {
+            let _ = updates.unbounded_send(Update { phase, duration });
+        }
+    }
+}
+
+/// A phase that has started, but not finished.
+#[derive(Debug)]
+pub(crate) struct PhaseTimer {
+    progress: StartupProgress,
+    phase: Phase,
+    started: Instant,
+}
+
+impl PhaseTimer {
+    pub(crate) fn finish(self) {
+        self.progress.send(self.phase, Some(self.started.elapsed()));
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const STARTUP_TYPE_DECLARATIONS: &str = r#"
+/**
+ * The steps {@link runWasix} goes through when starting a program, in the
+ * order they happen.
+ *
+ * When `runWasix()` is given a {@link Wasmer} package, it has already been
+ * downloaded, and compiling and instantiating its command happen as part of
+ * `"running"`.
+ */
+export type StartupPhase =
+    | "resolving"
+    | "compiling"
+    | "fs-setup"
+    | "instantiating"
+    | "running";
+
+/**
+ * Sent to `onProgress` when a startup phase starts and again when it
+ * finishes. The `"running"` phase never finishes.
+ */
+export type StartupProgress = {
+    phase: StartupPhase;
+    status: "started" | "finished";
+    /** Milliseconds since `runWasix()` was called. */
+    elapsed: number;
+    /** How long the phase took, in milliseconds (only set when finished). */
+    duration?: number;
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use js_sys::Reflect;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn progress_events_use_kebab_case_phases() {
+        let event = ProgressEvent {
+            phase: Phase::FsSetup,
+            status: Status::Finished,
+            elapsed: 12.0,
+            duration: Some(3.5),
+        };
+
+        let value = serde_wasm_bindgen::to_value(&event).unwrap();
+
+        let get = |key: &str| Reflect::get(&value, &JsValue::from_str(key)).unwrap();
+        assert_eq!(get("phase"), "fs-setup");
+        assert_eq!(get("status"), "finished");
+        assert_eq!(get("duration"), 3.5);
+    }
+}
@@ -0,0 +1,121 @@
+//! Negotiating persistent storage with the browser.
+//!
+//! Unless a page has been granted persistent storage, the browser may evict
+//! anything it has stored (IndexedDB, OPFS, etc.) when the device runs low on
+//! space. We ask for persistence up front and use the storage estimate to
+//! keep the modules we persist from eating into the origin's quota.
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::utils::{Error, GlobalScope};
+
+/// The fraction of the remaining quota persisted modules are allowed to use.
+const CACHE_BUDGET_FRACTION: f64 = 0.25;
+
+/// What the browser told us about the origin's storage.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StorageStatus {
+    /// Is the origin's storage protected from eviction?
+    pub(crate) persisted: bool,
+    /// Bytes currently used by the origin.
+    pub(crate) usage_bytes: Option<f64>,
+    /// The maximum number of bytes the origin may use.
+    pub(crate) quota_bytes: Option<f64>,
+}
+
+impl StorageStatus {
+    /// Ask the browser to make storage persistent and get an estimate of how
+    /// much space is available.
+    ///
+    /// Environments without a `StorageManager` (e.g. insecure contexts)
+    /// report non-persistent storage with no estimate.
+    pub(crate) async fn negotiate() -> Result<Self, Error> {
+        let Some(storage) = GlobalScope::current().storage() else {
+            tracing::debug!("The StorageManager API isn't available");
+            return Ok(StorageStatus::default());
+        };
+
+        // Note: the browser may show a permission prompt or silently refuse,
+        // so a rejection here isn't an error.
+        let persisted = match storage.persist() {
+            Ok(promise) => JsFuture::from(promise)
+                .await
+                .ok()
+                .and_then(|value| value.as_bool())
+                .unwrap_or(false),
+            Err(_) => false,
+        };
+
+        let estimate = JsFuture::from(storage.estimate().map_err(Error::js)?)
+            .await
+            .map_err(Error::js)?;
+        let number = |key: &str| {
+            js_sys::Reflect::get(&estimate, &JsValue::from_str(key))
+                .ok()
+                .and_then(|value| value.as_f64())
+        };
+
+        Ok(StorageStatus {
+            persisted,
+            usage_bytes: number("usage"),
+            quota_bytes: number("quota"),
+        })
+    }
+
+    /// How many bytes persisted modules may use, or `None` if there's no quota
+    /// to stay within.
+    pub(crate) fn cache_budget(&self) -> Option<u64> {
+        let quota = self.quota_bytes?;
+        let remaining = (quota - self.usage_bytes.unwrap_or(0.0)).max(0.0);
+        Some((remaining * CACHE_BUDGET_FRACTION) as u64)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const STORAGE_STATUS_TYPE_DECLARATION: &str = r#"
+/**
+ * The result of negotiating persistent storage with the browser.
+ */
+export type StorageStatus = {
+    /**
+     * Was the origin granted persistent storage?
+     *
+     * When this is `false`, the browser may evict cached packages and
+     * persistent filesystems under storage pressure, so you may want to warn
+     * users before relying on them.
+     */
+    persisted: boolean;
+    /** Bytes currently used by the origin, if known. */
+    usageBytes?: number;
+    /** The maximum number of bytes the origin may use, if known. */
+    quotaBytes?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "StorageStatus")]
+    pub type JsStorageStatus;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn cache_budget_is_a_fraction_of_the_remaining_quota() {
+        let status = StorageStatus {
+            persisted: true,
+            usage_bytes: Some(200.0),
+            quota_bytes: Some(1000.0),
+        };
+
+        assert_eq!(status.cache_budget(), Some(200));
+        assert_eq!(StorageStatus::default().cache_budget(), None);
+    }
+}
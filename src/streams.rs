@@ -1,23 +1,60 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{self, Poll},
+};
+
 use anyhow::Context;
 use bytes::BytesMut;
-use futures::{future::Either, Stream};
+use futures::{
+    future::{BoxFuture, Either},
+    Stream,
+};
 use js_sys::{JsString, Promise, Reflect, Uint8Array};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
 use tracing::Instrument;
-use virtual_fs::{AsyncReadExt, AsyncWriteExt, Pipe};
+use virtual_fs::{AsyncReadExt, AsyncWriteExt, Pipe, VirtualFile};
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    ReadableStream, ReadableStreamDefaultController, ReadableStreamDefaultReader, WritableStream,
+    ReadableByteStreamController, ReadableStream, ReadableStreamByobRequest,
+    ReadableStreamDefaultController, ReadableStreamDefaultReader, WritableStream,
 };
 
-use crate::utils::Error;
+use crate::{
+    chunking::ChunkSizer,
+    utils::{Error, GlobalScope},
+};
 
 /// Set up a pipe where data written from JavaScript can be read by the WASIX
 /// process.
 pub(crate) fn input_pipe() -> (Pipe, WritableStream) {
+    counted_input_pipe(Arc::default())
+}
+
+/// The same as [`input_pipe()`], except every byte written from JavaScript is
+/// added to `bytes_written`.
+pub(crate) fn counted_input_pipe(bytes_written: Arc<AtomicU64>) -> (Pipe, WritableStream) {
+    let (pipe, stream, _) = closable_input_pipe(bytes_written);
+    (pipe, stream)
+}
+
+/// The same as [`counted_input_pipe()`], but also returning a [`StdinHandle`]
+/// which can send EOF without going through the `WritableStream`.
+pub(crate) fn closable_input_pipe(
+    bytes_written: Arc<AtomicU64>,
+) -> (Pipe, WritableStream, StdinHandle) {
     let (left, right) = Pipe::channel();
+    let handle = StdinHandle(right.clone());
 
-    let sink = JsValue::from(WritableStreamSink { pipe: right });
+    let sink = JsValue::from(WritableStreamSink {
+        pipe: right,
+        bytes_written,
+    });
 
     let callback: wasm_bindgen::prelude::Closure<dyn Fn(Uint8Array) -> f64> =
         wasm_bindgen::closure::Closure::new(|chunk: Uint8Array| chunk.byte_length() as f64);
@@ -30,13 +67,28 @@ pub(crate) fn input_pipe() -> (Pipe, WritableStream) {
     )
     .unwrap();
 
-    (left, stream)
+    (left, stream, handle)
+}
+
+/// The writing end of an input pipe, used to signal EOF even when the
+/// `WritableStream` is locked to a writer.
+#[derive(Debug, Clone)]
+pub(crate) struct StdinHandle(Pipe);
+
+impl StdinHandle {
+    /// Close the pipe, waking up any reads that are blocked waiting for data
+    /// so they see EOF.
+    pub(crate) fn close(&self) {
+        tracing::debug!("Closing stdin");
+        self.0.close();
+    }
 }
 
 #[derive(Debug)]
 #[wasm_bindgen(skip_typescript)]
 struct WritableStreamSink {
     pipe: Pipe,
+    bytes_written: Arc<AtomicU64>,
 }
 
 #[wasm_bindgen]
@@ -86,6 +138,7 @@ impl WritableStreamSink {
     /// aborted (see below).
     pub fn write(&mut self, chunk: Uint8Array) -> Promise {
         let mut pipe = self.pipe.clone();
+        let bytes_written = Arc::clone(&self.bytes_written);
         let data = chunk.to_vec();
 
         wasm_bindgen_futures::future_to_promise(
@@ -100,6 +153,7 @@ impl WritableStreamSink {
                     .await
                     .context("Write failed")
                     .map_err(Error::from)?;
+                bytes_written.fetch_add(data.len() as u64, Ordering::Relaxed);
                 Ok(JsValue::UNDEFINED)
             }
             .in_current_span()
@@ -111,28 +165,126 @@ impl WritableStreamSink {
 /// Set up a pipe where the WASIX pipe writes data that will be read from
 /// JavaScript.
 pub(crate) fn output_pipe() -> (Pipe, ReadableStream) {
+    counted_output_pipe(Arc::default(), Arc::default())
+}
+
+/// The same as [`output_pipe()`], except every byte read by JavaScript is
+/// added to `bytes_read` and the chunk sizes used are recorded in `chunks`.
+///
+/// Where the browser supports it, this is a byte stream so consumers can use
+/// a BYOB (bring your own buffer) reader to read straight into their own
+/// buffers.
+pub(crate) fn counted_output_pipe(
+    bytes_read: Arc<AtomicU64>,
+    chunks: Arc<ChunkSizer>,
+) -> (Pipe, ReadableStream) {
     let (left, right) = Pipe::channel();
+    let byte_stream = byte_streams_supported();
 
-    let source = JsValue::from(ReadableStreamSource { pipe: right });
+    let source = JsValue::from(ReadableStreamSource {
+        pipe: right,
+        bytes_read,
+        chunks,
+        byte_stream,
+    });
 
-    let callback: wasm_bindgen::prelude::Closure<dyn Fn(Uint8Array) -> f64> =
-        wasm_bindgen::closure::Closure::new(|chunk: Uint8Array| chunk.byte_length() as f64);
+    let mut strategy = web_sys::QueuingStrategy::new();
+    strategy.high_water_mark(256.0);
 
-    let stream = ReadableStream::new_with_underlying_source_and_strategy(
-        source.unchecked_ref(),
-        web_sys::QueuingStrategy::new()
-            .high_water_mark(256.0)
-            .size(callback.into_js_value().unchecked_ref()),
-    )
-    .unwrap();
+    if !byte_stream {
+        // Note: byte streams always measure their queue in bytes and throw
+        // if given a size() function.
+        let callback: wasm_bindgen::prelude::Closure<dyn Fn(Uint8Array) -> f64> =
+            wasm_bindgen::closure::Closure::new(|chunk: Uint8Array| chunk.byte_length() as f64);
+        strategy.size(callback.into_js_value().unchecked_ref());
+    }
+
+    let stream =
+        ReadableStream::new_with_underlying_source_and_strategy(source.unchecked_ref(), &strategy)
+            .unwrap();
 
     (left, stream)
 }
 
+/// Safari will run the native constructor for `ReadableByteStreamController`
+/// when a source asks for `type: "bytes"`, which throws on versions that
+/// haven't implemented it yet.
+fn byte_streams_supported() -> bool {
+    GlobalScope::current()
+        .lookup(&["ReadableByteStreamController"])
+        .is_some()
+}
+
 #[derive(Debug)]
 #[wasm_bindgen(skip_typescript)]
 struct ReadableStreamSource {
     pipe: Pipe,
+    bytes_read: Arc<AtomicU64>,
+    chunks: Arc<ChunkSizer>,
+    byte_stream: bool,
+}
+
+/// Whichever controller the stream was created with.
+enum Controller {
+    Default(ReadableStreamDefaultController),
+    Bytes(ReadableByteStreamController),
+}
+
+impl Controller {
+    fn desired_size(&self) -> Option<f64> {
+        match self {
+            Controller::Default(c) => c.desired_size(),
+            Controller::Bytes(c) => c.desired_size(),
+        }
+    }
+
+    /// The reader's buffer, if a BYOB (or auto-allocated) read is waiting to
+    /// be filled.
+    fn byob_request(&self) -> Option<(ReadableStreamByobRequest, Uint8Array)> {
+        let Controller::Bytes(c) = self else {
+            return None;
+        };
+        let request = c.byob_request()?;
+        let view = request.view()?;
+        Some((request, as_bytes(&view)))
+    }
+
+    fn enqueue(&self, chunk: &Uint8Array) -> Result<(), JsValue> {
+        match self {
+            Controller::Default(c) => c.enqueue_with_chunk(chunk),
+            Controller::Bytes(c) => c.enqueue_with_array_buffer_view(chunk),
+        }
+    }
+
+    fn close(&self) -> Result<(), JsValue> {
+        match self {
+            Controller::Default(c) => c.close(),
+            Controller::Bytes(c) => c.close(),
+        }
+    }
+
+    fn error(&self, e: &JsValue) {
+        match self {
+            Controller::Default(c) => c.error_with_e(e),
+            Controller::Bytes(c) => c.error_with_e(e),
+        }
+    }
+}
+
+/// View any `ArrayBufferView` (e.g. a `DataView` or `Int32Array`) as bytes.
+fn as_bytes(view: &js_sys::Object) -> Uint8Array {
+    if let Some(bytes) = view.dyn_ref::<Uint8Array>() {
+        return bytes.clone();
+    }
+
+    let get = |name: &str| {
+        Reflect::get(view, &JsValue::from_str(name))
+            .ok()
+            .and_then(|v| v.as_f64())
+            .unwrap_or_default() as u32
+    };
+    let buffer = Reflect::get(view, &JsValue::from_str("buffer")).unwrap_or_default();
+    Uint8Array::new_with_byte_offset_and_length(&buffer, get("byteOffset"), get("byteLength"))
 }
 
 #[wasm_bindgen]
@@ -149,40 +301,64 @@ impl ReadableStreamSource {
     /// successfully completes. Additionally, it will only be called repeatedly
     /// if it enqueues at least one chunk or fulfills a BYOB request; a no-op
     /// pull() implementation will not be continually called.
-    pub fn pull(&mut self, controller: ReadableStreamDefaultController) -> Promise {
+    pub fn pull(&mut self, controller: JsValue) -> Promise {
         let mut pipe = self.pipe.clone();
+        let bytes_read = Arc::clone(&self.bytes_read);
+        let chunks = Arc::clone(&self.chunks);
+        let controller = if self.byte_stream {
+            Controller::Bytes(controller.unchecked_into())
+        } else {
+            Controller::Default(controller.unchecked_into())
+        };
 
         wasm_bindgen_futures::future_to_promise(
             async move {
-                /// The maximum buffer size we will allow - helps avoid OOMs.
-                const MAX_CAPACITY: usize = 10_000_000;
-
-                let capacity = controller
-                    .desired_size()
-                    .map(|size| std::cmp::min(size as usize, MAX_CAPACITY))
-                    .unwrap_or(128);
-                let mut buffer = BytesMut::with_capacity(capacity);
-
-                match pipe.read_buf(&mut buffer).await.context("Read failed") {
+                let request = controller.byob_request();
+                let capacity = match &request {
+                    Some((_, view)) => view.byte_length().min(chunks.next()),
+                    None => chunks.next(),
+                };
+                // Note: the buffer is never bigger than the reader's view, so
+                // we never read more than can be handed over
+                let mut buffer = BytesMut::zeroed(capacity as usize);
+
+                match pipe.read(&mut buffer).await.context("Read failed") {
                     Ok(0) => {
                         tracing::debug!("EOF");
                         controller.close()?;
+                        if let Some((request, _)) = request {
+                            // Closing leaves a pending BYOB read waiting for
+                            // us to hand back its (empty) buffer
+                            request.respond_with_u32(0)?;
+                        }
                     }
-                    Ok(bytes_read) => {
-                        let data = &buffer[..bytes_read];
+                    Ok(len) => {
+                        let data = &buffer[..len];
                         tracing::trace!(
-                            bytes_read,
+                            bytes_read = len,
                             ?data,
                             data_utf8 = String::from_utf8_lossy(data).as_ref()
                         );
-
-                        let buffer = Uint8Array::from(data);
-                        controller.enqueue_with_chunk(&buffer)?;
+                        bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+
+                        match request {
+                            Some((request, view)) => {
+                                // Copy into the reader's own buffer. Handing
+                                // out a view into linear memory would be a
+                                // use-after-free once this buffer is dropped.
+                                view.subarray(0, len as u32).copy_from(data);
+                                request.respond_with_u32(len as u32)?;
+                            }
+                            None => controller.enqueue(&Uint8Array::from(data))?,
+                        }
+
+                        let backpressure = controller.desired_size().map_or(false, |s| s <= 0.0);
+                        chunks.record(capacity, len as u32, backpressure);
                     }
                     Err(e) => {
                         tracing::debug!(error = &*e);
                         let err = JsValue::from(Error::from(e));
-                        controller.error_with_e(&err);
+                        controller.error(&err);
                     }
                 }
 
@@ -212,17 +388,21 @@ impl ReadableStreamSource {
     /// passed controller will be a `ReadableStreamDefaultController`.
     #[wasm_bindgen(getter, js_name = "type")]
     pub fn type_(&self) -> Option<JsString> {
-        // Note: We can't use BYOB for zero-copy streaming because it'd mean
-        // JavaScript code gets a reference to the buffer allocated inside the
-        // pull() method. That Uint8Array is a view into a linear memory
-        // and would any access after the pull method's promise completes would
-        // be a use-after-free.
-        //
-        // This also works around a limitation in Safari where returning
-        // JsString::from("bytes") causes the browser's *native* code to run the
-        // constructor for ReadableByteStreamController, which isn't implemented
-        // yet.
-        None
+        // Note: BYOB reads are still copied out of linear memory and into the
+        // reader's buffer, but that saves allocating a new Uint8Array for
+        // every chunk.
+        self.byte_stream.then(|| JsString::from("bytes"))
+    }
+
+    /// For byte streams, makes the stream allocate a buffer of this size
+    /// for default readers so every `pull()` gets a BYOB request to fill.
+    ///
+    /// This is only an upper bound, because `pull()` picks how much of it to
+    /// use.
+    #[wasm_bindgen(getter, js_name = "autoAllocateChunkSize")]
+    pub fn auto_allocate_chunk_size(&self) -> Option<u32> {
+        self.byte_stream
+            .then(|| crate::fingerprint::tuning().stream_chunk_size)
     }
 }
 
@@ -270,6 +450,115 @@ fn get_chunk(next_chunk: JsValue) -> Result<Option<Vec<u8>>, Error> {
     Ok(Some(chunk.to_vec()))
 }
 
+pub(crate) type BoxedFile = Box<dyn VirtualFile + Send + Sync + 'static>;
+
+/// Wrap a file so `on_write` sees every chunk successfully written to it,
+/// without changing what gets written.
+pub(crate) fn watch_writes(
+    inner: BoxedFile,
+    on_write: impl FnMut(&[u8]) + Send + Sync + 'static,
+) -> BoxedFile {
+    Box::new(WatchedFile {
+        inner,
+        on_write: Box::new(on_write),
+    })
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct WatchedFile {
+    inner: BoxedFile,
+    #[derivative(Debug = "ignore")]
+    on_write: Box<dyn FnMut(&[u8]) + Send + Sync>,
+}
+
+impl VirtualFile for WatchedFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
+        self.inner.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        self.inner.unlink()
+    }
+
+    fn poll_read_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_ready(cx)
+    }
+}
+
+impl AsyncRead for WatchedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WatchedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                (self.on_write)(&buf[..written]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for WatchedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use futures::TryStreamExt;
@@ -327,6 +616,29 @@ mod tests {
         drop(pipe);
     }
 
+    #[wasm_bindgen_test]
+    async fn byob_readers_fill_their_own_buffer() {
+        if !byte_streams_supported() {
+            return;
+        }
+        let (mut pipe, stream) = output_pipe();
+        pipe.write_all(b"Hello, World!").await.unwrap();
+        pipe.close();
+
+        let mut options = web_sys::ReadableStreamGetReaderOptions::new();
+        options.mode(web_sys::ReadableStreamReaderMode::Byob);
+        let reader: web_sys::ReadableStreamByobReader =
+            stream.get_reader_with_options(&options).unchecked_into();
+
+        let view = Uint8Array::new_with_length(5);
+        let result = JsFuture::from(reader.read_with_array_buffer_view(&view))
+            .await
+            .unwrap();
+
+        // The original buffer gets transferred into the view we read back
+        assert_eq!(get_chunk(result).unwrap().unwrap(), b"Hello");
+    }
+
     #[wasm_bindgen_test]
     async fn data_written_by_js_is_readable_from_the_pipe() {
         let (mut pipe, stream) = input_pipe();
@@ -343,4 +655,16 @@ mod tests {
 
         assert_eq!(data, "Hello, World!");
     }
+
+    #[wasm_bindgen_test]
+    async fn closing_the_handle_sends_eof_while_the_stream_is_locked() {
+        let (mut pipe, stream, handle) = closable_input_pipe(Arc::default());
+        let _writer = stream.get_writer().unwrap();
+
+        handle.close();
+
+        let mut data = Vec::new();
+        let bytes_read = pipe.read_to_end(&mut data).await.unwrap();
+        assert_eq!(bytes_read, 0);
+    }
 }
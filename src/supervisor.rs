@@ -0,0 +1,552 @@
+//! Keeping a command running, restarting it when it exits.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{Either, LocalBoxFuture, Shared},
+    FutureExt, StreamExt,
+};
+use instant::{Duration, Instant};
+use serde::Deserialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer_wasix::types::wasi::Signal;
+
+use crate::{
+    events::{RuntimeEvent, StopReason},
+    instance::ExitCondition,
+    processes::ProcessTable,
+    tasks::{TaskScope, ThreadPool},
+    utils::{Error, GlobalScope},
+    wasmer::Command,
+    Instance, SpawnOptions, Wasmer,
+};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A command that stays up at least this long is considered healthy, so the
+/// backoff starts again from scratch the next time it fails.
+const DEFAULT_RESET_AFTER: Duration = Duration::from_secs(10);
+
+/// When a supervised command should be restarted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Restart {
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// How a supervised command is restarted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RestartPolicy {
+    restart: Restart,
+    max_restarts: Option<u32>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            restart: Restart::OnFailure,
+            max_restarts: None,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            reset_after: DEFAULT_RESET_AFTER,
+        }
+    }
+}
+
+/// What to do after the command exits.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Decision {
+    Restart { delay: Duration },
+    Stop(StopReason),
+}
+
+impl RestartPolicy {
+    fn parse(spec: &SuperviseSpec) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Backoff {
+            initial: Option<f64>,
+            max: Option<f64>,
+        }
+
+        let defaults = RestartPolicy::default();
+        let restart = match spec.restart() {
+            Some(value) => serde_wasm_bindgen::from_value(value.into()).map_err(Error::js)?,
+            None => defaults.restart,
+        };
+        let backoff: Option<Backoff> = match spec.backoff() {
+            Some(value) => Some(serde_wasm_bindgen::from_value(value).map_err(Error::js)?),
+            None => None,
+        };
+        let millis = |ms: Option<f64>, default: Duration| match ms {
+            Some(ms) if ms.is_finite() && ms >= 0.0 => Ok(Duration::from_millis(ms as u64)),
+            Some(ms) => {
+                let msg = format!("Durations must be non-negative, not {ms}");
+                Err(Error::js(js_sys::RangeError::new(&msg)))
+            }
+            None => Ok(default),
+        };
+
+        Ok(RestartPolicy {
+            restart,
+            max_restarts: spec.max_restarts().map(|n| n.max(0.0) as u32),
+            initial_backoff: millis(
+                backoff.as_ref().and_then(|b| b.initial),
+                defaults.initial_backoff,
+            )?,
+            max_backoff: millis(backoff.as_ref().and_then(|b| b.max), defaults.max_backoff)?,
+            reset_after: millis(spec.reset_after(), defaults.reset_after)?,
+        })
+    }
+
+    /// Decide what to do after the command exits with `code`.
+    ///
+    /// `restarts` is how many times it has been restarted so far and
+    /// `failures` how many times in a row it has failed without staying up
+    /// for [`RestartPolicy::reset_after`].
+    pub(crate) fn decide(&self, code: i32, restarts: u32, failures: u32) -> Decision {
+        let wants_restart = match self.restart {
+            Restart::Always => true,
+            Restart::OnFailure => code != 0,
+            Restart::Never => false,
+        };
+
+        if !wants_restart {
+            return Decision::Stop(StopReason::Exited);
+        }
+        if self.max_restarts.is_some_and(|max| restarts >= max) {
+            return Decision::Stop(StopReason::RestartLimit);
+        }
+
+        let delay = match failures {
+            0 => Duration::ZERO,
+            n => self
+                .initial_backoff
+                .saturating_mul(1 << (n - 1).min(16))
+                .min(self.max_backoff),
+        };
+
+        Decision::Restart { delay }
+    }
+}
+
+/// A command being kept alive by {@link Runtime.supervise}.
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct Supervisor(Rc<State>);
+
+#[derive(Debug)]
+struct State {
+    name: String,
+    restarts: Cell<u32>,
+    stopped: Cell<bool>,
+    /// The instance that is currently running, if any.
+    current: RefCell<Option<Running>>,
+    /// Wakes the supervisor up if it is waiting to restart the command.
+    cancel_backoff: RefCell<Option<oneshot::Sender<()>>>,
+    done: Shared<LocalBoxFuture<'static, Option<i32>>>,
+}
+
+/// The parts of a running {@link Instance} needed to stop it.
+#[derive(Debug)]
+struct Running {
+    stdin: Option<web_sys::WritableStream>,
+    process: Option<(u32, ProcessTable)>,
+    tasks: Option<TaskScope>,
+}
+
+impl Running {
+    /// Close the command's stdin, then kill it and cancel everything it
+    /// started.
+    async fn stop(self) {
+        if let Some(stdin) = self.stdin.filter(|s| !s.locked()) {
+            let _ = JsFuture::from(stdin.close()).await;
+        }
+        if let Some((pid, processes)) = &self.process {
+            // Note: this fails if the program has already exited
+            let _ = processes.signal(*pid, Signal::Sigkill);
+        }
+        if let Some(tasks) = &self.tasks {
+            tasks.cancel();
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Supervisor {
+    /// The name this service is reported under in lifecycle events.
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.0.name.clone()
+    }
+
+    /// How many times the command has been restarted.
+    #[wasm_bindgen(getter)]
+    pub fn restarts(&self) -> u32 {
+        self.0.restarts.get()
+    }
+
+    /// Is the supervisor still keeping the command running?
+    #[wasm_bindgen(getter)]
+    pub fn running(&self) -> bool {
+        self.0.done.peek().is_none()
+    }
+
+    /// Stop restarting the command and wait for it to exit.
+    ///
+    /// The running command has its stdin closed (unless the caller locked
+    /// it), then is killed and has its tasks cancelled, which wakes sleeping
+    /// threads and aborts in-flight HTTP requests. A pending restart is
+    /// cancelled straight away.
+    pub async fn stop(&self) -> Option<i32> {
+        self.0.stopped.set(true);
+
+        if let Some(cancel) = self.0.cancel_backoff.borrow_mut().take() {
+            let _ = cancel.send(());
+        }
+        let current = self.0.current.borrow_mut().take();
+        if let Some(current) = current {
+            current.stop().await;
+        }
+
+        self.wait().await
+    }
+
+    /// Wait until the supervisor gives up (or is stopped), returning the
+    /// command's last exit code.
+    pub async fn wait(&self) -> Option<i32> {
+        self.0.done.clone().await
+    }
+}
+
+impl Supervisor {
+    pub(crate) fn start(pool: ThreadPool, spec: SuperviseSpec) -> Result<Supervisor, Error> {
+        let pkg = Wasmer::try_from(&spec.package()).map_err(|_| {
+            Error::js(js_sys::TypeError::new(
+                "Expected \"package\" to be a Wasmer package",
+            ))
+        })?;
+        let cmd = match spec.command() {
+            Some(name) => pkg.command(&name).ok_or_else(|| {
+                anyhow::anyhow!("The package doesn't contain a \"{name}\" command")
+            })?,
+            None => pkg.entrypoint.clone().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "The package doesn't have an entrypoint, so a command must be specified"
+                )
+            })?,
+        };
+        let name = spec.name().unwrap_or_else(|| String::from(&cmd.name));
+        let policy = RestartPolicy::parse(&spec)?;
+
+        let (sender, receiver) = oneshot::channel();
+        let state = Rc::new(State {
+            name,
+            restarts: Cell::new(0),
+            stopped: Cell::new(false),
+            current: RefCell::new(None),
+            cancel_backoff: RefCell::new(None),
+            done: receiver.map(|r| r.ok().flatten()).boxed_local().shared(),
+        });
+
+        let supervisor = Supervisor(state);
+        wasm_bindgen_futures::spawn_local({
+            let supervisor = supervisor.clone();
+            async move {
+                let code = supervisor
+                    .supervise(pool, cmd, policy, spec.options(), spec.on_start())
+                    .await;
+                let _ = sender.send(code);
+            }
+        });
+
+        Ok(supervisor)
+    }
+
+    async fn supervise(
+        &self,
+        pool: ThreadPool,
+        cmd: Command,
+        policy: RestartPolicy,
+        options: Option<SpawnOptions>,
+        on_start: Option<js_sys::Function>,
+    ) -> Option<i32> {
+        let state = &self.0;
+        let mut failures = 0;
+
+        loop {
+            let attempt = state.restarts.get() + 1;
+            let started_at = Instant::now();
+
+            let code = match self
+                .run_once(&cmd, options.clone(), on_start.as_ref())
+                .await
+            {
+                Ok(exit) => {
+                    pool.emit(RuntimeEvent::ServiceStarted {
+                        name: state.name.clone(),
+                        attempt,
+                    });
+                    exit.await.map(|ExitCondition(code)| code).unwrap_or(1)
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        error = &*e.into_anyhow(),
+                        name = %state.name,
+                        "Unable to start the supervised command",
+                    );
+                    1
+                }
+            };
+            state.current.borrow_mut().take();
+
+            if started_at.elapsed() >= policy.reset_after {
+                failures = 0;
+            } else if code != 0 || policy.restart == Restart::Always {
+                failures += 1;
+            }
+
+            let decision = if state.stopped.get() {
+                Decision::Stop(StopReason::Stopped)
+            } else {
+                policy.decide(code, state.restarts.get(), failures)
+            };
+
+            match decision {
+                Decision::Restart { delay } => {
+                    pool.emit(RuntimeEvent::ServiceExited {
+                        name: state.name.clone(),
+                        code,
+                        restart_in_ms: Some(delay.as_millis() as u64),
+                    });
+                    if !delay.is_zero() && !state.stopped.get() {
+                        let (cancel, cancelled) = oneshot::channel();
+                        *state.cancel_backoff.borrow_mut() = Some(cancel);
+                        let ms = i32::try_from(delay.as_millis()).unwrap_or(i32::MAX);
+                        let sleep = JsFuture::from(GlobalScope::current().sleep(ms));
+                        if let Either::Right(_) = futures::future::select(sleep, cancelled).await {
+                            tracing::debug!(name = %state.name, "The pending restart was cancelled");
+                        }
+                        state.cancel_backoff.borrow_mut().take();
+                    }
+                    if state.stopped.get() {
+                        pool.emit(RuntimeEvent::ServiceStopped {
+                            name: state.name.clone(),
+                            reason: StopReason::Stopped,
+                            restarts: state.restarts.get(),
+                        });
+                        return Some(code);
+                    }
+                    state.restarts.set(state.restarts.get() + 1);
+                }
+                Decision::Stop(reason) => {
+                    pool.emit(RuntimeEvent::ServiceExited {
+                        name: state.name.clone(),
+                        code,
+                        restart_in_ms: None,
+                    });
+                    pool.emit(RuntimeEvent::ServiceStopped {
+                        name: state.name.clone(),
+                        reason,
+                        restarts: state.restarts.get(),
+                    });
+                    return Some(code);
+                }
+            }
+        }
+    }
+
+    /// Start the command, handing the {@link Instance} to `on_start` and
+    /// returning something that resolves when it exits.
+    async fn run_once(
+        &self,
+        cmd: &Command,
+        options: Option<SpawnOptions>,
+        on_start: Option<&js_sys::Function>,
+    ) -> Result<oneshot::Receiver<ExitCondition>, Error> {
+        let mut instance: Instance = cmd.run(options).await?;
+
+        // Keep the exit notification for ourselves, while still letting
+        // whoever gets the instance wait() on it.
+        let (sender, receiver) = oneshot::channel();
+        let exit = std::mem::replace(&mut instance.exit, receiver);
+        let (tx, rx) = oneshot::channel();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Ok(condition) = exit.await {
+                let _ = sender.send(condition);
+                let _ = tx.send(condition);
+            }
+        });
+
+        *self.0.current.borrow_mut() = Some(Running {
+            stdin: instance.stdin.clone(),
+            process: instance.process.clone(),
+            tasks: instance.tasks.clone(),
+        });
+
+        match on_start {
+            Some(on_start) => {
+                on_start
+                    .call1(&JsValue::NULL, &JsValue::from(instance))
+                    .map_err(Error::js)?;
+            }
+            None => {
+                // Nobody else is interested in the instance, but its output
+                // still needs to be drained so the program doesn't block.
+                for stream in [instance.stdout.clone(), instance.stderr.clone()] {
+                    wasm_bindgen_futures::spawn_local(async move {
+                        crate::streams::read_to_end(stream)
+                            .for_each(|_| async {})
+                            .await;
+                    });
+                }
+            }
+        }
+
+        // Note: stop() may have been called while the command was starting
+        if self.0.stopped.get() {
+            let current = self.0.current.borrow_mut().take();
+            if let Some(current) = current {
+                current.stop().await;
+            }
+        }
+
+        Ok(rx)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const SUPERVISE_SPEC_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Which exits cause a supervised command to be restarted.
+ *
+ * - `"always"` - every time it exits
+ * - `"on-failure"` - only when it exits with a non-zero exit code
+ * - `"never"` - never (it is only started once)
+ */
+export type RestartPolicy = "always" | "on-failure" | "never";
+
+/**
+ * A command for {@link Runtime.supervise} to keep running.
+ */
+export type SuperviseSpec = {
+    /** The package containing the command. */
+    package: Wasmer;
+    /** The command to run. Defaults to the package's entrypoint. */
+    command?: string;
+    /**
+     * The name used in lifecycle events. Defaults to the command's name.
+     */
+    name?: string;
+    /** Options used every time the command is started. */
+    options?: SpawnOptions;
+    /** Defaults to `"on-failure"`. */
+    restart?: RestartPolicy;
+    /** Give up after this many restarts. Defaults to no limit. */
+    maxRestarts?: number;
+    /**
+     * How long to wait before restarting a command that keeps failing, in
+     * milliseconds. The delay starts at `initial` (default `100`) and
+     * doubles every time, up to `max` (default `30000`).
+     */
+    backoff?: { initial?: number; max?: number };
+    /**
+     * How long (in milliseconds) the command must stay up before the backoff
+     * is reset. Defaults to `10000`.
+     */
+    resetAfter?: number;
+    /**
+     * Called with each new {@link Instance}, so its stdio can be wired up.
+     * If not provided, the command's output is discarded.
+     */
+    onStart?: (instance: Instance) => void;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "SuperviseSpec")]
+    pub type SuperviseSpec;
+
+    #[wasm_bindgen(method, getter)]
+    fn package(this: &SuperviseSpec) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn command(this: &SuperviseSpec) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn name(this: &SuperviseSpec) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn options(this: &SuperviseSpec) -> Option<SpawnOptions>;
+
+    #[wasm_bindgen(method, getter)]
+    fn restart(this: &SuperviseSpec) -> Option<js_sys::JsString>;
+
+    #[wasm_bindgen(method, getter, js_name = "maxRestarts")]
+    fn max_restarts(this: &SuperviseSpec) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter)]
+    fn backoff(this: &SuperviseSpec) -> Option<JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = "resetAfter")]
+    fn reset_after(this: &SuperviseSpec) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "onStart")]
+    fn on_start(this: &SuperviseSpec) -> Option<js_sys::Function>;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn back_off_and_give_up() {
+        let policy = RestartPolicy {
+            max_restarts: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(policy.decide(0, 0, 0), Decision::Stop(StopReason::Exited));
+        assert_eq!(
+            policy.decide(1, 0, 0),
+            Decision::Restart {
+                delay: Duration::ZERO
+            }
+        );
+        assert_eq!(
+            policy.decide(1, 1, 3),
+            Decision::Restart {
+                delay: DEFAULT_INITIAL_BACKOFF * 4
+            }
+        );
+        assert_eq!(
+            policy.decide(1, 2, 20),
+            Decision::Restart {
+                delay: DEFAULT_MAX_BACKOFF
+            }
+        );
+        assert_eq!(
+            policy.decide(1, 3, 1),
+            Decision::Stop(StopReason::RestartLimit)
+        );
+
+        let always = RestartPolicy {
+            restart: Restart::Always,
+            ..Default::default()
+        };
+        assert!(matches!(always.decide(0, 10, 0), Decision::Restart { .. }));
+    }
+}
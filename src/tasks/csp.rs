@@ -0,0 +1,192 @@
+//! Diagnosing workers that can't be started because of the page's Content
+//! Security Policy.
+//!
+//! The thread pool's workers are started from a `blob:` URL by default,
+//! which a `worker-src` (or `child-src`, `script-src` or `default-src`)
+//! directive will often block. Browsers report that as a generic
+//! `SecurityError`, so we translate it into something that says what to
+//! change.
+
+use std::fmt::{self, Display};
+
+use js_sys::{Array, Reflect};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+use crate::utils::Error;
+
+/// The directives a browser consults, in order, when deciding whether a
+/// worker may be started.
+const DIRECTIVES: &[&str] = &["worker-src", "child-src", "script-src", "default-src"];
+
+/// What to do when the page won't let us start workers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum BlockedWorkerPolicy {
+    /// Refuse to run anything, failing with a [`WorkerSpawnBlocked`] error.
+    #[default]
+    Fail,
+    /// Run tasks on the scheduler's own thread instead.
+    SingleThreaded,
+}
+
+impl BlockedWorkerPolicy {
+    pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "fail" => Ok(BlockedWorkerPolicy::Fail),
+            "single-threaded" => Ok(BlockedWorkerPolicy::SingleThreaded),
+            other => {
+                let msg = format!("\"{other}\" isn't a valid value for \"onWorkerBlocked\"");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+/// A worker was blocked by the page's Content Security Policy.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WorkerSpawnBlocked {
+    /// The directive that (probably) blocked the worker.
+    pub(crate) directive: String,
+    /// The script the worker was started with.
+    pub(crate) blocked_url: String,
+    /// The browser's original error message.
+    pub(crate) message: String,
+}
+
+impl WorkerSpawnBlocked {
+    /// Check whether the error thrown by `new Worker(url)` came from a
+    /// Content Security Policy violation.
+    pub(crate) fn detect(error: &JsValue, url: &str) -> Option<Self> {
+        let field = |name: &str| {
+            Reflect::get(error, &JsValue::from_str(name))
+                .ok()
+                .and_then(|value| value.as_string())
+                .unwrap_or_default()
+        };
+        let name = field("name");
+        let message = field("message");
+
+        let is_csp = name == "SecurityError"
+            || message.contains("Content Security Policy")
+            || message.contains("Content-Security-Policy");
+        if !is_csp {
+            return None;
+        }
+
+        let directive = DIRECTIVES
+            .iter()
+            .find(|d| message.contains(*d))
+            .unwrap_or(&DIRECTIVES[0]);
+
+        Some(WorkerSpawnBlocked {
+            directive: directive.to_string(),
+            blocked_url: url.to_string(),
+            message,
+        })
+    }
+
+    /// Suggestions for getting workers running again.
+    pub(crate) fn hints(&self) -> Vec<String> {
+        let mut hints = Vec::new();
+
+        if self.blocked_url.starts_with("blob:") {
+            hints.push(
+                "Add blob: to the page's worker-src directive (e.g. \"worker-src 'self' blob:\")"
+                    .to_string(),
+            );
+            hints.push(
+                "Or serve the worker script from an origin the policy allows and pass its URL to setWorkerScriptUrl()"
+                    .to_string(),
+            );
+        } else {
+            hints.push(format!(
+                "Allow \"{}\" in the page's worker-src directive",
+                self.blocked_url
+            ));
+        }
+
+        if self.directive != "worker-src" {
+            hints.push(format!(
+                "The page has no worker-src directive, so the browser used {}. Adding worker-src only affects workers.",
+                self.directive
+            ));
+        }
+
+        hints.push(
+            "Set RuntimeOptions.onWorkerBlocked to \"single-threaded\" to run on the current thread instead, with reduced functionality"
+                .to_string(),
+        );
+
+        hints
+    }
+
+    /// A JavaScript `Error` named `WorkerSpawnBlocked`.
+    pub(crate) fn to_js(&self) -> JsValue {
+        let error = js_sys::Error::new(&self.to_string());
+        error.set_name("WorkerSpawnBlocked");
+
+        let hints: Array = self.hints().into_iter().map(JsValue::from).collect();
+        let _ = Reflect::set(&error, &"directive".into(), &self.directive.as_str().into());
+        let _ = Reflect::set(
+            &error,
+            &"blockedUrl".into(),
+            &self.blocked_url.as_str().into(),
+        );
+        let _ = Reflect::set(&error, &"hints".into(), &hints);
+
+        error.into()
+    }
+}
+
+impl Display for WorkerSpawnBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unable to start a worker from \"{}\" because the page's Content Security Policy ({}) blocks it",
+            self.blocked_url, self.directive,
+        )
+    }
+}
+
+impl std::error::Error for WorkerSpawnBlocked {}
+
+#[wasm_bindgen(typescript_custom_section)]
+const WORKER_SPAWN_BLOCKED_TYPE_DEFINITION: &'static str = r#"
+/**
+ * Thrown when the page's Content Security Policy won't let the thread pool
+ * start workers.
+ */
+export type WorkerSpawnBlocked = Error & {
+    name: "WorkerSpawnBlocked";
+    /** The directive that blocked the worker (usually `"worker-src"`). */
+    directive: string;
+    /** The script the worker was started from. */
+    blockedUrl: string;
+    /** Suggestions for changing the policy so workers are allowed. */
+    hints: string[];
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn csp_violations_become_typed_errors() {
+        let error = js_sys::Error::new(
+            "Refused to create a worker from 'blob:https://example.com/1234' because it violates the following Content Security Policy directive: \"script-src 'self'\"",
+        );
+        error.set_name("SecurityError");
+        assert!(WorkerSpawnBlocked::detect(&js_sys::Error::new("oops").into(), "a.js").is_none());
+
+        let blocked =
+            WorkerSpawnBlocked::detect(&error.into(), "blob:https://example.com/1234").unwrap();
+
+        assert_eq!(blocked.directive, "script-src");
+        assert!(blocked.hints()[0].contains("blob:"));
+        let js: js_sys::Error = blocked.to_js().unchecked_into();
+        assert_eq!(String::from(js.name()), "WorkerSpawnBlocked");
+    }
+}
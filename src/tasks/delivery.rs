@@ -0,0 +1,90 @@
+//! Handling `postMessage()` failures when handing a task to a worker.
+//!
+//! Sending a message can fail for reasons that have nothing to do with the
+//! task, like a worker that is still starting up or was just terminated, so
+//! the scheduler retries on a different worker a couple of times before
+//! giving up.
+
+use std::fmt::{self, Display};
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+
+/// How many workers we'll try to send a task to before giving up.
+pub(crate) const MAX_ATTEMPTS: usize = 3;
+
+/// Would sending the message to another worker help?
+///
+/// A `DataCloneError` means the message itself can't be sent, so every
+/// other worker would refuse it too.
+pub(crate) fn is_retryable(error: &JsValue) -> bool {
+    let name = Reflect::get(error, &JsValue::from_str("name"))
+        .ok()
+        .and_then(|name| name.as_string());
+    name.as_deref() != Some("DataCloneError")
+}
+
+/// A task couldn't be sent to any worker.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TaskUndelivered {
+    pub(crate) task_id: Option<u64>,
+    /// How many workers we tried.
+    pub(crate) attempts: usize,
+    /// Why the last attempt failed.
+    pub(crate) reason: String,
+}
+
+impl TaskUndelivered {
+    /// A JavaScript `Error` named `TaskDeliveryError`.
+    pub(crate) fn to_js(&self) -> JsValue {
+        let error = js_sys::Error::new(&self.to_string());
+        error.set_name("TaskDeliveryError");
+
+        let _ = Reflect::set(&error, &"attempts".into(), &(self.attempts as f64).into());
+        if let Some(id) = self.task_id {
+            let _ = Reflect::set(&error, &"taskId".into(), &(id as f64).into());
+        }
+
+        error.into()
+    }
+}
+
+impl Display for TaskUndelivered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unable to send the task to a worker after {} attempt(s): {}",
+            self.attempts, self.reason
+        )
+    }
+}
+
+impl std::error::Error for TaskUndelivered {}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn clone_errors_are_not_retried() {
+        let clone_error = js_sys::Error::new("could not be cloned");
+        clone_error.set_name("DataCloneError");
+        assert!(!is_retryable(&clone_error));
+        assert!(is_retryable(&js_sys::Error::new("worker is gone")));
+
+        let undelivered = TaskUndelivered {
+            task_id: Some(7),
+            attempts: MAX_ATTEMPTS,
+            reason: "worker is gone".to_string(),
+        };
+        let js: js_sys::Error = undelivered.to_js().unchecked_into();
+        assert_eq!(js.name(), "TaskDeliveryError");
+        assert_eq!(
+            Reflect::get(&js, &"attempts".into()).unwrap(),
+            JsValue::from(3)
+        );
+    }
+}
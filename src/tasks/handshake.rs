@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::GlobalScope;
+
+/// Information a worker reports about itself as soon as it has been
+/// initialized, so the scheduler can make sure it is compatible.
+///
+/// The worker is always given the same module and memory as the scheduler,
+/// so this only covers things the worker's own environment decides.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Handshake {
+    /// The protocol version the `worker.js` bootstrap script speaks, if it
+    /// told us.
+    pub protocol_version: Option<u32>,
+    /// The worker's `crossOriginIsolated` flag, if it has one.
+    pub cross_origin_isolated: Option<bool>,
+    /// Whether `SharedArrayBuffer` is available to the worker.
+    pub shared_array_buffer: bool,
+}
+
+impl Handshake {
+    /// The handshake for the current thread.
+    pub(crate) fn current(protocol_version: Option<u32>) -> Self {
+        let global = GlobalScope::current();
+
+        Handshake {
+            protocol_version,
+            cross_origin_isolated: global.cross_origin_isolated(),
+            shared_array_buffer: global.lookup(&["SharedArrayBuffer"]).is_some(),
+        }
+    }
+
+    /// Check whether a worker reporting this handshake can work alongside
+    /// `expected`, returning a human-friendly explanation if it can't.
+    pub(crate) fn check(&self, expected: &Handshake) -> Result<(), String> {
+        if self.protocol_version != expected.protocol_version {
+            let actual = match self.protocol_version {
+                Some(v) => format!("protocol version {v}"),
+                None => "an unversioned protocol".to_string(),
+            };
+            let wanted = match expected.protocol_version {
+                Some(v) => format!("version {v}"),
+                None => "no version".to_string(),
+            };
+            return Err(format!(
+                "the worker script speaks {actual}, but {wanted} was expected"
+            ));
+        }
+
+        if expected.cross_origin_isolated == Some(true) && self.cross_origin_isolated != Some(true)
+        {
+            return Err(
+                "the worker isn't cross-origin isolated, so make sure its script is served with the same COOP/COEP headers as the page"
+                    .to_string(),
+            );
+        }
+
+        if expected.shared_array_buffer && !self.shared_array_buffer {
+            return Err("SharedArrayBuffer isn't available to the worker".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    fn handshake(cross_origin_isolated: Option<bool>, shared_array_buffer: bool) -> Handshake {
+        Handshake {
+            protocol_version: Some(1),
+            cross_origin_isolated,
+            shared_array_buffer,
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn isolated_workers_are_accepted() {
+        let expected = handshake(Some(true), true);
+
+        assert_eq!(handshake(Some(true), true).check(&expected), Ok(()));
+        // Workers may be better off than the main thread
+        assert_eq!(
+            handshake(Some(true), true).check(&handshake(None, false)),
+            Ok(())
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn scripts_without_a_protocol_version_are_rejected() {
+        let expected = handshake(Some(true), true);
+        let reported = Handshake {
+            protocol_version: None,
+            ..expected.clone()
+        };
+
+        let err = reported.check(&expected).unwrap_err();
+
+        assert_eq!(
+            err,
+            "the worker script speaks an unversioned protocol, but version 1 was expected"
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn workers_which_arent_isolated_are_rejected() {
+        let expected = handshake(Some(true), true);
+
+        let err = handshake(Some(false), true).check(&expected).unwrap_err();
+        assert!(err.contains("cross-origin isolated"), "{err}");
+
+        let err = handshake(Some(true), false).check(&expected).unwrap_err();
+        assert_eq!(err, "SharedArrayBuffer isn't available to the worker");
+    }
+}
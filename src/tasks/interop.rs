@@ -1,11 +1,14 @@
 use anyhow::Context;
-use js_sys::{BigInt, JsString, Object, Reflect};
-use serde::de::DeserializeOwned;
+use js_sys::{Array, BigInt, JsString, Object, Reflect};
+use serde::{de::DeserializeOwned, Serialize};
 use wasm_bindgen::{JsCast, JsValue};
 
 use crate::utils::Error;
 
 const TYPE: &str = "type";
+/// Where a message keeps the objects that need to be passed as the
+/// `postMessage()` transfer list.
+const TRANSFER: &str = "transfer";
 
 #[derive(Debug, Clone)]
 pub(crate) struct Deserializer {
@@ -64,6 +67,7 @@ impl Deserializer {
 #[derive(Debug)]
 pub(crate) struct Serializer {
     obj: Object,
+    transfer: Array,
     error: Option<Error>,
 }
 
@@ -71,6 +75,7 @@ impl Serializer {
     pub fn new(ty: &str) -> Self {
         let ser = Serializer {
             obj: Object::new(),
+            transfer: Array::new(),
             error: None,
         };
 
@@ -95,6 +100,17 @@ impl Serializer {
         self
     }
 
+    /// Serialize a field using its [`Serialize`] implementation.
+    pub fn serde<T: Serialize>(self, field: &str, value: &T) -> Self {
+        match serde_wasm_bindgen::to_value(value) {
+            Ok(value) => self.set(field, value),
+            Err(e) => Serializer {
+                error: Some(Error::js(e)),
+                ..self
+            },
+        }
+    }
+
     /// Serialize a field by boxing it and passing the address to
     /// `postMessage()`.
     pub fn boxed<T: Send>(self, field: &str, value: T) -> Self {
@@ -102,11 +118,45 @@ impl Serializer {
         self.set(field, BigInt::from(ptr as usize))
     }
 
+    /// Set a field and add it to the transfer list, so ownership is moved to
+    /// the receiver instead of it being copied.
+    pub fn transferred(self, field: &str, value: JsValue) -> Self {
+        self.transfer.push(&value);
+        self.set(field, value)
+    }
+
+    /// Embed a message created by another [`Serializer`], making sure
+    /// anything it transfers gets transferred along with this one.
+    pub fn nested(self, field: &str, msg: JsValue) -> Self {
+        for item in transfer_list(&msg).iter() {
+            self.transfer.push(&item);
+        }
+        self.set(field, msg)
+    }
+
     pub fn finish(self) -> Result<JsValue, Error> {
-        let Serializer { obj, error } = self;
-        match error {
-            None => Ok(obj.into()),
-            Some(e) => Err(e),
+        let Serializer {
+            obj,
+            transfer,
+            error,
+        } = self;
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        if transfer.length() > 0 {
+            Reflect::set(&obj, &JsValue::from_str(TRANSFER), &transfer).map_err(Error::js)?;
         }
+
+        Ok(obj.into())
     }
 }
+
+/// The objects which should be passed as the transfer list when sending a
+/// message created by a [`Serializer`].
+pub(crate) fn transfer_list(msg: &JsValue) -> Array {
+    Reflect::get(msg, &JsValue::from_str(TRANSFER))
+        .ok()
+        .and_then(|value| value.dyn_into().ok())
+        .unwrap_or_else(Array::new)
+}
@@ -28,25 +28,50 @@
 //!   [`Worker`]
 //! - [`WorkerMessage`] - messages a [`Worker`] sends back to the [`Scheduler`]
 //!
+//! [`SchedulerSimulation`] runs the real [`Scheduler`] against fake workers
+//! and a virtual clock, so scheduling behaviour can be tested
+//! deterministically.
+//!
 //! [`Worker`]: thread_pool_worker::ThreadPoolWorker
 //! [`Scheduler`]: scheduler::Scheduler
 
+mod csp;
+mod delivery;
+mod handshake;
 mod interop;
+mod module_reuse;
+mod module_store;
+mod panics;
 mod post_message_payload;
 mod scheduler;
 mod scheduler_message;
+mod simulation;
+mod task_id;
+mod task_scope;
 mod task_wasm;
 mod thread_pool;
 mod thread_pool_worker;
+mod visibility;
+mod watchdog;
 mod worker_handle;
 mod worker_message;
 
 pub(crate) use self::{
+    csp::{BlockedWorkerPolicy, WorkerSpawnBlocked},
+    delivery::TaskUndelivered,
+    handshake::Handshake,
+    module_store::set_budget as set_module_store_budget,
+    panics::{handle_panic, on_panic, PanicGuard, PanicPolicy, PanicReport},
     post_message_payload::{AsyncJob, BlockingJob, Notification, PostMessagePayload},
     scheduler::Scheduler,
     scheduler_message::SchedulerMessage,
-    thread_pool::ThreadPool,
-    worker_handle::WorkerHandle,
+    simulation::SchedulerSimulation,
+    task_id::TaskId,
+    task_scope::{ScopedHttpClient, TaskScope},
+    thread_pool::{default_parallelism, PoolOptions, ThreadPool},
+    visibility::BackgroundPolicy,
+    watchdog::WatchdogOptions,
+    worker_handle::{import_meta_url, time_worker_spawn, PreparedMessage, WorkerHandle},
     worker_message::WorkerMessage,
 };
 
@@ -55,3 +80,5 @@ use std::{future::Future, pin::Pin};
 type AsyncTask = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + 'static>> + Send + 'static>;
 type BlockingTask = Box<dyn FnOnce() + Send + 'static>;
 type BlockingModuleTask = Box<dyn FnOnce(wasmer::Module) + Send + 'static>;
+/// A task which is invoked on every tick of a timer until it returns `false`.
+type PeriodicTask = Box<dyn FnMut() -> bool + Send + 'static>;
@@ -0,0 +1,123 @@
+//! Reusing parsed [`wasmer::Module`]s between spawns.
+//!
+//! Shells spend most of their time doing `vfork()` + `exec()`, which means
+//! the same handful of binaries get spawned over and over. Every spawn sends
+//! the module's bytes along with it, and turning a `WebAssembly.Module` back
+//! into a [`wasmer::Module`] re-parses those bytes to recover its imports and
+//! exports.
+//!
+//! Each spawn is tagged with the hash of its module's bytes, so we can
+//! recognise a module we've already parsed on this thread.
+//!
+//! This is the only part of the `vfork()` + `exec()` path the SDK controls.
+//! `wasmer-wasix` runs a `vfork()` child on the parent's own thread without
+//! copying its memory, and `exec()` starts the new program with a fresh
+//! memory, so neither ever reaches the synchronous copy in
+//! [`to_scheduler_message()`][super::task_wasm::to_scheduler_message] (only a
+//! real `fork()` does). The file descriptor and filesystem tables are also
+//! owned by `wasmer-wasix`, so making them copy-on-write has to happen there.
+
+use std::{cell::RefCell, collections::VecDeque};
+
+use bytes::Bytes;
+use js_sys::WebAssembly;
+use wasmer::Module;
+use wasmer_wasix::runtime::module_cache::ModuleHash;
+
+/// How many modules each thread remembers.
+const CAPACITY: usize = 16;
+
+thread_local! {
+    static MODULES: RefCell<ModuleCache> = RefCell::new(ModuleCache::new(CAPACITY));
+}
+
+/// Identifies a module by the hash of its bytes.
+///
+/// Note: the address of the bytes isn't enough, because an allocation can be
+/// freed and reused by a different module.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct ModuleKey(ModuleHash);
+
+impl ModuleKey {
+    pub(crate) fn of(bytes: &[u8]) -> Self {
+        ModuleKey(ModuleHash::hash(bytes))
+    }
+}
+
+/// Get the [`wasmer::Module`] for a `WebAssembly.Module`, reusing one this
+/// thread parsed earlier if possible.
+pub(crate) fn module(
+    js_module: WebAssembly::Module,
+    module_bytes: Bytes,
+    key: ModuleKey,
+) -> Module {
+    MODULES.with(|m| {
+        m.borrow_mut()
+            .get_or_insert(key, || Module::from((js_module, module_bytes)))
+    })
+}
+
+#[derive(Debug)]
+struct ModuleCache<M = Module> {
+    capacity: usize,
+    /// Entries, from least to most recently used.
+    entries: VecDeque<(ModuleKey, M)>,
+}
+
+impl<M: Clone> ModuleCache<M> {
+    fn new(capacity: usize) -> Self {
+        ModuleCache {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn get_or_insert(&mut self, key: ModuleKey, parse: impl FnOnce() -> M) -> M {
+        if let Some(ix) = self.entries.iter().position(|(k, _)| *k == key) {
+            let entry = self.entries.remove(ix).unwrap();
+            let module = entry.1.clone();
+            self.entries.push_back(entry);
+            return module;
+        }
+
+        let module = parse();
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, module.clone()));
+
+        module
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn modules_are_only_parsed_once() {
+        let mut cache = ModuleCache::new(2);
+        let first = ModuleKey::of(b"first");
+        let second = ModuleKey::of(b"second");
+        let third = ModuleKey::of(b"third");
+        let mut parsed = 0;
+        let mut parse = |len: usize| {
+            parsed += 1;
+            len
+        };
+
+        assert_eq!(cache.get_or_insert(first, || parse(5)), 5);
+        assert_eq!(cache.get_or_insert(first, || parse(5)), 5);
+        assert_eq!(cache.get_or_insert(second, || parse(6)), 6);
+        // A copy of the same bytes is the same module, wherever it lives
+        let copy = b"first".to_vec();
+        assert_eq!(cache.get_or_insert(ModuleKey::of(&copy), || parse(5)), 5);
+        assert_eq!(cache.get_or_insert(third, || parse(5)), 5);
+        // "second" was the least recently used, so it got evicted
+        cache.get_or_insert(second, || parse(6));
+
+        assert_eq!(parsed, 4);
+    }
+}
@@ -0,0 +1,218 @@
+//! A persistent copy of the scheduler's module cache, so the WebAssembly
+//! modules compiled in one session are still warm after a reload.
+//!
+//! Browsers no longer let a `WebAssembly.Module` be stored in IndexedDB, so
+//! we keep each module's bytes instead and compile them again on startup.
+//! That is still much cheaper than downloading and resolving a package from
+//! scratch.
+//!
+//! Each module is saved alongside the time it was saved, and once the store
+//! grows past its budget the oldest modules are deleted first.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use js_sys::{Reflect, Uint8Array, WebAssembly};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer_wasix::runtime::module_cache::ModuleHash;
+use web_sys::{IdbDatabase, IdbTransactionMode};
+
+use crate::utils::Error;
+
+const DB_NAME: &str = "wasmer-modules";
+const STORE_NAME: &str = "modules";
+
+/// The most bytes of WebAssembly the store may hold, where `u64::MAX` means
+/// there is no limit.
+static BUDGET: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Limit the number of bytes used by saved modules. The store is trimmed the
+/// next time a module is saved.
+///
+/// Passing `None` removes the limit.
+pub(crate) fn set_budget(budget: Option<u64>) {
+    BUDGET.store(budget.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// A module's bytes, from either a `{ wasm, savedAt }` record or an entry
+/// saved before records had timestamps.
+fn wasm_of(value: &JsValue) -> Uint8Array {
+    match value.dyn_ref::<Uint8Array>() {
+        Some(bytes) => bytes.clone(),
+        None => Uint8Array::new(&Reflect::get(value, &"wasm".into()).unwrap_or_default()),
+    }
+}
+
+/// When a module was saved, with entries from before records had timestamps
+/// counting as the oldest.
+fn saved_at(value: &JsValue) -> f64 {
+    Reflect::get(value, &"savedAt".into())
+        .ok()
+        .and_then(|value| value.as_f64())
+        .unwrap_or(0.0)
+}
+
+async fn open() -> Result<IdbDatabase, Error> {
+    crate::idb::open(DB_NAME, STORE_NAME).await
+}
+
+/// Compile every module that was saved by a previous session.
+///
+/// Modules which no longer compile (e.g. because the browser dropped support
+/// for a proposal they use) are skipped.
+pub(super) async fn load_all() -> Result<Vec<(ModuleHash, WebAssembly::Module)>, Error> {
+    let db = open().await?;
+    let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readonly)?;
+    let keys = store.get_all_keys().map_err(Error::js)?;
+    let keys: js_sys::Array = crate::idb::complete(&keys).await?.unchecked_into();
+    let values = store.get_all().map_err(Error::js)?;
+    let values: js_sys::Array = crate::idb::complete(&values).await?.unchecked_into();
+
+    let mut modules = Vec::new();
+    let mut restored_bytes = 0;
+    // Note: stop once the budget has been compiled, so a cache that has grown
+    // large over many sessions can't hold up startup for long
+    let max_restored_bytes = crate::fingerprint::tuning().restore_budget;
+
+    // Note: getAll() and getAllKeys() both return items in key order, so
+    // they will always line up.
+    for (key, value) in keys.iter().zip(values.iter()) {
+        let Some(hash) = key.as_string().and_then(|k| ModuleHash::parse_hex(&k).ok()) else {
+            continue;
+        };
+        let bytes = wasm_of(&value);
+        restored_bytes += bytes.byte_length() as usize;
+        if restored_bytes > max_restored_bytes {
+            tracing::debug!(
+                restored = modules.len(),
+                "Stopped restoring modules to avoid delaying startup",
+            );
+            break;
+        }
+
+        match JsFuture::from(WebAssembly::compile(&bytes)).await {
+            Ok(module) => {
+                tracing::trace!(%hash, "Restored a cached module");
+                modules.push((hash, module.unchecked_into()));
+            }
+            Err(e) => {
+                let error = crate::utils::js_error(e);
+                tracing::debug!(%hash, error = &*error, "Unable to recompile a cached module");
+            }
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Save a module's bytes so future sessions can restore it.
+///
+/// Modules bigger than the whole budget are never saved.
+pub(super) async fn save(hash: ModuleHash, wasm: &[u8]) -> Result<(), Error> {
+    let budget = BUDGET.load(Ordering::Relaxed);
+    if wasm.len() as u64 > budget {
+        tracing::debug!(%hash, size = wasm.len(), budget, "Module is too big to save");
+        return Ok(());
+    }
+
+    let record = js_sys::Object::new();
+    Reflect::set(&record, &"wasm".into(), &Uint8Array::from(wasm)).map_err(Error::js)?;
+    Reflect::set(&record, &"savedAt".into(), &js_sys::Date::now().into()).map_err(Error::js)?;
+
+    let db = open().await?;
+    let key = JsValue::from_str(&hash.to_string());
+    let store = crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readwrite)?;
+    let request = store.put_with_key(&record, &key).map_err(Error::js)?;
+    crate::idb::complete(&request).await?;
+
+    if budget != u64::MAX {
+        shrink(&db, &key, budget).await?;
+    }
+
+    Ok(())
+}
+
+/// Delete the oldest modules (other than `keep`) until the store fits in
+/// `budget` bytes.
+async fn shrink(db: &IdbDatabase, keep: &JsValue, budget: u64) -> Result<(), Error> {
+    let store = crate::idb::object_store(db, STORE_NAME, IdbTransactionMode::Readwrite)?;
+    let keys = store.get_all_keys().map_err(Error::js)?;
+    let keys: js_sys::Array = crate::idb::complete(&keys).await?.unchecked_into();
+    let values = store.get_all().map_err(Error::js)?;
+    let values: js_sys::Array = crate::idb::complete(&values).await?.unchecked_into();
+
+    let mut entries: Vec<_> = keys
+        .iter()
+        .zip(values.iter())
+        .map(|(key, value)| (saved_at(&value), key, wasm_of(&value).byte_length() as u64))
+        .collect();
+    let mut size: u64 = entries.iter().map(|(_, _, len)| len).sum();
+    entries.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    for (_, key, len) in entries {
+        if size <= budget {
+            break;
+        }
+        if key == *keep {
+            continue;
+        }
+
+        let hash = key.as_string().unwrap_or_default();
+        tracing::debug!(%hash, size = len, budget, "Deleting a saved module");
+        let request = store.delete(&key).map_err(Error::js)?;
+        crate::idb::complete(&request).await?;
+        size -= len;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn saved_modules_are_restored() {
+        let wasm: &[u8] = include_bytes!("../../tests/envvar.wasm");
+        let hash = ModuleHash::hash(wasm);
+
+        save(hash, wasm).await.unwrap();
+        let restored = load_all().await.unwrap();
+
+        let (_, module) = restored.iter().find(|(h, _)| *h == hash).unwrap();
+        assert!(WebAssembly::Module::exports(module).length() > 0);
+    }
+
+    async fn saved_hashes() -> Vec<ModuleHash> {
+        let db = open().await.unwrap();
+        let store =
+            crate::idb::object_store(&db, STORE_NAME, IdbTransactionMode::Readonly).unwrap();
+        let keys = store.get_all_keys().unwrap();
+        let keys: js_sys::Array = crate::idb::complete(&keys).await.unwrap().unchecked_into();
+
+        keys.iter()
+            .filter_map(|key| ModuleHash::parse_hex(&key.as_string()?).ok())
+            .collect()
+    }
+
+    #[wasm_bindgen_test]
+    async fn the_oldest_modules_are_deleted_when_over_budget() {
+        let first = vec![1_u8; 20];
+        let second = vec![2_u8; 20];
+        let huge = vec![3_u8; 40];
+
+        set_budget(Some(30));
+        save(ModuleHash::hash(&first), &first).await.unwrap();
+        save(ModuleHash::hash(&second), &second).await.unwrap();
+        // Modules bigger than the whole budget are never saved
+        save(ModuleHash::hash(&huge), &huge).await.unwrap();
+        set_budget(None);
+
+        let saved = saved_hashes().await;
+        assert!(!saved.contains(&ModuleHash::hash(&first)));
+        assert!(saved.contains(&ModuleHash::hash(&second)));
+        assert!(!saved.contains(&ModuleHash::hash(&huge)));
+    }
+}
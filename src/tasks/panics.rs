@@ -0,0 +1,139 @@
+use std::{
+    cell::{Cell, RefCell},
+    panic::PanicInfo,
+};
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::{
+    tasks::{TaskId, WorkerMessage},
+    utils::Error,
+};
+
+thread_local! {
+    /// Set when the current thread is one of the thread pool's workers.
+    static IS_POOL_WORKER: Cell<bool> = Cell::new(false);
+    /// Something to call if the current thread panics, typically used to fail
+    /// the instance the thread was running.
+    static ON_PANIC: RefCell<Option<Box<dyn FnOnce(&PanicReport)>>> = RefCell::new(None);
+}
+
+/// What the scheduler should do when one of its workers panics.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum PanicPolicy {
+    /// Stop using the worker that panicked, but keep everything else running.
+    #[default]
+    Quarantine,
+    /// Re-instantiate the SDK inside the worker that panicked and keep using
+    /// it, which is much faster than starting a new worker.
+    Recover,
+    /// Treat the whole thread pool as compromised, terminating every worker
+    /// and refusing to run anything else.
+    Abort,
+}
+
+impl PanicPolicy {
+    pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "quarantine" => Ok(PanicPolicy::Quarantine),
+            "recover" => Ok(PanicPolicy::Recover),
+            "abort" => Ok(PanicPolicy::Abort),
+            other => {
+                let msg = format!("\"{other}\" isn't a valid value for \"onPanic\"");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+/// The details of a Rust panic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PanicReport {
+    pub message: String,
+    /// Where the panic happened, as `file:line:column`.
+    pub location: Option<String>,
+    /// The JavaScript stack at the time of the panic.
+    pub backtrace: Option<String>,
+    /// The task that was running when the thread panicked.
+    #[serde(default)]
+    pub task_id: Option<TaskId>,
+}
+
+impl PanicReport {
+    fn from_panic_info(info: &PanicInfo<'_>) -> Self {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_string());
+
+        let location = info
+            .location()
+            .map(|loc| format!("{}:{}:{}", loc.file(), loc.line(), loc.column()));
+
+        let backtrace = js_sys::Reflect::get(&js_sys::Error::new(""), &JsValue::from_str("stack"))
+            .ok()
+            .and_then(|stack| stack.as_string());
+
+        PanicReport {
+            message,
+            location,
+            backtrace,
+            task_id: TaskId::current(),
+        }
+    }
+}
+
+/// Run `callback` if the current thread panics before the returned guard is
+/// dropped.
+///
+/// Only one callback can be registered per thread, with newer registrations
+/// replacing older ones.
+pub(crate) fn on_panic(callback: impl FnOnce(&PanicReport) + 'static) -> PanicGuard {
+    ON_PANIC.with(|slot| *slot.borrow_mut() = Some(Box::new(callback)));
+    PanicGuard {
+        _not_send: std::marker::PhantomData,
+    }
+}
+
+/// Unregisters an [`on_panic()`] callback when dropped.
+#[derive(Debug)]
+pub(crate) struct PanicGuard {
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        ON_PANIC.with(|slot| {
+            if let Ok(mut slot) = slot.try_borrow_mut() {
+                slot.take();
+            }
+        });
+    }
+}
+
+/// Mark the current thread as belonging to the thread pool, so panics get
+/// reported to the scheduler.
+pub(crate) fn mark_pool_worker() {
+    IS_POOL_WORKER.with(|flag| flag.set(true));
+}
+
+/// The panic hook installed when the module is first loaded.
+pub(crate) fn handle_panic(info: &PanicInfo<'_>) {
+    let report = PanicReport::from_panic_info(info);
+
+    let callback = ON_PANIC.with(|slot| slot.try_borrow_mut().ok().and_then(|mut s| s.take()));
+    if let Some(callback) = callback {
+        callback(&report);
+    }
+
+    // Note: The worker's state can't be trusted after a panic (e.g. locks
+    // may be poisoned or a busy guard will never be dropped), so we ask the
+    // scheduler to stop using it.
+    if IS_POOL_WORKER.with(|flag| flag.get()) {
+        let _ = WorkerMessage::Panicked(report).emit();
+    }
+}
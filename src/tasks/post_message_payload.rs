@@ -4,7 +4,7 @@ use wasm_bindgen::JsValue;
 use wasmer_wasix::runtime::module_cache::ModuleHash;
 
 use crate::tasks::{
-    interop::Serializer, task_wasm::SpawnWasm, AsyncTask, BlockingModuleTask, BlockingTask,
+    interop::Serializer, task_wasm::SpawnWasm, AsyncTask, BlockingModuleTask, BlockingTask, TaskId,
 };
 
 /// A message that will be sent from the scheduler to a worker using
@@ -65,6 +65,7 @@ mod consts {
     pub(crate) const MODULE: &str = "module";
     pub(crate) const MEMORY: &str = "memory";
     pub(crate) const MODULE_HASH: &str = "module-hash";
+    pub(crate) const TASK_ID: &str = "task-id";
 }
 
 impl PostMessagePayload {
@@ -104,6 +105,33 @@ impl PostMessagePayload {
         }
     }
 
+    /// Like [`PostMessagePayload::into_js()`], but also recording the id of
+    /// the task being sent.
+    pub(crate) fn into_js_for_task(
+        self,
+        task_id: Option<TaskId>,
+    ) -> Result<JsValue, crate::utils::Error> {
+        let js = self.into_js()?;
+        if let Some(id) = task_id {
+            js_sys::Reflect::set(
+                &js,
+                &JsValue::from_str(consts::TASK_ID),
+                &JsValue::from(id.get() as f64),
+            )
+            .map_err(crate::utils::Error::js)?;
+        }
+        Ok(js)
+    }
+
+    /// The id recorded by [`PostMessagePayload::into_js_for_task()`], if
+    /// there is one.
+    pub(crate) fn task_id(value: &JsValue) -> Option<TaskId> {
+        js_sys::Reflect::get(value, &JsValue::from_str(consts::TASK_ID))
+            .ok()?
+            .as_f64()
+            .map(|id| TaskId::from_raw(id as u64))
+    }
+
     /// Try to convert a [`PostMessagePayload`] back from a [`JsValue`].
     ///
     /// # Safety
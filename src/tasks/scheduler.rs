@@ -1,10 +1,14 @@
 use std::{
+    cell::Cell,
     collections::{BTreeMap, VecDeque},
     fmt::Debug,
+    num::NonZeroUsize,
+    rc::Rc,
     sync::atomic::{AtomicU32, Ordering},
 };
 
-use anyhow::{Context, Error};
+use anyhow::Error;
+use instant::Instant;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::mpsc::{self};
 use tracing::Instrument;
@@ -12,9 +16,19 @@ use wasm_bindgen::{JsCast, JsValue};
 use wasmer::AsJs;
 use wasmer_wasix::runtime::module_cache::ModuleHash;
 
-use crate::tasks::{
-    AsyncJob, BlockingJob, Notification, PostMessagePayload, SchedulerMessage, WorkerHandle,
-    WorkerMessage,
+use crate::{
+    events::{EventChannel, RuntimeEvent},
+    tasks::{
+        module_reuse::ModuleKey,
+        simulation::World,
+        thread_pool_worker::ThreadPoolWorker,
+        visibility::{BackgroundPolicy, Visibility},
+        watchdog::{Watchdog, WatchdogOptions, CHECK_INTERVAL},
+        worker_handle::WORKER_PROTOCOL_VERSION,
+        AsyncJob, BlockedWorkerPolicy, BlockingJob, Handshake, Notification, PanicPolicy,
+        PanicReport, PostMessagePayload, PreparedMessage, SchedulerMessage, TaskId,
+        TaskUndelivered, WorkerHandle, WorkerMessage, WorkerSpawnBlocked,
+    },
 };
 
 /// A handle for interacting with the threadpool's scheduler.
@@ -22,12 +36,19 @@ use crate::tasks::{
 pub(crate) struct Scheduler {
     scheduler_thread_id: u32,
     channel: UnboundedSender<SchedulerMessage>,
+    events: EventChannel,
 }
 
 impl Scheduler {
     /// Spin up a scheduler on the current thread and get a channel that can be
     /// used to communicate with it.
-    pub(crate) fn spawn() -> Scheduler {
+    pub(crate) fn spawn(
+        panic_policy: PanicPolicy,
+        background_policy: BackgroundPolicy,
+        watchdog: WatchdogOptions,
+        blocked_policy: BlockedWorkerPolicy,
+        capacity: NonZeroUsize,
+    ) -> Scheduler {
         let (sender, mut receiver) = mpsc::unbounded_channel();
 
         let thread_id = wasmer::current_thread_id();
@@ -35,13 +56,30 @@ impl Scheduler {
         let sender = unsafe { Scheduler::new(sender, thread_id) };
 
         let mut scheduler = SchedulerState::new(sender.clone());
+        scheduler.panic_policy = panic_policy;
+        scheduler.capacity = capacity;
+        scheduler.visibility = Rc::new(Visibility::new(background_policy));
+        scheduler.visibility.watch(sender.clone());
+        scheduler.watchdog = Watchdog::new(watchdog);
+        scheduler.blocked_policy = blocked_policy;
+
+        if scheduler.watchdog.is_enabled() {
+            let mailbox = sender.clone();
+            let task = Box::new(move || mailbox.send(SchedulerMessage::CheckForStall).is_ok());
+            let _ = sender.send(SchedulerMessage::SpawnPeriodic {
+                interval: CHECK_INTERVAL,
+                task,
+            });
+        }
 
         tracing::debug!(thread_id, "Spinning up the scheduler");
         wasm_bindgen_futures::spawn_local(
             async move {
-                while let Some(msg) = receiver.recv().await {
-                    tracing::trace!(?msg, "Executing a message");
+                // Note: Anything sent in the meantime waits in the channel,
+                // so even the first spawn benefits from the restored modules
+                scheduler.restore_cached_modules().await;
 
+                while let Some(msg) = receiver.recv().await {
                     if let Err(e) = scheduler.execute(msg) {
                         tracing::error!(error = &*e, "An error occurred while handling a message");
                     }
@@ -70,9 +108,15 @@ impl Scheduler {
         Scheduler {
             channel,
             scheduler_thread_id,
+            events: EventChannel::new(),
         }
     }
 
+    /// The channel events from this scheduler's runtime are dispatched on.
+    pub(crate) fn events(&self) -> EventChannel {
+        self.events
+    }
+
     pub fn send(&self, msg: SchedulerMessage) -> Result<(), Error> {
         if wasmer::current_thread_id() == self.scheduler_thread_id {
             tracing::debug!(
@@ -103,17 +147,82 @@ impl Scheduler {
 unsafe impl Send for Scheduler {}
 unsafe impl Sync for Scheduler {}
 
+/// Wait for `duration` using the current thread's `setTimeout()`.
+pub(super) async fn sleep(duration: std::time::Duration) {
+    let ms = i32::try_from(duration.as_millis()).unwrap_or(i32::MAX);
+    let _ =
+        wasm_bindgen_futures::JsFuture::from(crate::utils::GlobalScope::current().sleep(ms)).await;
+}
+
 /// The state for the actor in charge of the threadpool.
 #[derive(Debug)]
-struct SchedulerState {
+pub(super) struct SchedulerState {
     /// Workers that are able to receive work.
     idle: VecDeque<WorkerHandle>,
     /// Workers that are currently blocked on synchronous operations and can't
     /// receive work at this time.
     busy: VecDeque<WorkerHandle>,
+    /// Workers started to replace ones that panicked, which haven't finished
+    /// starting up yet, along with the worker they replace and when they
+    /// were started.
+    recovering: BTreeMap<u32, (WorkerHandle, u32, Instant)>,
+    /// How many of the async tasks sent to each worker haven't finished yet.
+    ///
+    /// Async tasks run alongside everything else on a worker's event loop,
+    /// so an idle worker may still be in the middle of some, and terminating
+    /// it would silently drop them.
+    async_tasks: BTreeMap<u32, usize>,
+    /// Workers we are done with that still have async tasks running. They
+    /// aren't given any more work, and are terminated once the last of their
+    /// tasks finishes.
+    draining: BTreeMap<u32, WorkerHandle>,
+    /// Tasks sent to workers which haven't answered the handshake yet, and
+    /// whether each one will block.
+    ///
+    /// They are held back until the worker turns out to be compatible, so a
+    /// worker that gets rejected can't take them down with it.
+    unverified: BTreeMap<u32, Vec<(PreparedMessage, bool)>>,
     /// A channel that can be used to send messages to this scheduler.
     mailbox: Scheduler,
     cached_modules: BTreeMap<ModuleHash, js_sys::WebAssembly::Module>,
+    /// The module each worker was last asked to spawn, so repeatedly running
+    /// the same program can go to a worker that has already parsed it.
+    last_spawned: BTreeMap<u32, ModuleKey>,
+    /// Set once a worker fails its handshake, at which point we stop starting
+    /// new workers because they would be incompatible too.
+    rejected: Option<String>,
+    /// What to do when a worker panics.
+    panic_policy: PanicPolicy,
+    /// The number of workers we expect to run in parallel.
+    ///
+    /// This is a soft limit. Async work is always shared between the existing
+    /// idle workers, but blocking work needs a dedicated worker, so we'll go
+    /// over capacity rather than risking a deadlock.
+    capacity: NonZeroUsize,
+    /// How many workers we may keep around when the page's workers are
+    /// shared with other runtimes.
+    ///
+    /// Like `capacity`, this is a soft limit which never stops a worker from
+    /// being started (blocking work always needs a worker of its own), so
+    /// busy workers can take us over our share for a while. Workers beyond
+    /// the share are retired as they go idle.
+    worker_share: Option<NonZeroUsize>,
+    /// Set once the pool has been shut down, so timers and periodic tasks
+    /// know to stop.
+    shut_down: Rc<Cell<bool>>,
+    /// Whether the page is visible, which decides how often periodic tasks
+    /// run.
+    visibility: Rc<Visibility>,
+    /// Watches for every worker being blocked with work queued behind them.
+    watchdog: Watchdog,
+    /// What to do when the page's Content Security Policy blocks workers.
+    blocked_policy: BlockedWorkerPolicy,
+    /// Set once we've fallen back to running tasks on the scheduler's own
+    /// thread.
+    single_threaded: bool,
+    /// Where workers, timers and the time come from when the scheduler is
+    /// being simulated, instead of the browser.
+    world: Option<Rc<World>>,
 }
 
 impl SchedulerState {
@@ -121,20 +230,146 @@ impl SchedulerState {
         SchedulerState {
             idle: VecDeque::new(),
             busy: VecDeque::new(),
+            recovering: BTreeMap::new(),
+            async_tasks: BTreeMap::new(),
+            draining: BTreeMap::new(),
+            unverified: BTreeMap::new(),
             mailbox,
             cached_modules: BTreeMap::new(),
+            last_spawned: BTreeMap::new(),
+            rejected: None,
+            panic_policy: PanicPolicy::default(),
+            capacity: NonZeroUsize::MAX,
+            worker_share: None,
+            shut_down: Rc::default(),
+            visibility: Rc::default(),
+            watchdog: Watchdog::default(),
+            blocked_policy: BlockedWorkerPolicy::default(),
+            single_threaded: false,
+            world: None,
         }
     }
 
-    fn execute(&mut self, message: SchedulerMessage) -> Result<(), Error> {
+    /// Create a scheduler whose workers, timers and clock all come from
+    /// `world`, returning it along with the receiving end of its mailbox.
+    ///
+    /// Nothing reads the mailbox, so the caller is in charge of passing its
+    /// messages to [`SchedulerState::execute()`].
+    pub(super) fn simulated(
+        world: Rc<World>,
+        panic_policy: PanicPolicy,
+        watchdog: WatchdogOptions,
+        capacity: NonZeroUsize,
+    ) -> (Self, mpsc::UnboundedReceiver<SchedulerMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // Safety: the simulation is driven from the current thread
+        let mailbox = unsafe { Scheduler::new(sender, wasmer::current_thread_id()) };
+
+        let mut scheduler = SchedulerState::new(mailbox.clone());
+        scheduler.panic_policy = panic_policy;
+        scheduler.capacity = capacity;
+        scheduler.watchdog = Watchdog::new(watchdog);
+        scheduler.world = Some(world);
+
+        if scheduler.watchdog.is_enabled() {
+            let task = Box::new(move || mailbox.send(SchedulerMessage::CheckForStall).is_ok());
+            let _ = scheduler.execute(SchedulerMessage::SpawnPeriodic {
+                interval: CHECK_INTERVAL,
+                task,
+            });
+        }
+
+        (scheduler, receiver)
+    }
+
+    pub(super) fn mailbox(&self) -> &Scheduler {
+        &self.mailbox
+    }
+
+    pub(super) fn idle_workers(&self) -> Vec<u32> {
+        self.idle.iter().map(|w| w.id()).collect()
+    }
+
+    pub(super) fn busy_workers(&self) -> Vec<u32> {
+        self.busy.iter().map(|w| w.id()).collect()
+    }
+
+    pub(super) fn recovering_workers(&self) -> Vec<u32> {
+        self.recovering.keys().copied().collect()
+    }
+
+    /// The current time, according to the simulation if there is one.
+    fn now(&self) -> Instant {
+        match &self.world {
+            Some(world) => world.now(),
+            None => Instant::now(),
+        }
+    }
+
+    pub(super) fn execute(&mut self, message: SchedulerMessage) -> Result<(), Error> {
+        // Note: The worker logs under the same task.id, so a slow task can be
+        // followed from here to wherever it ran
+        let task_id = message.spawns_task().then(TaskId::next);
+        tracing::trace!(
+            task.id = task_id.map(TaskId::get),
+            msg = ?message,
+            "Executing a message",
+        );
+
         match message {
             SchedulerMessage::SpawnAsync(task) => {
-                self.post_message(PostMessagePayload::Async(AsyncJob::Thunk(task)))
+                self.post_message(PostMessagePayload::Async(AsyncJob::Thunk(task)), task_id)
             }
-            SchedulerMessage::SpawnBlocking(task) => {
-                self.post_message(PostMessagePayload::Blocking(BlockingJob::Thunk(task)))
+            SchedulerMessage::SpawnBlocking(task) => self.post_message(
+                PostMessagePayload::Blocking(BlockingJob::Thunk(task)),
+                task_id,
+            ),
+            SchedulerMessage::SpawnAfter { delay, task } => {
+                if let Some(world) = &self.world {
+                    world.spawn_after(delay, task, Rc::clone(&self.shut_down));
+                    return Ok(());
+                }
+
+                let mailbox = self.mailbox.clone();
+                let shut_down = Rc::clone(&self.shut_down);
+                wasm_bindgen_futures::spawn_local(async move {
+                    sleep(delay).await;
+                    if shut_down.get() {
+                        return;
+                    }
+                    if let Err(e) = mailbox.send(SchedulerMessage::SpawnAsync(task)) {
+                        tracing::warn!(error = &*e, "Unable to run a delayed task");
+                    }
+                });
+                Ok(())
+            }
+            SchedulerMessage::SpawnPeriodic { interval, mut task } => {
+                if let Some(world) = &self.world {
+                    world.spawn_periodic(interval, task, Rc::clone(&self.shut_down));
+                    return Ok(());
+                }
+
+                let shut_down = Rc::clone(&self.shut_down);
+                let visibility = Rc::clone(&self.visibility);
+                wasm_bindgen_futures::spawn_local(async move {
+                    loop {
+                        visibility.next_tick(interval).await;
+                        if shut_down.get() || !task() {
+                            break;
+                        }
+                    }
+                });
+                Ok(())
+            }
+            SchedulerMessage::RunOnScheduler(task) => {
+                if !self.shut_down.get() {
+                    task();
+                }
+                Ok(())
             }
             SchedulerMessage::CacheModule { hash, module } => {
+                persist_module(hash, &module);
+
                 let module: js_sys::WebAssembly::Module = JsValue::from(module).unchecked_into();
                 self.cached_modules.insert(hash, module.clone());
 
@@ -149,12 +384,46 @@ impl SchedulerState {
 
                 Ok(())
             }
-            SchedulerMessage::SpawnWithModule { module, task } => {
-                self.post_message(PostMessagePayload::Blocking(BlockingJob::SpawnWithModule {
+            SchedulerMessage::Shutdown => {
+                self.shut_down();
+                Ok(())
+            }
+            SchedulerMessage::CheckForStall => self.check_for_stall(),
+            SchedulerMessage::ShedIdleWorkers => {
+                self.shed_idle_workers();
+                Ok(())
+            }
+            SchedulerMessage::TerminateTask { task_id } => {
+                self.terminate_task(task_id);
+                Ok(())
+            }
+            SchedulerMessage::SetWorkerShare { workers } => {
+                self.worker_share = workers.and_then(|n| NonZeroUsize::new(n as usize));
+                tracing::debug!(
+                    share = workers,
+                    "Updated this runtime's share of the workers"
+                );
+                self.trim_to_share();
+                Ok(())
+            }
+            SchedulerMessage::VisibilityChanged { hidden } => {
+                if !self.visibility.set_hidden(hidden) {
+                    return Ok(());
+                }
+
+                let event = RuntimeEvent::VisibilityChanged { hidden };
+                self.mailbox
+                    .events()
+                    .dispatch(&event)
+                    .map_err(|e| e.into_anyhow())
+            }
+            SchedulerMessage::SpawnWithModule { module, task } => self.post_message(
+                PostMessagePayload::Blocking(BlockingJob::SpawnWithModule {
                     module: JsValue::from(module).unchecked_into(),
                     task,
-                }))
-            }
+                }),
+                task_id,
+            ),
             SchedulerMessage::SpawnWithModuleAndMemory {
                 module,
                 memory,
@@ -163,17 +432,22 @@ impl SchedulerState {
                 let temp_store = wasmer::Store::default();
                 let memory = memory.map(|m| m.as_jsvalue(&temp_store).dyn_into().unwrap());
                 let module = JsValue::from(module).dyn_into().unwrap();
+                let key = spawn_wasm.module_key();
 
-                self.post_message(PostMessagePayload::Blocking(
-                    BlockingJob::SpawnWithModuleAndMemory {
+                self.post_message_preferring(
+                    PostMessagePayload::Blocking(BlockingJob::SpawnWithModuleAndMemory {
                         module,
                         memory,
                         spawn_wasm,
-                    },
-                ))
+                    }),
+                    Some(key),
+                    task_id,
+                )
             }
             SchedulerMessage::WorkerBusy { worker_id } => {
                 move_worker(worker_id, &mut self.idle, &mut self.busy);
+                let now = self.now();
+                self.watchdog.worker_busy(worker_id, now);
                 tracing::trace!(
                     worker.id=worker_id,
                     idle_workers=?self.idle.iter().map(|w| w.id()).collect::<Vec<_>>(),
@@ -184,35 +458,666 @@ impl SchedulerState {
             }
             SchedulerMessage::WorkerIdle { worker_id } => {
                 move_worker(worker_id, &mut self.busy, &mut self.idle);
+                self.watchdog.worker_idle(worker_id);
                 tracing::trace!(
                     worker.id=worker_id,
                     idle_workers=?self.idle.iter().map(|w| w.id()).collect::<Vec<_>>(),
                     busy_workers=?self.busy.iter().map(|w| w.id()).collect::<Vec<_>>(),
                     "Worker marked as idle",
                 );
+                self.trim_to_share();
+                Ok(())
+            }
+            SchedulerMessage::WorkerAsyncTaskDone { worker_id } => {
+                self.async_task_done(worker_id);
+                Ok(())
+            }
+            SchedulerMessage::WorkerHandshake {
+                worker_id,
+                handshake,
+            } => {
+                let expected = Handshake::current(Some(WORKER_PROTOCOL_VERSION));
+
+                match handshake.check(&expected) {
+                    Ok(()) => {
+                        tracing::trace!(worker.id = worker_id, ?handshake, "Worker is compatible");
+                        self.verified(worker_id);
+                        self.recovered(worker_id)
+                    }
+                    Err(reason) => self.reject_worker(worker_id, reason),
+                }
+            }
+            SchedulerMessage::WorkerPanicked { worker_id, report } => {
+                self.quarantine_worker(worker_id, report)
+            }
+            SchedulerMessage::Emit(event) => self
+                .mailbox
+                .events()
+                .dispatch(&event)
+                .map_err(|e| e.into_anyhow()),
+            SchedulerMessage::AttachMemory { id, memory } => {
+                crate::memory::attached(id, memory);
                 Ok(())
             }
             SchedulerMessage::Markers { uninhabited, .. } => match uninhabited {},
         }
     }
 
+    /// Load the modules persisted by previous sessions into
+    /// [`SchedulerState::cached_modules`].
+    async fn restore_cached_modules(&mut self) {
+        match super::module_store::load_all().await {
+            Ok(modules) => {
+                tracing::debug!(count = modules.len(), "Restored the module cache");
+                for (hash, module) in modules {
+                    self.cached_modules.entry(hash).or_insert(module);
+                }
+            }
+            Err(e) => {
+                tracing::debug!(
+                    error = &*e.into_anyhow(),
+                    "Unable to restore the module cache"
+                )
+            }
+        }
+    }
+
+    /// Terminate a worker that isn't compatible with this scheduler and let
+    /// the runtime's listeners know.
+    fn reject_worker(&mut self, worker_id: u32, reason: String) -> Result<(), Error> {
+        tracing::error!(
+            worker.id = worker_id,
+            %reason,
+            "Rejecting an incompatible worker. Make sure it is served with the same headers as the page.",
+        );
+
+        // Dropping the handle will terminate the worker
+        self.idle.retain(|w| w.id() != worker_id);
+        self.busy.retain(|w| w.id() != worker_id);
+        self.recovering.remove(&worker_id);
+        self.draining.remove(&worker_id);
+        self.async_tasks.remove(&worker_id);
+        self.last_spawned.remove(&worker_id);
+        self.watchdog.worker_idle(worker_id);
+        self.rejected = Some(reason.clone());
+
+        // The worker never saw its tasks, so they can go to a worker which
+        // was already running before this one was rejected
+        for (message, would_block) in self.unverified.remove(&worker_id).unwrap_or_default() {
+            self.requeue(message, would_block);
+        }
+
+        let event = RuntimeEvent::WorkerRejected { worker_id, reason };
+        self.mailbox
+            .events()
+            .dispatch(&event)
+            .map_err(|e| e.into_anyhow())
+    }
+
+    /// Send the tasks held back for a worker now that it is known to be
+    /// compatible.
+    fn verified(&mut self, worker_id: u32) {
+        let Some(held) = self.unverified.remove(&worker_id) else {
+            return;
+        };
+        let worker = self
+            .idle
+            .iter()
+            .chain(&self.busy)
+            .chain(self.recovering.values().map(|(w, _, _)| w))
+            .chain(self.draining.values())
+            .find(|w| w.id() == worker_id);
+        let Some(worker) = worker else {
+            // Note: the worker was terminated before it was ready
+            for (message, would_block) in held {
+                self.requeue(message, would_block);
+            }
+            return;
+        };
+
+        let failed: Vec<_> = held
+            .into_iter()
+            .filter_map(|(message, _)| match worker.post(&message) {
+                Ok(()) => None,
+                Err(error) => Some((message, error)),
+            })
+            .collect();
+        for (message, error) in failed {
+            let error = crate::utils::js_error(error)
+                .context(format!("Unable to send a message to worker {worker_id}"));
+            self.undelivered(message, 1, error);
+        }
+    }
+
+    /// Drop the tasks held back for workers which are being terminated, so
+    /// anything waiting on them finds out.
+    fn drop_held(&mut self) {
+        for (message, _) in std::mem::take(&mut self.unverified).into_values().flatten() {
+            if let Err(e) = message.into_payload() {
+                tracing::warn!(error = &*e, "Unable to drop a held task");
+            }
+        }
+    }
+
+    /// Give a task that was held back for a rejected worker to an idle worker
+    /// which has already passed its handshake.
+    fn requeue(&mut self, message: PreparedMessage, would_block: bool) {
+        let unverified = &self.unverified;
+        let Some(ix) = self
+            .idle
+            .iter()
+            .position(|w| !unverified.contains_key(&w.id()))
+        else {
+            let error = anyhow::anyhow!("No compatible workers are available to run the task");
+            self.undelivered(message, 1, error);
+            return;
+        };
+        let worker = self.idle.remove(ix).expect("the index is in bounds");
+
+        match worker.post(&message) {
+            Ok(()) => {
+                tracing::debug!(
+                    worker.id = worker.id(),
+                    task.id = message.task_id().map(TaskId::get),
+                    "Re-queued a task from a rejected worker",
+                );
+                self.sent(worker, would_block, message.task_id());
+            }
+            Err(error) => {
+                let error = crate::utils::js_error(error).context(format!(
+                    "Unable to send a message to worker {}",
+                    worker.id()
+                ));
+                self.idle.push_back(worker);
+                self.undelivered(message, 1, error);
+            }
+        }
+    }
+
+    /// Terminate every worker, forget any cached modules, and reject all
+    /// future work.
+    fn shut_down(&mut self) {
+        tracing::debug!(
+            workers = self.idle.len() + self.busy.len(),
+            "Shutting down the thread pool",
+        );
+
+        // Dropping the handles will terminate the workers
+        self.idle.clear();
+        self.busy.clear();
+        self.recovering.clear();
+        self.draining.clear();
+        self.drop_held();
+        self.async_tasks.clear();
+        self.cached_modules.clear();
+        self.rejected = Some("the runtime was disposed".to_string());
+        self.shut_down.set(true);
+        self.visibility.stop_watching();
+    }
+
+    /// Terminate every idle worker, which also frees the memory of whatever
+    /// modules they had loaded. New workers are started as work comes in.
+    ///
+    /// Workers still running async tasks are terminated once those finish.
+    fn shed_idle_workers(&mut self) {
+        if self.idle.is_empty() {
+            return;
+        }
+
+        tracing::info!(workers = self.idle.len(), "Terminating idle workers");
+        for worker in std::mem::take(&mut self.idle) {
+            self.retire(worker);
+        }
+    }
+
+    /// Terminate the worker running a blocking task, if it is still going.
+    ///
+    /// Unlike retiring a worker, this doesn't wait for its async tasks, which
+    /// go down with it.
+    fn terminate_task(&mut self, task_id: TaskId) {
+        let Some(worker_id) = self.watchdog.worker_running(task_id) else {
+            tracing::debug!(task.id = task_id.get(), "The task has already finished");
+            return;
+        };
+        let Some(worker) = take_worker(worker_id, &mut self.busy) else {
+            return;
+        };
+
+        tracing::info!(
+            worker.id = worker_id,
+            task.id = task_id.get(),
+            "Terminating a worker that is stuck running a task",
+        );
+        self.watchdog.worker_idle(worker_id);
+        self.last_spawned.remove(&worker_id);
+        self.async_tasks.remove(&worker_id);
+        // Dropping the handle will terminate the worker
+        drop(worker);
+    }
+
+    /// Retire idle workers until we are back within our share of the page's
+    /// workers, if we have one.
+    fn trim_to_share(&mut self) {
+        let Some(share) = self.worker_share else {
+            return;
+        };
+
+        while self.idle.len() + self.busy.len() > share.get() {
+            // Note: The front of the queue is the next to be reused, so give
+            // up workers from the back, preferring ones with nothing running
+            let ix = self
+                .idle
+                .iter()
+                .rposition(|w| !self.async_tasks.contains_key(&w.id()))
+                .or_else(|| self.idle.len().checked_sub(1));
+            let Some(worker) = ix.and_then(|ix| self.idle.remove(ix)) else {
+                break;
+            };
+            tracing::debug!(
+                worker.id = worker.id(),
+                share = share.get(),
+                "Retiring a worker to stay within our share"
+            );
+            self.retire(worker);
+        }
+    }
+
+    /// Stop using a worker, terminating it straight away if it has nothing
+    /// left to run, or once its async tasks have finished otherwise.
+    fn retire(&mut self, worker: WorkerHandle) {
+        let worker_id = worker.id();
+        self.last_spawned.remove(&worker_id);
+
+        if let Some(&running) = self.async_tasks.get(&worker_id) {
+            tracing::debug!(
+                worker.id = worker_id,
+                running,
+                "Waiting for the worker's async tasks before terminating it"
+            );
+            self.draining.insert(worker_id, worker);
+        }
+        // Otherwise, dropping the handle terminates the worker
+    }
+
+    fn async_task_done(&mut self, worker_id: u32) {
+        let Some(running) = self.async_tasks.get_mut(&worker_id) else {
+            return;
+        };
+
+        *running -= 1;
+        if *running == 0 {
+            self.async_tasks.remove(&worker_id);
+            if self.draining.remove(&worker_id).is_some() {
+                tracing::debug!(
+                    worker.id = worker_id,
+                    "Terminating a worker now its async tasks are done"
+                );
+            }
+        }
+    }
+
+    /// Let the runtime's listeners know if the pool looks deadlocked, starting
+    /// extra workers if we were asked to.
+    fn check_for_stall(&mut self) -> Result<(), Error> {
+        let now = self.now();
+
+        // Retire any workers from a previous boost that are still idle
+        for worker_id in self.watchdog.expired_boost(now) {
+            if let Some(worker) = take_worker(worker_id, &mut self.idle) {
+                self.retire(worker);
+            }
+        }
+
+        let idle: Vec<u32> = self.idle.iter().map(|w| w.id()).collect();
+        let busy: Vec<u32> = self.busy.iter().map(|w| w.id()).collect();
+        let Some(stall) = self.watchdog.check(&idle, &busy, now) else {
+            return Ok(());
+        };
+
+        tracing::warn!(
+            stalled_for = ?stall.stalled_for,
+            workers = ?stall.workers,
+            boost = self.watchdog.boost(),
+            "Every worker is blocked with work queued behind it. The thread pool may have deadlocked.",
+        );
+
+        // New work will land on these fresh workers instead of queueing up
+        // behind the blocked ones.
+        let mut boosted = Vec::new();
+        if self.rejected.is_none() && !self.single_threaded {
+            for _ in 0..self.watchdog.boost() {
+                let worker = self.start_worker()?;
+                boosted.push(worker.id());
+                self.idle.push_back(worker);
+            }
+        }
+        let boosted_by = boosted.len();
+        if !boosted.is_empty() {
+            self.watchdog.boosted(boosted, now);
+        }
+
+        let event = RuntimeEvent::DeadlockSuspected {
+            stalled_for_ms: stall.stalled_for.as_millis() as u64,
+            workers: stall.workers,
+            boosted_by,
+        };
+        self.mailbox
+            .events()
+            .dispatch(&event)
+            .map_err(|e| e.into_anyhow())
+    }
+
+    /// Stop using a worker that panicked, try to recover it, or tear down the
+    /// whole pool, depending on the [`PanicPolicy`].
+    ///
+    /// A worker which panics again while recovering is always quarantined.
+    fn quarantine_worker(&mut self, worker_id: u32, report: PanicReport) -> Result<(), Error> {
+        let aborted = self.panic_policy == PanicPolicy::Abort;
+        let failed_recovery = self.recovering.remove(&worker_id).is_some();
+        tracing::error!(
+            worker.id = worker_id,
+            message = %report.message,
+            location = report.location.as_deref(),
+            aborted,
+            failed_recovery,
+            "A worker panicked",
+        );
+
+        // Note: whatever async tasks it was running went down with it
+        self.async_tasks.remove(&worker_id);
+        self.draining.remove(&worker_id);
+
+        // Dropping the handle will terminate the worker
+        if aborted {
+            self.idle.clear();
+            self.busy.clear();
+            self.recovering.clear();
+            self.draining.clear();
+            self.drop_held();
+            self.async_tasks.clear();
+            self.rejected = Some(format!("worker {worker_id} panicked: {}", report.message));
+        } else {
+            let worker = take_worker(worker_id, &mut self.idle)
+                .or_else(|| take_worker(worker_id, &mut self.busy));
+
+            if let Some(worker) = worker.filter(|_| self.panic_policy == PanicPolicy::Recover) {
+                self.watchdog.worker_idle(worker_id);
+                self.last_spawned.remove(&worker_id);
+                // Note: the panicked thread's stack and TLS live in the shared
+                // memory and can't be trusted, so the worker is always
+                // replaced rather than re-instantiated in place
+                drop(worker);
+
+                // Note: the replacement sends a handshake once it is ready
+                let now = self.now();
+                match self.start_worker() {
+                    Ok(replacement) => {
+                        tracing::debug!(
+                            worker.id = worker_id,
+                            replacement.id = replacement.id(),
+                            "Replacing the worker",
+                        );
+                        self.recovering
+                            .insert(replacement.id(), (replacement, worker_id, now));
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            worker.id = worker_id,
+                            error = &*e,
+                            "Unable to start a replacement worker",
+                        );
+                    }
+                }
+            }
+        }
+
+        // Note: tasks it was never sent can still go to another worker
+        for (held, would_block) in self.unverified.remove(&worker_id).unwrap_or_default() {
+            self.requeue(held, would_block);
+        }
+
+        let PanicReport {
+            message,
+            location,
+            backtrace,
+            task_id,
+        } = report;
+        let event = RuntimeEvent::WorkerPanicked {
+            worker_id,
+            message,
+            location,
+            backtrace,
+            task_id: task_id.map(TaskId::get),
+            aborted,
+        };
+        self.mailbox
+            .events()
+            .dispatch(&event)
+            .map_err(|e| e.into_anyhow())
+    }
+
+    /// Put a worker that replaced one which panicked into rotation, once it
+    /// has finished starting up.
+    fn recovered(&mut self, replacement_id: u32) -> Result<(), Error> {
+        let Some((worker, worker_id, started)) = self.recovering.remove(&replacement_id) else {
+            return Ok(());
+        };
+
+        let recovery_ms = self.now().saturating_duration_since(started).as_secs_f64() * 1000.0;
+        tracing::info!(
+            worker.id = worker_id,
+            replacement.id = replacement_id,
+            recovery_ms,
+            "A worker that panicked was replaced"
+        );
+        self.idle.push_back(worker);
+
+        let event = RuntimeEvent::WorkerRecovered {
+            worker_id,
+            replacement_id,
+            recovery_ms,
+        };
+        self.mailbox
+            .events()
+            .dispatch(&event)
+            .map_err(|e| e.into_anyhow())
+    }
+
     /// Send a task to one of the worker threads, preferring workers that aren't
     /// running synchronous work.
-    fn post_message(&mut self, msg: PostMessagePayload) -> Result<(), Error> {
-        let worker = self.next_available_worker()?;
+    fn post_message(
+        &mut self,
+        msg: PostMessagePayload,
+        task_id: Option<TaskId>,
+    ) -> Result<(), Error> {
+        self.post_message_preferring(msg, None, task_id)
+    }
+
+    /// Like [`SchedulerState::post_message()`], except an idle worker which
+    /// last spawned the `module` will be chosen if there is one.
+    fn post_message_preferring(
+        &mut self,
+        msg: PostMessagePayload,
+        module: Option<ModuleKey>,
+        task_id: Option<TaskId>,
+    ) -> Result<(), Error> {
+        if let Some(reason) = &self.rejected {
+            anyhow::bail!("Unable to run the task because the thread pool is unusable: {reason}");
+        }
+        if self.single_threaded {
+            run_inline(msg, task_id);
+            return Ok(());
+        }
+
+        let mut worker = match module.and_then(|key| self.take_idle_worker_that_spawned(key)) {
+            Some(worker) => worker,
+            None => match self.next_available_worker() {
+                Ok(worker) => worker,
+                Err(e) => return self.worker_spawn_failed(e, msg, task_id),
+            },
+        };
 
         let would_block = msg.would_block();
-        worker
-            .send(msg)
-            .with_context(|| format!("Unable to send a message to worker {}", worker.id()))?;
+        let message = PreparedMessage::new(msg, task_id)?;
+        let mut attempts = 1;
+
+        loop {
+            if let Some(held) = self.unverified.get_mut(&worker.id()) {
+                held.push((message, would_block));
+                break;
+            }
+            let Err(error) = worker.post(&message) else {
+                break;
+            };
+
+            let retryable = super::delivery::is_retryable(&error);
+            let error = crate::utils::js_error(error).context(format!(
+                "Unable to send a message to worker {}",
+                worker.id()
+            ));
+            if !retryable || attempts >= super::delivery::MAX_ATTEMPTS {
+                return Err(self.undelivered(message, attempts, error));
+            }
 
+            tracing::warn!(
+                worker.id = worker.id(),
+                task.id = task_id.map(TaskId::get),
+                attempts,
+                error = &*error,
+                "Unable to send a message to a worker, trying another one",
+            );
+            // Note: the worker is probably broken, so stop handing it work.
+            // It may still be running async tasks it was given earlier, so
+            // those get a chance to finish before it is terminated.
+            self.retire(worker);
+
+            worker = match self.next_available_worker() {
+                Ok(worker) => worker,
+                Err(e) => return Err(self.undelivered(message, attempts, e)),
+            };
+            attempts += 1;
+        }
+
+        if let Some(key) = module {
+            let (idle, busy) = (&self.idle, &self.busy);
+            self.last_spawned
+                .retain(|id, _| idle.iter().chain(busy).any(|w| w.id() == *id));
+            self.last_spawned.insert(worker.id(), key);
+        }
+
+        self.sent(worker, would_block, task_id);
+
+        Ok(())
+    }
+
+    /// Keep track of a task that was given to a worker.
+    fn sent(&mut self, worker: WorkerHandle, would_block: bool, task_id: Option<TaskId>) {
         if would_block {
+            let now = self.now();
+            self.watchdog.worker_busy(worker.id(), now);
+            self.watchdog.running(worker.id(), task_id);
             self.busy.push_back(worker);
         } else {
+            self.watchdog.async_task_sent(worker.id());
+            *self.async_tasks.entry(worker.id()).or_default() += 1;
             self.idle.push_back(worker);
         }
+    }
 
-        Ok(())
+    /// Handle a worker that couldn't be started, falling back to running
+    /// `msg` inline if the page's Content Security Policy was to blame and
+    /// the [`BlockedWorkerPolicy`] allows it.
+    fn worker_spawn_failed(
+        &mut self,
+        error: Error,
+        msg: PostMessagePayload,
+        task_id: Option<TaskId>,
+    ) -> Result<(), Error> {
+        let Some(blocked) = error.downcast_ref::<WorkerSpawnBlocked>().cloned() else {
+            return Err(error);
+        };
+
+        let single_threaded = self.blocked_policy == BlockedWorkerPolicy::SingleThreaded;
+        let hints = blocked.hints();
+        tracing::error!(
+            directive = %blocked.directive,
+            url = %blocked.blocked_url,
+            browser_message = %blocked.message,
+            ?hints,
+            single_threaded,
+            "The page's Content Security Policy blocked the thread pool's workers",
+        );
+
+        let event = RuntimeEvent::WorkerSpawnBlocked {
+            directive: blocked.directive.clone(),
+            blocked_url: blocked.blocked_url.clone(),
+            hints,
+            single_threaded,
+        };
+        self.mailbox
+            .events()
+            .dispatch(&event)
+            .map_err(|e| e.into_anyhow())?;
+
+        if single_threaded {
+            self.single_threaded = true;
+            run_inline(msg, task_id);
+            Ok(())
+        } else {
+            // Note: Every other worker would be blocked too
+            self.rejected = Some(blocked.to_string());
+            Err(error)
+        }
+    }
+
+    /// Give up on a message no worker would accept, letting the runtime's
+    /// listeners know and dropping the task so anything waiting on it
+    /// finds out.
+    fn undelivered(&mut self, message: PreparedMessage, attempts: usize, error: Error) -> Error {
+        let task_id = message.task_id().map(TaskId::get);
+        tracing::error!(
+            task.id = task_id,
+            attempts,
+            error = &*error,
+            "Unable to send a task to any worker",
+        );
+
+        match message.into_payload() {
+            Ok(payload) => drop(payload),
+            Err(e) => tracing::warn!(error = &*e, "Unable to drop the undelivered task"),
+        }
+
+        let undelivered = TaskUndelivered {
+            task_id,
+            attempts,
+            reason: format!("{error:#}"),
+        };
+        let event = RuntimeEvent::TaskUndelivered {
+            task_id,
+            attempts,
+            reason: undelivered.reason.clone(),
+        };
+        if let Err(e) = self.mailbox.events().dispatch(&event) {
+            tracing::warn!(
+                error = &*e.into_anyhow(),
+                "Unable to report an undelivered task"
+            );
+        }
+
+        Error::new(undelivered)
+    }
+
+    fn take_idle_worker_that_spawned(&mut self, module: ModuleKey) -> Option<WorkerHandle> {
+        let ix = self
+            .idle
+            .iter()
+            .position(|w| self.last_spawned.get(&w.id()) == Some(&module))?;
+        let worker = self.idle.remove(ix)?;
+        tracing::trace!(
+            worker.id = worker.id(),
+            "Sending the message to a worker that already spawned this module"
+        );
+        Some(worker)
     }
 
     fn next_available_worker(&mut self) -> Result<WorkerHandle, Error> {
@@ -228,6 +1133,15 @@ impl SchedulerState {
         // Rather than sending the task to one of the blocking workers,
         // let's spawn a new worker
 
+        let worker_count = self.idle.len() + self.busy.len();
+        if worker_count >= self.capacity.get() {
+            tracing::debug!(
+                worker_count,
+                capacity = self.capacity.get(),
+                "Every worker is blocked, so the thread pool is going over capacity",
+            );
+        }
+
         let worker = self.start_worker()?;
         tracing::trace!(
             worker.id = worker.id(),
@@ -242,9 +1156,16 @@ impl SchedulerState {
         // unique ID.
         static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
-        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-
-        let handle = WorkerHandle::spawn(id, self.mailbox.clone())?;
+        let handle = match &self.world {
+            Some(world) => WorkerHandle::simulated(Rc::clone(world)),
+            None => {
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                let handle = WorkerHandle::spawn(id, self.mailbox.clone())?;
+                // Note: simulated workers never answer the handshake
+                self.unverified.insert(id, Vec::new());
+                handle
+            }
+        };
 
         // Prime the worker's module cache
         for (&hash, module) in &self.cached_modules {
@@ -259,13 +1180,54 @@ impl SchedulerState {
     }
 }
 
+/// Run a task on the scheduler's own thread, for when we aren't allowed to
+/// start workers.
+///
+/// Blocking tasks will block the scheduler (and everything else on this
+/// thread) until they finish.
+fn run_inline(msg: PostMessagePayload, task_id: Option<TaskId>) {
+    wasm_bindgen_futures::spawn_local(async move {
+        let worker = ThreadPoolWorker::inline();
+        let result = match task_id {
+            Some(id) => id.scope(worker.dispatch(msg)).await,
+            None => worker.dispatch(msg).await,
+        };
+
+        if let Err(e) = result {
+            tracing::error!(error = &*e.into_anyhow(), "Unable to run a task inline");
+        }
+    });
+}
+
+/// Write a module through to the persistent store in the background, so
+/// the next session can restore it.
+fn persist_module(hash: ModuleHash, module: &wasmer::Module) {
+    let wasm = match module.serialize() {
+        Ok(wasm) => wasm,
+        Err(e) => {
+            tracing::debug!(%hash, error = %e, "Unable to serialize a module");
+            return;
+        }
+    };
+
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) = super::module_store::save(hash, &wasm).await {
+            tracing::debug!(%hash, error = &*e.into_anyhow(), "Unable to persist a module");
+        }
+    });
+}
+
 fn move_worker(worker_id: u32, from: &mut VecDeque<WorkerHandle>, to: &mut VecDeque<WorkerHandle>) {
-    if let Some(ix) = from.iter().position(|w| w.id() == worker_id) {
-        let worker = from.remove(ix).unwrap();
+    if let Some(worker) = take_worker(worker_id, from) {
         to.push_back(worker);
     }
 }
 
+fn take_worker(worker_id: u32, from: &mut VecDeque<WorkerHandle>) -> Option<WorkerHandle> {
+    let ix = from.iter().position(|w| w.id() == worker_id)?;
+    from.remove(ix)
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::sync::oneshot;
@@ -273,6 +1235,31 @@ mod tests {
 
     use super::*;
 
+    /// Collect the `detail` of every `event_type` event the scheduler emits,
+    /// for as long as the returned listener is kept alive.
+    fn record_events(
+        scheduler: &Scheduler,
+        event_type: &str,
+    ) -> (
+        js_sys::Array,
+        wasm_bindgen::closure::Closure<dyn FnMut(web_sys::CustomEvent)>,
+    ) {
+        let details = js_sys::Array::new();
+        let listener = wasm_bindgen::closure::Closure::<dyn FnMut(web_sys::CustomEvent)>::new({
+            let details = details.clone();
+            move |ev: web_sys::CustomEvent| {
+                details.push(&ev.detail());
+            }
+        });
+        scheduler
+            .events()
+            .target()
+            .add_event_listener_with_callback(event_type, listener.as_ref().unchecked_ref())
+            .unwrap();
+
+        (details, listener)
+    }
+
     #[wasm_bindgen_test]
     async fn spawn_an_async_function() {
         let (sender, receiver) = oneshot::channel();
@@ -284,6 +1271,7 @@ mod tests {
                 let _ = sender.send(42);
             })
         }));
+        let handshake = Handshake::current(Some(WORKER_PROTOCOL_VERSION));
 
         // we start off with no workers
         assert_eq!(scheduler.idle.len(), 0);
@@ -298,8 +1286,292 @@ mod tests {
         assert_eq!(scheduler.idle.len(), 1);
         assert_eq!(scheduler.busy.len(), 0);
 
+        // The job is held back until the worker says it is compatible
+        let worker_id = scheduler.idle[0].id();
+        scheduler
+            .execute(SchedulerMessage::WorkerHandshake {
+                worker_id,
+                handshake,
+            })
+            .unwrap();
+
         // Make sure the background thread actually ran something and sent us
         // back a result
         assert_eq!(receiver.await.unwrap(), 42);
     }
+
+    #[wasm_bindgen_test]
+    async fn incompatible_workers_are_rejected() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx.clone());
+        let (rejections, _listener) = record_events(&tx, "worker-rejected");
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let worker_id = scheduler.idle[0].id();
+        let handshake = Handshake::current(Some(0));
+
+        scheduler
+            .execute(SchedulerMessage::WorkerHandshake {
+                worker_id,
+                handshake,
+            })
+            .unwrap();
+
+        // The worker was terminated and the host was told why
+        assert_eq!(scheduler.idle.len(), 0);
+        assert_eq!(rejections.length(), 1);
+        let detail = rejections.get(0);
+        let field = |name: &str| js_sys::Reflect::get(&detail, &name.into()).unwrap();
+        assert_eq!(field("workerId").as_f64(), Some(worker_id as f64));
+        let reason = field("reason").as_string().unwrap();
+        assert!(reason.contains("protocol"), "{reason}");
+        // No more work gets scheduled once a worker has been rejected, and no
+        // replacement worker is started
+        let err = scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap_err();
+        assert!(err.to_string().contains("protocol"), "{err}");
+        assert_eq!(scheduler.idle.len(), 0);
+        assert_eq!(scheduler.busy.len(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    async fn tasks_queued_on_rejected_workers_still_run() {
+        let (sender, receiver) = oneshot::channel();
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx);
+        let compatible = Handshake::current(Some(WORKER_PROTOCOL_VERSION));
+        // One worker is up and running, but blocked for now
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let first = scheduler.idle[0].id();
+        scheduler
+            .execute(SchedulerMessage::WorkerHandshake {
+                worker_id: first,
+                handshake: compatible,
+            })
+            .unwrap();
+        scheduler
+            .execute(SchedulerMessage::WorkerBusy { worker_id: first })
+            .unwrap();
+        // so the next task is queued on a new worker
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(move || {
+                Box::pin(async move {
+                    let _ = sender.send(42);
+                })
+            })))
+            .unwrap();
+        let second = scheduler.idle[0].id();
+        assert_ne!(second, first);
+        scheduler
+            .execute(SchedulerMessage::WorkerIdle { worker_id: first })
+            .unwrap();
+
+        // which turns out to be incompatible
+        scheduler
+            .execute(SchedulerMessage::WorkerHandshake {
+                worker_id: second,
+                handshake: Handshake::current(Some(0)),
+            })
+            .unwrap();
+
+        // The task went to the first worker instead of being dropped
+        assert_eq!(scheduler.idle.len(), 1);
+        assert_eq!(scheduler.idle[0].id(), first);
+        assert_eq!(receiver.await.unwrap(), 42);
+    }
+
+    #[wasm_bindgen_test]
+    async fn panicking_workers_are_quarantined() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx.clone());
+        let (panics, _listener) = record_events(&tx, "worker-panicked");
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let worker_id = scheduler.idle[0].id();
+
+        scheduler
+            .execute(SchedulerMessage::WorkerPanicked {
+                worker_id,
+                report: PanicReport {
+                    message: "oops".to_string(),
+                    location: None,
+                    backtrace: None,
+                    task_id: None,
+                },
+            })
+            .unwrap();
+
+        assert_eq!(panics.length(), 1);
+        assert_eq!(scheduler.idle.len(), 0);
+        // The rest of the pool is still usable
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        assert_eq!(scheduler.idle.len(), 1);
+        assert_ne!(scheduler.idle[0].id(), worker_id);
+    }
+
+    #[wasm_bindgen_test]
+    async fn panics_can_abort_the_whole_pool() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx);
+        scheduler.panic_policy = PanicPolicy::Abort;
+        scheduler
+            .execute(SchedulerMessage::SpawnBlocking(Box::new(|| {})))
+            .unwrap();
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let worker_id = scheduler.busy[0].id();
+
+        scheduler
+            .execute(SchedulerMessage::WorkerPanicked {
+                worker_id,
+                report: PanicReport {
+                    message: "oops".to_string(),
+                    location: None,
+                    backtrace: None,
+                    task_id: None,
+                },
+            })
+            .unwrap();
+
+        assert_eq!(scheduler.idle.len() + scheduler.busy.len(), 0);
+        let err = scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap_err();
+        assert!(err.to_string().contains("oops"), "{err}");
+    }
+
+    #[wasm_bindgen_test]
+    async fn recovered_workers_go_back_into_rotation() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx);
+        scheduler.panic_policy = PanicPolicy::Recover;
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let worker_id = scheduler.idle[0].id();
+        let panicked = |worker_id| SchedulerMessage::WorkerPanicked {
+            worker_id,
+            report: PanicReport {
+                message: "oops".to_string(),
+                location: None,
+                backtrace: None,
+                task_id: None,
+            },
+        };
+
+        // The worker is replaced rather than re-instantiated in place
+        scheduler.execute(panicked(worker_id)).unwrap();
+        assert_eq!(scheduler.idle.len(), 0);
+        let replacement_id = *scheduler.recovering.keys().next().unwrap();
+        assert_ne!(replacement_id, worker_id);
+
+        scheduler
+            .execute(SchedulerMessage::WorkerHandshake {
+                worker_id: replacement_id,
+                handshake: Handshake::current(Some(WORKER_PROTOCOL_VERSION)),
+            })
+            .unwrap();
+        assert!(scheduler.recovering.is_empty());
+        assert_eq!(scheduler.idle[0].id(), replacement_id);
+
+        // A replacement that panics before it is ready gets quarantined
+        scheduler.execute(panicked(replacement_id)).unwrap();
+        let second_replacement = *scheduler.recovering.keys().next().unwrap();
+        scheduler.execute(panicked(second_replacement)).unwrap();
+        assert!(scheduler.recovering.is_empty());
+        assert!(scheduler.idle.is_empty());
+        assert!(scheduler.rejected.is_none());
+    }
+
+    #[wasm_bindgen_test]
+    async fn stalled_pools_start_extra_workers() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+        let mut scheduler = SchedulerState::new(tx.clone());
+        scheduler.watchdog = Watchdog::new(WatchdogOptions {
+            stall_after: Some(instant::Duration::from_millis(1)),
+            boost: 2,
+        });
+        let (reports, _listener) = record_events(&tx, "deadlock-suspected");
+        // An async task gets queued on a worker which then blocks
+        scheduler
+            .execute(SchedulerMessage::SpawnAsync(Box::new(
+                || Box::pin(async {}),
+            )))
+            .unwrap();
+        let worker_id = scheduler.idle[0].id();
+        scheduler
+            .execute(SchedulerMessage::WorkerBusy { worker_id })
+            .unwrap();
+
+        sleep(std::time::Duration::from_millis(5)).await;
+        scheduler.execute(SchedulerMessage::CheckForStall).unwrap();
+
+        assert_eq!(reports.length(), 1);
+        assert_eq!(scheduler.idle.len(), 2);
+        // The stall is only reported once
+        scheduler.execute(SchedulerMessage::CheckForStall).unwrap();
+        assert_eq!(reports.length(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    async fn shutting_down_terminates_every_worker() {
+        let (tx, _) = mpsc::unbounded_channel();
+        let tx = unsafe { Scheduler::new(tx, wasmer::current_thread_id()) };
+
+        // Repeatedly spinning pools up and down shouldn't leave anything behind
+        for _ in 0..3 {
+            let mut scheduler = SchedulerState::new(tx.clone());
+            scheduler
+                .execute(SchedulerMessage::SpawnBlocking(Box::new(|| {})))
+                .unwrap();
+            scheduler
+                .execute(SchedulerMessage::SpawnAsync(Box::new(
+                    || Box::pin(async {}),
+                )))
+                .unwrap();
+            assert_eq!(scheduler.idle.len() + scheduler.busy.len(), 2);
+
+            scheduler.execute(SchedulerMessage::Shutdown).unwrap();
+
+            assert_eq!(scheduler.idle.len() + scheduler.busy.len(), 0);
+            assert!(scheduler.cached_modules.is_empty());
+            assert!(scheduler.shut_down.get());
+            let err = scheduler
+                .execute(SchedulerMessage::SpawnAsync(Box::new(
+                    || Box::pin(async {}),
+                )))
+                .unwrap_err();
+            assert!(err.to_string().contains("disposed"), "{err}");
+        }
+    }
 }
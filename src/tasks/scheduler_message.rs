@@ -1,16 +1,17 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
 use derivative::Derivative;
 use js_sys::WebAssembly;
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{JsCast, JsValue};
 use wasmer::AsJs;
 use wasmer_wasix::runtime::module_cache::ModuleHash;
 
 use crate::{
+    events::RuntimeEvent,
     tasks::{
         interop::{Deserializer, Serializer},
         task_wasm::SpawnWasm,
-        AsyncTask, BlockingModuleTask, BlockingTask,
+        AsyncTask, BlockingModuleTask, BlockingTask, Handshake, PanicReport, PeriodicTask, TaskId,
     },
     utils::Error,
 };
@@ -24,17 +25,71 @@ pub(crate) enum SchedulerMessage {
     SpawnAsync(#[derivative(Debug(format_with = "crate::utils::hidden"))] AsyncTask),
     /// Run a blocking operation on a worker thread.
     SpawnBlocking(#[derivative(Debug(format_with = "crate::utils::hidden"))] BlockingTask),
+    /// Run a promise on a worker thread once `delay` has elapsed.
+    SpawnAfter {
+        delay: Duration,
+        #[derivative(Debug(format_with = "crate::utils::hidden"))]
+        task: AsyncTask,
+    },
+    /// Call a function on the scheduler's thread every `interval` until it
+    /// returns `false`.
+    ///
+    /// The function should be quick, spawning anything expensive onto the
+    /// thread pool.
+    SpawnPeriodic {
+        interval: Duration,
+        #[derivative(Debug(format_with = "crate::utils::hidden"))]
+        task: PeriodicTask,
+    },
+    /// Call a function on the scheduler's thread as soon as the message
+    /// arrives (e.g. to reach a JavaScript callback that lives there).
+    ///
+    /// Like [`SchedulerMessage::SpawnPeriodic`], the function should be
+    /// quick.
+    RunOnScheduler(#[derivative(Debug(format_with = "crate::utils::hidden"))] BlockingTask),
     /// A message sent from a worker thread.
     /// Mark a worker as idle.
     WorkerIdle { worker_id: u32 },
     /// Mark a worker as busy.
     WorkerBusy { worker_id: u32 },
+    /// One of the async tasks sent to a worker has finished.
+    WorkerAsyncTaskDone { worker_id: u32 },
+    /// A worker has finished initializing and told us what it is running.
+    WorkerHandshake {
+        worker_id: u32,
+        handshake: Handshake,
+    },
+    /// A worker panicked and needs to be taken out of rotation.
+    WorkerPanicked { worker_id: u32, report: PanicReport },
+    /// Dispatch an event to the runtime's listeners.
+    Emit(RuntimeEvent),
+    /// A program's `WebAssembly.Memory` (or a copy of its contents, once it
+    /// has exited) so it can be inspected from JavaScript.
+    AttachMemory {
+        id: u32,
+        #[derivative(Debug(format_with = "crate::utils::hidden"))]
+        memory: JsValue,
+    },
     /// Tell all workers to cache a WebAssembly module.
     #[allow(dead_code)]
     CacheModule {
         hash: ModuleHash,
         module: wasmer::Module,
     },
+    /// Terminate every worker and refuse any more work.
+    Shutdown,
+    /// The page was hidden or shown.
+    VisibilityChanged { hidden: bool },
+    /// Check whether every worker is blocked with work queued behind them.
+    CheckForStall,
+    /// Terminate every idle worker to free up memory.
+    ShedIdleWorkers,
+    /// Terminate the worker running a blocking task (e.g. a program's thread
+    /// stuck in `Atomics.wait()` after its program was aborted).
+    TerminateTask { task_id: TaskId },
+    /// Keep no more than this many workers around once they go idle,
+    /// because the page's workers are being shared with other runtimes.
+    SetWorkerShare { workers: Option<u32> },
     /// Run a task in the background, explicitly transferring the
     /// [`js_sys::WebAssembly::Module`] to the worker.
     SpawnWithModule {
@@ -60,6 +115,17 @@ pub(crate) enum SchedulerMessage {
 }
 
 impl SchedulerMessage {
+    /// Does this message hand a task to one of the workers?
+    pub(crate) fn spawns_task(&self) -> bool {
+        matches!(
+            self,
+            SchedulerMessage::SpawnAsync(_)
+                | SchedulerMessage::SpawnBlocking(_)
+                | SchedulerMessage::SpawnWithModule { .. }
+                | SchedulerMessage::SpawnWithModuleAndMemory { .. }
+        )
+    }
+
     pub(crate) unsafe fn try_from_js(value: JsValue) -> Result<Self, Error> {
         let de = Deserializer::new(value);
 
@@ -72,6 +138,26 @@ impl SchedulerMessage {
                 let task = de.boxed(consts::PTR)?;
                 Ok(SchedulerMessage::SpawnBlocking(task))
             }
+            consts::TYPE_SPAWN_AFTER => {
+                let delay: u64 = de.serde(consts::DELAY_MS)?;
+                let task = de.boxed(consts::PTR)?;
+                Ok(SchedulerMessage::SpawnAfter {
+                    delay: Duration::from_millis(delay),
+                    task,
+                })
+            }
+            consts::TYPE_SPAWN_PERIODIC => {
+                let interval: u64 = de.serde(consts::INTERVAL_MS)?;
+                let task = de.boxed(consts::PTR)?;
+                Ok(SchedulerMessage::SpawnPeriodic {
+                    interval: Duration::from_millis(interval),
+                    task,
+                })
+            }
+            consts::TYPE_RUN_ON_SCHEDULER => {
+                let task = de.boxed(consts::PTR)?;
+                Ok(SchedulerMessage::RunOnScheduler(task))
+            }
             consts::TYPE_WORKER_IDLE => {
                 let worker_id = de.serde(consts::WORKER_ID)?;
                 Ok(SchedulerMessage::WorkerIdle { worker_id })
@@ -80,6 +166,32 @@ impl SchedulerMessage {
                 let worker_id = de.serde(consts::WORKER_ID)?;
                 Ok(SchedulerMessage::WorkerBusy { worker_id })
             }
+            consts::TYPE_WORKER_ASYNC_TASK_DONE => {
+                let worker_id = de.serde(consts::WORKER_ID)?;
+                Ok(SchedulerMessage::WorkerAsyncTaskDone { worker_id })
+            }
+            consts::TYPE_WORKER_HANDSHAKE => {
+                let worker_id = de.serde(consts::WORKER_ID)?;
+                let handshake = de.serde(consts::HANDSHAKE)?;
+                Ok(SchedulerMessage::WorkerHandshake {
+                    worker_id,
+                    handshake,
+                })
+            }
+            consts::TYPE_WORKER_PANICKED => {
+                let worker_id = de.serde(consts::WORKER_ID)?;
+                let report = de.serde(consts::REPORT)?;
+                Ok(SchedulerMessage::WorkerPanicked { worker_id, report })
+            }
+            consts::TYPE_EMIT => {
+                let event = de.serde(consts::EVENT)?;
+                Ok(SchedulerMessage::Emit(event))
+            }
+            consts::TYPE_ATTACH_MEMORY => {
+                let id = de.serde(consts::ID)?;
+                let memory = de.js(consts::MEMORY)?;
+                Ok(SchedulerMessage::AttachMemory { id, memory })
+            }
             consts::TYPE_CACHE_MODULE => {
                 let hash = de.string(consts::MODULE_HASH)?;
                 let hash = ModuleHash::parse_hex(&hash)?;
@@ -89,6 +201,21 @@ impl SchedulerMessage {
                     module: module.into(),
                 })
             }
+            consts::TYPE_SHUTDOWN => Ok(SchedulerMessage::Shutdown),
+            consts::TYPE_CHECK_FOR_STALL => Ok(SchedulerMessage::CheckForStall),
+            consts::TYPE_SHED_IDLE_WORKERS => Ok(SchedulerMessage::ShedIdleWorkers),
+            consts::TYPE_TERMINATE_TASK => {
+                let task_id = de.serde(consts::TASK_ID)?;
+                Ok(SchedulerMessage::TerminateTask { task_id })
+            }
+            consts::TYPE_SET_WORKER_SHARE => {
+                let workers = de.serde(consts::WORKERS)?;
+                Ok(SchedulerMessage::SetWorkerShare { workers })
+            }
+            consts::TYPE_VISIBILITY_CHANGED => {
+                let hidden = de.serde(consts::HIDDEN)?;
+                Ok(SchedulerMessage::VisibilityChanged { hidden })
+            }
             consts::TYPE_SPAWN_WITH_MODULE => {
                 let module: WebAssembly::Module = de.js(consts::MODULE)?;
                 let task = de.boxed(consts::PTR)?;
@@ -101,7 +228,11 @@ impl SchedulerMessage {
                 let spawn_wasm: SpawnWasm = de.boxed(consts::PTR)?;
                 let module: WebAssembly::Module = de.js(consts::MODULE)?;
                 let module_bytes = spawn_wasm.module_bytes();
-                let module = wasmer::Module::from((module, module_bytes));
+                let module = crate::tasks::module_reuse::module(
+                    module,
+                    module_bytes,
+                    spawn_wasm.module_key(),
+                );
 
                 let memory = match spawn_wasm.shared_memory_type() {
                     Some(ty) => {
@@ -133,18 +264,87 @@ impl SchedulerMessage {
             SchedulerMessage::SpawnBlocking(task) => Serializer::new(consts::TYPE_SPAWN_BLOCKING)
                 .boxed(consts::PTR, task)
                 .finish(),
+            SchedulerMessage::SpawnAfter { delay, task } => {
+                Serializer::new(consts::TYPE_SPAWN_AFTER)
+                    .serde(consts::DELAY_MS, &(delay.as_millis() as u64))
+                    .boxed(consts::PTR, task)
+                    .finish()
+            }
+            SchedulerMessage::SpawnPeriodic { interval, task } => {
+                Serializer::new(consts::TYPE_SPAWN_PERIODIC)
+                    .serde(consts::INTERVAL_MS, &(interval.as_millis() as u64))
+                    .boxed(consts::PTR, task)
+                    .finish()
+            }
+            SchedulerMessage::RunOnScheduler(task) => {
+                Serializer::new(consts::TYPE_RUN_ON_SCHEDULER)
+                    .boxed(consts::PTR, task)
+                    .finish()
+            }
             SchedulerMessage::WorkerIdle { worker_id } => Serializer::new(consts::TYPE_WORKER_IDLE)
                 .set(consts::WORKER_ID, worker_id)
                 .finish(),
             SchedulerMessage::WorkerBusy { worker_id } => Serializer::new(consts::TYPE_WORKER_BUSY)
                 .set(consts::WORKER_ID, worker_id)
                 .finish(),
+            SchedulerMessage::WorkerAsyncTaskDone { worker_id } => {
+                Serializer::new(consts::TYPE_WORKER_ASYNC_TASK_DONE)
+                    .set(consts::WORKER_ID, worker_id)
+                    .finish()
+            }
+            SchedulerMessage::WorkerHandshake {
+                worker_id,
+                handshake,
+            } => Serializer::new(consts::TYPE_WORKER_HANDSHAKE)
+                .set(consts::WORKER_ID, worker_id)
+                .serde(consts::HANDSHAKE, &handshake)
+                .finish(),
+            SchedulerMessage::WorkerPanicked { worker_id, report } => {
+                Serializer::new(consts::TYPE_WORKER_PANICKED)
+                    .set(consts::WORKER_ID, worker_id)
+                    .serde(consts::REPORT, &report)
+                    .finish()
+            }
+            SchedulerMessage::Emit(event) => Serializer::new(consts::TYPE_EMIT)
+                .serde(consts::EVENT, &event)
+                .finish(),
+            SchedulerMessage::AttachMemory { id, memory } => {
+                let ser = Serializer::new(consts::TYPE_ATTACH_MEMORY).set(consts::ID, id);
+                if memory.is_instance_of::<js_sys::ArrayBuffer>() {
+                    // Snapshots can be huge, so avoid copying them again
+                    ser.transferred(consts::MEMORY, memory).finish()
+                } else {
+                    ser.set(consts::MEMORY, memory).finish()
+                }
+            }
             SchedulerMessage::CacheModule { hash, module } => {
                 Serializer::new(consts::TYPE_CACHE_MODULE)
                     .set(consts::MODULE_HASH, hash.to_string())
                     .set(consts::MODULE, module)
                     .finish()
             }
+            SchedulerMessage::Shutdown => Serializer::new(consts::TYPE_SHUTDOWN).finish(),
+            SchedulerMessage::CheckForStall => {
+                Serializer::new(consts::TYPE_CHECK_FOR_STALL).finish()
+            }
+            SchedulerMessage::ShedIdleWorkers => {
+                Serializer::new(consts::TYPE_SHED_IDLE_WORKERS).finish()
+            }
+            SchedulerMessage::TerminateTask { task_id } => {
+                Serializer::new(consts::TYPE_TERMINATE_TASK)
+                    .serde(consts::TASK_ID, &task_id)
+                    .finish()
+            }
+            SchedulerMessage::SetWorkerShare { workers } => {
+                Serializer::new(consts::TYPE_SET_WORKER_SHARE)
+                    .serde(consts::WORKERS, &workers)
+                    .finish()
+            }
+            SchedulerMessage::VisibilityChanged { hidden } => {
+                Serializer::new(consts::TYPE_VISIBILITY_CHANGED)
+                    .set(consts::HIDDEN, hidden)
+                    .finish()
+            }
             SchedulerMessage::SpawnWithModule { module, task } => {
                 Serializer::new(consts::TYPE_SPAWN_WITH_MODULE)
                     .set(consts::MODULE, module)
@@ -175,14 +375,37 @@ impl SchedulerMessage {
 mod consts {
     pub const TYPE_SPAWN_ASYNC: &str = "spawn-async";
     pub const TYPE_SPAWN_BLOCKING: &str = "spawn-blocking";
+    pub const TYPE_SPAWN_AFTER: &str = "spawn-after";
+    pub const TYPE_SPAWN_PERIODIC: &str = "spawn-periodic";
+    pub const TYPE_RUN_ON_SCHEDULER: &str = "run-on-scheduler";
     pub const TYPE_WORKER_IDLE: &str = "worker-idle";
     pub const TYPE_WORKER_BUSY: &str = "worker-busy";
+    pub const TYPE_WORKER_ASYNC_TASK_DONE: &str = "worker-async-task-done";
+    pub const TYPE_WORKER_HANDSHAKE: &str = "worker-handshake";
+    pub const TYPE_WORKER_PANICKED: &str = "worker-panicked";
+    pub const TYPE_EMIT: &str = "emit";
+    pub const TYPE_ATTACH_MEMORY: &str = "attach-memory";
     pub const TYPE_CACHE_MODULE: &str = "cache-module";
+    pub const TYPE_SHUTDOWN: &str = "shutdown";
     pub const TYPE_SPAWN_WITH_MODULE: &str = "spawn-with-module";
     pub const TYPE_SPAWN_WITH_MODULE_AND_MEMORY: &str = "spawn-with-module-and-memory";
+    pub const TYPE_VISIBILITY_CHANGED: &str = "visibility-changed";
+    pub const TYPE_CHECK_FOR_STALL: &str = "check-for-stall";
+    pub const TYPE_SHED_IDLE_WORKERS: &str = "shed-idle-workers";
+    pub const TYPE_SET_WORKER_SHARE: &str = "set-worker-share";
+    pub const TYPE_TERMINATE_TASK: &str = "terminate-task";
+    pub const DELAY_MS: &str = "delay-ms";
+    pub const EVENT: &str = "event";
+    pub const HANDSHAKE: &str = "handshake";
+    pub const HIDDEN: &str = "hidden";
+    pub const ID: &str = "id";
+    pub const INTERVAL_MS: &str = "interval-ms";
     pub const MEMORY: &str = "memory";
     pub const MODULE_HASH: &str = "module-hash";
     pub const MODULE: &str = "module";
     pub const PTR: &str = "ptr";
+    pub const REPORT: &str = "report";
+    pub const TASK_ID: &str = "task-id";
     pub const WORKER_ID: &str = "worker-id";
+    pub const WORKERS: &str = "workers";
 }
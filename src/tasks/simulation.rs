@@ -0,0 +1,639 @@
+//! A deterministic stand-in for the browser, for testing scheduling logic
+//! without real workers or real time.
+//!
+//! A [`World`] replaces everything the [`Scheduler`] normally gets from the
+//! browser: workers are fake handles which record what they were sent,
+//! timers wait until the virtual clock is advanced past them, and the
+//! current time is whatever the virtual clock says. Everything else (which
+//! worker gets a task, when a stall is reported, how panics are handled) is
+//! the real scheduler, so a simulation behaves exactly like a live pool given
+//! the same sequence of events.
+//!
+//! [`Scheduler`]: super::Scheduler
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    rc::Rc,
+};
+
+use instant::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+use crate::{
+    event_log::{EventLogOptions, JsLoggedEvents},
+    events::RuntimeEventListener,
+    tasks::{
+        scheduler::SchedulerState, worker_handle::WORKER_PROTOCOL_VERSION, AsyncTask, Handshake,
+        PanicPolicy, PanicReport, PeriodicTask, PostMessagePayload, Scheduler, SchedulerMessage,
+        WatchdogOptions,
+    },
+    utils::Error,
+};
+
+/// How many workers a simulated pool aims to run, unless told otherwise.
+const DEFAULT_CAPACITY: usize = 4;
+
+/// The fake workers, timers and clock a simulated scheduler runs against.
+#[derive(Debug)]
+pub(crate) struct World {
+    origin: Instant,
+    elapsed: Cell<Duration>,
+    workers: RefCell<BTreeMap<u32, WorkerState>>,
+    deliveries: RefCell<Vec<Delivery>>,
+    timers: RefCell<Vec<Timer>>,
+    /// Breaks ties between timers that are due at the same time, so they
+    /// fire in the order they were set.
+    next_timer: Cell<u64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WorkerState {
+    Running,
+    /// Every `postMessage()` to the worker fails.
+    Broken,
+    Terminated,
+}
+
+/// Something the scheduler sent to a fake worker.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Delivery {
+    pub(crate) worker_id: u32,
+    pub(crate) kind: DeliveryKind,
+    /// The virtual time it was sent at, in milliseconds.
+    pub(crate) time: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DeliveryKind {
+    Async,
+    Blocking,
+    Notification,
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Timer {
+    due: Duration,
+    seq: u64,
+    /// Set once the scheduler has shut down.
+    cancelled: Rc<Cell<bool>>,
+    #[derivative(Debug = "ignore")]
+    kind: TimerKind,
+}
+
+enum TimerKind {
+    Once(AsyncTask),
+    Periodic {
+        interval: Duration,
+        task: PeriodicTask,
+    },
+}
+
+impl World {
+    pub(crate) fn new() -> Self {
+        World {
+            origin: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+            workers: RefCell::new(BTreeMap::new()),
+            deliveries: RefCell::new(Vec::new()),
+            timers: RefCell::new(Vec::new()),
+            next_timer: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.origin + self.elapsed.get()
+    }
+
+    fn elapsed_ms(&self) -> f64 {
+        self.elapsed.get().as_secs_f64() * 1000.0
+    }
+
+    /// Start a fake worker, returning its ID.
+    ///
+    /// IDs start at 1 in every world, so they are the same from one run to
+    /// the next.
+    pub(crate) fn start_worker(&self) -> u32 {
+        let mut workers = self.workers.borrow_mut();
+        let id = workers.keys().next_back().map_or(1, |last| last + 1);
+        workers.insert(id, WorkerState::Running);
+        id
+    }
+
+    /// Check whether a worker will accept a message, failing the same way
+    /// `postMessage()` would if it is broken.
+    pub(crate) fn accept(&self, worker_id: u32) -> Result<(), JsValue> {
+        match self.workers.borrow().get(&worker_id) {
+            Some(WorkerState::Broken) => {
+                let msg = format!("Worker {worker_id} is broken");
+                Err(js_sys::Error::new(&msg).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a message a worker accepted.
+    ///
+    /// Fake workers never run their tasks, so the payload is dropped here.
+    pub(crate) fn delivered(&self, worker_id: u32, payload: &PostMessagePayload) {
+        let kind = match payload {
+            PostMessagePayload::Async(_) => DeliveryKind::Async,
+            PostMessagePayload::Blocking(_) => DeliveryKind::Blocking,
+            PostMessagePayload::Notification(_) => DeliveryKind::Notification,
+        };
+        self.record(worker_id, kind);
+    }
+
+    pub(crate) fn terminated(&self, worker_id: u32) {
+        self.workers
+            .borrow_mut()
+            .insert(worker_id, WorkerState::Terminated);
+    }
+
+    fn record(&self, worker_id: u32, kind: DeliveryKind) {
+        self.deliveries.borrow_mut().push(Delivery {
+            worker_id,
+            kind,
+            time: self.elapsed_ms(),
+        });
+    }
+
+    pub(crate) fn spawn_after(&self, delay: Duration, task: AsyncTask, cancelled: Rc<Cell<bool>>) {
+        self.set_timer(delay, TimerKind::Once(task), cancelled);
+    }
+
+    pub(crate) fn spawn_periodic(
+        &self,
+        interval: Duration,
+        task: PeriodicTask,
+        cancelled: Rc<Cell<bool>>,
+    ) {
+        self.set_timer(interval, TimerKind::Periodic { interval, task }, cancelled);
+    }
+
+    fn set_timer(&self, delay: Duration, kind: TimerKind, cancelled: Rc<Cell<bool>>) {
+        let seq = self.next_timer.get();
+        self.next_timer.set(seq + 1);
+        self.timers.borrow_mut().push(Timer {
+            due: self.elapsed.get() + delay,
+            seq,
+            cancelled,
+            kind,
+        });
+    }
+
+    /// Move the clock to the next timer due by `until` and fire it,
+    /// returning `false` once there are none left.
+    ///
+    /// Delayed tasks are sent to `mailbox`, just like the browser's
+    /// `setTimeout()` callback would.
+    fn fire_next(&self, until: Duration, mailbox: &Scheduler) -> bool {
+        let timer = {
+            let mut timers = self.timers.borrow_mut();
+            let next = timers
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| t.due <= until)
+                .min_by_key(|(_, t)| (t.due, t.seq))
+                .map(|(ix, _)| ix);
+            match next {
+                Some(ix) => timers.remove(ix),
+                None => return false,
+            }
+        };
+
+        self.elapsed.set(self.elapsed.get().max(timer.due));
+        if timer.cancelled.get() {
+            return true;
+        }
+
+        match timer.kind {
+            TimerKind::Once(task) => {
+                if let Err(e) = mailbox.send(SchedulerMessage::SpawnAsync(task)) {
+                    tracing::warn!(error = &*e, "Unable to run a delayed task");
+                }
+            }
+            TimerKind::Periodic { interval, mut task } => {
+                // Note: the task may set more timers, so the list can't be
+                // borrowed while it runs
+                if task() {
+                    self.set_timer(
+                        interval,
+                        TimerKind::Periodic { interval, task },
+                        timer.cancelled,
+                    );
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// A thread pool scheduler running against fake workers and a virtual
+/// clock, for writing reproducible tests of code that depends on how work is
+/// scheduled.
+///
+/// Nothing happens on its own: tasks are only sent when spawned, workers
+/// only become busy or idle when told to, and time only passes when
+/// {@link SchedulerSimulation.advance} is called. Fake workers record what
+/// they were sent instead of running it, so every simulation with the same
+/// sequence of calls ends up in the same state with the same events.
+///
+/// @example
+/// ```ts
+/// import { SchedulerSimulation } from "@wasmer/sdk";
+///
+/// const sim = new SchedulerSimulation({ deadlockDetection: { after: 5000 } });
+/// const worker = sim.spawnAsync();
+/// sim.markBusy(worker);
+///
+/// sim.advance(5000);
+/// const [stall] = sim.events().filter(e => e.event.type === "deadlock-suspected");
+/// ```
+#[wasm_bindgen(js_name = "SchedulerSimulation")]
+pub struct SchedulerSimulation {
+    world: Rc<World>,
+    scheduler: SchedulerState,
+    mailbox: UnboundedReceiver<SchedulerMessage>,
+}
+
+#[wasm_bindgen(js_class = "SchedulerSimulation")]
+impl SchedulerSimulation {
+    #[wasm_bindgen(constructor)]
+    pub fn js_new(options: Option<SimulationOptions>) -> Result<SchedulerSimulation, Error> {
+        let panic_policy = match options.as_ref().and_then(|opts| opts.on_panic()) {
+            Some(policy) => PanicPolicy::parse(&policy)?,
+            None => PanicPolicy::default(),
+        };
+        let watchdog = match options.as_ref().and_then(|opts| opts.deadlock_detection()) {
+            Some(value) => WatchdogOptions::parse(value)?,
+            None => WatchdogOptions::default(),
+        };
+        let capacity = match options.as_ref().and_then(|opts| opts.capacity()) {
+            Some(capacity) => crate::js_runtime::parse_count("capacity", capacity)?,
+            None => NonZeroUsize::new(DEFAULT_CAPACITY).unwrap(),
+        };
+
+        Ok(SchedulerSimulation::new(panic_policy, watchdog, capacity))
+    }
+
+    /// Spawn an async task, returning the ID of the worker it was sent to.
+    #[wasm_bindgen(js_name = "spawnAsync")]
+    pub fn spawn_async(&mut self) -> Result<u32, Error> {
+        let task: AsyncTask = Box::new(|| Box::pin(async {}));
+        self.spawn(SchedulerMessage::SpawnAsync(task))
+    }
+
+    /// Spawn a blocking task, returning the ID of the worker it was sent to.
+    ///
+    /// The worker counts as busy until {@link SchedulerSimulation.markIdle}
+    /// is called.
+    #[wasm_bindgen(js_name = "spawnBlocking")]
+    pub fn spawn_blocking(&mut self) -> Result<u32, Error> {
+        self.spawn(SchedulerMessage::SpawnBlocking(Box::new(|| {})))
+    }
+
+    /// Spawn an async task once `delay` milliseconds of virtual time have
+    /// passed.
+    #[wasm_bindgen(js_name = "spawnAfter")]
+    pub fn spawn_after(&mut self, delay: f64) -> Result<(), Error> {
+        let delay = parse_duration("delay", delay)?;
+        let task: AsyncTask = Box::new(|| Box::pin(async {}));
+        self.execute(SchedulerMessage::SpawnAfter { delay, task })
+    }
+
+    /// Pretend a worker went into a blocking operation.
+    #[wasm_bindgen(js_name = "markBusy")]
+    pub fn mark_busy(&mut self, worker_id: u32) -> Result<(), Error> {
+        self.execute(SchedulerMessage::WorkerBusy { worker_id })
+    }
+
+    /// Pretend a worker finished its blocking operation.
+    #[wasm_bindgen(js_name = "markIdle")]
+    pub fn mark_idle(&mut self, worker_id: u32) -> Result<(), Error> {
+        self.execute(SchedulerMessage::WorkerIdle { worker_id })
+    }
+
+    /// Pretend one of the async tasks sent to a worker finished.
+    #[wasm_bindgen(js_name = "finishAsync")]
+    pub fn finish_async(&mut self, worker_id: u32) -> Result<(), Error> {
+        self.execute(SchedulerMessage::WorkerAsyncTaskDone { worker_id })
+    }
+
+    /// Pretend a worker panicked.
+    pub fn panic(&mut self, worker_id: u32, message: Option<String>) -> Result<(), Error> {
+        let report = PanicReport {
+            message: message.unwrap_or_else(|| "simulated panic".to_string()),
+            location: None,
+            backtrace: None,
+            task_id: None,
+        };
+        self.execute(SchedulerMessage::WorkerPanicked { worker_id, report })
+    }
+
+    /// Pretend a worker finished starting up (e.g. after replacing one that
+    /// panicked) and said hello.
+    pub fn handshake(&mut self, worker_id: u32) -> Result<(), Error> {
+        let handshake = Handshake::current(Some(WORKER_PROTOCOL_VERSION));
+        self.execute(SchedulerMessage::WorkerHandshake {
+            worker_id,
+            handshake,
+        })
+    }
+
+    /// Make every future `postMessage()` to a worker fail, as if it had
+    /// crashed.
+    #[wasm_bindgen(js_name = "breakWorker")]
+    pub fn break_worker(&mut self, worker_id: u32) {
+        let mut workers = self.world.workers.borrow_mut();
+        if let Some(state @ WorkerState::Running) = workers.get_mut(&worker_id) {
+            *state = WorkerState::Broken;
+        }
+    }
+
+    /// Let `ms` milliseconds of virtual time pass, firing any timers (e.g.
+    /// delayed tasks and deadlock checks) that come due along the way.
+    pub fn advance(&mut self, ms: f64) -> Result<(), Error> {
+        let until = self.world.elapsed.get() + parse_duration("ms", ms)?;
+
+        while self.world.fire_next(until, self.scheduler.mailbox()) {
+            self.drain();
+        }
+        self.world.elapsed.set(until);
+
+        Ok(())
+    }
+
+    /// Shut the pool down, terminating every worker.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        self.execute(SchedulerMessage::Shutdown)
+    }
+
+    /// How much virtual time has passed, in milliseconds.
+    #[wasm_bindgen(getter)]
+    pub fn now(&self) -> f64 {
+        self.world.elapsed_ms()
+    }
+
+    /// The workers that can receive work, in the order they'll be used.
+    #[wasm_bindgen(getter, js_name = "idleWorkers")]
+    pub fn idle_workers(&self) -> Vec<u32> {
+        self.scheduler.idle_workers()
+    }
+
+    /// The workers that are blocked.
+    #[wasm_bindgen(getter, js_name = "busyWorkers")]
+    pub fn busy_workers(&self) -> Vec<u32> {
+        self.scheduler.busy_workers()
+    }
+
+    /// The workers started to replace ones that panicked, which aren't ready
+    /// for work yet.
+    #[wasm_bindgen(getter, js_name = "recoveringWorkers")]
+    pub fn recovering_workers(&self) -> Vec<u32> {
+        self.scheduler.recovering_workers()
+    }
+
+    /// Every worker that has been started, including terminated ones.
+    #[wasm_bindgen(getter, js_name = "startedWorkers")]
+    pub fn started_workers(&self) -> Vec<u32> {
+        self.world.workers.borrow().keys().copied().collect()
+    }
+
+    /// Everything sent to the fake workers, oldest first.
+    pub fn deliveries(&self) -> Result<JsDeliveries, Error> {
+        let deliveries = self.world.deliveries.borrow();
+        let value = serde_wasm_bindgen::to_value(&*deliveries).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// The events the scheduler emitted, oldest first, in the same format as
+    /// {@link Runtime.events}.
+    pub fn events(&self, options: Option<EventLogOptions>) -> Result<JsLoggedEvents, Error> {
+        let since = match &options {
+            Some(options) => options.since_id()?,
+            None => None,
+        };
+        self.scheduler
+            .mailbox()
+            .events()
+            .with_log(|log| log.to_js(since))
+    }
+
+    /// Subscribe to the scheduler's events, like
+    /// {@link Runtime.addEventListener}.
+    #[wasm_bindgen(js_name = "addEventListener")]
+    pub fn add_event_listener(
+        &self,
+        ty: &str,
+        listener: &RuntimeEventListener,
+    ) -> Result<(), Error> {
+        self.scheduler
+            .mailbox()
+            .events()
+            .target()
+            .add_event_listener_with_callback(ty, listener)
+            .map_err(Error::js)
+    }
+}
+
+impl SchedulerSimulation {
+    pub(crate) fn new(
+        panic_policy: PanicPolicy,
+        watchdog: WatchdogOptions,
+        capacity: NonZeroUsize,
+    ) -> Self {
+        let world = Rc::new(World::new());
+        let (scheduler, mailbox) =
+            SchedulerState::simulated(Rc::clone(&world), panic_policy, watchdog, capacity);
+        let mut simulation = SchedulerSimulation {
+            world,
+            scheduler,
+            mailbox,
+        };
+        simulation.drain();
+
+        simulation
+    }
+
+    fn execute(&mut self, message: SchedulerMessage) -> Result<(), Error> {
+        let result = self.scheduler.execute(message);
+        self.drain();
+        result.map_err(Error::from)
+    }
+
+    fn spawn(&mut self, message: SchedulerMessage) -> Result<u32, Error> {
+        let before = self.world.deliveries.borrow().len();
+        self.execute(message)?;
+
+        let deliveries = self.world.deliveries.borrow();
+        match deliveries[before..].last() {
+            Some(delivery) => Ok(delivery.worker_id),
+            None => Err(anyhow::anyhow!("The task wasn't sent to a worker").into()),
+        }
+    }
+
+    /// Handle anything the scheduler sent to itself, like the real
+    /// scheduler's event loop would.
+    fn drain(&mut self) {
+        while let Ok(message) = self.mailbox.try_recv() {
+            if let Err(e) = self.scheduler.execute(message) {
+                tracing::error!(error = &*e, "An error occurred while handling a message");
+            }
+        }
+    }
+}
+
+fn parse_duration(name: &str, ms: f64) -> Result<Duration, Error> {
+    if ms.is_finite() && ms >= 0.0 {
+        Ok(Duration::from_secs_f64(ms / 1000.0))
+    } else {
+        let msg = format!("\"{name}\" must be a non-negative number of milliseconds, not {ms}");
+        Err(Error::js(js_sys::RangeError::new(&msg)))
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const SIMULATION_TYPE_DEFS: &'static str = r#"
+/**
+ * Options for a {@link SchedulerSimulation}, which mean the same as the
+ * equivalent {@link RuntimeOptions}.
+ */
+export type SimulationOptions = {
+    /** The number of workers the pool aims to run in parallel. Defaults to 4. */
+    capacity?: number;
+    onPanic?: "quarantine" | "recover" | "abort";
+    deadlockDetection?: boolean | { after?: number; boost?: number };
+};
+
+/** Something a {@link SchedulerSimulation} sent to one of its fake workers. */
+export type SimulatedDelivery = {
+    workerId: number;
+    kind: "async" | "blocking" | "notification";
+    /** The virtual time it was sent at, in milliseconds. */
+    time: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "SimulationOptions")]
+    pub type SimulationOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn capacity(this: &SimulationOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = "onPanic")]
+    fn on_panic(this: &SimulationOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "deadlockDetection")]
+    fn deadlock_detection(this: &SimulationOptions) -> Option<JsValue>;
+
+    #[wasm_bindgen(typescript_type = "SimulatedDelivery[]")]
+    pub type JsDeliveries;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+    use crate::tasks::watchdog::BOOST_DURATION;
+
+    fn events(sim: &SchedulerSimulation) -> Vec<String> {
+        let events: js_sys::Array = sim.events(None).unwrap().unchecked_into();
+        events
+            .iter()
+            .map(|entry| {
+                let event = js_sys::Reflect::get(&entry, &"event".into()).unwrap();
+                js_sys::Reflect::get(&event, &"type".into())
+                    .unwrap()
+                    .as_string()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[wasm_bindgen_test]
+    fn stalls_are_detected_on_the_virtual_clock() {
+        let watchdog = WatchdogOptions {
+            stall_after: Some(Duration::from_secs(2)),
+            boost: 1,
+        };
+        let mut sim = SchedulerSimulation::new(
+            PanicPolicy::default(),
+            watchdog,
+            NonZeroUsize::new(4).unwrap(),
+        );
+
+        // An async task gets queued on a worker which then blocks
+        let worker = sim.spawn_async().unwrap();
+        assert_eq!(worker, 1);
+        sim.mark_busy(worker).unwrap();
+
+        sim.advance(1999.0).unwrap();
+        assert!(events(&sim).is_empty());
+        sim.advance(1.0).unwrap();
+        assert_eq!(events(&sim), ["deadlock-suspected"]);
+        assert_eq!(sim.idle_workers(), [2]);
+
+        // The stall clears up, and the extra worker is retired once the
+        // boost is over
+        sim.mark_idle(worker).unwrap();
+        sim.advance(BOOST_DURATION.as_millis() as f64).unwrap();
+        assert_eq!(sim.idle_workers(), [1]);
+        assert_eq!(sim.world.workers.borrow()[&2], WorkerState::Terminated);
+        assert_eq!(events(&sim), ["deadlock-suspected"]);
+    }
+
+    #[wasm_bindgen_test]
+    fn delayed_tasks_and_broken_workers() {
+        let watchdog = WatchdogOptions {
+            stall_after: None,
+            boost: 0,
+        };
+        let mut sim = SchedulerSimulation::new(
+            PanicPolicy::Recover,
+            watchdog,
+            NonZeroUsize::new(4).unwrap(),
+        );
+
+        sim.spawn_after(100.0).unwrap();
+        sim.advance(99.0).unwrap();
+        assert!(sim.world.deliveries.borrow().is_empty());
+        sim.advance(1.0).unwrap();
+        assert_eq!(
+            *sim.world.deliveries.borrow(),
+            [Delivery {
+                worker_id: 1,
+                kind: DeliveryKind::Async,
+                time: 100.0,
+            }]
+        );
+
+        // Tasks go to another worker when the first one won't take them,
+        // although it isn't terminated until its async task is done
+        sim.break_worker(1);
+        assert_eq!(sim.spawn_async().unwrap(), 2);
+        assert_eq!(sim.world.workers.borrow()[&1], WorkerState::Broken);
+        sim.finish_async(1).unwrap();
+        assert_eq!(sim.world.workers.borrow()[&1], WorkerState::Terminated);
+
+        // A worker that panics is replaced, and the replacement goes into
+        // rotation once it says hello
+        sim.panic(2, None).unwrap();
+        assert_eq!(sim.world.workers.borrow()[&2], WorkerState::Terminated);
+        assert_eq!(sim.recovering_workers(), [3]);
+        sim.advance(250.0).unwrap();
+        sim.handshake(3).unwrap();
+        assert_eq!(sim.idle_workers(), [3]);
+        assert_eq!(events(&sim), ["worker-panicked", "worker-recovered"]);
+    }
+}
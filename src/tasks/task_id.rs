@@ -0,0 +1,96 @@
+//! Ids for correlating a task's log lines and events across the scheduler and
+//! the worker that ran it.
+
+use std::{
+    cell::Cell,
+    fmt::{self, Display},
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+
+thread_local! {
+    /// The task the current thread is running, if any.
+    static CURRENT: Cell<Option<TaskId>> = const { Cell::new(None) };
+}
+
+/// A unique id given to every task the scheduler hands to a worker.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct TaskId(u64);
+
+impl TaskId {
+    pub(crate) fn next() -> Self {
+        // Note: All threads share the same linear memory, so ids are unique
+        // across every thread pool on the page.
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub(crate) fn get(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn from_raw(id: u64) -> Self {
+        TaskId(id)
+    }
+
+    /// The task the current thread is running, if any.
+    pub(crate) fn current() -> Option<TaskId> {
+        CURRENT.with(|c| c.get())
+    }
+
+    /// Treat `future` as part of this task whenever it is polled, even when
+    /// other tasks are interleaved on the same thread.
+    pub(crate) fn scope<F: Future>(self, future: F) -> Scoped<F> {
+        Scoped {
+            task: self,
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The future returned by [`TaskId::scope()`].
+pub(crate) struct Scoped<F> {
+    task: TaskId,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let task = self.task;
+        let previous = CURRENT.with(|c| c.replace(Some(task)));
+        let result = self.future.as_mut().poll(cx);
+        CURRENT.with(|c| c.set(previous));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn the_current_task_is_only_set_while_polling() {
+        let task = TaskId::next();
+        assert_ne!(task, TaskId::next());
+
+        let seen = task.scope(async { TaskId::current() }).await;
+
+        assert_eq!(seen, Some(task));
+        assert_eq!(TaskId::current(), None);
+    }
+}
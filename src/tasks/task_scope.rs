@@ -0,0 +1,539 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::future::{AbortHandle, Abortable, BoxFuture, LocalBoxFuture};
+use instant::Duration;
+use serde::Serialize;
+use tokio::sync::Notify;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+use wasmer_wasix::{
+    http::{DynHttpClient, HttpClient, HttpRequest, HttpResponse},
+    runtime::task_manager::TaskWasm,
+    VirtualTaskManager, WasiThreadError,
+};
+
+use crate::{
+    tasks::{TaskId, ThreadPool},
+    utils::Error,
+};
+
+/// How long an aborted program's threads get to exit on their own before
+/// their workers are terminated.
+const STUCK_THREAD_GRACE: Duration = Duration::from_millis(250);
+
+/// A group of tasks which are cancelled and awaited together.
+///
+/// Every task spawned through the scope (including any threads a WASIX
+/// program spawns, when the scope is used as its task manager) is tracked
+/// until it completes, along with its sleeps and HTTP requests. Cancelling
+/// the scope aborts its `async` tasks at their next `.await` point, skips
+/// anything that hasn't started yet, wakes sleeping tasks straight away and
+/// aborts in-flight requests. Blocking tasks that are already running can't
+/// be interrupted, but they stop waiting on anything the scope owns, and
+/// {@link TaskScope.join} will wait for them to finish. Aborting an
+/// {@link Instance} also terminates the workers of any threads still running
+/// shortly afterwards, since a thread blocked in `Atomics.wait()` would never
+/// wake up.
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct TaskScope {
+    pool: ThreadPool,
+    state: Arc<ScopeState>,
+}
+
+#[derive(Debug, Default)]
+struct ScopeState {
+    cancelled: AtomicBool,
+    /// Woken when the scope is cancelled.
+    cancellation: Notify,
+    pending: AtomicUsize,
+    idle: Notify,
+    next_id: AtomicU64,
+    /// Every task that hasn't finished yet. This always has `pending`
+    /// entries, and both are only updated while it is locked.
+    live: Mutex<BTreeMap<u64, LiveTask>>,
+}
+
+#[derive(Debug)]
+struct LiveTask {
+    kind: TaskKind,
+    /// When the task was spawned, as a Unix timestamp in milliseconds.
+    started_at: f64,
+    abort: Option<AbortHandle>,
+    /// The thread pool task a program's thread is running on, once it has
+    /// started.
+    running_as: Option<TaskId>,
+}
+
+/// The different things a scope keeps track of.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TaskKind {
+    Async,
+    Blocking,
+    /// A thread spawned by the WASIX program.
+    Thread,
+    Sleep,
+    Fetch,
+}
+
+/// A task which hasn't finished yet, as reported by [`TaskScope::live_tasks()`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TaskInfo {
+    pub(crate) id: u64,
+    pub(crate) kind: TaskKind,
+    pub(crate) started_at: f64,
+}
+
+#[wasm_bindgen]
+impl TaskScope {
+    /// The number of tasks that have been spawned and haven't finished yet.
+    #[wasm_bindgen(getter)]
+    pub fn pending(&self) -> usize {
+        self.state.pending.load(Ordering::SeqCst)
+    }
+
+    /// Has this scope been cancelled?
+    #[wasm_bindgen(getter)]
+    pub fn cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// List the tasks which haven't finished yet.
+    #[wasm_bindgen(js_name = "list")]
+    pub fn js_list(&self) -> Result<ListOfScopedTask, Error> {
+        let value = serde_wasm_bindgen::to_value(&self.live_tasks()).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// Cancel every task in the scope.
+    ///
+    /// Nothing new can be spawned in the scope afterwards.
+    pub fn cancel(&self) {
+        if self.state.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let aborts: Vec<AbortHandle> = self
+            .state
+            .live
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|task| task.abort.clone())
+            .collect();
+        tracing::debug!(
+            pending = self.pending(),
+            aborted = aborts.len(),
+            "Cancelling a task scope"
+        );
+
+        for handle in aborts {
+            handle.abort();
+        }
+        self.state.cancellation.notify_waiters();
+    }
+
+    /// Wait for every task in the scope to finish.
+    pub async fn join(&self) {
+        loop {
+            // Note: the Notified future needs to be created before checking
+            // the counter so we can't miss a wake-up.
+            let idle = self.state.idle.notified();
+            {
+                // Note: the counter only changes while the lock is held, so
+                // it must agree with the tasks we know about
+                let live = self.state.live.lock().unwrap();
+                debug_assert_eq!(live.len(), self.pending(), "Orphaned tasks");
+                if live.is_empty() {
+                    return;
+                }
+            }
+            idle.await;
+        }
+    }
+
+    /// Cancel the scope and wait for its tasks to wind down.
+    pub async fn shutdown(&self) {
+        self.cancel();
+        self.join().await;
+    }
+}
+
+impl TaskScope {
+    /// Cancel the scope and wait for its tasks to wind down, terminating the
+    /// workers of any threads which are still running after a short grace
+    /// period.
+    ///
+    /// A thread blocked in `Atomics.wait()` (e.g. on a mutex held by a thread
+    /// that was killed) can't be woken by cancelling the scope, and would
+    /// keep its worker forever.
+    pub(crate) async fn abort(&self) {
+        self.cancel();
+
+        let joined = Box::pin(self.join());
+        let grace = self.pool.sleep_now(STUCK_THREAD_GRACE);
+        if let futures::future::Either::Right((_, joined)) =
+            futures::future::select(joined, grace).await
+        {
+            self.terminate_threads();
+            joined.await;
+        }
+    }
+
+    /// Stop tracking every thread that is still running and terminate the
+    /// workers they are running on.
+    fn terminate_threads(&self) {
+        let mut live = self.state.live.lock().unwrap();
+        let stuck: Vec<(u64, TaskId)> = live
+            .iter()
+            .filter_map(|(&id, task)| task.running_as.map(|task_id| (id, task_id)))
+            .collect();
+
+        // Note: a terminated worker never drops its Tracked guard
+        for (id, _) in &stuck {
+            live.remove(id);
+            self.state.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        let finished = live.is_empty();
+        drop(live);
+
+        for (_, task_id) in &stuck {
+            tracing::warn!(
+                task.id = task_id.get(),
+                "Terminating a thread that didn't exit after its program was aborted"
+            );
+            self.pool.terminate_task(*task_id);
+        }
+        if finished && !stuck.is_empty() {
+            self.state.idle.notify_waiters();
+        }
+    }
+
+    pub(crate) fn new(pool: &ThreadPool) -> Self {
+        TaskScope {
+            pool: pool.clone(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Every task which hasn't finished yet, oldest first.
+    pub(crate) fn live_tasks(&self) -> Vec<TaskInfo> {
+        self.state
+            .live
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, task)| TaskInfo {
+                id,
+                kind: task.kind,
+                started_at: task.started_at,
+            })
+            .collect()
+    }
+
+    /// Run an `async` function to completion on the threadpool as part of
+    /// this scope.
+    pub(crate) fn spawn(
+        &self,
+        task: Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>,
+    ) -> Result<(), WasiThreadError> {
+        let (handle, registration) = AbortHandle::new_pair();
+        let Some(tracked) = self.track(TaskKind::Async, Some(handle)) else {
+            return Ok(());
+        };
+
+        self.pool.spawn(Box::new(move || {
+            Box::pin(async move {
+                if tracked.is_cancelled() {
+                    return;
+                }
+                let _ = Abortable::new(task(), registration).await;
+                drop(tracked);
+            })
+        }))
+    }
+
+    /// Run a blocking function on the threadpool as part of this scope.
+    pub(crate) fn spawn_blocking(
+        &self,
+        task: Box<dyn FnOnce() + Send + 'static>,
+    ) -> Result<(), WasiThreadError> {
+        let Some(tracked) = self.track(TaskKind::Blocking, None) else {
+            return Ok(());
+        };
+
+        self.pool.task_dedicated(Box::new(move || {
+            if !tracked.is_cancelled() {
+                task();
+            }
+        }))
+    }
+
+    /// Start tracking a new task, or `None` if the scope has been cancelled.
+    fn track(&self, kind: TaskKind, abort: Option<AbortHandle>) -> Option<Tracked> {
+        // Note: the lock makes sure cancel() either sees the new task or we
+        // see that the scope was cancelled
+        let mut live = self.state.live.lock().unwrap();
+        if self.cancelled() {
+            tracing::debug!(?kind, "Ignoring a task spawned in a cancelled scope");
+            return None;
+        }
+
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        let task = LiveTask {
+            kind,
+            started_at: js_sys::Date::now(),
+            abort,
+            running_as: None,
+        };
+        live.insert(id, task);
+        self.state.pending.fetch_add(1, Ordering::SeqCst);
+
+        Some(Tracked {
+            id,
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+/// An [`HttpClient`] whose requests belong to a [`TaskScope`], so they are
+/// aborted when it is cancelled.
+#[derive(Debug, Clone)]
+pub(crate) struct ScopedHttpClient {
+    pub(crate) inner: DynHttpClient,
+    pub(crate) scope: TaskScope,
+}
+
+impl HttpClient for ScopedHttpClient {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, anyhow::Error>> {
+        let (handle, registration) = AbortHandle::new_pair();
+        let Some(tracked) = self.scope.track(TaskKind::Fetch, Some(handle)) else {
+            return Box::pin(async { Err(cancelled()) });
+        };
+
+        let response = Abortable::new(self.inner.request(request), registration);
+        Box::pin(async move {
+            let result = response.await;
+            drop(tracked);
+            result.unwrap_or_else(|_| Err(cancelled()))
+        })
+    }
+}
+
+fn cancelled() -> anyhow::Error {
+    anyhow::anyhow!("The request was aborted because its program was stopped")
+}
+
+/// A guard representing a task that is still in flight.
+#[derive(Debug)]
+struct Tracked {
+    id: u64,
+    state: Arc<ScopeState>,
+}
+
+impl Tracked {
+    fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Remember which thread pool task a program's thread is running on, so
+    /// its worker can be terminated if it gets stuck.
+    fn running(&self) {
+        if let Some(task) = self.state.live.lock().unwrap().get_mut(&self.id) {
+            task.running_as = TaskId::current();
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut live = self.state.live.lock().unwrap();
+        // Note: the task is already gone if its worker was terminated
+        let finished = live.remove(&self.id).is_some()
+            && self.state.pending.fetch_sub(1, Ordering::SeqCst) == 1;
+        drop(live);
+
+        if finished {
+            self.state.idle.notify_waiters();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualTaskManager for TaskScope {
+    fn sleep_now(
+        &self,
+        time: Duration,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>> {
+        let Some(tracked) = self.track(TaskKind::Sleep, None) else {
+            return Box::pin(async {});
+        };
+        let sleep = self.pool.sleep_now(time);
+
+        Box::pin(async move {
+            // Note: cancelling the scope wakes the sleeper straight away, so
+            // a thread blocked on the sleep doesn't keep its worker busy
+            let state = Arc::clone(&tracked.state);
+            let cancellation = state.cancellation.notified();
+            if !tracked.is_cancelled() {
+                futures::future::select(sleep, Box::pin(cancellation)).await;
+            }
+            drop(tracked);
+        })
+    }
+
+    fn task_shared(
+        &self,
+        task: Box<
+            dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + 'static,
+        >,
+    ) -> Result<(), WasiThreadError> {
+        self.spawn(Box::new(move || Box::pin(async move { task().await })))
+    }
+
+    fn task_wasm(&self, mut task: TaskWasm<'_, '_>) -> Result<(), WasiThreadError> {
+        let Some(tracked) = self.track(TaskKind::Thread, None) else {
+            return Ok(());
+        };
+
+        let run = task.run;
+        task.run = Box::new(move |props| {
+            if !tracked.is_cancelled() {
+                tracked.running();
+                run(props);
+            }
+        });
+
+        self.pool.task_wasm(task)
+    }
+
+    fn task_dedicated(
+        &self,
+        task: Box<dyn FnOnce() + Send + 'static>,
+    ) -> Result<(), WasiThreadError> {
+        self.spawn_blocking(task)
+    }
+
+    fn thread_parallelism(&self) -> Result<usize, WasiThreadError> {
+        self.pool.thread_parallelism()
+    }
+
+    fn spawn_with_module(
+        &self,
+        module: wasmer::Module,
+        task: Box<dyn FnOnce(wasmer::Module) + Send + 'static>,
+    ) -> Result<(), WasiThreadError> {
+        let Some(tracked) = self.track(TaskKind::Thread, None) else {
+            return Ok(());
+        };
+
+        self.pool.spawn_with_module(
+            module,
+            Box::new(move |module| {
+                if !tracked.is_cancelled() {
+                    tracked.running();
+                    task(module);
+                }
+            }),
+        )
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const SCOPED_TASK_TYPE_DECLARATION: &str = r#"
+/**
+ * Something a {@link TaskScope} is waiting on.
+ *
+ * - `"async"` and `"blocking"` are tasks spawned on the thread pool
+ * - `"thread"` is a thread the program spawned
+ * - `"sleep"` is a task waiting for a timer
+ * - `"fetch"` is an HTTP request
+ */
+export type ScopedTask = {
+    id: number;
+    kind: "async" | "blocking" | "thread" | "sleep" | "fetch";
+    /** When the task was started, as a Unix timestamp in milliseconds. */
+    startedAt: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ScopedTask[]")]
+    pub type ListOfScopedTask;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::oneshot;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn join_waits_for_every_task() {
+        let scope = TaskScope::new(&ThreadPool::new());
+        let (sender, receiver) = oneshot::channel();
+
+        scope
+            .spawn(Box::new(move || {
+                Box::pin(async move {
+                    sender.send(42_u32).unwrap();
+                })
+            }))
+            .unwrap();
+        scope.join().await;
+
+        assert_eq!(scope.pending(), 0);
+        assert_eq!(receiver.await.unwrap(), 42);
+    }
+
+    #[wasm_bindgen_test]
+    async fn cancelled_scopes_reject_new_tasks() {
+        let scope = TaskScope::new(&ThreadPool::new());
+        let (sender, receiver) = oneshot::channel::<()>();
+
+        scope.cancel();
+        scope
+            .spawn_blocking(Box::new(move || drop(sender)))
+            .unwrap();
+
+        assert!(scope.cancelled());
+        assert_eq!(scope.pending(), 0);
+        // The task was dropped without ever running
+        assert!(receiver.await.is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn shutting_down_leaves_no_orphans() {
+        let scope = TaskScope::new(&ThreadPool::new());
+        let sleep = scope.sleep_now(Duration::from_secs(60 * 60));
+
+        scope
+            .spawn(Box::new(move || Box::pin(async move { sleep.await })))
+            .unwrap();
+        scope
+            .spawn(Box::new(|| {
+                Box::pin(async { futures::future::pending::<()>().await })
+            }))
+            .unwrap();
+        let kinds: Vec<_> = scope.live_tasks().iter().map(|task| task.kind).collect();
+        assert_eq!(kinds, [TaskKind::Sleep, TaskKind::Async, TaskKind::Async]);
+
+        // This would hang if the sleep or the never-ending task were left
+        // behind
+        scope.shutdown().await;
+
+        assert_eq!(scope.pending(), 0);
+        assert!(scope.live_tasks().is_empty());
+    }
+}
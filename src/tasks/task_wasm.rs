@@ -19,7 +19,7 @@ use wasmer_wasix::{
     InstanceSnapshot, WasiEnv, WasiFunctionEnv, WasiThreadError,
 };
 
-use crate::tasks::SchedulerMessage;
+use crate::tasks::{module_reuse::ModuleKey, SchedulerMessage};
 
 pub(crate) fn to_scheduler_message(
     task: TaskWasm<'_, '_>,
@@ -36,6 +36,7 @@ pub(crate) fn to_scheduler_message(
     } = task;
 
     let module_bytes = module.serialize().unwrap();
+    let module_key = ModuleKey::of(&module_bytes);
     let snapshot = snapshot.map(InstanceSnapshot::clone);
 
     let (memory_ty, memory, run_type) = match spawn_type {
@@ -94,6 +95,7 @@ pub(crate) fn to_scheduler_message(
         run_type,
         env,
         module_bytes,
+        module_key,
         snapshot,
         update_layout,
         result: None,
@@ -148,22 +150,9 @@ fn copy_memory(memory: &JsValue, ty: MemoryType) -> Result<JsValue, WasiThreadEr
 
     tracing::trace!(src_size, "memory copy started");
 
-    {
-        let mut offset = 0_u64;
-        let mut chunk = [0u8; 40960];
-        while offset < src_size {
-            let remaining = src_size - offset;
-            let sublen = remaining.min(chunk.len() as u64);
-            let end = offset.checked_add(sublen).unwrap();
-            src_view
-                .subarray(offset.try_into().unwrap(), end.try_into().unwrap())
-                .copy_to(&mut chunk[..sublen as usize]);
-            dst_view
-                .subarray(offset.try_into().unwrap(), end.try_into().unwrap())
-                .copy_from(&chunk[..sublen as usize]);
-            offset += sublen;
-        }
-    }
+    // Note: Copying directly between the two buffers means each byte is only
+    // copied once, rather than bouncing through our own linear memory.
+    dst_view.set(&src_view, 0);
 
     Ok(new_memory.into())
 }
@@ -190,6 +179,9 @@ pub(crate) struct SpawnWasm {
     /// The raw bytes for the WebAssembly module being run.
     #[derivative(Debug(format_with = "crate::utils::hidden"))]
     module_bytes: Bytes,
+    /// Identifies the module, so workers can reuse it if they've seen it
+    /// before.
+    module_key: ModuleKey,
     /// A snapshot of the instance, if we are forking an existing instance.
     snapshot: Option<InstanceSnapshot>,
     /// An asynchronous callback which is used to run asyncify methods. The
@@ -208,6 +200,10 @@ impl SpawnWasm {
         self.module_bytes.clone()
     }
 
+    pub(crate) fn module_key(&self) -> ModuleKey {
+        self.module_key
+    }
+
     pub(crate) fn shared_memory_type(&self) -> Option<MemoryType> {
         match self.run_type {
             WasmMemoryType::ShareMemory(ty) => Some(ty),
@@ -242,6 +238,7 @@ impl ReadySpawnWasm {
             run_type,
             env,
             module_bytes,
+            module_key,
             snapshot,
             update_layout,
             result,
@@ -254,6 +251,7 @@ impl ReadySpawnWasm {
             wasm_module,
             wasm_memory,
             module_bytes,
+            module_key,
             env,
             run_type,
             snapshot,
@@ -277,13 +275,16 @@ fn build_ctx_and_store(
     module: js_sys::WebAssembly::Module,
     memory: JsValue,
     module_bytes: Bytes,
+    module_key: ModuleKey,
     env: WasiEnv,
     run_type: WasmMemoryType,
     snapshot: Option<InstanceSnapshot>,
     update_layout: bool,
 ) -> Option<(WasiFunctionEnv, Store)> {
-    // Compile the web assembly module
-    let module: Module = (module, module_bytes).into();
+    // Compile the web assembly module, reusing the one from last time if this
+    // worker has already run it (e.g. a shell running the same command in a
+    // loop)
+    let module: Module = crate::tasks::module_reuse::module(module, module_bytes, module_key);
 
     // Make a fake store which will hold the memory we just transferred
     let mut temp_store = env.runtime().new_store();
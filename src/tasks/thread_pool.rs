@@ -1,28 +1,107 @@
-use std::{fmt::Debug, future::Future, pin::Pin};
+use std::{fmt::Debug, future::Future, num::NonZeroUsize, pin::Pin};
 
 use futures::future::LocalBoxFuture;
 use instant::Duration;
-use wasm_bindgen_futures::JsFuture;
 use wasmer_wasix::{runtime::task_manager::TaskWasm, VirtualTaskManager, WasiThreadError};
 
 use crate::{
-    tasks::{Scheduler, SchedulerMessage},
-    utils::GlobalScope,
+    events::{EventChannel, RuntimeEvent},
+    tasks::{
+        BackgroundPolicy, BlockedWorkerPolicy, PanicPolicy, Scheduler, SchedulerMessage, TaskId,
+        WatchdogOptions,
+    },
 };
 
+/// Settings used when creating a [`ThreadPool`].
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub(crate) struct PoolOptions {
+    /// What to do when a worker panics.
+    pub(crate) panic_policy: PanicPolicy,
+    /// How many threads the pool should aim to run in parallel. Defaults to
+    /// the value tuned by [`crate::fingerprint`], or [`default_parallelism()`].
+    pub(crate) threads: Option<NonZeroUsize>,
+    /// What to do with periodic tasks while the page is hidden.
+    pub(crate) background_policy: BackgroundPolicy,
+    /// How to detect (and respond to) every worker being blocked.
+    pub(crate) watchdog: WatchdogOptions,
+    /// What to do when the page's Content Security Policy blocks workers.
+    pub(crate) on_worker_blocked: BlockedWorkerPolicy,
+}
+
 /// A handle to a threadpool backed by Web Workers.
+///
+/// This is the [`VirtualTaskManager`] used by the JavaScript API, but other
+/// `wasm-bindgen` crates can use it directly to run work on the same kind of
+/// worker pool.
+///
+/// ```rust,ignore
+/// use std::num::NonZeroUsize;
+///
+/// let pool = wasmer_js::ThreadPool::with_threads(NonZeroUsize::new(2).unwrap());
+/// pool.spawn_blocking(|| expensive_computation())?;
+/// pool.sleep(Duration::from_millis(100)).await;
+/// ```
+///
+/// Handles are cheap to clone and every clone talks to the same workers,
+/// which are only started once there is work for them.
 #[derive(Debug, Clone)]
 pub struct ThreadPool {
     scheduler: Scheduler,
+    parallelism: NonZeroUsize,
 }
 
 impl ThreadPool {
+    /// Create a thread pool sized to the machine it is running on (see
+    /// [`ThreadPool::parallelism()`]).
+    ///
+    /// This must be called from a thread with access to the DOM or a
+    /// `WorkerGlobalScope`, because that is where the workers get started
+    /// from.
     pub fn new() -> Self {
-        let sender = Scheduler::spawn();
-        ThreadPool { scheduler: sender }
+        ThreadPool::with_options(PoolOptions::default())
+    }
+
+    /// Create a thread pool which aims to run `threads` tasks in parallel.
+    pub fn with_threads(threads: NonZeroUsize) -> Self {
+        ThreadPool::with_options(PoolOptions {
+            threads: Some(threads),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn with_options(options: PoolOptions) -> Self {
+        let parallelism = options
+            .threads
+            .or(crate::fingerprint::tuning().threads)
+            .unwrap_or_else(|| {
+                default_parallelism(crate::utils::GlobalScope::current().hardware_concurrency())
+            });
+        let sender = Scheduler::spawn(
+            options.panic_policy,
+            options.background_policy,
+            options.watchdog,
+            options.on_worker_blocked,
+            parallelism,
+        );
+
+        ThreadPool {
+            scheduler: sender,
+            parallelism,
+        }
+    }
+
+    /// The number of threads this pool aims to run in parallel.
+    ///
+    /// More workers may be started while existing ones are blocked, so this
+    /// is a target rather than a hard limit.
+    pub fn parallelism(&self) -> NonZeroUsize {
+        self.parallelism
     }
 
     /// Run an `async` function to completion on the threadpool.
+    ///
+    /// The future doesn't need to be `Send` because it never leaves the
+    /// worker it was created on, but it must not block.
     pub fn spawn(
         &self,
         task: Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>,
@@ -32,6 +111,83 @@ impl ThreadPool {
         Ok(())
     }
 
+    /// Run a function which may block on a worker of its own.
+    pub fn spawn_blocking(
+        &self,
+        task: impl FnOnce() + Send + 'static,
+    ) -> Result<(), WasiThreadError> {
+        self.send(SchedulerMessage::SpawnBlocking(Box::new(task)));
+
+        Ok(())
+    }
+
+    /// Wait for `duration` to elapse.
+    ///
+    /// Unlike a plain `setTimeout()`, this is safe to `.await` from inside a
+    /// blocking task on a worker because the timer is kept by the scheduler.
+    pub async fn sleep(&self, duration: Duration) {
+        self.sleep_now(duration).await
+    }
+
+    /// Run an `async` function on the threadpool once `delay` has elapsed.
+    pub(crate) fn spawn_after(
+        &self,
+        delay: Duration,
+        task: Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>,
+    ) {
+        self.send(SchedulerMessage::SpawnAfter { delay, task });
+    }
+
+    /// Call `task` every `interval` until it returns `false`.
+    ///
+    /// This is meant for housekeeping (e.g. evicting caches or flushing
+    /// metrics). The function runs on the scheduler's thread, so it should
+    /// only do a small amount of work or hand it off with
+    /// [`ThreadPool::spawn()`].
+    pub(crate) fn spawn_periodic(&self, interval: Duration, task: Box<dyn FnMut() -> bool + Send>) {
+        self.send(SchedulerMessage::SpawnPeriodic { interval, task });
+    }
+
+    /// Call `task` once on the scheduler's thread, which is where the
+    /// callbacks the host gave us live.
+    ///
+    /// Like [`ThreadPool::spawn_periodic()`], `task` should be quick.
+    pub(crate) fn run_on_scheduler(&self, task: Box<dyn FnOnce() + Send>) {
+        self.send(SchedulerMessage::RunOnScheduler(task));
+    }
+
+    /// Terminate the worker running a blocking task, if it hasn't finished.
+    pub(crate) fn terminate_task(&self, task_id: TaskId) {
+        self.send(SchedulerMessage::TerminateTask { task_id });
+    }
+
+    /// Terminate any workers that aren't doing anything.
+    pub(crate) fn shed_idle_workers(&self) {
+        self.send(SchedulerMessage::ShedIdleWorkers);
+    }
+
+    /// Keep at most `workers` workers around once they go idle, or remove
+    /// the limit with `None`.
+    pub(crate) fn set_worker_share(&self, workers: Option<NonZeroUsize>) {
+        let workers = workers.map(|n| u32::try_from(n.get()).unwrap_or(u32::MAX));
+        self.send(SchedulerMessage::SetWorkerShare { workers });
+    }
+
+    /// Terminate every worker. Any work submitted afterwards will fail.
+    pub(crate) fn shutdown(&self) {
+        self.send(SchedulerMessage::Shutdown);
+    }
+
+    /// Dispatch an event to the runtime's listeners from any thread.
+    pub(crate) fn emit(&self, event: RuntimeEvent) {
+        self.send(SchedulerMessage::Emit(event));
+    }
+
+    /// The channel this thread pool's events are dispatched on.
+    pub(crate) fn events(&self) -> EventChannel {
+        self.scheduler.events()
+    }
+
     pub(crate) fn send(&self, msg: SchedulerMessage) {
         self.scheduler.send(msg).expect("scheduler is dead");
     }
@@ -49,24 +205,20 @@ impl VirtualTaskManager for ThreadPool {
     ) -> Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>> {
         let (tx, rx) = tokio::sync::oneshot::channel();
 
-        let time = if time.as_millis() < i32::MAX as u128 {
-            time.as_millis() as i32
-        } else {
-            i32::MAX
-        };
-
         // Note: We can't use wasm_bindgen_futures::spawn_local() directly
         // because we might be invoked from inside a syscall. This causes a
         // deadlock because the syscall will block block until the future
         // resolves, but the JsFuture will never get a chance to mark itself as
         // resolved because the JavaScript VM is still blocked by the syscall.
-        let _ = self.task_dedicated(Box::new(move || {
-            wasm_bindgen_futures::spawn_local(async move {
-                let global = GlobalScope::current();
-                let _ = JsFuture::from(global.sleep(time)).await;
-                let _ = tx.send(());
-            })
-        }));
+        // Instead, we let the scheduler's thread keep track of the timer.
+        self.spawn_after(
+            time,
+            Box::new(move || {
+                Box::pin(async move {
+                    let _ = tx.send(());
+                })
+            }),
+        );
 
         Box::pin(async move {
             let _ = rx.await;
@@ -100,17 +252,12 @@ impl VirtualTaskManager for ThreadPool {
         &self,
         task: Box<dyn FnOnce() + Send + 'static>,
     ) -> Result<(), WasiThreadError> {
-        self.send(SchedulerMessage::SpawnBlocking(task));
-
-        Ok(())
+        self.spawn_blocking(task)
     }
 
     /// Returns the amount of parallelism that is possible on this platform
     fn thread_parallelism(&self) -> Result<usize, WasiThreadError> {
-        match crate::utils::GlobalScope::current().hardware_concurrency() {
-            Some(n) => Ok(n.get()),
-            None => Err(WasiThreadError::Unsupported),
-        }
+        Ok(self.parallelism.get())
     }
 
     fn spawn_with_module(
@@ -161,6 +308,38 @@ mod tests {
         assert_eq!(exports, 5);
     }
 
+    #[wasm_bindgen_test]
+    async fn delayed_and_periodic_tasks() {
+        let pool = ThreadPool::new();
+        let (sender, receiver) = oneshot::channel();
+        let (ticks_tx, mut ticks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        pool.spawn_after(
+            Duration::from_millis(10),
+            Box::new(move || {
+                Box::pin(async move {
+                    sender.send(()).unwrap();
+                })
+            }),
+        );
+        let mut remaining = 3;
+        pool.spawn_periodic(
+            Duration::from_millis(5),
+            Box::new(move || {
+                remaining -= 1;
+                ticks_tx.send(remaining).unwrap();
+                remaining > 0
+            }),
+        );
+
+        receiver.await.unwrap();
+        assert_eq!(ticks_rx.recv().await, Some(2));
+        assert_eq!(ticks_rx.recv().await, Some(1));
+        assert_eq!(ticks_rx.recv().await, Some(0));
+        // The timer stops once the function returns false
+        assert_eq!(ticks_rx.recv().await, None);
+    }
+
     #[wasm_bindgen_test]
     async fn spawned_tasks_can_communicate_with_the_main_thread() {
         let pool = ThreadPool::new();
@@ -179,6 +358,19 @@ mod tests {
         assert_eq!(result, 42);
     }
 
+    #[wasm_bindgen_test]
+    async fn rust_api_runs_blocking_work_and_sleeps() {
+        let pool = ThreadPool::with_threads(NonZeroUsize::new(2).unwrap());
+        assert_eq!(pool.parallelism().get(), 2);
+        let (sender, receiver) = oneshot::channel();
+
+        pool.spawn_blocking(move || sender.send(7_u32).unwrap())
+            .unwrap();
+        pool.sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(receiver.await.unwrap(), 7);
+    }
+
     /// This is a regression test for [#355].
     ///
     /// Here is a description of the original bug:
@@ -229,3 +421,39 @@ mod tests {
         }
     }
 }
+
+/// Pick a sensible number of threads given the value of
+/// `navigator.hardwareConcurrency`.
+///
+/// One core is left for the main thread (which renders the page and runs the
+/// scheduler), and the result is capped so big machines don't spin up more
+/// workers than the browser is happy to run.
+pub(crate) fn default_parallelism(hardware_concurrency: Option<NonZeroUsize>) -> NonZeroUsize {
+    const FALLBACK: usize = 4;
+    const MAX: usize = 16;
+
+    let threads = match hardware_concurrency {
+        Some(cores) => cores.get().saturating_sub(1).clamp(1, MAX),
+        None => FALLBACK,
+    };
+
+    NonZeroUsize::new(threads).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn parallelism_scales_with_hardware_concurrency() {
+        let parallelism = |cores: usize| default_parallelism(NonZeroUsize::new(cores)).get();
+
+        assert_eq!(parallelism(0), 4);
+        assert_eq!(parallelism(1), 1);
+        assert_eq!(parallelism(2), 1);
+        assert_eq!(parallelism(8), 7);
+        assert_eq!(parallelism(64), 16);
+    }
+}
@@ -1,33 +1,73 @@
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
-use crate::tasks::{AsyncJob, BlockingJob, Notification, PostMessagePayload, WorkerMessage};
+use crate::tasks::{
+    AsyncJob, BlockingJob, Handshake, Notification, PostMessagePayload, TaskId, WorkerMessage,
+};
 
 /// The Rust state for a worker in the threadpool.
 #[wasm_bindgen(skip_typescript)]
 #[derive(Debug)]
 pub struct ThreadPoolWorker {
     id: u32,
+    /// Set when the scheduler runs tasks on its own thread because it wasn't
+    /// allowed to start workers, in which case there is nobody to tell when
+    /// we are busy.
+    inline: bool,
 }
 
 impl ThreadPoolWorker {
+    /// Run tasks directly on the scheduler's thread.
+    pub(super) fn inline() -> Self {
+        ThreadPoolWorker {
+            id: 0,
+            inline: true,
+        }
+    }
+
     fn busy(&self) -> impl Drop {
-        struct BusyGuard;
+        struct BusyGuard {
+            inline: bool,
+        }
         impl Drop for BusyGuard {
             fn drop(&mut self) {
-                let _ = WorkerMessage::MarkIdle.emit();
+                if !self.inline {
+                    let _ = WorkerMessage::MarkIdle.emit();
+                }
             }
         }
 
-        let _ = WorkerMessage::MarkBusy.emit();
+        if !self.inline {
+            let _ = WorkerMessage::MarkBusy.emit();
+        }
 
-        BusyGuard
+        BusyGuard {
+            inline: self.inline,
+        }
     }
 
-    #[tracing::instrument(level = "debug", skip_all, fields(worker.id = self.id))]
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(worker.id = self.id, task.id = tracing::field::Empty),
+    )]
     pub async fn handle(&self, msg: JsValue) -> Result<(), crate::utils::Error> {
+        let task_id = PostMessagePayload::task_id(&msg);
         // Safety: The message was created using PostMessagePayload::to_js()
         let msg = unsafe { PostMessagePayload::try_from_js(msg)? };
 
+        match task_id {
+            Some(id) => {
+                tracing::Span::current().record("task.id", id.get());
+                id.scope(self.dispatch(msg)).await
+            }
+            None => self.dispatch(msg).await,
+        }
+    }
+
+    pub(super) async fn dispatch(
+        &self,
+        msg: PostMessagePayload,
+    ) -> Result<(), crate::utils::Error> {
         tracing::trace!(?msg, "Handling a message");
 
         match msg {
@@ -48,6 +88,12 @@ impl ThreadPoolWorker {
             }
         }
 
+        // Note: the scheduler won't terminate a worker while it still has
+        // async tasks running, so it needs to hear when they are done
+        if !self.inline {
+            let _ = WorkerMessage::AsyncTaskDone.emit();
+        }
+
         Ok(())
     }
 
@@ -78,9 +124,20 @@ impl ThreadPoolWorker {
 
 #[wasm_bindgen]
 impl ThreadPoolWorker {
+    /// Create the worker's state, letting the scheduler know what we are
+    /// running so it can reject us if we aren't compatible.
+    ///
+    /// The `protocol_version` is provided by the `worker.js` bootstrap script.
     #[wasm_bindgen(constructor)]
-    pub fn new(id: u32) -> ThreadPoolWorker {
-        ThreadPoolWorker { id }
+    pub fn new(id: u32, protocol_version: Option<u32>) -> ThreadPoolWorker {
+        crate::tasks::panics::mark_pool_worker();
+
+        let handshake = Handshake::current(protocol_version);
+        if let Err(e) = WorkerMessage::Handshake(handshake).emit() {
+            tracing::warn!(error = &*e.into_anyhow(), "Unable to send the handshake");
+        }
+
+        ThreadPoolWorker { id, inline: false }
     }
 
     #[wasm_bindgen(js_name = "handle")]
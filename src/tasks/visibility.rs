@@ -0,0 +1,212 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use instant::Duration;
+use tokio::sync::oneshot;
+use wasm_bindgen::{closure::Closure, JsCast};
+
+use crate::{
+    tasks::{Scheduler, SchedulerMessage},
+    utils::{Error, GlobalScope},
+};
+
+/// How often throttled periodic tasks may run while the page is hidden.
+const THROTTLED_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the scheduler should do with non-critical work (i.e. periodic
+/// housekeeping) while the page is hidden.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum BackgroundPolicy {
+    /// Keep going as normal, and let the browser throttle us however it
+    /// likes.
+    #[default]
+    Run,
+    /// Run periodic tasks at most once every [`THROTTLED_INTERVAL`].
+    Throttle,
+    /// Hold periodic tasks back until the page is visible again.
+    Pause,
+}
+
+impl BackgroundPolicy {
+    pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "run" => Ok(BackgroundPolicy::Run),
+            "throttle" => Ok(BackgroundPolicy::Throttle),
+            "pause" => Ok(BackgroundPolicy::Pause),
+            other => {
+                let msg = format!("\"{other}\" isn't a valid value for \"whenHidden\"");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+/// Tracks whether the page the scheduler lives on is visible.
+///
+/// Only schedulers running on a page's main thread can see its visibility.
+/// Anywhere else, we always consider ourselves visible.
+#[derive(Debug, Default)]
+pub(super) struct Visibility {
+    policy: BackgroundPolicy,
+    hidden: Cell<bool>,
+    /// Periodic tasks waiting for the page to become visible again.
+    waiting: RefCell<Vec<oneshot::Sender<()>>>,
+    listener: RefCell<Option<Listener>>,
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Listener {
+    document: web_sys::Document,
+    #[derivative(Debug = "ignore")]
+    callback: Closure<dyn FnMut()>,
+}
+
+impl Visibility {
+    pub(super) fn new(policy: BackgroundPolicy) -> Self {
+        Visibility {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Start listening for `visibilitychange` events, letting the scheduler
+    /// know whenever the page is hidden or shown.
+    pub(super) fn watch(&self, mailbox: Scheduler) {
+        let GlobalScope::Window(window) = GlobalScope::current() else {
+            return;
+        };
+        let Some(document) = window.document() else {
+            return;
+        };
+
+        self.hidden.set(document.hidden());
+
+        let doc = document.clone();
+        let callback = Closure::<dyn FnMut()>::new(move || {
+            let hidden = doc.hidden();
+            if let Err(e) = mailbox.send(SchedulerMessage::VisibilityChanged { hidden }) {
+                tracing::warn!(
+                    error = &*e,
+                    "Unable to notify the scheduler about visibility"
+                );
+            }
+        });
+
+        if let Err(e) = document
+            .add_event_listener_with_callback("visibilitychange", callback.as_ref().unchecked_ref())
+        {
+            tracing::warn!(error = ?e, "Unable to listen for visibility changes");
+            return;
+        }
+
+        *self.listener.borrow_mut() = Some(Listener { document, callback });
+    }
+
+    /// Stop listening for `visibilitychange` events and release anything
+    /// that was waiting for the page to be shown.
+    pub(super) fn stop_watching(&self) {
+        if let Some(Listener { document, callback }) = self.listener.borrow_mut().take() {
+            let _ = document.remove_event_listener_with_callback(
+                "visibilitychange",
+                callback.as_ref().unchecked_ref(),
+            );
+        }
+        self.waiting.borrow_mut().clear();
+    }
+
+    pub(super) fn is_hidden(&self) -> bool {
+        self.hidden.get()
+    }
+
+    /// Record a visibility change, returning `false` if nothing changed.
+    pub(super) fn set_hidden(&self, hidden: bool) -> bool {
+        if self.hidden.replace(hidden) == hidden {
+            return false;
+        }
+
+        tracing::debug!(hidden, policy = ?self.policy, "The page's visibility changed");
+
+        if !hidden {
+            for waiter in self.waiting.borrow_mut().drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+
+        true
+    }
+
+    /// How long a periodic task should wait before its next tick.
+    fn periodic_delay(&self, interval: Duration) -> Duration {
+        match self.policy {
+            BackgroundPolicy::Throttle if self.is_hidden() => interval.max(THROTTLED_INTERVAL),
+            _ => interval,
+        }
+    }
+
+    /// Wait for the next tick of a periodic task.
+    pub(super) async fn next_tick(self: &Rc<Self>, interval: Duration) {
+        // Note: Zero-interval tasks are just a way to get something running on
+        // the scheduler's thread, so they are never held back.
+        if interval.is_zero() {
+            super::scheduler::sleep(interval).await;
+            return;
+        }
+
+        super::scheduler::sleep(self.periodic_delay(interval)).await;
+
+        while self.policy == BackgroundPolicy::Pause && self.is_hidden() {
+            let (sender, receiver) = oneshot::channel();
+            self.waiting.borrow_mut().push(sender);
+            if receiver.await.is_err() {
+                // We stopped watching, so there is nothing to wait for
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_throttle_while_hidden() {
+        let interval = Duration::from_millis(100);
+        let visibility = Visibility::new(BackgroundPolicy::Throttle);
+
+        assert_eq!(visibility.periodic_delay(interval), interval);
+
+        assert!(visibility.set_hidden(true));
+        assert!(!visibility.set_hidden(true));
+        assert_eq!(visibility.periodic_delay(interval), THROTTLED_INTERVAL);
+
+        visibility.set_hidden(false);
+        assert_eq!(visibility.periodic_delay(interval), interval);
+        assert!(BackgroundPolicy::parse("sometimes").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn paused_tasks_resume_when_shown() {
+        let visibility = Rc::new(Visibility::new(BackgroundPolicy::Pause));
+        visibility.set_hidden(true);
+
+        let (sender, receiver) = oneshot::channel();
+        let v = Rc::clone(&visibility);
+        wasm_bindgen_futures::spawn_local(async move {
+            v.next_tick(Duration::from_millis(1)).await;
+            let _ = sender.send(());
+        });
+
+        // Give the task a chance to start waiting
+        super::super::scheduler::sleep(Duration::from_millis(20)).await;
+        assert_eq!(visibility.waiting.borrow().len(), 1);
+
+        visibility.set_hidden(false);
+        receiver.await.unwrap();
+    }
+}
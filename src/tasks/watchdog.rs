@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+
+use instant::{Duration, Instant};
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+
+use crate::{events::StalledWorker, tasks::TaskId, utils::Error};
+
+/// How often the scheduler checks whether the pool has stalled.
+pub(super) const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long every worker needs to be blocked before we suspect a deadlock.
+const DEFAULT_STALL_AFTER: Duration = Duration::from_secs(10);
+/// How long extra workers started in response to a stall are kept around.
+pub(super) const BOOST_DURATION: Duration = Duration::from_secs(30);
+
+/// Settings for detecting a thread pool where every worker is blocked.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct WatchdogOptions {
+    /// How long every worker must be blocked before a stall is reported, or
+    /// `None` to never check.
+    pub(crate) stall_after: Option<Duration>,
+    /// How many extra workers to start when a stall is detected.
+    pub(crate) boost: usize,
+}
+
+impl Default for WatchdogOptions {
+    fn default() -> Self {
+        WatchdogOptions {
+            stall_after: Some(DEFAULT_STALL_AFTER),
+            boost: 0,
+        }
+    }
+}
+
+impl WatchdogOptions {
+    /// Parse the `deadlockDetection` option, which is either a boolean or an
+    /// object.
+    pub(crate) fn parse(value: JsValue) -> Result<Self, Error> {
+        if let Some(enabled) = value.as_bool() {
+            return Ok(if enabled {
+                WatchdogOptions::default()
+            } else {
+                WatchdogOptions {
+                    stall_after: None,
+                    boost: 0,
+                }
+            });
+        }
+
+        #[derive(Deserialize)]
+        struct Init {
+            after: Option<f64>,
+            boost: Option<usize>,
+        }
+
+        let Init { after, boost } = serde_wasm_bindgen::from_value(value).map_err(Error::js)?;
+
+        let stall_after = match after {
+            Some(ms) if ms.is_finite() && ms > 0.0 => Duration::from_millis(ms as u64),
+            Some(ms) => {
+                let msg = format!("The deadlock detection timeout must be positive, not {ms}");
+                return Err(Error::js(js_sys::RangeError::new(&msg)));
+            }
+            None => DEFAULT_STALL_AFTER,
+        };
+
+        Ok(WatchdogOptions {
+            stall_after: Some(stall_after),
+            boost: boost.unwrap_or(0),
+        })
+    }
+}
+
+/// Keeps an eye on what each worker is doing so we can tell when the pool
+/// has wedged itself.
+///
+/// The classic failure mode is a worker that picks up a blocking task while
+/// async tasks are still queued on its event loop. If the blocking task
+/// waits (i.e. `Atomics.wait()`) on one of those async tasks, neither can
+/// make progress. Once every worker is stuck like that, nothing else in the
+/// pool can run either.
+#[derive(Debug, Default)]
+pub(super) struct Watchdog {
+    options: WatchdogOptions,
+    workers: BTreeMap<u32, Activity>,
+    /// Set once the current stall has been reported, so we only report it
+    /// once.
+    reported: bool,
+    /// Workers started to break a stall and when they should be retired.
+    boosted: Option<(Instant, Vec<u32>)>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Activity {
+    /// When the worker was last seen going into a blocking task.
+    busy_since: Option<Instant>,
+    /// Async tasks handed to the worker since it last came out of a blocking
+    /// task. We don't hear about async tasks completing, so this is an upper
+    /// bound on what might be stuck behind it.
+    queued_tasks: u32,
+    /// The blocking task the worker was given.
+    task_id: Option<TaskId>,
+}
+
+/// A stall that should be reported to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Stall {
+    pub(super) stalled_for: Duration,
+    pub(super) workers: Vec<StalledWorker>,
+}
+
+impl Watchdog {
+    pub(super) fn new(options: WatchdogOptions) -> Self {
+        Watchdog {
+            options,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.options.stall_after.is_some()
+    }
+
+    pub(super) fn boost(&self) -> usize {
+        self.options.boost
+    }
+
+    pub(super) fn async_task_sent(&mut self, worker_id: u32) {
+        self.workers.entry(worker_id).or_default().queued_tasks += 1;
+    }
+
+    pub(super) fn worker_busy(&mut self, worker_id: u32, now: Instant) {
+        let activity = self.workers.entry(worker_id).or_default();
+        activity.busy_since.get_or_insert(now);
+    }
+
+    /// Remember which blocking task a worker was given, so it can be named
+    /// if the worker gets stuck.
+    pub(super) fn running(&mut self, worker_id: u32, task_id: Option<TaskId>) {
+        self.workers.entry(worker_id).or_default().task_id = task_id;
+    }
+
+    /// The worker running a particular blocking task, if it hasn't finished.
+    pub(super) fn worker_running(&self, task_id: TaskId) -> Option<u32> {
+        self.workers
+            .iter()
+            .find(|(_, activity)| activity.task_id == Some(task_id))
+            .map(|(&worker_id, _)| worker_id)
+    }
+
+    pub(super) fn worker_idle(&mut self, worker_id: u32) {
+        // The worker's event loop is free again, so anything queued on it
+        // gets a chance to run.
+        self.workers.insert(worker_id, Activity::default());
+    }
+
+    /// Record that a worker is being used to break a stall. It will be
+    /// returned by [`Watchdog::expired_boost()`] once [`BOOST_DURATION`] has
+    /// passed.
+    pub(super) fn boosted(&mut self, worker_ids: Vec<u32>, now: Instant) {
+        self.boosted = Some((now + BOOST_DURATION, worker_ids));
+    }
+
+    /// Take the workers started to break a stall, if it's time to retire
+    /// them.
+    pub(super) fn expired_boost(&mut self, now: Instant) -> Vec<u32> {
+        match &self.boosted {
+            Some((expires, _)) if *expires <= now => self.boosted.take().unwrap().1,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Check whether the pool has stalled, returning a report the first time
+    /// each stall is noticed.
+    pub(super) fn check(&mut self, idle: &[u32], busy: &[u32], now: Instant) -> Option<Stall> {
+        let stall_after = self.options.stall_after?;
+
+        // Forget about workers that have been terminated
+        self.workers
+            .retain(|id, _| idle.contains(id) || busy.contains(id));
+
+        let stalled_since = self.stalled_since(idle, busy);
+        let stalled_for = stalled_since.map(|since| now.saturating_duration_since(since));
+
+        match stalled_for {
+            Some(stalled_for) if stalled_for >= stall_after => {
+                if std::mem::replace(&mut self.reported, true) {
+                    return None;
+                }
+
+                let workers = busy
+                    .iter()
+                    .map(|&worker_id| {
+                        let activity = self.workers.get(&worker_id).cloned().unwrap_or_default();
+                        StalledWorker {
+                            worker_id,
+                            busy_for_ms: activity
+                                .busy_since
+                                .map(|since| {
+                                    now.saturating_duration_since(since).as_millis() as u64
+                                })
+                                .unwrap_or_default(),
+                            queued_tasks: activity.queued_tasks,
+                            task_id: activity.task_id.map(TaskId::get),
+                        }
+                    })
+                    .collect();
+
+                Some(Stall {
+                    stalled_for,
+                    workers,
+                })
+            }
+            Some(_) => None,
+            None => {
+                self.reported = false;
+                None
+            }
+        }
+    }
+
+    /// When the pool became unable to make progress, if it is currently
+    /// stalled.
+    ///
+    /// That is, every worker is blocked and at least one of them has async
+    /// tasks queued behind it.
+    fn stalled_since(&self, idle: &[u32], busy: &[u32]) -> Option<Instant> {
+        if !idle.is_empty() || busy.is_empty() {
+            return None;
+        }
+
+        let mut anything_queued = false;
+        let mut latest = None;
+
+        for id in busy {
+            let activity = self.workers.get(id)?;
+            let since = activity.busy_since?;
+            anything_queued |= activity.queued_tasks > 0;
+            latest = latest.max(Some(since));
+        }
+
+        if anything_queued {
+            latest
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_report_stalls_with_queued_work() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(WatchdogOptions::default());
+
+        // Two workers are blocked, but nothing is waiting on them
+        watchdog.worker_busy(1, start);
+        watchdog.worker_busy(2, start);
+        let later = start + DEFAULT_STALL_AFTER * 2;
+        assert_eq!(watchdog.check(&[], &[1, 2], later), None);
+
+        // An async task was queued on worker 2 before it blocked
+        watchdog.worker_idle(2);
+        watchdog.async_task_sent(2);
+        watchdog.worker_busy(2, later);
+        assert_eq!(watchdog.check(&[], &[1, 2], later + CHECK_INTERVAL), None);
+
+        let stall = watchdog
+            .check(&[], &[1, 2], later + DEFAULT_STALL_AFTER)
+            .unwrap();
+        assert_eq!(stall.stalled_for, DEFAULT_STALL_AFTER);
+        assert_eq!(stall.workers[1].queued_tasks, 1);
+        // The same stall is only reported once
+        assert_eq!(
+            watchdog.check(&[], &[1, 2], later + DEFAULT_STALL_AFTER * 2),
+            None
+        );
+
+        // An idle worker means things can still make progress
+        watchdog.worker_idle(1);
+        assert_eq!(watchdog.check(&[1], &[2], later + BOOST_DURATION), None);
+        assert!(!watchdog.reported);
+    }
+
+    #[wasm_bindgen_test]
+    fn parse_options() {
+        assert_eq!(
+            WatchdogOptions::parse(JsValue::TRUE).unwrap(),
+            WatchdogOptions::default()
+        );
+        assert_eq!(
+            WatchdogOptions::parse(JsValue::FALSE).unwrap().stall_after,
+            None
+        );
+
+        let obj = js_sys::Object::new();
+        js_sys::Reflect::set(&obj, &"after".into(), &JsValue::from(500)).unwrap();
+        js_sys::Reflect::set(&obj, &"boost".into(), &JsValue::from(2)).unwrap();
+        let options = WatchdogOptions::parse(obj.into()).unwrap();
+        assert_eq!(options.stall_after, Some(Duration::from_millis(500)));
+        assert_eq!(options.boost, 2);
+    }
+}
@@ -1,6 +1,10 @@
 Error.stackTraceLimit = 50;
 globalThis.onerror = console.error;
 
+// Note: This must be kept in sync with WORKER_PROTOCOL_VERSION in
+// worker_handle.rs.
+const PROTOCOL_VERSION = 1;
+
 let pendingMessages = [];
 let worker = undefined;
 let handleMessage = async data => {
@@ -12,44 +16,51 @@ let handleMessage = async data => {
     }
 };
 
-globalThis.onmessage = async ev => {
-    if (ev.data.type == "init") {
-        const { memory, module, id, import_url } = ev.data;
-        const imported = await import(
-            new URL(import_url, self.location.origin)
-        );
-
-        // HACK: How we load our imports will change depending on how the code
-        // is deployed. If we are being used in "wasm-pack test" then we can
-        // access the things we want from the imported object. Otherwise, if we
-        // are being used from a bundler, chances are those things are no longer
-        // directly accessible and we need to get them from the
-        // __WASMER_INTERNALS__ object stashed on the global scope when the
-        // package was imported.
-        let init;
-        let ThreadPoolWorker;
-        if ("ThreadPoolWorker" in imported) {
-            if ("default" in imported) {
-                init = imported.default;
-            }
-            else if ("init" in imported) {
-                init = imported.init;
-            }
-            ThreadPoolWorker = imported.ThreadPoolWorker;
-        } else {
-            init = globalThis["__WASMER_INTERNALS__"].init;
-            ThreadPoolWorker =
-                globalThis["__WASMER_INTERNALS__"].ThreadPoolWorker;
+async function start(config) {
+    const { memory, module, id } = config;
+    const importUrl = new URL(config.import_url, self.location.origin);
+    const imported = await import(importUrl);
+
+    // HACK: How we load our imports will change depending on how the code
+    // is deployed. If we are being used in "wasm-pack test" then we can
+    // access the things we want from the imported object. Otherwise, if we
+    // are being used from a bundler, chances are those things are no longer
+    // directly accessible and we need to get them from the
+    // __WASMER_INTERNALS__ object stashed on the global scope when the
+    // package was imported.
+    let init;
+    let ThreadPoolWorker;
+    if ("ThreadPoolWorker" in imported) {
+        if ("default" in imported) {
+            init = imported.default;
         }
+        else if ("init" in imported) {
+            init = imported.init;
+        }
+        ThreadPoolWorker = imported.ThreadPoolWorker;
+    } else {
+        init = globalThis["__WASMER_INTERNALS__"].init;
+        ThreadPoolWorker =
+            globalThis["__WASMER_INTERNALS__"].ThreadPoolWorker;
+    }
 
-        await init(module, memory);
+    await init(module, memory);
 
-        worker = new ThreadPoolWorker(id);
+    worker = new ThreadPoolWorker(id, PROTOCOL_VERSION);
 
-        // Now that we're initialized, we need to handle any buffered messages
-        for (const msg of pendingMessages.splice(0, pendingMessages.length)) {
-            await worker.handle(msg);
-        }
+    // Now that we're initialized, we need to handle any buffered messages
+    for (const msg of pendingMessages.splice(0, pendingMessages.length)) {
+        await worker.handle(msg);
+    }
+}
+
+globalThis.onmessage = async ev => {
+    if (ev.data.type == "handshake") {
+        // Used by the host to make sure this script is compatible with the
+        // code it was loaded alongside.
+        globalThis.postMessage({ type: "handshake", version: PROTOCOL_VERSION });
+    } else if (ev.data.type == "init") {
+        await start(ev.data);
     } else {
         // Handle the message like normal.
         await handleMessage(ev.data);
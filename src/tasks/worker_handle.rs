@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, rc::Rc};
 
 use anyhow::{Context, Error};
 use js_sys::{Array, JsString, Uint8Array};
@@ -8,7 +8,23 @@ use wasm_bindgen::{
     JsCast, JsValue,
 };
 
-use crate::tasks::{PostMessagePayload, Scheduler, SchedulerMessage, WorkerMessage};
+use crate::{
+    tasks::{
+        simulation::World, PostMessagePayload, Scheduler, SchedulerMessage, TaskId, WorkerMessage,
+        WorkerSpawnBlocked,
+    },
+    utils::GlobalScope,
+};
+
+/// The version of the protocol spoken between [`WorkerHandle`] and the
+/// `worker.js` bootstrap script.
+///
+/// This needs to be bumped whenever the `worker.js` script changes in a way
+/// that isn't backwards compatible.
+pub(crate) const WORKER_PROTOCOL_VERSION: u32 = 1;
+
+/// How long [`verify_worker_script()`] will wait for a worker to respond.
+const HANDSHAKE_TIMEOUT_MS: i32 = 10_000;
 
 /// A handle to a running [`web_sys::Worker`].
 ///
@@ -17,18 +33,24 @@ use crate::tasks::{PostMessagePayload, Scheduler, SchedulerMessage, WorkerMessag
 #[derive(Debug)]
 pub(crate) struct WorkerHandle {
     id: u32,
-    inner: web_sys::Worker,
+    inner: Backend,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Worker(web_sys::Worker),
+    /// A fake worker, which records what it was sent instead of running it.
+    Simulated(Rc<World>),
 }
 
 impl WorkerHandle {
     pub(crate) fn spawn(worker_id: u32, sender: Scheduler) -> Result<Self, Error> {
         let name = format!("worker-{worker_id}");
 
-        let worker = web_sys::Worker::new_with_options(
-            &WORKER_URL,
-            web_sys::WorkerOptions::new().name(&name),
-        )
-        .map_err(crate::utils::js_error)?;
+        let url = worker_script_url();
+        let worker =
+            web_sys::Worker::new_with_options(&url, web_sys::WorkerOptions::new().name(&name))
+                .map_err(|e| spawn_error(e, &url))?;
 
         let on_message: Closure<dyn FnMut(web_sys::MessageEvent)> = Closure::new({
             let sender = sender.clone();
@@ -53,24 +75,109 @@ impl WorkerHandle {
 
         Ok(WorkerHandle {
             id: worker_id,
-            inner: worker,
+            inner: Backend::Worker(worker),
         })
     }
 
+    /// Start a fake worker in a simulated [`World`].
+    pub(crate) fn simulated(world: Rc<World>) -> Self {
+        WorkerHandle {
+            id: world.start_worker(),
+            inner: Backend::Simulated(world),
+        }
+    }
+
     pub(crate) fn id(&self) -> u32 {
         self.id
     }
 
     /// Send a message to the worker.
     pub(crate) fn send(&self, msg: PostMessagePayload) -> Result<(), Error> {
-        tracing::trace!(?msg, worker.id = self.id(), "sending a message to a worker");
-        let js = msg.into_js().map_err(|e| e.into_anyhow())?;
+        self.send_task(msg, None)
+    }
 
-        self.inner
-            .post_message(&js)
-            .map_err(crate::utils::js_error)?;
+    /// Send a message to the worker, tagging it with the task it belongs to
+    /// so the worker's logs can be correlated with the scheduler's.
+    pub(crate) fn send_task(
+        &self,
+        msg: PostMessagePayload,
+        task_id: Option<TaskId>,
+    ) -> Result<(), Error> {
+        let message = PreparedMessage::new(msg, task_id)?;
+        self.post(&message).map_err(crate::utils::js_error)
+    }
 
-        Ok(())
+    /// Send a message that has already been converted for `postMessage()`,
+    /// returning the error `postMessage()` threw if it failed.
+    pub(crate) fn post(&self, message: &PreparedMessage) -> Result<(), JsValue> {
+        tracing::trace!(
+            worker.id = self.id(),
+            task.id = message.task_id.map(TaskId::get),
+            "sending a message to a worker",
+        );
+        match &self.inner {
+            Backend::Worker(worker) => {
+                worker.post_message_with_transfer(&message.js, &message.transfer)
+            }
+            Backend::Simulated(world) => {
+                world.accept(self.id)?;
+                // Safety: the message was created by PostMessagePayload::into_js()
+                // and the simulated worker is its only recipient, so it takes
+                // ownership of the task just like a real worker would
+                let payload = unsafe { PostMessagePayload::try_from_js(message.js.clone()) }
+                    .map_err(JsValue::from)?;
+                world.delivered(self.id, &payload);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A [`PostMessagePayload`] that has been converted for `postMessage()`, so
+/// it can be offered to another worker if sending it to the first one fails.
+#[derive(Debug)]
+pub(crate) struct PreparedMessage {
+    js: JsValue,
+    transfer: Array,
+    task_id: Option<TaskId>,
+}
+
+impl PreparedMessage {
+    pub(crate) fn new(msg: PostMessagePayload, task_id: Option<TaskId>) -> Result<Self, Error> {
+        tracing::trace!(
+            ?msg,
+            task.id = task_id.map(TaskId::get),
+            "preparing a message"
+        );
+        let js = msg.into_js_for_task(task_id).map_err(|e| e.into_anyhow())?;
+        let transfer = crate::tasks::interop::transfer_list(&js);
+
+        Ok(PreparedMessage {
+            js,
+            transfer,
+            task_id,
+        })
+    }
+
+    pub(crate) fn task_id(&self) -> Option<TaskId> {
+        self.task_id
+    }
+
+    /// Turn a message nobody received back into its payload, so the task is
+    /// dropped instead of leaked.
+    pub(crate) fn into_payload(self) -> Result<PostMessagePayload, Error> {
+        // Safety: the value was created by PostMessagePayload::into_js() and
+        // was never delivered, so nothing else owns the task
+        unsafe { PostMessagePayload::try_from_js(self.js) }.map_err(|e| e.into_anyhow())
+    }
+}
+
+/// Turn the error thrown by `new Worker()` into a [`WorkerSpawnBlocked`] if
+/// the page's Content Security Policy was to blame.
+fn spawn_error(error: JsValue, url: &str) -> Error {
+    match WorkerSpawnBlocked::detect(&error, url) {
+        Some(blocked) => Error::new(blocked),
+        None => crate::utils::js_error(error),
     }
 }
 
@@ -103,6 +210,14 @@ fn on_message(msg: web_sys::MessageEvent, sender: &Scheduler, worker_id: u32) {
             let msg = match msg {
                 WorkerMessage::MarkBusy => SchedulerMessage::WorkerBusy { worker_id },
                 WorkerMessage::MarkIdle => SchedulerMessage::WorkerIdle { worker_id },
+                WorkerMessage::AsyncTaskDone => SchedulerMessage::WorkerAsyncTaskDone { worker_id },
+                WorkerMessage::Handshake(handshake) => SchedulerMessage::WorkerHandshake {
+                    worker_id,
+                    handshake,
+                },
+                WorkerMessage::Panicked(report) => {
+                    SchedulerMessage::WorkerPanicked { worker_id, report }
+                }
                 WorkerMessage::Scheduler(msg) => msg,
             };
             sender.send(msg).map_err(|_| Error::msg("Send failed"))
@@ -121,7 +236,10 @@ fn on_message(msg: web_sys::MessageEvent, sender: &Scheduler, worker_id: u32) {
 impl Drop for WorkerHandle {
     fn drop(&mut self) {
         tracing::trace!(id = self.id(), "Terminating worker");
-        self.inner.terminate();
+        match &self.inner {
+            Backend::Worker(worker) => worker.terminate(),
+            Backend::Simulated(world) => world.terminated(self.id),
+        }
     }
 }
 
@@ -148,7 +266,7 @@ fn init_message(id: u32) -> Result<JsValue, JsValue> {
 
 /// The URL used by the bootstrapping script to import the `wasm-bindgen` glue
 /// code.
-fn import_meta_url() -> String {
+pub(crate) fn import_meta_url() -> String {
     #[wasm_bindgen]
     #[allow(non_snake_case)]
     extern "C" {
@@ -162,6 +280,78 @@ fn import_meta_url() -> String {
     import_url.to_string()
 }
 
+/// The URL for the script each worker is started with, preferring one provided
+/// via `setWorkerScriptUrl()` over the embedded copy.
+fn worker_script_url() -> String {
+    match crate::CUSTOM_WORKER_SCRIPT_URL.lock().unwrap().as_deref() {
+        Some(url) => url.to_string(),
+        None => WORKER_URL.clone(),
+    }
+}
+
+/// Make sure a worker script is compatible with this version of the SDK.
+///
+/// This starts a throwaway worker from the script (defaulting to the one set
+/// with {@link setWorkerScriptUrl}), asks it which protocol version it speaks,
+/// and throws if it doesn't match.
+#[wasm_bindgen(js_name = "verifyWorkerScript")]
+pub async fn verify_worker_script(url: Option<String>) -> Result<(), crate::utils::Error> {
+    let url = url.unwrap_or_else(worker_script_url);
+    let reply = handshake(&url).await?;
+
+    let version = js_sys::Reflect::get(&reply.data(), &JsString::from("version"))
+        .ok()
+        .and_then(|v| v.as_f64());
+
+    match version {
+        Some(v) if v == f64::from(WORKER_PROTOCOL_VERSION) => Ok(()),
+        Some(v) => Err(anyhow::anyhow!(
+            "The worker script at \"{url}\" uses protocol version {v}, but version {WORKER_PROTOCOL_VERSION} was expected. Make sure it comes from the same version of the @wasmer/sdk package."
+        )
+        .into()),
+        None => Err(anyhow::anyhow!(
+            "\"{url}\" doesn't look like a @wasmer/sdk worker script"
+        )
+        .into()),
+    }
+}
+
+/// How long it takes to start a worker from the pool's script and hear back
+/// from it, in milliseconds.
+pub(crate) async fn time_worker_spawn() -> Result<f64, crate::utils::Error> {
+    let start = instant::Instant::now();
+    handshake(&worker_script_url()).await?;
+    Ok(start.elapsed().as_secs_f64() * 1000.0)
+}
+
+/// Start a throwaway worker from `url` and wait for its reply to a
+/// handshake.
+async fn handshake(url: &str) -> Result<web_sys::MessageEvent, crate::utils::Error> {
+    let worker = web_sys::Worker::new(url).map_err(|e| spawn_error(e, url))?;
+
+    let reply = js_sys::Promise::new(&mut |resolve, reject| {
+        worker.set_onmessage(Some(&resolve));
+        worker.set_onerror(Some(&reject));
+    });
+
+    let msg = js_sys::Object::new();
+    js_sys::Reflect::set(&msg, &JsString::from("type"), &JsString::from("handshake"))
+        .and_then(|_| worker.post_message(&msg))
+        .map_err(crate::utils::Error::js)?;
+
+    let timeout = GlobalScope::current().sleep(HANDSHAKE_TIMEOUT_MS);
+    let outcome = js_sys::Promise::race(&Array::of2(&reply, &timeout));
+    let result = wasm_bindgen_futures::JsFuture::from(outcome).await;
+    worker.terminate();
+
+    let reply = result
+        .map_err(|_| anyhow::anyhow!("Unable to start a worker using \"{url}\""))?
+        .dyn_into::<web_sys::MessageEvent>()
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for \"{url}\" to respond"))?;
+
+    Ok(reply)
+}
+
 /// A data URL containing our worker's bootstrap script.
 static WORKER_URL: Lazy<String> = Lazy::new(|| {
     let script = include_str!("worker.js");
@@ -174,3 +364,25 @@ static WORKER_URL: Lazy<String> = Lazy::new(|| {
 
     web_sys::Url::create_object_url_with_blob(&blob).unwrap()
 });
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn worker_script_and_handle_agree_on_protocol_version() {
+        let script = include_str!("worker.js");
+        let expected = format!("const PROTOCOL_VERSION = {WORKER_PROTOCOL_VERSION};");
+
+        assert!(script.contains(&expected));
+    }
+
+    #[wasm_bindgen_test]
+    async fn the_embedded_worker_script_passes_the_handshake() {
+        verify_worker_script(Some(WORKER_URL.clone()))
+            .await
+            .unwrap();
+    }
+}
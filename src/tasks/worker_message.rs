@@ -4,7 +4,7 @@ use web_sys::DedicatedWorkerGlobalScope;
 use crate::{
     tasks::{
         interop::{Deserializer, Serializer},
-        SchedulerMessage,
+        Handshake, PanicReport, SchedulerMessage,
     },
     utils::Error,
 };
@@ -16,6 +16,12 @@ pub(crate) enum WorkerMessage {
     MarkBusy,
     /// Mark this worker as idle.
     MarkIdle,
+    /// One of the async tasks the worker was given has finished.
+    AsyncTaskDone,
+    /// The worker has been initialized and is reporting what it is running.
+    Handshake(Handshake),
+    /// The worker panicked and shouldn't be used any more.
+    Panicked(PanicReport),
     Scheduler(SchedulerMessage),
 }
 
@@ -26,6 +32,15 @@ impl WorkerMessage {
         match de.ty()?.as_str() {
             consts::TYPE_BUSY => Ok(WorkerMessage::MarkBusy),
             consts::TYPE_IDLE => Ok(WorkerMessage::MarkIdle),
+            consts::TYPE_ASYNC_TASK_DONE => Ok(WorkerMessage::AsyncTaskDone),
+            consts::TYPE_HANDSHAKE => {
+                let handshake = de.serde(consts::HANDSHAKE)?;
+                Ok(WorkerMessage::Handshake(handshake))
+            }
+            consts::TYPE_PANICKED => {
+                let report = de.serde(consts::REPORT)?;
+                Ok(WorkerMessage::Panicked(report))
+            }
             consts::TYPE_SCHEDULER => {
                 let value: JsValue = de.js(consts::MESSAGE)?;
                 let msg = SchedulerMessage::try_from_js(value)?;
@@ -39,10 +54,17 @@ impl WorkerMessage {
         match self {
             WorkerMessage::MarkBusy => Serializer::new(consts::TYPE_BUSY).finish(),
             WorkerMessage::MarkIdle => Serializer::new(consts::TYPE_IDLE).finish(),
+            WorkerMessage::AsyncTaskDone => Serializer::new(consts::TYPE_ASYNC_TASK_DONE).finish(),
+            WorkerMessage::Handshake(handshake) => Serializer::new(consts::TYPE_HANDSHAKE)
+                .serde(consts::HANDSHAKE, &handshake)
+                .finish(),
+            WorkerMessage::Panicked(report) => Serializer::new(consts::TYPE_PANICKED)
+                .serde(consts::REPORT, &report)
+                .finish(),
             WorkerMessage::Scheduler(msg) => {
                 let msg = msg.into_js()?;
                 Serializer::new(consts::TYPE_SCHEDULER)
-                    .set(consts::MESSAGE, msg)
+                    .nested(consts::MESSAGE, msg)
                     .finish()
             }
         }
@@ -60,7 +82,10 @@ impl WorkerMessage {
             .expect("Should only ever be executed from a worker");
 
         let value = self.into_js()?;
-        scope.post_message(&value).map_err(Error::js)?;
+        let transfer = crate::tasks::interop::transfer_list(&value);
+        scope
+            .post_message_with_transfer(&value, &transfer)
+            .map_err(Error::js)?;
 
         Ok(())
     }
@@ -69,6 +94,11 @@ impl WorkerMessage {
 mod consts {
     pub const TYPE_BUSY: &str = "busy";
     pub const TYPE_IDLE: &str = "idle";
+    pub const TYPE_ASYNC_TASK_DONE: &str = "async-task-done";
+    pub const TYPE_HANDSHAKE: &str = "handshake";
+    pub const HANDSHAKE: &str = "handshake";
+    pub const TYPE_PANICKED: &str = "panicked";
+    pub const REPORT: &str = "report";
     pub const TYPE_SCHEDULER: &str = "scheduler";
     pub const MESSAGE: &str = "msg";
 }
@@ -97,6 +127,33 @@ mod tests {
         assert!(matches!(round_tripped, WorkerMessage::MarkIdle));
     }
 
+    #[test]
+    fn round_trip_handshake() {
+        let handshake = Handshake::current(Some(1));
+        let msg = WorkerMessage::Handshake(handshake.clone());
+
+        let js = msg.into_js().unwrap();
+        let round_tripped = unsafe { WorkerMessage::try_from_js(js).unwrap() };
+
+        assert!(matches!(round_tripped, WorkerMessage::Handshake(h) if h == handshake));
+    }
+
+    #[test]
+    fn round_trip_panicked() {
+        let report = PanicReport {
+            message: "oops".to_string(),
+            location: Some("src/lib.rs:1:1".to_string()),
+            backtrace: None,
+            task_id: Some(crate::tasks::TaskId::from_raw(7)),
+        };
+        let msg = WorkerMessage::Panicked(report.clone());
+
+        let js = msg.into_js().unwrap();
+        let round_tripped = unsafe { WorkerMessage::try_from_js(js).unwrap() };
+
+        assert!(matches!(round_tripped, WorkerMessage::Panicked(r) if r == report));
+    }
+
     #[test]
     fn round_trip_scheduler_message() {
         let msg = WorkerMessage::Scheduler(SchedulerMessage::WorkerBusy { worker_id: 42 });
@@ -0,0 +1,212 @@
+//! Deciding whether a program gets a terminal and setting one up only when
+//! it is actually needed.
+//!
+//! Programs often change behaviour depending on whether `isatty()` says
+//! they're attached to a terminal (prompts, colours, progress bars), while
+//! the TTY itself (echo, line buffering, line editing) costs an extra copy of
+//! every byte of input. With `tty: "auto"` the TTY is only created the first
+//! time a program asks about the terminal, so batch jobs get plain pipes.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use virtual_fs::{AsyncWriteExt, Pipe};
+use wasmer_wasix::os::{InputEvent, Tty, TtyOptions};
+
+use crate::{events::RuntimeEvent, tasks::ThreadPool, utils::Error};
+
+/// The parsed `tty` option.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TtyMode {
+    /// Create the TTY the first time the program queries the terminal.
+    #[default]
+    Auto,
+    /// Always create a TTY.
+    Always,
+    /// Never create a TTY, and tell the program it isn't attached to one.
+    Never,
+}
+
+impl TtyMode {
+    pub(crate) fn parse(mode: Option<&str>) -> Result<Self, Error> {
+        match mode {
+            None | Some("auto") => Ok(TtyMode::Auto),
+            Some("always") => Ok(TtyMode::Always),
+            Some("never") => Ok(TtyMode::Never),
+            Some(other) => {
+                let msg =
+                    format!("Expected \"auto\", \"always\", or \"never\" for tty, not \"{other}\"");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+
+    /// Should the program be told it is attached to a terminal?
+    pub(crate) fn is_connected(self) -> bool {
+        self != TtyMode::Never
+    }
+}
+
+/// Set when a program first queries or configures its terminal.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TtyDemand(Arc<AtomicBool>);
+
+impl TtyDemand {
+    pub(crate) fn request(&self) {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            tracing::debug!("The program queried its terminal");
+        }
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Who turned input echo on or off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum EchoSource {
+    /// The program changed its terminal settings.
+    Program,
+    /// The host called `instance.setEcho()`.
+    Host,
+}
+
+/// Turns a TTY's input echo on and off, letting the host know whenever it
+/// changes.
+#[derive(Debug, Clone)]
+pub(crate) struct EchoControl {
+    tty: TtyOptions,
+    pool: ThreadPool,
+}
+
+impl EchoControl {
+    pub(crate) fn new(tty: TtyOptions, pool: ThreadPool) -> Self {
+        EchoControl { tty, pool }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.tty.echo()
+    }
+
+    pub(crate) fn set(&self, echo: bool, changed_by: EchoSource) {
+        if self.tty.echo() == echo {
+            return;
+        }
+
+        tracing::debug!(echo, ?changed_by, "Toggling input echo");
+        self.tty.set_echo(echo);
+        self.pool
+            .emit(RuntimeEvent::EchoChanged { echo, changed_by });
+    }
+}
+
+/// A [`Tty`] which isn't created until its [`TtyDemand`] has been requested.
+///
+/// Until then, input is forwarded to the program's stdin untouched.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct LazyTty {
+    stdin: Pipe,
+    stdout: Pipe,
+    is_mobile: bool,
+    options: TtyOptions,
+    /// `None` means the TTY is needed straight away.
+    demand: Option<TtyDemand>,
+    #[derivative(Debug = "ignore")]
+    tty: Option<Tty>,
+}
+
+impl LazyTty {
+    pub(crate) fn new(
+        stdin: Pipe,
+        stdout: Pipe,
+        is_mobile: bool,
+        options: TtyOptions,
+        demand: Option<TtyDemand>,
+    ) -> Self {
+        let mut tty = LazyTty {
+            stdin,
+            stdout,
+            is_mobile,
+            options,
+            demand,
+            tty: None,
+        };
+        tty.activate();
+        tty
+    }
+
+    /// Create the TTY if it has been asked for, returning whether input
+    /// should go through it.
+    pub(crate) fn activate(&mut self) -> bool {
+        let requested = self.demand.as_ref().map_or(true, TtyDemand::is_requested);
+
+        if self.tty.is_none() && requested {
+            tracing::debug!("Allocating the TTY");
+            self.tty = Some(Tty::new(
+                Box::new(self.stdin.clone()),
+                Box::new(self.stdout.clone()),
+                self.is_mobile,
+                self.options.clone(),
+            ));
+        }
+
+        self.tty.is_some()
+    }
+
+    /// Pass input from the user on to the program.
+    pub(crate) async fn send(&mut self, data: Vec<u8>) {
+        self.activate();
+
+        match self.tty.take() {
+            Some(tty) => self.tty = Some(tty.on_event(InputEvent::Raw(data)).await),
+            None => {
+                if let Err(e) = self.stdin.write_all(&data).await {
+                    tracing::warn!(
+                        error = &e as &dyn std::error::Error,
+                        "Unable to forward input to stdin",
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use virtual_fs::AsyncReadExt;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn the_tty_is_only_created_once_requested() {
+        let (stdin_tx, mut stdin_rx) = Pipe::channel();
+        let (stdout_tx, _stdout_rx) = Pipe::channel();
+        let demand = TtyDemand::default();
+        let mut tty = LazyTty::new(
+            stdin_tx,
+            stdout_tx,
+            false,
+            TtyOptions::default(),
+            Some(demand.clone()),
+        );
+
+        tty.send(b"raw\n".to_vec()).await;
+        let mut buf = [0; 4];
+        stdin_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"raw\n");
+        assert!(!tty.activate());
+
+        demand.request();
+        assert!(tty.activate());
+        assert_eq!(TtyMode::parse(None).unwrap(), TtyMode::Auto);
+        assert!(!TtyMode::parse(Some("never")).unwrap().is_connected());
+        assert!(TtyMode::parse(Some("sometimes")).is_err());
+    }
+}
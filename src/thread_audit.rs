@@ -0,0 +1,296 @@
+//! Explaining how a module will be threaded before running it.
+//!
+//! Whether a program actually runs in parallel depends on details of how it
+//! was built (does it import a shared memory? which `thread_spawn` does it
+//! use?) and on the page (is it cross-origin isolated?). When any of them is
+//! off, threads either fail to spawn or the program quietly runs on one
+//! core, which is hard to tell apart from it just being slow. This audit
+//! spells out what the runtime will do with a module and why.
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::{
+    module_info::{self, ModuleReport},
+    utils::Error,
+};
+
+/// What the runtime will do when a module starts threads.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ThreadingReport {
+    /// How many memories the module imports or defines.
+    memories: usize,
+    /// Is the first memory imported, so the runtime provides it?
+    memory_imported: bool,
+    memory_shared: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_spawn: Option<ThreadSpawn>,
+    forks: bool,
+    pathways: Pathways,
+    /// Will threads run in parallel on this page?
+    parallel: bool,
+    /// Why not, or anything else worth knowing.
+    notes: Vec<String>,
+}
+
+/// Which `thread_spawn` a module imports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ThreadSpawn {
+    /// `wasix_32v1.thread_spawn` (or `_v2`, or the 64-bit equivalents).
+    Wasix,
+    /// `wasi.thread-spawn` from the wasi-threads proposal.
+    WasiThreads,
+}
+
+/// The scheduler messages each way of starting the module goes through.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Pathways {
+    /// Running the module with `runWasix()`.
+    run_wasix: Pathway,
+    /// Running the module as a package's command.
+    command: Pathway,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads: Option<Pathway>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fork: Option<Pathway>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+struct Pathway {
+    message: Message,
+    memory: MemoryStrategy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+enum Message {
+    /// Only the compiled module is sent to the worker.
+    SpawnWithModule,
+    /// The module and (maybe) a memory are sent to the worker.
+    SpawnWithModuleAndMemory,
+}
+
+/// Where the new instance's memory comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum MemoryStrategy {
+    /// Created while instantiating, on the worker.
+    Instantiate,
+    /// The module defines its own memory.
+    Create,
+    /// The runtime creates a memory matching the module's import.
+    CreateOfType,
+    /// The thread shares its parent's memory.
+    Share,
+    /// The child gets a copy of its parent's memory.
+    Copy,
+}
+
+impl ThreadingReport {
+    pub(crate) fn new(module: &ModuleReport, cross_origin_isolated: bool) -> Self {
+        let functions = || {
+            module
+                .imports
+                .iter()
+                .filter(|import| import.kind == "function")
+        };
+        let thread_spawn =
+            functions().find_map(
+                |import| match (import.module.as_str(), import.name.as_str()) {
+                    ("wasix_32v1" | "wasix_64v1", "thread_spawn" | "thread_spawn_v2") => {
+                        Some(ThreadSpawn::Wasix)
+                    }
+                    ("wasi", "thread-spawn") => Some(ThreadSpawn::WasiThreads),
+                    _ => None,
+                },
+            );
+        let forks = functions().any(|import| {
+            matches!(import.module.as_str(), "wasix_32v1" | "wasix_64v1")
+                && import.name == "proc_fork"
+        });
+
+        // Note: WASIX only ever gives threads the first memory
+        let memory = module.memories.first();
+        let memory_imported = memory.map_or(false, |m| m.imported);
+        let memory_shared = memory.map_or(false, |m| m.shared);
+
+        let mut notes = Vec::new();
+        if !module.valid {
+            notes.push("The module isn't valid, so it won't run at all".to_string());
+        }
+        match thread_spawn {
+            None => notes.push(
+                "The module doesn't import thread_spawn, so it never starts threads".to_string(),
+            ),
+            Some(_) => {
+                if memory.is_none() {
+                    notes.push("The module has no memory for its threads to share".to_string());
+                } else if !memory_imported {
+                    notes.push(
+                        "The module defines its own memory instead of importing it, so threads can't share it (link with --import-memory)".to_string(),
+                    );
+                }
+                if memory.is_some() && !memory_shared {
+                    notes.push(
+                        "The module's memory isn't shared, so threads can't use it (link with --shared-memory)".to_string(),
+                    );
+                }
+                if !cross_origin_isolated {
+                    notes.push(
+                        "The page isn't cross-origin isolated, so shared memories aren't available (see the COOP and COEP headers)".to_string(),
+                    );
+                }
+            }
+        }
+        if module.memories.len() > 1 {
+            notes.push(format!(
+                "The module has {} memories, but only the first is shared with threads",
+                module.memories.len()
+            ));
+        }
+
+        let parallel = module.valid
+            && thread_spawn.is_some()
+            && memory_imported
+            && memory_shared
+            && cross_origin_isolated;
+
+        let with_memory = |memory| Pathway {
+            message: Message::SpawnWithModuleAndMemory,
+            memory,
+        };
+        let pathways = Pathways {
+            run_wasix: Pathway {
+                message: Message::SpawnWithModule,
+                memory: MemoryStrategy::Instantiate,
+            },
+            command: with_memory(if memory_imported {
+                MemoryStrategy::CreateOfType
+            } else {
+                MemoryStrategy::Create
+            }),
+            threads: thread_spawn.map(|_| with_memory(MemoryStrategy::Share)),
+            fork: forks.then(|| with_memory(MemoryStrategy::Copy)),
+        };
+
+        ThreadingReport {
+            memories: module.memories.len(),
+            memory_imported,
+            memory_shared,
+            thread_spawn,
+            forks,
+            pathways,
+            parallel,
+            notes,
+        }
+    }
+
+    pub(crate) fn to_js(&self) -> Result<JsThreadingReport, Error> {
+        let value = serde_wasm_bindgen::to_value(self).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+}
+
+/// Audit `wasm`, a binary or `*.wat` text.
+pub(crate) fn audit(wasm: &[u8]) -> ThreadingReport {
+    let module = module_info::inspect(wasm);
+    ThreadingReport::new(&module, module_info::is_supported("threads"))
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const THREADING_REPORT_TYPE_DEFS: &'static str = r#"
+/**
+ * How the runtime starts a module on a worker.
+ *
+ * `message` is the scheduler message used (as it appears in the runtime's
+ * logs), and `memory` is where the new instance's memory comes from:
+ *
+ * - `"instantiate"`: created on the worker while instantiating
+ * - `"create"`: the module defines its own memory
+ * - `"create-of-type"`: the runtime creates a memory matching the module's
+ *   import
+ * - `"share"`: the thread shares its parent's memory
+ * - `"copy"`: the child gets a copy of its parent's memory
+ */
+export type SpawnPathway = {
+    message: "SpawnWithModule" | "SpawnWithModuleAndMemory";
+    memory: "instantiate" | "create" | "create-of-type" | "share" | "copy";
+};
+
+/**
+ * What {@link Wasmer.auditThreads} found out about a module.
+ */
+export type ThreadingReport = {
+    /** How many memories the module imports or defines. */
+    memories: number;
+    /** Whether the module's first memory is imported. */
+    memoryImported: boolean;
+    /** Whether the module's first memory is shared. */
+    memoryShared: boolean;
+    /** Which `thread_spawn` the module imports, if any. */
+    threadSpawn?: "wasix" | "wasi-threads";
+    /** Whether the module imports `proc_fork`. */
+    forks: boolean;
+    pathways: {
+        /** Used when the module is run with {@link runWasix}. */
+        runWasix: SpawnPathway;
+        /** Used when the module is run as a package's command. */
+        command: SpawnPathway;
+        /** Used for each thread the module starts. */
+        threads?: SpawnPathway;
+        /** Used for each `fork()`. */
+        fork?: SpawnPathway;
+    };
+    /** Whether the module's threads will run in parallel on this page. */
+    parallel: boolean;
+    /** Why they won't, and anything else worth knowing. */
+    notes: string[];
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ThreadingReport")]
+    pub type JsThreadingReport;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn explain_why_threads_dont_run_in_parallel() {
+        let threaded = module_info::inspect(
+            br#"(module
+                (import "env" "memory" (memory 1 16 shared))
+                (import "wasix_32v1" "thread_spawn_v2" (func (param i32 i32) (result i32))))"#,
+        );
+
+        let report = ThreadingReport::new(&threaded, true);
+        assert!(report.parallel, "{:?}", report.notes);
+        assert_eq!(report.thread_spawn, Some(ThreadSpawn::Wasix));
+        assert_eq!(report.pathways.command.memory, MemoryStrategy::CreateOfType);
+        assert_eq!(
+            report.pathways.threads.unwrap().message,
+            Message::SpawnWithModuleAndMemory
+        );
+
+        let isolated = ThreadingReport::new(&threaded, false);
+        assert!(!isolated.parallel);
+        assert_eq!(isolated.notes.len(), 1);
+
+        let private = module_info::inspect(
+            br#"(module
+                (import "wasi" "thread-spawn" (func (param i32) (result i32)))
+                (memory 1))"#,
+        );
+        let report = ThreadingReport::new(&private, true);
+        assert!(!report.parallel);
+        assert_eq!(report.pathways.command.memory, MemoryStrategy::Create);
+        assert_eq!(report.notes.len(), 2);
+    }
+}
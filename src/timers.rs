@@ -0,0 +1,308 @@
+//! Periodic wakeups for guests, backed by the host's timers.
+//!
+//! Long-running programs often poll for work by sleeping in a loop, which
+//! keeps a worker busy even when nothing is happening. Every instance gets a
+//! `/dev/timer/periodic` device that works like Linux's `timerfd` instead:
+//!
+//! - Each open handle is an independent timer
+//! - Writing an interval in milliseconds (e.g. `"250\n"`) arms the timer,
+//!   and writing `0` disarms it
+//! - Each read waits for the timer to fire, then returns the number of times
+//!   it has fired since the previous read as a decimal number followed by a
+//!   newline
+//!
+//! Waiting is handled by the scheduler's `setTimeout()`, so the guest's
+//! worker is idle until the timer fires.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use futures::future::BoxFuture;
+use instant::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use virtual_fs::VirtualFile;
+use wasmer_wasix::VirtualTaskManager;
+
+use crate::{
+    fs::{Device, DeviceFileSystem, Opener},
+    tasks::ThreadPool,
+};
+
+/// Where the timer devices get mounted.
+pub(crate) const MOUNT_POINT: &str = "/dev/timer";
+
+/// Would mounting the timers clash with something the user mounted at
+/// `mount_point`?
+pub(crate) fn conflicts_with(mount_point: &str) -> bool {
+    let mount_point = mount_point.trim_end_matches('/');
+    mount_point.is_empty()
+        || MOUNT_POINT == mount_point
+        || MOUNT_POINT.starts_with(&format!("{mount_point}/"))
+}
+
+/// A directory containing the `periodic` device, ready to be mounted at
+/// [`MOUNT_POINT`].
+pub(crate) fn filesystem(pool: &ThreadPool) -> DeviceFileSystem {
+    let fs = DeviceFileSystem::default();
+
+    let pool = pool.clone();
+    let periodic = Opener::new(move || Box::new(TimerFile::new(pool.clone())));
+    fs.insert("periodic", Device::Opener(periodic));
+
+    fs
+}
+
+/// A single timer, created whenever the device is opened.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct TimerFile {
+    pool: ThreadPool,
+    interval: Option<Duration>,
+    next_deadline: Instant,
+    /// Part of the last expiration count that didn't fit in the caller's
+    /// buffer.
+    unread: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    sleep: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+    /// Someone polling for readiness while the timer was disarmed, who
+    /// needs waking once it is armed.
+    disarmed_waker: Option<Waker>,
+}
+
+impl TimerFile {
+    fn new(pool: ThreadPool) -> Self {
+        TimerFile {
+            pool,
+            interval: None,
+            next_deadline: Instant::now(),
+            unread: Vec::new(),
+            sleep: None,
+            disarmed_waker: None,
+        }
+    }
+
+    fn arm(&mut self, command: &str) -> io::Result<()> {
+        let ms: u64 = command.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{command}\" isn't an interval in milliseconds"),
+            )
+        })?;
+
+        self.sleep = None;
+        if ms == 0 {
+            tracing::trace!("Disarming a timer");
+            self.interval = None;
+        } else {
+            let interval = Duration::from_millis(ms);
+            tracing::trace!(?interval, "Arming a timer");
+            self.interval = Some(interval);
+            self.next_deadline = Instant::now() + interval;
+            if let Some(waker) = self.disarmed_waker.take() {
+                waker.wake();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the timer to fire, leaving the expiration count in
+    /// `unread`.
+    fn poll_fired(&mut self, interval: Duration, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                futures::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            let now = Instant::now();
+            let count = self.expirations(interval, now);
+            if count > 0 {
+                self.unread = format!("{count}\n").into_bytes();
+                return Poll::Ready(());
+            }
+
+            let remaining = self.next_deadline.duration_since(now);
+            self.sleep = Some(self.pool.sleep_now(remaining));
+        }
+    }
+
+    /// How many times has the timer fired by `now`? Advances the deadline
+    /// past `now`.
+    fn expirations(&mut self, interval: Duration, now: Instant) -> u64 {
+        if now < self.next_deadline {
+            return 0;
+        }
+
+        let overdue = now.duration_since(self.next_deadline);
+        let count = 1 + (overdue.as_nanos() / interval.as_nanos()) as u64;
+        self.next_deadline += interval * count as u32;
+        count
+    }
+}
+
+impl VirtualFile for TimerFile {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> virtual_fs::Result<()> {
+        Err(virtual_fs::FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> BoxFuture<'static, virtual_fs::Result<()>> {
+        Box::pin(async { Err(virtual_fs::FsError::PermissionDenied) })
+    }
+
+    fn poll_read_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if this.unread.is_empty() {
+            // Note: like a timerfd, a disarmed timer never becomes readable
+            let Some(interval) = this.interval else {
+                this.disarmed_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+            futures::ready!(this.poll_fired(interval, cx));
+        }
+
+        Poll::Ready(Ok(this.unread.len()))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(1))
+    }
+}
+
+impl AsyncRead for TimerFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.unread.is_empty() {
+            let Some(interval) = self.interval else {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The timer hasn't been armed",
+                )));
+            };
+            futures::ready!(self.poll_fired(interval, cx));
+        }
+
+        let n = self.unread.len().min(buf.remaining());
+        buf.put_slice(&self.unread[..n]);
+        self.unread.drain(..n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TimerFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Note: each write is treated as a complete command, so the last
+        // line written wins.
+        let text = String::from_utf8_lossy(buf);
+        for command in text.lines().filter(|line| !line.trim().is_empty()) {
+            self.arm(command)?;
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for TimerFile {
+    fn start_seek(self: Pin<&mut Self>, _position: io::SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Timers can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn count_missed_expirations() {
+        let mut timer = TimerFile::new(ThreadPool::new());
+        timer.arm("100\n").unwrap();
+        let interval = timer.interval.unwrap();
+        let start = timer.next_deadline;
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(
+            timer.expirations(interval, ms(0) - Duration::from_millis(1)),
+            0
+        );
+        assert_eq!(timer.expirations(interval, ms(0)), 1);
+        // Reading late reports every expiration that was missed
+        assert_eq!(timer.expirations(interval, ms(350)), 3);
+        assert_eq!(timer.next_deadline, ms(400));
+
+        timer.arm("0").unwrap();
+        assert!(timer.interval.is_none());
+        assert!(timer.arm("soon").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn only_fired_timers_are_readable() {
+        let mut timer = TimerFile::new(ThreadPool::new());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut timer).poll_read_ready(&mut cx).is_pending());
+
+        // Pretend the timer fired a while ago
+        timer.arm("100").unwrap();
+        timer.next_deadline -= Duration::from_millis(350);
+        match Pin::new(&mut timer).poll_read_ready(&mut cx) {
+            Poll::Ready(Ok(len)) => assert_eq!(len, "3\n".len()),
+            other => panic!("Unexpected readiness: {other:?}"),
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn user_mounts_can_hide_the_timers() {
+        assert!(conflicts_with("/dev/timer"));
+        assert!(conflicts_with("/dev/"));
+        assert!(conflicts_with("/"));
+        assert!(!conflicts_with("/dev/timers"));
+        assert!(!conflicts_with("/data"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Aggregated span timings, for tracking performance regressions.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use instant::Instant;
+use serde::Serialize;
+use tracing::{span, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
+
+use crate::utils::Error;
+
+/// How many durations to keep for each span name when calculating
+/// percentiles.
+const MAX_SAMPLES: usize = 1024;
+
+/// Timings for every span that has closed since startup (or the last reset).
+static TIMINGS: Mutex<BTreeMap<&'static str, Samples>> = Mutex::new(BTreeMap::new());
+
+/// Get a summary of how long each kind of span took, keyed by span name.
+///
+/// Only spans enabled by the filter passed to {@link initializeLogger} are
+/// recorded, so nothing is collected until the logger is initialized.
+#[wasm_bindgen(js_name = "getTimingReport")]
+pub fn get_timing_report() -> Result<TimingReport, Error> {
+    let report: BTreeMap<_, _> = TIMINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&name, samples)| (name, samples.summary()))
+        .collect();
+
+    let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+    let value = report.serialize(&serializer).map_err(Error::js)?;
+
+    Ok(value.unchecked_into())
+}
+
+/// Forget every timing recorded so far.
+#[wasm_bindgen(js_name = "resetTimingReport")]
+pub fn reset_timing_report() {
+    TIMINGS.lock().unwrap().clear();
+}
+
+/// A [`Layer`] which records how long each span was open for.
+#[derive(Debug, Default)]
+pub(crate) struct TimingLayer;
+
+/// When a span was opened, stored in the span's extensions.
+struct Opened(Instant);
+
+impl<S> Layer<S> for TimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Opened(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(opened) = span.extensions().get::<Opened>().map(|o| o.0) else {
+            return;
+        };
+
+        let elapsed_ms = opened.elapsed().as_secs_f64() * 1000.0;
+        TIMINGS
+            .lock()
+            .unwrap()
+            .entry(span.name())
+            .or_default()
+            .record(elapsed_ms);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    count: u64,
+    total_ms: f64,
+    /// The most recent durations, used as a sample for percentiles.
+    recent: Vec<f64>,
+    next: usize,
+}
+
+impl Samples {
+    fn record(&mut self, duration_ms: f64) {
+        self.count += 1;
+        self.total_ms += duration_ms;
+
+        if self.recent.len() < MAX_SAMPLES {
+            self.recent.push(duration_ms);
+        } else {
+            self.recent[self.next] = duration_ms;
+            self.next = (self.next + 1) % MAX_SAMPLES;
+        }
+    }
+
+    fn summary(&self) -> SpanTiming {
+        let mut sorted = self.recent.clone();
+        sorted.sort_by(f64::total_cmp);
+
+        SpanTiming {
+            count: self.count,
+            total_ms: self.total_ms,
+            p50_ms: percentile(&sorted, 0.5),
+            p95_ms: percentile(&sorted, 0.95),
+        }
+    }
+}
+
+/// Nearest-rank percentile of an already sorted list.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanTiming {
+    count: u64,
+    total_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const TIMING_REPORT_TYPE_DECLARATION: &str = r#"
+/**
+ * How long a particular kind of span took, as returned by
+ * {@link getTimingReport}.
+ */
+export type SpanTiming = {
+    /** How many times the span was closed. */
+    count: number;
+    totalMs: number;
+    /** The median duration, based on (at most) the last 1024 samples. */
+    p50Ms: number;
+    /** The 95th percentile, based on (at most) the last 1024 samples. */
+    p95Ms: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Record<string, SpanTiming>")]
+    pub type TimingReport;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn summarise_samples() {
+        let mut samples = Samples::default();
+        for ms in 1..=100 {
+            samples.record(ms as f64);
+        }
+
+        let summary = samples.summary();
+
+        assert_eq!(
+            summary,
+            SpanTiming {
+                count: 100,
+                total_ms: 5050.0,
+                p50_ms: 50.0,
+                p95_ms: 95.0,
+            }
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn only_keep_recent_samples_for_percentiles() {
+        let mut samples = Samples::default();
+        for _ in 0..MAX_SAMPLES {
+            samples.record(1000.0);
+        }
+        for _ in 0..MAX_SAMPLES {
+            samples.record(1.0);
+        }
+
+        let summary = samples.summary();
+
+        assert_eq!(summary.count, 2 * MAX_SAMPLES as u64);
+        assert_eq!(summary.p95_ms, 1.0);
+    }
+}
@@ -0,0 +1,155 @@
+//! Propagating a host application's [W3C Trace Context][spec] through the
+//! work done on behalf of an instance.
+//!
+//! [spec]: https://www.w3.org/TR/trace-context/
+
+use std::{fmt, str::FromStr};
+
+use futures::future::BoxFuture;
+use http::HeaderValue;
+use wasmer_wasix::http::{DynHttpClient, HttpClient, HttpRequest, HttpResponse};
+
+use crate::utils::Error;
+
+/// A parsed `traceparent` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TraceContext {
+    trace_id: String,
+    parent_id: String,
+    flags: u8,
+}
+
+impl TraceContext {
+    /// A context for a new operation in the same trace, with this one as its
+    /// parent.
+    fn child(&self) -> TraceContext {
+        let parent_id = loop {
+            let id: String = (0..2)
+                .map(|_| format!("{:08x}", (js_sys::Math::random() * u32::MAX as f64) as u32))
+                .collect();
+            if !is_zero(&id) {
+                break id;
+            }
+        };
+
+        TraceContext {
+            parent_id,
+            ..self.clone()
+        }
+    }
+
+    /// Attach this context to a span which declared an empty `traceparent`
+    /// field.
+    pub(crate) fn record(context: Option<&TraceContext>, span: &tracing::Span) {
+        if let Some(context) = context {
+            span.record("traceparent", tracing::field::display(context));
+        }
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.bytes().all(|b| b == b'0')
+}
+
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('-').collect();
+
+        match parts.as_slice() {
+            [version, trace_id, parent_id, flags]
+                if is_hex(version, 2)
+                    && *version != "ff"
+                    && is_hex(trace_id, 32)
+                    && !is_zero(trace_id)
+                    && is_hex(parent_id, 16)
+                    && !is_zero(parent_id)
+                    && is_hex(flags, 2) =>
+            {
+                Ok(TraceContext {
+                    trace_id: trace_id.to_string(),
+                    parent_id: parent_id.to_string(),
+                    flags: u8::from_str_radix(flags, 16)?,
+                })
+            }
+            _ => {
+                let msg = format!("\"{s}\" isn't a valid traceparent");
+                Err(Error::js(js_sys::TypeError::new(&msg)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let TraceContext {
+            trace_id,
+            parent_id,
+            flags,
+        } = self;
+        write!(f, "00-{trace_id}-{parent_id}-{flags:02x}")
+    }
+}
+
+/// An [`HttpClient`] which adds a `traceparent` header to every request.
+#[derive(Debug, Clone)]
+pub(crate) struct TracedHttpClient {
+    pub(crate) inner: DynHttpClient,
+    pub(crate) context: TraceContext,
+}
+
+impl HttpClient for TracedHttpClient {
+    fn request(
+        &self,
+        mut request: HttpRequest,
+    ) -> BoxFuture<'_, Result<HttpResponse, anyhow::Error>> {
+        let traceparent = self.context.child().to_string();
+        if let Ok(value) = HeaderValue::from_str(&traceparent) {
+            request.headers.insert("traceparent", value);
+        }
+
+        self.inner.request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[wasm_bindgen_test]
+    fn parse_a_traceparent() {
+        let context: TraceContext = TRACEPARENT.parse().unwrap();
+
+        assert_eq!(context.to_string(), TRACEPARENT);
+        assert!("00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+            .parse::<TraceContext>()
+            .is_err());
+        assert!("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse::<TraceContext>()
+            .is_err());
+        assert!("not a traceparent".parse::<TraceContext>().is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn children_keep_the_trace_id() {
+        let context: TraceContext = TRACEPARENT.parse().unwrap();
+
+        let child = context.child();
+
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_eq!(child.flags, context.flags);
+        assert_ne!(child.parent_id, context.parent_id);
+        assert!(is_hex(&child.parent_id, 16));
+    }
+}
@@ -0,0 +1,274 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    chunking::{ChunkSizeSnapshot, ChunkSizer},
+    metering::GasUsage,
+    net::NetworkCounters,
+};
+
+/// Approximate resource accounting for a single running command.
+///
+/// The counters are shared with the threads doing the actual work, so a
+/// snapshot can be taken at any time. The stdio byte counters are updated as
+/// data crosses between JavaScript and the command's pipes, and the network
+/// ones cover the runtime's gateway traffic while the command runs.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceUsage {
+    pub(crate) stdin_bytes: Arc<AtomicU64>,
+    pub(crate) stdout_bytes: Arc<AtomicU64>,
+    pub(crate) stderr_bytes: Arc<AtomicU64>,
+    pub(crate) stdout_chunks: Arc<ChunkSizer>,
+    pub(crate) stderr_chunks: Arc<ChunkSizer>,
+    busy: Mutex<BusyTime>,
+    network: Mutex<NetworkWindow>,
+    /// Set when a metered program exits.
+    pub(crate) gas: Mutex<Option<GasUsage>>,
+}
+
+#[derive(Debug, Default)]
+struct BusyTime {
+    /// Milliseconds spent busy in completed intervals.
+    total_ms: f64,
+    /// When the current busy interval started, as a Unix timestamp in
+    /// milliseconds.
+    ///
+    /// We use `Date.now()` instead of `performance.now()` because each worker
+    /// has its own time origin and snapshots are taken from a different thread.
+    started_at: Option<f64>,
+}
+
+/// The networking gateway's traffic between a command starting and exiting.
+#[derive(Debug, Default)]
+struct NetworkWindow {
+    counters: Option<Arc<NetworkCounters>>,
+    /// The bytes sent and received before the command started.
+    start: (u64, u64),
+    /// The bytes sent and received when the command exited.
+    end: Option<(u64, u64)>,
+}
+
+impl NetworkWindow {
+    fn current(&self) -> (u64, u64) {
+        let Some(counters) = &self.counters else {
+            return (0, 0);
+        };
+        let (sent, received) = self
+            .end
+            .unwrap_or_else(|| (counters.sent(), counters.received()));
+        (sent - self.start.0, received - self.start.1)
+    }
+}
+
+impl ResourceUsage {
+    /// Start counting the traffic through the runtime's networking gateway.
+    pub(crate) fn track_network(&self, counters: Arc<NetworkCounters>) {
+        let mut network = self.network.lock().unwrap();
+        network.start = (counters.sent(), counters.received());
+        network.end = None;
+        network.counters = Some(counters);
+    }
+
+    /// Stop counting network traffic, once the command has exited.
+    pub(crate) fn stop_tracking_network(&self) {
+        let mut network = self.network.lock().unwrap();
+        if let Some(counters) = &network.counters {
+            network.end = Some((counters.sent(), counters.received()));
+        }
+    }
+
+    /// Mark the command as busy (i.e. its main thread is occupying a worker)
+    /// until the returned guard is dropped.
+    pub(crate) fn busy(self: &Arc<Self>) -> impl Drop {
+        struct BusyGuard(Arc<ResourceUsage>);
+
+        impl Drop for BusyGuard {
+            fn drop(&mut self) {
+                let mut busy = self.0.busy.lock().unwrap();
+                if let Some(started_at) = busy.started_at.take() {
+                    busy.total_ms += js_sys::Date::now() - started_at;
+                }
+            }
+        }
+
+        self.busy.lock().unwrap().started_at = Some(js_sys::Date::now());
+        BusyGuard(Arc::clone(self))
+    }
+
+    pub(crate) fn snapshot(&self) -> UsageSnapshot {
+        let busy = self.busy.lock().unwrap();
+        let in_progress = busy
+            .started_at
+            .map(|started_at| js_sys::Date::now() - started_at)
+            .unwrap_or(0.0);
+        let (network_sent_bytes, network_received_bytes) = self.network.lock().unwrap().current();
+
+        UsageSnapshot {
+            busy_time_ms: busy.total_ms + in_progress,
+            stdin_bytes: self.stdin_bytes.load(Ordering::Relaxed),
+            stdout_bytes: self.stdout_bytes.load(Ordering::Relaxed),
+            stderr_bytes: self.stderr_bytes.load(Ordering::Relaxed),
+            network_sent_bytes,
+            network_received_bytes,
+            chunk_sizes: ChunkSizes {
+                stdout: self.stdout_chunks.snapshot(),
+                stderr: self.stderr_chunks.snapshot(),
+            },
+            gas: *self.gas.lock().unwrap(),
+        }
+    }
+}
+
+/// A point-in-time copy of a [`ResourceUsage`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsageSnapshot {
+    pub(crate) busy_time_ms: f64,
+    pub(crate) stdin_bytes: u64,
+    pub(crate) stdout_bytes: u64,
+    pub(crate) stderr_bytes: u64,
+    pub(crate) network_sent_bytes: u64,
+    pub(crate) network_received_bytes: u64,
+    pub(crate) chunk_sizes: ChunkSizes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) gas: Option<GasUsage>,
+}
+
+/// The chunk sizes chosen for each of a command's output streams.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ChunkSizes {
+    pub(crate) stdout: ChunkSizeSnapshot,
+    pub(crate) stderr: ChunkSizeSnapshot,
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const RESOURCE_USAGE_TYPE_DECLARATION: &str = r#"
+/**
+ * Approximate resources used by a command.
+ */
+export type ResourceUsage = {
+    /**
+     * How long the command's main thread has kept a worker busy, in
+     * milliseconds.
+     *
+     * This is wall-clock time, not CPU time. It includes time spent blocked
+     * on I/O or waiting for other threads, and doesn't include time spent in
+     * threads the program spawned.
+     */
+    busyTimeMs: number;
+    /**
+     * Bytes JavaScript has written to the command's stdin, whether or not
+     * the command has read them yet.
+     */
+    stdinBytes: number;
+    /**
+     * Bytes of stdout that have been passed on to JavaScript. Output the
+     * command wrote which hasn't been read from {@link Instance.stdout} yet
+     * isn't counted.
+     */
+    stdoutBytes: number;
+    /** Like `stdoutBytes`, but for stderr. */
+    stderrBytes: number;
+    /**
+     * Bytes sent to the runtime's networking gateway while the command was
+     * running, including the tunnel's own framing.
+     *
+     * Every command using the runtime shares the gateway connection, so this
+     * also counts traffic from any commands running at the same time.
+     */
+    networkSentBytes: number;
+    /** Like `networkSentBytes`, but for bytes received from the gateway. */
+    networkReceivedBytes: number;
+    /**
+     * The buffer sizes used when streaming stdout and stderr to JavaScript,
+     * which grow for bulk output and shrink for interactive output.
+     */
+    chunkSizes: { stdout: ChunkSizeStats; stderr: ChunkSizeStats };
+    /**
+     * How many instructions the program executed, if it was started with
+     * `metering` enabled. This is only known once the program exits.
+     */
+    gas?: {
+        /** The number of WebAssembly instructions executed. */
+        instructions: number;
+        /** Was the program stopped because it hit its gas limit? */
+        exhausted: boolean;
+    };
+};
+
+/**
+ * How a single output stream has been chunked, in bytes.
+ */
+export type ChunkSizeStats = {
+    /** The size the next read will use. */
+    current: number;
+    /** The smallest size used so far (0 if nothing has been read). */
+    smallest: number;
+    /** The largest size used so far. */
+    largest: number;
+    /** How many chunks have been read. */
+    chunks: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ResourceUsage")]
+    pub type JsResourceUsage;
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn busy_time_accumulates_across_intervals() {
+        let usage = Arc::new(ResourceUsage::default());
+        assert_eq!(usage.snapshot().busy_time_ms, 0.0);
+
+        drop(usage.busy());
+        let first = usage.snapshot().busy_time_ms;
+        drop(usage.busy());
+        let second = usage.snapshot().busy_time_ms;
+
+        assert!(first >= 0.0);
+        assert!(second >= first);
+    }
+
+    #[wasm_bindgen_test]
+    fn byte_counters_show_up_in_snapshots() {
+        let usage = ResourceUsage::default();
+
+        usage.stdout_bytes.fetch_add(42, Ordering::Relaxed);
+
+        assert_eq!(usage.snapshot().stdout_bytes, 42);
+        assert_eq!(usage.snapshot().stderr_bytes, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn network_traffic_is_counted_while_the_command_runs() {
+        let usage = ResourceUsage::default();
+        let counters = Arc::new(NetworkCounters::default());
+        // Traffic from before the command started doesn't count
+        counters.record_sent(100);
+        usage.track_network(Arc::clone(&counters));
+
+        counters.record_sent(10);
+        counters.record_received(20);
+        let snapshot = usage.snapshot();
+        assert_eq!(snapshot.network_sent_bytes, 10);
+        assert_eq!(snapshot.network_received_bytes, 20);
+
+        // ... and neither does traffic after it exits
+        usage.stop_tracking_network();
+        counters.record_received(1);
+        assert_eq!(usage.snapshot().network_received_bytes, 20);
+    }
+}
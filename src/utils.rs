@@ -9,6 +9,8 @@ use js_sys::{JsString, Promise, Uint8Array};
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
 use web_sys::{Window, WorkerGlobalScope};
 
+use crate::tasks::{TaskUndelivered, WorkerSpawnBlocked};
+
 /// Try to extract the most appropriate error message from a [`JsValue`],
 /// falling back to a generic error message.
 pub(crate) fn js_error(value: JsValue) -> anyhow::Error {
@@ -92,6 +94,26 @@ impl GlobalScope {
         }
     }
 
+    pub fn crypto(&self) -> Option<web_sys::Crypto> {
+        match self {
+            GlobalScope::Window(scope) => scope.crypto().ok(),
+            GlobalScope::Worker(scope) => scope.crypto().ok(),
+            GlobalScope::Other(_) => None,
+        }
+    }
+
+    /// Start a `fetch()` request using the current global scope.
+    pub fn fetch(&self, request: &web_sys::Request) -> Promise {
+        match self {
+            GlobalScope::Window(scope) => scope.fetch_with_request(request),
+            GlobalScope::Worker(scope) => scope.fetch_with_request(request),
+            GlobalScope::Other(_) => {
+                let error = js_sys::Error::new("Unable to call fetch()");
+                Promise::reject(&error)
+            }
+        }
+    }
+
     pub fn cross_origin_isolated(&self) -> Option<bool> {
         let obj = self.as_object();
         js_sys::Reflect::get(obj, &JsValue::from_str("crossOriginIsolated"))
@@ -99,6 +121,65 @@ impl GlobalScope {
             .and_then(|obj| obj.as_bool())
     }
 
+    /// Does this environment support the [JavaScript Promise Integration][jspi]
+    /// proposal?
+    ///
+    /// Both the current (`WebAssembly.Suspending`) and the original
+    /// (`WebAssembly.Function` + `WebAssembly.promising`) iterations of the
+    /// API are detected.
+    ///
+    /// [jspi]: https://github.com/WebAssembly/js-promise-integration
+    pub fn supports_jspi(&self) -> bool {
+        let Ok(wasm) = js_sys::Reflect::get(self.as_object(), &JsValue::from_str("WebAssembly"))
+        else {
+            return false;
+        };
+
+        let has = |name: &str| {
+            js_sys::Reflect::get(&wasm, &JsValue::from_str(name))
+                .map(|value| value.is_function())
+                .unwrap_or(false)
+        };
+
+        has("Suspending") || (has("Function") && has("promising"))
+    }
+
+    /// Get a handle to the IndexedDB factory, if one is available.
+    pub fn indexed_db(&self) -> Option<web_sys::IdbFactory> {
+        match self {
+            GlobalScope::Window(scope) => scope.indexed_db().ok().flatten(),
+            GlobalScope::Worker(scope) => scope.indexed_db().ok().flatten(),
+            GlobalScope::Other(_) => None,
+        }
+    }
+
+    /// Get a handle to the origin's `StorageManager`, if one is available.
+    pub fn storage(&self) -> Option<web_sys::StorageManager> {
+        let storage = match self {
+            GlobalScope::Window(scope) => scope.navigator().storage(),
+            GlobalScope::Worker(scope) => scope.navigator().storage(),
+            GlobalScope::Other(_) => return None,
+        };
+
+        // Note: navigator.storage is missing in insecure contexts.
+        (!JsValue::from(storage.clone()).is_undefined()).then_some(storage)
+    }
+
+    /// Look up a property on the global scope (e.g. `["navigator", "gpu"]`),
+    /// returning `None` if it is missing.
+    pub(crate) fn lookup(&self, path: &[&str]) -> Option<JsValue> {
+        let mut value: JsValue = self.as_object().clone().into();
+
+        for name in path {
+            value = js_sys::Reflect::get(&value, &JsValue::from_str(name)).ok()?;
+            if value.is_undefined() || value.is_null() {
+                return None;
+            }
+        }
+
+        Some(value)
+    }
+
     fn as_object(&self) -> &js_sys::Object {
         match self {
             GlobalScope::Window(w) => w,
@@ -140,6 +221,13 @@ impl From<Error> for JsValue {
         match error {
             Error::JavaScript(e) => e,
             Error::Rust(error) => {
+                if let Some(blocked) = error.downcast_ref::<WorkerSpawnBlocked>() {
+                    return blocked.to_js();
+                }
+                if let Some(undelivered) = error.downcast_ref::<TaskUndelivered>() {
+                    return undelivered.to_js();
+                }
+
                 let message = error.to_string();
                 let js_error = js_sys::Error::new(&message);
 
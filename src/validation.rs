@@ -0,0 +1,389 @@
+//! Validating a module ourselves before the browser compiles it, so
+//! untrusted packages can be stopped from using WebAssembly features (e.g.
+//! threads or SIMD) and the error says exactly where the offending code is.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasmparser::{
+    FuncValidatorAllocations, Parser, Payload, ValidPayload, Validator, WasmFeatures,
+};
+
+use crate::utils::Error;
+
+/// A post-MVP WebAssembly feature a module can be forbidden from using.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Feature {
+    Threads,
+    Simd,
+    RelaxedSimd,
+    Exceptions,
+    TailCalls,
+    Memory64,
+    MultiMemory,
+    ExtendedConst,
+    ReferenceTypes,
+    BulkMemory,
+}
+
+impl Feature {
+    const ALL: [Feature; 10] = [
+        Feature::Threads,
+        Feature::Simd,
+        Feature::RelaxedSimd,
+        Feature::Exceptions,
+        Feature::TailCalls,
+        Feature::Memory64,
+        Feature::MultiMemory,
+        Feature::ExtendedConst,
+        Feature::ReferenceTypes,
+        Feature::BulkMemory,
+    ];
+
+    fn disable(self, features: &mut WasmFeatures) {
+        match self {
+            Feature::Threads => features.threads = false,
+            // Note: relaxed SIMD builds on top of SIMD
+            Feature::Simd => {
+                features.simd = false;
+                features.relaxed_simd = false;
+            }
+            Feature::RelaxedSimd => features.relaxed_simd = false,
+            Feature::Exceptions => features.exceptions = false,
+            Feature::TailCalls => features.tail_call = false,
+            Feature::Memory64 => features.memory64 = false,
+            Feature::MultiMemory => features.multi_memory = false,
+            Feature::ExtendedConst => features.extended_const = false,
+            Feature::ReferenceTypes => features.reference_types = false,
+            Feature::BulkMemory => features.bulk_memory = false,
+        }
+    }
+}
+
+/// How thoroughly a module is checked before it is compiled.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ValidationLevel {
+    /// Leave it to the browser, unless some features are denied.
+    #[default]
+    Browser,
+    /// Always validate the module ourselves.
+    Strict,
+}
+
+/// The parsed `validation` option.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+pub(crate) struct ModuleValidation {
+    level: ValidationLevel,
+    deny: Vec<Feature>,
+}
+
+impl ModuleValidation {
+    pub(crate) fn parse(value: JsValue) -> Result<Self, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(ModuleValidation::default());
+        }
+
+        serde_wasm_bindgen::from_value(value).map_err(Error::js)
+    }
+
+    /// Does the module need to be validated before it is compiled?
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.level == ValidationLevel::Strict || !self.deny.is_empty()
+    }
+
+    /// Everything the browsers we support can compile, minus the features
+    /// that were denied.
+    fn allowed_features(&self) -> WasmFeatures {
+        let mut features = all_features();
+        for feature in &self.deny {
+            feature.disable(&mut features);
+        }
+        features
+    }
+
+    /// Make sure `wasm` (a binary or `*.wat` text) only uses the features it
+    /// is allowed to, raising a `ModuleValidationError` listing every
+    /// violation otherwise.
+    pub(crate) fn check(&self, wasm: &[u8]) -> Result<(), Error> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let wasm = wasmer::wat2wasm(wasm)?;
+        let violations = violations(&wasm, self.allowed_features());
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        let message = ValidationReport(&violations).to_string();
+        let error = js_sys::Error::new(&message);
+        error.set_name("ModuleValidationError");
+        let violations = serde_wasm_bindgen::to_value(&violations).map_err(Error::js)?;
+        js_sys::Reflect::set(&error, &JsValue::from_str("violations"), &violations)
+            .map_err(Error::js)?;
+
+        Err(Error::js(error))
+    }
+}
+
+fn all_features() -> WasmFeatures {
+    WasmFeatures {
+        mutable_global: true,
+        saturating_float_to_int: true,
+        sign_extension: true,
+        reference_types: true,
+        multi_value: true,
+        bulk_memory: true,
+        simd: true,
+        relaxed_simd: true,
+        threads: true,
+        tail_call: true,
+        multi_memory: true,
+        exceptions: true,
+        memory64: true,
+        extended_const: true,
+        ..WasmFeatures::default()
+    }
+}
+
+/// Every problem which makes `wasm` invalid, no matter which features are
+/// allowed.
+pub(crate) fn errors(wasm: &[u8]) -> Vec<Violation> {
+    violations(wasm, all_features())
+}
+
+/// The features a valid module can't be compiled without.
+///
+/// This validates the module once per feature, each time with just that
+/// feature taken away.
+pub(crate) fn required_features(wasm: &[u8]) -> Vec<Feature> {
+    Feature::ALL
+        .into_iter()
+        .filter(|feature| {
+            let mut allowed = all_features();
+            feature.disable(&mut allowed);
+            !violations(wasm, allowed).is_empty()
+        })
+        .collect()
+}
+
+/// Something in a module that isn't allowed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Violation {
+    /// The section the problem was found in (e.g. `"memory"` or `"code"`).
+    section: &'static str,
+    /// The index of the offending function, for problems in a function body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function: Option<u32>,
+    /// Where the problem is, in bytes from the start of the module.
+    offset: usize,
+    message: String,
+}
+
+/// Find everything in `wasm` which isn't valid with `allowed` features.
+///
+/// Two validators run side by side. One has every feature enabled, so we can
+/// keep going past a disallowed section and still check each function body,
+/// while the other rejects the first section using a disallowed feature.
+/// That way we report one problem per function instead of giving up at the
+/// first opcode.
+fn violations(wasm: &[u8], allowed: WasmFeatures) -> Vec<Violation> {
+    let mut full = Validator::new_with_features(all_features());
+    let mut restricted = Some(Validator::new_with_features(allowed));
+    let mut violations = Vec::new();
+    let mut allocations = FuncValidatorAllocations::default();
+
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = match payload {
+            Ok(p) => p,
+            Err(e) => {
+                violations.push(Violation {
+                    section: "module",
+                    function: None,
+                    offset: e.offset(),
+                    message: e.message().to_string(),
+                });
+                break;
+            }
+        };
+        let section = section_name(&payload);
+        let mut reported = false;
+
+        if let Some(validator) = restricted.as_mut() {
+            if let Err(e) = validator.payload(&payload) {
+                violations.push(Violation {
+                    section,
+                    function: None,
+                    offset: e.offset(),
+                    message: e.message().to_string(),
+                });
+                // Note: the validator's state is unreliable after an error
+                restricted = None;
+                reported = true;
+            }
+        }
+
+        match full.payload(&payload) {
+            Ok(ValidPayload::Func(mut func, body)) => {
+                func.features = allowed;
+                let index = func.index;
+                let mut validator = func.into_validator(allocations);
+                if let Err(e) = validator.validate(&body) {
+                    violations.push(Violation {
+                        section,
+                        function: Some(index),
+                        offset: e.offset(),
+                        message: e.message().to_string(),
+                    });
+                }
+                allocations = validator.into_allocations();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The module is invalid no matter which features are allowed
+                if !reported {
+                    violations.push(Violation {
+                        section,
+                        function: None,
+                        offset: e.offset(),
+                        message: e.message().to_string(),
+                    });
+                }
+                break;
+            }
+        }
+    }
+
+    violations
+}
+
+fn section_name(payload: &Payload<'_>) -> &'static str {
+    match payload {
+        Payload::Version { .. } => "header",
+        Payload::TypeSection(_) => "type",
+        Payload::ImportSection(_) => "import",
+        Payload::FunctionSection(_) => "function",
+        Payload::TableSection(_) => "table",
+        Payload::MemorySection(_) => "memory",
+        Payload::TagSection(_) => "tag",
+        Payload::GlobalSection(_) => "global",
+        Payload::ExportSection(_) => "export",
+        Payload::StartSection { .. } => "start",
+        Payload::ElementSection(_) => "element",
+        Payload::DataCountSection { .. } => "datacount",
+        Payload::DataSection(_) => "data",
+        Payload::CodeSectionStart { .. } | Payload::CodeSectionEntry(_) => "code",
+        Payload::CustomSection(_) => "custom",
+        _ => "module",
+    }
+}
+
+struct ValidationReport<'a>(&'a [Violation]);
+
+impl fmt::Display for ValidationReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The module failed validation:")?;
+
+        for violation in self.0 {
+            let Violation {
+                section,
+                function,
+                offset,
+                message,
+            } = violation;
+            write!(f, "\n  - {section} section")?;
+            if let Some(index) = function {
+                write!(f, ", function {index}")?;
+            }
+            write!(f, " (offset {offset:#x}): {message}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const MODULE_VALIDATION_TYPE_DEF: &'static str = r#"
+/**
+ * A post-MVP WebAssembly feature which can be denied with
+ * {@link ModuleValidation.deny}.
+ */
+export type WasmFeature =
+    | "threads"
+    | "simd"
+    | "relaxed-simd"
+    | "exceptions"
+    | "tail-calls"
+    | "memory64"
+    | "multi-memory"
+    | "extended-const"
+    | "reference-types"
+    | "bulk-memory";
+
+/**
+ * How a program's module is checked before it is compiled.
+ */
+export type ModuleValidation = {
+    /**
+     * `"browser"` (the default) leaves validation to the browser's
+     * compiler, while `"strict"` always validates the module first so
+     * errors point at the offending section and byte offset.
+     */
+    level?: "browser" | "strict";
+    /**
+     * Features the module may not use. Denying anything implies strict
+     * validation.
+     */
+    deny?: WasmFeature[];
+};
+
+/**
+ * One of the problems listed on a `ModuleValidationError`'s `violations`.
+ */
+export type ValidationViolation = {
+    section: string;
+    /** The index of the offending function, for problems in its body. */
+    function?: number;
+    /** Where the problem is, in bytes from the start of the module. */
+    offset: number;
+    message: string;
+};
+"#;
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn denied_features_are_reported_per_function() {
+        let wat = r#"
+            (module
+                (memory 1 1 shared)
+                (func (result i32) i32.const 0 i32.atomic.load)
+                (func (result i32) i32.const 1)
+                (func (local v128)))
+        "#;
+        let wasm = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+        let validation = ModuleValidation {
+            level: ValidationLevel::Browser,
+            deny: vec![Feature::Threads, Feature::Simd],
+        };
+
+        let violations = violations(&wasm, validation.allowed_features());
+
+        let found: Vec<_> = violations.iter().map(|v| (v.section, v.function)).collect();
+        assert_eq!(
+            found,
+            [("memory", None), ("code", Some(0)), ("code", Some(2))]
+        );
+        assert!(violations(&wasm, all_features()).is_empty());
+        assert!(!ModuleValidation::default().is_enabled());
+    }
+}
@@ -1,23 +1,54 @@
 use std::sync::Arc;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{channel::oneshot, TryStreamExt};
 use js_sys::{JsString, Reflect, Uint8Array};
 use tracing::Instrument;
-use virtual_fs::{AsyncReadExt, Pipe};
-use wasm_bindgen::{prelude::wasm_bindgen, JsValue, UnwrapThrowExt};
+use virtual_fs::{AsyncReadExt, Pipe, VirtualFile};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue, UnwrapThrowExt};
 use wasmer_wasix::{
     bin_factory::BinaryPackage,
-    os::{Tty, TtyOptions},
+    os::TtyOptions,
     runners::{wasi::WasiRunner, Runner},
-    runtime::resolver::PackageSpecifier,
+    runtime::{
+        module_cache::ModuleHash,
+        package_loader::PackageLoader as _,
+        resolver::{PackageSpecifier, PackageSummary, Source as _},
+    },
     Runtime as _,
 };
 use web_sys::{ReadableStream, WritableStream};
+use webc::Container;
 
 use crate::{
-    instance::ExitCondition,
+    bench::{BenchOptions, JsBenchReport},
+    blobs::{Blob, BlobStore},
+    build_info::{BuildInfo, JsBuildInfo},
+    crash::{CrashContext, CrashReporter},
+    descriptors::DescriptorTable,
+    dns,
+    events::{MountKind, RuntimeEvent},
+    fs::report_shadowed,
+    host_info::{self, HostInfo},
+    instance::{ExitCondition, ExitSender},
+    json_rpc::LanguageServer,
+    manifest::{JsPackageManifest, PackageManifest},
+    module_cache::Origin,
+    module_info::JsModuleReport,
+    options::Stdio,
+    package_info::{ListOfAtomInfo, ListOfVolumeInfo},
+    preload::{ListOfPreloadHint, PreloadOptions},
+    provenance::{JsProvenance, Provenance},
+    readline::{self, Edit, LineEditor},
+    recording::{EventKind, SessionRecording},
     runtime::Runtime,
+    scripts::{Script, Scripts},
+    sequenced_output::OutputStream,
+    streams::StdinHandle,
+    terminal::{LazyTty, TtyDemand, TtyMode},
+    thread_audit::JsThreadingReport,
+    trace_context::TraceContext,
+    usage::ResourceUsage,
     utils::{Error, GlobalScope},
     Instance, JsRuntime, SpawnOptions,
 };
@@ -38,7 +69,7 @@ use crate::{
 ///     throw new Error(`Python exited with ${code}: ${stderr}`);
 /// }
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, wasm_bindgen_derive::TryFromJsValue)]
 #[wasm_bindgen]
 pub struct Wasmer {
     /// The package's entrypoint.
@@ -48,6 +79,14 @@ pub struct Wasmer {
     /// dependencies).
     #[wasm_bindgen(getter_with_clone)]
     pub commands: Commands,
+    pkg: Arc<BinaryPackage>,
+    runtime: Arc<Runtime>,
+    /// The package's webc file, if we could get hold of it.
+    container: Option<Container>,
+    /// Keeps the bytes of a package loaded from a file in the shared blob
+    /// store while the package is in use.
+    _webc: Option<Blob>,
+    provenance: Provenance,
 }
 
 #[wasm_bindgen]
@@ -61,6 +100,88 @@ impl Wasmer {
         Wasmer::from_registry(specifier, runtime).await
     }
 
+    /// Get the URLs of the assets the SDK will need once programs start
+    /// running, so the browser can fetch them early.
+    ///
+    /// By default, they get preloaded straight away. The returned hints can
+    /// also be rendered as `<link>` elements in a page's HTML.
+    ///
+    /// @example
+    /// ```ts
+    /// await init();
+    /// Wasmer.preload();
+    /// ```
+    pub fn preload(options: Option<PreloadOptions>) -> Result<ListOfPreloadHint, Error> {
+        crate::preload::preload(options)
+    }
+
+    /// Details about this build of the SDK (its version, the versions of the
+    /// `wasmer` and `wasmer-wasix` crates, the commit it was built from, and
+    /// the capabilities this context supports), for bug reports and feature
+    /// negotiation.
+    #[wasm_bindgen(js_name = "buildInfo")]
+    pub fn build_info() -> Result<JsBuildInfo, Error> {
+        BuildInfo::current().to_js()
+    }
+
+    /// Describe a WebAssembly module (binary or text) without compiling or
+    /// instantiating it: what it imports and exports, the memories and
+    /// tables it needs, the post-MVP features it uses, and whether it has a
+    /// start function.
+    ///
+    /// Invalid modules don't throw. The problems are listed in the report's
+    /// `errors` instead.
+    ///
+    /// @example
+    /// ```ts
+    /// const report = Wasmer.inspect(bytes);
+    /// if (report.unsupported.length > 0) {
+    ///     console.warn("This browser can't run it:", report.unsupported);
+    /// }
+    /// ```
+    pub fn inspect(module: Uint8Array) -> Result<JsModuleReport, Error> {
+        crate::module_info::inspect(&module.to_vec()).to_js()
+    }
+
+    /// Explain how the runtime will start a module (binary or text) and its
+    /// threads, and whether those threads will actually run in parallel on
+    /// this page.
+    ///
+    /// @example
+    /// ```ts
+    /// const report = Wasmer.auditThreads(bytes);
+    /// if (!report.parallel) {
+    ///     console.warn(report.notes.join("\n"));
+    /// }
+    /// ```
+    #[wasm_bindgen(js_name = "auditThreads")]
+    pub fn audit_threads(module: Uint8Array) -> Result<JsThreadingReport, Error> {
+        crate::thread_audit::audit(&module.to_vec()).to_js()
+    }
+
+    /// Measure how long a package takes to load, compile and run, both right
+    /// after the runtime's caches are dropped and with warm caches.
+    ///
+    /// This is meant for comparing performance across browsers and for
+    /// attaching comparable numbers to bug reports.
+    ///
+    /// @example
+    /// ```ts
+    /// const report = await Wasmer.bench("wasmer/python", {
+    ///     args: ["-c", "print(1)"],
+    ///     iterations: 10,
+    /// });
+    /// console.log(report.warm.total.median);
+    /// ```
+    pub async fn bench(
+        specifier: String,
+        options: Option<BenchOptions>,
+        runtime: Option<OptionalRuntime>,
+    ) -> Result<JsBenchReport, Error> {
+        let runtime = runtime.unwrap_or_default().resolve()?.into_inner();
+        crate::bench::bench(&specifier, options, runtime).await
+    }
+
     /// Load a package from a package file.
     #[wasm_bindgen(js_name = "fromFile")]
     pub async fn js_from_file(
@@ -69,6 +190,68 @@ impl Wasmer {
     ) -> Result<Wasmer, Error> {
         Wasmer::from_file(binary.to_vec(), runtime).await
     }
+
+    /// Metadata from the package's manifest, including a description of each
+    /// command.
+    #[wasm_bindgen(getter)]
+    pub fn manifest(&self) -> Result<JsPackageManifest, Error> {
+        let manifest = PackageManifest::from_package(&self.pkg)?;
+        Ok(manifest.to_js()?.unchecked_into())
+    }
+
+    /// Named presets for running the package's commands with particular
+    /// arguments and environment variables, like `npm run` scripts.
+    ///
+    /// They are declared in the `scripts` annotation of the package's
+    /// manifest, so this is empty if the package's contents aren't
+    /// available. Scripts written as a command line are split on whitespace
+    /// and skipped if they contain quotes or backslashes.
+    #[wasm_bindgen(getter)]
+    pub fn scripts(&self) -> Result<Scripts, Error> {
+        let scripts = Scripts::default();
+        let Some(container) = &self.container else {
+            return Ok(scripts);
+        };
+
+        let presets = crate::scripts::presets(container, self.pkg.entrypoint_cmd.as_deref())?;
+        for (name, preset) in presets {
+            let target = self.command(&preset.command);
+            let key = JsValue::from_str(&name);
+            let script = Script::new(name, preset, target);
+            Reflect::set(&scripts, &key, &JsValue::from(script)).map_err(Error::js)?;
+        }
+
+        Ok(scripts)
+    }
+
+    /// Where the package came from, including who published it and the hash
+    /// of its contents, so hosts can decide whether to trust it.
+    pub fn provenance(&self) -> Result<JsProvenance, Error> {
+        let value = serde_wasm_bindgen::to_value(&self.provenance).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// List the WebAssembly modules bundled with this package.
+    pub fn atoms(&self) -> Result<ListOfAtomInfo, Error> {
+        let atoms = crate::package_info::atoms(self.container()?);
+        let value = serde_wasm_bindgen::to_value(&atoms).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// List the package's volumes, with the number of files each contains.
+    pub fn volumes(&self) -> Result<ListOfVolumeInfo, Error> {
+        let volumes = crate::package_info::volumes(self.container()?);
+        let value = serde_wasm_bindgen::to_value(&volumes).map_err(Error::js)?;
+        Ok(value.unchecked_into())
+    }
+
+    /// Read a single file from one of the package's volumes without mounting
+    /// it, returning `undefined` if there is no such file.
+    #[wasm_bindgen(js_name = "readVolumeFile")]
+    pub fn read_volume_file(&self, volume: &str, path: &str) -> Result<Option<Uint8Array>, Error> {
+        let contents = crate::package_info::read_file(self.container()?, volume, path);
+        Ok(contents.map(|bytes| Uint8Array::from(bytes.as_slice())))
+    }
 }
 
 /// The actual impl - with `#[tracing::instrument]` macros.
@@ -78,27 +261,63 @@ impl Wasmer {
         specifier: &str,
         runtime: Option<OptionalRuntime>,
     ) -> Result<Self, Error> {
-        let specifier = PackageSpecifier::parse(specifier)?;
         let runtime = runtime.unwrap_or_default().resolve()?.into_inner();
+        Wasmer::from_registry_with_runtime(specifier, runtime).await
+    }
+
+    pub(crate) async fn from_registry_with_runtime(
+        specifier: &str,
+        runtime: Arc<Runtime>,
+    ) -> Result<Self, Error> {
+        let specifier = PackageSpecifier::parse(specifier)?;
         let pkg = BinaryPackage::from_registry(&specifier, &*runtime).await?;
+        let summary = find_summary(&specifier, &pkg, &runtime).await;
+        let provenance = match &summary {
+            Some(summary) => Provenance::from_summary(summary),
+            None => Provenance::unknown(&pkg.package_name, &pkg.version.to_string()),
+        };
+        let container = match &summary {
+            Some(summary) => load_container(summary, &runtime).await,
+            None => None,
+        };
 
-        Wasmer::from_package(pkg, runtime)
+        Wasmer::from_package(pkg, runtime, container, None, provenance)
     }
 
     #[tracing::instrument(skip(runtime))]
     async fn from_file(binary: Vec<u8>, runtime: Option<OptionalRuntime>) -> Result<Self, Error> {
         let runtime = runtime.unwrap_or_default().resolve()?.into_inner();
-        let container = webc::Container::from_bytes(binary)?;
+        // Note: the container's volumes are slices of the stored copy, so
+        // loading the same file twice doesn't keep two copies around
+        let webc = BlobStore::global().insert(Bytes::from(binary));
+        let container = webc::Container::from_bytes(webc.bytes())?;
         let pkg = BinaryPackage::from_webc(&container, &*runtime).await?;
+        let provenance =
+            Provenance::from_file(&pkg.package_name, &pkg.version.to_string(), webc.hash());
 
-        Wasmer::from_package(pkg, runtime)
+        Wasmer::from_package(pkg, runtime, Some(container), Some(webc), provenance)
     }
 
-    fn from_package(pkg: BinaryPackage, runtime: Arc<Runtime>) -> Result<Self, Error> {
+    fn from_package(
+        pkg: BinaryPackage,
+        runtime: Arc<Runtime>,
+        container: Option<Container>,
+        webc: Option<Blob>,
+        provenance: Provenance,
+    ) -> Result<Self, Error> {
         let pkg = Arc::new(pkg);
         let commands = Commands::default();
 
         for cmd in &pkg.commands {
+            runtime.modules().describe(
+                ModuleHash::hash(cmd.atom()),
+                cmd.atom().len(),
+                Origin::Atom {
+                    package: format!("{}@{}", pkg.package_name, pkg.version),
+                    command: cmd.name().to_string(),
+                },
+            );
+
             let name = JsString::from(cmd.name());
             let value = JsValue::from(Command {
                 name: name.clone(),
@@ -111,14 +330,59 @@ impl Wasmer {
         let entrypoint = pkg.entrypoint_cmd.as_deref().map(|name| Command {
             name: name.into(),
             pkg: Arc::clone(&pkg),
-            runtime,
+            runtime: Arc::clone(&runtime),
         });
 
         Ok(Wasmer {
             entrypoint,
             commands,
+            pkg,
+            runtime,
+            container,
+            _webc: webc,
+            provenance,
         })
     }
+
+    fn container(&self) -> Result<&Container, Error> {
+        self.container
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The package's contents aren't available").into())
+    }
+
+    /// Look up one of the package's commands by name.
+    pub(crate) fn command(&self, name: &str) -> Option<Command> {
+        self.pkg.get_command(name).map(|_| Command {
+            name: name.into(),
+            pkg: Arc::clone(&self.pkg),
+            runtime: Arc::clone(&self.runtime),
+        })
+    }
+}
+
+/// Get the webc file for a package that was just loaded from the registry.
+///
+/// The package loader caches downloads, so this shouldn't hit the network.
+/// Look up the summary the package was resolved from.
+async fn find_summary(
+    specifier: &PackageSpecifier,
+    pkg: &BinaryPackage,
+    runtime: &Runtime,
+) -> Option<PackageSummary> {
+    let summaries = runtime.source().query(specifier).await.ok()?;
+    summaries
+        .into_iter()
+        .find(|s| s.pkg.name == pkg.package_name && s.pkg.version == pkg.version)
+}
+
+async fn load_container(summary: &PackageSummary, runtime: &Runtime) -> Option<Container> {
+    match runtime.package_loader().load(summary).await {
+        Ok(container) => Some(container),
+        Err(e) => {
+            tracing::debug!(error = &*e, "Unable to load the package's webc file");
+            None
+        }
+    }
 }
 
 /// A runnable WASIX command.
@@ -133,33 +397,113 @@ pub struct Command {
 
 #[wasm_bindgen]
 impl Command {
+    /// Run this command as a language server, returning a JSON-RPC
+    /// connection to it over stdin and stdout.
+    ///
+    /// `options.stdin` must not be set because the connection needs it.
+    #[wasm_bindgen(js_name = "startLanguageServer")]
+    pub async fn start_language_server(
+        &self,
+        options: Option<SpawnOptions>,
+    ) -> Result<LanguageServer, Error> {
+        let instance = self.run(options).await?;
+        LanguageServer::new(instance)
+    }
+
     pub async fn run(&self, options: Option<SpawnOptions>) -> Result<Instance, Error> {
         let runtime = Arc::clone(&self.runtime);
         let pkg = Arc::clone(&self.pkg);
         let tasks = Arc::clone(runtime.task_manager());
 
         let options = options.unwrap_or_default();
+        let slot = runtime.acquire_instance_slot().await?;
 
+        let usage = Arc::new(ResourceUsage::default());
+        usage.track_network(runtime.network_counters());
         let mut runner = WasiRunner::new();
-        let (stdin, stdout, stderr) = configure_runner(&options, &mut runner, &runtime).await?;
+        let stdio = configure_runner(&options, &mut runner, &runtime, &usage).await?;
         let command_name = String::from(&self.name);
 
+        if let Some(cmd) = pkg.get_command(&command_name) {
+            options.validation()?.check(cmd.atom())?;
+        }
+
+        let reporter = CrashReporter::new(
+            &options,
+            || {
+                Ok(CrashContext {
+                    module_hash: pkg
+                        .get_command(&command_name)
+                        .map(|cmd| ModuleHash::hash(cmd.atom())),
+                    package: Some(format!("{}@{}", pkg.package_name, pkg.version)),
+                    command: Some(command_name.clone()),
+                    ..CrashContext::from_options(&options)?
+                })
+            },
+            runtime.http_client(),
+        )?;
+        let crashes = CrashReporter::spawn(reporter);
+
         tracing::debug!(%command_name, "Starting the WASI runner");
 
         let (sender, receiver) = oneshot::channel();
 
+        // Note: anything the program spawns goes through a scoped runtime so
+        // it can be cancelled once the program exits.
+        let (mut scoped_runtime, scope) = runtime.scoped();
+        let trace = options.trace_context()?;
+        if let Some(trace) = trace.clone() {
+            scoped_runtime = scoped_runtime.with_trace_context(trace);
+        }
+
         // Note: The WasiRunner::run_command() method blocks, so we need to run
         // it on the thread pool.
-        tasks.task_dedicated(Box::new(move || {
-            let result = runner.run_command(&command_name, &pkg, runtime);
-            let _ = sender.send(ExitCondition::from_result(result));
+        let descriptors = DescriptorTable::default();
+        tasks.task_dedicated(Box::new({
+            let usage = Arc::clone(&usage);
+            let scope = scope.clone();
+            let descriptors = descriptors.clone();
+            move || {
+                let span = tracing::debug_span!(
+                    "run_command",
+                    %command_name,
+                    traceparent = tracing::field::Empty
+                );
+                TraceContext::record(trace.as_ref(), &span);
+                let _span = span.entered();
+                let exit = ExitSender::new(sender);
+                let _busy = usage.busy();
+                let result = crate::run::run_command(
+                    &runner,
+                    &command_name,
+                    &pkg,
+                    Arc::new(scoped_runtime),
+                    &descriptors,
+                );
+                usage.stop_tracking_network();
+                drop(slot);
+                scope.cancel();
+                crashes.notify(&result);
+                exit.send(ExitCondition::from_result(result));
+            }
         }))?;
 
         Ok(Instance {
-            stdin,
-            stdout,
-            stderr,
+            stdin: stdio.stdin,
+            stdin_handle: stdio.stdin_handle,
+            stdout: stdio.stdout,
+            stderr: stdio.stderr,
+            output: stdio.output,
+            echo: stdio.echo,
+            recording: stdio.recording,
             exit: receiver,
+            descriptors: Some(descriptors),
+            fs: None,
+            usage,
+            tasks: Some(scope),
+            process: None,
+            env: options.effective_env(&runtime)?,
+            memory: None,
         })
     }
 
@@ -207,23 +551,142 @@ pub(crate) async fn configure_runner(
     options: &SpawnOptions,
     runner: &mut WasiRunner,
     runtime: &Runtime,
-) -> Result<
-    (
-        Option<web_sys::WritableStream>,
-        web_sys::ReadableStream,
-        web_sys::ReadableStream,
-    ),
-    Error,
-> {
+    usage: &Arc<ResourceUsage>,
+) -> Result<Stdio, Error> {
     let args = options.parse_args()?;
     runner.set_args(args);
 
-    let env = options.parse_env()?;
+    let env = options.effective_env(runtime)?;
     runner.set_envs(env);
 
-    for (dest, dir) in options.mounted_directories()? {
+    let mounted = options.mounted_directories()?;
+    let line_endings = options.line_endings()?;
+    let faults = options.faults()?;
+    let pool = runtime.thread_pool();
+    let sandbox = options.sandbox(pool)?;
+    let attached = |path: &str, kind: MountKind| {
+        pool.emit(RuntimeEvent::MountAttached {
+            pid: None,
+            path: path.to_string(),
+            kind,
+        });
+    };
+
+    match mounted
+        .iter()
+        .find(|(dest, _)| dest == host_info::MOUNT_POINT)
+    {
+        Some((dest, _)) => report_shadowed(pool, None, host_info::MOUNT_POINT, dest),
+        None => {
+            let proc = HostInfo::current().filesystem(Arc::clone(usage));
+            runner.mount(host_info::MOUNT_POINT.to_string(), Arc::new(proc));
+            attached(host_info::MOUNT_POINT, MountKind::Generated);
+        }
+    }
+    if let Some(etc) = runtime.etc_filesystem() {
+        match mounted.iter().find(|(dest, _)| dest == dns::MOUNT_POINT) {
+            Some((dest, _)) => report_shadowed(pool, None, dns::MOUNT_POINT, dest),
+            None => {
+                runner.mount(dns::MOUNT_POINT.to_string(), Arc::new(etc));
+                attached(dns::MOUNT_POINT, MountKind::Generated);
+            }
+        }
+    }
+    match mounted
+        .iter()
+        .find(|(dest, _)| crate::timers::conflicts_with(dest))
+    {
+        Some((dest, _)) => report_shadowed(pool, None, crate::timers::MOUNT_POINT, dest),
+        None => {
+            let timers = crate::timers::filesystem(pool);
+            runner.mount(crate::timers::MOUNT_POINT.to_string(), Arc::new(timers));
+            attached(crate::timers::MOUNT_POINT, MountKind::Generated);
+        }
+    }
+    if runtime.credentials().is_enabled() {
+        match mounted
+            .iter()
+            .find(|(dest, _)| crate::credentials::conflicts_with(dest))
+        {
+            Some((dest, _)) => report_shadowed(pool, None, crate::credentials::MOUNT_POINT, dest),
+            None => {
+                let credentials = runtime.credentials().filesystem(pool);
+                runner.mount(
+                    crate::credentials::MOUNT_POINT.to_string(),
+                    Arc::new(credentials),
+                );
+                attached(crate::credentials::MOUNT_POINT, MountKind::Generated);
+            }
+        }
+    }
+    match mounted
+        .iter()
+        .find(|(dest, _)| crate::progress::conflicts_with(dest))
+    {
+        Some((dest, _)) => report_shadowed(pool, None, crate::progress::MOUNT_POINT, dest),
+        None => {
+            let progress = crate::progress::filesystem(pool, None);
+            runner.mount(crate::progress::MOUNT_POINT.to_string(), Arc::new(progress));
+            attached(crate::progress::MOUNT_POINT, MountKind::Generated);
+        }
+    }
+    match mounted
+        .iter()
+        .find(|(dest, _)| crate::pipes::conflicts_with(dest))
+    {
+        Some((dest, _)) => report_shadowed(pool, None, crate::pipes::MOUNT_POINT, dest),
+        None => {
+            let pipes = runtime.pipes().filesystem();
+            runner.mount(crate::pipes::MOUNT_POINT.to_string(), Arc::new(pipes));
+            attached(crate::pipes::MOUNT_POINT, MountKind::Generated);
+        }
+    }
+    match mounted
+        .iter()
+        .find(|(dest, _)| crate::shared_memory::conflicts_with(dest))
+    {
+        Some((dest, _)) => report_shadowed(pool, None, crate::shared_memory::MOUNT_POINT, dest),
+        None => {
+            let segments = runtime.shared_memory().filesystem();
+            runner.mount(
+                crate::shared_memory::MOUNT_POINT.to_string(),
+                Arc::new(segments),
+            );
+            attached(crate::shared_memory::MOUNT_POINT, MountKind::Generated);
+        }
+    }
+    if let Some(locale) = options.locale()? {
+        for (dir, fs) in locale.mounts(runtime.http_client().cloned()) {
+            match mounted
+                .iter()
+                .find(|(dest, _)| crate::locale::conflicts_with(&dir, dest))
+            {
+                Some((dest, _)) => report_shadowed(pool, None, &dir, dest),
+                None => {
+                    runner.mount(dir.clone(), Arc::new(fs));
+                    attached(&dir, MountKind::Generated);
+                }
+            }
+        }
+    }
+    let (mut files, dirs) = match options.profile()? {
+        Some(profile) => {
+            let profile = profile.for_package(&mounted);
+            (profile.files, profile.dirs)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+    files.extend(options.files()?);
+    let (files, mounted) = crate::fs::inline_files::into_mounts(files, &dirs, mounted)?;
+    for (dest, dir) in files {
+        attached(&dest, MountKind::Generated);
         runner.mount(dest, Arc::new(dir));
     }
+    for (dest, dir) in mounted {
+        attached(&dest, MountKind::Directory);
+        let fs = sandbox.mount(&dest, faults.mount(&dest, line_endings.mount(&dest, dir)));
+        runner.mount(dest, fs);
+    }
 
     if let Some(uses) = options.uses() {
         let uses = crate::utils::js_string_array(uses)?;
@@ -231,28 +694,85 @@ pub(crate) async fn configure_runner(
         runner.add_injected_packages(packages);
     }
 
-    let (stderr_pipe, stderr_stream) = crate::streams::output_pipe();
-    runner.set_stderr(Box::new(stderr_pipe));
+    let log = options.output_log();
+    let tee = |stream, pipe: Pipe| -> Box<dyn VirtualFile + Send + Sync> {
+        match &log {
+            Some((log, _)) => Box::new(log.tee(stream, pipe)),
+            None => Box::new(pipe),
+        }
+    };
+    let output = log.as_ref().map(|(_, stream)| stream.clone());
+
+    let (stderr_pipe, stderr_stream) = crate::streams::counted_output_pipe(
+        usage.stderr_bytes.clone(),
+        usage.stderr_chunks.clone(),
+    );
+    let stderr = tee(OutputStream::Stderr, stderr_pipe);
 
     let tty_options = runtime.tty_options().clone();
-    match setup_tty(options, tty_options) {
+    let recording = options
+        .record()
+        .unwrap_or(false)
+        .then(|| SessionRecording::new(tty_options.cols(), tty_options.rows()));
+    match setup_tty(
+        options,
+        options.tty()?,
+        tty_options,
+        usage,
+        recording.clone(),
+    )? {
         TerminalMode::Interactive {
             stdin_pipe,
             stdout_pipe,
             stdout_stream,
             stdin_stream,
+            stdin_handle,
+            connected,
+            demand,
         } => {
-            tracing::debug!("Setting up interactive TTY");
-            runner.set_stdin(Box::new(stdin_pipe));
-            runner.set_stdout(Box::new(stdout_pipe));
-            runtime.set_connected_to_tty(true);
-            Ok((Some(stdin_stream), stdout_stream, stderr_stream))
+            tracing::debug!(
+                connected,
+                lazy = demand.is_some(),
+                "Setting up interactive TTY"
+            );
+            let recording = recording.filter(|_| connected);
+            let mut stdout = tee(OutputStream::Stdout, stdout_pipe);
+            let mut stderr = stderr;
+            if connected {
+                stdout = crate::osc::watch_stdout(stdout, runtime.thread_pool().clone());
+            }
+            if let Some(recording) = &recording {
+                stdout = recording.watch(stdout);
+                stderr = recording.watch(stderr);
+            }
+            runner.set_stdin(faults.stdin(line_endings.stdin(Box::new(stdin_pipe))));
+            runner.set_stdout(faults.stdout(line_endings.stdout(stdout)));
+            runner.set_stderr(faults.stderr(line_endings.stderr(stderr)));
+            // Note: this has the same problem with shared runtimes as the
+            // HACK below
+            runtime.set_connected_to_tty(connected);
+            runtime.set_tty_demand(demand);
+            Ok(Stdio {
+                stdin: Some(stdin_stream),
+                stdin_handle: Some(stdin_handle),
+                stdout: stdout_stream,
+                stderr: stderr_stream,
+                output,
+                echo: connected.then(|| runtime.echo()),
+                recording,
+            })
         }
         TerminalMode::NonInteractive { stdin } => {
             tracing::debug!("Setting up non-interactive TTY");
-            let (stdout_pipe, stdout_stream) = crate::streams::output_pipe();
-            runner.set_stdin(Box::new(stdin));
-            runner.set_stdout(Box::new(stdout_pipe));
+            let (stdout_pipe, stdout_stream) = crate::streams::counted_output_pipe(
+                usage.stdout_bytes.clone(),
+                usage.stdout_chunks.clone(),
+            );
+            runner.set_stdin(faults.stdin(line_endings.stdin(Box::new(stdin))));
+            runner.set_stdout(
+                faults.stdout(line_endings.stdout(tee(OutputStream::Stdout, stdout_pipe))),
+            );
+            runner.set_stderr(faults.stderr(line_endings.stderr(stderr)));
 
             // HACK: Make sure we don't report stdin as interactive.  This
             // doesn't belong here because now it'll affect every other
@@ -260,21 +780,62 @@ pub(crate) async fn configure_runner(
             // instance should get its own TTY state, but that's an issue
             // for wasmer-wasix to work out.
             runtime.set_connected_to_tty(false);
+            runtime.set_tty_demand(None);
 
-            Ok((None, stdout_stream, stderr_stream))
+            Ok(Stdio {
+                stdin: None,
+                stdin_handle: None,
+                stdout: stdout_stream,
+                stderr: stderr_stream,
+                output,
+                echo: None,
+                recording: None,
+            })
         }
     }
 }
 
-fn setup_tty(options: &SpawnOptions, tty_options: TtyOptions) -> TerminalMode {
+fn setup_tty(
+    options: &SpawnOptions,
+    mode: TtyMode,
+    tty_options: TtyOptions,
+    usage: &ResourceUsage,
+    recording: Option<SessionRecording>,
+) -> Result<TerminalMode, Error> {
     // Handle the simple (non-interactive) case first.
     if let Some(stdin) = options.read_stdin() {
-        return TerminalMode::NonInteractive {
+        usage
+            .stdin_bytes
+            .fetch_add(stdin.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        return Ok(TerminalMode::NonInteractive {
             stdin: virtual_fs::StaticFile::new(stdin),
-        };
+        });
     }
 
-    let (stdout_pipe, stdout_stream) = crate::streams::output_pipe();
+    let editor = match options.readline() {
+        Some(readline) => Some(readline.editor()?),
+        None => None,
+    };
+
+    let (stdout_pipe, stdout_stream) = crate::streams::counted_output_pipe(
+        usage.stdout_bytes.clone(),
+        usage.stdout_chunks.clone(),
+    );
+    let (u_stdin_rx, stdin_stream, stdin_handle) =
+        crate::streams::closable_input_pipe(usage.stdin_bytes.clone());
+
+    if mode == TtyMode::Never {
+        // Without a TTY, the program can read the user's pipe directly
+        return Ok(TerminalMode::Interactive {
+            stdin_pipe: u_stdin_rx,
+            stdout_pipe,
+            stdout_stream,
+            stdin_stream,
+            stdin_handle,
+            connected: false,
+            demand: None,
+        });
+    }
 
     // Note: Because this is an interactive session, we want to intercept
     // stdin and let the TTY modify it.
@@ -289,14 +850,18 @@ fn setup_tty(options: &SpawnOptions, tty_options: TtyOptions) -> TerminalMode {
     //  ---------------------------------            --------------------          ----------------------------
     // | stdin_stream (user) u_stdin_rx | --copy--> | (tty) u_stdin_tx  | --pipe-> | stdin_pipe (runtime) ... |
     // ---------------------------------            --------------------          ----------------------------
-    let (u_stdin_rx, stdin_stream) = crate::streams::input_pipe();
+    //
+    // In "auto" mode the TTY isn't created until the program first asks
+    // about its terminal, and until then input is copied straight through.
     let (u_stdin_tx, stdin_pipe) = Pipe::channel();
 
-    let tty = Tty::new(
-        Box::new(u_stdin_tx),
-        Box::new(stdout_pipe.clone()),
+    let demand = (mode == TtyMode::Auto).then(TtyDemand::default);
+    let tty = LazyTty::new(
+        u_stdin_tx,
+        stdout_pipe.clone(),
         GlobalScope::current().is_mobile(),
-        tty_options,
+        tty_options.clone(),
+        demand.clone(),
     );
 
     // Because the TTY is manually copying between pipes, we need to make
@@ -312,22 +877,28 @@ fn setup_tty(options: &SpawnOptions, tty_options: TtyOptions) -> TerminalMode {
 
     // Use the JS event loop to drive our manual user->tty copy
     wasm_bindgen_futures::spawn_local(
-        copy_stdin_to_tty(u_stdin_rx, tty, cleanup)
+        copy_stdin_to_tty(u_stdin_rx, tty, tty_options, editor, recording, cleanup)
             .in_current_span()
             .instrument(tracing::debug_span!("tty")),
     );
 
-    TerminalMode::Interactive {
+    Ok(TerminalMode::Interactive {
         stdin_pipe,
         stdout_pipe,
         stdout_stream,
         stdin_stream,
-    }
+        stdin_handle,
+        connected: true,
+        demand,
+    })
 }
 
 fn copy_stdin_to_tty(
     mut u_stdin_rx: Pipe,
-    mut tty: Tty,
+    mut tty: LazyTty,
+    tty_options: TtyOptions,
+    mut editor: Option<LineEditor>,
+    recording: Option<SessionRecording>,
     cleanup: impl FnOnce(),
 ) -> impl std::future::Future<Output = ()> {
     /// A RAII guard used to make sure the cleanup function always gets called.
@@ -352,8 +923,51 @@ fn copy_stdin_to_tty(
                 Ok(_) => {
                     // PERF: It'd be nice if we didn't need to do a copy here.
                     let data = buffer.to_vec();
-                    tty = tty.on_event(wasmer_wasix::os::InputEvent::Raw(data)).await;
                     buffer.clear();
+                    if let Some(recording) = &recording {
+                        recording.record(EventKind::Input, &data);
+                    }
+
+                    // Note: line editing only makes sense once there is a
+                    // TTY doing line buffering, and anything typed with echo
+                    // off (e.g. a password) mustn't end up in the history
+                    let active = tty.activate();
+                    let Some(editor) = editor.as_mut().filter(|_| active && tty_options.echo())
+                    else {
+                        tty.send(data).await;
+                        continue;
+                    };
+
+                    for edit in editor.process(&data, tty_options.line_buffering()) {
+                        let data = match edit {
+                            Edit::Forward(data) => data,
+                            Edit::Submitted(line) => {
+                                if let Err(e) = editor.save(&line).await {
+                                    tracing::warn!(
+                                        error = &*e.into_anyhow(),
+                                        "Unable to save the command history",
+                                    );
+                                }
+                                continue;
+                            }
+                            Edit::Complete(line) => match editor.completions(&line).await {
+                                Ok(candidates) => {
+                                    editor.insert(&readline::common_suffix(&line, &candidates))
+                                }
+                                Err(e) => {
+                                    tracing::warn!(
+                                        error = &*e.into_anyhow(),
+                                        "The completion callback failed",
+                                    );
+                                    continue;
+                                }
+                            },
+                        };
+
+                        if !data.is_empty() {
+                            tty.send(data).await;
+                        }
+                    }
                 }
                 Err(e) => {
                     tracing::warn!(
@@ -378,6 +992,13 @@ enum TerminalMode {
         stdout_stream: ReadableStream,
         /// The [`WritableStream`] our JavaScript caller will write stdin to.
         stdin_stream: WritableStream,
+        /// Closes the writing end of `stdin_stream`'s pipe.
+        stdin_handle: StdinHandle,
+        /// Should the program be told it is attached to a terminal?
+        connected: bool,
+        /// Requested when the program first queries its terminal, if the TTY
+        /// is created on demand.
+        demand: Option<TtyDemand>,
     },
     NonInteractive {
         /// The file to use as the WASIX instance's stdin.
@@ -0,0 +1,511 @@
+//! A `wasmer_crypto` extension which gives guests access to the browser's
+//! (usually hardware-accelerated) Web Crypto API instead of slow in-wasm
+//! implementations.
+//!
+//! Programs started with `runWasix()` can import these functions from the
+//! `wasmer_crypto` module. Each returns a WASI errno.
+//!
+//! ```text
+//! crypto_digest(hash, data_ptr, data_len, out_ptr, out_len, written_out) -> errno
+//! crypto_hmac(hash, key_ptr, key_len, data_ptr, data_len, out_ptr, out_len,
+//!             written_out) -> errno
+//! crypto_aes_gcm_encrypt(key_ptr, key_len, iv_ptr, iv_len, aad_ptr, aad_len,
+//!                        data_ptr, data_len, out_ptr, out_len, written_out) -> errno
+//! crypto_aes_gcm_decrypt(/* same as crypto_aes_gcm_encrypt() */) -> errno
+//! crypto_random(buf_ptr, buf_len) -> errno
+//! ```
+//!
+//! `hash` is `0` for SHA-256, `1` for SHA-384 or `2` for SHA-512. AES-GCM
+//! uses a 128-bit tag appended to the ciphertext. If `out_len` is too small,
+//! `EOVERFLOW` is returned and `written_out` is set to the length needed.
+//! Decrypting a message that fails authentication returns `EBADMSG`.
+
+use futures::channel::oneshot;
+use js_sys::{Object, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer::{
+    imports, AsStoreMut, Function as WasmFunction, FunctionEnv, FunctionEnvMut, Imports, Memory,
+    WasmPtr,
+};
+use wasmer_wasix::types::wasi::Errno;
+
+use crate::{tasks::ThreadPool, utils::GlobalScope};
+
+/// The import module the functions are exposed under.
+pub(crate) const NAMESPACE: &str = "wasmer_crypto";
+
+/// `crypto.getRandomValues()` refuses to fill more than this many bytes at a
+/// time.
+const MAX_RANDOM_BYTES: usize = 65536;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Hash {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Hash {
+    fn from_raw(raw: u32) -> Result<Self, Errno> {
+        match raw {
+            0 => Ok(Hash::Sha256),
+            1 => Ok(Hash::Sha384),
+            2 => Ok(Hash::Sha512),
+            _ => Err(Errno::Inval),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Hash::Sha256 => "SHA-256",
+            Hash::Sha384 => "SHA-384",
+            Hash::Sha512 => "SHA-512",
+        }
+    }
+}
+
+/// Something to ask `crypto.subtle` to do.
+#[derive(Debug)]
+enum Operation {
+    Digest {
+        hash: Hash,
+        data: Vec<u8>,
+    },
+    Hmac {
+        hash: Hash,
+        key: Vec<u8>,
+        data: Vec<u8>,
+    },
+    AesGcm {
+        encrypt: bool,
+        key: Vec<u8>,
+        iv: Vec<u8>,
+        aad: Vec<u8>,
+        data: Vec<u8>,
+    },
+}
+
+impl Operation {
+    /// Run the operation on the scheduler's thread, blocking until it is
+    /// done.
+    ///
+    /// Note: `crypto.subtle` only has async methods, and promises can't
+    /// resolve on a worker that is blocked in a syscall, so the work needs
+    /// to happen elsewhere.
+    fn run_blocking(self, pool: &ThreadPool) -> Result<Vec<u8>, Errno> {
+        let (sender, receiver) = oneshot::channel();
+        pool.run_on_scheduler(Box::new(move || {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = sender.send(self.perform().await);
+            });
+        }));
+
+        crate::blocking::block_on("waiting for WebCrypto", receiver).unwrap_or(Err(Errno::Canceled))
+    }
+
+    async fn perform(self) -> Result<Vec<u8>, Errno> {
+        let subtle = GlobalScope::current()
+            .crypto()
+            .ok_or(Errno::Notsup)?
+            .subtle();
+
+        let result = match self {
+            Operation::Digest { hash, mut data } => {
+                call(subtle.digest_with_str_and_u8_array(hash.name(), &mut data)).await
+            }
+            Operation::Hmac {
+                hash,
+                key,
+                mut data,
+            } => {
+                let algorithm = object(&[("name", "HMAC".into()), ("hash", hash.name().into())]);
+                let key = import_key(&subtle, &key, &algorithm, "sign").await?;
+                call(subtle.sign_with_str_and_u8_array("HMAC", &key, &mut data)).await
+            }
+            Operation::AesGcm {
+                encrypt,
+                key,
+                iv,
+                aad,
+                mut data,
+            } => {
+                let algorithm = object(&[("name", "AES-GCM".into())]);
+                let usage = if encrypt { "encrypt" } else { "decrypt" };
+                let key = import_key(&subtle, &key, &algorithm, usage).await?;
+                let params = object(&[
+                    ("name", "AES-GCM".into()),
+                    ("iv", Uint8Array::from(&iv[..]).into()),
+                    ("additionalData", Uint8Array::from(&aad[..]).into()),
+                ]);
+
+                let promise = if encrypt {
+                    subtle.encrypt_with_object_and_u8_array(&params, &key, &mut data)
+                } else {
+                    subtle.decrypt_with_object_and_u8_array(&params, &key, &mut data)
+                };
+                // Note: the only way decryption fails with valid arguments is
+                // if the message couldn't be authenticated.
+                call(promise).await.map_err(|e| match e {
+                    Errno::Inval if !encrypt => Errno::Badmsg,
+                    other => other,
+                })
+            }
+        };
+
+        result.map(|value| Uint8Array::new(&value).to_vec())
+    }
+}
+
+/// Await a `crypto.subtle` promise, treating failures as invalid arguments.
+async fn call(promise: Result<Promise, JsValue>) -> Result<JsValue, Errno> {
+    let promise = promise.map_err(|_| Errno::Inval)?;
+    JsFuture::from(promise).await.map_err(|e| {
+        tracing::debug!(error = ?e, "A Web Crypto operation failed");
+        Errno::Inval
+    })
+}
+
+async fn import_key(
+    subtle: &web_sys::SubtleCrypto,
+    raw: &[u8],
+    algorithm: &Object,
+    usage: &str,
+) -> Result<web_sys::CryptoKey, Errno> {
+    let usages = js_sys::Array::of1(&JsValue::from_str(usage));
+    let key_data: Object = Uint8Array::from(raw).into();
+    let key =
+        call(subtle.import_key_with_object("raw", &key_data, algorithm, false, &usages)).await?;
+    Ok(key.unchecked_into())
+}
+
+fn object(fields: &[(&str, JsValue)]) -> Object {
+    let obj = Object::new();
+    for (key, value) in fields {
+        let _ = Reflect::set(&obj, &JsValue::from_str(key), value);
+    }
+    obj
+}
+
+/// State shared by the `wasmer_crypto` functions of a single instance.
+#[derive(Debug)]
+pub(crate) struct WebCrypto {
+    pub(crate) pool: ThreadPool,
+}
+
+#[derive(Debug)]
+struct CryptoEnv {
+    pool: ThreadPool,
+    memory: Option<Memory>,
+}
+
+impl WebCrypto {
+    /// Create the imports, returning a handle which needs to be given the
+    /// instance's memory once it has been instantiated.
+    pub(crate) fn imports(self, store: &mut impl AsStoreMut) -> (Imports, WebCryptoMemory) {
+        let env = FunctionEnv::new(
+            store,
+            CryptoEnv {
+                pool: self.pool,
+                memory: None,
+            },
+        );
+
+        let imports = imports! {
+            NAMESPACE => {
+                "crypto_digest" => WasmFunction::new_typed_with_env(store, &env, crypto_digest),
+                "crypto_hmac" => WasmFunction::new_typed_with_env(store, &env, crypto_hmac),
+                "crypto_aes_gcm_encrypt" => WasmFunction::new_typed_with_env(store, &env, crypto_aes_gcm_encrypt),
+                "crypto_aes_gcm_decrypt" => WasmFunction::new_typed_with_env(store, &env, crypto_aes_gcm_decrypt),
+                "crypto_random" => WasmFunction::new_typed_with_env(store, &env, crypto_random),
+            }
+        };
+
+        (imports, WebCryptoMemory(env))
+    }
+}
+
+/// Gives the `wasmer_crypto` functions access to the guest's memory.
+pub(crate) struct WebCryptoMemory(FunctionEnv<CryptoEnv>);
+
+impl WebCryptoMemory {
+    pub(crate) fn attach(&self, store: &mut impl AsStoreMut, memory: Memory) {
+        self.0.as_mut(store).memory = Some(memory);
+    }
+}
+
+fn errno(e: Errno) -> i32 {
+    e as i32
+}
+
+fn read_bytes(
+    env: &FunctionEnvMut<'_, CryptoEnv>,
+    ptr: WasmPtr<u8>,
+    len: u32,
+) -> Result<Vec<u8>, Errno> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let memory = env.data().memory.as_ref().ok_or(Errno::Fault)?;
+    let view = memory.view(env);
+    ptr.slice(&view, len)
+        .and_then(|slice| slice.read_to_vec())
+        .map_err(|_| Errno::Fault)
+}
+
+/// Copy `result` into the guest's output buffer.
+fn write_output(
+    env: &FunctionEnvMut<'_, CryptoEnv>,
+    result: Result<Vec<u8>, Errno>,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    let bytes = match result {
+        Ok(bytes) => bytes,
+        Err(e) => return errno(e),
+    };
+    let Some(memory) = env.data().memory.as_ref() else {
+        return errno(Errno::Fault);
+    };
+    let view = memory.view(env);
+
+    if written_out.write(&view, bytes.len() as u32).is_err() {
+        return errno(Errno::Fault);
+    }
+    if bytes.len() > out_len as usize {
+        return errno(Errno::Overflow);
+    }
+
+    match out
+        .slice(&view, bytes.len() as u32)
+        .and_then(|slice| slice.write_slice(&bytes))
+    {
+        Ok(()) => errno(Errno::Success),
+        Err(_) => errno(Errno::Fault),
+    }
+}
+
+fn crypto_digest(
+    env: FunctionEnvMut<'_, CryptoEnv>,
+    hash: u32,
+    data: WasmPtr<u8>,
+    data_len: u32,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    let result = (|| {
+        let hash = Hash::from_raw(hash)?;
+        let data = read_bytes(&env, data, data_len)?;
+        Operation::Digest { hash, data }.run_blocking(&env.data().pool)
+    })();
+
+    write_output(&env, result, out, out_len, written_out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn crypto_hmac(
+    env: FunctionEnvMut<'_, CryptoEnv>,
+    hash: u32,
+    key: WasmPtr<u8>,
+    key_len: u32,
+    data: WasmPtr<u8>,
+    data_len: u32,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    let result = (|| {
+        let hash = Hash::from_raw(hash)?;
+        let key = read_bytes(&env, key, key_len)?;
+        let data = read_bytes(&env, data, data_len)?;
+        Operation::Hmac { hash, key, data }.run_blocking(&env.data().pool)
+    })();
+
+    write_output(&env, result, out, out_len, written_out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn aes_gcm(
+    env: FunctionEnvMut<'_, CryptoEnv>,
+    encrypt: bool,
+    key: WasmPtr<u8>,
+    key_len: u32,
+    iv: WasmPtr<u8>,
+    iv_len: u32,
+    aad: WasmPtr<u8>,
+    aad_len: u32,
+    data: WasmPtr<u8>,
+    data_len: u32,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    let result = (|| {
+        let op = Operation::AesGcm {
+            encrypt,
+            key: read_bytes(&env, key, key_len)?,
+            iv: read_bytes(&env, iv, iv_len)?,
+            aad: read_bytes(&env, aad, aad_len)?,
+            data: read_bytes(&env, data, data_len)?,
+        };
+        op.run_blocking(&env.data().pool)
+    })();
+
+    write_output(&env, result, out, out_len, written_out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn crypto_aes_gcm_encrypt(
+    env: FunctionEnvMut<'_, CryptoEnv>,
+    key: WasmPtr<u8>,
+    key_len: u32,
+    iv: WasmPtr<u8>,
+    iv_len: u32,
+    aad: WasmPtr<u8>,
+    aad_len: u32,
+    data: WasmPtr<u8>,
+    data_len: u32,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    aes_gcm(
+        env,
+        true,
+        key,
+        key_len,
+        iv,
+        iv_len,
+        aad,
+        aad_len,
+        data,
+        data_len,
+        out,
+        out_len,
+        written_out,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn crypto_aes_gcm_decrypt(
+    env: FunctionEnvMut<'_, CryptoEnv>,
+    key: WasmPtr<u8>,
+    key_len: u32,
+    iv: WasmPtr<u8>,
+    iv_len: u32,
+    aad: WasmPtr<u8>,
+    aad_len: u32,
+    data: WasmPtr<u8>,
+    data_len: u32,
+    out: WasmPtr<u8>,
+    out_len: u32,
+    written_out: WasmPtr<u32>,
+) -> i32 {
+    aes_gcm(
+        env,
+        false,
+        key,
+        key_len,
+        iv,
+        iv_len,
+        aad,
+        aad_len,
+        data,
+        data_len,
+        out,
+        out_len,
+        written_out,
+    )
+}
+
+fn crypto_random(env: FunctionEnvMut<'_, CryptoEnv>, buf: WasmPtr<u8>, buf_len: u32) -> i32 {
+    let Some(crypto) = GlobalScope::current().crypto() else {
+        return errno(Errno::Notsup);
+    };
+    let Some(memory) = env.data().memory.as_ref() else {
+        return errno(Errno::Fault);
+    };
+    let view = memory.view(&env);
+
+    // Note: check the whole range up front, so a bogus length can't make us
+    // allocate (or fill) gigabytes before failing
+    let Ok(slice) = buf.slice(&view, buf_len) else {
+        return errno(Errno::Fault);
+    };
+
+    // Note: getRandomValues() won't write to a view of shared memory, so we
+    // fill a temporary buffer and copy it across one chunk at a time.
+    let len = u64::from(buf_len);
+    let mut chunk = vec![0; (buf_len as usize).min(MAX_RANDOM_BYTES)];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(MAX_RANDOM_BYTES as u64);
+        let chunk = &mut chunk[..n as usize];
+        if crypto.get_random_values_with_u8_array(chunk).is_err() {
+            return errno(Errno::Io);
+        }
+        if slice
+            .subslice(offset..offset + n)
+            .write_slice(chunk)
+            .is_err()
+        {
+            return errno(Errno::Fault);
+        }
+        offset += n;
+    }
+
+    errno(Errno::Success)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn hash_and_round_trip_with_subtle_crypto() {
+        let digest = Operation::Digest {
+            hash: Hash::Sha256,
+            data: b"abc".to_vec(),
+        }
+        .perform()
+        .await
+        .unwrap();
+        assert_eq!(
+            digest[..4],
+            [0xba, 0x78, 0x16, 0xbf],
+            "SHA-256(\"abc\") starts with ba7816bf"
+        );
+
+        let key = vec![7; 16];
+        let iv = vec![1; 12];
+        let encrypted = Operation::AesGcm {
+            encrypt: true,
+            key: key.clone(),
+            iv: iv.clone(),
+            aad: b"header".to_vec(),
+            data: b"secret".to_vec(),
+        }
+        .perform()
+        .await
+        .unwrap();
+        assert_eq!(encrypted.len(), b"secret".len() + 16);
+
+        let decrypt = |aad: &[u8]| Operation::AesGcm {
+            encrypt: false,
+            key: key.clone(),
+            iv: iv.clone(),
+            aad: aad.to_vec(),
+            data: encrypted.clone(),
+        };
+        assert_eq!(decrypt(b"header").perform().await.unwrap(), b"secret");
+        assert_eq!(
+            decrypt(b"tampered").perform().await.unwrap_err(),
+            Errno::Badmsg
+        );
+    }
+}
@@ -0,0 +1,605 @@
+//! An experimental `wasmer_gpu` extension which lets guests run WebGPU
+//! compute shaders and draw frames onto a canvas provided by the host.
+//!
+//! Programs started with `runWasix()` can import these functions from the
+//! `wasmer_gpu` module. Each returns a WASI errno.
+//!
+//! ```text
+//! gpu_compute(shader_ptr, shader_len, entry_ptr, entry_len, buf_ptr, buf_len,
+//!             groups_x, groups_y, groups_z) -> errno
+//! gpu_present(pixels_ptr, width, height) -> errno
+//! ```
+//!
+//! `gpu_compute()` compiles a WGSL shader and dispatches its `entry` point
+//! over `groups_x * groups_y * groups_z` workgroups. The guest's buffer is
+//! bound as `@group(0) @binding(0) var<storage, read_write>`: its contents
+//! are uploaded before the dispatch, and whatever the shader left in the
+//! storage buffer is mapped and copied back into guest memory afterwards.
+//! `buf_len` must be a non-zero multiple of 4. Shaders which fail to compile
+//! or validate return `EINVAL`.
+//!
+//! `gpu_present()` draws `width * height` RGBA pixels (4 bytes each) onto
+//! the runtime's `gpuCanvas`, resizing it to match. Without a canvas it
+//! returns `ENODEV`.
+//!
+//! Everything requires the `gpu` capability, failing with `EACCES`
+//! otherwise, and `ENOTSUP` is returned where WebGPU isn't available.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use futures::channel::oneshot;
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmer::{
+    imports, AsStoreMut, Function as WasmFunction, FunctionEnv, FunctionEnvMut, Imports, Memory,
+    WasmPtr,
+};
+use wasmer_wasix::types::wasi::Errno;
+
+use crate::{
+    capabilities::{Capabilities, Capability},
+    tasks::ThreadPool,
+    utils::GlobalScope,
+};
+
+/// The import module the functions are exposed under.
+pub(crate) const NAMESPACE: &str = "wasmer_gpu";
+
+// Note: these are the values of the GPUBufferUsage, GPUTextureUsage and
+// GPUMapMode flags from the WebGPU spec.
+const BUFFER_MAP_READ: u32 = 0x0001;
+const BUFFER_COPY_SRC: u32 = 0x0004;
+const BUFFER_COPY_DST: u32 = 0x0008;
+const BUFFER_STORAGE: u32 = 0x0080;
+const TEXTURE_COPY_DST: u32 = 0x02;
+const TEXTURE_RENDER_ATTACHMENT: u32 = 0x10;
+const MAP_MODE_READ: u32 = 0x0001;
+
+thread_local! {
+    /// The device every program shares, which lives on the scheduler's
+    /// thread.
+    static DEVICE: RefCell<Option<JsValue>> = const { RefCell::new(None) };
+    /// `gpuCanvas`es, which live on the scheduler's thread.
+    static CANVASES: RefCell<BTreeMap<u32, Canvas>> = RefCell::default();
+}
+
+#[derive(Debug)]
+struct Canvas {
+    element: JsValue,
+    /// The size the canvas' context was last configured for.
+    configured: Option<(u32, u32)>,
+}
+
+/// Make `canvas` available to `gpu_present()`, returning an ID that can be
+/// passed to [`WebGpu`].
+///
+/// This must be called on the scheduler's thread.
+pub(crate) fn register_canvas(canvas: JsValue) -> u32 {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let canvas = Canvas {
+        element: canvas,
+        configured: None,
+    };
+    CANVASES.with(|canvases| canvases.borrow_mut().insert(id, canvas));
+    id
+}
+
+/// Forget a canvas registered with [`register_canvas()`].
+///
+/// This must be called on the scheduler's thread.
+pub(crate) fn unregister_canvas(id: u32) {
+    CANVASES.with(|canvases| canvases.borrow_mut().remove(&id));
+}
+
+/// Something to ask the GPU to do.
+#[derive(Debug)]
+enum Operation {
+    Compute {
+        shader: String,
+        entry_point: String,
+        data: Vec<u8>,
+        workgroups: [u32; 3],
+    },
+    Present {
+        canvas: u32,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Operation {
+    /// Run the operation on the scheduler's thread, blocking until it is
+    /// done.
+    ///
+    /// Note: WebGPU objects can't be shared between threads and most of its
+    /// methods are async, so like `wasmer_crypto`, the work happens on the
+    /// scheduler's thread while the guest waits.
+    fn run_blocking(self, pool: &ThreadPool) -> Result<Vec<u8>, Errno> {
+        let (sender, receiver) = oneshot::channel();
+        pool.run_on_scheduler(Box::new(move || {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = sender.send(self.perform().await);
+            });
+        }));
+
+        crate::blocking::block_on("waiting for WebGPU", receiver).unwrap_or(Err(Errno::Canceled))
+    }
+
+    async fn perform(self) -> Result<Vec<u8>, Errno> {
+        let device = device().await?;
+
+        match self {
+            Operation::Compute {
+                shader,
+                entry_point,
+                data,
+                workgroups,
+            } => compute(&device, &shader, &entry_point, &data, workgroups).await,
+            Operation::Present {
+                canvas,
+                pixels,
+                width,
+                height,
+            } => {
+                present(&device, canvas, &pixels, width, height)?;
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// Get the shared device, requesting one the first time it is needed.
+async fn device() -> Result<JsValue, Errno> {
+    if let Some(device) = DEVICE.with(|d| d.borrow().clone()) {
+        return Ok(device);
+    }
+
+    let gpu = GlobalScope::current()
+        .lookup(&["navigator", "gpu"])
+        .ok_or(Errno::Notsup)?;
+    let adapter = call_async(&gpu, "requestAdapter", &[]).await?;
+    if adapter.is_null() {
+        tracing::debug!("The browser doesn't have a WebGPU adapter");
+        return Err(Errno::Notsup);
+    }
+    let device = call_async(&adapter, "requestDevice", &[]).await?;
+
+    // Note: a lost device (e.g. after a driver reset) is useless, so we
+    // forget it and request a new one next time.
+    if let Ok(lost) = get(&device, "lost") {
+        let forget = Closure::once_into_js(|_: JsValue| {
+            tracing::warn!("The WebGPU device was lost");
+            DEVICE.with(|d| d.borrow_mut().take());
+        });
+        let _ = call(&lost, "then", &[forget]);
+    }
+
+    DEVICE.with(|d| *d.borrow_mut() = Some(device.clone()));
+    Ok(device)
+}
+
+async fn compute(
+    device: &JsValue,
+    shader: &str,
+    entry_point: &str,
+    data: &[u8],
+    [x, y, z]: [u32; 3],
+) -> Result<Vec<u8>, Errno> {
+    let size = JsValue::from(data.len() as f64);
+    let buffer = |usage: u32| {
+        let descriptor = object(&[("size", size.clone()), ("usage", usage.into())]);
+        call(device, "createBuffer", &[descriptor.into()])
+    };
+
+    call(device, "pushErrorScope", &["validation".into()])?;
+    let created = (|| {
+        let module = call(
+            device,
+            "createShaderModule",
+            &[object(&[("code", shader.into())]).into()],
+        )?;
+        let stage = object(&[("module", module), ("entryPoint", entry_point.into())]);
+        let pipeline = call(
+            device,
+            "createComputePipeline",
+            &[object(&[("layout", "auto".into()), ("compute", stage.into())]).into()],
+        )?;
+        let storage = buffer(BUFFER_STORAGE | BUFFER_COPY_SRC | BUFFER_COPY_DST)?;
+        let readback = buffer(BUFFER_MAP_READ | BUFFER_COPY_DST)?;
+        Ok::<_, Errno>((pipeline, storage, readback))
+    })();
+    let (pipeline, storage, readback) = match created {
+        Ok(created) => created,
+        Err(e) => {
+            let _ = call_async(device, "popErrorScope", &[]).await;
+            return Err(e);
+        }
+    };
+
+    let submitted = (|| {
+        let queue = get(device, "queue")?;
+        call(
+            &queue,
+            "writeBuffer",
+            &[storage.clone(), 0.into(), Uint8Array::from(data).into()],
+        )?;
+
+        let layout = call(&pipeline, "getBindGroupLayout", &[0.into()])?;
+        let resource = object(&[("buffer", storage.clone())]);
+        let entry = object(&[("binding", 0.into()), ("resource", resource.into())]);
+        let bind_group = call(
+            device,
+            "createBindGroup",
+            &[object(&[("layout", layout), ("entries", Array::of1(&entry).into())]).into()],
+        )?;
+
+        let encoder = call(device, "createCommandEncoder", &[])?;
+        let pass = call(&encoder, "beginComputePass", &[])?;
+        call(&pass, "setPipeline", &[pipeline.clone()])?;
+        call(&pass, "setBindGroup", &[0.into(), bind_group])?;
+        call(&pass, "dispatchWorkgroups", &[x.into(), y.into(), z.into()])?;
+        call(&pass, "end", &[])?;
+        call(
+            &encoder,
+            "copyBufferToBuffer",
+            &[
+                storage.clone(),
+                0.into(),
+                readback.clone(),
+                0.into(),
+                size.clone(),
+            ],
+        )?;
+        let commands = call(&encoder, "finish", &[])?;
+        call(&queue, "submit", &[Array::of1(&commands).into()])?;
+        Ok::<_, Errno>(())
+    })();
+
+    let validation = call_async(device, "popErrorScope", &[]).await;
+    let result = match (submitted, validation) {
+        (Err(e), _) | (_, Err(e)) => Err(e),
+        (Ok(()), Ok(error)) if !error.is_null() && !error.is_undefined() => {
+            let message = get(&error, "message").ok().and_then(|m| m.as_string());
+            tracing::debug!(?message, "A compute shader failed validation");
+            Err(Errno::Inval)
+        }
+        (Ok(()), Ok(_)) => read_back(&readback).await,
+    };
+
+    let _ = call(&storage, "destroy", &[]);
+    let _ = call(&readback, "destroy", &[]);
+    result
+}
+
+/// Map a buffer and copy its contents out.
+async fn read_back(buffer: &JsValue) -> Result<Vec<u8>, Errno> {
+    call_async(buffer, "mapAsync", &[MAP_MODE_READ.into()]).await?;
+    let range = call(buffer, "getMappedRange", &[])?;
+    let bytes = Uint8Array::new(&range).to_vec();
+    call(buffer, "unmap", &[])?;
+    Ok(bytes)
+}
+
+fn present(
+    device: &JsValue,
+    canvas: u32,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), Errno> {
+    let context = CANVASES.with(|canvases| {
+        let mut canvases = canvases.borrow_mut();
+        let canvas = canvases.get_mut(&canvas).ok_or(Errno::Nodev)?;
+        let context = call(&canvas.element, "getContext", &["webgpu".into()])?;
+        if context.is_null() {
+            tracing::debug!("The canvas already has a non-WebGPU context");
+            return Err(Errno::Nodev);
+        }
+
+        // Note: resizing and configuring a canvas throws away its current
+        // frame, so we only do it when the frame size changes
+        if canvas.configured != Some((width, height)) {
+            set(&canvas.element, "width", width.into())?;
+            set(&canvas.element, "height", height.into())?;
+            let usage = TEXTURE_COPY_DST | TEXTURE_RENDER_ATTACHMENT;
+            let configuration = object(&[
+                ("device", device.clone()),
+                ("format", "rgba8unorm".into()),
+                ("usage", usage.into()),
+            ]);
+            call(&context, "configure", &[configuration.into()])?;
+            canvas.configured = Some((width, height));
+        }
+
+        Ok(context)
+    })?;
+
+    let texture = call(&context, "getCurrentTexture", &[])?;
+    let queue = get(device, "queue")?;
+    let layout = object(&[
+        ("bytesPerRow", (width * 4).into()),
+        ("rowsPerImage", height.into()),
+    ]);
+    let extent = object(&[("width", width.into()), ("height", height.into())]);
+    call(
+        &queue,
+        "writeTexture",
+        &[
+            object(&[("texture", texture)]).into(),
+            Uint8Array::from(pixels).into(),
+            layout.into(),
+            extent.into(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Call a method on a WebGPU object, treating exceptions as invalid
+/// arguments.
+///
+/// Note: the WebGPU bindings in `web-sys` are still unstable and change
+/// between releases, so we go through `Reflect` instead.
+fn call(target: &JsValue, method: &str, args: &[JsValue]) -> Result<JsValue, Errno> {
+    let function: Function = get(target, method)?.dyn_into().map_err(|_| Errno::Notsup)?;
+    let args: Array = args.iter().collect();
+    function.apply(target, &args).map_err(|e| {
+        tracing::debug!(method, error = ?e, "A WebGPU call failed");
+        Errno::Inval
+    })
+}
+
+/// Call an async method on a WebGPU object and wait for the result.
+async fn call_async(target: &JsValue, method: &str, args: &[JsValue]) -> Result<JsValue, Errno> {
+    let promise: Promise = call(target, method, args)?
+        .dyn_into()
+        .map_err(|_| Errno::Inval)?;
+    JsFuture::from(promise).await.map_err(|e| {
+        tracing::debug!(method, error = ?e, "A WebGPU call failed");
+        Errno::Inval
+    })
+}
+
+fn get(target: &JsValue, key: &str) -> Result<JsValue, Errno> {
+    Reflect::get(target, &JsValue::from_str(key)).map_err(|_| Errno::Notsup)
+}
+
+fn set(target: &JsValue, key: &str, value: JsValue) -> Result<(), Errno> {
+    Reflect::set(target, &JsValue::from_str(key), &value)
+        .map(|_| ())
+        .map_err(|_| Errno::Inval)
+}
+
+fn object(fields: &[(&str, JsValue)]) -> Object {
+    let obj = Object::new();
+    for (key, value) in fields {
+        let _ = Reflect::set(&obj, &JsValue::from_str(key), value);
+    }
+    obj
+}
+
+/// State shared by the `wasmer_gpu` functions of a single instance.
+#[derive(Debug)]
+pub(crate) struct WebGpu {
+    pub(crate) pool: ThreadPool,
+    pub(crate) capabilities: Capabilities,
+    /// The runtime's `gpuCanvas`, as returned by [`register_canvas()`].
+    pub(crate) canvas: Option<u32>,
+    /// The program using the GPU.
+    pub(crate) pid: u32,
+}
+
+#[derive(Debug)]
+struct GpuEnv {
+    config: WebGpu,
+    memory: Option<Memory>,
+}
+
+impl WebGpu {
+    /// Create the imports, returning a handle which needs to be given the
+    /// instance's memory once it has been instantiated.
+    pub(crate) fn imports(self, store: &mut impl AsStoreMut) -> (Imports, WebGpuMemory) {
+        let env = FunctionEnv::new(
+            store,
+            GpuEnv {
+                config: self,
+                memory: None,
+            },
+        );
+
+        let imports = imports! {
+            NAMESPACE => {
+                "gpu_compute" => WasmFunction::new_typed_with_env(store, &env, gpu_compute),
+                "gpu_present" => WasmFunction::new_typed_with_env(store, &env, gpu_present),
+            }
+        };
+
+        (imports, WebGpuMemory(env))
+    }
+
+    fn check(&self, detail: &str) -> Result<(), Errno> {
+        if self.capabilities.check(
+            Capability::Gpu,
+            &self.pool,
+            Some(self.pid),
+            Some(detail.to_string()),
+        ) {
+            Ok(())
+        } else {
+            Err(Errno::Acces)
+        }
+    }
+}
+
+/// Gives the `wasmer_gpu` functions access to the guest's memory.
+pub(crate) struct WebGpuMemory(FunctionEnv<GpuEnv>);
+
+impl WebGpuMemory {
+    pub(crate) fn attach(&self, store: &mut impl AsStoreMut, memory: Memory) {
+        self.0.as_mut(store).memory = Some(memory);
+    }
+}
+
+fn errno(e: Errno) -> i32 {
+    e as i32
+}
+
+fn read_bytes(
+    env: &FunctionEnvMut<'_, GpuEnv>,
+    ptr: WasmPtr<u8>,
+    len: u32,
+) -> Result<Vec<u8>, Errno> {
+    let memory = env.data().memory.as_ref().ok_or(Errno::Fault)?;
+    let view = memory.view(env);
+    ptr.slice(&view, len)
+        .and_then(|slice| slice.read_to_vec())
+        .map_err(|_| Errno::Fault)
+}
+
+fn read_string(
+    env: &FunctionEnvMut<'_, GpuEnv>,
+    ptr: WasmPtr<u8>,
+    len: u32,
+) -> Result<String, Errno> {
+    String::from_utf8(read_bytes(env, ptr, len)?).map_err(|_| Errno::Ilseq)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn gpu_compute(
+    env: FunctionEnvMut<'_, GpuEnv>,
+    shader: WasmPtr<u8>,
+    shader_len: u32,
+    entry: WasmPtr<u8>,
+    entry_len: u32,
+    buf: WasmPtr<u8>,
+    buf_len: u32,
+    groups_x: u32,
+    groups_y: u32,
+    groups_z: u32,
+) -> i32 {
+    let result = (|| {
+        env.data().config.check("gpu_compute")?;
+        if buf_len == 0 || buf_len % 4 != 0 {
+            return Err(Errno::Inval);
+        }
+
+        let op = Operation::Compute {
+            shader: read_string(&env, shader, shader_len)?,
+            entry_point: read_string(&env, entry, entry_len)?,
+            data: read_bytes(&env, buf, buf_len)?,
+            workgroups: [groups_x, groups_y, groups_z],
+        };
+        let output = op.run_blocking(&env.data().config.pool)?;
+
+        let memory = env.data().memory.as_ref().ok_or(Errno::Fault)?;
+        let view = memory.view(&env);
+        buf.slice(&view, buf_len)
+            .and_then(|slice| slice.write_slice(&output))
+            .map_err(|_| Errno::Fault)
+    })();
+
+    match result {
+        Ok(()) => errno(Errno::Success),
+        Err(e) => errno(e),
+    }
+}
+
+fn gpu_present(
+    env: FunctionEnvMut<'_, GpuEnv>,
+    pixels: WasmPtr<u8>,
+    width: u32,
+    height: u32,
+) -> i32 {
+    let result = (|| {
+        let config = &env.data().config;
+        config.check("gpu_present")?;
+        let canvas = config.canvas.ok_or(Errno::Nodev)?;
+        let len = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(4))
+            .filter(|&len| len > 0)
+            .ok_or(Errno::Inval)?;
+
+        let op = Operation::Present {
+            canvas,
+            pixels: read_bytes(&env, pixels, len)?,
+            width,
+            height,
+        };
+        op.run_blocking(&config.pool).map(|_| ())
+    })();
+
+    match result {
+        Ok(()) => errno(Errno::Success),
+        Err(e) => errno(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn run_a_compute_shader() {
+        if GlobalScope::current()
+            .lookup(&["navigator", "gpu"])
+            .is_none()
+        {
+            return;
+        }
+
+        let shader = r#"
+            @group(0) @binding(0) var<storage, read_write> data: array<u32>;
+
+            @compute @workgroup_size(1)
+            fn double(@builtin(global_invocation_id) id: vec3<u32>) {
+                data[id.x] = data[id.x] * 2u;
+            }
+        "#;
+        let data: Vec<u8> = [1_u32, 2, 3, 4]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+
+        let output = Operation::Compute {
+            shader: shader.to_string(),
+            entry_point: "double".to_string(),
+            data,
+            workgroups: [4, 1, 1],
+        }
+        .perform()
+        .await;
+
+        // Note: headless browsers often expose navigator.gpu without an
+        // adapter
+        if output == Err(Errno::Notsup) {
+            return;
+        }
+        let doubled: Vec<u32> = output
+            .unwrap()
+            .chunks(4)
+            .map(|n| u32::from_le_bytes(n.try_into().unwrap()))
+            .collect();
+        assert_eq!(doubled, [2, 4, 6, 8]);
+
+        let invalid = Operation::Compute {
+            shader: "this isn't WGSL".to_string(),
+            entry_point: "main".to_string(),
+            data: vec![0; 4],
+            workgroups: [1, 1, 1],
+        }
+        .perform()
+        .await;
+        assert_eq!(invalid, Err(Errno::Inval));
+    }
+}
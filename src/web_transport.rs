@@ -0,0 +1,201 @@
+//! Tunnelling the networking protocol over WebTransport (HTTP/3).
+//!
+//! This is only used with `networkTransport: "webtransport"`, whose docs
+//! describe the wire format for gateway authors.
+//!
+//! The gateway speaks the same protocol as over a WebSocket, but a WebSocket
+//! gives us message boundaries for free and a WebTransport stream doesn't, so
+//! each bincode-encoded message is length-prefixed.
+//!
+//! Messages about a particular guest socket go on a bidirectional stream of
+//! their own, opened the first time something is sent on that socket, so a
+//! lost packet only holds up the socket it belongs to. Everything else (e.g.
+//! opening sockets or DNS lookups) goes on the first stream of the session.
+//! The gateway should reply on the stream a socket's messages arrived on,
+//! and finish that stream once the socket is closed. Responses are read
+//! from every stream, since they carry their own socket and request IDs.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use virtual_net::meta::{MessageRequest, SocketId};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{WebTransport, WebTransportBidirectionalStream};
+
+use crate::{
+    framing::{FrameWriter, Framing},
+    utils::Error,
+};
+
+/// An open WebTransport session to a networking gateway.
+#[derive(Debug)]
+pub(crate) struct WebTransportTunnel {
+    transport: WebTransport,
+    /// The session's first stream, for messages that aren't about a socket.
+    control: FrameWriter,
+    /// The streams for each socket, which are removed once the gateway
+    /// finishes them.
+    sockets: Rc<RefCell<HashMap<SocketId, FrameWriter>>>,
+    relay_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl WebTransportTunnel {
+    /// Does this browser implement WebTransport?
+    pub(crate) fn is_supported() -> bool {
+        js_sys::Reflect::has(&js_sys::global(), &"WebTransport".into()).unwrap_or(false)
+    }
+
+    /// Connect to `url`, forwarding every message the gateway sends to
+    /// `relay_tx`.
+    ///
+    /// Like a WebSocket, an empty message is sent once the session closes.
+    pub(crate) async fn open(
+        url: &str,
+        relay_tx: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Result<Self, Error> {
+        let transport = WebTransport::new(url).map_err(Error::js)?;
+        JsFuture::from(transport.ready()).await.map_err(Error::js)?;
+
+        let control = open_stream(&transport, relay_tx.clone(), None).await?;
+
+        Ok(WebTransportTunnel {
+            transport,
+            control,
+            sockets: Rc::default(),
+            relay_tx,
+        })
+    }
+
+    /// Send a message on the stream it belongs to.
+    pub(crate) async fn send(
+        &self,
+        request: &MessageRequest,
+        message: Vec<u8>,
+    ) -> Result<(), Error> {
+        let writer = match socket_of(request) {
+            Some(socket) => self.socket_stream(socket).await?,
+            None => self.control.clone(),
+        };
+
+        // Note: wait until the stream has room, so a gateway that isn't
+        // keeping up pushes back on the guest instead of having writes pile
+        // up in memory
+        writer.ready().await?;
+        writer.send(&message).await
+    }
+
+    async fn socket_stream(&self, socket: SocketId) -> Result<FrameWriter, Error> {
+        if let Some(writer) = self.sockets.borrow().get(&socket) {
+            return Ok(writer.clone());
+        }
+
+        let closed = Rc::downgrade(&self.sockets);
+        let on_finished = Box::new(move || {
+            if let Some(sockets) = closed.upgrade() {
+                sockets.borrow_mut().remove(&socket);
+            }
+        });
+        let writer = open_stream(&self.transport, self.relay_tx.clone(), Some(on_finished)).await?;
+        self.sockets.borrow_mut().insert(socket, writer.clone());
+
+        Ok(writer)
+    }
+}
+
+/// The socket a message is about, if any.
+fn socket_of(request: &MessageRequest) -> Option<SocketId> {
+    match request {
+        MessageRequest::Send { socket, .. }
+        | MessageRequest::SendTo { socket, .. }
+        | MessageRequest::Socket { socket, .. } => Some(*socket),
+        _ => None,
+    }
+}
+
+/// Open a bidirectional stream, forwarding whatever the gateway sends on it
+/// to `relay_tx`.
+///
+/// When the gateway finishes the stream, `on_finished` is called. Without
+/// one, an empty message is sent to `relay_tx` to say the session is over.
+async fn open_stream(
+    transport: &WebTransport,
+    relay_tx: mpsc::UnboundedSender<Vec<u8>>,
+    on_finished: Option<Box<dyn FnOnce()>>,
+) -> Result<FrameWriter, Error> {
+    let stream: WebTransportBidirectionalStream =
+        JsFuture::from(transport.create_bidirectional_stream())
+            .await
+            .map_err(Error::js)?
+            .unchecked_into();
+    let writer = FrameWriter::new(Framing::LengthPrefixed, stream.writable().unchecked_ref())?;
+    let mut messages =
+        crate::framing::decode(Framing::LengthPrefixed, stream.readable().unchecked_into());
+
+    wasm_bindgen_futures::spawn_local(async move {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(message) if !message.is_empty() => {
+                    if relay_tx.send(message).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::debug!(
+                        error = &*e.into_anyhow(),
+                        "Unable to read from the WebTransport stream"
+                    );
+                    break;
+                }
+            }
+        }
+
+        match on_finished {
+            Some(on_finished) => on_finished(),
+            None => {
+                relay_tx.send(Vec::new()).ok();
+            }
+        }
+    });
+
+    Ok(writer)
+}
+
+impl Drop for WebTransportTunnel {
+    fn drop(&mut self) {
+        self.transport.close();
+    }
+}
+
+/// The WebTransport equivalent of a gateway's WebSocket URL, if it has one.
+///
+/// WebTransport only works over HTTPS, so there is nothing to try for a
+/// plain `ws://` gateway.
+pub(crate) fn session_url(gateway: &str) -> Option<String> {
+    let rest = gateway
+        .strip_prefix("wss://")
+        .or_else(|| gateway.strip_prefix("https://"))?;
+    Some(format!("https://{rest}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn only_secure_gateways_have_a_session_url() {
+        assert_eq!(
+            session_url("wss://gateway.example/net").as_deref(),
+            Some("https://gateway.example/net")
+        );
+        assert_eq!(
+            session_url("https://gateway.example:4433").as_deref(),
+            Some("https://gateway.example:4433")
+        );
+        assert_eq!(session_url("ws://localhost:8080"), None);
+    }
+}
@@ -59,4 +59,27 @@ describe("In-Memory Directory", function () {
             "another",
         );
     });
+
+    it("can copy a directory tree", async () => {
+        const dir = new Directory({
+            "/src/file.txt": "file",
+            "/src/nested/file.txt": "nested",
+        });
+
+        await dir.copy("/src", "/dst");
+
+        expect(await dir.readTextFile("/dst/file.txt")).to.equal("file");
+        expect(await dir.readTextFile("/dst/nested/file.txt")).to.equal(
+            "nested",
+        );
+        expect(await dir.readTextFile("/src/file.txt")).to.equal("file");
+    });
+
+    it("leaves a file alone when it is copied onto itself", async () => {
+        const dir = new Directory({ "/file.txt": "file" });
+
+        await dir.copy("/file.txt", "/./nested/../file.txt");
+
+        expect(await dir.readTextFile("/file.txt")).to.equal("file");
+    });
 });
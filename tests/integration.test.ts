@@ -1,5 +1,5 @@
 import { expect } from "@esm-bundle/chai";
-import { Wasmer, init, initializeLogger, Directory } from "..";
+import { Wasmer, init, initializeLogger, Directory, runWasix } from "..";
 
 const encoder = new TextEncoder();
 const decoder = new TextDecoder("utf-8");
@@ -42,6 +42,32 @@ describe("Wasmer.spawn", function () {
         expect(output.stderr.length).to.equal(0);
     });
 
+    it("Can run a package's entrypoint with runWasix()", async () => {
+        const pkg = await Wasmer.fromRegistry("saghul/quickjs@0.0.3");
+        const manifest = pkg.manifest;
+
+        expect(manifest.name).to.equal("saghul/quickjs");
+        expect(manifest.entrypoint).to.equal("quickjs");
+        expect(manifest.commands["quickjs"].runner).to.contain("wasi");
+
+        const instance = await runWasix(pkg, {
+            args: ["--eval", "console.log('Hello, World!')"],
+        });
+        const output = await instance.wait();
+
+        expect(output.stdout).to.equal("Hello, World!\n");
+    });
+
+    it("Can inspect a package's atoms", async () => {
+        const pkg = await Wasmer.fromRegistry("saghul/quickjs@0.0.3");
+
+        const atoms = pkg.atoms();
+
+        expect(atoms.length).to.be.greaterThan(0);
+        expect(atoms[0].size).to.be.greaterThan(0);
+        expect(atoms[0].features).to.include("wasi");
+    });
+
     it("Can pass stdin to a dumb echo program", async () => {
         const pkg = await Wasmer.fromRegistry(
             "christoph/wasix-test-stdinout@0.1.1",
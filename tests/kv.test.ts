@@ -0,0 +1,55 @@
+import { expect } from "@esm-bundle/chai";
+import { init, initializeLogger, KeyValueStore } from "..";
+
+const initialized = (async () => {
+    await init(new URL("../dist/wasmer_js_bg.wasm", import.meta.url));
+    initializeLogger("warn");
+})();
+
+describe("KeyValueStore", function () {
+    this.timeout("60s").beforeAll(async () => await initialized);
+
+    it("can round-trip an entry", async () => {
+        const store = await KeyValueStore.open("round-trip");
+
+        await store.set("greeting", "Hello, World!");
+
+        expect(await store.getText("greeting")).to.equal("Hello, World!");
+        expect(await store.keys()).to.contain("greeting");
+    });
+
+    it("persists entries between opens", async () => {
+        const first = await KeyValueStore.open("persistence");
+        await first.set("count", "42");
+
+        const second = await KeyValueStore.open("persistence");
+
+        expect(await second.getText("count")).to.equal("42");
+    });
+
+    it("exposes entries as files", async () => {
+        const store = await KeyValueStore.open("as-files");
+
+        await store.set("file.txt", "contents");
+
+        expect(await store.directory.readTextFile("/file.txt")).to.equal(
+            "contents",
+        );
+    });
+
+    it("syncs changes made through the directory", async () => {
+        const store = await KeyValueStore.open("sync");
+        await store.directory.writeFile("/written-by-guest", "guest");
+
+        await store.sync();
+
+        const reopened = await KeyValueStore.open("sync");
+        expect(await reopened.getText("written-by-guest")).to.equal("guest");
+    });
+
+    it("returns undefined for missing entries", async () => {
+        const store = await KeyValueStore.open("missing");
+
+        expect(await store.get("does-not-exist")).to.be.undefined;
+    });
+});
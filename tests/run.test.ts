@@ -6,6 +6,7 @@ import {
     init,
     initializeLogger,
     Directory,
+    Runtime,
 } from "../dist/WasmerSDKBundled";
 
 const initialized = (async () => {
@@ -13,6 +14,50 @@ const initialized = (async () => {
     initializeLogger("warn");
 })();
 
+/**
+ * A program which fetches `url` through the host, writes the body to stdout,
+ * and exits with the status code minus 200 (or 100 plus the errno of
+ * whichever call failed).
+ */
+function hostFetchProgram(url: string): string {
+    return `(
+        module
+            (import "wasmer_host" "host_fetch" (func $fetch (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+            (import "wasmer_host" "host_fetch_read" (func $read (param i32 i32 i32 i32) (result i32)))
+            (import "wasmer_host" "host_fetch_close" (func $close (param i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory $memory 1)
+            (export "memory" (memory $memory))
+            (data (i32.const 0) "GET")
+            (data (i32.const 16) "${url}")
+            (func $check (param $errno i32)
+                (if (local.get $errno)
+                    (then (call $exit (i32.add (i32.const 100) (local.get $errno))))))
+            (func (export "_start")
+                ;; 1024: status, 1028: handle, 1032: nread, 1040: iovec, 4096: buffer
+                (call $check (call $fetch
+                    (i32.const 16) (i32.const ${url.length})
+                    (i32.const 0) (i32.const 3)
+                    (i32.const 0) (i32.const 0)
+                    (i32.const 1024) (i32.const 1028)))
+                (block $done
+                    (loop $next
+                        (call $check (call $read
+                            (i32.load (i32.const 1028))
+                            (i32.const 4096) (i32.const 4096)
+                            (i32.const 1032)))
+                        (br_if $done (i32.eqz (i32.load (i32.const 1032))))
+                        (i32.store (i32.const 1040) (i32.const 4096))
+                        (i32.store (i32.const 1044) (i32.load (i32.const 1032)))
+                        (call $check (call $fd_write
+                            (i32.const 1) (i32.const 1040) (i32.const 1) (i32.const 1048)))
+                        (br $next)))
+                (call $check (call $close (i32.load (i32.const 1028))))
+                (call $exit (i32.sub (i32.load (i32.const 1024)) (i32.const 200))))
+        )`;
+}
+
 describe("run", function () {
     this.timeout("60s").beforeAll(async () => await initialized);
 
@@ -162,4 +207,33 @@ describe("run", function () {
         expect(output.stdout).to.contain("Hello, World!\n");
         expect(output.stderr).to.be.empty;
     });
+
+    it("can stream a response through host_fetch", async () => {
+        const url = URL.createObjectURL(new Blob(["Hello, World!"]));
+        const module = await WebAssembly.compile(wat2wasm(hostFetchProgram(url)));
+        const runtime = new Runtime({
+            hostFetch: { allowedOrigins: [location.origin] },
+        });
+
+        const instance = await runWasix(module, { program: "fetch", runtime });
+        const output = await instance.wait();
+
+        expect(output.code).to.equal(0);
+        expect(output.stdout).to.equal("Hello, World!");
+    });
+
+    it("stops reading responses which are too large", async () => {
+        const url = URL.createObjectURL(new Blob(["Hello, World!"]));
+        const module = await WebAssembly.compile(wat2wasm(hostFetchProgram(url)));
+        const runtime = new Runtime({
+            hostFetch: { allowedOrigins: [location.origin], maxResponseBytes: 4 },
+        });
+
+        const instance = await runWasix(module, { program: "fetch", runtime });
+        const output = await instance.wait();
+
+        // 100 + EFBIG
+        expect(output.code).to.equal(122);
+        expect(output.stdout).to.be.empty;
+    });
 })